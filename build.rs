@@ -0,0 +1,117 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generates the HPACK Huffman decode table from the code table in
+//! `src/hpack/huffman_codes.rs`; see `hpack::huffman`.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+static HUFFMAN_CODE_TABLE: [(u32, u8); 257] = include!("src/hpack/huffman_codes.rs");
+
+/// Keep in sync with `hpack::huffman`.
+const EMIT: u8 = 0x1;
+const FAIL: u8 = 0x2;
+
+#[derive(Copy, Clone)]
+enum Node {
+    Internal(usize),
+    Leaf(usize),
+}
+
+/// Returns the transitions, indexed by `state * 16 + nibble` as `(state, flags, symbol)`, and
+/// the padding of every state.
+fn decode_table(table: &[(u32, u8)]) -> (Vec<(u8, u8, u8)>, Vec<(u8, bool)>) {
+    // The code tree: a complete prefix code for 257 symbols has exactly
+    // 256 internal nodes, the root being 0, which fits the `u8` states.
+    let mut tree: Vec<[Option<Node>; 2]> = vec![[None, None]];
+    let mut padding = vec![(0, true)];
+    for (symbol, &(code, code_len)) in table.iter().enumerate() {
+        let mut node = 0;
+        for i in (0..code_len).rev() {
+            let bit = ((code >> i) & 1) as usize;
+            if i == 0 {
+                tree[node][bit] = Some(Node::Leaf(symbol));
+                break;
+            }
+            node = match tree[node][bit] {
+                Some(Node::Internal(next)) => next,
+                Some(Node::Leaf(_)) => panic!("Invalid Huffman code table. It is not a prefix code."),
+                None => {
+                    let next = tree.len();
+                    let (depth, ones) = padding[node];
+                    tree.push([None, None]);
+                    padding.push((depth + 1, ones && bit == 1));
+                    tree[node][bit] = Some(Node::Internal(next));
+                    next
+                },
+            };
+        }
+    }
+    if tree.len() != 256 {
+        panic!("Invalid Huffman code table. It is not a complete prefix code.");
+    }
+
+    let mut transitions = Vec::with_capacity(256 * 16);
+    for state in 0..256 {
+        for nibble in 0..16 {
+            let (mut flags, mut emitted) = (0, 0);
+            let mut node = state;
+            for i in (0..4).rev() {
+                match tree[node][(nibble >> i) & 1] {
+                    Some(Node::Internal(next)) => node = next,
+                    Some(Node::Leaf(256)) => {
+                        flags = FAIL;
+                        break;
+                    },
+                    Some(Node::Leaf(symbol)) => {
+                        flags = EMIT;
+                        emitted = symbol as u8;
+                        node = 0;
+                    },
+                    None => unreachable!(),
+                }
+            }
+            transitions.push((node as u8, flags, emitted));
+        }
+    }
+    (transitions, padding)
+}
+
+fn write_decode_table(path: &Path) -> io::Result<()> {
+    let (transitions, padding) = decode_table(&HUFFMAN_CODE_TABLE);
+    let mut out = try!(File::create(path));
+    try!(writeln!(out, "/// Generated by build.rs, indexed by `state * 16 + nibble`."));
+    try!(writeln!(out, "pub static DECODE_TABLE: [Transition; 4096] = ["));
+    for &(state, flags, symbol) in &transitions {
+        try!(writeln!(out, "    Transition {{ state: {}, flags: {}, symbol: {} }},", state, flags, symbol));
+    }
+    try!(writeln!(out, "];"));
+    try!(writeln!(out, "/// Generated by build.rs: the padding bits read in every state, and whether they are all ones."));
+    try!(writeln!(out, "pub static DECODE_PADDING: [(u8, bool); 256] = ["));
+    for &(depth, ones) in &padding {
+        try!(writeln!(out, "    ({}, {}),", depth, ones));
+    }
+    try!(writeln!(out, "];"));
+    Ok(())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/hpack/huffman_codes.rs");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    write_decode_table(&Path::new(&out_dir).join("huffman_decode.rs")).unwrap();
+}
@@ -0,0 +1,357 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared by the HTTP/2 examples. `serve` drives a server `Connection` over a blocking socket,
+//! a `TcpStream` or a `TlsStream` around one, and hands each request to a `Handler` as its
+//! frames arrive. Responses go out through the `Responder`, as fast as the client's flow
+//! control windows let them and in the order the connection's write scheduler picks.
+
+// Each example uses only some of it.
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use bytes::BytesMut;
+
+use tokio_http2::hpack::{Decoder, Encoder};
+use tokio_http2::http2::{ErrorCode, Http2FrameCodec, StreamIdentifier, PROTOCOL_ERROR};
+use tokio_http2::http2::connection::{Connection, ConnectionConfig, Recv};
+use tokio_http2::http2::continuation::{split_headers, HeaderBlock};
+use tokio_http2::http2::flag::{DataFlags, Flag};
+use tokio_http2::http2::frame::Frame;
+use tokio_http2::http2::kind::Kind;
+use tokio_http2::http2::payload::Payload;
+use tokio_http2::http2::preface::{PrefaceConfig, PrefaceError, PrefaceReader};
+use tokio_http2::http2::settings::Settings;
+
+pub fn invalid<E: fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Appends `frame` to `out`.
+pub fn encode(frame: &Frame, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.resize(start + frame.encoded_len(), 0);
+    frame.encode(&mut out[start..]);
+}
+
+/// The flow controlled length of a frame: its whole length if it is DATA.
+pub fn data_len(frame: &Frame) -> u32 {
+    if frame.header.kind == Kind::Data { frame.header.length } else { 0 }
+}
+
+/// The value of the field `name` of a decoded header block.
+pub fn field<'a>(fields: &'a [(Vec<u8>, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    fields.iter().find(|&&(ref n, _)| n == name.as_bytes()).map(|&(_, ref v)| &v[..])
+}
+
+pub struct Request {
+    pub id: StreamIdentifier,
+    pub method: String,
+    pub path: String,
+    /// All the fields, the pseudo-header fields included.
+    pub fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        field(&self.fields, name)
+    }
+}
+
+pub trait Handler {
+    /// The headers of a request came; `end_stream` if it has no body.
+    fn request(&mut self, res: &mut Responder, req: Request, end_stream: bool) -> io::Result<()>;
+
+    /// Some of the body of the request on stream `id`, as it arrives; `end_stream` with the last
+    /// of it, which is empty when trailers end the request.
+    fn data(&mut self, _res: &mut Responder, _id: StreamIdentifier, _data: &[u8], _end_stream: bool)
+            -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What is left to send of a response.
+struct Outgoing {
+    body: Vec<u8>,
+    sent: usize,
+    end_stream: bool,
+    trailers: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+/// Sends the responses of a connection. Headers go out at once; bodies and trailers are queued
+/// and written by `flush`, which `serve` calls after every read.
+pub struct Responder {
+    conn: Connection,
+    encoder: Encoder<'static>,
+    /// The frames to write, encoded.
+    out: Vec<u8>,
+    outgoing: HashMap<StreamIdentifier, Outgoing>,
+}
+
+impl Responder {
+    fn write(&mut self, frame: &Frame) {
+        encode(frame, &mut self.out);
+    }
+
+    fn send_block(&mut self, id: StreamIdentifier, fields: &[(&[u8], &[u8])], end_stream: bool) -> io::Result<()> {
+        try!(self.conn.send_headers(id, end_stream, Instant::now()).map_err(invalid));
+        let block = try!(self.encoder.encode(fields.iter().cloned()));
+        let flag = if end_stream { Flag::end_stream() } else { Flag::empty() };
+        for frame in split_headers(id, flag, None, &block, self.conn.peer_max_frame_size() as usize) {
+            self.write(&frame);
+        }
+        Ok(())
+    }
+
+    /// Sends the response headers of stream `id`; `end_stream` if there is no body.
+    pub fn headers(&mut self, id: StreamIdentifier, status: u16, fields: &[(&[u8], &[u8])], end_stream: bool)
+                   -> io::Result<()> {
+        let status = status.to_string();
+        let mut block: Vec<(&[u8], &[u8])> = vec![(b":status", status.as_bytes())];
+        block.extend_from_slice(fields);
+        self.send_block(id, &block, end_stream)
+    }
+
+    /// Queues `data` to send on stream `id`, after its headers; `end_stream` with the last.
+    pub fn data(&mut self, id: StreamIdentifier, data: &[u8], end_stream: bool) {
+        {
+            let outgoing = self.outgoing.entry(id).or_insert_with(|| {
+                Outgoing { body: Vec::new(), sent: 0, end_stream: false, trailers: None }
+            });
+            outgoing.body.extend_from_slice(data);
+            outgoing.end_stream = end_stream;
+        }
+        self.conn.queued(id, data.len() as u32, Instant::now());
+    }
+
+    /// Queues the trailers that end the response of stream `id`, after its body.
+    pub fn trailers(&mut self, id: StreamIdentifier, fields: &[(&[u8], &[u8])]) {
+        {
+            let outgoing = self.outgoing.entry(id).or_insert_with(|| {
+                Outgoing { body: Vec::new(), sent: 0, end_stream: false, trailers: None }
+            });
+            outgoing.trailers = Some(fields.iter().map(|&(n, v)| (n.to_vec(), v.to_vec())).collect());
+            outgoing.end_stream = true;
+        }
+        self.conn.queued(id, 0, Instant::now());
+    }
+
+    /// Resets stream `id`, dropping what is left of its response.
+    pub fn reset(&mut self, id: StreamIdentifier, code: ErrorCode) {
+        self.outgoing.remove(&id);
+        if let Some(frame) = self.conn.send_reset(id, code) {
+            self.write(&frame);
+        }
+    }
+
+    /// Writes the DATA and trailers the windows allow, of the streams the scheduler picks.
+    fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some(id) = self.conn.scheduler_mut().next() {
+            let mut outgoing = match self.outgoing.remove(&id) {
+                Some(outgoing) => outgoing,
+                None => {
+                    self.conn.scheduler_mut().set_ready(id, false);
+                    continue;
+                },
+            };
+            let left = outgoing.body.len() - outgoing.sent;
+            let last = outgoing.end_stream && outgoing.trailers.is_none();
+            if left > 0 || last {
+                let capacity = if left > 0 { self.conn.poll_capacity(id, now) as usize } else { 0 };
+                if left > 0 && capacity == 0 {
+                    // Blocked: the scheduler has it again after a WINDOW_UPDATE.
+                    self.outgoing.insert(id, outgoing);
+                    continue;
+                }
+                let len = cmp::min(left, cmp::min(capacity, self.conn.peer_max_frame_size() as usize));
+                let end_stream = last && len == left;
+                try!(self.conn.send_data(id, len as u32, end_stream, now).map_err(invalid));
+                let flags = if end_stream { DataFlags::end_stream() } else { DataFlags::empty() };
+                encode(&Frame::data(flags, id, &outgoing.body[outgoing.sent..outgoing.sent + len]), &mut self.out);
+                outgoing.sent += len;
+                if end_stream {
+                    continue;
+                }
+                if outgoing.sent < outgoing.body.len() {
+                    self.outgoing.insert(id, outgoing);
+                    continue;
+                }
+            }
+
+            // All of the body queued went out.
+            outgoing.body.clear();
+            outgoing.sent = 0;
+            self.conn.scheduler_mut().set_ready(id, false);
+            match outgoing.trailers.take() {
+                Some(trailers) => {
+                    let fields: Vec<(&[u8], &[u8])> = trailers.iter().map(|&(ref n, ref v)| (&n[..], &v[..])).collect();
+                    try!(self.send_block(id, &fields, true));
+                },
+                None => {
+                    self.outgoing.insert(id, outgoing);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves one connection, whose client speaks HTTP/2 from the start (h2c with prior knowledge,
+/// or h2 over TLS), until the client closes it or sends GOAWAY.
+pub fn serve<S: Read + Write, H: Handler>(mut socket: S, handler: &mut H) -> io::Result<()> {
+    let settings = Settings { max_concurrent_streams: Some(100), ..Settings::default() };
+    let mut res = Responder {
+        conn: Connection::new(true, ConnectionConfig::default()),
+        encoder: Encoder::new(),
+        out: Vec::new(),
+        outgoing: HashMap::new(),
+    };
+    res.out = res.conn.preface(&settings, Instant::now());
+    let mut preface = PrefaceReader::new(PrefaceConfig::default(), Instant::now());
+    let mut decoder = Decoder::new();
+    let mut codec = Http2FrameCodec::new();
+    let mut buf = BytesMut::with_capacity(16384);
+    let mut read = vec![0; 16384];
+    // The streams whose request hasn't ended, and the last stream a request came on.
+    let mut requests = HashSet::new();
+    let mut last = StreamIdentifier(0);
+
+    loop {
+        try!(res.flush());
+        try!(socket.write_all(&res.out));
+        res.out.clear();
+
+        let n = try!(socket.read(&mut read));
+        if n == 0 {
+            return Ok(());
+        }
+        let mut octets = &read[..n];
+        if !preface.is_done() {
+            match preface.recv(octets) {
+                Ok(Some(len)) => octets = &octets[len..],
+                Ok(None) => continue,
+                Err(e) => {
+                    if let PrefaceError::Invalid(invalid) = e {
+                        if let Some(response) = invalid.response() {
+                            let _ = socket.write_all(response);
+                        }
+                    }
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+                },
+            }
+        }
+        buf.extend_from_slice(octets);
+
+        while let Some(frame) = try!(codec.decode_bytes(&mut buf).map_err(invalid)) {
+            let parsed = try!(frame.frame().map_err(invalid));
+            let recv = match res.conn.recv(&parsed, Instant::now()) {
+                Ok(recv) => recv,
+                Err(e) => {
+                    res.write(&Frame::goaway(last, e.error_code(), &[]));
+                    let _ = socket.write_all(&res.out);
+                    return Err(invalid(e));
+                },
+            };
+            preface.settings_received();
+            match recv {
+                Recv::Settings(_) => {
+                    let ack = res.conn.settings_ack();
+                    res.write(&ack);
+                    // A larger SETTINGS_INITIAL_WINDOW_SIZE lets blocked streams send again.
+                    res.conn.poll_unblocked();
+                },
+                Recv::Ping(ack) => res.write(&ack),
+                Recv::Headers(block) => {
+                    let fields = try!(decode(&mut decoder, &block));
+                    let id = block.id;
+                    if requests.contains(&id) {
+                        // Trailers.
+                        if block.end_stream {
+                            requests.remove(&id);
+                            try!(handler.data(&mut res, id, &[], true));
+                        }
+                        continue;
+                    }
+                    let (method, path) = match (field(&fields, ":method"), field(&fields, ":path")) {
+                        (Some(method), Some(path)) => {
+                            (String::from_utf8_lossy(method).into_owned(), String::from_utf8_lossy(path).into_owned())
+                        },
+                        _ => {
+                            res.reset(id, PROTOCOL_ERROR);
+                            continue;
+                        },
+                    };
+                    last = id;
+                    if !block.end_stream {
+                        requests.insert(id);
+                    }
+                    let req = Request { id: id, method: method, path: path, fields: fields };
+                    try!(handler.request(&mut res, req, block.end_stream));
+                },
+                Recv::Data { id, end_stream } => {
+                    if let Some(data) = frame.data_payload() {
+                        try!(handler.data(&mut res, id, &data, end_stream));
+                    }
+                    if end_stream {
+                        requests.remove(&id);
+                    }
+                    for update in res.conn.consumed(id, frame.header.length) {
+                        res.write(&update);
+                    }
+                },
+                Recv::Reset(id, _) => {
+                    requests.remove(&id);
+                    res.outgoing.remove(&id);
+                },
+                Recv::Ignored(block) => {
+                    if let Some(block) = block {
+                        try!(decode(&mut decoder, &block));
+                    }
+                    for update in res.conn.consumed(frame.header.id, data_len(&parsed)) {
+                        res.write(&update);
+                    }
+                },
+                Recv::StreamError(error, block) => {
+                    if let Some(block) = block {
+                        try!(decode(&mut decoder, &block));
+                    }
+                    for update in res.conn.consumed(frame.header.id, data_len(&parsed)) {
+                        res.write(&update);
+                    }
+                    requests.remove(&error.id);
+                    res.outgoing.remove(&error.id);
+                    res.write(&error.frame());
+                },
+                Recv::Connection => {
+                    if let Payload::GoAway { .. } = parsed.payload {
+                        try!(res.flush());
+                        return socket.write_all(&res.out);
+                    }
+                },
+                Recv::WindowUpdate(_) | Recv::SettingsAcked(_) | Recv::Pending => {},
+            }
+        }
+    }
+}
+
+/// Decodes a header block, which every one must go through to keep the HPACK tables in step.
+fn decode(decoder: &mut Decoder<'static>, block: &HeaderBlock) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    decoder.decode(&block.block).map_err(|e| invalid(format!("HPACK: {:?}", e)))
+}
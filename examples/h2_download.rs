@@ -0,0 +1,316 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Concurrent download client: fetches all the URLs given over one HTTP/2 connection, as many
+//! at once as the server's SETTINGS_MAX_CONCURRENT_STREAMS allows, and prints how each went.
+//! h2c with prior knowledge for `http://` URLs, TLS for `https://` ones (without ALPN, see
+//! `h2_static_tls`). With `-o DIR`, the bodies are saved there under the last segment of their
+//! path.
+//!
+//! cargo run --example h2_download --features examples -- -o /tmp http://127.0.0.1:8080/a http://127.0.0.1:8080/b
+
+extern crate bytes;
+extern crate native_tls;
+extern crate url;
+extern crate tokio_http2;
+
+mod common;
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use native_tls::TlsConnector;
+use url::Url;
+
+use tokio_http2::hpack::{Decoder, Encoder};
+use tokio_http2::http2::{ErrorCode, Http2FrameCodec, StreamIdentifier};
+use tokio_http2::http2::connection::{Connection, ConnectionConfig, OpenError, Recv};
+use tokio_http2::http2::continuation::HeaderBlock;
+use tokio_http2::http2::flag::HeadersFlags;
+use tokio_http2::http2::frame::Frame;
+use tokio_http2::http2::payload::Payload;
+use tokio_http2::http2::settings::Settings;
+
+use common::{data_len, encode, field, invalid};
+
+trait Socket: Read + Write {}
+
+impl<S: Read + Write> Socket for S {}
+
+struct Download {
+    url: Url,
+    status: Option<String>,
+    len: usize,
+    started: Instant,
+    file: Option<File>,
+}
+
+struct Client {
+    conn: Connection,
+    encoder: Encoder<'static>,
+    decoder: Decoder<'static>,
+    /// The frames to write, encoded.
+    out: Vec<u8>,
+    /// The URLs not requested yet, waiting for the server's SETTINGS_MAX_CONCURRENT_STREAMS.
+    pending: VecDeque<Url>,
+    downloads: HashMap<StreamIdentifier, Download>,
+    dir: Option<PathBuf>,
+    failed: usize,
+}
+
+impl Client {
+    /// Requests as many of the pending URLs as the server lets us open streams for.
+    fn send_requests(&mut self) -> io::Result<()> {
+        while let Some(url) = self.pending.pop_front() {
+            let id = match self.conn.open_stream() {
+                Ok(id) => id,
+                Err(OpenError::TooManyStreams) => {
+                    self.pending.push_front(url);
+                    return Ok(());
+                },
+                Err(e) => return Err(invalid(e)),
+            };
+            let authority = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+                None => url.host_str().unwrap_or("").to_string(),
+            };
+            let path = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            try!(self.conn.send_headers(id, true, Instant::now()).map_err(invalid));
+            let block = try!(self.encoder.encode(vec![
+                (&b":method"[..], &b"GET"[..]),
+                (b":scheme", url.scheme().as_bytes()),
+                (b":authority", authority.as_bytes()),
+                (b":path", path.as_bytes()),
+                (b"user-agent", b"h2_download"),
+            ]));
+            encode(&Frame::headers(HeadersFlags::end_headers() | HeadersFlags::end_stream(), id, None, &block),
+                   &mut self.out);
+
+            let file = match self.dir {
+                Some(ref dir) => {
+                    let name = url.path_segments().and_then(|s| s.last()).unwrap_or("");
+                    Some(try!(File::create(dir.join(if name.is_empty() { "index.html" } else { name }))))
+                },
+                None => None,
+            };
+            self.downloads.insert(id, Download { url: url, status: None, len: 0, started: Instant::now(), file: file });
+        }
+        Ok(())
+    }
+
+    /// Decodes a header block, which every one must go through to keep the HPACK tables in
+    /// step, and takes the status of a response.
+    fn decode(&mut self, block: &HeaderBlock) -> io::Result<()> {
+        let fields = try!(self.decoder.decode(&block.block).map_err(|e| invalid(format!("HPACK: {:?}", e))));
+        if let Some(download) = self.downloads.get_mut(&block.id) {
+            if download.status.is_none() {
+                download.status = field(&fields, ":status").map(|status| String::from_utf8_lossy(status).into_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// The download on stream `id` is over, or failed with `error`.
+    fn finish(&mut self, id: StreamIdentifier, error: Option<String>) {
+        if let Some(download) = self.downloads.remove(&id) {
+            let elapsed = download.started.elapsed();
+            let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+            match error {
+                Some(error) => {
+                    self.failed += 1;
+                    println!("{}: failed after {} bytes, {}", download.url, download.len, error);
+                },
+                None => {
+                    println!("{}: {} {} bytes in {} ms", download.url, download.status.unwrap_or_default(),
+                             download.len, ms);
+                },
+            }
+        }
+    }
+
+    fn data(&mut self, id: StreamIdentifier, data: &[u8]) -> io::Result<()> {
+        if let Some(download) = self.downloads.get_mut(&id) {
+            download.len += data.len();
+            if let Some(ref mut file) = download.file {
+                try!(file.write_all(data));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads frames until every URL was downloaded.
+    fn run(&mut self, socket: &mut Socket) -> io::Result<()> {
+        let mut codec = Http2FrameCodec::new();
+        let mut buf = BytesMut::with_capacity(16384);
+        let mut read = vec![0; 16384];
+        loop {
+            try!(self.send_requests());
+            try!(socket.write_all(&self.out));
+            self.out.clear();
+            if self.pending.is_empty() && self.downloads.is_empty() {
+                return socket.flush();
+            }
+
+            let n = try!(socket.read(&mut read));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection"));
+            }
+            buf.extend_from_slice(&read[..n]);
+            while let Some(frame) = try!(codec.decode_bytes(&mut buf).map_err(invalid)) {
+                let parsed = try!(frame.frame().map_err(invalid));
+                let recv = match self.conn.recv(&parsed, Instant::now()) {
+                    Ok(recv) => recv,
+                    Err(e) => {
+                        encode(&Frame::goaway(StreamIdentifier(0), e.error_code(), &[]), &mut self.out);
+                        let _ = socket.write_all(&self.out);
+                        return Err(invalid(e));
+                    },
+                };
+                match recv {
+                    Recv::Settings(_) => {
+                        let ack = self.conn.settings_ack();
+                        encode(&ack, &mut self.out);
+                    },
+                    Recv::Ping(ack) => encode(&ack, &mut self.out),
+                    Recv::Headers(block) => {
+                        try!(self.decode(&block));
+                        if block.end_stream {
+                            self.finish(block.id, None);
+                        }
+                    },
+                    Recv::Data { id, end_stream } => {
+                        if let Some(data) = frame.data_payload() {
+                            try!(self.data(id, &data));
+                        }
+                        for update in self.conn.consumed(id, frame.header.length) {
+                            encode(&update, &mut self.out);
+                        }
+                        if end_stream {
+                            self.finish(id, None);
+                        }
+                    },
+                    Recv::Reset(id, error) => self.finish(id, Some(format!("reset with {}", error))),
+                    Recv::Ignored(block) => {
+                        if let Some(block) = block {
+                            try!(self.decode(&block));
+                        }
+                        for update in self.conn.consumed(frame.header.id, data_len(&parsed)) {
+                            encode(&update, &mut self.out);
+                        }
+                    },
+                    Recv::StreamError(error, block) => {
+                        if let Some(block) = block {
+                            try!(self.decode(&block));
+                        }
+                        for update in self.conn.consumed(frame.header.id, data_len(&parsed)) {
+                            encode(&update, &mut self.out);
+                        }
+                        encode(&error.frame(), &mut self.out);
+                        self.finish(error.id, Some(format!("failed with {}", error.code)));
+                    },
+                    Recv::Connection => {
+                        if let Payload::GoAway { last, error, .. } = parsed.payload {
+                            // The server won't answer the requests after `last`, nor any more.
+                            let unanswered: Vec<_> =
+                                self.downloads.keys().filter(|id| id.0 > last.0).cloned().collect();
+                            for id in unanswered {
+                                self.finish(id, Some(format!("not processed, GOAWAY {}", error)));
+                            }
+                            for url in self.pending.drain(..) {
+                                println!("{}: not requested, GOAWAY {}", url, error);
+                            }
+                            if error != ErrorCode::NoError {
+                                return Err(io::Error::new(io::ErrorKind::Other, format!("GOAWAY {}", error)));
+                            }
+                        }
+                    },
+                    Recv::WindowUpdate(_) | Recv::SettingsAcked(_) | Recv::Pending => {},
+                }
+            }
+        }
+    }
+}
+
+fn run() -> io::Result<usize> {
+    let mut args = env::args().skip(1).peekable();
+    let dir = if args.peek().map_or(false, |arg| arg == "-o") {
+        args.next();
+        args.next().map(PathBuf::from)
+    } else {
+        None
+    };
+    let mut urls = VecDeque::new();
+    for arg in args {
+        let url = try!(Url::parse(&arg).map_err(invalid));
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only http:// and https:// URLs are supported"));
+        }
+        if urls.front().map_or(false, |first: &Url| (first.scheme(), first.host_str(), first.port()) !=
+                                                  (url.scheme(), url.host_str(), url.port())) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "all URLs must share the same origin"));
+        }
+        urls.push_back(url);
+    }
+    if urls.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: h2_download [-o DIR] URL..."));
+    }
+
+    let host = urls[0].host_str().unwrap_or("").to_string();
+    let tcp = try!(TcpStream::connect((&host[..], urls[0].port_or_known_default().unwrap_or(80))));
+    let mut socket: Box<Socket> = if urls[0].scheme() == "https" {
+        let connector = try!(TlsConnector::builder().and_then(|builder| builder.build()).map_err(invalid));
+        Box::new(try!(connector.connect(&host, tcp).map_err(invalid)))
+    } else {
+        Box::new(tcp)
+    };
+
+    let mut client = Client {
+        conn: Connection::new(false, ConnectionConfig::default()),
+        encoder: Encoder::new(),
+        decoder: Decoder::new(),
+        out: Vec::new(),
+        pending: urls,
+        downloads: HashMap::new(),
+        dir: dir,
+        failed: 0,
+    };
+    let settings = Settings { enable_push: Some(false), ..Settings::default() };
+    client.out = client.conn.preface(&settings, Instant::now());
+    try!(client.run(&mut *socket));
+    Ok(client.failed)
+}
+
+fn main() {
+    match run() {
+        Ok(0) => {},
+        Ok(failed) => {
+            println!("h2_download: {} downloads failed", failed);
+            process::exit(1);
+        },
+        Err(e) => {
+            println!("h2_download: {}", e);
+            process::exit(1);
+        },
+    }
+}
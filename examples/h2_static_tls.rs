@@ -0,0 +1,141 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Static file server over TLS: serves the files under a directory to GET and HEAD requests,
+//! `index.html` for a directory.
+//!
+//! mkcert -install && mkcert -pkcs12 localhost
+//! cargo run --example h2_static_tls --features examples -- localhost.p12 changeit ./public
+//! cargo run --example h2_download --features examples -- https://localhost:8443/index.html
+//!
+//! NB: native-tls 0.1 can't negotiate ALPN, so the server speaks HTTP/2 to every TLS client as
+//! if it had picked "h2": clients that insist on ALPN, such as browsers and curl, won't use it.
+
+extern crate bytes;
+extern crate mime_guess;
+extern crate native_tls;
+extern crate tokio_http2;
+
+mod common;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use native_tls::{Pkcs12, TlsAcceptor};
+use tokio_http2::http::connections::Connections;
+use tokio_http2::http::tls::{self, Export};
+
+use common::{Handler, Request, Responder};
+
+struct Files {
+    root: Arc<PathBuf>,
+}
+
+impl Files {
+    /// The file `path` asks for, unless it would leave the root.
+    fn file(&self, path: &str) -> Option<PathBuf> {
+        let path = path.splitn(2, '?').next().unwrap_or("");
+        let mut file = (*self.root).clone();
+        for component in Path::new(path.trim_left_matches('/')).components() {
+            match component {
+                Component::Normal(part) => file.push(part),
+                Component::CurDir => {},
+                _ => return None,
+            }
+        }
+        if file.is_dir() {
+            file.push("index.html");
+        }
+        Some(file)
+    }
+}
+
+impl Handler for Files {
+    fn request(&mut self, res: &mut Responder, req: Request, _end_stream: bool) -> io::Result<()> {
+        if req.method != "GET" && req.method != "HEAD" {
+            return res.headers(req.id, 405, &[(b"allow", b"GET, HEAD")], true);
+        }
+        let mut contents = Vec::new();
+        let file = self.file(&req.path);
+        match file.as_ref().map(File::open) {
+            Some(Ok(mut f)) => try!(f.read_to_end(&mut contents)),
+            _ => return res.headers(req.id, 404, &[], true),
+        };
+        let content_type = mime_guess::guess_mime_type(file.unwrap()).to_string();
+        let length = contents.len().to_string();
+        let head = req.method == "HEAD";
+        try!(res.headers(req.id, 200, &[(b"content-type", content_type.as_bytes()),
+                                        (b"content-length", length.as_bytes())], head));
+        if !head {
+            res.data(req.id, &contents, true);
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        println!("usage: h2_static_tls <identity.p12> <password> <root>");
+        process::exit(2);
+    }
+    let mut der = Vec::new();
+    File::open(&args[1]).and_then(|mut f| f.read_to_end(&mut der)).unwrap();
+    let identity = Pkcs12::from_der(&der, &args[2]).unwrap();
+    let acceptor = Arc::new(TlsAcceptor::builder(identity).unwrap().build().unwrap());
+    let root = Arc::new(PathBuf::from(&args[3]));
+    // Each connection can export its token binding key (RFC 8471) with
+    // `Connection::export_keying_material`.
+    let connections = Connections::new();
+    let exports = Arc::new(vec![Export::new("EXPORTER-Token-Binding", None, 32)]);
+
+    let listener = TcpListener::bind("127.0.0.1:8443").unwrap();
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            },
+        };
+        let registration = match socket.peer_addr() {
+            Ok(addr) => connections.register(addr),
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            },
+        };
+        let acceptor = acceptor.clone();
+        let exports = exports.clone();
+        let root = root.clone();
+        thread::spawn(move || {
+            let socket = match tls::accept(&acceptor, socket, registration.connection(), &exports) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    println!("TLS handshake failed: {}", e);
+                    return;
+                },
+            };
+            if let Err(e) = common::serve(socket, &mut Files { root: root }) {
+                println!("connection failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming service, gRPC style: every length-prefixed message a client streams to
+//! `/Upper/Stream` comes back upper-cased as soon as all of it arrived, on a response that
+//! stays open until the request ends. Trailers carry the `grpc-status`. Over h2c.
+//!
+//! cargo run --example h2_streaming --features examples
+//! printf '\0\0\0\0\5hello\0\0\0\0\5world' | curl --http2-prior-knowledge -sS --data-binary @- \
+//!     -H 'content-type: application/grpc' -H 'te: trailers' http://127.0.0.1:8080/Upper/Stream | xxd
+
+extern crate bytes;
+extern crate tokio_http2;
+
+mod common;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+
+use tokio_http2::http2::StreamIdentifier;
+
+use common::{Handler, Request, Responder};
+
+/// The compressed flag and the length before every message.
+const PREFIX_BYTES: usize = 5;
+
+const OK: &'static [u8] = b"0";
+const UNIMPLEMENTED: &'static [u8] = b"12";
+const INTERNAL: &'static [u8] = b"13";
+
+/// The octets of each call's request not making up a whole message yet.
+struct Upper {
+    calls: HashMap<StreamIdentifier, Vec<u8>>,
+}
+
+impl Upper {
+    fn finish(&mut self, res: &mut Responder, id: StreamIdentifier, status: &[u8]) {
+        self.calls.remove(&id);
+        res.trailers(id, &[(b"grpc-status", status)]);
+    }
+}
+
+impl Handler for Upper {
+    fn request(&mut self, res: &mut Responder, req: Request, end_stream: bool) -> io::Result<()> {
+        if req.method != "POST" || req.path != "/Upper/Stream" {
+            // Trailers-only.
+            return res.headers(req.id, 200, &[(b"content-type", b"application/grpc"),
+                                               (b"grpc-status", UNIMPLEMENTED)], true);
+        }
+        try!(res.headers(req.id, 200, &[(b"content-type", b"application/grpc")], false));
+        if end_stream {
+            res.trailers(req.id, &[(b"grpc-status", OK)]);
+        } else {
+            self.calls.insert(req.id, Vec::new());
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, res: &mut Responder, id: StreamIdentifier, data: &[u8], end_stream: bool) -> io::Result<()> {
+        let mut replies = Vec::new();
+        let status = match self.calls.get_mut(&id) {
+            Some(buf) => {
+                buf.extend_from_slice(data);
+                let mut status = None;
+                while buf.len() >= PREFIX_BYTES {
+                    let len = ((buf[1] as usize) << 24) | ((buf[2] as usize) << 16) |
+                              ((buf[3] as usize) << 8) | buf[4] as usize;
+                    if buf[0] != 0 {
+                        // We don't take compressed messages.
+                        status = Some(UNIMPLEMENTED);
+                        break;
+                    }
+                    if buf.len() < PREFIX_BYTES + len {
+                        break;
+                    }
+                    let message: Vec<u8> = buf.drain(..PREFIX_BYTES + len).collect();
+                    replies.extend_from_slice(&message[..PREFIX_BYTES]);
+                    replies.extend(message[PREFIX_BYTES..].iter().map(|b| b.to_ascii_uppercase()));
+                }
+                match status {
+                    Some(status) => Some(status),
+                    None if end_stream && !buf.is_empty() => Some(INTERNAL),
+                    None if end_stream => Some(OK),
+                    None => None,
+                }
+            },
+            // The call failed already, or there was none.
+            None => return Ok(()),
+        };
+        if !replies.is_empty() {
+            res.data(id, &replies, false);
+        }
+        if let Some(status) = status {
+            self.finish(res, id, status);
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            },
+        };
+        thread::spawn(move || if let Err(e) = common::serve(socket, &mut Upper { calls: HashMap::new() }) {
+            println!("connection failed: {}", e);
+        });
+    }
+}
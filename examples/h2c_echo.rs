@@ -0,0 +1,66 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! h2c echo server: answers every request with its own body, each DATA frame sent back as it
+//! arrives. Cleartext HTTP/2 with prior knowledge.
+//!
+//! cargo run --example h2c_echo --features examples
+//! curl --http2-prior-knowledge -d 'hello' http://127.0.0.1:8080/
+
+extern crate bytes;
+extern crate tokio_http2;
+
+mod common;
+
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+
+use tokio_http2::http2::StreamIdentifier;
+
+use common::{Handler, Request, Responder};
+
+struct Echo;
+
+impl Handler for Echo {
+    fn request(&mut self, res: &mut Responder, req: Request, end_stream: bool) -> io::Result<()> {
+        let content_type = req.header("content-type").unwrap_or(b"application/octet-stream").to_vec();
+        try!(res.headers(req.id, 200, &[(b"content-type", &content_type)], false));
+        if end_stream {
+            res.data(req.id, &[], true);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, res: &mut Responder, id: StreamIdentifier, data: &[u8], end_stream: bool) -> io::Result<()> {
+        res.data(id, data, end_stream);
+        Ok(())
+    }
+}
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            },
+        };
+        thread::spawn(move || if let Err(e) = common::serve(socket, &mut Echo) {
+            println!("connection failed: {}", e);
+        });
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encodes two header blocks with a shared HPACK context and decodes them again, printing the
+//! wire size and the fields of each block.
+//!
+//! cargo run --example hpack --features examples
+
+extern crate tokio_http2;
+
+use tokio_http2::hpack::{Decoder, Encoder};
+
+fn main() {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+
+    let headers: Vec<(&[u8], &[u8])> = vec![
+        (b":method", b"GET"),
+        (b":path", b"/index.html"),
+        (b":authority", b"www.example.com"),
+        (b"user-agent", b"tokio-http2-example"),
+    ];
+
+    for round in 1..3 {
+        let block = encoder.encode(headers.iter().cloned()).expect("encoding failed");
+        let decoded = decoder.decode(&block).expect("decoding our own block failed");
+        assert_eq!(decoded.len(), headers.len());
+
+        println!("block {}: {} bytes", round, block.len());
+        for (name, value) in decoded {
+            println!("  {}: {}", String::from_utf8_lossy(&name), String::from_utf8_lossy(&value));
+        }
+    }
+}
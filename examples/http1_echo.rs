@@ -0,0 +1,53 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/1.1 echo server: answers every request with its own body.
+//!
+//! cargo run --example http1_echo --features examples
+//! curl -d 'hello' http://127.0.0.1:8080/
+
+extern crate futures;
+extern crate tokio_proto;
+extern crate tokio_service;
+extern crate tokio_http2;
+
+use std::io;
+
+use futures::future;
+use tokio_proto::TcpServer;
+use tokio_service::Service;
+
+use tokio_http2::{Request, Response};
+use tokio_http2::http::HttpProto;
+
+struct Echo;
+
+impl Service for Echo {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = future::FutureResult<Response, io::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let body = req.payload().unwrap_or(b"").to_vec();
+        future::ok(Response::new()
+            .with_header("Content-Type", req.content_type())
+            .with_body(body))
+    }
+}
+
+fn main() {
+    let addr = "127.0.0.1:8080".parse().unwrap();
+    TcpServer::new(HttpProto::default(), addr).serve(|| Ok(Echo));
+}
@@ -0,0 +1,145 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Security audit hook. Every time the server turns a connection away or resets a stream for
+//! policy or protocol reasons it calls the `AuditHook` with an `AuditEvent`, so deployments can
+//! feed fail2ban-style tooling directly instead of scraping the logs.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use http::accept::Rejection;
+use http2::{ErrorCode, InvalidPreface, StreamIdentifier};
+use http2::flood::Flood;
+
+/// Why the connection or stream was refused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Reason {
+    /// Refused by the accept limits.
+    Accept(Rejection),
+    /// The handshake did not complete within its deadline.
+    HandshakeTimeout,
+    /// The peer did not send a valid connection preface.
+    InvalidPreface(InvalidPreface),
+    /// The peer flooded the connection.
+    Flood(Flood),
+    /// The peer stopped reading response data.
+    Stall,
+    /// The peer violated the protocol; carries the error code sent to it.
+    Protocol(ErrorCode),
+}
+
+impl Reason {
+    /// A stable, machine readable name for the reason.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Reason::Accept(Rejection::PerPeerLimit) => "per_peer_limit",
+            Reason::Accept(Rejection::HandshakeRate) => "handshake_rate",
+            Reason::Accept(Rejection::Policy) => "policy",
+            Reason::Accept(Rejection::PreHandshakeBytes) => "pre_handshake_bytes",
+            Reason::Accept(Rejection::PreHandshakeTimeout) => "pre_handshake_timeout",
+            Reason::HandshakeTimeout => "handshake_timeout",
+            Reason::InvalidPreface(InvalidPreface::Http1) => "preface_http1",
+            Reason::InvalidPreface(InvalidPreface::Tls) => "preface_tls",
+            Reason::InvalidPreface(InvalidPreface::Garbage) => "preface_garbage",
+            Reason::InvalidPreface(InvalidPreface::NoSettings) => "preface_no_settings",
+            Reason::Flood(Flood::Settings) => "settings_flood",
+            Reason::Flood(Flood::Ping) => "ping_flood",
+            Reason::Flood(Flood::EmptyFrames) => "empty_frame_flood",
+            Reason::Flood(Flood::WindowUpdate) => "window_update_flood",
+            Reason::Flood(Flood::StreamChurn) => "stream_churn_flood",
+            Reason::Flood(Flood::RapidReset) => "rapid_reset_flood",
+            Reason::Stall => "stall",
+            Reason::Protocol(_) => "protocol_error",
+        }
+    }
+}
+
+/// Totals across the server at the time of the event, including the event itself.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Counters {
+    pub connections_rejected: usize,
+    pub streams_reset: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AuditEvent {
+    pub peer: SocketAddr,
+    pub reason: Reason,
+    /// The stream that was reset, or `None` when the whole connection was refused.
+    pub stream: Option<StreamIdentifier>,
+    pub counters: Counters,
+}
+
+/// Shared by every connection of a server; cheap to clone.
+#[derive(Clone)]
+pub struct AuditHook {
+    callback: Arc<Fn(&AuditEvent) + Send + Sync>,
+    connections_rejected: Arc<AtomicUsize>,
+    streams_reset: Arc<AtomicUsize>,
+}
+
+impl AuditHook {
+    pub fn new<F>(callback: F) -> AuditHook
+            where F: Fn(&AuditEvent) + Send + Sync + 'static {
+        AuditHook {
+            callback: Arc::new(callback),
+            connections_rejected: Arc::new(AtomicUsize::new(0)),
+            streams_reset: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reports a connection that was refused or dropped.
+    pub fn connection_rejected(&self, peer: SocketAddr, reason: Reason) {
+        let connections_rejected = self.connections_rejected.fetch_add(1, Ordering::SeqCst) + 1;
+        self.report(peer, reason, None, Counters {
+            connections_rejected: connections_rejected,
+            streams_reset: self.streams_reset.load(Ordering::SeqCst),
+        });
+    }
+
+    /// Reports a stream that was reset.
+    pub fn stream_reset(&self, peer: SocketAddr, stream: StreamIdentifier, reason: Reason) {
+        let streams_reset = self.streams_reset.fetch_add(1, Ordering::SeqCst) + 1;
+        self.report(peer, reason, Some(stream), Counters {
+            connections_rejected: self.connections_rejected.load(Ordering::SeqCst),
+            streams_reset: streams_reset,
+        });
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            connections_rejected: self.connections_rejected.load(Ordering::SeqCst),
+            streams_reset: self.streams_reset.load(Ordering::SeqCst),
+        }
+    }
+
+    fn report(&self, peer: SocketAddr, reason: Reason, stream: Option<StreamIdentifier>,
+              counters: Counters) {
+        (self.callback)(&AuditEvent {
+            peer: peer,
+            reason: reason,
+            stream: stream,
+            counters: counters,
+        });
+    }
+}
+
+impl fmt::Debug for AuditHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuditHook {{ counters: {:?} }}", self.counters())
+    }
+}
@@ -0,0 +1,501 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `h2cli` issues HTTP/2 requests over cleartext (prior knowledge or `Upgrade: h2c`) and prints
+//! the responses. With `-v` every frame sent and received is printed to stderr, including the
+//! decoded HPACK fields of each header block. Streams and flow control are kept by a client
+//! `http2::Connection`, so request bodies go out as fast as the server's WINDOW_UPDATEs allow.
+//!
+//! cargo run --bin h2cli --features h2cli -- -v -n 4 http://127.0.0.1:8080/
+//!
+//! NB: TLS (h2) is not supported yet since it needs ALPN.
+
+extern crate clap;
+extern crate url;
+extern crate tokio_http2;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::rc::Rc;
+use std::time::Instant;
+
+use clap::{App, Arg};
+use url::Url;
+
+use tokio_http2::hpack::{Decoder, Encoder};
+use tokio_http2::http2::{ErrorCode, SizeIncrement, StreamIdentifier, FRAME_HEADER_BYTES};
+use tokio_http2::http2::connection::{Connection, ConnectionConfig, OpenError, Recv};
+use tokio_http2::http2::continuation::HeaderBlock;
+use tokio_http2::http2::flag::{DataFlags, Flag, HeadersFlags};
+use tokio_http2::http2::frame::{Frame, FrameHeader};
+use tokio_http2::http2::kind::Kind;
+use tokio_http2::http2::payload::Payload;
+use tokio_http2::http2::priority::PriorityUpdate;
+use tokio_http2::http2::settings::Settings;
+
+/// Drives a client `Connection` over a blocking socket: the connection keeps the stream states
+/// and both directions' flow control windows, and the client writes what it says to.
+struct Client {
+    socket: TcpStream,
+    conn: Connection,
+    verbose: bool,
+    encoder: Encoder<'static>,
+    decoder: Decoder<'static>,
+    buf: Vec<u8>,
+    /// Sent as the `priority` header of every request.
+    priority: Option<String>,
+    /// POSTed by every request, if set.
+    body: Option<Rc<Vec<u8>>>,
+    /// The requests not sent yet, waiting for the server's SETTINGS_MAX_CONCURRENT_STREAMS.
+    pending: VecDeque<Url>,
+    /// The streams whose response hasn't ended.
+    open: Vec<StreamIdentifier>,
+    /// The streams with some of the body left to send, and how much of it they sent.
+    uploads: Vec<(StreamIdentifier, usize)>,
+}
+
+impl Client {
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.verbose {
+            print_frame(">", frame);
+        }
+        self.buf.resize(frame.encoded_len(), 0);
+        let len = frame.encode(&mut self.buf);
+        self.socket.write_all(&self.buf[..len])
+    }
+
+    fn send_priority_update(&mut self, update: PriorityUpdate) -> io::Result<()> {
+        if self.verbose {
+            eprintln!("> PRIORITY_UPDATE stream {}: {}", update.stream.0, update.priority);
+        }
+        self.buf.resize(update.encoded_len(), 0);
+        let len = update.encode(&mut self.buf);
+        self.socket.write_all(&self.buf[..len])
+    }
+
+    /// Sends as many of the pending requests as the server lets us open streams for.
+    fn send_requests(&mut self) -> io::Result<()> {
+        while let Some(url) = self.pending.pop_front() {
+            let id = match self.conn.open_stream() {
+                Ok(id) => id,
+                Err(OpenError::TooManyStreams) => {
+                    self.pending.push_front(url);
+                    break;
+                },
+                Err(e) => return Err(invalid(e)),
+            };
+            try!(self.send_request(id, &url));
+        }
+        self.send_bodies()
+    }
+
+    fn send_request(&mut self, id: StreamIdentifier, url: &Url) -> io::Result<()> {
+        let end_stream = self.body.as_ref().map_or(true, |body| body.is_empty());
+        let method: &[u8] = if self.body.is_some() { b"POST" } else { b"GET" };
+        let authority = authority(url);
+        let path = path(url);
+
+        let priority = self.priority.clone();
+        let mut fields: Vec<(&[u8], &[u8])> = vec![
+            (b":method", method),
+            (b":scheme", b"http"),
+            (b":authority", authority.as_bytes()),
+            (b":path", path.as_bytes()),
+            (b"user-agent", b"h2cli"),
+        ];
+        if let Some(ref priority) = priority {
+            fields.push((b"priority", priority.as_bytes()));
+        }
+        let block = try!(self.encoder.encode(fields.iter().cloned()));
+
+        try!(self.conn.send_headers(id, end_stream, Instant::now()).map_err(invalid));
+        let mut flags = HeadersFlags::end_headers();
+        if end_stream {
+            flags = flags | HeadersFlags::end_stream();
+        }
+        try!(self.write(&Frame::headers(flags, id, None, &block)));
+        if self.verbose {
+            print_fields(">", block.len(), &fields);
+        }
+        self.open.push(id);
+        if !end_stream {
+            self.uploads.push((id, 0));
+        }
+        Ok(())
+    }
+
+    /// Sends what the flow control windows allow of the bodies left to send.
+    fn send_bodies(&mut self) -> io::Result<()> {
+        let mut uploads = Vec::new();
+        for (id, mut sent) in self.uploads.split_off(0) {
+            let len = self.body.as_ref().map_or(0, |body| body.len());
+            while sent < len {
+                let capacity = self.conn.poll_capacity(id, Instant::now()) as usize;
+                if capacity == 0 {
+                    break;
+                }
+                let max_frame_size = self.conn.peer_max_frame_size() as usize;
+                let chunk = cmp::min(len - sent, cmp::min(capacity, max_frame_size));
+                let last = sent + chunk == len;
+                try!(self.conn.send_data(id, chunk as u32, last, Instant::now()).map_err(invalid));
+                let flags = if last { DataFlags::end_stream() } else { DataFlags::empty() };
+                let body = self.body.clone().unwrap_or_default();
+                try!(self.write(&Frame::data(flags, id, &body[sent..sent + chunk])));
+                sent += chunk;
+            }
+            // A stream the server reset or answered early may not take the rest.
+            if sent < len && self.open.contains(&id) {
+                uploads.push((id, sent));
+            }
+        }
+        self.uploads = uploads;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> io::Result<(FrameHeader, Vec<u8>)> {
+        let mut head = [0u8; FRAME_HEADER_BYTES];
+        try!(self.socket.read_exact(&mut head));
+        let header = try!(FrameHeader::parse(&head).map_err(invalid));
+        let mut payload = vec![0u8; header.length as usize];
+        try!(self.socket.read_exact(&mut payload));
+        Ok((header, payload))
+    }
+
+    /// Decodes a header block, which every one must go through to keep the HPACK tables in
+    /// step, and prints it.
+    fn decode(&mut self, block: &HeaderBlock) -> io::Result<()> {
+        let fields = try!(self.decoder.decode(&block.block)
+            .map_err(|e| invalid(format!("HPACK: {:?}", e))));
+        if self.verbose {
+            let fields: Vec<(&[u8], &[u8])> =
+                fields.iter().map(|&(ref n, ref v)| (&n[..], &v[..])).collect();
+            print_fields("<", block.block.len(), &fields);
+        }
+        Ok(())
+    }
+
+    /// The response on stream `id` ended, which may leave room for a pending request.
+    fn finish(&mut self, id: StreamIdentifier) -> io::Result<()> {
+        self.open.retain(|&s| s != id);
+        self.uploads.retain(|&(s, _)| s != id);
+        self.send_requests()
+    }
+
+    /// Reads frames until every request was sent and answered.
+    fn run(&mut self) -> io::Result<()> {
+        let stdout = io::stdout();
+
+        while !self.open.is_empty() || !self.pending.is_empty() {
+            let (header, buf) = try!(self.read_frame());
+            let frame = try!(Frame::parse(header, &buf).map_err(invalid));
+            if self.verbose {
+                print_frame("<", &frame);
+            }
+
+            let recv = match self.conn.recv(&frame, Instant::now()) {
+                Ok(recv) => recv,
+                Err(e) => {
+                    let goaway = Payload::GoAway { last: StreamIdentifier(0), error: e.error_code(), data: &[] };
+                    let _ = self.write(&Frame::new(Flag::empty(), StreamIdentifier(0), goaway));
+                    return Err(invalid(e));
+                },
+            };
+            match recv {
+                Recv::Settings(_) => {
+                    if self.verbose {
+                        print_settings(&buf);
+                    }
+                    let ack = self.conn.settings_ack();
+                    try!(self.write(&ack));
+                    // A larger SETTINGS_INITIAL_WINDOW_SIZE or SETTINGS_MAX_CONCURRENT_STREAMS
+                    // lets more go out.
+                    try!(self.send_requests());
+                },
+                Recv::Ping(ack) => try!(self.write(&ack)),
+                Recv::WindowUpdate(_) => try!(self.send_bodies()),
+                Recv::Headers(block) => {
+                    try!(self.decode(&block));
+                    if block.end_stream {
+                        try!(self.finish(block.id));
+                    }
+                },
+                Recv::Data { id, end_stream } => {
+                    if let Payload::Data { data } = frame.payload {
+                        try!(stdout.lock().write_all(data));
+                    }
+                    for update in self.conn.consumed(id, header.length) {
+                        try!(self.write(&update));
+                    }
+                    if end_stream {
+                        try!(self.finish(id));
+                    }
+                },
+                Recv::Reset(id, error) => {
+                    eprintln!("stream {} reset with {}", id.0, error);
+                    try!(self.finish(id));
+                },
+                Recv::Ignored(block) => {
+                    if let Some(block) = block {
+                        try!(self.decode(&block));
+                    }
+                    for update in self.conn.consumed(header.id, data_len(&frame)) {
+                        try!(self.write(&update));
+                    }
+                },
+                Recv::StreamError(error, block) => {
+                    if let Some(block) = block {
+                        try!(self.decode(&block));
+                    }
+                    for update in self.conn.consumed(header.id, data_len(&frame)) {
+                        try!(self.write(&update));
+                    }
+                    eprintln!("stream {} failed with {}", error.id.0, error.code);
+                    try!(self.write(&error.frame()));
+                    try!(self.finish(error.id));
+                },
+                Recv::Connection => {
+                    if let Payload::GoAway { last, error, data } = frame.payload {
+                        eprintln!("GOAWAY: last stream {}, {} {}",
+                                  last.0, error, String::from_utf8_lossy(data));
+                        self.pending.clear();
+                        self.open.retain(|&s| s.0 <= last.0);
+                        if error != ErrorCode::NoError {
+                            return Ok(());
+                        }
+                    }
+                },
+                Recv::Pending | Recv::SettingsAcked(_) => {},
+            }
+        }
+        self.socket.flush()
+    }
+}
+
+/// The flow controlled length of a frame: its whole length if it is DATA.
+fn data_len(frame: &Frame) -> u32 {
+    if frame.header.kind == Kind::Data { frame.header.length } else { 0 }
+}
+
+fn invalid<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+fn authority(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string(),
+    }
+}
+
+fn path(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+fn print_frame(dir: &str, frame: &Frame) {
+    let header = &frame.header;
+    let mut flags = Vec::new();
+    match header.kind {
+        Kind::Settings | Kind::Ping if header.flag.contains(Flag::ack()) => flags.push("ACK"),
+        Kind::Data | Kind::Headers if header.flag.contains(Flag::end_stream()) => flags.push("END_STREAM"),
+        _ => {},
+    }
+    if header.kind != Kind::Data && header.flag.contains(Flag::end_headers()) {
+        flags.push("END_HEADERS");
+    }
+    if header.flag.contains(Flag::padded()) {
+        flags.push("PADDED");
+    }
+    if header.flag.contains(Flag::priority()) {
+        flags.push("PRIORITY");
+    }
+
+    let detail = match frame.payload {
+        Payload::WindowUpdate(SizeIncrement(increment)) => format!(" increment={}", increment),
+        Payload::Ping(data) => format!(" opaque={:#018x}", data),
+        Payload::Reset(error) => format!(" error_code={}", error),
+        _ => String::new(),
+    };
+    eprintln!("{} {:?} stream={} length={} flags=[{}]{}",
+              dir, header.kind, header.id.0, header.length, flags.join(","), detail);
+}
+
+fn print_fields(dir: &str, block_len: usize, fields: &[(&[u8], &[u8])]) {
+    let plain: usize = fields.iter().map(|&(n, v)| n.len() + v.len()).sum();
+    eprintln!("{}   HPACK block: {} bytes for {} bytes of fields", dir, block_len, plain);
+    for &(name, value) in fields {
+        eprintln!("{}     {}: {}", dir, String::from_utf8_lossy(name), String::from_utf8_lossy(value));
+    }
+}
+
+fn print_settings(buf: &[u8]) {
+    for setting in buf.chunks(6) {
+        if setting.len() < 6 {
+            break;
+        }
+        let id = ((setting[0] as u16) << 8) | setting[1] as u16;
+        let value = ((setting[2] as u32) << 24) | ((setting[3] as u32) << 16) |
+                    ((setting[4] as u32) << 8) | setting[5] as u32;
+        let name = match id {
+            0x1 => "HEADER_TABLE_SIZE",
+            0x2 => "ENABLE_PUSH",
+            0x3 => "MAX_CONCURRENT_STREAMS",
+            0x4 => "INITIAL_WINDOW_SIZE",
+            0x5 => "MAX_FRAME_SIZE",
+            0x6 => "MAX_HEADER_LIST_SIZE",
+            0x8 => "ENABLE_CONNECT_PROTOCOL",
+            0x9 => "NO_RFC7540_PRIORITIES",
+            _ => "UNKNOWN",
+        };
+        eprintln!("<     {} ({:#x}) = {}", name, id, value);
+    }
+}
+
+/// Sends the HTTP/1.1 upgrade request for `url`, which becomes stream 1. Its body, if any, goes
+/// with it in HTTP/1.1: stream 1 is half closed once the server switches protocols.
+fn upgrade(stream: &mut TcpStream, url: &Url, body: Option<&[u8]>, verbose: bool) -> io::Result<()> {
+    let (method, length) = match body {
+        Some(body) => ("POST", format!("Content-Length: {}\r\n", body.len())),
+        None => ("GET", String::new()),
+    };
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                           Upgrade: h2c\r\nHTTP2-Settings: \r\nUser-Agent: h2cli\r\n{}\r\n",
+                          method, path(url), authority(url), length);
+    try!(stream.write_all(request.as_bytes()));
+    if let Some(body) = body {
+        try!(stream.write_all(body));
+    }
+
+    // Read the response head byte by byte so that no HTTP/2 frame is consumed.
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if try!(stream.read(&mut byte)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during upgrade"));
+        }
+        head.push(byte[0]);
+    }
+    if verbose {
+        eprint!("{}", String::from_utf8_lossy(&head));
+    }
+    if !head.starts_with(b"HTTP/1.1 101") {
+        return Err(io::Error::new(io::ErrorKind::Other, "server refused the h2c upgrade"));
+    }
+    Ok(())
+}
+
+fn run() -> io::Result<()> {
+    let matches = App::new("h2cli")
+        .about("Issues HTTP/2 requests and shows what goes over the wire")
+        .arg(Arg::with_name("verbose").short("v").long("verbose")
+             .help("Prints every frame sent and received, with decoded header blocks"))
+        .arg(Arg::with_name("upgrade").short("u").long("upgrade")
+             .help("Starts with an HTTP/1.1 Upgrade: h2c request instead of prior knowledge"))
+        .arg(Arg::with_name("parallel").short("n").long("parallel").takes_value(true)
+             .help("Sends every request this many times, in parallel on the same connection"))
+        .arg(Arg::with_name("data").short("d").long("data").takes_value(true)
+             .help("POSTs the given data, or stdin when `-`"))
+        .arg(Arg::with_name("priority").short("p").long("priority").takes_value(true)
+             .help("Sends this `priority` header (e.g. `u=5, i`) with every request"))
+        .arg(Arg::with_name("boost").long("boost")
+             .help("Once every request is sent, raises the last one to urgency 0 with PRIORITY_UPDATE"))
+        .arg(Arg::with_name("url").required(true).multiple(true)
+             .help("http:// URLs; they must all share the same authority"))
+        .get_matches();
+
+    let verbose = matches.is_present("verbose");
+    let parallel = try!(matches.value_of("parallel").unwrap_or("1").parse::<u32>().map_err(invalid));
+    let body = match matches.value_of("data") {
+        Some("-") => {
+            let mut body = Vec::new();
+            try!(io::stdin().read_to_end(&mut body));
+            Some(body)
+        },
+        Some(data) => Some(data.as_bytes().to_vec()),
+        None => None,
+    };
+
+    let mut urls = Vec::new();
+    for url in matches.values_of("url").unwrap() {
+        let url = try!(Url::parse(url).map_err(invalid));
+        if url.scheme() != "http" {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only http:// (h2c) URLs are supported"));
+        }
+        if !urls.is_empty() && authority(&url) != authority(&urls[0]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "all URLs must share the same authority"));
+        }
+        urls.push(url);
+    }
+
+    let addr = (urls[0].host_str().unwrap_or(""), urls[0].port().unwrap_or(80));
+    let mut socket = try!(TcpStream::connect(addr));
+    let upgraded = matches.is_present("upgrade");
+    if upgraded {
+        try!(upgrade(&mut socket, &urls[0], body.as_ref().map(|b| &b[..]), verbose));
+    }
+
+    let mut client = Client {
+        socket: socket,
+        conn: Connection::new(false, ConnectionConfig::default()),
+        verbose: verbose,
+        encoder: Encoder::new(),
+        decoder: Decoder::new(),
+        buf: Vec::new(),
+        priority: matches.value_of("priority").map(|p| p.to_string()),
+        body: body.map(Rc::new),
+        pending: VecDeque::new(),
+        open: Vec::new(),
+        uploads: Vec::new(),
+    };
+    let settings = Settings { enable_push: Some(false), ..Settings::default() };
+    let preface = client.conn.preface(&settings, Instant::now());
+    try!(client.socket.write_all(&preface));
+
+    if upgraded {
+        // The upgrade request, body and all, is stream 1.
+        let id = try!(client.conn.open_stream().map_err(invalid));
+        try!(client.conn.send_headers(id, true, Instant::now()).map_err(invalid));
+        client.open.push(id);
+    }
+    for (i, url) in urls.iter().enumerate() {
+        for n in 0..parallel {
+            // The upgrade request already stands in for the first request.
+            if upgraded && i == 0 && n == 0 {
+                continue;
+            }
+            client.pending.push_back(url.clone());
+        }
+    }
+    try!(client.send_requests());
+
+    if matches.is_present("boost") {
+        if let Some(&last) = client.open.last() {
+            try!(client.send_priority_update(PriorityUpdate::new(last, 0, false)));
+        }
+    }
+
+    client.run()
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("h2cli: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,718 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! A synchronous HTTP/2 client for CLI tools and build scripts that don't want to write async
+//! code. Requests are built with `Client::get`/`post`/`request` and the response body is read
+//! through `std::io::Read` as DATA frames arrive.
+//!
+//! ```rust,no_run
+//! use std::io::Read;
+//! use tokio_http2::blocking::Client;
+//!
+//! let mut client = Client::new();
+//! let mut res = client.get("http://localhost:8080/").header("accept", "text/plain").send().unwrap();
+//! let mut body = String::new();
+//! res.read_to_string(&mut body).unwrap();
+//! println!("{} {}", res.status(), body);
+//! ```
+//!
+//! The requests go out on a `ClientConnection` (see `exchange`), one at a time per connection.
+//! The connection is kept and reused for the next request to the same origin. By default it is
+//! an HTTP/2 one: until the async HTTP/2 client is public it talks to the socket directly
+//! instead of driving one on a private event loop, so it only supports cleartext HTTP/2 with
+//! prior knowledge (`http://` URLs). `Client::with_connector` opens the connections of another
+//! backend instead.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::time::Duration;
+
+use url::Url;
+
+use exchange::{BodyStream, ClientConnection, OutgoingRequest, ResponseHead, ResponseStream};
+use hpack::{Decoder, Encoder};
+use http2::{ErrorCode, SizeIncrement, StreamIdentifier, FRAME_HEADER_BYTES, CANCEL, NO_ERROR};
+use http2::flag::Flag;
+use http2::frame::{Frame, FrameHeader};
+use http2::kind::Kind;
+use http2::payload::Payload;
+use http2::preface::PREFACE;
+use http2::priority::PriorityUpdate;
+use http2::settings::Settings;
+use http2::stream::Stream;
+use Method;
+
+/// Flow control window and maximum frame size until the peer's SETTINGS say otherwise.
+const DEFAULT_WINDOW: i64 = 65535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+fn invalid<E: ::std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// What a frame read from the connection meant for the streams.
+enum Event {
+    Headers(u32, Vec<(Vec<u8>, Vec<u8>)>, bool),
+    Data(u32, Vec<u8>, bool),
+    Reset(u32, ErrorCode),
+    /// Connection-level frame, already dealt with.
+    Control,
+}
+
+impl Event {
+    fn stream(&self) -> Option<u32> {
+        match *self {
+            Event::Headers(id, ..) | Event::Data(id, ..) | Event::Reset(id, _) => Some(id),
+            Event::Control => None,
+        }
+    }
+}
+
+struct Connection {
+    socket: TcpStream,
+    encoder: Encoder<'static>,
+    decoder: Decoder<'static>,
+    buf: Vec<u8>,
+    next_id: u32,
+    max_frame_size: usize,
+    initial_window: i64,
+    conn_window: i64,
+    stream_window: i64,
+    /// Events of the current stream that arrived while the request body was being sent.
+    early: VecDeque<Event>,
+    /// Set by GOAWAY or a failed read or write: no new request may be started.
+    closed: bool,
+}
+
+impl Connection {
+    fn open(authority: &str, timeout: Option<Duration>) -> io::Result<Connection> {
+        let socket = try!(TcpStream::connect(authority));
+        try!(socket.set_read_timeout(timeout));
+        try!(socket.set_write_timeout(timeout));
+        let _ = socket.set_nodelay(true);
+        let mut conn = Connection {
+            socket: socket,
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+            buf: Vec::new(),
+            next_id: 1,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            conn_window: DEFAULT_WINDOW,
+            stream_window: DEFAULT_WINDOW,
+            early: VecDeque::new(),
+            closed: false,
+        };
+        try!(conn.socket.write_all(PREFACE));
+        // SETTINGS_ENABLE_PUSH = 0, written by hand: one setting, big endian.
+        try!(conn.socket.write_all(&[0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]));
+        Ok(conn)
+    }
+
+    fn send(&mut self, kind: Kind, flag: Flag, id: u32, payload: Payload) -> io::Result<()> {
+        let frame = Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: kind,
+                flag: flag,
+                id: StreamIdentifier(id),
+            },
+            payload: payload,
+        };
+        self.buf.resize(frame.encoded_len(), 0);
+        let len = frame.encode(&mut self.buf);
+        let res = self.socket.write_all(&self.buf[..len]);
+        if res.is_err() {
+            self.closed = true;
+        }
+        res
+    }
+
+    fn recv(&mut self) -> io::Result<Event> {
+        let res = self.recv_frame();
+        if res.is_err() {
+            self.closed = true;
+        }
+        res
+    }
+
+    fn read_frame(&mut self) -> io::Result<(FrameHeader, Vec<u8>)> {
+        let mut head = [0u8; FRAME_HEADER_BYTES];
+        try!(self.socket.read_exact(&mut head));
+        let header = try!(FrameHeader::parse(&head).map_err(invalid));
+        let mut payload = vec![0u8; header.length as usize];
+        try!(self.socket.read_exact(&mut payload));
+        Ok((header, payload))
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Event> {
+        let (header, buf) = try!(self.read_frame());
+        let frame = try!(Frame::parse(header, &buf).map_err(invalid));
+        let id = header.id.0;
+        match frame.payload {
+            Payload::Settings(settings) if !header.flag.contains(Flag::ack()) => {
+                // Out of range values (a SETTINGS_MAX_FRAME_SIZE of 0 would have us send empty
+                // frames forever) close the connection.
+                let settings = match Settings::from_payload(settings) {
+                    Ok(settings) => settings,
+                    Err(code) => {
+                        let _ = self.send(Kind::GoAway, Flag::empty(), 0, Payload::GoAway { last: StreamIdentifier(0), error: code, data: &[] });
+                        return Err(invalid(code));
+                    },
+                };
+                // SETTINGS_INITIAL_WINDOW_SIZE applies to open streams as a delta.
+                if let Some(value) = settings.initial_window_size {
+                    self.stream_window += value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                }
+                if let Some(value) = settings.max_frame_size {
+                    self.max_frame_size = value as usize;
+                }
+                try!(self.send(Kind::Settings, Flag::ack(), 0, Payload::Settings(&[])));
+                Ok(Event::Control)
+            },
+            Payload::Ping(data) if !header.flag.contains(Flag::ack()) => {
+                try!(self.send(Kind::Ping, Flag::ack(), 0, Payload::Ping(data)));
+                Ok(Event::Control)
+            },
+            Payload::WindowUpdate(SizeIncrement(increment)) => {
+                if id == 0 {
+                    self.conn_window += increment as i64;
+                } else if id + 2 == self.next_id {
+                    self.stream_window += increment as i64;
+                }
+                Ok(Event::Control)
+            },
+            Payload::Headers { block, .. } => {
+                let mut block = block.to_vec();
+                let mut flag = header.flag;
+                while !flag.contains(Flag::end_headers()) {
+                    let (next, buf) = try!(self.read_frame());
+                    if next.kind != Kind::Continuation || next.id != header.id {
+                        return Err(invalid("header block interrupted"));
+                    }
+                    block.extend_from_slice(&buf);
+                    flag = next.flag;
+                }
+                let fields = try!(self.decoder.decode(&block).map_err(invalid));
+                Ok(Event::Headers(id, fields, header.flag.contains(Flag::end_stream())))
+            },
+            Payload::Data { data } => {
+                // Window is handed back as soon as the data is buffered by the reader.
+                if header.length > 0 {
+                    let increment = Payload::WindowUpdate(SizeIncrement(header.length));
+                    try!(self.send(Kind::WindowUpdate, Flag::empty(), 0, increment));
+                    if !header.flag.contains(Flag::end_stream()) {
+                        try!(self.send(Kind::WindowUpdate, Flag::empty(), id, increment));
+                    }
+                }
+                Ok(Event::Data(id, data.to_vec(), header.flag.contains(Flag::end_stream())))
+            },
+            Payload::Reset(code) => Ok(Event::Reset(id, code)),
+            Payload::GoAway { last, error, .. } => {
+                self.closed = true;
+                // The current stream is only lost if the peer never processed it.
+                let current = self.next_id.wrapping_sub(2);
+                if self.next_id > 1 && (last.0 < current || error != NO_ERROR) {
+                    return Ok(Event::Reset(current, error));
+                }
+                Ok(Event::Control)
+            },
+            _ => Ok(Event::Control),
+        }
+    }
+
+    /// The next event of stream `id`, from the early events first.
+    fn next_event(&mut self, id: u32) -> io::Result<Event> {
+        if let Some(event) = self.early.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let event = try!(self.recv());
+            if event.stream() == Some(id) {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn start(&mut self, req: &OutgoingRequest) -> io::Result<(u32, Stream)> {
+        let id = self.next_id;
+        self.next_id += 2;
+        self.stream_window = self.initial_window;
+        self.early.clear();
+
+        let mut fields: Vec<(&[u8], &[u8])> = vec![
+            (b":method", req.method.as_ref().as_bytes()),
+            (b":scheme", req.scheme.as_bytes()),
+            (b":authority", req.authority.as_bytes()),
+            (b":path", req.target.as_bytes()),
+        ];
+        fields.extend(req.headers.iter().map(|&(ref n, ref v)| (n.as_bytes(), v.as_bytes())));
+        let block = try!(self.encoder.encode(fields.iter().cloned()));
+
+        let body = req.body;
+        let mut stream = Stream::new(StreamIdentifier(id));
+        let end_stream = body.is_empty();
+        try!(stream.send_headers(end_stream).map_err(invalid));
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut first = true;
+        while let Some(chunk) = chunks.next() {
+            let mut flag = if chunks.peek().is_none() { Flag::end_headers() } else { Flag::empty() };
+            if first && end_stream {
+                flag = flag | Flag::end_stream();
+            }
+            let (kind, payload) = if first {
+                (Kind::Headers, Payload::Headers { priority: None, block: chunk })
+            } else {
+                (Kind::Continuation, Payload::Continuation(chunk))
+            };
+            try!(self.send(kind, flag, id, payload));
+            first = false;
+        }
+
+        let mut sent = 0;
+        while sent < body.len() {
+            let window = ::std::cmp::min(self.conn_window, self.stream_window);
+            if window <= 0 {
+                let event = try!(self.recv());
+                if event.stream() == Some(id) {
+                    // The peer may answer (e.g. with 413) before it read the whole body.
+                    let reset = match event { Event::Reset(..) => true, _ => false };
+                    self.early.push_back(event);
+                    if reset {
+                        return Ok((id, stream));
+                    }
+                }
+                continue;
+            }
+            let len = ::std::cmp::min(::std::cmp::min(body.len() - sent, self.max_frame_size), window as usize);
+            let last = sent + len == body.len();
+            try!(stream.send_data(last).map_err(invalid));
+            let flag = if last { Flag::end_stream() } else { Flag::empty() };
+            try!(self.send(Kind::Data, flag, id, Payload::Data { data: &body[sent..sent + len] }));
+            self.conn_window -= len as i64;
+            self.stream_window -= len as i64;
+            sent += len;
+        }
+        Ok((id, stream))
+    }
+}
+
+impl ClientConnection for Connection {
+    fn send<'a>(&'a mut self, req: &OutgoingRequest) -> io::Result<Box<ResponseStream + 'a>> {
+        let head = *req.method == Method::Head;
+        let (id, mut stream) = try!(self.start(req));
+
+        loop {
+            match try!(self.next_event(id)) {
+                Event::Headers(_, fields, end_stream) => {
+                    try!(stream.recv_headers(end_stream).map_err(invalid));
+                    let status = fields.iter()
+                        .find(|&&(ref n, _)| n == b":status")
+                        .and_then(|&(_, ref v)| str::from_utf8(v).ok())
+                        .and_then(|v| v.parse::<u16>().ok());
+                    let status = try!(status.ok_or_else(|| invalid("response without :status")));
+                    if status >= 100 && status < 200 && !end_stream {
+                        continue;
+                    }
+                    let headers = fields.into_iter()
+                        .filter(|&(ref n, _)| !n.starts_with(b":"))
+                        .map(|(n, v)| (String::from_utf8_lossy(&n).into_owned(),
+                                       String::from_utf8_lossy(&v).into_owned()))
+                        .collect();
+                    return Ok(Box::new(Http2Response {
+                        status: status,
+                        headers: headers,
+                        conn: self,
+                        id: id,
+                        stream: stream,
+                        done: end_stream || head,
+                        chunk: Vec::new(),
+                        pos: 0,
+                    }));
+                },
+                Event::Reset(_, code) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                              format!("stream reset with {}", code)));
+                },
+                Event::Data(..) => return Err(invalid("DATA before response headers")),
+                Event::Control => {},
+            }
+        }
+    }
+
+    fn is_reusable(&self) -> bool {
+        !self.closed && self.next_id < 1 << 30
+    }
+}
+
+/// Opens the connections of a `Client` to the origin of a URL, with a timeout for every socket
+/// read and write.
+pub type Connector = Box<Fn(&Url, Option<Duration>) -> io::Result<Box<ClientConnection>>>;
+
+/// The default connector: cleartext HTTP/2 with prior knowledge.
+fn connect_h2(url: &Url, timeout: Option<Duration>) -> io::Result<Box<ClientConnection>> {
+    if url.scheme() != "http" {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "only http:// URLs (HTTP/2 with prior knowledge) are supported"));
+    }
+    let authority = try!(authority(url));
+    Ok(Box::new(try!(Connection::open(&authority, timeout))))
+}
+
+/// `host:port` of `url`, with the scheme's default port if it has none.
+fn authority(url: &Url) -> io::Result<String> {
+    let host = try!(url.host_str().ok_or_else(|| invalid("URL without host")));
+    let port = try!(url.port_or_known_default().ok_or_else(|| invalid("URL without port")));
+    Ok(format!("{}:{}", host, port))
+}
+
+/// A synchronous HTTP client. See the module documentation.
+pub struct Client {
+    /// The origin, `scheme://host:port`, and the connection to it.
+    conn: Option<(String, Box<ClientConnection>)>,
+    connector: Connector,
+    timeout: Option<Duration>,
+    user_agent: String,
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            conn: None,
+            connector: Box::new(connect_h2),
+            timeout: Some(Duration::from_secs(30)),
+            user_agent: concat!("tokio-http2/", env!("CARGO_PKG_VERSION")).to_string(),
+        }
+    }
+
+    /// Opens the connections with `connector` instead of as cleartext HTTP/2, e.g. for another
+    /// backend.
+    pub fn with_connector<F>(mut self, connector: F) -> Client
+            where F: Fn(&Url, Option<Duration>) -> io::Result<Box<ClientConnection>> + 'static {
+        self.connector = Box::new(connector);
+        self.conn = None;
+        self
+    }
+
+    /// Timeout of every socket read and write; `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_user_agent(&mut self, user_agent: &str) {
+        self.user_agent = user_agent.to_string();
+    }
+
+    pub fn get(&mut self, url: &str) -> RequestBuilder {
+        self.request(Method::Get, url)
+    }
+
+    pub fn post(&mut self, url: &str) -> RequestBuilder {
+        self.request(Method::Post, url)
+    }
+
+    pub fn request(&mut self, method: Method, url: &str) -> RequestBuilder {
+        let user_agent = self.user_agent.clone();
+        RequestBuilder {
+            client: self,
+            method: method,
+            url: Url::parse(url).map_err(invalid),
+            headers: vec![("user-agent".to_string(), user_agent)],
+            body: Vec::new(),
+        }
+    }
+
+    fn connection(&mut self, url: &Url, origin: &str) -> io::Result<&mut ClientConnection> {
+        let reuse = match self.conn {
+            Some((ref open, ref conn)) => open == origin && conn.is_reusable(),
+            None => false,
+        };
+        if !reuse {
+            self.conn = Some((origin.to_string(), try!((self.connector)(url, self.timeout))));
+        }
+        Ok(&mut *self.conn.as_mut().unwrap().1)
+    }
+}
+
+pub struct RequestBuilder<'a> {
+    client: &'a mut Client,
+    method: Method,
+    url: io::Result<Url>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Adds a header; names are lowercased as HTTP/2 requires. Replaces the default `user-agent`.
+    pub fn header(mut self, name: &str, value: &str) -> RequestBuilder<'a> {
+        let name = name.to_ascii_lowercase();
+        if name == "user-agent" {
+            self.headers.retain(|&(ref n, _)| n != "user-agent");
+        }
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> RequestBuilder<'a> {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends the request and waits for the response headers. Informational (1xx) responses are
+    /// skipped.
+    pub fn send(self) -> io::Result<Response<'a>> {
+        let url = try!(self.url);
+        let authority = try!(authority(&url));
+        let mut target = url.path().to_string();
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let origin = format!("{}://{}", url.scheme(), authority);
+        let conn = try!(self.client.connection(&url, &origin));
+        let res = try!(conn.send(&OutgoingRequest {
+            method: &self.method,
+            scheme: url.scheme(),
+            authority: &authority,
+            target: &target,
+            headers: &self.headers,
+            body: &self.body,
+        }));
+        Ok(Response { inner: res })
+    }
+}
+
+/// A response whose body is read from the connection as it arrives. Dropping it before the end
+/// of the body cancels the request.
+pub struct Response<'a> {
+    inner: Box<ResponseStream + 'a>,
+}
+
+impl<'a> Response<'a> {
+    pub fn status(&self) -> u16 {
+        self.inner.status()
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        self.inner.headers()
+    }
+
+    /// The first value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.inner.header(name)
+    }
+
+    /// Changes the priority of the request while its response is being read, with a
+    /// PRIORITY_UPDATE frame (RFC 9218): urgency 0 is most urgent, 7 least.
+    pub fn set_priority(&mut self, urgency: u8, incremental: bool) -> io::Result<()> {
+        self.inner.set_priority(urgency, incremental)
+    }
+}
+
+impl<'a> Read for Response<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// The response to a request of an HTTP/2 `Connection`, read from the stream as DATA arrives.
+struct Http2Response<'a> {
+    status: u16,
+    headers: Vec<(String, String)>,
+    conn: &'a mut Connection,
+    id: u32,
+    stream: Stream,
+    done: bool,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> ResponseHead for Http2Response<'a> {
+    fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| &v[..])
+    }
+}
+
+impl<'a> BodyStream for Http2Response<'a> {
+    fn is_end(&self) -> bool {
+        self.done && self.pos == self.chunk.len()
+    }
+}
+
+impl<'a> ResponseStream for Http2Response<'a> {
+    fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    fn set_priority(&mut self, urgency: u8, incremental: bool) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        let value = PriorityUpdate::new(StreamIdentifier(self.id), urgency, incremental).priority.to_string();
+        self.conn.send(Kind::PriorityUpdate, Flag::empty(), 0, Payload::PriorityUpdate {
+            prioritized: StreamIdentifier(self.id),
+            value: value.as_bytes(),
+        })
+    }
+}
+
+impl<'a> Read for Http2Response<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match try!(self.conn.next_event(self.id)) {
+                Event::Data(_, data, end_stream) => {
+                    try!(self.stream.recv_data(end_stream).map_err(invalid));
+                    self.chunk = data;
+                    self.pos = 0;
+                    self.done = end_stream;
+                },
+                // Trailers.
+                Event::Headers(_, _, end_stream) => {
+                    try!(self.stream.recv_headers(end_stream).map_err(invalid));
+                    self.done = end_stream;
+                },
+                Event::Reset(_, code) => {
+                    self.stream.reset();
+                    self.done = true;
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                              format!("stream reset with {}", code)));
+                },
+                Event::Control => {},
+            }
+        }
+        let len = ::std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'a> Drop for Http2Response<'a> {
+    fn drop(&mut self) {
+        if !self.done && !self.conn.closed {
+            let _ = self.conn.send(Kind::Reset, Flag::empty(), self.id, Payload::Reset(CANCEL));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::{self, Cursor, Read};
+    use std::rc::Rc;
+
+    use exchange::{BodyStream, ClientConnection, OutgoingRequest, ResponseHead, ResponseStream};
+
+    use super::Client;
+
+    /// A backend that answers every request with what it was sent, two per connection.
+    struct Echo {
+        sent: usize,
+    }
+
+    struct EchoResponse {
+        headers: Vec<(String, String)>,
+        body: Cursor<Vec<u8>>,
+    }
+
+    impl ResponseHead for EchoResponse {
+        fn status(&self) -> u16 {
+            200
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| &v[..])
+        }
+    }
+
+    impl Read for EchoResponse {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.body.read(buf)
+        }
+    }
+
+    impl BodyStream for EchoResponse {
+        fn is_end(&self) -> bool {
+            self.body.position() as usize == self.body.get_ref().len()
+        }
+    }
+
+    impl ResponseStream for EchoResponse {
+        fn headers(&self) -> &[(String, String)] {
+            &self.headers
+        }
+    }
+
+    impl ClientConnection for Echo {
+        fn send<'a>(&'a mut self, req: &OutgoingRequest) -> io::Result<Box<ResponseStream + 'a>> {
+            self.sent += 1;
+            let body = format!("{} {}://{}{}", req.method, req.scheme, req.authority, req.target);
+            Ok(Box::new(EchoResponse {
+                headers: vec![("x-sent".to_string(), self.sent.to_string())],
+                body: Cursor::new(body.into_bytes()),
+            }))
+        }
+
+        fn is_reusable(&self) -> bool {
+            self.sent < 2
+        }
+    }
+
+    #[test]
+    fn test_connector() {
+        let opened = Rc::new(Cell::new(0));
+        let mut client = {
+            let opened = opened.clone();
+            Client::new().with_connector(move |_, _| {
+                opened.set(opened.get() + 1);
+                Ok(Box::new(Echo { sent: 0 }))
+            })
+        };
+        let mut send = |url: &str| {
+            let mut res = client.post(url).body("x").send().unwrap();
+            let mut body = String::new();
+            res.read_to_string(&mut body).unwrap();
+            (body, res.header("x-sent").unwrap().to_string())
+        };
+
+        assert_eq!(send("https://example.com/a?b=1"),
+                   ("POST https://example.com:443/a?b=1".to_string(), "1".to_string()));
+        assert_eq!(send("https://example.com/"), ("POST https://example.com:443/".to_string(), "2".to_string()));
+        assert_eq!(opened.get(), 1);
+        // A connection that can't take more, or another origin, makes for a new one.
+        assert_eq!(send("https://example.com/").1, "1");
+        assert_eq!(send("http://example.com:8080/").0, "POST http://example.com:8080/");
+        assert_eq!(opened.get(), 3);
+    }
+}
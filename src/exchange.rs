@@ -0,0 +1,332 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocol-neutral exchanges. Code written against `RequestHead` and `ResponseHead` instead of
+//! the HTTP/1 `Request` and `Response` keeps working when the same exchange arrives over another
+//! backend: `HeaderFields`, the fields of a decoded HTTP/2 header block, implements them too, and
+//! an HTTP/3 backend can hand out the same.
+//!
+//! On the server, a `Handler` answers requests whatever their version: `Server` serves it as the
+//! `Service` of the HTTP/1 server and, with `Server::serve_fields`, to the requests of an HTTP/2
+//! connection. On the client, a `ClientConnection` sends requests and hands back their responses
+//! as `ResponseStream`s: `blocking::Client` drives the HTTP/2 one, and takes other backends
+//! through `Client::with_connector`.
+
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+
+use futures::future::{self, FutureResult};
+use tokio_service::Service;
+
+use http::{Request, Response};
+use sf::is_tchar;
+use Method;
+use HttpVersion;
+use StatusCode;
+
+pub trait RequestHead {
+    /// `None` when the request came without a method, or with one that isn't a token.
+    fn method(&self) -> Option<Method>;
+
+    /// Path and query: the request target of HTTP/1, `:path` of HTTP/2 and HTTP/3.
+    fn target(&self) -> &str;
+
+    /// `host` in HTTP/1, `:authority` in HTTP/2 and HTTP/3.
+    fn authority(&self) -> Option<&str>;
+
+    /// The first value of header `name`, compared case-insensitively.
+    fn header(&self, name: &str) -> Option<&str>;
+
+    fn http_version(&self) -> HttpVersion;
+
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+pub trait ResponseHead {
+    fn status(&self) -> u16;
+
+    /// The first value of header `name`, compared case-insensitively.
+    fn header(&self, name: &str) -> Option<&str>;
+}
+
+impl RequestHead for Request {
+    fn method(&self) -> Option<Method> {
+        Some(Request::method(self))
+    }
+
+    fn target(&self) -> &str {
+        self.uri()
+    }
+
+    fn authority(&self) -> Option<&str> {
+        Request::header(self, "host")
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        Request::header(self, name)
+    }
+
+    fn http_version(&self) -> HttpVersion {
+        if self.version() == 0 { HttpVersion::Http10 } else { HttpVersion::Http11 }
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Request::remote_addr(self)
+    }
+}
+
+/// A request or response head from the fields of a header block, as `hpack::Decoder` decodes
+/// them, pseudo-header fields included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderFields {
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+    version: HttpVersion,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl HeaderFields {
+    pub fn new(fields: Vec<(Vec<u8>, Vec<u8>)>, version: HttpVersion, remote_addr: Option<SocketAddr>) -> HeaderFields {
+        HeaderFields {
+            fields: fields,
+            version: version,
+            remote_addr: remote_addr,
+        }
+    }
+
+    pub fn fields(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.fields
+    }
+
+    /// The first value of field `name`, compared case-insensitively, if it is UTF-8.
+    fn value(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+            .and_then(|&(_, ref v)| str::from_utf8(v).ok())
+    }
+}
+
+impl RequestHead for HeaderFields {
+    fn method(&self) -> Option<Method> {
+        self.value(":method")
+            .and_then(|method| if method.bytes().all(is_tchar) { method.parse().ok() } else { None })
+    }
+
+    fn target(&self) -> &str {
+        self.value(":path").unwrap_or("")
+    }
+
+    fn authority(&self) -> Option<&str> {
+        self.value(":authority").or_else(|| self.value("host"))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.value(name)
+    }
+
+    fn http_version(&self) -> HttpVersion {
+        self.version
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+impl ResponseHead for HeaderFields {
+    fn status(&self) -> u16 {
+        self.value(":status").and_then(|status| status.parse().ok()).unwrap_or(0)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.value(name)
+    }
+}
+
+impl ResponseHead for Response {
+    fn status(&self) -> u16 {
+        self.code
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        Response::header(self, name)
+    }
+}
+
+/// A body read as it arrives, whatever framing carries it: HTTP/1 chunks, HTTP/2 or HTTP/3 DATA
+/// frames. `read` returns 0 at its end.
+pub trait BodyStream: Read {
+    /// The body was read to its end, trailers included.
+    fn is_end(&self) -> bool;
+}
+
+/// The response to a request sent on a `ClientConnection`: its head, then its body. Dropping it
+/// before the end of the body cancels the request.
+pub trait ResponseStream: ResponseHead + BodyStream {
+    /// The header fields, without the pseudo-header fields.
+    fn headers(&self) -> &[(String, String)];
+
+    /// Changes the priority of the request while its response is being read (RFC 9218):
+    /// urgency 0 is most urgent, 7 least. Backends without priorities ignore it.
+    fn set_priority(&mut self, _urgency: u8, _incremental: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A request as a client sends it, whatever the version it goes out with.
+#[derive(Clone, Copy, Debug)]
+pub struct OutgoingRequest<'a> {
+    pub method: &'a Method,
+    pub scheme: &'a str,
+    pub authority: &'a str,
+    /// Path and query.
+    pub target: &'a str,
+    /// Lowercase names.
+    pub headers: &'a [(String, String)],
+    pub body: &'a [u8],
+}
+
+/// A client connection of one backend (HTTP/2, later HTTP/3) to one origin.
+pub trait ClientConnection {
+    /// Sends `req` and waits for its response head; informational (1xx) responses are skipped.
+    /// Nothing else can be sent until the response is dropped.
+    fn send<'a>(&'a mut self, req: &OutgoingRequest) -> io::Result<Box<ResponseStream + 'a>>;
+
+    /// Whether another request can still be sent on the connection.
+    fn is_reusable(&self) -> bool;
+}
+
+/// Answers requests, whatever the version they came with.
+pub trait Handler: Send + Sync {
+    fn handle(&self, head: &RequestHead, body: &[u8]) -> Response;
+}
+
+/// Serves a `Handler` to every backend. Cheap to clone; all clones share the handler.
+pub struct Server<H> {
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Server<H> {
+    pub fn new(handler: H) -> Server<H> {
+        Server { handler: Arc::new(handler) }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Answers the request of `head`, the decoded fields of an HTTP/2 (or HTTP/3) request, and
+    /// its body. Requests without a valid `:method`, or without `:path` other than CONNECT, are
+    /// malformed (RFC 9113 section 8.3.1) and answered with 400; the handler isn't called.
+    pub fn serve_fields(&self, head: &HeaderFields, body: &[u8]) -> Response {
+        let valid = match head.method() {
+            Some(Method::Connect) => true,
+            Some(_) => !head.target().is_empty(),
+            None => false,
+        };
+        if !valid {
+            return Response::new().with_status(StatusCode::BadRequest);
+        }
+        self.handler.handle(head, body)
+    }
+}
+
+impl<H> Clone for Server<H> {
+    fn clone(&self) -> Server<H> {
+        Server { handler: self.handler.clone() }
+    }
+}
+
+impl<H: Handler> Service for Server<H> {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = FutureResult<Response, io::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        future::ok(self.handler.handle(&req, req.payload().unwrap_or(&[])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hpack::{Decoder, Encoder};
+    use http::Response;
+    use Method;
+    use HttpVersion;
+
+    use super::{Handler, HeaderFields, RequestHead, ResponseHead, Server};
+
+    fn decoded(fields: &[(&str, &str)]) -> HeaderFields {
+        let fields = fields.iter().map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+        HeaderFields::new(fields, HttpVersion::H2, None)
+    }
+
+    #[test]
+    fn test_request_head() {
+        let block = Encoder::new().encode(vec![(&b":method"[..], &b"PUT"[..]), (b":scheme", b"https"),
+                                               (b":authority", b"example.com"), (b":path", b"/a?b=1"),
+                                               (b"accept", b"*/*")]).unwrap();
+        let head = HeaderFields::new(Decoder::new().decode(&block).unwrap(), HttpVersion::H2, None);
+        assert_eq!(head.method(), Some(Method::Put));
+        assert_eq!((head.target(), head.authority()), ("/a?b=1", Some("example.com")));
+        assert_eq!(RequestHead::header(&head, "Accept"), Some("*/*"));
+        assert_eq!(head.http_version(), HttpVersion::H2);
+
+        // HTTP/2 requests converted from HTTP/1 may carry `host` instead.
+        let fields = vec![(b":method".to_vec(), b"GET".to_vec()), (b":path".to_vec(), b"/".to_vec()),
+                          (b"host".to_vec(), b"example.org".to_vec())];
+        let head = HeaderFields::new(fields, HttpVersion::H2c, None);
+        assert_eq!((head.method(), head.authority()), (Some(Method::Get), Some("example.org")));
+
+        // A missing or malformed method isn't taken for GET.
+        let head = HeaderFields::new(vec![(b":path".to_vec(), b"/".to_vec())], HttpVersion::H2c, None);
+        assert_eq!(head.method(), None);
+        assert_eq!(decoded(&[(":method", "")]).method(), None);
+        assert_eq!(decoded(&[(":method", "GET /")]).method(), None);
+        assert_eq!(decoded(&[(":method", "PURGE")]).method(), Some(Method::Extension("PURGE".to_string())));
+    }
+
+    #[test]
+    fn test_server() {
+        struct Echo;
+        impl Handler for Echo {
+            fn handle(&self, head: &RequestHead, body: &[u8]) -> Response {
+                let method = head.method().map(|method| method.to_string()).unwrap_or_default();
+                Response::new().with_header("x-target", head.target()).with_header("x-method", &method)
+                    .with_body(body.to_vec())
+            }
+        }
+
+        let server = Server::new(Echo);
+        let res = server.serve_fields(&decoded(&[(":method", "POST"), (":path", "/echo")]), b"hi");
+        assert_eq!((res.code, res.header("x-method"), res.header("x-target")), (200, Some("POST"), Some("/echo")));
+        assert_eq!(res.body, b"hi");
+        assert_eq!(server.serve_fields(&decoded(&[(":method", "CONNECT"), (":authority", "a:443")]), b"").code, 200);
+
+        assert_eq!(server.serve_fields(&decoded(&[(":path", "/echo")]), b"").code, 400);
+        assert_eq!(server.serve_fields(&decoded(&[(":method", "G(E)T"), (":path", "/")]), b"").code, 400);
+        assert_eq!(server.serve_fields(&decoded(&[(":method", "GET")]), b"").code, 400);
+    }
+
+    #[test]
+    fn test_response_head() {
+        let fields = vec![(b":status".to_vec(), b"404".to_vec()), (b"content-type".to_vec(), b"text/plain".to_vec())];
+        let head = HeaderFields::new(fields, HttpVersion::H2c, None);
+        assert_eq!(head.status(), 404);
+        assert_eq!(ResponseHead::header(&head, "Content-Type"), Some("text/plain"));
+        assert_eq!(HeaderFields::new(Vec::new(), HttpVersion::H2c, None).status(), 0);
+    }
+}
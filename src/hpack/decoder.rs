@@ -10,7 +10,7 @@
 //! A simple example of using the decoder that demonstrates its API:
 //!
 //! ```rust
-//! use hpack::Decoder;
+//! use tokio_http2::hpack::Decoder;
 //! let mut decoder = Decoder::new();
 //!
 //! let header_list = decoder.decode(&[0x82, 0x84]).unwrap();
@@ -25,7 +25,7 @@
 //! borrowed representation of each header, rather than an owned representation.
 //!
 //! ```rust
-//! use hpack::Decoder;
+//! use tokio_http2::hpack::Decoder;
 //! let mut decoder = Decoder::new();
 //!
 //! let mut count = 0;
@@ -47,12 +47,21 @@
 
 use std::num::Wrapping;
 use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::mem;
+
+use bytes::Bytes;
 
 use super::huffman::HuffmanDecoder;
 use super::huffman::HuffmanDecoderError;
 
 use super::STATIC_TABLE;
-use super::{StaticTable, HeaderTable};
+use super::{entry_size, is_connection_specific, EvictionReason, static_name_range, StaticTable, HeaderTable};
+
+/// The most octets a 64-bit integer takes after the prefix octet: ten continuation octets
+/// carry 70 bits.
+const U64_OCTET_LIMIT: usize = 11;
 
 /// Decodes an integer encoded with a given prefix size (in bits).
 /// Assumes that the buffer `buf` contains the integer to be decoded,
@@ -61,8 +70,26 @@ use super::{StaticTable, HeaderTable};
 ///
 /// Returns a tuple representing the decoded integer and the number
 /// of bytes from the buffer that were used.
-fn decode_integer(buf: &[u8], prefix_size: u8)
+pub fn decode_integer(buf: &[u8], prefix_size: u8)
         -> Result<(usize, usize), DecoderError> {
+    // The octet limit is chosen such that the maximum allowed *value* can
+    // never overflow an unsigned 32-bit integer. The maximum value of any
+    // integer that can be encoded with 5 octets is ~2^28
+    let (value, consumed) = try!(decode_limited(buf, prefix_size, 5));
+    Ok((value as usize, consumed))
+}
+
+/// Decodes an integer like `decode_integer`, but a 64-bit one, e.g. a QUIC stream ID.
+///
+/// An encoding longer than a 64-bit value needs fails with `TooManyOctets` and one whose
+/// value doesn't fit with `ValueTooLarge`, rather than wrapping around.
+pub fn decode_integer_u64(buf: &[u8], prefix_size: u8)
+        -> Result<(u64, usize), DecoderError> {
+    decode_limited(buf, prefix_size, U64_OCTET_LIMIT)
+}
+
+fn decode_limited(buf: &[u8], prefix_size: u8, octet_limit: usize)
+        -> Result<(u64, usize), DecoderError> {
     if prefix_size < 1 || prefix_size > 8 {
         return Err(
             DecoderError::IntegerDecodingError(
@@ -80,8 +107,8 @@ fn decode_integer(buf: &[u8], prefix_size: u8)
     } else {
         Wrapping(1u8 << prefix_size) - Wrapping(1)
     };
-    let mut value = (buf[0] & mask) as usize;
-    if value < (mask as usize) {
+    let mut value = (buf[0] & mask) as u64;
+    if value < (mask as u64) {
         // Value fits in the prefix bits.
         return Ok((value, 1));
     }
@@ -91,14 +118,20 @@ fn decode_integer(buf: &[u8], prefix_size: u8)
     // Already one byte used (the prefix)
     let mut total = 1;
     let mut m = 0;
-    // The octet limit is chosen such that the maximum allowed *value* can
-    // never overflow an unsigned 32-bit integer. The maximum value of any
-    // integer that can be encoded with 5 octets is ~2^28
-    let octet_limit = 5;
 
     for &b in buf[1..].iter() {
         total += 1;
-        value += ((b & 127) as usize) * (1 << m);
+        let bits = (b & 127) as u64;
+        // The bits shifted out of a u64, or the carry, would be lost.
+        let shifted = if m < 64 { bits << m } else { 0 };
+        if m < 64 && shifted >> m != bits || m >= 64 && bits != 0 {
+            return Err(
+                DecoderError::IntegerDecodingError(
+                    IntegerDecodingError::ValueTooLarge));
+        }
+        value = try!(value.checked_add(shifted).ok_or(
+            DecoderError::IntegerDecodingError(
+                IntegerDecodingError::ValueTooLarge)));
         m += 7;
 
         if b & 128 != 128 {
@@ -129,7 +162,12 @@ fn decode_integer(buf: &[u8], prefix_size: u8)
 ///
 /// Returns the decoded string in a newly allocated `Vec` and the number of
 /// bytes consumed from the given buffer.
-fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderError> {
+///
+/// If `max_len` is given, strings whose decoded length exceeds it are
+/// rejected with `StringDecodingError::StringTooLong`. The check happens
+/// before anything is allocated whenever the encoded length allows it.
+fn decode_string<'a>(buf: &'a [u8], max_len: Option<usize>)
+        -> Result<(Cow<'a, [u8]>, usize), DecoderError> {
     let (len, consumed) = try!(decode_integer(buf, 7));
     // debug!("decode_string: Consumed = {}, len = {}", consumed, len);
     if consumed + len > buf.len() {
@@ -137,8 +175,19 @@ fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderErr
             DecoderError::StringDecodingError(
                 StringDecodingError::NotEnoughOctets));
     }
+    let huffman = buf[0] & 128 == 128;
+    if let Some(max_len) = max_len {
+        // The longest Huffman code is 30 bits, which bounds how short the
+        // decoded string can possibly be.
+        let min_decoded_len = if huffman { len * 8 / 30 } else { len };
+        if min_decoded_len > max_len {
+            return Err(
+                DecoderError::StringDecodingError(
+                    StringDecodingError::StringTooLong));
+        }
+    }
     let raw_string = &buf[consumed..consumed + len];
-    if buf[0] & 128 == 128 {
+    if huffman {
         // debug!("decode_string: Using the Huffman code");
         // Huffman coding used: pass the raw octets to the Huffman decoder
         // and return its result.
@@ -150,6 +199,11 @@ fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderErr
             },
             Ok(res) => res,
         };
+        if max_len.map_or(false, |max_len| decoded.len() > max_len) {
+            return Err(
+                DecoderError::StringDecodingError(
+                    StringDecodingError::StringTooLong));
+        }
         Ok((Cow::Owned(decoded), consumed + len))
     } else {
         // The octets were transmitted raw
@@ -158,6 +212,30 @@ fn decode_string<'a>(buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, usize), DecoderErr
     }
 }
 
+/// The offset of `s` within `buf`, if it is a slice of it.
+fn offset_in(s: &[u8], buf: &[u8]) -> Option<usize> {
+    let start = s.as_ptr() as usize;
+    let buf_start = buf.as_ptr() as usize;
+    if start >= buf_start && start + s.len() <= buf_start + buf.len() {
+        Some(start - buf_start)
+    } else {
+        None
+    }
+}
+
+/// Turns a name or value from `decode_borrowed` into `Bytes`: a slice of `buf` shares its
+/// storage, a Huffman decoded string is moved in and the short static table entries are
+/// copied (which `Bytes` stores inline when they are small enough).
+fn to_bytes(s: Cow<[u8]>, buf: &Bytes) -> Bytes {
+    match s {
+        Cow::Owned(s) => Bytes::from(s),
+        Cow::Borrowed(s) => match offset_in(s, buf) {
+            Some(offset) => buf.slice(offset, offset + s.len()),
+            None => Bytes::from(s),
+        },
+    }
+}
+
 /// Different variants of how a particular header field can be represented in
 /// an HPACK encoding.
 enum FieldRepresentation {
@@ -191,6 +269,15 @@ impl FieldRepresentation {
             FieldRepresentation::LiteralWithoutIndexing
         }
     }
+
+    /// Whether the representation is a dynamic table size update rather than
+    /// a header field.
+    fn is_size_update(&self) -> bool {
+        match *self {
+            FieldRepresentation::SizeUpdate => true,
+            _ => false,
+        }
+    }
 }
 
 /// Represents all errors that can be encountered while decoding an
@@ -224,6 +311,30 @@ pub enum IntegerDecodingError {
 pub enum StringDecodingError {
     NotEnoughOctets,
     HuffmanDecoderError(HuffmanDecoderError),
+    /// The decoded string is longer than allowed by
+    /// `Decoder::set_max_string_length`.
+    StringTooLong,
+}
+
+/// The HTTP/2 field rules a header field broke, when the `Decoder` validates
+/// fields.
+#[derive(PartialEq)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum FieldError {
+    /// Field names must be lowercase.
+    UppercaseName,
+    /// `connection`, `keep-alive`, `transfer-encoding` and the like, or a `te`
+    /// field other than "trailers".
+    ConnectionSpecific,
+    /// All pseudo-header fields must come before the regular fields.
+    PseudoAfterRegular,
+    /// Field names must not be empty, nor hold control characters, whitespace, DEL, non-ASCII
+    /// octets or colons other than the one starting a pseudo-header field.
+    InvalidName,
+    /// Field values must not hold NUL, CR or LF, nor start or end with whitespace.
+    InvalidValue,
 }
 
 /// Represents all errors that can be encountered while performing the decoding
@@ -240,19 +351,218 @@ pub enum DecoderError {
     /// size mandated to the decoder by the protocol. (by perfroming changes
     /// made by SizeUpdate blocks).
     InvalidMaxDynamicSize,
+    /// The header block contains more header fields than allowed by
+    /// `Decoder::set_max_header_count`.
+    TooManyHeaders,
+    /// The decoded header list is larger than allowed by
+    /// `Decoder::set_max_header_list_size`.
+    HeaderListTooLarge,
+    /// A dynamic table size update came after a header field; it may only
+    /// start a header block.
+    SizeUpdateNotAtStart,
+    /// The header block passed to `Decoder::decode_fragment` ended in the
+    /// middle of a field.
+    Truncated,
+    /// A field broke the rules checked by `Decoder::set_validate_fields`.
+    MalformedField(FieldError),
+}
+
+impl DecoderError {
+    /// Whether the error leaves the decoder's dynamic table out of sync with
+    /// the peer's encoder, which HTTP/2 treats as a connection error of type
+    /// COMPRESSION_ERROR. The limits (`TooManyHeaders`, `HeaderListTooLarge`,
+    /// `StringTooLong`) are the other case: the decoder still goes through
+    /// the rest of the block, applying its table updates without handing out
+    /// any more fields, before it reports them, so the connection may answer
+    /// the offending request with a 431 and carry on.
+    pub fn is_compression_error(&self) -> bool {
+        match *self {
+            DecoderError::TooManyHeaders |
+            DecoderError::HeaderListTooLarge |
+            DecoderError::StringDecodingError(StringDecodingError::StringTooLong) |
+            DecoderError::MalformedField(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Whether the error makes the request or response malformed, which
+    /// HTTP/2 treats as a stream error of type PROTOCOL_ERROR.
+    pub fn is_malformed(&self) -> bool {
+        match *self {
+            DecoderError::MalformedField(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            FieldError::UppercaseName => "uppercase field name",
+            FieldError::ConnectionSpecific => "connection-specific field",
+            FieldError::PseudoAfterRegular => "pseudo-header field after a regular field",
+            FieldError::InvalidName => "invalid octet in field name",
+            FieldError::InvalidValue => "invalid octet or surrounding whitespace in field value",
+        })
+    }
+}
+
+impl fmt::Display for IntegerDecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            IntegerDecodingError::TooManyOctets => "integer encoded with too many octets",
+            IntegerDecodingError::ValueTooLarge => "integer value too large",
+            IntegerDecodingError::NotEnoughOctets => "integer truncated",
+            IntegerDecodingError::InvalidPrefix => "invalid integer prefix size",
+        })
+    }
+}
+
+impl fmt::Display for StringDecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StringDecodingError::NotEnoughOctets => f.write_str("string literal truncated"),
+            StringDecodingError::HuffmanDecoderError(e) => write!(f, "invalid Huffman code: {:?}", e),
+            StringDecodingError::StringTooLong => f.write_str("string literal too long"),
+        }
+    }
+}
+
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecoderError::HeaderIndexOutOfBounds => f.write_str("header table index out of bounds"),
+            DecoderError::IntegerDecodingError(e) => e.fmt(f),
+            DecoderError::StringDecodingError(e) => e.fmt(f),
+            DecoderError::InvalidMaxDynamicSize => f.write_str("dynamic table size update over the allowed maximum"),
+            DecoderError::TooManyHeaders => f.write_str("too many header fields"),
+            DecoderError::HeaderListTooLarge => f.write_str("header list too large"),
+            DecoderError::SizeUpdateNotAtStart => f.write_str("dynamic table size update after a header field"),
+            DecoderError::Truncated => f.write_str("header block truncated"),
+            DecoderError::MalformedField(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for DecoderError {
+    fn description(&self) -> &str {
+        "HPACK decoding error"
+    }
+}
+
+/// What has been decoded of the current header block so far.
+#[derive(Copy, Clone, Debug, Default)]
+struct BlockProgress {
+    fields: usize,
+    /// The `entry_size` of every field.
+    size: usize,
+    /// Whether a regular (not pseudo-) field was decoded.
+    regular: bool,
+    /// The first limit the block broke. The rest of it is still decoded, for the
+    /// table updates, but no more fields are handed out.
+    failed: Option<DecoderError>,
+}
+
+impl BlockProgress {
+    /// Records `err`, unless an earlier error was recorded already.
+    fn fail(&mut self, err: DecoderError) {
+        if self.failed.is_none() {
+            self.failed = Some(err);
+        }
+    }
+
+    /// The recorded error, if any, as the result of the block.
+    fn result(&self) -> Result<(), DecoderError> {
+        match self.failed {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn add(&mut self, name: &[u8], value: &[u8], max_size: Option<usize>) -> Result<(), DecoderError> {
+        self.size += entry_size(name, value);
+        if max_size.map_or(false, |max| self.size > max) {
+            return Err(DecoderError::HeaderListTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Applies the HTTP/2 field rules of RFC 9113 sections 8.2 and 8.3 to the next field.
+    fn validate(&mut self, name: &[u8], value: &[u8]) -> Result<(), DecoderError> {
+        if name.iter().any(|&b| b >= b'A' && b <= b'Z') {
+            return Err(DecoderError::MalformedField(FieldError::UppercaseName));
+        }
+        let bare = if name.first() == Some(&b':') { &name[1..] } else { name };
+        if bare.is_empty() || bare.iter().any(|&b| b <= 0x20 || b >= 0x7f || b == b':') {
+            return Err(DecoderError::MalformedField(FieldError::InvalidName));
+        }
+        let is_ws = |b: &u8| *b == b' ' || *b == b'\t';
+        if value.iter().any(|&b| b == 0 || b == b'\r' || b == b'\n') || value.first().map_or(false, &is_ws) ||
+                value.last().map_or(false, &is_ws) {
+            return Err(DecoderError::MalformedField(FieldError::InvalidValue));
+        }
+        if name.first() == Some(&b':') {
+            if self.regular {
+                return Err(DecoderError::MalformedField(FieldError::PseudoAfterRegular));
+            }
+        } else {
+            self.regular = true;
+        }
+        if is_connection_specific(name, value) {
+            return Err(DecoderError::MalformedField(FieldError::ConnectionSpecific));
+        }
+        Ok(())
+    }
 }
 
 /// The result returned by the `decode` method of the `Decoder`.
 pub type DecoderResult = Result<Vec<(Vec<u8>, Vec<u8>)>, DecoderError>;
 
+/// The result returned by the `decode_borrowed` method of the `Decoder`.
+pub type BorrowedDecoderResult<'b> = Result<Vec<(Cow<'b, [u8]>, Cow<'b, [u8]>)>, DecoderError>;
+
+/// Turns a name or value handed out by `decode_with_cb` into one that lives as long as the
+/// header block `buf`: a plain literal is a slice of `buf`, and a static table entry is
+/// borrowed from the static `entry` it was found to be. Anything else (a dynamic table entry)
+/// is copied.
+fn rebase<'b>(s: Cow<[u8]>, buf: &'b [u8], entry: Option<&'b [u8]>) -> Cow<'b, [u8]> {
+    let s = match s {
+        Cow::Owned(s) => return Cow::Owned(s),
+        Cow::Borrowed(s) => s,
+    };
+    if let Some(offset) = offset_in(s, buf) {
+        return Cow::Borrowed(&buf[offset..offset + s.len()]);
+    }
+    match entry {
+        Some(entry) if entry.as_ptr() == s.as_ptr() && entry.len() == s.len() => Cow::Borrowed(entry),
+        _ => Cow::Owned(s.to_vec()),
+    }
+}
+
 /// Decodes headers encoded using HPACK.
 ///
-/// For now, incremental decoding is not supported, i.e. it is necessary
-/// to pass in the entire encoded representation of all headers to the
-/// decoder, rather than processing it piece-by-piece.
+/// `decode` takes the entire encoded representation of all headers at once;
+/// `decode_fragment` processes it piece-by-piece as the frames carrying it
+/// arrive.
 pub struct Decoder<'a> {
     // The dynamic table will own its own copy of headers
     header_table: HeaderTable<'a>,
+    // The maximum number of header fields a single header block may carry
+    max_header_count: Option<usize>,
+    // The maximum length of a single decoded string literal
+    max_string_length: Option<usize>,
+    // Whether `decode` joins crumbled cookie fields back into one
+    join_cookies: bool,
+    // The unconsumed tail of the header block being fed to `decode_fragment`
+    pending: Vec<u8>,
+    // Header fields decoded so far in that block
+    fragment_progress: BlockProgress,
+    // The maximum decoded size of a header block, counted as SETTINGS_MAX_HEADER_LIST_SIZE does
+    max_header_list_size: Option<usize>,
+    // The largest size the encoder may set the dynamic table to
+    max_allowed_table_size: usize,
+    // Whether decoded fields are checked against the HTTP/2 field rules
+    validate_fields: bool,
 }
 
 /// Represents a decoder of HPACK encoded headers. Maintains the state
@@ -274,15 +584,95 @@ impl<'a> Decoder<'a> {
     ///       the one defined in the HPACK spec.
     fn with_static_table(static_table: StaticTable<'a>) -> Decoder<'a> {
         Decoder {
-            header_table: HeaderTable::with_static_table(static_table)
+            header_table: HeaderTable::with_static_table(static_table),
+            max_header_count: None,
+            max_string_length: None,
+            join_cookies: false,
+            pending: Vec::new(),
+            fragment_progress: BlockProgress::default(),
+            max_header_list_size: None,
+            max_allowed_table_size: 4096,
+            validate_fields: false,
         }
     }
 
+    /// Makes `observer` be called with every entry evicted from the decoder's
+    /// dynamic table. See `HeaderTable::set_eviction_observer`.
+    pub fn set_eviction_observer<F>(&mut self, observer: F)
+            where F: FnMut(&[u8], &[u8], EvictionReason) + Send + 'static {
+        self.header_table.set_eviction_observer(observer);
+    }
+
+    /// The decoder's header table, for inspecting the dynamic table.
+    pub fn header_table(&self) -> &HeaderTable<'a> {
+        &self.header_table
+    }
+
+    /// The current size of the dynamic table in octets, counting every entry
+    /// as its name and value lengths plus 32 octets.
+    pub fn dynamic_table_size(&self) -> usize {
+        self.header_table.dynamic_size()
+    }
+
     /// Sets a new maximum dynamic table size for the decoder.
     pub fn set_max_table_size(&mut self, new_max_size: usize) {
         self.header_table.dynamic_table.set_max_table_size(new_max_size);
     }
 
+    /// Sets the SETTINGS_HEADER_TABLE_SIZE announced to the peer (4096 by
+    /// default): dynamic table size updates above it fail with
+    /// `DecoderError::InvalidMaxDynamicSize`.
+    pub fn set_max_allowed_table_size(&mut self, max_size: usize) {
+        self.max_allowed_table_size = max_size;
+    }
+
+    /// Limits the number of header fields accepted in a single header block
+    /// (e.g. a request's headers or its trailers). Blocks carrying more fields
+    /// fail with `DecoderError::TooManyHeaders`: the fields past the limit are
+    /// not handed out, but still decoded into the dynamic table. `None` (the
+    /// default) means no limit.
+    pub fn set_max_header_count(&mut self, max_header_count: Option<usize>) {
+        self.max_header_count = max_header_count;
+    }
+
+    /// Limits the length of any single decoded string literal (header name or
+    /// value), independently of any limit on the whole header list. This
+    /// stops a single pathological literal from forcing a huge allocation:
+    /// the block fails with `StringDecodingError::StringTooLong`, once the
+    /// rest of it has been decoded into the dynamic table without handing out
+    /// any more fields. `None` (the default) means no limit.
+    pub fn set_max_string_length(&mut self, max_string_length: Option<usize>) {
+        self.max_string_length = max_string_length;
+    }
+
+    /// Limits the decoded size of a header block, computed as for SETTINGS_MAX_HEADER_LIST_SIZE
+    /// (name and value lengths plus 32 octets per field). Past the limit the block fails with
+    /// `DecoderError::HeaderListTooLarge`, and no more fields are handed out, so a small block
+    /// that refers to a large table entry over and over can't expand to megabytes; the rest of
+    /// it is still decoded for the table updates. `None` (the default) means no limit.
+    pub fn set_max_header_list_size(&mut self, max_size: Option<usize>) {
+        self.max_header_list_size = max_size;
+    }
+
+    /// Makes `decode` join the `cookie` fields of a header block into a single field, with the
+    /// cookies separated by "; " (RFC 7540 section 8.1.2.5), where the first one was. Off by
+    /// default; `decode_with_cb` always hands out the fields as they are.
+    pub fn set_join_cookies(&mut self, join_cookies: bool) {
+        self.join_cookies = join_cookies;
+    }
+
+    /// Makes the decoder check every field against the HTTP/2 rules of RFC 9113 section 8.2:
+    /// lowercase names of visible ASCII, values without NUL, CR, LF or surrounding whitespace,
+    /// no connection-specific fields and no pseudo-header fields after regular ones. A field breaking them fails the block with `DecoderError::MalformedField` instead
+    /// of being handed out. Off by default.
+    ///
+    /// Like the limits, the error only stops the fields from being handed out: the rest of the
+    /// block is still decoded, for the updates of the dynamic table it makes, before it is
+    /// reported.
+    pub fn set_validate_fields(&mut self, validate_fields: bool) {
+        self.validate_fields = validate_fields;
+    }
+
     /// Decodes the headers found in the given buffer `buf`. Invokes the callback `cb` for each
     /// decoded header in turn, by providing it the header name and value as `Cow` byte array
     /// slices.
@@ -297,76 +687,176 @@ impl<'a> Decoder<'a> {
     /// decoding begins, meaning until the end of the callback's body.
     ///
     /// If an error is encountered during the decoding of any header, decoding halts and the
-    /// appropriate error is returned as the `Err` variant of the `Result`.
+    /// appropriate error is returned as the `Err` variant of the `Result`. A broken limit only
+    /// stops `cb` from being called: the block is decoded to the end first.
     pub fn decode_with_cb<F>(&mut self, buf: &[u8], mut cb: F) -> Result<(), DecoderError>
             where F: FnMut(Cow<[u8]>, Cow<[u8]>) {
         let mut current_octet_index = 0;
+        let mut progress = BlockProgress::default();
 
         while current_octet_index < buf.len() {
             // At this point we are always at the beginning of the next block
             // within the HPACK data.
-            // The type of the block can always be determined from the first
-            // byte.
-            let initial_octet = buf[current_octet_index];
-            let buffer_leftover = &buf[current_octet_index..];
-            let consumed = match FieldRepresentation::new(initial_octet) {
-                FieldRepresentation::Indexed => {
-                    let ((name, value), consumed) =
-                        try!(self.decode_indexed(buffer_leftover));
-                    cb(Cow::Borrowed(name), Cow::Borrowed(value));
+            current_octet_index += try!(self.decode_one(&buf[current_octet_index..],
+                                                        &mut progress,
+                                                        &mut cb));
+        }
+
+        progress.result()
+    }
 
-                    consumed
+    /// Decodes the next fragment of a header block, e.g. the block fragment of a HEADERS frame
+    /// and then of each CONTINUATION frame, and returns the headers it completed. A field cut
+    /// in two by the fragment boundary is kept until the next fragment brings the rest; call
+    /// `finish` after the last fragment.
+    ///
+    /// The dynamic table is updated as the fields are decoded, since later fields of the block
+    /// may refer to them. A block that broke a limit is still decoded into the table, and only
+    /// fails at `finish`; any other error leaves the table out of sync with the encoder, which
+    /// is a connection error (COMPRESSION_ERROR) anyway.
+    pub fn decode_fragment(&mut self, fragment: &[u8]) -> DecoderResult {
+        let mut pending = mem::replace(&mut self.pending, Vec::new());
+        pending.extend_from_slice(fragment);
+
+        let mut header_list = Vec::new();
+        let mut consumed = 0;
+        while consumed < pending.len() {
+            let mut progress = self.fragment_progress;
+            let res = self.decode_one(&pending[consumed..], &mut progress, &mut |n: Cow<[u8]>, v: Cow<[u8]>| {
+                header_list.push((n.into_owned(), v.into_owned()))
+            });
+            match res {
+                Ok(len) => {
+                    consumed += len;
+                    self.fragment_progress = progress;
+                },
+                Err(DecoderError::IntegerDecodingError(IntegerDecodingError::NotEnoughOctets)) |
+                Err(DecoderError::StringDecodingError(StringDecodingError::NotEnoughOctets)) => break,
+                Err(e) => {
+                    self.fragment_progress = BlockProgress::default();
+                    return Err(e);
                 },
-                FieldRepresentation::LiteralWithIncrementalIndexing => {
-                    let ((name, value), consumed) = {
-                        let ((name, value), consumed) = try!(
-                            self.decode_literal(buffer_leftover, true));
+            }
+        }
+        pending.drain(..consumed);
+        self.pending = pending;
+        Ok(header_list)
+    }
+
+    /// Ends the header block fed to `decode_fragment`, failing if it ended in the middle of a
+    /// field, or with the limit it broke: the fields past it were not handed out.
+    pub fn finish(&mut self) -> Result<(), DecoderError> {
+        let progress = mem::replace(&mut self.fragment_progress, BlockProgress::default());
+        if self.pending.is_empty() {
+            progress.result()
+        } else {
+            self.pending.clear();
+            Err(DecoderError::Truncated)
+        }
+    }
+
+    /// Decodes the single field representation (or size update) at the start of `buf`, which
+    /// must not be empty, passes a decoded header to `cb` and returns the number of octets
+    /// consumed.
+    fn decode_one<F>(&mut self, buf: &[u8], progress: &mut BlockProgress, cb: &mut F)
+            -> Result<usize, DecoderError>
+            where F: FnMut(Cow<[u8]>, Cow<[u8]>) {
+        // The type of the block can always be determined from the first
+        // byte.
+        let representation = FieldRepresentation::new(buf[0]);
+        if representation.is_size_update() {
+            if progress.fields > 0 {
+                return Err(DecoderError::SizeUpdateNotAtStart);
+            }
+        } else {
+            progress.fields += 1;
+            if self.max_header_count.map_or(false, |max| progress.fields > max) {
+                progress.fail(DecoderError::TooManyHeaders);
+            }
+        }
+        let consumed = match representation {
+            FieldRepresentation::Indexed => {
+                let ((name, value), consumed) =
+                    try!(self.decode_indexed(buf));
+                if self.check_field(progress, name, value) {
+                    cb(Cow::Borrowed(name), Cow::Borrowed(value));
+                }
+
+                consumed
+            },
+            FieldRepresentation::LiteralWithIncrementalIndexing => {
+                let ((name, value), consumed) = {
+                    let ((name, value), consumed) = try!(
+                        self.decode_literal(buf, true, progress));
+                    if self.check_field(progress, &name, &value) {
                         cb(Cow::Borrowed(&name), Cow::Borrowed(&value));
+                    }
 
-                        // Since we are to add the decoded header to the header table, we need to
-                        // convert them into owned buffers that the decoder can keep internally.
-                        let name = name.into_owned();
-                        let value = value.into_owned();
-
-                        ((name, value), consumed)
-                    };
-                    // This cannot be done in the same scope as the `decode_literal` call, since
-                    // Rust cannot figure out that the `into_owned` calls effectively drop the
-                    // borrow on `self` that the `decode_literal` return value had. Since adding
-                    // a header to the table requires a `&mut self`, it fails to compile.
-                    // Manually separating it out here works around it...
-                    self.header_table.add_header(name, value);
-
-                    consumed
-                },
-                FieldRepresentation::LiteralWithoutIndexing => {
-                    let ((name, value), consumed) =
-                        try!(self.decode_literal(buffer_leftover, false));
-                    cb(name, value);
+                    // Since we are to add the decoded header to the header table, we need to
+                    // convert them into owned buffers that the decoder can keep internally.
+                    let name = name.into_owned();
+                    let value = value.into_owned();
 
-                    consumed
-                },
-                FieldRepresentation::LiteralNeverIndexed => {
-                    // Same as the previous one, except if we were also a proxy
-                    // we would need to make sure not to change the
-                    // representation received here. We don't care about this
-                    // for now.
-                    let ((name, value), consumed) =
-                        try!(self.decode_literal(buffer_leftover, false));
+                    ((name, value), consumed)
+                };
+                // This cannot be done in the same scope as the `decode_literal` call, since
+                // Rust cannot figure out that the `into_owned` calls effectively drop the
+                // borrow on `self` that the `decode_literal` return value had. Since adding
+                // a header to the table requires a `&mut self`, it fails to compile.
+                // Manually separating it out here works around it...
+                self.header_table.add_header(name, value);
+
+                consumed
+            },
+            FieldRepresentation::LiteralWithoutIndexing => {
+                let ((name, value), consumed) =
+                    try!(self.decode_literal(buf, false, progress));
+                if self.check_field(progress, &name, &value) {
                     cb(name, value);
+                }
 
-                    consumed
-                },
-                FieldRepresentation::SizeUpdate => {
-                    // Handle the dynamic table size update...
-                    self.update_max_dynamic_size(buffer_leftover)
+                consumed
+            },
+            FieldRepresentation::LiteralNeverIndexed => {
+                // Same as the previous one, except if we were also a proxy
+                // we would need to make sure not to change the
+                // representation received here. We don't care about this
+                // for now.
+                let ((name, value), consumed) =
+                    try!(self.decode_literal(buf, false, progress));
+                if self.check_field(progress, &name, &value) {
+                    cb(name, value);
                 }
-            };
 
-            current_octet_index += consumed;
-        }
+                consumed
+            },
+            FieldRepresentation::SizeUpdate => {
+                // Handle the dynamic table size update...
+                try!(self.update_max_dynamic_size(buf))
+            }
+        };
 
-        Ok(())
+        Ok(consumed)
+    }
+
+    /// Accounts for the next field of the block, and returns whether to hand it out: not once
+    /// the block broke a limit or, when fields are validated, the field rules.
+    fn check_field(&self, progress: &mut BlockProgress, name: &[u8], value: &[u8]) -> bool {
+        if progress.failed.is_some() {
+            return false;
+        }
+        let res = progress.add(name, value, self.max_header_list_size).and_then(|()| {
+            if self.validate_fields {
+                progress.validate(name, value)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = res {
+            progress.fail(err);
+            return false;
+        }
+        true
     }
 
     /// Decode the header block found in the given buffer.
@@ -376,15 +866,71 @@ impl<'a> Decoder<'a> {
     ///
     /// The buffer should represent the entire block that should be decoded.
     /// For example, in HTTP/2, all continuation frames need to be concatenated
-    /// to a single buffer before passing them to the decoder.
+    /// to a single buffer before passing them to the decoder; `decode_fragment`
+    /// takes them one at a time instead.
     pub fn decode(&mut self, buf: &[u8]) -> DecoderResult {
         let mut header_list = Vec::new();
 
         try!(self.decode_with_cb(buf, |n, v| header_list.push((n.into_owned(), v.into_owned()))));
 
+        if self.join_cookies {
+            join_cookies(&mut header_list);
+        }
         Ok(header_list)
     }
 
+    /// Returns an iterator decoding the header block in `buf` one field at a time, so that a
+    /// request can be turned down at its first bad header without collecting the headers into
+    /// a list first. It stops after yielding an error.
+    ///
+    /// The dynamic table is updated as the iterator goes, and the later fields of the block
+    /// can change it as well: dropping the iterator before the end decodes the rest of the
+    /// block without handing it out (see `DecodeIter::skip_rest`), which keeps the decoder in
+    /// sync with the encoder. Cookies are never joined here; `decode` does that.
+    pub fn decode_iter<'d, 'b>(&'d mut self, buf: &'b [u8]) -> DecodeIter<'d, 'a, 'b> {
+        DecodeIter {
+            decoder: self,
+            buf: buf,
+            consumed: 0,
+            progress: BlockProgress::default(),
+            done: false,
+        }
+    }
+
+    /// Decodes the header block found in the given buffer without copying what doesn't need to
+    /// be: plain literals are borrowed from `buf` and static table entries from the static
+    /// table. Only Huffman coded strings and fields from the dynamic table are allocated.
+    ///
+    /// Cookies are never joined here; `decode` does that.
+    pub fn decode_borrowed<'b>(&mut self, buf: &'b [u8]) -> BorrowedDecoderResult<'b> where 'a: 'b {
+        let static_table: StaticTable<'b> = self.header_table.static_table;
+        let spec_table = static_table.as_ptr() == STATIC_TABLE.as_ptr();
+        let mut header_list = Vec::new();
+
+        try!(self.decode_with_cb(buf, |n, v| {
+            // The static table entry the field may have come from.
+            let entry = match n {
+                Cow::Borrowed(name) if spec_table => static_name_range(name).and_then(|(first, last)| {
+                    static_table[first - 1..last].iter().find(|e| e.0.as_ptr() == name.as_ptr())
+                }),
+                _ => None,
+            };
+            let name = rebase(n, buf, entry.map(|e| e.0));
+            let value = rebase(v, buf, entry.map(|e| e.1));
+            header_list.push((name, value));
+        }));
+
+        Ok(header_list)
+    }
+
+    /// Decodes the header block in `buf` into fields whose plain literals are slices of `buf`
+    /// sharing its storage rather than copies, e.g. for a proxy forwarding them as they are.
+    /// What `decode_borrowed` would allocate is still allocated.
+    pub fn decode_bytes(&mut self, buf: &Bytes) -> Result<Vec<(Bytes, Bytes)>, DecoderError> {
+        let header_list = try!(self.decode_borrowed(buf));
+        Ok(header_list.into_iter().map(|(name, value)| (to_bytes(name, buf), to_bytes(value, buf))).collect())
+    }
+
     /// Decodes an indexed header representation.
     fn decode_indexed(&self, buf: &[u8])
             -> Result<((&[u8], &[u8]), usize), DecoderError> {
@@ -413,7 +959,8 @@ impl<'a> Decoder<'a> {
     ///
     /// - index: whether or not the decoded value should be indexed (i.e.
     ///   included in the dynamic table).
-    fn decode_literal<'b>(&'b self, buf: &'b [u8], index: bool)
+    /// - progress: where a string over `max_string_length` is recorded.
+    fn decode_literal<'b>(&'b self, buf: &'b [u8], index: bool, progress: &mut BlockProgress)
             -> Result<((Cow<[u8]>, Cow<[u8]>), usize), DecoderError> {
         let prefix = if index {
             6
@@ -425,7 +972,7 @@ impl<'a> Decoder<'a> {
         // First read the name appropriately
         let name = if table_index == 0 {
             // Read name string as literal
-            let (name, name_len) = try!(decode_string(&buf[consumed..]));
+            let (name, name_len) = try!(self.decode_literal_string(&buf[consumed..], index, progress));
             consumed += name_len;
             name
         } else {
@@ -435,12 +982,37 @@ impl<'a> Decoder<'a> {
         };
 
         // Now read the value as a literal...
-        let (value, value_len) = try!(decode_string(&buf[consumed..]));
+        let (value, value_len) = try!(self.decode_literal_string(&buf[consumed..], index, progress));
         consumed += value_len;
 
         Ok(((name, value), consumed))
     }
 
+    /// Decodes a string of a literal representation, recording in `progress` if it is over
+    /// `max_string_length` instead of failing, so that the rest of the block can be decoded.
+    /// A field to be indexed goes into the dynamic table whatever its length (the size of the
+    /// block bounds it anyway); any other too long string is skipped without decoding it.
+    fn decode_literal_string<'b>(&self, buf: &'b [u8], index: bool, progress: &mut BlockProgress)
+            -> Result<(Cow<'b, [u8]>, usize), DecoderError> {
+        let too_long = DecoderError::StringDecodingError(StringDecodingError::StringTooLong);
+        let max_len = if index { None } else { self.max_string_length };
+        match decode_string(buf, max_len) {
+            Ok((string, consumed)) => {
+                if self.max_string_length.map_or(false, |max_len| string.len() > max_len) {
+                    progress.fail(too_long);
+                }
+                Ok((string, consumed))
+            },
+            Err(ref err) if *err == too_long => {
+                progress.fail(too_long);
+                // `decode_string` made sure the whole string is there.
+                let (len, consumed) = try!(decode_integer(buf, 7));
+                Ok((Cow::Borrowed(&[]), consumed + len))
+            },
+            Err(err) => Err(err),
+        }
+    }
+
     /// Handles processing the `SizeUpdate` HPACK block: updates the maximum
     /// size of the underlying dynamic table, possibly causing a number of
     /// headers to be evicted from it.
@@ -449,14 +1021,370 @@ impl<'a> Decoder<'a> {
     /// octet in the `SizeUpdate` block.
     ///
     /// Returns the number of octets consumed from the given buffer.
-    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> usize {
-        let (new_size, consumed) = decode_integer(buf, 5).ok().unwrap();
+    fn update_max_dynamic_size(&mut self, buf: &[u8]) -> Result<usize, DecoderError> {
+        let (new_size, consumed) = try!(decode_integer(buf, 5));
+        if new_size > self.max_allowed_table_size {
+            return Err(DecoderError::InvalidMaxDynamicSize);
+        }
         self.header_table.dynamic_table.set_max_table_size(new_size);
 
         // info!("Decoder changed max table size from {} to {}",
             //   self.header_table.dynamic_table.get_size(),
             //   new_size);
 
-        consumed
+        Ok(consumed)
+    }
+}
+
+/// The iterator returned by `Decoder::decode_iter`.
+pub struct DecodeIter<'d, 'a: 'd, 'b> {
+    decoder: &'d mut Decoder<'a>,
+    buf: &'b [u8],
+    consumed: usize,
+    progress: BlockProgress,
+    /// The block's error (or its end) was returned already.
+    done: bool,
+}
+
+impl<'d, 'a, 'b> DecodeIter<'d, 'a, 'b> {
+    /// Decodes the rest of the block, for the updates of the dynamic table it makes, without
+    /// handing out its fields. Fails with the error the block ends with, unless the iterator
+    /// yielded it already.
+    pub fn skip_rest(&mut self) -> Result<(), DecoderError> {
+        while self.consumed < self.buf.len() {
+            let res = self.decoder.decode_one(&self.buf[self.consumed..], &mut self.progress,
+                                              &mut |_: Cow<[u8]>, _: Cow<[u8]>| ());
+            match res {
+                Ok(len) => self.consumed += len,
+                Err(e) => {
+                    self.consumed = self.buf.len();
+                    self.done = true;
+                    return Err(e);
+                },
+            }
+        }
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        self.progress.result()
+    }
+}
+
+impl<'d, 'a, 'b> Iterator for DecodeIter<'d, 'a, 'b> {
+    type Item = Result<(Vec<u8>, Vec<u8>), DecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        // Size updates don't yield anything, so go on until a field is decoded.
+        while self.consumed < self.buf.len() {
+            let mut header = None;
+            let res = self.decoder.decode_one(&self.buf[self.consumed..], &mut self.progress,
+                                              &mut |n: Cow<[u8]>, v: Cow<[u8]>| {
+                header = Some((n.into_owned(), v.into_owned()))
+            });
+            match res {
+                Ok(len) => {
+                    self.consumed += len;
+                    if let Some(header) = header {
+                        return Some(Ok(header));
+                    }
+                    // Past a broken limit, nothing more is handed out.
+                    if self.progress.failed.is_some() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    self.consumed = self.buf.len();
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        self.skip_rest().err().map(Err)
+    }
+}
+
+impl<'d, 'a, 'b> Drop for DecodeIter<'d, 'a, 'b> {
+    fn drop(&mut self) {
+        let _ = self.skip_rest();
+    }
+}
+
+/// Folds every `cookie` field after the first into the first one.
+fn join_cookies(header_list: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+    let first = match header_list.iter().position(|&(ref name, _)| name == b"cookie") {
+        Some(first) => first,
+        None => return,
+    };
+    let mut i = first + 1;
+    while i < header_list.len() {
+        if header_list[i].0 == b"cookie" {
+            let (_, crumb) = header_list.remove(i);
+            header_list[first].1.extend_from_slice(b"; ");
+            header_list[first].1.extend_from_slice(&crumb);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use bytes::Bytes;
+
+    use super::{decode_integer, decode_integer_u64, Decoder, DecoderError, FieldError, IntegerDecodingError};
+    use super::StringDecodingError;
+    use hpack::encoder::encode_integer_u64_into;
+
+    #[test]
+    fn test_max_header_count() {
+        let mut decoder = Decoder::new();
+        decoder.set_max_header_count(Some(2));
+        // A size update does not count as a header field.
+        assert!(decoder.decode(&[0x3f, 0xe1, 0x1f, 0x82, 0x84]).is_ok());
+        assert_eq!(decoder.decode(&[0x82, 0x84, 0x86]), Err(DecoderError::TooManyHeaders));
+
+        // The count is per block, and the fields past the limit still go into the dynamic table.
+        assert_eq!(decoder.decode(&[0x82, 0x84, 0x40, 1, b'a', 1, b'b']), Err(DecoderError::TooManyHeaders));
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b"a".to_vec(), b"b".to_vec())]);
+        decoder.set_max_header_count(None);
+        assert_eq!(decoder.decode(&[0x82, 0x84, 0x86]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_max_string_length() {
+        // Literal without indexing, literal name "custom-key", value "custom-value".
+        let block = [0x00,
+                     10, b'c', b'u', b's', b't', b'o', b'm', b'-', b'k', b'e', b'y',
+                     12, b'c', b'u', b's', b't', b'o', b'm', b'-', b'v', b'a', b'l', b'u', b'e'];
+
+        let mut decoder = Decoder::new();
+        decoder.set_max_string_length(Some(12));
+        assert!(decoder.decode(&block).is_ok());
+
+        decoder.set_max_string_length(Some(11));
+        assert_eq!(decoder.decode(&block),
+                   Err(DecoderError::StringDecodingError(StringDecodingError::StringTooLong)));
+
+        // The block is still decoded to the end, a too long field to be indexed included.
+        let mut indexed = block.to_vec();
+        indexed[0] = 0x40;
+        indexed.extend_from_slice(&[0x40, 1, b'a', 1, b'b']);
+        assert_eq!(decoder.decode(&indexed),
+                   Err(DecoderError::StringDecodingError(StringDecodingError::StringTooLong)));
+        assert_eq!(decoder.header_table().dynamic_len(), 2);
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b"a".to_vec(), b"b".to_vec())]);
+    }
+
+    #[test]
+    fn test_decode_integer_u64() {
+        for &value in &[0, 30, 31, 1337, 1 << 32, u64::max_value() - 1, u64::max_value()] {
+            let mut buf = Vec::new();
+            encode_integer_u64_into(value, 5, 0, &mut buf).unwrap();
+            assert_eq!(decode_integer_u64(&buf, 5), Ok((value, buf.len())));
+        }
+        let too_large = Err(DecoderError::IntegerDecodingError(IntegerDecodingError::ValueTooLarge));
+        // 2^64 + 30, then 2^64 in the last octet.
+        assert_eq!(decode_integer_u64(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], 5),
+                   too_large);
+        assert_eq!(decode_integer_u64(&[0x1f, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02], 5),
+                   too_large);
+        // Padding with zero continuation octets is cut off too.
+        let mut padded = vec![0x1f];
+        padded.extend_from_slice(&[0x80; 10]);
+        padded.push(0);
+        assert_eq!(decode_integer_u64(&padded, 5),
+                   Err(DecoderError::IntegerDecodingError(IntegerDecodingError::TooManyOctets)));
+        // The `usize` codec keeps its shorter limit.
+        assert_eq!(decode_integer(&[0x1f, 0x80, 0x80, 0x80, 0x80, 0x01], 5),
+                   Err(DecoderError::IntegerDecodingError(IntegerDecodingError::TooManyOctets)));
+    }
+
+    #[test]
+    fn test_decode_fragment() {
+        // RFC 7541 C.3.1, cut inside the :authority literal.
+        let block = [0x82, 0x86, 0x84, 0x41, 0x0f, b'w', b'w', b'w', b'.', b'e', b'x', b'a',
+                     b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm'];
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode_fragment(&block[..8]).unwrap().len(), 3);
+        assert_eq!(decoder.decode_fragment(&block[8..]).unwrap(),
+                   vec![(b":authority".to_vec(), b"www.example.com".to_vec())]);
+        assert!(decoder.finish().is_ok());
+        // The literal went into the dynamic table.
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b":authority".to_vec(), b"www.example.com".to_vec())]);
+
+        assert!(decoder.decode_fragment(&block[..5]).is_ok());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_max_header_list_size() {
+        let mut decoder = Decoder::new();
+        // :method GET is 7 + 3 + 32 = 42.
+        decoder.set_max_header_list_size(Some(84));
+        assert!(decoder.decode(&[0x82, 0x82]).is_ok());
+        assert_eq!(decoder.decode(&[0x82, 0x82, 0x82]), Err(DecoderError::HeaderListTooLarge));
+
+        // Past the limit, the block still goes into the table: the next one refers to it.
+        assert_eq!(decoder.decode(&[0x82, 0x82, 0x40, 1, b'a', 1, b'b']), Err(DecoderError::HeaderListTooLarge));
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b"a".to_vec(), b"b".to_vec())]);
+        decoder.set_max_header_count(Some(1));
+        assert!(decoder.decode_fragment(&[0x82, 0x40, 1, b'c']).is_ok());
+        assert!(decoder.decode_fragment(&[1, b'd']).unwrap().is_empty());
+        assert_eq!(decoder.finish(), Err(DecoderError::TooManyHeaders));
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b"c".to_vec(), b"d".to_vec())]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&[0x80 | 70]), Err(DecoderError::HeaderIndexOutOfBounds));
+        assert_eq!(decoder.decode(&[0x3f, 0xe2, 0x1f]), Err(DecoderError::InvalidMaxDynamicSize));
+        assert_eq!(decoder.decode(&[0x82, 0x20]), Err(DecoderError::SizeUpdateNotAtStart));
+        // A raw literal value cut short, then a Huffman code padded with zeros.
+        assert_eq!(decoder.decode(&[0x00, 1, b'a', 2, b'b']),
+                   Err(DecoderError::StringDecodingError(StringDecodingError::NotEnoughOctets)));
+        let err = decoder.decode(&[0x00, 1, b'a', 0x81, 0x00]).unwrap_err();
+        assert!(err.is_compression_error());
+        assert!(!DecoderError::HeaderListTooLarge.is_compression_error());
+        assert_eq!(DecoderError::Truncated.to_string(), "header block truncated");
+    }
+
+    #[test]
+    fn test_header_table() {
+        let mut decoder = Decoder::new();
+        // Two literals with incremental indexing, RFC 7541 C.3.1 and C.3.2.
+        decoder.decode(&[0x41, 0x0f, b'w', b'w', b'w', b'.', b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+                         b'.', b'c', b'o', b'm', 0x58, 0x08, b'n', b'o', b'-', b'c', b'a', b'c', b'h', b'e'])
+            .unwrap();
+        let table = decoder.header_table();
+        assert_eq!(table.dynamic_len(), 2);
+        assert_eq!(table.dynamic_size(), 57 + 53);
+        assert_eq!(table.max_dynamic_size(), 4096);
+        let entries: Vec<_> = table.dynamic_entries().collect();
+        assert_eq!(entries, vec![(62, &b"cache-control"[..], &b"no-cache"[..]),
+                                 (63, &b":authority"[..], &b"www.example.com"[..])]);
+    }
+
+    #[test]
+    fn test_validate_fields() {
+        fn literal(name: &[u8], value: &[u8]) -> Vec<u8> {
+            let mut buf = vec![0x00, name.len() as u8];
+            buf.extend_from_slice(name);
+            buf.push(value.len() as u8);
+            buf.extend_from_slice(value);
+            buf
+        }
+        let mut decoder = Decoder::new();
+        let uppercase = literal(b"X-Custom", b"a");
+        assert_eq!(decoder.decode(&uppercase).unwrap().len(), 1);
+
+        decoder.set_validate_fields(true);
+        let err = decoder.decode(&uppercase).unwrap_err();
+        assert_eq!(err, DecoderError::MalformedField(FieldError::UppercaseName));
+        assert!(err.is_malformed() && !err.is_compression_error());
+        assert_eq!(decoder.decode(&literal(b"keep-alive", b"300")),
+                   Err(DecoderError::MalformedField(FieldError::ConnectionSpecific)));
+        assert_eq!(decoder.decode(&literal(b"te", b"gzip")),
+                   Err(DecoderError::MalformedField(FieldError::ConnectionSpecific)));
+        assert_eq!(decoder.decode(&literal(b"x-custom", b"a b")).unwrap().len(), 1);
+        for name in &[&b"x:y"[..], b"x y", b"\xc3\xa9", b":"] {
+            assert_eq!(decoder.decode(&literal(name, b"a")),
+                       Err(DecoderError::MalformedField(FieldError::InvalidName)));
+        }
+        for value in &[&b" a"[..], b"a\t", b"a\r\nb", b"a\0"] {
+            assert_eq!(decoder.decode(&literal(b"x", value)),
+                       Err(DecoderError::MalformedField(FieldError::InvalidValue)));
+        }
+
+        // :method GET, te: trailers, then :path / where it is too late for it.
+        let mut block = vec![0x82];
+        block.extend(literal(b"te", b"trailers"));
+        assert_eq!(decoder.decode(&block).unwrap().len(), 2);
+        block.push(0x84);
+        assert_eq!(decoder.decode(&block), Err(DecoderError::MalformedField(FieldError::PseudoAfterRegular)));
+
+        // The rest of a malformed block still goes into the table.
+        let mut block = uppercase.clone();
+        block.extend_from_slice(&[0x40, 1, b'a', 1, b'b']);
+        assert_eq!(decoder.decode(&block), Err(DecoderError::MalformedField(FieldError::UppercaseName)));
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![(b"a".to_vec(), b"b".to_vec())]);
+    }
+
+    #[test]
+    fn test_decode_iter() {
+        let mut decoder = Decoder::new();
+        // A size update, :method GET, an out of bounds index, then :path /.
+        let block = [0x3f, 0xe1, 0x1f, 0x82, 0x80 | 70, 0x84];
+        let mut iter = decoder.decode_iter(&block);
+        assert_eq!(iter.next(), Some(Ok((b":method".to_vec(), b"GET".to_vec()))));
+        assert_eq!(iter.next(), Some(Err(DecoderError::HeaderIndexOutOfBounds)));
+        assert_eq!(iter.next(), None);
+        drop(iter);
+
+        // Dropping it early still decodes the rest of the block, so the next one can refer to it.
+        let block = [0x40, 1, b'a', 1, b'b', 0x40, 1, b'c', 1, b'd'];
+        assert_eq!(decoder.decode_iter(&block).next(), Some(Ok((b"a".to_vec(), b"b".to_vec()))));
+        assert_eq!(decoder.header_table().dynamic_len(), 2);
+        assert_eq!(decoder.decode(&[0xbe, 0xbf]).unwrap(),
+                   vec![(b"c".to_vec(), b"d".to_vec()), (b"a".to_vec(), b"b".to_vec())]);
+        assert_eq!(decoder.decode_iter(&[]).next(), None);
+
+        // A broken limit is yielded once the block is decoded, as is one found by `skip_rest`.
+        decoder.set_max_header_count(Some(1));
+        let block = [0x82, 0x40, 1, b'e', 1, b'f', 0x84];
+        let mut iter = decoder.decode_iter(&block);
+        assert_eq!(iter.next(), Some(Ok((b":method".to_vec(), b"GET".to_vec()))));
+        assert_eq!(iter.next(), Some(Err(DecoderError::TooManyHeaders)));
+        assert_eq!(iter.next(), None);
+        drop(iter);
+        assert_eq!(decoder.header_table().dynamic_len(), 3);
+        let mut iter = decoder.decode_iter(&block);
+        assert!(iter.next().unwrap().is_ok());
+        assert_eq!(iter.skip_rest(), Err(DecoderError::TooManyHeaders));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_decode_borrowed() {
+        let mut decoder = Decoder::new();
+        // :method GET, then custom-key: custom-value indexed, then the same from the table,
+        // then a literal with a Huffman coded value (RFC 7541 C.4.1's www.example.com).
+        let mut block = vec![0x82, 0x40, 10];
+        block.extend_from_slice(b"custom-key");
+        block.push(12);
+        block.extend_from_slice(b"custom-value");
+        block.extend_from_slice(&[0x80 | 62, 0x01, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0,
+                                  0xab, 0x90, 0xf4, 0xff]);
+
+        let headers = decoder.decode_borrowed(&block).unwrap();
+        let borrowed: Vec<(bool, bool)> = headers.iter().map(|&(ref name, ref value)| {
+            let is_borrowed = |s: &Cow<[u8]>| match *s { Cow::Borrowed(_) => true, Cow::Owned(_) => false };
+            (is_borrowed(name), is_borrowed(value))
+        }).collect();
+        assert_eq!(borrowed, vec![(true, true), (true, true), (false, false), (true, false)]);
+        assert_eq!(&headers[2].1[..], b"custom-value");
+        assert_eq!((&headers[3].0[..], &headers[3].1[..]), (&b":authority"[..], &b"www.example.com"[..]));
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        // Large enough for `Bytes` not to store it inline.
+        let value = [b'v'; 40];
+        let mut block = vec![0x82, 0x00, 10];
+        block.extend_from_slice(b"custom-key");
+        block.push(40);
+        block.extend_from_slice(&value);
+        let block = Bytes::from(block);
+
+        let headers = Decoder::new().decode_bytes(&block).unwrap();
+        assert_eq!(headers, vec![(Bytes::from_static(b":method"), Bytes::from_static(b"GET")),
+                                 (Bytes::from_static(b"custom-key"), Bytes::from(&value[..]))]);
+        assert_eq!(headers[1].1.as_ptr(), block[14..].as_ptr());
     }
 }
@@ -9,15 +9,17 @@
 //! Encodes a header using a literal encoding.
 //!
 //! ```rust
-//! use hpack::Encoder;
+//! use tokio_http2::hpack::{Encoder, StringStrategy};
 //!
 //! let mut encoder = Encoder::new();
+//! // Plain string literals rather than the shorter Huffman code, to keep the result readable.
+//! encoder.set_string_strategy(StringStrategy::AlwaysPlain);
 //!
 //! let headers = vec![
 //!     (&b"custom-key"[..], &b"custom-value"[..]),
 //! ];
 //! // First encoding...
-//! let result = encoder.encode(headers);
+//! let result = encoder.encode(headers).unwrap();
 //! // The result is a literal encoding of the header name and value, with an
 //! // initial byte representing the type of the encoding
 //! // (incremental indexing).
@@ -32,7 +34,7 @@
 //! Encodes some pseudo-headers that are already found in the static table.
 //!
 //! ```rust
-//! use hpack::Encoder;
+//! use tokio_http2::hpack::Encoder;
 //!
 //! let mut encoder = Encoder::new();
 //! let headers = vec![
@@ -42,13 +44,23 @@
 //!
 //! // The headers are encoded by providing their index (with a bit flag
 //! // indicating that the indexed representation is used).
-//! assert_eq!(encoder.encode(headers), vec![2 | 0x80, 4 | 0x80]);
+//! assert_eq!(encoder.encode(headers).unwrap(), vec![2 | 0x80, 4 | 0x80]);
 //! ```
+use std::borrow::Cow;
 use std::io;
+use std::mem;
 use std::num::Wrapping;
 
 use super::STATIC_TABLE;
-use super::HeaderTable;
+use super::{entry_size, EvictionReason, HeaderTable};
+#[cfg(feature = "http")]
+use super::is_connection_specific;
+use super::huffman;
+
+use bytes::{Bytes, BytesMut};
+
+#[cfg(feature = "http")]
+use http_crate::HeaderMap;
 
 /// Encode an integer to the representation defined by HPACK, writing it into the provider
 /// `io::Write` instance. Also allows the caller to specify the leading bits of the first
@@ -60,7 +72,7 @@ use super::HeaderTable;
 /// # Example
 ///
 /// ```rust
-/// use hpack::encoder::encode_integer_into;
+/// use tokio_http2::hpack::encoder::encode_integer_into;
 ///
 /// {
 ///     // No bits specified in the 3 most significant bits of the first octet
@@ -90,7 +102,18 @@ use super::HeaderTable;
 /// }
 /// ```
 pub fn encode_integer_into<W: io::Write>(
-        mut value: usize,
+        value: usize,
+        prefix_size: u8,
+        leading_bits: u8,
+        writer: &mut W)
+        -> io::Result<()> {
+    encode_integer_u64_into(value as u64, prefix_size, leading_bits, writer)
+}
+
+/// Encodes a 64-bit integer like `encode_integer_into`, e.g. a QUIC stream ID; it takes up to
+/// eleven octets.
+pub fn encode_integer_u64_into<W: io::Write>(
+        mut value: u64,
         prefix_size: u8,
         leading_bits: u8,
         writer: &mut W)
@@ -103,7 +126,7 @@ pub fn encode_integer_into<W: io::Write>(
     // Clear any bits within the last `prefix_size` bits of the provided `leading_bits`.
     // Failing to do so might lead to an incorrect encoding of the integer.
     let leading_bits = leading_bits & (!mask);
-    let mask = mask as usize;
+    let mask = mask as u64;
     if value < mask {
         try!(writer.write_all(&[leading_bits | value as u8]));
         return Ok(());
@@ -142,15 +165,17 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 /// representations, due to the utilization of HPACK compression.
 ///
 /// ```rust
-/// use hpack::Encoder;
+/// use tokio_http2::hpack::{Encoder, StringStrategy};
 ///
 /// let mut encoder = Encoder::new();
+/// // Plain string literals, to keep the output readable.
+/// encoder.set_string_strategy(StringStrategy::AlwaysPlain);
 ///
 /// let headers = vec![
 ///     (b"custom-key".to_vec(), b"custom-value".to_vec()),
 /// ];
 /// // First encoding...
-/// let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+/// let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..]))).unwrap();
 /// // The result is a literal encoding of the header name and value, with an
 /// // initial byte representing the type of the encoding
 /// // (incremental indexing).
@@ -162,7 +187,7 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 ///     result);
 ///
 /// // Encode the same headers again!
-/// let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+/// let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..]))).unwrap();
 /// // The result is simply the index of the header in the header table (62),
 /// // with a flag representing that the decoder should use the index.
 /// assert_eq!(vec![0x80 | 62], result);
@@ -170,6 +195,220 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+    name_case: NameCase,
+    policy: Option<Box<EncodingPolicy + Send>>,
+    indexing: Box<IndexingPolicy + Send>,
+    strings: StringStrategy,
+    /// Dynamic table size updates owed to the decoder: the smallest size the table went through
+    /// since the last header block, and the final one.
+    size_update: Option<(usize, usize)>,
+    /// The table size asked for with `set_max_dynamic_table_size` and the peer's limit; the
+    /// table uses the smaller of the two.
+    preferred_table_size: usize,
+    peer_table_size: usize,
+    /// A SETTINGS_HEADER_TABLE_SIZE received but not acknowledged yet.
+    pending_peer_table_size: Option<usize>,
+    crumble_cookies: bool,
+    max_indexable_value_size: Option<usize>,
+    /// Full matches with more than this many dynamic table entries in front of them are
+    /// inserted again.
+    duplicate_after: Option<usize>,
+    /// Set by `stateless`.
+    stateless: bool,
+    stats: EncoderStats,
+    /// Reused by `encode_bytes`.
+    buf: BytesMut,
+}
+
+/// `io::Write` for a `BytesMut`, growing it as needed.
+struct BytesWriter<'b>(&'b mut BytesMut);
+
+impl<'b> io::Write for BytesWriter<'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Splits a `cookie` value into the individual cookies, at every "; ".
+fn crumbs(value: &[u8]) -> Vec<&[u8]> {
+    let mut crumbs = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i + 1 < value.len() {
+        if value[i] == b';' && value[i + 1] == b' ' {
+            crumbs.push(&value[start..i]);
+            start = i + 2;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    crumbs.push(&value[start..]);
+    crumbs
+}
+
+/// How a header field is represented in the header block (HPACK spec section 6).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Indexing {
+    /// An index into the header table when the whole header is there, otherwise a literal with
+    /// incremental indexing.
+    Indexed,
+    /// A literal added to the dynamic table.
+    Incremental,
+    /// A literal not added to the dynamic table.
+    WithoutIndexing,
+    /// A literal intermediaries must not add to a dynamic table either.
+    NeverIndexed,
+}
+
+/// The full choice of encoding for one header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Representation {
+    pub indexing: Indexing,
+    /// Whether a literal refers to the name by its index when the name is in the header table.
+    pub name_index: bool,
+    pub huffman_name: bool,
+    pub huffman_value: bool,
+}
+
+/// Chooses the representation of every header the encoder encodes. Interop tests use this to
+/// force the choices another implementation made, and reproduce its output byte for byte.
+pub trait EncodingPolicy {
+    /// `found` is what the header table lookup gave: the index of the matching header or name,
+    /// and whether the value matched too.
+    fn choose(&mut self, name: &[u8], value: &[u8], found: Option<(usize, bool)>) -> Representation;
+}
+
+impl<F> EncodingPolicy for F where F: FnMut(&[u8], &[u8], Option<(usize, bool)>) -> Representation {
+    fn choose(&mut self, name: &[u8], value: &[u8], found: Option<(usize, bool)>) -> Representation {
+        self(name, value, found)
+    }
+}
+
+/// Whether the Huffman code is the shorter representation of `octet_str`.
+fn huffman_shorter(octet_str: &[u8]) -> bool {
+    huffman::encoded_len(octet_str) < octet_str.len()
+}
+
+/// Decides whether headers go into the dynamic table, for encoders that otherwise keep their
+/// own strategy. Proxies that see many one-off values can index less to keep the table useful,
+/// clients talking to a single origin can index more.
+pub trait IndexingPolicy {
+    /// `found` is what the header table lookup gave, as for `EncodingPolicy::choose`.
+    /// `Indexing::Indexed` falls back to `Incremental` when the header isn't in the table.
+    fn indexing(&mut self, name: &[u8], value: &[u8], found: Option<(usize, bool)>) -> Indexing;
+}
+
+/// The encoder's default: the index of a header found in the table, otherwise a literal that is
+/// indexed only if the name wasn't found either.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DefaultIndexing;
+
+impl IndexingPolicy for DefaultIndexing {
+    fn indexing(&mut self, name: &[u8], value: &[u8], found: Option<(usize, bool)>) -> Indexing {
+        match found {
+            None => Indexing::Incremental,
+            Some((_, false)) => Indexing::WithoutIndexing,
+            Some((_, true)) => Indexing::Indexed,
+        }
+    }
+}
+
+/// How the encoder's own strategy encodes string literals.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StringStrategy {
+    AlwaysPlain,
+    AlwaysHuffman,
+    /// Whichever of the plain octets and the Huffman code is shorter (the default); plain on a
+    /// tie, as it is cheaper to decode.
+    Shortest,
+}
+
+impl Default for StringStrategy {
+    fn default() -> StringStrategy {
+        StringStrategy::Shortest
+    }
+}
+
+impl StringStrategy {
+    fn huffman(&self, octet_str: &[u8]) -> bool {
+        match *self {
+            StringStrategy::AlwaysPlain => false,
+            StringStrategy::AlwaysHuffman => true,
+            StringStrategy::Shortest => huffman_shorter(octet_str),
+        }
+    }
+}
+
+/// The encoder's own strategy, see `Encoder::encode`.
+fn default_representation(header: (&[u8], &[u8]), indexing: Indexing, strings: StringStrategy) -> Representation {
+    Representation {
+        indexing: indexing,
+        name_index: true,
+        huffman_name: strings.huffman(header.0),
+        huffman_value: strings.huffman(header.1),
+    }
+}
+
+/// How the encoder treats header names containing uppercase characters, which HTTP/2 forbids.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NameCase {
+    /// Lowercase the name before encoding it (the default), so that code ported from HTTP/1.1
+    /// doesn't emit protocol-invalid header blocks.
+    Lowercase,
+    /// Refuse the header with an `io::ErrorKind::InvalidInput` error, whichever of the
+    /// encoding methods it is passed to.
+    Strict,
+}
+
+impl Default for NameCase {
+    fn default() -> NameCase {
+        NameCase::Lowercase
+    }
+}
+
+/// What the encoder did so far, for tuning table sizes and the indexing policy (see
+/// `Encoder::stats`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncoderStats {
+    /// Headers passed to the encoder. A crumbled cookie counts once.
+    pub headers: u64,
+    /// Fields sent as a reference to a full match in the static table...
+    pub static_hits: u64,
+    /// ...or in the dynamic table.
+    pub dynamic_hits: u64,
+    /// Fields sent as a literal, whatever their indexing.
+    pub literals: u64,
+    /// Octets of header names and values passed to the encoder.
+    pub bytes_in: u64,
+    /// Octets of header blocks produced, dynamic table size updates included.
+    pub bytes_out: u64,
+    /// Current size of the dynamic table in octets, and its number of entries.
+    pub table_size: usize,
+    pub table_entries: usize,
+}
+
+/// `io::Write` counting what goes through it.
+struct CountingWriter<'w, W: 'w> {
+    inner: &'w mut W,
+    written: usize,
+}
+
+impl<'w, W: io::Write> io::Write for CountingWriter<'w, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl<'a> Encoder<'a> {
@@ -178,9 +417,183 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            name_case: NameCase::default(),
+            policy: None,
+            indexing: Box::new(DefaultIndexing),
+            strings: StringStrategy::default(),
+            size_update: None,
+            preferred_table_size: 4096,
+            peer_table_size: 4096,
+            pending_peer_table_size: None,
+            duplicate_after: None,
+            stateless: false,
+            stats: EncoderStats::default(),
+            crumble_cookies: false,
+            max_indexable_value_size: None,
+            buf: BytesMut::new(),
         }
     }
 
+    /// Creates an `Encoder` that never uses the dynamic table: every field is a literal without
+    /// indexing (or never indexed, if the `IndexingPolicy` or `EncodingPolicy` asks for it),
+    /// naming the static table entry when there is one, and no table size update is ever sent.
+    ///
+    /// Blocks it produces don't depend on what was encoded before, so the same block can be
+    /// sent on any number of connections, e.g. by an intermediary fanning one header set out.
+    pub fn stateless() -> Encoder<'a> {
+        let mut encoder = Encoder::new();
+        encoder.stateless = true;
+        encoder
+    }
+
+    /// Changes the maximum size of the dynamic table, evicting entries that no longer fit. The
+    /// decoder is told with a dynamic table size update at the start of the next header block.
+    ///
+    /// The size is capped by the peer's SETTINGS_HEADER_TABLE_SIZE, see
+    /// `set_peer_max_table_size`.
+    pub fn set_max_dynamic_table_size(&mut self, max_size: usize) {
+        self.preferred_table_size = max_size;
+        self.resize_table();
+    }
+
+    /// Takes in the SETTINGS_HEADER_TABLE_SIZE the peer announced. It only takes effect with
+    /// `ack_settings`, when the SETTINGS frame carrying it is acknowledged; a later value
+    /// received before then replaces it.
+    pub fn set_peer_max_table_size(&mut self, max_size: usize) {
+        self.pending_peer_table_size = Some(max_size);
+    }
+
+    /// Applies the SETTINGS_HEADER_TABLE_SIZE given to `set_peer_max_table_size`, to be called
+    /// as the SETTINGS frame is acknowledged: from then on the dynamic table never grows over
+    /// it, and it shrinks right away if it is currently larger. Either way the next header
+    /// block starts with a dynamic table size update if the size changed.
+    pub fn ack_settings(&mut self) {
+        if let Some(max_size) = self.pending_peer_table_size.take() {
+            self.peer_table_size = max_size;
+            self.resize_table();
+        }
+    }
+
+    /// The peer's SETTINGS_HEADER_TABLE_SIZE waiting for `ack_settings`, if any.
+    pub fn pending_peer_table_size(&self) -> Option<usize> {
+        self.pending_peer_table_size
+    }
+
+    /// The peer's SETTINGS_HEADER_TABLE_SIZE in effect, 4096 until one was acknowledged.
+    pub fn acknowledged_peer_table_size(&self) -> usize {
+        self.peer_table_size
+    }
+
+    /// Makes `observer` be called with every entry evicted from the encoder's dynamic table.
+    /// See `HeaderTable::set_eviction_observer`.
+    pub fn set_eviction_observer<F>(&mut self, observer: F)
+            where F: FnMut(&[u8], &[u8], EvictionReason) + Send + 'static {
+        self.header_table.set_eviction_observer(observer);
+    }
+
+    /// The encoder's header table, for inspecting the dynamic table.
+    pub fn header_table(&self) -> &HeaderTable<'a> {
+        &self.header_table
+    }
+
+    /// The maximum size of the dynamic table.
+    pub fn max_dynamic_table_size(&self) -> usize {
+        self.header_table.max_dynamic_size()
+    }
+
+    /// The current size of the dynamic table in octets, counting every entry as its name and
+    /// value lengths plus 32 octets. It never exceeds `max_dynamic_table_size`.
+    pub fn dynamic_table_size(&self) -> usize {
+        self.header_table.dynamic_size()
+    }
+
+    fn resize_table(&mut self) {
+        if self.stateless {
+            return;
+        }
+        let max_size = ::std::cmp::min(self.preferred_table_size, self.peer_table_size);
+        if max_size == self.header_table.max_dynamic_size() && self.size_update.is_none() {
+            return;
+        }
+        self.header_table.set_max_dynamic_size(max_size);
+        self.size_update = Some(match self.size_update {
+            Some((smallest, _)) if smallest < max_size => (smallest, max_size),
+            _ => (max_size, max_size),
+        });
+    }
+
+    /// Makes `policy` decide which headers are indexed; strings are still encoded as set with
+    /// `set_huffman`. An `EncodingPolicy` takes precedence.
+    pub fn set_indexing_policy<P>(&mut self, policy: P) where P: IndexingPolicy + Send + 'static {
+        self.indexing = Box::new(policy);
+    }
+
+    /// Splits `cookie` headers into one field per cookie (RFC 7540 section 8.1.2.5), so that
+    /// cookies which don't change between requests are indexed on their own. Decoders put
+    /// them back together, see `Decoder::set_join_cookies`.
+    pub fn set_crumble_cookies(&mut self, crumble_cookies: bool) {
+        self.crumble_cookies = crumble_cookies;
+    }
+
+    /// Headers with values longer than `max_size` octets are not added to the dynamic table, so
+    /// that one-off values (long URLs, tracing baggage) don't evict useful entries. Applies on
+    /// top of the `IndexingPolicy`, but not to an `EncodingPolicy`. `None` (the default) means no
+    /// limit.
+    pub fn set_max_indexable_value_size(&mut self, max_size: Option<usize>) {
+        self.max_indexable_value_size = max_size;
+    }
+
+    /// Makes headers found in the dynamic table behind more than `entries` newer entries be sent
+    /// as a literal and indexed again, rather than as a reference to the old entry. The few
+    /// octets this costs now keep a header that is still in use from being evicted as the
+    /// table fills up. Applies on top of the `IndexingPolicy`, but not to an `EncodingPolicy`.
+    /// `None` (the default) never duplicates entries.
+    pub fn set_duplicate_after(&mut self, entries: Option<usize>) {
+        self.duplicate_after = entries;
+    }
+
+    /// The counters since the encoder was created or `reset_stats` was called, along with the
+    /// current occupancy of the dynamic table.
+    pub fn stats(&self) -> EncoderStats {
+        EncoderStats {
+            table_size: self.header_table.dynamic_size(),
+            table_entries: self.header_table.dynamic_len(),
+            ..self.stats
+        }
+    }
+
+    /// Zeroes the counters of `stats`.
+    pub fn reset_stats(&mut self) {
+        self.stats = EncoderStats::default();
+    }
+
+    /// Sets how string literals are encoded; an `EncodingPolicy` makes its own choice
+    /// regardless of this setting.
+    pub fn set_string_strategy(&mut self, strings: StringStrategy) {
+        self.strings = strings;
+    }
+
+    /// Shorthand for `StringStrategy::Shortest` (`true`) or `StringStrategy::AlwaysPlain`.
+    pub fn set_huffman(&mut self, huffman: bool) {
+        self.strings = if huffman { StringStrategy::Shortest } else { StringStrategy::AlwaysPlain };
+    }
+
+    /// Makes `policy` choose the representation of every header from now on, instead of the
+    /// encoder's own strategy.
+    pub fn set_policy<P>(&mut self, policy: P) where P: EncodingPolicy + Send + 'static {
+        self.policy = Some(Box::new(policy));
+    }
+
+    /// Goes back to the encoder's own strategy.
+    pub fn clear_policy(&mut self) {
+        self.policy = None;
+    }
+
+    /// Sets how header names with uppercase characters are handled.
+    pub fn set_name_case(&mut self, name_case: NameCase) {
+        self.name_case = name_case;
+    }
+
     /// Encodes the given headers using the HPACK rules and returns a newly
     /// allocated `Vec` containing the bytes representing the encoded header
     /// set.
@@ -190,13 +603,31 @@ impl<'a> Encoder<'a> {
     /// already found in the header table and a literal otherwise. When a
     /// header isn't found in the table, it is added if the header name wasn't
     /// found either (i.e. there are never two header names with different
-    /// values in the produced header table). Strings are always encoded as
-    /// literals (Huffman encoding is not used).
-    pub fn encode<'b, I>(&mut self, headers: I) -> Vec<u8>
+    /// values in the produced header table). Strings are Huffman coded when
+    /// that is shorter, see `set_string_strategy`. An `IndexingPolicy` replaces the indexing part of this strategy, an
+    /// `EncodingPolicy` set with `set_policy` all of it.
+    ///
+    /// Writing into memory can't fail, so the only error is that of `NameCase::Strict`.
+    pub fn encode<'b, I>(&mut self, headers: I) -> io::Result<Vec<u8>>
             where I: IntoIterator<Item=(&'b [u8], &'b [u8])> {
         let mut encoded: Vec<u8> = Vec::new();
-        self.encode_into(headers, &mut encoded).unwrap();
-        encoded
+        try!(self.encode_into(headers, &mut encoded));
+        Ok(encoded)
+    }
+
+    /// Like `encode`, but writes into a buffer the encoder keeps and returns the block as
+    /// `Bytes` split off it, so encoding doesn't allocate once the buffer has grown large enough
+    /// and the blocks sent before it have been dropped.
+    pub fn encode_bytes<'b, I>(&mut self, headers: I) -> io::Result<Bytes>
+            where I: IntoIterator<Item=(&'b [u8], &'b [u8])> {
+        let mut buf = mem::replace(&mut self.buf, BytesMut::new());
+        if buf.capacity() - buf.len() < 1024 {
+            buf.reserve(4096);
+        }
+        let res = self.encode_into(headers, &mut BytesWriter(&mut buf));
+        let encoded = buf.take().freeze();
+        self.buf = buf;
+        res.map(|()| encoded)
     }
 
     /// Encodes the given headers into the given `io::Write` instance. If the io::Write raises an
@@ -212,7 +643,34 @@ impl<'a> Encoder<'a> {
         Ok(())
     }
 
-    /// Encodes a single given header into the given `io::Write` instance.
+    /// Encodes the fields of an `http::HeaderMap`, every value of a multi-valued header as a
+    /// field of its own. See `encode_header_map_into`.
+    #[cfg(feature = "http")]
+    pub fn encode_header_map(&mut self, headers: &HeaderMap) -> io::Result<Vec<u8>> {
+        let mut encoded: Vec<u8> = Vec::new();
+        try!(self.encode_header_map_into(&[], headers, &mut encoded));
+        Ok(encoded)
+    }
+
+    /// Encodes the pseudo-header fields `pseudo` (`:method`, `:status`, ...), which a
+    /// `HeaderMap` can't hold and which must come first, followed by the fields of `headers`.
+    /// Connection-specific fields and a `te` other than `trailers` are left out, as HTTP/2
+    /// requires.
+    #[cfg(feature = "http")]
+    pub fn encode_header_map_into<W: io::Write>(
+            &mut self,
+            pseudo: &[(&[u8], &[u8])],
+            headers: &HeaderMap,
+            writer: &mut W)
+            -> io::Result<()> {
+        let fields = headers.iter()
+            .filter(|&(name, value)| !is_connection_specific(name.as_str().as_bytes(), value.as_bytes()))
+            .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes()));
+        self.encode_into(pseudo.iter().cloned().chain(fields), writer)
+    }
+
+    /// Encodes a single given header into the given `io::Write` instance. A pending dynamic
+    /// table size update is written first, so this must start a new header block then.
     ///
     /// Any errors are propagated, similarly to the `encode_into` method, and it is the callers
     /// responsiblity to make sure that the paired encoder sees them too.
@@ -221,90 +679,160 @@ impl<'a> Encoder<'a> {
             header: (&[u8], &[u8]),
             writer: &mut W)
             -> io::Result<()> {
-        match self.header_table.find_header(header) {
+        let mut writer = CountingWriter { inner: writer, written: 0 };
+        let result = self.encode_header_counted(header, &mut writer);
+        self.stats.headers += 1;
+        self.stats.bytes_in += (header.0.len() + header.1.len()) as u64;
+        self.stats.bytes_out += writer.written as u64;
+        result
+    }
+
+    fn encode_header_counted<W: io::Write>(
+            &mut self,
+            header: (&[u8], &[u8]),
+            writer: &mut W)
+            -> io::Result<()> {
+        // Checked first, so that a refused header leaves a pending table size update pending.
+        let name = if header.0.iter().any(|&b| b >= b'A' && b <= b'Z') {
+            match self.name_case {
+                NameCase::Lowercase => Cow::Owned(header.0.to_ascii_lowercase()),
+                NameCase::Strict => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "header name contains uppercase characters"));
+                },
+            }
+        } else {
+            Cow::Borrowed(header.0)
+        };
+        let header = (&name[..], header.1);
+
+        if let Some((smallest, last)) = self.size_update.take() {
+            // A shrink followed by a grow must be signalled as both (HPACK spec section 4.2).
+            if smallest < last {
+                try!(encode_integer_into(smallest, 5, 0x20, writer));
+            }
+            try!(encode_integer_into(last, 5, 0x20, writer));
+        }
+
+        if self.crumble_cookies && header.0 == b"cookie" {
+            for crumb in crumbs(header.1) {
+                try!(self.encode_field((header.0, crumb), true, writer));
+            }
+            return Ok(());
+        }
+        self.encode_field(header, false, writer)
+    }
+
+    /// Encodes a header whose name was already validated. Cookie crumbs are indexed by the
+    /// default strategy even though the name is in the static table: that is their point.
+    fn encode_field<W: io::Write>(&mut self, header: (&[u8], &[u8]), crumb: bool, writer: &mut W)
+            -> io::Result<()> {
+        let found = self.header_table.find_header(header);
+        let representation = match self.policy {
+            Some(ref mut policy) => policy.choose(header.0, header.1, found),
             None => {
-                // The name of the header is in no tables: need to encode
-                // it with both a literal name and value.
-                try!(self.encode_literal(&header, true, writer));
-                self.header_table.add_header(header.0.to_vec(), header.1.to_vec());
-            },
-            Some((index, false)) => {
-                // The name of the header is at the given index, but the
-                // value does not match the current one: need to encode
-                // only the value as a literal.
-                try!(self.encode_indexed_name((index, header.1), false, writer));
+                let indexing = match self.indexing.indexing(header.0, header.1, found) {
+                    Indexing::WithoutIndexing if crumb => Indexing::Incremental,
+                    indexing => indexing,
+                };
+                // An entry larger than the whole table would only flush it.
+                let oversized = self.max_indexable_value_size.map_or(false, |max| header.1.len() > max) ||
+                    entry_size(header.0, header.1) > self.header_table.max_dynamic_size();
+                let indexing = match (indexing, found) {
+                    (Indexing::Indexed, Some((_, true))) => indexing,
+                    (Indexing::Indexed, _) | (Indexing::Incremental, _) if oversized => Indexing::WithoutIndexing,
+                    _ => indexing,
+                };
+                let static_len = self.header_table.static_table.len();
+                let indexing = match (indexing, found, self.duplicate_after) {
+                    (Indexing::Indexed, Some((index, true)), Some(after)) if index > static_len + 1 + after => {
+                        Indexing::Incremental
+                    },
+                    _ => indexing,
+                };
+                default_representation(header, indexing, self.strings)
             },
-            Some((index, true)) => {
+        };
+        let representation = if self.stateless {
+            Representation {
+                indexing: match representation.indexing {
+                    Indexing::NeverIndexed => Indexing::NeverIndexed,
+                    _ => Indexing::WithoutIndexing,
+                },
+                ..representation
+            }
+        } else {
+            representation
+        };
+
+        match (representation.indexing, found) {
+            (Indexing::Indexed, Some((index, true))) => {
                 // The full header was found in one of the tables, so we
                 // just encode the index.
                 try!(self.encode_indexed(index, writer));
+                if index <= self.header_table.static_table.len() {
+                    self.stats.static_hits += 1;
+                } else {
+                    self.stats.dynamic_hits += 1;
+                }
+            },
+            (indexing, found) => {
+                let name_index = match found {
+                    Some((index, _)) if representation.name_index => index,
+                    _ => 0,
+                };
+                try!(self.encode_literal(header, indexing, name_index, &representation, writer));
+                self.stats.literals += 1;
+                if indexing == Indexing::Indexed || indexing == Indexing::Incremental {
+                    self.header_table.add_header(header.0.to_vec(), header.1.to_vec());
+                }
             }
         };
         Ok(())
     }
 
-    /// Encodes a header as a literal (i.e. both the name and the value are
-    /// encoded as a string literal) and places the result in the given buffer
-    /// `buf`.
-    ///
-    /// # Parameters
-    ///
-    /// - `header` - the header to be encoded
-    /// - `should_index` - indicates whether the given header should be indexed, i.e.
-    ///                    inserted into the dynamic table
-    /// - `buf` - The buffer into which the result is placed
-    ///
+    /// Encodes a header as a literal and places the result in the given buffer `buf`. The name
+    /// is a reference to the header table when `name_index` isn't 0, and a string literal
+    /// otherwise.
     fn encode_literal<W: io::Write>(
             &mut self,
-            header: &(&[u8], &[u8]),
-            should_index: bool,
+            header: (&[u8], &[u8]),
+            indexing: Indexing,
+            name_index: usize,
+            representation: &Representation,
             buf: &mut W)
             -> io::Result<()> {
-        let mask = if should_index {
-            0x40
-        } else {
-            0x0
+        let (mask, prefix) = match indexing {
+            Indexing::Indexed | Indexing::Incremental => (0x40, 6),
+            Indexing::WithoutIndexing => (0x0, 4),
+            Indexing::NeverIndexed => (0x10, 4),
         };
 
-        try!(buf.write_all(&[mask]));
-        try!(self.encode_string_literal(&header.0, buf));
-        try!(self.encode_string_literal(&header.1, buf));
+        try!(encode_integer_into(name_index, prefix, mask, buf));
+        if name_index == 0 {
+            try!(self.encode_string_literal(header.0, representation.huffman_name, buf));
+        }
+        try!(self.encode_string_literal(header.1, representation.huffman_value, buf));
         Ok(())
     }
 
-    /// Encodes a string literal and places the result in the given buffer
-    /// `buf`.
-    ///
-    /// The function does not consider Huffman encoding for now, but always
-    /// produces a string literal representations, according to the HPACK spec
-    /// section 5.2.
+    /// Encodes a string literal, Huffman coded if `huffman` is set, and places the result in
+    /// the given buffer `buf` (HPACK spec section 5.2).
     fn encode_string_literal<W: io::Write>(
             &mut self,
             octet_str: &[u8],
+            huffman: bool,
             buf: &mut W)
             -> io::Result<()> {
-        try!(encode_integer_into(octet_str.len(), 7, 0, buf));
-        try!(buf.write_all(octet_str));
-        Ok(())
-    }
-
-    /// Encodes a header whose name is indexed and places the result in the
-    /// given buffer `buf`.
-    fn encode_indexed_name<W: io::Write>(
-            &mut self,
-            header: (usize, &[u8]),
-            should_index: bool,
-            buf: &mut W)
-            -> io::Result<()> {
-        let (mask, prefix) = if should_index {
-            (0x40, 6)
+        if huffman {
+            let mut encoded = Vec::with_capacity(huffman::encoded_len(octet_str));
+            huffman::encode(octet_str, &mut encoded);
+            try!(encode_integer_into(encoded.len(), 7, 0x80, buf));
+            try!(buf.write_all(&encoded));
         } else {
-            (0x0, 4)
-        };
-
-        try!(encode_integer_into(header.0, prefix, mask, buf));
-        // So far, we rely on just one strategy for encoding string literals.
-        try!(self.encode_string_literal(&header.1, buf));
+            try!(encode_integer_into(octet_str.len(), 7, 0, buf));
+            try!(buf.write_all(octet_str));
+        }
         Ok(())
     }
 
@@ -319,3 +847,357 @@ impl<'a> Encoder<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::{Encoder, Indexing, IndexingPolicy, NameCase, Representation, StringStrategy};
+    use hpack::{static_name_range, Decoder, EvictionReason, STATIC_TABLE};
+
+    #[test]
+    fn test_name_case() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let result = encoder.encode(vec![(&b"Content-Type"[..], &b"Text/Plain"[..])]).unwrap();
+        assert_eq!(decoder.decode(&result).unwrap(),
+                   vec![(b"content-type".to_vec(), b"Text/Plain".to_vec())]);
+
+        // Every entry point refuses the name when strict.
+        encoder.set_name_case(NameCase::Strict);
+        let mut buf = Vec::new();
+        let err = encoder.encode_into(vec![(&b"X-Custom"[..], &b"a"[..])], &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+        let err = encoder.encode(vec![(&b"X-Custom"[..], &b"a"[..])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = encoder.encode_bytes(vec![(&b"X-Custom"[..], &b"a"[..])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = encoder.encode_header_into((b"X-Custom", b"a"), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        // Lowercase names still go through, and nothing refused was indexed.
+        let result = encoder.encode_bytes(vec![(&b"x-custom"[..], &b"a"[..])]).unwrap();
+        assert_eq!(decoder.decode(&result).unwrap(), vec![(b"x-custom".to_vec(), b"a".to_vec())]);
+        assert_eq!(encoder.encode(vec![(&b"x-custom"[..], &b"a"[..])]).unwrap(), vec![0xbe]);
+    }
+
+    #[test]
+    fn test_policy() {
+        // RFC 7541 C.4.1: the :authority value is Huffman coded and indexed, which the
+        // encoder's own strategy would do neither of.
+        let mut encoder = Encoder::new();
+        encoder.set_policy(|_: &[u8], _: &[u8], found: Option<(usize, bool)>| Representation {
+            indexing: if found.map_or(false, |f| f.1) { Indexing::Indexed } else { Indexing::Incremental },
+            name_index: true,
+            huffman_name: true,
+            huffman_value: true,
+        });
+        let headers = vec![(&b":method"[..], &b"GET"[..]), (b":scheme", b"http"), (b":path", b"/"),
+                           (b":authority", b"www.example.com")];
+        let result = encoder.encode(headers.clone()).unwrap();
+        assert_eq!(result, vec![0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a,
+                                0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff]);
+        assert_eq!(Decoder::new().decode(&result).unwrap().len(), 4);
+
+        // Never indexed with a literal name, raw strings.
+        let mut encoder = Encoder::new();
+        encoder.set_policy(|_: &[u8], _: &[u8], _: Option<(usize, bool)>| Representation {
+            indexing: Indexing::NeverIndexed,
+            name_index: false,
+            huffman_name: false,
+            huffman_value: false,
+        });
+        assert_eq!(encoder.encode(vec![(&b"a"[..], &b"b"[..])]).unwrap(), vec![0x10, 1, b'a', 1, b'b']);
+        assert_eq!(encoder.encode(vec![(&b"a"[..], &b"b"[..])]).unwrap(), vec![0x10, 1, b'a', 1, b'b']);
+    }
+
+    #[test]
+    fn test_huffman() {
+        let mut encoder = Encoder::new();
+        encoder.set_huffman(true);
+        // The name is shorter Huffman coded, the value isn't.
+        let result = encoder.encode(vec![(&b"custom-key"[..], &b"\x00"[..])]).unwrap();
+        assert_eq!(result[1], 0x80 | 8);
+        assert_eq!(&result[10..], &[1, 0]);
+        assert_eq!(Decoder::new().decode(&result).unwrap(), vec![(b"custom-key".to_vec(), vec![0])]);
+    }
+
+    #[test]
+    fn test_size_update() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let header = vec![(&b"custom-key"[..], &b"custom-value"[..])];
+        decoder.decode(&encoder.encode(header.clone()).unwrap()).unwrap();
+
+        encoder.set_max_dynamic_table_size(0);
+        encoder.set_max_dynamic_table_size(256);
+        let result = encoder.encode(header.clone()).unwrap();
+        // 0, then 256 (0x20 | 31, 225): the table was emptied and the header is a new literal.
+        assert_eq!(&result[..4], &[0x20, 0x3f, 0xe1, 0x01]);
+        assert_eq!(result[4], 0x40);
+        assert_eq!(decoder.decode(&result).unwrap().len(), 1);
+        assert_eq!(encoder.max_dynamic_table_size(), 256);
+        assert_eq!(encoder.encode(header).unwrap(), vec![0x80 | 62]);
+    }
+
+    #[test]
+    fn test_indexing_policy() {
+        struct NeverIndexAuthorization;
+        impl IndexingPolicy for NeverIndexAuthorization {
+            fn indexing(&mut self, name: &[u8], _: &[u8], found: Option<(usize, bool)>) -> Indexing {
+                match found {
+                    _ if name == b"authorization" => Indexing::NeverIndexed,
+                    Some((_, true)) => Indexing::Indexed,
+                    _ => Indexing::Incremental,
+                }
+            }
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.set_string_strategy(StringStrategy::AlwaysPlain);
+        encoder.set_indexing_policy(NeverIndexAuthorization);
+        let headers = vec![(&b"authorization"[..], &b"secret"[..]), (b"accept", b"text/html")];
+        let result = encoder.encode(headers.clone()).unwrap();
+        // authorization is static index 23, accept 19.
+        assert_eq!(&result[..2], &[0x10 | 15, 23 - 15]);
+        assert_eq!(result[9], 0x40 | 19);
+        assert_eq!(encoder.encode(headers).unwrap(), vec![0x10 | 15, 23 - 15, 6, b's', b'e', b'c', b'r', b'e', b't', 0x80 | 62]);
+    }
+
+    #[test]
+    fn test_peer_table_size() {
+        let mut encoder = Encoder::new();
+        encoder.encode(vec![(&b"custom-key"[..], &b"custom-value"[..])]).unwrap();
+        encoder.set_peer_max_table_size(100);
+        // Nothing changes until the SETTINGS is acknowledged.
+        assert_eq!(encoder.max_dynamic_table_size(), 4096);
+        assert_eq!(encoder.pending_peer_table_size(), Some(100));
+        assert_eq!(encoder.encode(vec![(&b"custom-key"[..], &b"custom-value"[..])]).unwrap(), vec![0x80 | 62]);
+        encoder.ack_settings();
+        assert_eq!((encoder.pending_peer_table_size(), encoder.acknowledged_peer_table_size()), (None, 100));
+        assert_eq!(encoder.max_dynamic_table_size(), 100);
+        // Asking for more than the peer allows is capped.
+        encoder.set_max_dynamic_table_size(8192);
+        assert_eq!(encoder.max_dynamic_table_size(), 100);
+        assert_eq!(&encoder.encode(vec![(&b"a"[..], &b"b"[..])]).unwrap()[..2], &[0x20 | 31, 100 - 31]);
+        // Unchanged: no further size update.
+        encoder.set_peer_max_table_size(100);
+        encoder.ack_settings();
+        assert_eq!(encoder.encode(vec![(&b"a"[..], &b"b"[..])]).unwrap(), vec![0x80 | 62]);
+    }
+
+    #[test]
+    fn test_duplicate_after() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        encoder.set_string_strategy(StringStrategy::AlwaysPlain);
+        encoder.set_duplicate_after(Some(1));
+        for name in &[&b"x-a"[..], b"x-b", b"x-c"] {
+            decoder.decode(&encoder.encode(vec![(*name, &b"1"[..])]).unwrap()).unwrap();
+        }
+        // x-a is at 64, behind two newer entries: it goes in again, named by its old index.
+        let result = encoder.encode(vec![(&b"x-a"[..], &b"1"[..])]).unwrap();
+        assert_eq!(result, vec![0x40 | 63, 64 - 63, 1, b'1']);
+        assert_eq!(decoder.decode(&result).unwrap(), vec![(b"x-a".to_vec(), b"1".to_vec())]);
+        assert_eq!(encoder.encode(vec![(&b"x-a"[..], &b"1"[..])]).unwrap(), vec![0x80 | 62]);
+        // x-c is at 63, behind a single entry.
+        assert_eq!(encoder.encode(vec![(&b"x-c"[..], &b"1"[..])]).unwrap(), vec![0x80 | 63]);
+    }
+
+    #[test]
+    fn test_stateless() {
+        let mut encoder = Encoder::stateless();
+        encoder.set_string_strategy(StringStrategy::AlwaysPlain);
+        encoder.set_max_dynamic_table_size(0);
+        let headers = vec![(&b":method"[..], &b"GET"[..]), (b"custom-key", b"custom-value")];
+        let result = encoder.encode(headers.clone()).unwrap();
+        // :method is static index 2; no size update.
+        assert_eq!(&result[..5], &[0x02, 3, b'G', b'E', b'T']);
+        assert_eq!(result[5], 0x00);
+        assert_eq!(encoder.encode(headers.clone()).unwrap(), result);
+        assert_eq!(encoder.header_table().dynamic_len(), 0);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&result).unwrap().len(), 2);
+        assert_eq!(decoder.header_table().dynamic_len(), 0);
+    }
+
+    #[test]
+    fn test_eviction_observer() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let log = evicted.clone();
+        encoder.set_eviction_observer(move |name, value, reason| {
+            log.lock().unwrap().push(("encoder", name.to_vec(), value.to_vec(), reason))
+        });
+        let log = evicted.clone();
+        decoder.set_eviction_observer(move |name, _, reason| {
+            log.lock().unwrap().push(("decoder", name.to_vec(), Vec::new(), reason))
+        });
+
+        // 54 octets each: the second evicts the first from a 100 octet table.
+        encoder.set_max_dynamic_table_size(100);
+        for name in &[&b"x-first-key"[..], b"x-other-key"] {
+            decoder.decode(&encoder.encode(vec![(*name, &b"hello world"[..])]).unwrap()).unwrap();
+        }
+        encoder.set_max_dynamic_table_size(0);
+        decoder.decode(&encoder.encode(vec![(&b":method"[..], &b"GET"[..])]).unwrap()).unwrap();
+
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            ("encoder", b"x-first-key".to_vec(), b"hello world".to_vec(), EvictionReason::SizeLimit),
+            ("decoder", b"x-first-key".to_vec(), vec![], EvictionReason::SizeLimit),
+            ("encoder", b"x-other-key".to_vec(), b"hello world".to_vec(), EvictionReason::Resize),
+            ("decoder", b"x-other-key".to_vec(), vec![], EvictionReason::Resize),
+        ]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut encoder = Encoder::new();
+        encoder.set_string_strategy(StringStrategy::AlwaysPlain);
+        let headers = vec![(&b":method"[..], &b"GET"[..]), (b"custom-key", b"custom-value")];
+        assert_eq!(encoder.encode(headers.clone()).unwrap().len(), 1 + 25);
+        assert_eq!(encoder.encode(headers).unwrap().len(), 2);
+
+        let stats = encoder.stats();
+        assert_eq!((stats.headers, stats.static_hits, stats.dynamic_hits, stats.literals), (4, 2, 1, 1));
+        assert_eq!((stats.bytes_in, stats.bytes_out), (2 * (7 + 3 + 10 + 12), 28));
+        assert_eq!((stats.table_size, stats.table_entries), (54, 1));
+
+        encoder.reset_stats();
+        assert_eq!(encoder.stats().headers, 0);
+        assert_eq!(encoder.stats().table_entries, 1);
+    }
+
+    #[test]
+    fn test_dynamic_table_size() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        encoder.set_max_dynamic_table_size(100);
+        // 10 + 12 + 32 = 54 octets, so a second one evicts the first.
+        for value in &[&b"custom-value"[..], b"other-value!"] {
+            decoder.decode(&encoder.encode(vec![(&b"custom-key"[..], *value)]).unwrap()).unwrap();
+            assert_eq!(encoder.dynamic_table_size(), 54);
+            assert_eq!(decoder.dynamic_table_size(), 54);
+        }
+        assert_eq!(encoder.header_table().dynamic_len(), 1);
+
+        // An entry that can't fit isn't indexed at all rather than flushing the table: a literal
+        // without indexing, whose 0000 pattern leaves the rest of the first octet to the name index.
+        let value = [b'x'; 80];
+        assert_eq!(encoder.encode(vec![(&b"custom-key"[..], &value[..])]).unwrap()[0], 15);
+        assert_eq!(encoder.dynamic_table_size(), 54);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_header_map() {
+        use http_crate::HeaderMap;
+        use http_crate::header::{HeaderValue, ACCEPT, CONNECTION, TE};
+
+        let mut headers = HeaderMap::new();
+        headers.append(ACCEPT, HeaderValue::from_static("text/html"));
+        headers.append(ACCEPT, HeaderValue::from_static("*/*"));
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        headers.insert(TE, HeaderValue::from_static("gzip"));
+
+        let mut encoded = Vec::new();
+        let pseudo = [(&b":method"[..], &b"GET"[..]), (b":path", b"/")];
+        Encoder::new().encode_header_map_into(&pseudo, &headers, &mut encoded).unwrap();
+        assert_eq!(Decoder::new().decode(&encoded).unwrap(),
+                   vec![(b":method".to_vec(), b"GET".to_vec()), (b":path".to_vec(), b"/".to_vec()),
+                        (b"accept".to_vec(), b"text/html".to_vec()), (b"accept".to_vec(), b"*/*".to_vec())]);
+
+        // A `HeaderName` is lowercase, so only the pseudo-header fields can be refused when strict.
+        let mut encoder = Encoder::new();
+        encoder.set_name_case(NameCase::Strict);
+        let encoded = encoder.encode_header_map(&headers).unwrap();
+        assert_eq!(Decoder::new().decode(&encoded).unwrap().len(), 2);
+        let pseudo = [(&b":Method"[..], &b"GET"[..])];
+        let err = encoder.encode_header_map_into(&pseudo, &headers, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_crumble_cookies() {
+        let mut encoder = Encoder::new();
+        encoder.set_crumble_cookies(true);
+        let mut decoder = Decoder::new();
+        decoder.set_join_cookies(true);
+
+        let cookie = vec![(&b"cookie"[..], &b"session=1; theme=dark"[..])];
+        let first = encoder.encode(cookie.clone()).unwrap();
+        assert_eq!(decoder.decode(&first).unwrap(), vec![(b"cookie".to_vec(), b"session=1; theme=dark".to_vec())]);
+
+        // Only the cookie that changed is sent as a literal; theme=dark moved to 63 when
+        // session=2 was added.
+        let result = encoder.encode(vec![(&b"cookie"[..], &b"session=2; theme=dark"[..])]).unwrap();
+        assert_eq!(*result.last().unwrap(), 0x80 | 63);
+        assert_eq!(decoder.decode(&result).unwrap(), vec![(b"cookie".to_vec(), b"session=2; theme=dark".to_vec())]);
+    }
+
+    #[test]
+    fn test_encode_bytes() {
+        let mut encoder = Encoder::new();
+        encoder.set_string_strategy(StringStrategy::AlwaysPlain);
+        let headers = vec![(&b"custom-key"[..], &b"custom-value"[..])];
+        let first = encoder.encode_bytes(headers.clone()).unwrap();
+        assert_eq!(first.len(), 25);
+        assert_eq!(&encoder.encode_bytes(headers).unwrap()[..], &[0x80 | 62]);
+        // Later blocks don't clobber earlier ones.
+        assert_eq!(first[0], 0x40);
+    }
+
+    #[test]
+    fn test_max_indexable_value_size() {
+        let mut encoder = Encoder::new();
+        encoder.set_max_indexable_value_size(Some(8));
+        let long = vec![(&b"x-trace"[..], &b"0123456789"[..])];
+        assert_eq!(encoder.encode(long.clone()).unwrap()[0], 0x00);
+        assert_eq!(encoder.encode(long).unwrap()[0], 0x00);
+        assert_eq!(encoder.encode(vec![(&b"x-short"[..], &b"01234567"[..])]).unwrap()[0], 0x40);
+    }
+
+    #[test]
+    fn test_string_strategy() {
+        let header = vec![(&b"custom-key"[..], &b"custom-value"[..])];
+        let lens: Vec<usize> = [StringStrategy::AlwaysPlain, StringStrategy::AlwaysHuffman, StringStrategy::Shortest]
+            .iter()
+            .map(|&strings| {
+                let mut encoder = Encoder::new();
+                encoder.set_string_strategy(strings);
+                encoder.encode(header.clone()).unwrap().len()
+            })
+            .collect();
+        assert_eq!(lens, vec![25, 20, 20]);
+
+        // 0xff takes 8 bits plus padding when Huffman coded.
+        let mut encoder = Encoder::new();
+        assert_eq!(encoder.encode(vec![(&b"a"[..], &[0xff][..])]).unwrap(), vec![0x40, 1, b'a', 1, 0xff]);
+    }
+
+    #[test]
+    fn test_static_lookup() {
+        for (i, &(name, _)) in STATIC_TABLE.iter().enumerate() {
+            let (first, last) = static_name_range(name).unwrap();
+            assert!(first <= i + 1 && i + 1 <= last);
+            assert!(STATIC_TABLE[first - 1..last].iter().all(|h| h.0 == name));
+        }
+        assert_eq!(static_name_range(b"x-custom"), None);
+
+        let encoder = Encoder::new();
+        assert_eq!(encoder.header_table().find_header((b":status", b"404")), Some((13, true)));
+        assert_eq!(encoder.header_table().find_header((b":status", b"418")), Some((14, false)));
+        assert_eq!(encoder.header_table().find_header((b"content-type", b"text/plain")), Some((31, false)));
+
+        // Once the dynamic table has the name it is preferred, and a full match in it wins.
+        let mut decoder = Decoder::new();
+        decoder.decode(b"\x5f\x0atext/plain").unwrap();
+        assert_eq!(decoder.header_table().find_header((b"content-type", b"text/plain")), Some((62, true)));
+        assert_eq!(decoder.header_table().find_header((b"content-type", b"text/html")), Some((62, false)));
+    }
+}
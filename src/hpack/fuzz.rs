@@ -0,0 +1,154 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Entry points for fuzz targets (e.g. with cargo-fuzz). Each function panics when the codec
+//! misbehaves, which is what the fuzzer looks for, and returns normally on input that is
+//! merely invalid.
+//!
+//! With the `arbitrary` feature, `HeaderList` implements `arbitrary::Arbitrary`, so a target
+//! can be as short as:
+//!
+//! ```rust,ignore
+//! fuzz_target!(|input: (u16, Vec<HeaderList>)| {
+//!     tokio_http2::hpack::fuzz::round_trip(input.0 as usize, &input.1);
+//! });
+//! ```
+
+#[cfg(feature = "arbitrary")]
+use arbitrary::{self, Arbitrary, Unstructured};
+
+use super::decoder::{Decoder, DecoderResult};
+use super::encoder::Encoder;
+use super::huffman::{self, HuffmanDecoder};
+#[cfg(feature = "arbitrary")]
+use super::STATIC_TABLE;
+
+/// A header list for the encoder. Generated ones mix static table entries, static table names
+/// with other values and arbitrary fields, so that fuzzing reaches every representation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderList(pub Vec<(Vec<u8>, Vec<u8>)>);
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for HeaderList {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<HeaderList> {
+        let len = try!(u.arbitrary_len::<(Vec<u8>, Vec<u8>)>());
+        let mut headers = Vec::with_capacity(len);
+        for _ in 0..len {
+            let header = match try!(u.int_in_range(0u8..=2)) {
+                0 => {
+                    let &(name, value) = try!(u.choose(STATIC_TABLE));
+                    (name.to_vec(), value.to_vec())
+                },
+                1 => {
+                    let &(name, _) = try!(u.choose(STATIC_TABLE));
+                    (name.to_vec(), try!(Vec::<u8>::arbitrary(u)))
+                },
+                _ => (try!(Vec::<u8>::arbitrary(u)), try!(Vec::<u8>::arbitrary(u))),
+            };
+            headers.push(header);
+        }
+        Ok(HeaderList(headers))
+    }
+}
+
+/// Encodes `lists` in turn, on one connection with a dynamic table of up to `max_table_size`
+/// octets, and decodes them. Panics unless each decodes to the headers given, with the names
+/// lowercased as the encoder does by default.
+pub fn round_trip(max_table_size: usize, lists: &[HeaderList]) {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+    encoder.set_peer_max_table_size(max_table_size);
+    encoder.ack_settings();
+    encoder.set_max_dynamic_table_size(max_table_size);
+    decoder.set_max_allowed_table_size(max_table_size);
+
+    for list in lists {
+        let block = encoder.encode(list.0.iter().map(|&(ref n, ref v)| (&n[..], &v[..]))).unwrap();
+        let expected: Vec<_> = list.0.iter().map(|&(ref n, ref v)| (n.to_ascii_lowercase(), v.clone())).collect();
+        match decoder.decode(&block) {
+            Ok(headers) => assert_eq!(headers, expected),
+            Err(e) => panic!("decoding an encoded block failed: {}", e),
+        }
+        assert_eq!(encoder.dynamic_table_size(), decoder.dynamic_table_size());
+    }
+}
+
+/// Decodes `buf` as a header block with every decoding API of a fresh `Decoder` and returns
+/// what `Decoder::decode` did. Panics if the APIs disagree.
+pub fn decode(buf: &[u8]) -> DecoderResult {
+    let result = Decoder::new().decode(buf);
+
+    let borrowed = Decoder::new().decode_borrowed(buf)
+        .map(|headers| headers.into_iter().map(|(n, v)| (n.into_owned(), v.into_owned())).collect());
+    assert_eq!(borrowed, result);
+
+    let iterated: DecoderResult = Decoder::new().decode_iter(buf).collect();
+    assert_eq!(iterated, result);
+
+    // In two fragments; a block cut short only fails in `finish`.
+    let mut decoder = Decoder::new();
+    let (first, second) = buf.split_at(buf.len() / 2);
+    let fragmented = decoder.decode_fragment(first).and_then(|mut headers| {
+        headers.extend(try!(decoder.decode_fragment(second)));
+        try!(decoder.finish());
+        Ok(headers)
+    });
+    match result {
+        Ok(ref headers) => assert_eq!(fragmented.as_ref(), Ok(headers)),
+        Err(_) => assert!(fragmented.is_err()),
+    }
+
+    result
+}
+
+/// Huffman decodes `buf` and, if that works, checks that encoding the result again decodes to
+/// the same string. Also checks `buf` itself survives a round trip.
+pub fn huffman(buf: &[u8]) {
+    let mut decoder = HuffmanDecoder::new();
+    if let Ok(decoded) = decoder.decode(buf) {
+        let mut encoded = Vec::new();
+        huffman::encode(&decoded, &mut encoded);
+        assert_eq!(encoded.len(), huffman::encoded_len(&decoded));
+        assert_eq!(decoder.decode(&encoded), Ok(decoded));
+    }
+    let mut encoded = Vec::new();
+    huffman::encode(buf, &mut encoded);
+    assert_eq!(decoder.decode(&encoded), Ok(buf.to_vec()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, huffman, round_trip, HeaderList};
+
+    #[test]
+    fn test_fuzz_helpers() {
+        let list = HeaderList(vec![(b":method".to_vec(), b"GET".to_vec()),
+                                   (b"X-Custom".to_vec(), b"value".to_vec()),
+                                   (b"x-custom".to_vec(), b"value".to_vec()),
+                                   (b"x-long".to_vec(), vec![b'v'; 100])]);
+        round_trip(4096, &[list.clone(), list.clone(), HeaderList::default()]);
+        round_trip(64, &[list.clone(), list]);
+
+        // RFC 7541 C.3.1, whole and cut short, then an index out of bounds.
+        let block = [0x82, 0x86, 0x84, 0x41, 0x0f, b'w', b'w', b'w', b'.', b'e', b'x', b'a',
+                     b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm'];
+        assert_eq!(decode(&block).unwrap().len(), 4);
+        assert!(decode(&block[..10]).is_err());
+        assert!(decode(&[0x82, 0x80 | 70]).is_err());
+
+        huffman(&[0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff]);
+        huffman(&[0xff, 0xff, 0xff, 0xfc]);
+        huffman(&[]);
+    }
+}
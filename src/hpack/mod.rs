@@ -30,12 +30,34 @@ use std::collections::VecDeque;
 use std::collections::vec_deque;
 
 // Re-export the main HPACK API entry points.
-pub use self::decoder::Decoder;
-pub use self::encoder::Encoder;
+pub use self::decoder::{Decoder, DecoderError, FieldError};
+pub use self::encoder::{DefaultIndexing, Encoder, EncoderStats, EncodingPolicy, Indexing, IndexingPolicy,
+                        NameCase, Representation, StringStrategy};
 
 pub mod encoder;
 pub mod decoder;
 pub mod huffman;
+pub mod fuzz;
+
+/// The octets the HPACK spec charges for every table entry on top of its name
+/// and value, an estimate of the cost of storing it (section 4.1).
+pub const ENTRY_OVERHEAD: usize = 32;
+
+/// The size of an entry in the dynamic table, which is also how each field
+/// counts towards SETTINGS_MAX_HEADER_LIST_SIZE.
+pub fn entry_size(name: &[u8], value: &[u8]) -> usize {
+    name.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// Connection-specific fields, which HTTP/2 forbids (RFC 7540 section 8.1.2.2).
+const CONNECTION_SPECIFIC: &'static [&'static [u8]] = &[b"connection", b"keep-alive", b"proxy-connection",
+                                                         b"transfer-encoding", b"upgrade"];
+
+/// Whether the (lowercase) field is connection-specific and so not allowed in HTTP/2: one of
+/// the fields above, or a `te` field with a value other than "trailers".
+pub fn is_connection_specific(name: &[u8], value: &[u8]) -> bool {
+    CONNECTION_SPECIFIC.contains(&name) || (name == b"te" && !value.eq_ignore_ascii_case(b"trailers"))
+}
 
 /// An `Iterator` through elements of the `DynamicTable`.
 ///
@@ -62,6 +84,19 @@ impl<'a> Iterator for DynamicTableIter<'a> {
     }
 }
 
+/// Why an entry was evicted from the dynamic table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    /// A new entry needed the room.
+    SizeLimit,
+    /// The maximum size of the table was lowered.
+    Resize,
+}
+
+/// Called with every entry evicted from a dynamic table, see
+/// `HeaderTable::set_eviction_observer`.
+pub type EvictionObserver = Box<FnMut(&[u8], &[u8], EvictionReason) + Send>;
+
 /// A struct representing the dynamic table that needs to be maintained by the
 /// coder.
 ///
@@ -91,6 +126,7 @@ struct DynamicTable {
     table: VecDeque<(Vec<u8>, Vec<u8>)>,
     size: usize,
     max_size: usize,
+    observer: Option<EvictionObserver>,
 }
 
 impl DynamicTable {
@@ -107,6 +143,7 @@ impl DynamicTable {
             table: VecDeque::new(),
             size: 0,
             max_size: max_size,
+            observer: None,
         }
     }
 
@@ -137,7 +174,7 @@ impl DynamicTable {
     fn set_max_table_size(&mut self, new_max_size: usize) {
         self.max_size = new_max_size;
         // Make the table size fit within the new constraints.
-        self.consolidate_table();
+        self.consolidate_table(EvictionReason::Resize);
     }
 
     /// Returns the maximum size of the table in octets.
@@ -154,35 +191,32 @@ impl DynamicTable {
     /// case the total size of the given header exceeds the maximum size of the
     /// dynamic table.
     fn add_header(&mut self, name: Vec<u8>, value: Vec<u8>) {
-        // This is how the HPACK spec makes us calculate the size.  The 32 is
-        // a magic number determined by them (under reasonable assumptions of
-        // how the table is stored).
-        self.size += name.len() + value.len() + 32;
+        self.size += entry_size(&name, &value);
         // debug!("New dynamic table size {}", self.size);
         // Now add it to the internal buffer
         self.table.push_front((name, value));
         // ...and make sure we're not over the maximum size.
-        self.consolidate_table();
+        self.consolidate_table(EvictionReason::SizeLimit);
         // debug!("After consolidation dynamic table size {}", self.size);
     }
 
     /// Consolidates the table entries so that the table size is below the
     /// maximum allowed size, by evicting headers from the table in a FIFO
-    /// fashion.
-    fn consolidate_table(&mut self) {
+    /// fashion. The observer, if any, is told the `reason`.
+    fn consolidate_table(&mut self, reason: EvictionReason) {
         while self.size > self.max_size {
-            {
-                let last_header = match self.table.back() {
-                    Some(x) => x,
-                    None => {
-                        // Can never happen as the size of the table must reach
-                        // 0 by the time we've exhausted all elements.
-                        panic!("Size of table != 0, but no headers left!");
-                    }
-                };
-                self.size -= last_header.0.len() + last_header.1.len() + 32;
+            let last_header = match self.table.pop_back() {
+                Some(x) => x,
+                None => {
+                    // Can never happen as the size of the table must reach
+                    // 0 by the time we've exhausted all elements.
+                    panic!("Size of table != 0, but no headers left!");
+                }
+            };
+            self.size -= entry_size(&last_header.0, &last_header.1);
+            if let Some(ref mut observer) = self.observer {
+                observer(&last_header.0, &last_header.1, reason);
             }
-            self.table.pop_back();
         }
     }
 
@@ -231,7 +265,7 @@ type StaticTable<'a> = &'a [(&'a [u8], &'a [u8])];
 /// The declaration of the inner iterator that is wrapped by this struct is a
 /// monstrosity, that is required because "abstract return types" don't exist
 /// yet ([https://github.com/rust-lang/rfcs/pull/105]).
-struct HeaderTableIter<'a> {
+pub struct HeaderTableIter<'a> {
     // Represents a chain of static-table -> dynamic-table elements.
     // The mapper is required to transform the elements yielded from the static
     // table to a type that matches the elements yielded from the dynamic table.
@@ -264,10 +298,42 @@ fn static_table_mapper<'a>(h: &'a (&'a [u8], &'a [u8]))
     *h
 }
 
+/// Iterator through the dynamic table entries of a `HeaderTable`, yielding
+/// `(index, name, value)`.
+pub struct DynamicEntries<'t> {
+    inner: iter::Enumerate<vec_deque::Iter<'t, (Vec<u8>, Vec<u8>)>>,
+    first_index: usize,
+}
+
+impl<'t> Iterator for DynamicEntries<'t> {
+    type Item = (usize, &'t [u8], &'t [u8]);
+
+    fn next(&mut self) -> Option<(usize, &'t [u8], &'t [u8])> {
+        self.inner.next().map(|(i, &(ref name, ref value))| (self.first_index + i, &name[..], &value[..]))
+    }
+}
+
+/// Scans `headers` for `header`, returning the first entry matching both the
+/// name and the value, or else the last one matching the name. Indices start
+/// at `offset + 1`.
+fn scan<'h, I>(headers: I, header: (&[u8], &[u8]), offset: usize) -> Option<(usize, bool)>
+        where I: Iterator<Item=(&'h [u8], &'h [u8])> {
+    let mut matching_name: Option<usize> = None;
+    for (i, h) in headers.enumerate() {
+        if header.0 == h.0 {
+            if header.1 == h.1 {
+                return Some((offset + i + 1, true));
+            }
+            matching_name = Some(offset + i + 1);
+        }
+    }
+    matching_name.map(|i| (i, false))
+}
+
 /// The struct represents the header table obtained by merging the static and
 /// dynamic tables into a single index address space, as described in section
 /// `2.3.3.` of the HPACK spec.
-struct HeaderTable<'a> {
+pub struct HeaderTable<'a> {
     static_table: StaticTable<'a>,
     dynamic_table: DynamicTable,
 }
@@ -299,6 +365,45 @@ impl<'a> HeaderTable<'a> {
         }
     }
 
+    /// Returns the current size of the dynamic part of the table in octets: the
+    /// name and value lengths of the entries plus 32 octets for each (HPACK
+    /// spec section 4.1).
+    pub fn dynamic_size(&self) -> usize {
+        self.dynamic_table.get_size()
+    }
+
+    /// Returns the number of entries in the dynamic part of the table.
+    pub fn dynamic_len(&self) -> usize {
+        self.dynamic_table.len()
+    }
+
+    /// Returns an iterator through the entries of the dynamic part of the
+    /// table, newest first, together with the index they are referred to by.
+    pub fn dynamic_entries(&self) -> DynamicEntries {
+        DynamicEntries {
+            inner: self.dynamic_table.table.iter().enumerate(),
+            first_index: self.static_table.len() + 1,
+        }
+    }
+
+    /// Returns the maximum size of the dynamic part of the table in octets.
+    pub fn max_dynamic_size(&self) -> usize {
+        self.dynamic_table.get_max_table_size()
+    }
+
+    /// Sets the maximum size of the dynamic part of the table, evicting entries that no longer
+    /// fit.
+    pub fn set_max_dynamic_size(&mut self, max_size: usize) {
+        self.dynamic_table.set_max_table_size(max_size);
+    }
+
+    /// Makes `observer` be called with the name and value of every entry
+    /// evicted from the dynamic table, and why. Replaces any earlier observer.
+    pub fn set_eviction_observer<F>(&mut self, observer: F)
+            where F: FnMut(&[u8], &[u8], EvictionReason) + Send + 'static {
+        self.dynamic_table.observer = Some(Box::new(observer));
+    }
+
     /// Adds the given header to the table. Of course, this means that the new
     /// header is added to the dynamic part of the table.
     ///
@@ -361,38 +466,92 @@ impl<'a> HeaderTable<'a> {
     /// of the header in the header tables (the 1-based index that HPACK uses)
     /// and a `bool` indicating whether the value of the header also matched.
     pub fn find_header(&self, header: (&[u8], &[u8])) -> Option<(usize, bool)> {
-        // Just does a simple scan of the entire table, searching for a header
-        // that matches both the name and the value of the given header.
-        // If no such header is found, then any one of the headers that had a
-        // matching name is returned, with the appropriate return flag set.
-        //
-        // The tables are so small that it is unlikely that the linear scan
-        // would be a major performance bottlneck. If it does prove to be,
-        // though, a more efficient lookup/header representation method could
-        // be devised.
-        let mut matching_name: Option<usize> = None;
-        for (i, h) in self.iter().enumerate() {
-            if header.0 == h.0 {
-                if header.1 == h.1 {
-                    // Both name and value matched: returns it immediately
-                    return Some((i + 1, true));
+        // The static part is looked up by name in constant time, unless the
+        // table was built with a static table other than the spec's. The
+        // dynamic part is scanned: it is small, and changes all the time.
+        let static_match = if self.static_table.as_ptr() == STATIC_TABLE.as_ptr() {
+            static_name_range(header.0).map(|(first, last)| {
+                match (first..last + 1).find(|&i| STATIC_TABLE[i - 1].1 == header.1) {
+                    Some(i) => (i, true),
+                    None => (last, false),
                 }
-                // If only the name was valid, we continue scanning, hoping to
-                // find one where both the name and value match. We remember
-                // this one, in case such a header isn't found after all.
-                matching_name = Some(i + 1);
-            }
+            })
+        } else {
+            scan(self.static_table.iter().map(static_table_mapper), header, 0)
+        };
+        if let Some((i, true)) = static_match {
+            return Some((i, true));
         }
 
-        // Finally, if there's no header with a matching name and value,
-        // return one that matched only the name, if that *was* found.
-        match matching_name {
-            Some(i) => Some((i, false)),
-            None => None,
+        // A name match in the dynamic table is preferred over one in the
+        // static table.
+        match scan(self.dynamic_table.iter(), header, self.static_table.len()) {
+            Some(found) => Some(found),
+            None => static_match,
         }
     }
 }
 
+/// Returns the first and last index of the static table entries named `name`. The `match`
+/// compiles to a few length and byte comparisons rather than a scan of the table.
+pub fn static_name_range(name: &[u8]) -> Option<(usize, usize)> {
+    match name {
+        b":authority" => Some((1, 1)),
+        b":method" => Some((2, 3)),
+        b":path" => Some((4, 5)),
+        b":scheme" => Some((6, 7)),
+        b":status" => Some((8, 14)),
+        b"accept-" => Some((15, 15)),
+        b"accept-encoding" => Some((16, 16)),
+        b"accept-language" => Some((17, 17)),
+        b"accept-ranges" => Some((18, 18)),
+        b"accept" => Some((19, 19)),
+        b"access-control-allow-origin" => Some((20, 20)),
+        b"age" => Some((21, 21)),
+        b"allow" => Some((22, 22)),
+        b"authorization" => Some((23, 23)),
+        b"cache-control" => Some((24, 24)),
+        b"content-disposition" => Some((25, 25)),
+        b"content-encoding" => Some((26, 26)),
+        b"content-language" => Some((27, 27)),
+        b"content-length" => Some((28, 28)),
+        b"content-location" => Some((29, 29)),
+        b"content-range" => Some((30, 30)),
+        b"content-type" => Some((31, 31)),
+        b"cookie" => Some((32, 32)),
+        b"date" => Some((33, 33)),
+        b"etag" => Some((34, 34)),
+        b"expect" => Some((35, 35)),
+        b"expires" => Some((36, 36)),
+        b"from" => Some((37, 37)),
+        b"host" => Some((38, 38)),
+        b"if-match" => Some((39, 39)),
+        b"if-modified-since" => Some((40, 40)),
+        b"if-none-match" => Some((41, 41)),
+        b"if-range" => Some((42, 42)),
+        b"if-unmodified-since" => Some((43, 43)),
+        b"last-modified" => Some((44, 44)),
+        b"link" => Some((45, 45)),
+        b"location" => Some((46, 46)),
+        b"max-forwards" => Some((47, 47)),
+        b"proxy-authenticate" => Some((48, 48)),
+        b"proxy-authorization" => Some((49, 49)),
+        b"range" => Some((50, 50)),
+        b"referer" => Some((51, 51)),
+        b"refresh" => Some((52, 52)),
+        b"retry-after" => Some((53, 53)),
+        b"server" => Some((54, 54)),
+        b"set-cookie" => Some((55, 55)),
+        b"strict-transport-security" => Some((56, 56)),
+        b"transfer-encoding" => Some((57, 57)),
+        b"user-agent" => Some((58, 58)),
+        b"vary" => Some((59, 59)),
+        b"via" => Some((60, 60)),
+        b"www-authenticate" => Some((61, 61)),
+        _ => None,
+    }
+}
+
 /// The table represents the static header table defined by the HPACK spec.
 /// (HPACK, Appendix A)
 static STATIC_TABLE: &'static [(&'static [u8], &'static [u8])] = &[
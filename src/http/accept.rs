@@ -0,0 +1,463 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accept-layer limits so a single client can't exhaust the server.
+//!
+//! `AcceptLimiter` is shared by every connection of a server (it is cheap to clone). When a
+//! connection is bound it must be admitted, which yields a `Permit` that is held for the lifetime
+//! of the connection, and a `Budget` bounding how many bytes and how much time the peer may use
+//! before completing its handshake (the first full request head for HTTP/1.1). The budget is
+//! only checked as bytes come in, so a `HandshakeDeadline` races the transport against a timer
+//! for the peers that send nothing at all.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Remote, Timeout};
+
+/// Limits enforced by an `AcceptLimiter`. Every limit is off (`None`) by default.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AcceptConfig {
+    /// Maximum number of concurrent connections per peer prefix.
+    pub max_connections_per_peer: Option<usize>,
+    /// Prefix length used to group IPv4 peers (32 means per address).
+    pub ipv4_prefix_len: u8,
+    /// Prefix length used to group IPv6 peers (64 is a typical end site).
+    pub ipv6_prefix_len: u8,
+    /// Maximum number of connections admitted per second, across all peers.
+    pub max_handshakes_per_second: Option<u32>,
+    /// Maximum number of bytes a peer may send before completing its handshake.
+    pub pre_handshake_bytes: Option<usize>,
+    /// Maximum time a peer may take to complete its handshake.
+    pub pre_handshake_timeout: Option<Duration>,
+}
+
+impl Default for AcceptConfig {
+    fn default() -> AcceptConfig {
+        AcceptConfig {
+            max_connections_per_peer: None,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 64,
+            max_handshakes_per_second: None,
+            pre_handshake_bytes: None,
+            pre_handshake_timeout: None,
+        }
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Rejection {
+    /// The peer already holds `max_connections_per_peer` connections.
+    PerPeerLimit,
+    /// More than `max_handshakes_per_second` connections arrived within the last second.
+    HandshakeRate,
+    /// The external decision function refused the peer.
+    Policy,
+    /// The peer sent more than `pre_handshake_bytes` without completing its handshake.
+    PreHandshakeBytes,
+    /// The peer did not complete its handshake within `pre_handshake_timeout`.
+    PreHandshakeTimeout,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Rejection::PerPeerLimit => "too many connections from peer",
+            Rejection::HandshakeRate => "handshake rate exceeded",
+            Rejection::Policy => "refused by policy",
+            Rejection::PreHandshakeBytes => "pre-handshake byte budget exceeded",
+            Rejection::PreHandshakeTimeout => "pre-handshake time budget exceeded",
+        })
+    }
+}
+
+struct State {
+    per_peer: HashMap<IpAddr, usize>,
+    window_start: Option<Instant>,
+    window_count: u32,
+}
+
+/// Shared admission control for the server accept path.
+#[derive(Clone)]
+pub struct AcceptLimiter {
+    config: AcceptConfig,
+    state: Arc<Mutex<State>>,
+    decide: Option<Arc<Fn(&SocketAddr) -> bool + Send + Sync>>,
+}
+
+impl AcceptLimiter {
+    pub fn new(config: AcceptConfig) -> AcceptLimiter {
+        AcceptLimiter {
+            config: config,
+            state: Arc::new(Mutex::new(State {
+                per_peer: HashMap::new(),
+                window_start: None,
+                window_count: 0,
+            })),
+            decide: None,
+        }
+    }
+
+    /// Plugs in an external decision function (deny lists, reputation services, ...). It is
+    /// consulted before the built-in limits and refuses the peer by returning `false`.
+    pub fn with_decision<F>(mut self, decide: F) -> AcceptLimiter
+            where F: Fn(&SocketAddr) -> bool + Send + Sync + 'static {
+        self.decide = Some(Arc::new(decide));
+        self
+    }
+
+    pub fn config(&self) -> &AcceptConfig {
+        &self.config
+    }
+
+    /// Admits a newly accepted connection from `addr`. The returned `Permit` must be kept alive
+    /// for as long as the connection is open.
+    pub fn admit(&self, addr: &SocketAddr) -> Result<Permit, Rejection> {
+        if let Some(ref decide) = self.decide {
+            if !decide(addr) {
+                return Err(Rejection::Policy);
+            }
+        }
+
+        let key = self.peer_key(addr.ip());
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(max) = self.config.max_handshakes_per_second {
+            let now = Instant::now();
+            let expired = match state.window_start {
+                Some(start) => now.duration_since(start) >= Duration::from_secs(1),
+                None => true,
+            };
+            if expired {
+                state.window_start = Some(now);
+                state.window_count = 0;
+            }
+            if state.window_count >= max {
+                return Err(Rejection::HandshakeRate);
+            }
+            state.window_count += 1;
+        }
+
+        let count = state.per_peer.entry(key).or_insert(0);
+        if let Some(max) = self.config.max_connections_per_peer {
+            if *count >= max {
+                return Err(Rejection::PerPeerLimit);
+            }
+        }
+        *count += 1;
+
+        Ok(Permit { limiter: self.clone(), key: key })
+    }
+
+    /// A fresh pre-handshake budget for a connection admitted just now.
+    pub fn budget(&self) -> Budget {
+        Budget {
+            started: Instant::now(),
+            max_bytes: self.config.pre_handshake_bytes,
+            timeout: self.config.pre_handshake_timeout,
+            done: false,
+        }
+    }
+
+    /// Number of connections currently held by the prefix `addr` belongs to.
+    pub fn connections(&self, addr: &IpAddr) -> usize {
+        let key = self.peer_key(*addr);
+        self.state.lock().unwrap().per_peer.get(&key).cloned().unwrap_or(0)
+    }
+
+    fn peer_key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let bits = mask_bits(u32::from(ip) as u64, 32, self.config.ipv4_prefix_len) as u32;
+                IpAddr::V4(Ipv4Addr::from(bits))
+            },
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                let mut high: u64 = 0;
+                let mut low: u64 = 0;
+                for i in 0..4 {
+                    high = (high << 16) | segments[i] as u64;
+                    low = (low << 16) | segments[i + 4] as u64;
+                }
+                let prefix = self.config.ipv6_prefix_len;
+                let (high, low) = if prefix <= 64 {
+                    (mask_bits(high, 64, prefix), 0)
+                } else {
+                    (high, mask_bits(low, 64, prefix - 64))
+                };
+                IpAddr::V6(Ipv6Addr::new((high >> 48) as u16, (high >> 32) as u16,
+                                         (high >> 16) as u16, high as u16,
+                                         (low >> 48) as u16, (low >> 32) as u16,
+                                         (low >> 16) as u16, low as u16))
+            },
+        }
+    }
+
+    fn release(&self, key: &IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        let remove = match state.per_peer.get_mut(key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+        if remove {
+            state.per_peer.remove(key);
+        }
+    }
+}
+
+impl fmt::Debug for AcceptLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AcceptLimiter {{ config: {:?} }}", self.config)
+    }
+}
+
+/// Keeps only the `prefix` most significant of the `width` low bits of `value`.
+fn mask_bits(value: u64, width: u8, prefix: u8) -> u64 {
+    if prefix >= width {
+        value
+    } else if prefix == 0 {
+        0
+    } else {
+        value & (!0u64 << (width - prefix)) & (!0u64 >> (64 - width))
+    }
+}
+
+/// Held by an admitted connection; releases its slot when dropped.
+pub struct Permit {
+    limiter: AcceptLimiter,
+    key: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.key);
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Permit {{ peer: {} }}", self.key)
+    }
+}
+
+/// Byte and time budget a connection may use before completing its handshake.
+#[derive(Copy, Clone, Debug)]
+pub struct Budget {
+    started: Instant,
+    max_bytes: Option<usize>,
+    timeout: Option<Duration>,
+    done: bool,
+}
+
+impl Budget {
+    /// Checks the budget against the number of bytes buffered so far.
+    pub fn check(&self, buffered: usize) -> Result<(), Rejection> {
+        if self.done {
+            return Ok(());
+        }
+        if self.max_bytes.map_or(false, |max| buffered > max) {
+            return Err(Rejection::PreHandshakeBytes);
+        }
+        if self.timeout.map_or(false, |timeout| self.started.elapsed() > timeout) {
+            return Err(Rejection::PreHandshakeTimeout);
+        }
+        Ok(())
+    }
+
+    /// Marks the handshake as completed; the budget no longer applies.
+    pub fn complete(&mut self) {
+        self.done = true;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done
+    }
+}
+
+/// A transport that fails with `Rejection::PreHandshakeTimeout` when its first item (for
+/// HTTP/1.1, the first request) doesn't come before `timeout`, whether or not the peer sent
+/// anything.
+pub struct HandshakeDeadline<T> {
+    inner: T,
+    /// Fires at the deadline; gone once the handshake completed.
+    expired: Option<oneshot::Receiver<()>>,
+    /// Called once, when the deadline passes.
+    on_timeout: Option<Box<FnMut()>>,
+}
+
+impl<T> HandshakeDeadline<T> {
+    /// Starts the `timeout` on the event loop of `timer`, which may run on another thread than
+    /// the transport. `on_timeout` is called when it expires, e.g. to count the rejection.
+    pub fn new<F>(inner: T, timeout: Duration, timer: &Remote, on_timeout: F) -> HandshakeDeadline<T>
+            where F: FnMut() + 'static {
+        let (tx, rx) = oneshot::channel::<()>();
+        timer.spawn(move |handle| {
+            future::result(Timeout::new(timeout, handle)).flatten().then(move |_| {
+                tx.complete(());
+                Ok::<(), ()>(())
+            })
+        });
+        HandshakeDeadline {
+            inner: inner,
+            expired: Some(rx),
+            on_timeout: Some(Box::new(on_timeout)),
+        }
+    }
+
+    /// A transport without a deadline.
+    pub fn none(inner: T) -> HandshakeDeadline<T> {
+        HandshakeDeadline {
+            inner: inner,
+            expired: None,
+            on_timeout: None,
+        }
+    }
+}
+
+impl<T: Stream<Error = io::Error>> Stream for HandshakeDeadline<T> {
+    type Item = T::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, io::Error> {
+        // What the peer sent is taken in first: a request that beat the timer stops it.
+        let item = try!(self.inner.poll());
+        if let Async::Ready(_) = item {
+            self.expired = None;
+            return Ok(item);
+        }
+        let expired = match self.expired {
+            Some(ref mut expired) => match expired.poll() {
+                Ok(Async::Ready(())) => true,
+                Ok(Async::NotReady) => false,
+                // The timer's event loop is gone; `Budget::check` is all that is left.
+                Err(oneshot::Canceled) => {
+                    self.expired = None;
+                    false
+                },
+            },
+            None => false,
+        };
+        if expired {
+            self.expired = None;
+            if let Some(mut on_timeout) = self.on_timeout.take() {
+                on_timeout();
+            }
+            return Err(io::Error::new(io::ErrorKind::TimedOut, Rejection::PreHandshakeTimeout.to_string()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: Sink> Sink for HandshakeDeadline<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem) -> StartSend<T::SinkItem, T::SinkError> {
+        match try!(self.inner.start_send(item)) {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(item) => Ok(AsyncSink::NotReady(item)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for HandshakeDeadline<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HandshakeDeadline {{ inner: {:?}, pending: {} }}", self.inner, self.expired.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use futures::Stream;
+    use futures::sync::mpsc;
+    use tokio_core::reactor::Core;
+
+    use super::{AcceptConfig, AcceptLimiter, HandshakeDeadline, Rejection};
+
+    #[test]
+    fn test_per_peer_limit() {
+        let limiter = AcceptLimiter::new(AcceptConfig {
+            max_connections_per_peer: Some(2),
+            ipv4_prefix_len: 24,
+            .. AcceptConfig::default()
+        });
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let c: SocketAddr = "10.0.1.1:1000".parse().unwrap();
+
+        let first = limiter.admit(&a).unwrap();
+        let _second = limiter.admit(&b).unwrap();
+        assert_eq!(limiter.admit(&a).unwrap_err(), Rejection::PerPeerLimit);
+        assert!(limiter.admit(&c).is_ok());
+
+        drop(first);
+        let ip: IpAddr = "10.0.0.9".parse().unwrap();
+        assert_eq!(limiter.connections(&ip), 1);
+        assert!(limiter.admit(&a).is_ok());
+    }
+
+    #[test]
+    fn test_decision_and_budget() {
+        let limiter = AcceptLimiter::new(AcceptConfig {
+            pre_handshake_bytes: Some(16),
+            .. AcceptConfig::default()
+        }).with_decision(|addr| addr.port() != 666);
+
+        assert_eq!(limiter.admit(&"127.0.0.1:666".parse().unwrap()).unwrap_err(), Rejection::Policy);
+
+        let mut budget = limiter.budget();
+        assert_eq!(budget.check(16), Ok(()));
+        assert_eq!(budget.check(17), Err(Rejection::PreHandshakeBytes));
+        budget.complete();
+        assert_eq!(budget.check(1024), Ok(()));
+    }
+
+    #[test]
+    fn test_handshake_deadline() {
+        let mut core = Core::new().unwrap();
+        let (_tx, rx) = mpsc::unbounded::<()>();
+        let fired = Rc::new(Cell::new(false));
+        let on_timeout = fired.clone();
+        let silent = rx.map_err(|()| io::Error::new(io::ErrorKind::Other, "closed"));
+        let deadline = HandshakeDeadline::new(silent, Duration::from_millis(10), &core.remote(),
+                                              move || on_timeout.set(true));
+
+        // A peer that sends nothing is still timed out.
+        match core.run(deadline.into_future()) {
+            Err((err, _)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            Ok(_) => panic!("the deadline did not fire"),
+        }
+        assert!(fired.get());
+    }
+}
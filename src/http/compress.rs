@@ -0,0 +1,219 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Response compression. `Compress` wraps a `Service`, negotiates `accept-encoding` with the
+//! client and compresses eligible response bodies with gzip or brotli, setting
+//! `content-encoding` and `vary` accordingly. Bodies are fully buffered in this crate, so each
+//! body is compressed in one go.
+//!
+//! Only built with the `compression` feature.
+
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use brotli::CompressorWriter;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures::Future;
+use tokio_service::Service;
+
+use http::{Request, Response};
+use http::negotiate::{self, Field};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressConfig {
+    /// Bodies smaller than this are sent as they are.
+    pub min_size: usize,
+    /// Media types (or prefixes such as `text/`) worth compressing. Already-compressed types
+    /// like images, video or archives should not be listed.
+    pub content_types: Vec<String>,
+    /// Encodings offered, in order of preference when the client weighs them equally.
+    pub encodings: Vec<Encoding>,
+    /// Compression level, 0-9 for gzip and 0-11 for brotli.
+    pub gzip_level: u32,
+    pub brotli_level: u32,
+}
+
+impl Default for CompressConfig {
+    fn default() -> CompressConfig {
+        CompressConfig {
+            min_size: 1024,
+            content_types: vec!["text/".to_string(),
+                                "application/json".to_string(),
+                                "application/javascript".to_string(),
+                                "application/xml".to_string(),
+                                "image/svg+xml".to_string()],
+            encodings: vec![Encoding::Brotli, Encoding::Gzip],
+            gzip_level: 6,
+            brotli_level: 5,
+        }
+    }
+}
+
+impl CompressConfig {
+    /// Picks the encoding to use for a request carrying the given `accept-encoding` value.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<Encoding> {
+        let names: Vec<&str> = self.encodings.iter().map(|encoding| encoding.as_str()).collect();
+        negotiate::negotiate(Field::AcceptEncoding, Some(accept_encoding), &names)
+            .and_then(|name| self.encodings.iter().cloned().find(|encoding| encoding.as_str() == name))
+    }
+
+    /// Whether the response is worth compressing at all, whatever the client accepts.
+    pub fn eligible(&self, res: &Response) -> bool {
+        if res.code < 200 || res.code == 204 || res.code == 304 {
+            return false;
+        }
+        if res.header("content-encoding").is_some() || res.body.len() < self.min_size {
+            return false;
+        }
+        let content_type = res.header("content-type").unwrap_or("").to_lowercase();
+        self.content_types.iter().any(|t| content_type.starts_with(&t[..]))
+    }
+
+    /// Compresses `res` in place if it is eligible and `encoding` is set. `vary` is updated for
+    /// every eligible response so caches keep the variants apart.
+    pub fn compress(&self, res: &mut Response, encoding: Option<Encoding>) -> io::Result<()> {
+        if !self.eligible(res) {
+            return Ok(());
+        }
+        add_vary(res);
+
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return Ok(()),
+        };
+        let body = match encoding {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.gzip_level));
+                try!(encoder.write_all(&res.body));
+                try!(encoder.finish())
+            },
+            Encoding::Brotli => {
+                let mut encoder = CompressorWriter::new(Vec::new(), 4096, self.brotli_level, 22);
+                try!(encoder.write_all(&res.body));
+                encoder.into_inner()
+            },
+        };
+
+        res.body = body;
+        let length = res.body.len().to_string();
+        for header in res.headers.iter_mut() {
+            if header.0.eq_ignore_ascii_case("content-length") {
+                header.1 = length.clone();
+            }
+        }
+        res.headers.push(("Content-Encoding".to_string(), encoding.as_str().to_string()));
+        Ok(())
+    }
+}
+
+fn add_vary(res: &mut Response) {
+    for header in res.headers.iter_mut() {
+        if header.0.eq_ignore_ascii_case("vary") {
+            let present = header.1.split(',').any(|v| {
+                let v = v.trim();
+                v == "*" || v.eq_ignore_ascii_case("accept-encoding")
+            });
+            if !present {
+                header.1.push_str(", Accept-Encoding");
+            }
+            return;
+        }
+    }
+    res.headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+}
+
+/// Wraps a service and compresses its responses.
+pub struct Compress<S> {
+    inner: S,
+    config: Arc<CompressConfig>,
+}
+
+impl<S> Compress<S> {
+    pub fn new(inner: S, config: CompressConfig) -> Compress<S> {
+        Compress {
+            inner: inner,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Service for Compress<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: From<io::Error> + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let encoding = req.header("accept-encoding").and_then(|value| self.config.negotiate(value));
+        let config = self.config.clone();
+        Box::new(self.inner.call(req).and_then(move |mut res| {
+            try!(config.compress(&mut res, encoding));
+            Ok(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+    use super::{CompressConfig, Encoding};
+
+    #[test]
+    fn test_negotiate() {
+        let config = CompressConfig::default();
+        assert_eq!(config.negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(config.negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(config.negotiate("br;q=0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(config.negotiate("identity"), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let config = CompressConfig::default();
+        let body = vec![b'a'; 4096];
+        let mut res = Response::new()
+            .with_header("Content-Type", "text/plain")
+            .with_header("Content-Length", "4096")
+            .with_body(body.clone());
+        config.compress(&mut res, Some(Encoding::Gzip)).unwrap();
+        assert_eq!(res.header("content-encoding"), Some("gzip"));
+        assert_eq!(res.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(res.content_length(), res.body.len() as u64);
+        assert!(res.body.len() < body.len());
+
+        let mut png = Response::new().with_header("Content-Type", "image/png").with_body(body);
+        config.compress(&mut png, Some(Encoding::Gzip)).unwrap();
+        assert_eq!(png.header("content-encoding"), None);
+        assert_eq!(png.header("vary"), None);
+    }
+}
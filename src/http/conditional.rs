@@ -0,0 +1,277 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conditional requests (RFC 9110 section 13): ETag generation and evaluation of the `If-*`
+//! preconditions into a 200/304/412 decision.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use time;
+
+use exchange::RequestHead;
+use http::Response;
+use Method;
+use StatusCode;
+
+/// An entity tag.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ETag {
+    pub weak: bool,
+    /// The opaque tag, without quotes.
+    pub tag: String,
+}
+
+impl ETag {
+    /// A strong ETag over the exact bytes of a body.
+    pub fn from_body(body: &[u8]) -> ETag {
+        ETag {
+            weak: false,
+            tag: format!("{:016x}", fnv1a(body)),
+        }
+    }
+
+    /// A weak ETag over metadata (length and modification time), for when hashing the body is
+    /// too expensive.
+    pub fn from_metadata(len: u64, modified: SystemTime) -> ETag {
+        ETag {
+            weak: true,
+            tag: format!("{:x}-{:x}", len, unix_secs(modified)),
+        }
+    }
+
+    /// Parses a single entity tag such as `"abc"` or `W/"abc"`.
+    pub fn parse(value: &str) -> Option<ETag> {
+        let value = value.trim();
+        let (weak, value) = if value.starts_with("W/") { (true, &value[2..]) } else { (false, value) };
+        if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+            return None;
+        }
+        Some(ETag {
+            weak: weak,
+            tag: value[1..value.len() - 1].to_string(),
+        })
+    }
+
+    /// Strong comparison: both tags must be strong and identical.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the tags must be identical, weakness is ignored.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.weak {
+            try!(f.write_str("W/"));
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// The outcome of evaluating the preconditions of a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Decision {
+    /// Process the request normally.
+    Proceed,
+    /// Answer 304 without a body.
+    NotModified,
+    /// Answer 412.
+    PreconditionFailed,
+}
+
+impl Decision {
+    /// Turns `res` into the 304 or 412 response if the decision calls for it. Validator headers
+    /// (`etag`, `last-modified`, ...) already set on `res` are kept.
+    pub fn apply(&self, res: Response) -> Response {
+        match *self {
+            Decision::Proceed => res,
+            Decision::NotModified => {
+                let mut res = res.with_status(StatusCode::NotModified).with_body(Vec::new());
+                res.headers.retain(|&(ref name, _)| {
+                    !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("content-type")
+                });
+                res
+            },
+            Decision::PreconditionFailed => {
+                let mut res = res.with_status(StatusCode::PreconditionFailed).with_body(Vec::new());
+                res.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("content-length"));
+                res.with_header("Content-Length", "0")
+            },
+        }
+    }
+}
+
+/// The precondition headers of a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Conditions {
+    /// GET or HEAD; only those get 304 and honour `If-Modified-Since`.
+    pub safe: bool,
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub if_unmodified_since: Option<String>,
+    pub if_range: Option<String>,
+}
+
+impl Conditions {
+    pub fn from_request<R: RequestHead>(req: &R) -> Conditions {
+        let header = |name: &str| req.header(name).map(|value| value.to_string());
+        Conditions {
+            safe: match req.method() {
+                Some(Method::Get) | Some(Method::Head) => true,
+                _ => false,
+            },
+            if_match: header("if-match"),
+            if_none_match: header("if-none-match"),
+            if_modified_since: header("if-modified-since"),
+            if_unmodified_since: header("if-unmodified-since"),
+            if_range: header("if-range"),
+        }
+    }
+
+    /// Evaluates the preconditions against the current validators of the selected
+    /// representation, in the order of RFC 9110 section 13.2.2.
+    pub fn evaluate(&self, etag: Option<&ETag>, last_modified: Option<SystemTime>) -> Decision {
+        if let Some(ref if_match) = self.if_match {
+            if !list_matches(if_match, etag, true) {
+                return Decision::PreconditionFailed;
+            }
+        } else if let (Some(ref since), Some(modified)) = (self.if_unmodified_since.as_ref(), last_modified) {
+            if let Some(since) = parse_http_date(since) {
+                if unix_secs(modified) > since {
+                    return Decision::PreconditionFailed;
+                }
+            }
+        }
+
+        if let Some(ref if_none_match) = self.if_none_match {
+            if list_matches(if_none_match, etag, false) {
+                return if self.safe { Decision::NotModified } else { Decision::PreconditionFailed };
+            }
+        } else if let (true, Some(ref since), Some(modified)) = (self.safe, self.if_modified_since.as_ref(), last_modified) {
+            if let Some(since) = parse_http_date(since) {
+                if unix_secs(modified) <= since {
+                    return Decision::NotModified;
+                }
+            }
+        }
+
+        Decision::Proceed
+    }
+
+    /// Whether a `range` header should be honoured: true without `If-Range`, otherwise only if
+    /// the validator it carries still matches (strongly, for an ETag).
+    pub fn if_range(&self, etag: Option<&ETag>, last_modified: Option<SystemTime>) -> bool {
+        let value = match self.if_range {
+            Some(ref value) => value,
+            None => return true,
+        };
+        match ETag::parse(value) {
+            Some(tag) => etag.map_or(false, |etag| etag.strong_eq(&tag)),
+            None => match (parse_http_date(value), last_modified) {
+                (Some(date), Some(modified)) => unix_secs(modified) == date,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Matches an `If-Match`/`If-None-Match` list against the current ETag.
+fn list_matches(list: &str, etag: Option<&ETag>, strong: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    // Entity tags can't contain commas, so splitting on them is safe.
+    list.split(',').filter_map(ETag::parse).any(|tag| {
+        if strong { tag.strong_eq(etag) } else { tag.weak_eq(etag) }
+    })
+}
+
+/// Parses an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, into seconds since the epoch.
+fn parse_http_date(value: &str) -> Option<i64> {
+    time::strptime(value.trim(), "%a, %d %b %Y %T GMT").ok().map(|tm| tm.to_timespec().sec)
+}
+
+/// Formats `time` as an IMF-fixdate for `last-modified`.
+pub fn http_date(time: SystemTime) -> String {
+    let tm = time::at_utc(time::Timespec::new(unix_secs(time), 0));
+    tm.strftime("%a, %d %b %Y %T GMT").map(|tm| tm.to_string()).unwrap_or_default()
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// 64-bit FNV-1a. Stable across builds and platforms, unlike the std hasher, so ETags survive
+/// restarts and are shared by all instances of a deployment.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{http_date, Conditions, Decision, ETag};
+
+    #[test]
+    fn test_etag() {
+        let etag = ETag::from_body(b"hello");
+        assert_eq!(ETag::parse(&etag.to_string()), Some(etag.clone()));
+        let weak = ETag::parse("W/\"abc\"").unwrap();
+        assert!(weak.weak && weak.tag == "abc");
+        assert!(!weak.strong_eq(&weak) && weak.weak_eq(&weak));
+        assert_eq!(ETag::parse("abc"), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let etag = ETag::from_body(b"hello");
+        let modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        let get = Conditions { safe: true, .. Conditions::default() };
+
+        let cond = Conditions { if_none_match: Some(format!("\"x\", W/{}", etag)), .. get.clone() };
+        assert_eq!(cond.evaluate(Some(&etag), Some(modified)), Decision::NotModified);
+        let cond = Conditions { safe: false, .. cond };
+        assert_eq!(cond.evaluate(Some(&etag), Some(modified)), Decision::PreconditionFailed);
+
+        let cond = Conditions { if_match: Some("\"other\"".to_string()), .. get.clone() };
+        assert_eq!(cond.evaluate(Some(&etag), None), Decision::PreconditionFailed);
+        let cond = Conditions { if_match: Some("*".to_string()), .. get.clone() };
+        assert_eq!(cond.evaluate(Some(&etag), None), Decision::Proceed);
+
+        assert_eq!(http_date(modified), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let cond = Conditions { if_modified_since: Some(http_date(modified)), .. get.clone() };
+        assert_eq!(cond.evaluate(Some(&etag), Some(modified)), Decision::NotModified);
+        assert_eq!(cond.evaluate(Some(&etag), Some(modified + Duration::from_secs(1))), Decision::Proceed);
+
+        let cond = Conditions { if_range: Some(etag.to_string()), .. get };
+        assert!(cond.if_range(Some(&etag), None));
+        assert!(!cond.if_range(Some(&ETag::from_body(b"changed")), None));
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection tagging. `Connections` keeps a registry of the open connections of a server, each
+//! with an optional tag (e.g. a tenant id) and arbitrary user data. Every request carries its
+//! `Connection`, and connections can be enumerated or closed by tag for admin operations such as
+//! "kick this tenant".
+//!
+//! `Connection::export_keying_material` (RFC 5705 / RFC 8446 section 7.5) makes keying material
+//! of the connection's TLS session available for token binding and channel-bound
+//! authentication. TLS servers accept with `tls::accept`, which installs an exporter on the
+//! connection once the handshake is done; the HTTP/1 server is cleartext, so the export fails
+//! with `NotConnected` on its connections.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use futures::task::{self, Task};
+
+/// Exports keying material from the TLS session of a connection, e.g. `tls::TlsExporter`.
+pub trait Exporter: Send + Sync {
+    fn export_keying_material(&self, label: &str, context: Option<&[u8]>, len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// An open connection as seen by the registry and by its requests.
+pub struct Connection {
+    id: usize,
+    peer: SocketAddr,
+    tag: Mutex<Option<String>>,
+    data: Mutex<Option<Arc<Any + Send + Sync>>>,
+    exporter: Mutex<Option<Arc<Exporter>>>,
+    closed: AtomicBool,
+    /// The task of the connection's transport, woken by `close`.
+    task: Mutex<Option<Task>>,
+}
+
+impl Connection {
+    /// Unique for the lifetime of the registry.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn tag(&self) -> Option<String> {
+        self.tag.lock().unwrap().clone()
+    }
+
+    /// Tags (or re-tags) the connection, e.g. once the first request has been authenticated.
+    pub fn set_tag(&self, tag: Option<String>) {
+        *self.tag.lock().unwrap() = tag;
+    }
+
+    pub fn data(&self) -> Option<Arc<Any + Send + Sync>> {
+        self.data.lock().unwrap().clone()
+    }
+
+    pub fn set_data(&self, data: Option<Arc<Any + Send + Sync>>) {
+        *self.data.lock().unwrap() = data;
+    }
+
+    /// Registers the TLS session's exporter, as `tls::accept` does after the handshake.
+    pub fn set_exporter(&self, exporter: Arc<Exporter>) {
+        *self.exporter.lock().unwrap() = Some(exporter);
+    }
+
+    /// Derives `len` bytes of keying material bound to this connection's TLS session. Fails
+    /// with `ErrorKind::NotConnected` on cleartext connections and when no exporter was
+    /// registered.
+    pub fn export_keying_material(&self, label: &str, context: Option<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+        if label.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "exporter label must not be empty"));
+        }
+        let exporter = self.exporter.lock().unwrap().clone();
+        match exporter {
+            Some(exporter) => exporter.export_keying_material(label, context, len),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "connection has no TLS session to export from")),
+        }
+    }
+
+    /// Closes the connection: its transport is woken and drops it, idle or not. Requests in
+    /// flight aren't answered.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.unpark();
+        }
+    }
+
+    /// Has the current task, the connection's transport, woken by `close`. The transport
+    /// checks `is_closed` afterwards, so a `close` in between isn't missed.
+    pub fn park(&self) {
+        *self.task.lock().unwrap() = Some(task::park());
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection {{ id: {}, peer: {}, tag: {:?}, closed: {} }}",
+               self.id, self.peer, self.tag(), self.is_closed())
+    }
+}
+
+/// The registry of open connections. Cheap to clone; all clones share the same registry.
+#[derive(Clone)]
+pub struct Connections {
+    open: Arc<Mutex<HashMap<usize, Arc<Connection>>>>,
+    next_id: Arc<AtomicUsize>,
+    tagger: Option<Arc<Fn(&SocketAddr) -> Option<String> + Send + Sync>>,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections {
+            open: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            tagger: None,
+        }
+    }
+
+    /// Tags every connection on accept with what `tagger` returns for its peer.
+    pub fn with_tagger<F>(mut self, tagger: F) -> Connections
+            where F: Fn(&SocketAddr) -> Option<String> + Send + Sync + 'static {
+        self.tagger = Some(Arc::new(tagger));
+        self
+    }
+
+    /// Registers a newly accepted connection. It stays registered until the returned
+    /// `Registration` is dropped.
+    pub fn register(&self, peer: SocketAddr) -> Registration {
+        let connection = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            peer: peer,
+            tag: Mutex::new(self.tagger.as_ref().and_then(|tagger| tagger(&peer))),
+            data: Mutex::new(None),
+            exporter: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            task: Mutex::new(None),
+        });
+        self.open.lock().unwrap().insert(connection.id, connection.clone());
+        Registration {
+            connections: self.clone(),
+            connection: connection,
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<Arc<Connection>> {
+        self.open.lock().unwrap().get(&id).cloned()
+    }
+
+    /// All open connections.
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        self.open.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The open connections tagged `tag`.
+    pub fn tagged(&self, tag: &str) -> Vec<Arc<Connection>> {
+        self.open.lock().unwrap().values()
+            .filter(|connection| connection.tag().as_ref().map_or(false, |t| t == tag))
+            .cloned()
+            .collect()
+    }
+
+    /// Closes every connection tagged `tag` and returns how many there were.
+    pub fn close_tagged(&self, tag: &str) -> usize {
+        let connections = self.tagged(tag);
+        for connection in &connections {
+            connection.close();
+        }
+        connections.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Connections {
+        Connections::new()
+    }
+}
+
+impl fmt::Debug for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connections {{ open: {} }}", self.len())
+    }
+}
+
+/// Held by the codec of a registered connection; unregisters it when dropped.
+pub struct Registration {
+    connections: Connections,
+    connection: Arc<Connection>,
+}
+
+impl Registration {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.connection.id);
+    }
+}
+
+impl fmt::Debug for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registration {{ connection: {:?} }}", self.connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::{executor, future, Async};
+    use futures::executor::Unpark;
+
+    use super::{Connections, Exporter};
+
+    #[test]
+    fn test_tags() {
+        let connections = Connections::new()
+            .with_tagger(|addr| if addr.port() == 1 { Some("tenant-a".to_string()) } else { None });
+        let a = connections.register("127.0.0.1:1".parse().unwrap());
+        let b = connections.register("127.0.0.1:2".parse().unwrap());
+        assert_eq!(connections.len(), 2);
+        assert_eq!(a.connection().tag(), Some("tenant-a".to_string()));
+
+        b.connection().set_tag(Some("tenant-a".to_string()));
+        b.connection().set_data(Some(Arc::new(42u32)));
+        assert_eq!(b.connection().data().unwrap().downcast_ref::<u32>(), Some(&42));
+
+        assert_eq!(connections.close_tagged("tenant-a"), 2);
+        assert!(a.connection().is_closed());
+        assert_eq!(connections.close_tagged("tenant-b"), 0);
+
+        drop(a);
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.list()[0].id(), b.connection().id());
+    }
+
+    #[test]
+    fn test_exporter() {
+        struct Fixed;
+        impl Exporter for Fixed {
+            fn export_keying_material(&self, label: &str, _: Option<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+                Ok(label.bytes().cycle().take(len).collect())
+            }
+        }
+
+        let connections = Connections::new();
+        let registration = connections.register("127.0.0.1:1".parse().unwrap());
+        let connection = registration.connection();
+        let err = connection.export_keying_material("EXPORTER-test", None, 32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        connection.set_exporter(Arc::new(Fixed));
+        assert_eq!(connection.export_keying_material("ab", None, 5).unwrap(), b"ababa");
+        assert!(connection.export_keying_material("", None, 5).is_err());
+    }
+
+    #[test]
+    fn test_close_wakes() {
+        struct Woken(AtomicBool);
+        impl Unpark for Woken {
+            fn unpark(&self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let connections = Connections::new();
+        let registration = connections.register("127.0.0.1:1".parse().unwrap());
+        let connection = registration.connection().clone();
+        // An idle transport, waiting on its socket.
+        let mut transport = executor::spawn(future::poll_fn(move || {
+            connection.park();
+            if connection.is_closed() { Ok(Async::Ready(())) } else { Ok::<_, ()>(Async::NotReady) }
+        }));
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        assert_eq!(transport.poll_future(woken.clone()), Ok(Async::NotReady));
+
+        registration.connection().close();
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert_eq!(transport.poll_future(woken), Ok(Async::Ready(())));
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Request deadlines. A deadline is read from `grpc-timeout` (or another configured header) when
+//! a request arrives, stored on the `Request`, enforced by `Deadlines`, which drops the handler's
+//! future and answers 504 once it passes, and stamped back onto upstream requests by the
+//! `proxy` as the time that is left.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+use exchange::RequestHead;
+use http::{Request, Response};
+use StatusCode;
+
+/// How the timeout header is written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TimeoutFormat {
+    /// `grpc-timeout`: up to 8 digits and a unit, e.g. `250m` or `5S`.
+    Grpc,
+    /// A plain number of milliseconds.
+    Milliseconds,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlineConfig {
+    pub header: String,
+    pub format: TimeoutFormat,
+    /// Applied to requests that don't carry the header.
+    pub default: Option<Duration>,
+    /// Longer timeouts asked for by clients are cut down to this.
+    pub max: Option<Duration>,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> DeadlineConfig {
+        DeadlineConfig {
+            header: "grpc-timeout".to_string(),
+            format: TimeoutFormat::Grpc,
+            default: None,
+            max: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+impl DeadlineConfig {
+    pub fn parse(&self, value: &str) -> Option<Duration> {
+        match self.format {
+            TimeoutFormat::Grpc => parse_grpc_timeout(value),
+            TimeoutFormat::Milliseconds => value.trim().parse::<u64>().ok().map(Duration::from_millis),
+        }
+    }
+
+    pub fn format(&self, timeout: Duration) -> String {
+        match self.format {
+            TimeoutFormat::Grpc => format_grpc_timeout(timeout),
+            TimeoutFormat::Milliseconds => {
+                (timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64).to_string()
+            },
+        }
+    }
+
+    /// The deadline of a request that arrived at `now`, if it has one.
+    pub fn deadline<R: RequestHead>(&self, req: &R, now: Instant) -> Option<Instant> {
+        let timeout = req.header(&self.header).and_then(|value| self.parse(value)).or(self.default);
+        timeout.map(|timeout| match self.max {
+            Some(max) if timeout > max => now + max,
+            _ => now + timeout,
+        })
+    }
+
+    /// Replaces the timeout header in `headers` with the time left until `deadline`.
+    pub fn stamp(&self, headers: &mut Vec<(String, String)>, deadline: Instant, now: Instant) {
+        let remaining = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        let header = &self.header;
+        headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case(header));
+        headers.push((header.clone(), self.format(remaining)));
+    }
+}
+
+/// Parses a `grpc-timeout` value.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b >= b'0' && b <= b'9') {
+        return None;
+    }
+    let amount = match digits.parse::<u64>() {
+        Ok(amount) => amount,
+        Err(_) => return None,
+    };
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::new(amount / 1_000_000, (amount % 1_000_000) as u32 * 1000)),
+        "n" => Some(Duration::new(amount / 1_000_000_000, (amount % 1_000_000_000) as u32)),
+        _ => None,
+    }
+}
+
+/// Formats a `grpc-timeout` value in the finest unit that fits the 8 digits allowed.
+pub fn format_grpc_timeout(timeout: Duration) -> String {
+    const MAX: u64 = 99_999_999;
+    let nanos = timeout.as_secs().saturating_mul(1_000_000_000).saturating_add(timeout.subsec_nanos() as u64);
+    let units: [(u64, &str); 6] = [(1, "n"), (1_000, "u"), (1_000_000, "m"), (1_000_000_000, "S"),
+                                   (60_000_000_000, "M"), (3_600_000_000_000, "H")];
+    for &(scale, unit) in units.iter() {
+        // Round up so the upstream never gets more time than is left.
+        let amount = (nanos + scale - 1) / scale;
+        if amount <= MAX {
+            return format!("{}{}", amount, unit);
+        }
+    }
+    format!("{}H", MAX)
+}
+
+/// Wraps a service: sets each request's deadline and answers 504 once it has passed, dropping
+/// (and so cancelling) the inner future.
+pub struct Deadlines<S> {
+    inner: S,
+    config: DeadlineConfig,
+    handle: Handle,
+}
+
+impl<S> Deadlines<S> {
+    pub fn new(inner: S, config: DeadlineConfig, handle: Handle) -> Deadlines<S> {
+        Deadlines {
+            inner: inner,
+            config: config,
+            handle: handle,
+        }
+    }
+}
+
+impl<S> Service for Deadlines<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: From<io::Error> + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, mut req: Request) -> Self::Future {
+        let now = Instant::now();
+        let deadline = match self.config.deadline(&req, now) {
+            Some(deadline) => deadline,
+            None => return Box::new(self.inner.call(req)),
+        };
+        req.set_deadline(deadline);
+
+        let timeout = match Timeout::new(deadline - now, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(err) => return Box::new(::futures::future::err(S::Error::from(err))),
+        };
+        let grpc = self.config.format == TimeoutFormat::Grpc;
+        let expired = timeout.map_err(S::Error::from).map(move |_| {
+            let res = Response::new()
+                .with_status(StatusCode::GatewayTimeout)
+                .with_header("Content-Length", "0");
+            // DEADLINE_EXCEEDED, for gRPC clients.
+            if grpc { res.with_header("grpc-status", "4") } else { res }
+        });
+        Box::new(self.inner.call(req).select(expired).map(|(res, _)| res).map_err(|(err, _)| err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{format_grpc_timeout, parse_grpc_timeout, DeadlineConfig};
+
+    #[test]
+    fn test_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("1500u"), Some(Duration::new(0, 1_500_000)));
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("1s"), None);
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("1é"), None);
+
+        assert_eq!(format_grpc_timeout(Duration::from_millis(250)), "250000u");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(3600)), "3600000m");
+        assert_eq!(format_grpc_timeout(Duration::new(100_000, 1)), "100001S");
+    }
+
+    #[test]
+    fn test_stamp() {
+        let config = DeadlineConfig::default();
+        let now = Instant::now();
+        let mut headers = vec![("Grpc-Timeout".to_string(), "10S".to_string())];
+        config.stamp(&mut headers, now + Duration::from_secs(2), now);
+        assert_eq!(headers, vec![("grpc-timeout".to_string(), "2000000u".to_string())]);
+        config.stamp(&mut headers, now, now + Duration::from_secs(1));
+        assert_eq!(headers[0].1, "0n");
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Early Hints (RFC 8297). While a handler is still working on a response, it can send a 103
+//! with `Request::hints`: the server writes it right away, in a write of its own, or once the
+//! responses to the requests pipelined ahead of it are. `EarlyHints` wraps a `Service` and
+//! sends `link: rel=preload` hints for the resources the `PushManifest` lists for the
+//! requested route that way, as an alternative to pushing them.
+//!
+//! Hints set with `Response::with_hint` are only known with the response; they go out in a 103
+//! right ahead of it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use futures::task::{self, Task};
+use tokio_service::Service;
+
+use http::{Request, Response};
+use http::response;
+use http2::push::PushManifest;
+use Headers;
+
+#[derive(Default)]
+struct Shared {
+    /// Encoded 103s in the order they were sent, with the exchange (the index of the request
+    /// on the connection) of each.
+    queued: VecDeque<(u64, Vec<u8>)>,
+    /// Requests decoded and responses encoded on the connection so far.
+    requests: u64,
+    responses: u64,
+    /// The connection's task, woken when a 103 is queued.
+    task: Option<Task>,
+}
+
+/// The 103s a connection's handlers sent and which aren't written yet, shared by its codec,
+/// its transport and the `HintSender` of every request.
+#[derive(Clone, Default)]
+pub struct HintQueue {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl HintQueue {
+    pub fn new() -> HintQueue {
+        HintQueue::default()
+    }
+
+    /// A request of HTTP/1.`version` was decoded: the sender of its hints, or `None` for
+    /// HTTP/1.0 clients, which don't expect 1xx responses.
+    pub fn register(&self, version: u8) -> Option<HintSender> {
+        let mut shared = self.shared.lock().unwrap();
+        let exchange = shared.requests;
+        shared.requests += 1;
+        if version == 0 {
+            return None;
+        }
+        Some(HintSender {
+            shared: self.shared.clone(),
+            exchange: exchange,
+        })
+    }
+
+    /// The response to the oldest request waiting for one was encoded. Its hints that didn't
+    /// go out before it are dropped.
+    pub fn responded(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        let exchange = shared.responses;
+        shared.responses += 1;
+        shared.queued.retain(|&(e, _)| e > exchange);
+    }
+
+    /// Takes the 103s that can be written now, those of the oldest request waiting for its
+    /// response, for a transport that wrote the responses before it. The current task is
+    /// woken when more are queued.
+    pub fn take(&self) -> Option<Vec<u8>> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.task = Some(task::park());
+        let exchange = shared.responses;
+        let (ready, waiting): (VecDeque<_>, VecDeque<_>) =
+            shared.queued.drain(..).partition(|&(e, _)| e == exchange);
+        shared.queued = waiting;
+        if ready.is_empty() {
+            return None;
+        }
+        Some(ready.into_iter().flat_map(|(_, hints)| hints).collect())
+    }
+}
+
+impl fmt::Debug for HintQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shared = self.shared.lock().unwrap();
+        write!(f, "HintQueue {{ queued: {}, requests: {}, responses: {} }}",
+               shared.queued.len(), shared.requests, shared.responses)
+    }
+}
+
+/// Sends 103 Early Hints ahead of the response to one request.
+#[derive(Clone)]
+pub struct HintSender {
+    shared: Arc<Mutex<Shared>>,
+    exchange: u64,
+}
+
+impl HintSender {
+    /// Queues a 103 with `hints`, to be written right away, or once the responses to the
+    /// requests pipelined ahead of this one are. Returns false, sending nothing, if the
+    /// response was written already.
+    pub fn send(&self, hints: &Headers) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.responses > self.exchange {
+            return false;
+        }
+        if !hints.is_empty() {
+            let mut buf = Vec::new();
+            response::encode_hints(hints, &mut buf);
+            shared.queued.push_back((self.exchange, buf));
+            if let Some(ref task) = shared.task {
+                task.unpark();
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Debug for HintSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HintSender {{ exchange: {} }}", self.exchange)
+    }
+}
+
+/// Wraps a service and adds preload hints from a manifest to its successful responses.
+pub struct EarlyHints<S> {
+    inner: S,
+    manifest: Arc<PushManifest>,
+}
+
+impl<S> EarlyHints<S> {
+    pub fn new(inner: S, manifest: PushManifest) -> EarlyHints<S> {
+        EarlyHints {
+            inner: inner,
+            manifest: Arc::new(manifest),
+        }
+    }
+}
+
+/// Adds a `link` hint for every resource of `route` in `manifest` not already in `hints`.
+pub fn add_hints(hints: &mut Headers, manifest: &PushManifest, route: &str) {
+    for resource in manifest.resources(route) {
+        let link = resource.link();
+        if !hints.iter().any(|&(ref name, ref value)| name.eq_ignore_ascii_case("link") && *value == link) {
+            hints.push(("Link".to_string(), link));
+        }
+    }
+}
+
+impl<S> Service for EarlyHints<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let route = req.path().to_string();
+        // Sent before the handler runs where the connection can, whatever it answers.
+        let sent = req.hints().map_or(false, |sender| {
+            let mut hints = Headers::new();
+            add_hints(&mut hints, &self.manifest, &route);
+            sender.send(&hints)
+        });
+        let manifest = self.manifest.clone();
+        Box::new(self.inner.call(req).map(move |mut res| {
+            // No point preloading for an error page.
+            if !sent && res.code >= 200 && res.code < 300 {
+                add_hints(&mut res.hints, &manifest, &route);
+            }
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Async, Future};
+    use http::Response;
+    use http::response;
+    use http2::push::PushManifest;
+    use super::{add_hints, HintQueue};
+
+    #[test]
+    fn test_hints() {
+        let manifest = PushManifest::from_json(r#"{"/": ["/a.css", "/b.js"]}"#).unwrap();
+        let mut res = Response::new().with_hint("Link", "</a.css>; rel=preload; as=style");
+        add_hints(&mut res.hints, &manifest, "/");
+        assert_eq!(res.hints.len(), 2);
+
+        let mut buf = Vec::new();
+        response::encode(&res, &mut buf);
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload; as=style\r\n\
+                                  Link: </b.js>; rel=preload; as=script\r\n\r\nHTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_hint_queue() {
+        let queue = HintQueue::new();
+        let first = queue.register(1).unwrap();
+        let second = queue.register(1).unwrap();
+        assert!(queue.register(0).is_none());
+        let hints = vec![("Link".to_string(), "</a.css>; rel=preload".to_string())];
+
+        future::lazy(|| {
+            // The second request's hints wait for the response to the first.
+            assert!(second.send(&hints));
+            assert_eq!(queue.take(), None);
+            assert!(first.send(&hints));
+            assert_eq!(queue.take().unwrap(), &b"HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload\r\n\r\n"[..]);
+            queue.responded();
+            assert!(queue.take().is_some());
+
+            // Too late once the response was encoded.
+            queue.responded();
+            assert!(!second.send(&hints));
+            assert!(!first.send(&hints));
+            assert_eq!(queue.take(), None);
+            Ok::<_, ()>(Async::Ready(()))
+        }).wait().unwrap();
+    }
+}
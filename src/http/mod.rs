@@ -13,11 +13,14 @@
 // limitations under the License.
 
 use std::io;
+use std::collections::VecDeque;
+use std::time::Instant;
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::Remote;
 use std::net::SocketAddr;
 
 use tokio_proto::pipeline::ServerProto;
-use tokio_core::io::{Io, Codec, Framed, EasyBuf};
+use tokio_core::io::{Io, Codec, EasyBuf};
 
 use tokio_tls::TlsAcceptorExt;
 use native_tls::{Pkcs12, TlsAcceptor, TlsStream};
@@ -25,6 +28,14 @@ use native_tls::{Pkcs12, TlsAcceptor, TlsStream};
 use Router;
 use Logger;
 use LoggerLevel;
+use metrics::{self, Metrics};
+use leak::{LeakTracker, Tracked};
+use audit::{AuditHook, Reason};
+use self::accept::{AcceptLimiter, Budget, HandshakeDeadline, Permit, Rejection};
+use self::connections::{Connections, Registration};
+use self::hints::HintQueue;
+use self::shed::{ConnectionGuard, LoadShedder, Pending};
+use self::tunnel::Tunnel;
 
 pub use self::request::Request;
 pub use self::response::Response;
@@ -33,6 +44,21 @@ mod date;
 mod request;
 mod response;
 pub mod buffer;
+pub mod accept;
+pub mod negotiate;
+pub mod range;
+pub mod conditional;
+pub mod ratelimit;
+pub mod connections;
+pub mod proxy;
+pub mod tunnel;
+pub mod shed;
+pub mod deadline;
+pub mod hints;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub mod tls;
+#[cfg(feature = "compression")]
+pub mod compress;
 
 /// Proto and Codec can have STATE so you can add features to these two and then pass them to
 /// TcpServer.
@@ -40,24 +66,94 @@ pub mod buffer;
 pub struct HttpProto {
     pub logger: Option<Logger>,
     pub router: Option<Router>,
+    pub metrics: Option<Metrics>,
+    /// Only does anything when built with the `leak-detect` feature.
+    pub leak_tracker: Option<LeakTracker>,
+    pub accept_limiter: Option<AcceptLimiter>,
+    pub audit: Option<AuditHook>,
+    pub connections: Option<Connections>,
+    pub load_shedder: Option<LoadShedder>,
+    /// The event loop the accept limiter's `pre_handshake_timeout` runs on. Without one, the
+    /// timeout is only checked when the peer sends something, so silent peers are not let go.
+    pub timer: Option<Remote>,
 }
 
 // codec here so as to create a Codec that can handle a remote_addr field.
 impl HttpProto {
-    fn codec(&self, remote_addr: SocketAddr, router: Option<Router>, logger: Option<Logger>) -> HttpCodec {
-        HttpCodec{ request: None, remote_addr: Some(remote_addr), router: router, logger: logger }
+    fn codec(&self,
+             remote_addr: SocketAddr,
+             router: Option<Router>,
+             logger: Option<Logger>,
+             permit: Option<Permit>,
+             hints: HintQueue)
+             -> HttpCodec {
+        HttpCodec{
+            request: None,
+            remote_addr: Some(remote_addr),
+            router: router,
+            logger: logger,
+            metrics: self.metrics.clone(),
+            leak_tracker: self.leak_tracker.clone(),
+            in_flight: VecDeque::new(),
+            budget: self.accept_limiter.as_ref().map(|limiter| limiter.budget()),
+            _permit: permit,
+            audit: self.audit.clone(),
+            registration: self.connections.as_ref().map(|connections| connections.register(remote_addr)),
+            load_shedder: self.load_shedder.clone(),
+            _shed_guard: self.load_shedder.as_ref().map(|shedder| shedder.connection_opened()),
+            pending: VecDeque::new(),
+            hints: hints,
+            versions: VecDeque::new(),
+        }
+    }
+
+    fn reject(&self, addr: SocketAddr, rejection: Rejection) -> io::Error {
+        if let Some(ref metrics) = self.metrics {
+            metrics.counter(metrics::names::CONNECTIONS_REJECTED, 1);
+        }
+        if let Some(ref audit) = self.audit {
+            audit.connection_rejected(addr, Reason::Accept(rejection));
+        }
+        io::Error::new(io::ErrorKind::ConnectionRefused, rejection.to_string())
     }
 }
 
 impl ServerProto<TcpStream> for HttpProto {
     type Request = Request;
     type Response = Response;
-    type Transport = Framed<TcpStream, HttpCodec>;
-    type BindTransport = io::Result<Framed<TcpStream, HttpCodec>>;
+    type Transport = HandshakeDeadline<Tunnel>;
+    type BindTransport = io::Result<HandshakeDeadline<Tunnel>>;
 
-    fn bind_transport(&self, io: TcpStream) -> io::Result<Framed<TcpStream, HttpCodec>> {
+    fn bind_transport(&self, io: TcpStream) -> io::Result<HandshakeDeadline<Tunnel>> {
         let addr = io.peer_addr()?;
-        Ok(io.framed(self.codec(addr, self.router.clone(), self.logger.clone())))
+        let permit = match self.accept_limiter {
+            Some(ref limiter) => Some(limiter.admit(&addr).map_err(|rejection| self.reject(addr, rejection))?),
+            None => None,
+        };
+        if let Some(ref metrics) = self.metrics {
+            metrics.counter(metrics::names::CONNECTIONS_ACCEPTED, 1);
+        }
+        let hints = HintQueue::new();
+        let codec = self.codec(addr, self.router.clone(), self.logger.clone(), permit, hints.clone());
+        let connection = codec.registration.as_ref().map(|registration| registration.connection().clone());
+        let framed = Tunnel::new(io.framed(codec), hints, connection);
+        let timeout = self.accept_limiter.as_ref().and_then(|limiter| limiter.config().pre_handshake_timeout);
+        Ok(match (timeout, self.timer.as_ref()) {
+            (Some(timeout), Some(timer)) => {
+                let metrics = self.metrics.clone();
+                let audit = self.audit.clone();
+                HandshakeDeadline::new(framed, timeout, timer, move || {
+                    if let Some(ref metrics) = metrics {
+                        metrics.counter(metrics::names::CONNECTIONS_REJECTED, 1);
+                        metrics.counter(metrics::names::HANDSHAKE_TIMEOUTS, 1);
+                    }
+                    if let Some(ref audit) = audit {
+                        audit.connection_rejected(addr, Reason::Accept(Rejection::PreHandshakeTimeout));
+                    }
+                })
+            },
+            _ => HandshakeDeadline::none(framed),
+        })
     }
 }
 
@@ -68,6 +164,36 @@ pub struct HttpCodec {
     remote_addr: Option<SocketAddr>,
     router: Option<Router>,
     logger: Option<Logger>,
+    metrics: Option<Metrics>,
+    leak_tracker: Option<LeakTracker>,
+    /// One entry per pipelined request that has been decoded but not yet answered.
+    in_flight: VecDeque<Tracked>,
+    /// Pre-handshake budget; the handshake completes with the first full request head.
+    budget: Option<Budget>,
+    /// Connection slot held in the `AcceptLimiter` until the codec is dropped.
+    _permit: Option<Permit>,
+    audit: Option<AuditHook>,
+    /// Entry in the server's `Connections` registry, removed when the codec is dropped.
+    registration: Option<Registration>,
+    load_shedder: Option<LoadShedder>,
+    /// Counts the connection in the `LoadShedder` until the codec is dropped.
+    _shed_guard: Option<ConnectionGuard>,
+    /// Pressure reported to the `LoadShedder`, one entry per request awaiting its response.
+    pending: VecDeque<Pending>,
+    /// The 103s sent by the handlers, which the transport writes ahead of the responses.
+    hints: HintQueue,
+    /// The HTTP/1.x minor version of every request awaiting its response, in order.
+    versions: VecDeque<u8>,
+}
+
+impl HttpCodec {
+    /// The connection is going away: the exchanges still in flight are closed, so the leak
+    /// tracker reports them if the codec is kept alive past its threshold.
+    fn teardown(&mut self) {
+        for tracked in &mut self.in_flight {
+            tracked.close();
+        }
+    }
 }
 
 impl Codec for HttpCodec {
@@ -76,22 +202,86 @@ impl Codec for HttpCodec {
 
     /// HttpCodec::decode can be modified to fit whatever is needed.
     fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Request>> {
+        if let Some(ref budget) = self.budget {
+            if let Err(rejection) = budget.check(buf.len()) {
+                if let Some(ref metrics) = self.metrics {
+                    metrics.counter(metrics::names::CONNECTIONS_REJECTED, 1);
+                    if rejection == Rejection::PreHandshakeTimeout {
+                        metrics.counter(metrics::names::HANDSHAKE_TIMEOUTS, 1);
+                    }
+                }
+                if let (Some(audit), Some(addr)) = (self.audit.as_ref(), self.remote_addr) {
+                    audit.connection_rejected(addr, Reason::Accept(rejection));
+                }
+                self.teardown();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, rejection.to_string()));
+            }
+        }
+
+        if let Some(ref registration) = self.registration {
+            if registration.connection().is_closed() {
+                self.teardown();
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by the server"));
+            }
+        }
+
         match request::decode(buf, self.remote_addr, self.router.clone(), self.logger.clone()) {
             Ok(req) => {
                 match req {
-                    Some(req) => {
+                    Some(mut req) => {
+                        if let Some(ref registration) = self.registration {
+                            req.set_connection(registration.connection().clone());
+                        }
+                        if let Some(sender) = self.hints.register(req.version()) {
+                            req.set_hints(sender);
+                        }
+                        self.versions.push_back(req.version());
+                        if let Some(ref metrics) = self.metrics {
+                            metrics.counter(metrics::names::REQUESTS, 1);
+                        }
+                        if let Some(ref mut budget) = self.budget {
+                            budget.complete();
+                        }
+                        if let Some(ref shedder) = self.load_shedder {
+                            self.pending.push_back(shedder.request_started(Instant::now()));
+                        }
+                        if let Some(ref tracker) = self.leak_tracker {
+                            tracker.sweep();
+                            self.in_flight.push_back(tracker.track("request"));
+                        }
                         self.request = Some(req.clone());
                         Ok(Some(req))
                     }
                     None => Ok(None)
                 }
             }
-            Err(e) => Err(e)
+            Err(e) => {
+                if let Some(ref metrics) = self.metrics {
+                    metrics.counter(metrics::names::REQUEST_ERRORS, 1);
+                }
+                self.teardown();
+                Err(e)
+            }
         }
     }
 
-    fn encode(&mut self, msg: Response, buf: &mut Vec<u8>) -> io::Result<()> {
+    fn encode(&mut self, mut msg: Response, buf: &mut Vec<u8>) -> io::Result<()> {
+        if self.versions.pop_front() == Some(0) {
+            msg.hints.clear();
+        }
+        let start = buf.len();
         response::encode(&msg, buf);
+        self.hints.responded();
+        if let Some(ref metrics) = self.metrics {
+            metrics.counter(metrics::names::RESPONSES, 1);
+            metrics.histogram(metrics::names::RESPONSE_BYTES, (buf.len() - start) as f64);
+        }
+        if let Some(tracked) = self.in_flight.pop_front() {
+            tracked.complete();
+        }
+        if let Some(pending) = self.pending.pop_front() {
+            pending.finish(Instant::now());
+        }
         if self.logger.is_some() {
             let logger = self.logger.clone().unwrap();
             let request = self.request.clone().unwrap();
@@ -0,0 +1,193 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proactive content negotiation (RFC 9110 section 12) for `Accept`, `Accept-Language` and
+//! `Accept-Encoding`.
+//!
+//! ```rust
+//! use tokio_http2::http::negotiate::{negotiate, Field};
+//!
+//! let accept = Some("text/html;q=0.9, application/json");
+//! assert_eq!(negotiate(Field::Accept, accept, &["text/html", "application/json"]),
+//!            Some("application/json"));
+//! ```
+
+/// The header being negotiated; each has its own matching rules.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+    /// Media ranges such as `text/*` (section 12.5.1).
+    Accept,
+    /// Language ranges matched by prefix, `en` matching `en-US` (section 12.5.4, RFC 4647).
+    AcceptLanguage,
+    /// Content codings; `identity` is acceptable unless excluded (section 12.5.3).
+    AcceptEncoding,
+}
+
+impl Field {
+    pub fn header_name(&self) -> &'static str {
+        match *self {
+            Field::Accept => "accept",
+            Field::AcceptLanguage => "accept-language",
+            Field::AcceptEncoding => "accept-encoding",
+        }
+    }
+}
+
+/// One element of a q-weighted list, with its weight in thousandths.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QItem<'a> {
+    pub value: &'a str,
+    pub q: u16,
+}
+
+/// Parses a q-weighted list. Parameters other than `q` are dropped and items keep their order.
+pub fn parse(value: &str) -> Vec<QItem> {
+    let mut items = Vec::new();
+    for element in value.split(',') {
+        let mut parts = element.split(';');
+        let value = parts.next().unwrap_or("").trim();
+        if value.is_empty() {
+            continue;
+        }
+        let mut q = 1000;
+        for param in parts {
+            let param = param.trim();
+            if param.starts_with("q=") || param.starts_with("Q=") {
+                q = parse_q(&param[2..]);
+            }
+        }
+        items.push(QItem { value: value, q: q });
+    }
+    items
+}
+
+fn parse_q(value: &str) -> u16 {
+    match value.trim().parse::<f32>() {
+        Ok(q) if q >= 0.0 && q <= 1.0 => (q * 1000.0).round() as u16,
+        _ => 0,
+    }
+}
+
+/// How specifically `range` matches `candidate`, or `None` if it doesn't match at all.
+fn specificity(field: Field, range: &str, candidate: &str) -> Option<usize> {
+    match field {
+        Field::Accept => {
+            let mut range_parts = range.splitn(2, '/');
+            let mut parts = candidate.splitn(2, '/');
+            let (range_type, range_sub) = (range_parts.next().unwrap_or(""), range_parts.next().unwrap_or(""));
+            let (kind, sub) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            if range_type == "*" && range_sub == "*" {
+                Some(0)
+            } else if !range_type.eq_ignore_ascii_case(kind) {
+                None
+            } else if range_sub == "*" {
+                Some(1)
+            } else if range_sub.eq_ignore_ascii_case(sub) {
+                Some(2)
+            } else {
+                None
+            }
+        },
+        Field::AcceptLanguage => {
+            if range == "*" {
+                Some(0)
+            } else if range.eq_ignore_ascii_case(candidate) || candidate.len() > range.len() &&
+                    candidate.as_bytes()[range.len()] == b'-' &&
+                    candidate[..range.len()].eq_ignore_ascii_case(range) {
+                Some(range.len())
+            } else {
+                None
+            }
+        },
+        Field::AcceptEncoding => {
+            if range == "*" {
+                Some(0)
+            } else if range.eq_ignore_ascii_case(candidate) {
+                Some(1)
+            } else {
+                None
+            }
+        },
+    }
+}
+
+/// The weight `items` give to `candidate`: that of the most specific matching item.
+pub fn quality(field: Field, items: &[QItem], candidate: &str) -> u16 {
+    let mut best: Option<(usize, u16)> = None;
+    for item in items {
+        if let Some(specificity) = specificity(field, item.value, candidate) {
+            if best.map_or(true, |(s, _)| specificity > s) {
+                best = Some((specificity, item.q));
+            }
+        }
+    }
+    match best {
+        Some((_, q)) => q,
+        None if field == Field::AcceptEncoding && candidate.eq_ignore_ascii_case("identity") => 1000,
+        None => 0,
+    }
+}
+
+/// Chooses among `supported`, listed in the server's order of preference, given the value of
+/// the request header for `field`. Without the header every candidate is acceptable and the
+/// first one is chosen; `None` means nothing supported is acceptable to the client (406).
+pub fn negotiate<'s>(field: Field, value: Option<&str>, supported: &[&'s str]) -> Option<&'s str> {
+    let items = match value {
+        Some(value) => parse(value),
+        None => return supported.first().cloned(),
+    };
+
+    let mut chosen: Option<(&'s str, u16)> = None;
+    for &candidate in supported {
+        let q = quality(field, &items, candidate);
+        if q > 0 && chosen.map_or(true, |(_, best)| q > best) {
+            chosen = Some((candidate, q));
+        }
+    }
+    chosen.map(|(candidate, _)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, parse, Field, QItem};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("text/html;level=1;q=0.5, */*;q=0.1 ,,application/json"),
+                   vec![QItem { value: "text/html", q: 500 },
+                        QItem { value: "*/*", q: 100 },
+                        QItem { value: "application/json", q: 1000 }]);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let types = ["application/json", "application/protobuf", "text/html"];
+        assert_eq!(negotiate(Field::Accept, None, &types), Some("application/json"));
+        assert_eq!(negotiate(Field::Accept, Some("text/*, application/*;q=0.5"), &types), Some("text/html"));
+        assert_eq!(negotiate(Field::Accept, Some("*/*;q=0.1, application/protobuf"), &types),
+                   Some("application/protobuf"));
+        assert_eq!(negotiate(Field::Accept, Some("image/*"), &types), None);
+        assert_eq!(negotiate(Field::Accept, Some("application/*, application/json;q=0"), &types),
+                   Some("application/protobuf"));
+
+        let languages = ["en-US", "fr-CA"];
+        assert_eq!(negotiate(Field::AcceptLanguage, Some("fr, en;q=0.8"), &languages), Some("fr-CA"));
+        assert_eq!(negotiate(Field::AcceptLanguage, Some("de"), &languages), None);
+
+        let encodings = ["br", "gzip", "identity"];
+        assert_eq!(negotiate(Field::AcceptEncoding, Some("gzip"), &encodings), Some("gzip"));
+        assert_eq!(negotiate(Field::AcceptEncoding, Some("deflate"), &encodings), Some("identity"));
+        assert_eq!(negotiate(Field::AcceptEncoding, Some("*;q=0"), &encodings), None);
+    }
+}
@@ -0,0 +1,770 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reverse proxy. `Proxy` is a `Service` that forwards requests to a set of upstream origins
+//! over HTTP/1.1: hop-by-hop headers are dropped in both directions, `forwarded` and
+//! `x-forwarded-*` are added, failed attempts are retried on the next upstream where that is
+//! safe, and upstream failures are answered with 502 or 504.
+//!
+//! Upstream connections are kept alive in an `UpstreamPool` and reused by later requests to the
+//! same upstream. Bodies aren't streamed: the HTTP/1 server hands requests over and takes
+//! responses back with their bodies in one piece, so both are buffered, the response up to
+//! `ProxyConfig::max_response_size`. The upstream exchange is blocking and runs on a `CpuPool`
+//! to keep it off the event loop; the backoff between attempts is a timer on the event loop, so
+//! that it holds no pool thread.
+
+use std::error::Error as StdError;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use httparse;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+use http::{Request, Response};
+use http::deadline::DeadlineConfig;
+use StatusCode;
+
+/// Headers that only apply to a single connection and are never forwarded (RFC 9110 section
+/// 7.6.1), in addition to any named by `connection`.
+pub const HOP_BY_HOP: &'static [&'static str] = &["connection", "keep-alive", "proxy-connection",
+                                                  "proxy-authenticate", "proxy-authorization", "te",
+                                                  "trailer", "transfer-encoding", "upgrade"];
+
+/// The most a response head, or the trailers of a chunked body, may take up.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The most a chunk-size line (with its extensions) or a trailer line may take up.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// When a failed attempt is tried again, always on a different upstream than the last one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: usize,
+    /// Pause before each retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// Origins to forward to, used round-robin.
+    pub upstreams: Vec<SocketAddr>,
+    pub connect_timeout: Duration,
+    /// How long a single read or write on the upstream connection may take.
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+    /// Add `forwarded`, `x-forwarded-for`, `x-forwarded-proto` and `x-forwarded-host`.
+    pub forwarded: bool,
+    /// Upstream responses with larger bodies are answered with 502.
+    pub max_response_size: usize,
+    /// Tell upstreams how long is left of a request's deadline, and give up on them once it has
+    /// passed.
+    pub deadline: Option<DeadlineConfig>,
+    /// Idle connections kept for reuse per upstream; 0 closes every connection after its
+    /// response.
+    pub max_idle_per_upstream: usize,
+    /// Idle connections older than this aren't reused.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            upstreams: Vec::new(),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            forwarded: true,
+            max_response_size: 64 * 1024 * 1024,
+            deadline: None,
+            max_idle_per_upstream: 8,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Idle keep-alive connections to the upstreams. Cheap to clone; all clones share the same
+/// connections.
+#[derive(Clone, Debug)]
+pub struct UpstreamPool {
+    idle: Arc<Mutex<HashMap<SocketAddr, Vec<(TcpStream, Instant)>>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> UpstreamPool {
+        UpstreamPool {
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            max_idle: max_idle,
+            idle_timeout: idle_timeout,
+        }
+    }
+
+    /// The most recently used idle connection to `upstream`, leaving out those that idled too
+    /// long or that the upstream closed or sent something on meanwhile.
+    pub fn take(&self, upstream: SocketAddr) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = match idle.get_mut(&upstream) {
+            Some(streams) => streams,
+            None => return None,
+        };
+        while let Some((stream, since)) = streams.pop() {
+            if since.elapsed() < self.idle_timeout && is_idle(&stream) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Keeps `stream` for the next request to `upstream`, unless as many are kept already.
+    pub fn put(&self, upstream: SocketAddr, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(upstream).or_insert_with(Vec::new);
+        if streams.len() < self.max_idle {
+            streams.push((stream, Instant::now()));
+        }
+    }
+
+    /// The connections to `upstream` kept for reuse.
+    pub fn idle(&self, upstream: SocketAddr) -> usize {
+        self.idle.lock().unwrap().get(&upstream).map_or(0, |streams| streams.len())
+    }
+}
+
+/// Whether the upstream neither closed `stream` nor sent anything on it since its last response.
+fn is_idle(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let idle = match stream.peek(&mut [0]) {
+        Err(ref err) => err.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    };
+    stream.set_nonblocking(false).is_ok() && idle
+}
+
+/// Why forwarding to an upstream failed.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The upstream could not be reached; nothing was sent.
+    Connect(io::Error),
+    /// The upstream did not answer in time.
+    Timeout,
+    Io(io::Error),
+    /// The upstream sent something that isn't an HTTP/1.x response.
+    InvalidResponse,
+    /// The upstream body exceeded `ProxyConfig::max_response_size`.
+    ResponseTooLarge,
+    NoUpstream,
+}
+
+impl ProxyError {
+    /// The status the client is answered with.
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            ProxyError::Timeout => StatusCode::GatewayTimeout,
+            _ => StatusCode::BadGateway,
+        }
+    }
+
+    /// Whether another upstream may be tried. Requests that reached an upstream are only
+    /// repeated if their method is idempotent.
+    pub fn retriable(&self, idempotent: bool) -> bool {
+        match *self {
+            ProxyError::Connect(_) => true,
+            ProxyError::Timeout | ProxyError::Io(_) => idempotent,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyError::Connect(ref err) => write!(f, "upstream connect failed: {}", err),
+            ProxyError::Io(ref err) => write!(f, "upstream I/O failed: {}", err),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl StdError for ProxyError {
+    fn description(&self) -> &str {
+        match *self {
+            ProxyError::Connect(_) => "upstream connect failed",
+            ProxyError::Timeout => "upstream timed out",
+            ProxyError::Io(_) => "upstream I/O failed",
+            ProxyError::InvalidResponse => "invalid upstream response",
+            ProxyError::ResponseTooLarge => "upstream response too large",
+            ProxyError::NoUpstream => "no upstream configured",
+        }
+    }
+}
+
+fn io_error(err: io::Error) -> ProxyError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProxyError::Timeout,
+        _ => ProxyError::Io(err),
+    }
+}
+
+/// A request as it is sent upstream, owned so it can move to the pool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub idempotent: bool,
+    pub deadline: Option<Instant>,
+}
+
+impl UpstreamRequest {
+    /// Copies `req`, dropping hop-by-hop headers and, if `forwarded` is set, recording the client
+    /// in the forwarding headers.
+    pub fn from_request(req: &Request, forwarded: bool) -> UpstreamRequest {
+        let mut headers: Vec<(String, String)> = req.headers()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        strip_hop_by_hop(&mut headers);
+        headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("content-length"));
+
+        if forwarded {
+            if let Some(addr) = req.remote_addr() {
+                let host = req.header("host").map(|host| host.to_string());
+                add_forwarded(&mut headers, addr, req.scheme(), host.as_ref().map(|h| &h[..]));
+            }
+        }
+
+        let method = req.method();
+        UpstreamRequest {
+            method: method.to_string(),
+            uri: req.uri().to_string(),
+            headers: headers,
+            body: req.payload().map(|body| body.to_vec()).unwrap_or_default(),
+            idempotent: method.idempotent(),
+            deadline: req.deadline(),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, keep_alive: bool) {
+        buf.extend_from_slice(format!("{} {} HTTP/1.1\r\n", self.method, self.uri).as_bytes());
+        for &(ref name, ref value) in &self.headers {
+            buf.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        buf.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        if !keep_alive {
+            buf.extend_from_slice(b"Connection: close\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&self.body);
+    }
+}
+
+/// Removes the hop-by-hop headers, including those listed in `connection`.
+pub fn strip_hop_by_hop(headers: &mut Vec<(String, String)>) {
+    let listed: Vec<String> = headers.iter()
+        .filter(|&&(ref name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|&(_, ref value)| value.split(',').map(|v| v.trim().to_lowercase()).collect::<Vec<_>>())
+        .collect();
+    headers.retain(|&(ref name, _)| {
+        let name = name.to_lowercase();
+        !HOP_BY_HOP.contains(&&name[..]) && !listed.contains(&name)
+    });
+}
+
+/// Appends the client to `forwarded` and `x-forwarded-for` (keeping what earlier proxies added)
+/// and sets `x-forwarded-proto` and `x-forwarded-host`.
+pub fn add_forwarded(headers: &mut Vec<(String, String)>, client: SocketAddr, proto: &str, host: Option<&str>) {
+    // IPv6 addresses have to be quoted and bracketed in `forwarded` (RFC 7239 section 6).
+    let node = match client {
+        SocketAddr::V4(addr) => addr.ip().to_string(),
+        SocketAddr::V6(addr) => format!("\"[{}]\"", addr.ip()),
+    };
+    let proto = if proto.is_empty() { "http" } else { proto };
+    let mut element = format!("for={};proto={}", node, proto);
+    if let Some(host) = host {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+
+    append(headers, "Forwarded", &element);
+    append(headers, "X-Forwarded-For", &client.ip().to_string());
+    headers.retain(|&(ref name, _)| {
+        !name.eq_ignore_ascii_case("x-forwarded-proto") && !name.eq_ignore_ascii_case("x-forwarded-host")
+    });
+    headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+    if let Some(host) = host {
+        headers.push(("X-Forwarded-Host".to_string(), host.to_string()));
+    }
+}
+
+fn append(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    for header in headers.iter_mut() {
+        if header.0.eq_ignore_ascii_case(name) {
+            header.1.push_str(", ");
+            header.1.push_str(value);
+            return;
+        }
+    }
+    headers.push((name.to_string(), value.to_string()));
+}
+
+/// Sends `req` to `upstream` and reads the whole response, on an idle connection of `pool` if
+/// there is one and the request is idempotent: a reused connection the upstream closes meanwhile
+/// fails the attempt, and only idempotent requests can be tried again after that. The
+/// connection goes back to `pool` if the upstream keeps it open. Blocking.
+pub fn forward(config: &ProxyConfig, pool: &UpstreamPool, req: &UpstreamRequest, upstream: SocketAddr)
+               -> Result<Response, ProxyError> {
+    // No single wait may outlast the request's deadline.
+    let remaining = match req.deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if deadline <= now {
+                return Err(ProxyError::Timeout);
+            }
+            Some(deadline - now)
+        },
+        None => None,
+    };
+    let cap = |timeout: Duration| remaining.map_or(timeout, |remaining| if remaining < timeout { remaining } else { timeout });
+
+    let reused = if req.idempotent { pool.take(upstream) } else { None };
+    let mut stream = match reused {
+        Some(stream) => stream,
+        None => try!(TcpStream::connect_timeout(&upstream, cap(config.connect_timeout)).map_err(|err| {
+            if err.kind() == io::ErrorKind::TimedOut { ProxyError::Timeout } else { ProxyError::Connect(err) }
+        })),
+    };
+    try!(stream.set_read_timeout(Some(cap(config.timeout))).map_err(ProxyError::Io));
+    try!(stream.set_write_timeout(Some(cap(config.timeout))).map_err(ProxyError::Io));
+    let _ = stream.set_nodelay(true);
+
+    let keep_alive = config.max_idle_per_upstream > 0;
+    let mut buf = Vec::new();
+    match (config.deadline.as_ref(), req.deadline) {
+        (Some(deadline_config), Some(deadline)) => {
+            let mut req = req.clone();
+            deadline_config.stamp(&mut req.headers, deadline, Instant::now());
+            req.encode(&mut buf, keep_alive);
+        },
+        _ => req.encode(&mut buf, keep_alive),
+    }
+    try!(stream.write_all(&buf).map_err(io_error));
+
+    let (res, reusable) = try!(read_response(&mut stream, req.method == "HEAD", config.max_response_size));
+    if keep_alive && reusable {
+        pool.put(upstream, stream);
+    }
+    Ok(res)
+}
+
+/// Reads a response, and whether the connection can take another request after it: the
+/// upstream didn't ask to close it, the body had a length and nothing followed it.
+fn read_response<R: Read>(stream: &mut R, head: bool, max_size: usize) -> Result<(Response, bool), ProxyError> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        let (amt, mut res, http11) = {
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut parsed = httparse::Response::new(&mut headers);
+            match parsed.parse(&buf) {
+                Ok(httparse::Status::Complete(amt)) => {
+                    let code = parsed.code.unwrap_or(0);
+                    let mut res = Response::new().status_code(code, parsed.reason.unwrap_or(""));
+                    for header in parsed.headers.iter() {
+                        res.headers.push((header.name.to_string(), String::from_utf8_lossy(header.value).into_owned()));
+                    }
+                    (Some(amt), res, parsed.version == Some(1))
+                },
+                Ok(httparse::Status::Partial) => (None, Response::new(), false),
+                Err(_) => return Err(ProxyError::InvalidResponse),
+            }
+        };
+
+        let amt = match amt {
+            Some(amt) => amt,
+            None => {
+                if buf.len() > MAX_HEAD_SIZE {
+                    return Err(ProxyError::InvalidResponse);
+                }
+                let n = try!(stream.read(&mut chunk).map_err(io_error));
+                if n == 0 {
+                    return Err(if buf.is_empty() {
+                        ProxyError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection"))
+                    } else {
+                        ProxyError::InvalidResponse
+                    });
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                continue;
+            },
+        };
+
+        // Interim responses (100 Continue, 103 Early Hints) aren't relayed.
+        if res.code >= 100 && res.code < 200 {
+            buf.drain(..amt);
+            continue;
+        }
+
+        let rest = buf.split_off(amt);
+        let chunked = res.header("transfer-encoding").map_or(false, |te| te.to_lowercase().contains("chunked"));
+        let length = res.header("content-length").and_then(|len| len.trim().parse::<usize>().ok());
+        let close = !http11 || res.headers.iter().any(|&(ref name, ref value)| {
+            name.eq_ignore_ascii_case("connection") && value.split(',').any(|v| v.trim().eq_ignore_ascii_case("close"))
+        });
+        strip_hop_by_hop(&mut res.headers);
+        // The server writes its own `date`, and the body length is known once it is buffered
+        // (except for HEAD, where the upstream's `content-length` is kept).
+        res.headers.retain(|&(ref name, _)| {
+            !name.eq_ignore_ascii_case("date") && (head || !name.eq_ignore_ascii_case("content-length"))
+        });
+
+        let mut reader = BufReader::new(Cursor::new(rest).chain(stream));
+        let mut delimited = true;
+        let body = if head || res.code == 204 || res.code == 304 {
+            Vec::new()
+        } else if chunked {
+            try!(read_chunked(&mut reader, max_size))
+        } else if let Some(length) = length {
+            if length > max_size {
+                return Err(ProxyError::ResponseTooLarge);
+            }
+            let mut body = Vec::with_capacity(length);
+            try!((&mut reader).take(length as u64).read_to_end(&mut body).map_err(io_error));
+            if body.len() < length {
+                return Err(ProxyError::InvalidResponse);
+            }
+            body
+        } else {
+            delimited = false;
+            let mut body = Vec::new();
+            try!((&mut reader).take(max_size as u64 + 1).read_to_end(&mut body).map_err(io_error));
+            if body.len() > max_size {
+                return Err(ProxyError::ResponseTooLarge);
+            }
+            body
+        };
+        // Anything read past the response would be taken for the start of the next one.
+        let drained = reader.buffer().is_empty() && {
+            let (rest, _) = reader.into_inner().into_inner();
+            rest.position() as usize == rest.get_ref().len()
+        };
+
+        if !head {
+            let length = body.len().to_string();
+            res = res.with_header("Content-Length", &length);
+        }
+        return Ok((res.with_body(body), delimited && !close && drained));
+    }
+}
+
+/// Reads a line of at most `MAX_LINE_SIZE` octets into `line`, without its CRLF.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> Result<(), ProxyError> {
+    line.clear();
+    try!(reader.take(MAX_LINE_SIZE as u64 + 2).read_until(b'\n', line).map_err(io_error));
+    if line.pop() != Some(b'\n') {
+        // Too long, or the upstream closed the connection in the middle of it.
+        return Err(ProxyError::InvalidResponse);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(())
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, ProxyError> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        try!(read_line(reader, &mut line));
+        let size = line.split(|&b| b == b';').next().and_then(|size| ::std::str::from_utf8(size).ok());
+        let size = try!(size.and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                        .ok_or(ProxyError::InvalidResponse));
+        if size == 0 {
+            // Trailers are hop-by-hop here: read and drop them, as long as they fit in a head.
+            let mut trailers = 0;
+            loop {
+                try!(read_line(reader, &mut line));
+                if line.is_empty() {
+                    return Ok(body);
+                }
+                trailers += line.len() + 2;
+                if trailers > MAX_HEAD_SIZE {
+                    return Err(ProxyError::InvalidResponse);
+                }
+            }
+        }
+        if size.checked_add(body.len()).map_or(true, |len| len > max_size) {
+            return Err(ProxyError::ResponseTooLarge);
+        }
+        let start = body.len();
+        try!(reader.take(size as u64).read_to_end(&mut body).map_err(io_error));
+        if body.len() - start < size {
+            return Err(ProxyError::InvalidResponse);
+        }
+        try!(read_line(reader, &mut line));
+        if !line.is_empty() {
+            return Err(ProxyError::InvalidResponse);
+        }
+    }
+}
+
+/// A `Service` forwarding every request upstream.
+#[derive(Clone)]
+pub struct Proxy {
+    config: Arc<ProxyConfig>,
+    pool: CpuPool,
+    upstreams: UpstreamPool,
+    next: Arc<AtomicUsize>,
+    handle: Handle,
+}
+
+impl Proxy {
+    /// Runs the upstream exchanges on `pool`, and the retry backoff on the event loop of
+    /// `handle`.
+    pub fn new(config: ProxyConfig, pool: CpuPool, handle: Handle) -> Proxy {
+        Proxy {
+            upstreams: UpstreamPool::new(config.max_idle_per_upstream, config.idle_timeout),
+            config: Arc::new(config),
+            pool: pool,
+            next: Arc::new(AtomicUsize::new(0)),
+            handle: handle,
+        }
+    }
+
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    pub fn upstreams(&self) -> &UpstreamPool {
+        &self.upstreams
+    }
+
+    /// Forwards `req`, retrying on other upstreams as the retry policy allows. Blocking, the
+    /// backoff included: for callers off the event loop and the pool; `call` doesn't use it.
+    pub fn send(config: &ProxyConfig, pool: &UpstreamPool, req: &UpstreamRequest, first: usize)
+                -> Result<Response, ProxyError> {
+        if config.upstreams.is_empty() {
+            return Err(ProxyError::NoUpstream);
+        }
+        let mut attempt = 0;
+        loop {
+            let upstream = config.upstreams[(first + attempt) % config.upstreams.len()];
+            match forward(config, pool, req, upstream) {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    if attempt >= config.retry.retries || !err.retriable(req.idempotent) {
+                        return Err(err);
+                    }
+                },
+            }
+            attempt += 1;
+            thread::sleep(config.retry.backoff);
+        }
+    }
+
+    /// Makes attempt number `attempt` of forwarding `req` on the pool, and on a failure that
+    /// may be retried schedules the next one after the backoff.
+    fn attempt(&self, req: Arc<UpstreamRequest>, first: usize, attempt: usize)
+            -> Box<Future<Item = Response, Error = ProxyError>> {
+        let upstream = self.config.upstreams[(first + attempt) % self.config.upstreams.len()];
+        let forwarded = {
+            let (config, upstreams) = (self.config.clone(), self.upstreams.clone());
+            let req = req.clone();
+            self.pool.spawn_fn(move || forward(&config, &upstreams, &req, upstream))
+        };
+        let proxy = self.clone();
+        Box::new(forwarded.or_else(move |err| -> Box<Future<Item = Response, Error = ProxyError>> {
+            if attempt >= proxy.config.retry.retries || !err.retriable(req.idempotent) {
+                return Box::new(future::err(err));
+            }
+            match Timeout::new(proxy.config.retry.backoff, &proxy.handle) {
+                Ok(timeout) => Box::new(timeout.map_err(ProxyError::Io).and_then(move |_| proxy.attempt(req, first, attempt + 1))),
+                Err(err) => Box::new(future::err(ProxyError::Io(err))),
+            }
+        }))
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Proxy {{ config: {:?} }}", self.config)
+    }
+}
+
+impl Service for Proxy {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Response, Error = io::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let upstream_req = Arc::new(UpstreamRequest::from_request(&req, self.config.forwarded));
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        let res: Box<Future<Item = Response, Error = ProxyError>> = if self.config.upstreams.is_empty() {
+            Box::new(future::err(ProxyError::NoUpstream))
+        } else {
+            self.attempt(upstream_req, first, 0)
+        };
+        Box::new(res.or_else(|err| {
+            let body = err.to_string().into_bytes();
+            let length = body.len().to_string();
+            Ok::<_, io::Error>(Response::new()
+                .with_status(err.status())
+                .with_header("Content-Type", "text/plain")
+                .with_header("Content-Length", &length)
+                .with_body(body))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::{add_forwarded, read_chunked, strip_hop_by_hop, Proxy, ProxyConfig, ProxyError, UpstreamPool,
+                UpstreamRequest, MAX_HEAD_SIZE, MAX_LINE_SIZE};
+
+    #[test]
+    fn test_headers() {
+        let mut headers = vec![("Connection".to_string(), "close, X-Secret".to_string()),
+                               ("X-Secret".to_string(), "1".to_string()),
+                               ("Keep-Alive".to_string(), "timeout=5".to_string()),
+                               ("Accept".to_string(), "*/*".to_string()),
+                               ("X-Forwarded-For".to_string(), "10.0.0.1".to_string())];
+        strip_hop_by_hop(&mut headers);
+        add_forwarded(&mut headers, "[2001:db8::1]:4711".parse().unwrap(), "https", Some("example.com"));
+        let get = |name: &str| headers.iter().find(|h| h.0.eq_ignore_ascii_case(name)).map(|h| &h.1[..]);
+        assert_eq!(get("x-secret"), None);
+        assert_eq!(get("keep-alive"), None);
+        assert_eq!(get("accept"), Some("*/*"));
+        assert_eq!(get("x-forwarded-for"), Some("10.0.0.1, 2001:db8::1"));
+        assert_eq!(get("forwarded"), Some("for=\"[2001:db8::1]\";proto=https;host=\"example.com\""));
+        assert_eq!(get("x-forwarded-proto"), Some("https"));
+    }
+
+    #[test]
+    fn test_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).unwrap();
+            let first = String::from_utf8_lossy(&buf[..n]).into_owned();
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n\
+                               HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nKeep-Alive: 5\r\n\r\n\
+                               5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n").unwrap();
+            // The next request comes on the same connection.
+            let n = stream.read(&mut buf).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nbye").unwrap();
+            (first, String::from_utf8_lossy(&buf[..n]).into_owned())
+        });
+
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = ProxyConfig { upstreams: vec![dead, upstream], .. ProxyConfig::default() };
+        let pool = UpstreamPool::new(config.max_idle_per_upstream, config.idle_timeout);
+        let req = UpstreamRequest {
+            method: "GET".to_string(),
+            uri: "/a?b=c".to_string(),
+            headers: vec![("Host".to_string(), "example.com".to_string())],
+            body: Vec::new(),
+            idempotent: true,
+            deadline: None,
+        };
+        // The first upstream refuses the connection; the request is retried on the second.
+        let res = Proxy::send(&config, &pool, &req, 0).unwrap();
+        assert_eq!(res.code, 200);
+        assert_eq!(res.body, b"hello world");
+        assert_eq!(res.header("content-length"), Some("11"));
+        assert_eq!(res.header("keep-alive"), None);
+        assert_eq!(pool.idle(upstream), 1);
+
+        // The connection is reused, and not kept once the upstream asks for it to be closed.
+        let res = Proxy::send(&config, &pool, &req, 1).unwrap();
+        assert_eq!(res.body, b"bye");
+        assert_eq!(res.header("connection"), None);
+        assert_eq!(pool.idle(upstream), 0);
+        let (first, second) = server.join().unwrap();
+        assert!(first.starts_with("GET /a?b=c HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(!first.contains("Connection: close"));
+        assert!(second.starts_with("GET /a?b=c HTTP/1.1\r\n"));
+
+        let config = ProxyConfig { upstreams: vec![dead], .. ProxyConfig::default() };
+        match Proxy::send(&config, &pool, &req, 0) {
+            Err(err @ ProxyError::Connect(_)) => assert_eq!(err.status().to_u16(), 502),
+            other => panic!("unexpected {:?}", other.map(|res| res.code)),
+        }
+    }
+
+    #[test]
+    fn test_chunk_size_overflow() {
+        // A size that would wrap the length check must not lead to an unbounded read.
+        let mut body = &b"5\r\nhello\r\nffffffffffffffff\r\n"[..];
+        match read_chunked(&mut body, 1024) {
+            Err(ProxyError::ResponseTooLarge) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_chunk_lines() {
+        let mut body = &b"5;ext=1\r\nhello\r\n0\r\nx-checksum: 1\r\n\r\n"[..];
+        assert_eq!(read_chunked(&mut body, 1024).unwrap(), b"hello");
+
+        // Neither a size line nor the trailers may go on without end.
+        let long = format!("5;{}\r\nhello\r\n", "x".repeat(MAX_LINE_SIZE));
+        match read_chunked(&mut long.as_bytes(), 1024) {
+            Err(ProxyError::InvalidResponse) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let long = format!("0\r\nx-trailer: {}\r\n\r\n", "x".repeat(MAX_LINE_SIZE));
+        match read_chunked(&mut long.as_bytes(), 1024) {
+            Err(ProxyError::InvalidResponse) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let many = format!("0\r\n{}\r\n", "x-trailer: 1\r\n".repeat(MAX_HEAD_SIZE / 14 + 1));
+        match read_chunked(&mut many.as_bytes(), 1024) {
+            Err(ProxyError::InvalidResponse) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        // The CRLF after the data has to be there.
+        match read_chunked(&mut &b"5\r\nhelloX\r\n0\r\n\r\n"[..], 1024) {
+            Err(ProxyError::InvalidResponse) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
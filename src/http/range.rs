@@ -0,0 +1,266 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Range requests (RFC 9110 section 14). `RangedBody` serves single ranges, multiple ranges as
+//! `multipart/byteranges` and unsatisfiable ranges as 416 over any `Read + Seek` source, so a
+//! storage backend only has to hand over its object.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use http::Response;
+use StatusCode;
+
+/// At most this many ranges are served; beyond that the full body is sent instead, which keeps
+/// requests with thousands of tiny ranges from being amplified.
+pub const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range, already resolved against the length of the representation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ranges {
+    /// No (usable) `range` header: send everything with 200.
+    Full,
+    /// Send these ranges with 206. Sorted and without overlaps.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlap the representation: 416.
+    Unsatisfiable,
+}
+
+/// Parses a `range` header value for a representation of `len` bytes. Syntactically invalid
+/// headers and units other than `bytes` are ignored, as the RFC requires.
+pub fn parse(value: &str, len: u64) -> Ranges {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in value[6..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return Ranges::Full,
+        };
+        let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+        let range = if first.is_empty() {
+            // Suffix range: the last `last` bytes.
+            match last.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) if len > 0 => Some(ByteRange { start: len.saturating_sub(suffix), end: len - 1 }),
+                Ok(_) => None,
+                Err(_) => return Ranges::Full,
+            }
+        } else {
+            let start = match first.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return Ranges::Full,
+            };
+            let end = if last.is_empty() {
+                len.saturating_sub(1)
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return Ranges::Full,
+                }
+            };
+            if start < len {
+                Some(ByteRange { start: start, end: if end < len { end } else { len - 1 } })
+            } else {
+                None
+            }
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    // Coalesce overlapping and adjacent ranges.
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if let Some(last) = merged.last_mut() {
+            if range.start <= last.end + 1 {
+                if range.end > last.end {
+                    last.end = range.end;
+                }
+                continue;
+            }
+        }
+        merged.push(range);
+    }
+
+    if merged.len() > MAX_RANGES {
+        Ranges::Full
+    } else {
+        Ranges::Partial(merged)
+    }
+}
+
+/// A response body that honours a `range` header.
+pub struct RangedBody<R> {
+    source: R,
+    len: u64,
+    ranges: Ranges,
+}
+
+impl<R: Read + Seek> RangedBody<R> {
+    /// Wraps `source`, evaluating the request's `range` header (if any) against its length.
+    /// Pass `None` when `conditional::Conditions::if_range` says the range no longer applies.
+    pub fn new(mut source: R, range: Option<&str>) -> io::Result<RangedBody<R>> {
+        let len = try!(source.seek(SeekFrom::End(0)));
+        let ranges = match range {
+            Some(range) => parse(range, len),
+            None => Ranges::Full,
+        };
+        Ok(RangedBody {
+            source: source,
+            len: len,
+            ranges: ranges,
+        })
+    }
+
+    /// Length of the whole representation.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn ranges(&self) -> &Ranges {
+        &self.ranges
+    }
+
+    /// Fills in status, `content-range`, `content-length` and body of `res`. The `content-type`
+    /// already set on `res` is used for the parts of a multi-range response.
+    pub fn respond(mut self, res: Response) -> io::Result<Response> {
+        let len = self.len;
+        let mut res = res.with_header("Accept-Ranges", "bytes");
+
+        match self.ranges.clone() {
+            Ranges::Full => {
+                let body = try!(self.read(ByteRange { start: 0, end: len.saturating_sub(1) }, len));
+                res = res.with_body(body);
+            },
+            Ranges::Unsatisfiable => {
+                res = res.with_status(StatusCode::RangeNotSatisfiable)
+                         .with_header("Content-Range", &format!("bytes */{}", len))
+                         .with_body(Vec::new());
+            },
+            Ranges::Partial(ref ranges) if ranges.len() == 1 => {
+                let range = ranges[0];
+                let body = try!(self.read(range, len));
+                res = res.with_status(StatusCode::PartialContent)
+                         .with_header("Content-Range", &format!("bytes {}-{}/{}", range.start, range.end, len))
+                         .with_body(body);
+            },
+            Ranges::Partial(ref ranges) => {
+                let boundary = ::random_alphanumeric(24);
+                let content_type = res.header("content-type").map(|t| t.to_string());
+                let mut body = Vec::new();
+                for &range in ranges {
+                    body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                    if let Some(ref content_type) = content_type {
+                        body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+                    }
+                    body.extend_from_slice(format!("Content-Range: bytes {}-{}/{}\r\n\r\n",
+                                                   range.start, range.end, len).as_bytes());
+                    body.extend_from_slice(&try!(self.read(range, len)));
+                    body.extend_from_slice(b"\r\n");
+                }
+                body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+                res.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("content-type"));
+                res = res.with_status(StatusCode::PartialContent)
+                         .with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+                         .with_body(body);
+            },
+        }
+
+        res.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("content-length"));
+        let length = res.body.len().to_string();
+        Ok(res.with_header("Content-Length", &length))
+    }
+
+    fn read(&mut self, range: ByteRange, len: u64) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        try!(self.source.seek(SeekFrom::Start(range.start)));
+        let mut buf = Vec::with_capacity(range.len() as usize);
+        try!((&mut self.source).take(range.len()).read_to_end(&mut buf));
+        if (buf.len() as u64) < range.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "source shorter than its length"));
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use http::Response;
+    use super::{parse, ByteRange, RangedBody, Ranges};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("bytes=0-9", 100), Ranges::Partial(vec![ByteRange { start: 0, end: 9 }]));
+        assert_eq!(parse("bytes=-10", 100), Ranges::Partial(vec![ByteRange { start: 90, end: 99 }]));
+        assert_eq!(parse("bytes=95-", 100), Ranges::Partial(vec![ByteRange { start: 95, end: 99 }]));
+        assert_eq!(parse("bytes=90-200", 100), Ranges::Partial(vec![ByteRange { start: 90, end: 99 }]));
+        assert_eq!(parse("bytes=10-19, 0-4, 5-9", 100), Ranges::Partial(vec![ByteRange { start: 0, end: 19 }]));
+        assert_eq!(parse("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=9-0", 100), Ranges::Full);
+        assert_eq!(parse("items=0-1", 100), Ranges::Full);
+    }
+
+    #[test]
+    fn test_respond() {
+        let data: Vec<u8> = (0..100).collect();
+
+        let res = RangedBody::new(Cursor::new(data.clone()), Some("bytes=10-19")).unwrap()
+            .respond(Response::new()).unwrap();
+        assert_eq!(res.code, 206);
+        assert_eq!(res.header("content-range"), Some("bytes 10-19/100"));
+        assert_eq!(res.body, &data[10..20]);
+
+        let res = RangedBody::new(Cursor::new(data.clone()), Some("bytes=0-0,-1")).unwrap()
+            .respond(Response::new().with_header("Content-Type", "text/plain")).unwrap();
+        assert_eq!(res.code, 206);
+        assert!(res.header("content-type").unwrap().starts_with("multipart/byteranges; boundary="));
+        assert_eq!(res.content_length(), res.body.len() as u64);
+
+        let res = RangedBody::new(Cursor::new(data), Some("bytes=500-")).unwrap()
+            .respond(Response::new()).unwrap();
+        assert_eq!(res.code, 416);
+        assert_eq!(res.header("content-range"), Some("bytes */100"));
+    }
+}
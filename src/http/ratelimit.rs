@@ -0,0 +1,270 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token-bucket rate limiting. A `RateLimiter` keeps one bucket per key, where the key is
+//! extracted from the request (peer IP, authenticated principal, route, ...). It is cheap to
+//! clone and shared by every connection of a server. `RateLimit` wraps a `Service` and answers
+//! 429 with `retry-after` once a bucket is empty.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_service::Service;
+
+use http::{Request, Response};
+use metrics::{self, Metrics};
+use StatusCode;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BucketConfig {
+    /// Burst size: the number of requests a full bucket allows at once.
+    pub capacity: u32,
+    /// Tokens added back per second.
+    pub refill_per_second: f64,
+    /// Number of buckets kept. Past it, the bucket used least recently is dropped, and its key
+    /// starts over with a full one.
+    pub max_keys: usize,
+}
+
+impl Default for BucketConfig {
+    fn default() -> BucketConfig {
+        BucketConfig {
+            capacity: 100,
+            refill_per_second: 10.0,
+            max_keys: 100000,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket was last used, in `Buckets::uses`.
+    used: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * config.refill_per_second).min(config.capacity as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// The keys by when their bucket was last used, the least recently used first.
+    recent: BTreeMap<u64, String>,
+    /// Buckets used so far.
+    uses: u64,
+}
+
+impl Buckets {
+    /// The bucket of `key`, marked as the most recently used. A new key drops the least
+    /// recently used bucket when there are `max_keys` already.
+    fn get(&mut self, key: &str, config: &BucketConfig, now: Instant) -> &mut Bucket {
+        let used = self.uses;
+        self.uses += 1;
+        if let Some(bucket) = self.buckets.get_mut(key) {
+            self.recent.remove(&bucket.used);
+            self.recent.insert(used, key.to_string());
+            bucket.used = used;
+        }
+        if !self.buckets.contains_key(key) {
+            if self.buckets.len() >= config.max_keys {
+                let oldest = self.recent.keys().next().cloned();
+                if let Some(oldest) = oldest {
+                    let evicted = self.recent.remove(&oldest).unwrap();
+                    self.buckets.remove(&evicted);
+                }
+            }
+            self.recent.insert(used, key.to_string());
+            self.buckets.insert(key.to_string(), Bucket {
+                tokens: config.capacity as f64,
+                updated: now,
+                used: used,
+            });
+        }
+        self.buckets.get_mut(key).unwrap()
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: BucketConfig,
+    buckets: Arc<Mutex<Buckets>>,
+    key: Arc<Fn(&Request) -> Option<String> + Send + Sync>,
+    metrics: Option<Metrics>,
+}
+
+impl RateLimiter {
+    /// Limits requests by the key `key` extracts from them. Requests for which it returns
+    /// `None` are not limited.
+    pub fn new<F>(config: BucketConfig, key: F) -> RateLimiter
+            where F: Fn(&Request) -> Option<String> + Send + Sync + 'static {
+        RateLimiter {
+            config: config,
+            buckets: Arc::new(Mutex::new(Buckets::default())),
+            key: Arc::new(key),
+            metrics: None,
+        }
+    }
+
+    /// Limits requests per peer IP address.
+    pub fn per_peer(config: BucketConfig) -> RateLimiter {
+        RateLimiter::new(config, |req| req.remote_addr().map(|addr| addr.ip().to_string()))
+    }
+
+    /// Limits requests per request path.
+    pub fn per_route(config: BucketConfig) -> RateLimiter {
+        RateLimiter::new(config, |req| Some(req.path().to_string()))
+    }
+
+    /// Counts limited requests under `metrics::names::RATE_LIMITED`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> RateLimiter {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn config(&self) -> &BucketConfig {
+        &self.config
+    }
+
+    /// Takes a token for `req`, or returns how long to wait until one is available.
+    pub fn check(&self, req: &Request) -> Result<(), Duration> {
+        match (self.key)(req) {
+            Some(key) => self.check_key(&key, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a token from the bucket of `key` at `now`, or returns how long to wait until one is
+    /// available.
+    pub fn check_key(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let config = self.config;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(key, &config, now);
+        bucket.refill(&config, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.counter(metrics::names::RATE_LIMITED, 1);
+        }
+        let wait = if config.refill_per_second > 0.0 {
+            (1.0 - bucket.tokens) / config.refill_per_second
+        } else {
+            u32::max_value() as f64
+        };
+        Err(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RateLimiter {{ config: {:?} }}", self.config)
+    }
+}
+
+/// Wraps a service and rejects requests over the limit with 429.
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> RateLimit<S> {
+    pub fn new(inner: S, limiter: RateLimiter) -> RateLimit<S> {
+        RateLimit {
+            inner: inner,
+            limiter: limiter,
+        }
+    }
+}
+
+impl<S> Service for RateLimit<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        match self.limiter.check(&req) {
+            Ok(()) => Box::new(self.inner.call(req)),
+            Err(wait) => {
+                // Round up: a client retrying after `retry-after` seconds must find a token.
+                let seconds = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                Box::new(future::ok(Response::new()
+                    .with_status(StatusCode::TooManyRequests)
+                    .with_header("Retry-After", &seconds.to_string())
+                    .with_header("Content-Length", "0")))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BucketConfig, RateLimiter};
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(BucketConfig {
+            capacity: 2,
+            refill_per_second: 1.0,
+            max_keys: 2,
+        }, |_| None);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_key("a", now), Ok(()));
+        assert_eq!(limiter.check_key("a", now), Ok(()));
+        assert_eq!(limiter.check_key("a", now), Err(Duration::from_secs(1)));
+        // Other keys have their own bucket.
+        assert_eq!(limiter.check_key("b", now), Ok(()));
+        assert_eq!(limiter.check_key("a", now + Duration::from_millis(500)), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.check_key("a", now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let limiter = RateLimiter::new(BucketConfig {
+            capacity: 1,
+            refill_per_second: 0.0,
+            max_keys: 2,
+        }, |_| None);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_key("a", now), Ok(()));
+        assert_eq!(limiter.check_key("b", now), Ok(()));
+        assert!(limiter.check_key("a", now).is_err());
+        // "c" takes the bucket of "b", the least recently used.
+        assert_eq!(limiter.check_key("c", now), Ok(()));
+        assert!(limiter.check_key("a", now).is_err());
+        assert_eq!(limiter.check_key("b", now), Ok(()));
+        assert!(limiter.check_key("a", now).is_err());
+    }
+}
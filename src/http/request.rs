@@ -21,6 +21,8 @@ use std::net::SocketAddr;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::*;
 use std::ops::DerefMut;
+use std::sync::Arc;
+use std::time::Instant;
 use std::cmp;
 use std::str::FromStr;
 
@@ -32,6 +34,8 @@ use url::form_urlencoded;
 // use multipart::server::{HttpRequest, Multipart, Entries, SaveResult};
 use server::{HttpRequest, Multipart, Entries, SaveResult};
 use super::buffer::Buffer;
+use super::connections::Connection;
+use super::hints::HintSender;
 use Method;
 use Handler;
 use Router;
@@ -102,6 +106,12 @@ pub struct Request {
     handler: Option<Handler>,
     /// Optional Logger associated with a given request
     pub logger: Option<Logger>,
+    /// The connection the request arrived on, when the server keeps a `Connections` registry.
+    connection: Option<Arc<Connection>>,
+    /// When the client stops waiting for the response, as set by `deadline::Deadlines`.
+    deadline: Option<Instant>,
+    /// Sends 103 Early Hints ahead of the response; `None` for HTTP/1.0 clients.
+    hints: Option<HintSender>,
 }
 
 type Slice = (usize, usize);
@@ -204,10 +214,36 @@ impl Request {
         self.remote_addr = Some(remote_addr);
     }
 
-    pub fn remote_addr(&mut self) -> Option<SocketAddr> {
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
         self.remote_addr
     }
 
+    pub fn connection(&self) -> Option<&Arc<Connection>> {
+        self.connection.as_ref()
+    }
+
+    pub fn set_connection(&mut self, connection: Arc<Connection>) {
+        self.connection = Some(connection);
+    }
+
+    /// Sends 103 Early Hints while the response is being worked on, when the client takes
+    /// them.
+    pub fn hints(&self) -> Option<&HintSender> {
+        self.hints.as_ref()
+    }
+
+    pub fn set_hints(&mut self, hints: HintSender) {
+        self.hints = Some(hints);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
     pub fn request_line(&self) -> &str {
         &self.request_line
     }
@@ -414,6 +450,9 @@ pub fn decode(buf: &mut EasyBuf,
         data: ReqReader::new(buf.drain_to(amt)),
         handler: handler,
         logger: logger,
+        connection: None,
+        deadline: None,
+        hints: None,
     };
 
     Ok(Some(res))
@@ -20,6 +20,7 @@ use std::str::FromStr;
 
 use unicase::UniCase;
 use http::date;
+use http::tunnel::Upstream;
 use Body;
 use Headers;
 use StatusCode;
@@ -31,6 +32,12 @@ pub struct Response {
     pub status_message: StatusMessage,
     pub code: u16,
     pub message: String,
+    /// Headers of a `103 Early Hints` written ahead of the response, typically `link` headers
+    /// preloading resources. Dropped for HTTP/1.0 clients, which don't expect 1xx responses.
+    pub hints: Headers,
+    /// The outbound connection of a CONNECT this response accepts: once the response is
+    /// written, the server tunnels the connection to it.
+    pub tunnel: Option<Upstream>,
 }
 
 #[derive(Clone, Debug)]
@@ -49,6 +56,8 @@ impl Response {
             status_message: StatusMessage::Custom(status.to_u16(), status.canonical_reason().unwrap_or("").to_string()),
             code: status.to_u16(),
             message: status.canonical_reason().unwrap_or("").to_string(),
+            hints: Headers::new(),
+            tunnel: None,
         };
 
         res
@@ -66,6 +75,18 @@ impl Response {
         self
     }
 
+    #[inline]
+    pub fn with_hint(mut self, name: &str, val: &str) -> Self {
+        self.hints.push((name.to_string(), val.to_string()));
+        self
+    }
+
+    #[inline]
+    pub fn with_tunnel(mut self, upstream: Upstream) -> Self {
+        self.tunnel = Some(upstream);
+        self
+    }
+
     #[inline]
     pub fn with_status(mut self, code: StatusCode) -> Self {
         self.code = code.to_u16();
@@ -102,6 +123,10 @@ pub fn encode(res: &Response, buf: &mut Vec<u8>) {
     let length = res.body.len();
     let now = date::now();
 
+    if !res.hints.is_empty() {
+        encode_hints(&res.hints, buf);
+    }
+
     write!(FastWrite(buf), "\
         HTTP/1.1 {}\r\n\
         Date: {}\r\n\
@@ -118,6 +143,18 @@ pub fn encode(res: &Response, buf: &mut Vec<u8>) {
     buf.extend_from_slice(&res.body[..]); //.as_bytes());
 }
 
+/// Writes a `103 Early Hints` with `hints`.
+pub fn encode_hints(hints: &Headers, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"HTTP/1.1 103 Early Hints\r\n");
+    for &(ref k, ref v) in hints {
+        buf.extend_from_slice(k.as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(v.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"\r\n");
+}
+
 // TODO: impl fmt::Write for Vec<u8>
 //
 // Right now `write!` on `Vec<u8>` goes through io::Write and is not super
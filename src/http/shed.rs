@@ -0,0 +1,298 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Load shedding. The server reports its pressure signals (open connections, requests decoded
+//! but not yet answered, and the latency between the two) into a `LoadShedder`, which switches
+//! to shedding once any signal crosses its limit and back only once all of them have fallen
+//! below `recover` times their limit, so it doesn't flap around the threshold. `Shed` wraps a
+//! `Service` and answers 503 while shedding; an HTTP/2 `Connection` given the shedder refuses
+//! new streams with `REFUSED_STREAM` instead.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_service::Service;
+
+use http::{Request, Response};
+use metrics::{self, Metrics};
+use StatusCode;
+
+/// Weight of the newest sample in the latency moving average.
+const LATENCY_ALPHA: f64 = 0.1;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShedConfig {
+    pub max_connections: Option<usize>,
+    /// Requests decoded but not yet answered, over all connections.
+    pub max_pending: Option<usize>,
+    /// Moving average of the time from decoding a request to encoding its response.
+    pub max_latency: Option<Duration>,
+    /// Shedding stops once every signal is below this fraction of its limit.
+    pub recover: f64,
+    /// Sent as `retry-after` with the 503.
+    pub retry_after: u32,
+}
+
+impl Default for ShedConfig {
+    fn default() -> ShedConfig {
+        ShedConfig {
+            max_connections: None,
+            max_pending: Some(1024),
+            max_latency: Some(Duration::from_secs(1)),
+            recover: 0.8,
+            retry_after: 1,
+        }
+    }
+}
+
+/// A snapshot of the pressure signals.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pressure {
+    pub connections: usize,
+    pub pending: usize,
+    pub latency: Duration,
+    pub shedding: bool,
+}
+
+#[derive(Default)]
+struct State {
+    connections: usize,
+    pending: usize,
+    latency_us: f64,
+    shedding: bool,
+}
+
+/// Shared by every connection of a server; cheap to clone.
+#[derive(Clone)]
+pub struct LoadShedder {
+    config: ShedConfig,
+    state: Arc<Mutex<State>>,
+    metrics: Option<Metrics>,
+}
+
+impl LoadShedder {
+    pub fn new(config: ShedConfig) -> LoadShedder {
+        LoadShedder {
+            config: config,
+            state: Arc::new(Mutex::new(State::default())),
+            metrics: None,
+        }
+    }
+
+    /// Counts shed requests in `metrics::names::LOAD_SHED`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> LoadShedder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn config(&self) -> &ShedConfig {
+        &self.config
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        let state = self.state.lock().unwrap();
+        Pressure {
+            connections: state.connections,
+            pending: state.pending,
+            latency: Duration::new((state.latency_us / 1e6) as u64, ((state.latency_us % 1e6) * 1000.0) as u32),
+            shedding: state.shedding,
+        }
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.state.lock().unwrap().shedding
+    }
+
+    /// Whether to turn a new request or stream away now; counts it as shed if so.
+    pub fn refuse(&self) -> bool {
+        let shedding = self.is_shedding();
+        if shedding {
+            self.shed();
+        }
+        shedding
+    }
+
+    /// Counts a connection until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.update(|state| state.connections += 1);
+        ConnectionGuard { shedder: self.clone() }
+    }
+
+    /// Counts a decoded request as pending until the returned `Pending` is finished or dropped.
+    pub fn request_started(&self, now: Instant) -> Pending {
+        self.update(|state| state.pending += 1);
+        Pending {
+            shedder: self.clone(),
+            started: now,
+        }
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, change: F) {
+        let mut state = self.state.lock().unwrap();
+        change(&mut state);
+
+        let config = &self.config;
+        let latency = state.latency_us;
+        let ratio = |value: f64, limit: f64| value / limit;
+        let worst = [config.max_connections.map(|max| ratio(state.connections as f64, max as f64)),
+                     config.max_pending.map(|max| ratio(state.pending as f64, max as f64)),
+                     config.max_latency.map(|max| ratio(latency, duration_us(max)))]
+            .iter()
+            .filter_map(|ratio| *ratio)
+            .fold(0.0, f64::max);
+
+        if !state.shedding && worst > 1.0 {
+            state.shedding = true;
+        } else if state.shedding && worst < config.recover {
+            state.shedding = false;
+        }
+    }
+
+    fn shed(&self) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.counter(metrics::names::LOAD_SHED, 1);
+        }
+    }
+}
+
+impl fmt::Debug for LoadShedder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LoadShedder {{ config: {:?}, pressure: {:?} }}", self.config, self.pressure())
+    }
+}
+
+fn duration_us(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1e6 + (duration.subsec_nanos() / 1000) as f64
+}
+
+/// An open connection, counted until dropped.
+pub struct ConnectionGuard {
+    shedder: LoadShedder,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.shedder.update(|state| state.connections -= 1);
+    }
+}
+
+impl fmt::Debug for ConnectionGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConnectionGuard")
+    }
+}
+
+/// A decoded request awaiting its response.
+pub struct Pending {
+    shedder: LoadShedder,
+    started: Instant,
+}
+
+impl Pending {
+    /// The response has been encoded: records the latency. Shed requests are answered at once
+    /// and pull the average down, which is what ends a shedding period once the backlog drains.
+    pub fn finish(self, now: Instant) {
+        let latency = duration_us(now.duration_since(self.started));
+        self.shedder.update(|state| {
+            state.latency_us = if state.latency_us == 0.0 {
+                latency
+            } else {
+                state.latency_us + LATENCY_ALPHA * (latency - state.latency_us)
+            };
+        });
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.shedder.update(|state| state.pending -= 1);
+    }
+}
+
+impl fmt::Debug for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pending {{ started: {:?} }}", self.started)
+    }
+}
+
+/// Wraps a service and answers 503 while the shedder is shedding.
+pub struct Shed<S> {
+    inner: S,
+    shedder: LoadShedder,
+}
+
+impl<S> Shed<S> {
+    pub fn new(inner: S, shedder: LoadShedder) -> Shed<S> {
+        Shed {
+            inner: inner,
+            shedder: shedder,
+        }
+    }
+}
+
+impl<S> Service for Shed<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        if !self.shedder.refuse() {
+            return Box::new(self.inner.call(req));
+        }
+        Box::new(future::ok(Response::new()
+            .with_status(StatusCode::ServiceUnavailable)
+            .with_header("Retry-After", &self.shedder.config.retry_after.to_string())
+            .with_header("Content-Length", "0")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{LoadShedder, ShedConfig};
+
+    #[test]
+    fn test_hysteresis() {
+        let shedder = LoadShedder::new(ShedConfig {
+            max_pending: Some(10),
+            max_latency: Some(Duration::from_millis(100)),
+            .. ShedConfig::default()
+        });
+        let now = Instant::now();
+
+        let mut pending: Vec<_> = (0..11).map(|_| shedder.request_started(now)).collect();
+        assert!(shedder.is_shedding());
+        // 10 pending is back at the limit but not below 80% of it: still shedding.
+        pending.pop();
+        assert!(shedder.is_shedding());
+        pending.truncate(7);
+        assert!(!shedder.is_shedding());
+        assert_eq!(shedder.pressure().pending, 7);
+
+        // The first sample sets the latency outright.
+        pending.pop().unwrap().finish(now + Duration::from_millis(200));
+        assert!(shedder.is_shedding());
+        for _ in 0..20 {
+            shedder.request_started(now).finish(now + Duration::from_millis(1));
+        }
+        assert!(!shedder.is_shedding());
+    }
+}
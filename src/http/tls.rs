@@ -0,0 +1,195 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS accept with keying material export. `accept` performs the handshake of a server-side
+//! TLS connection and installs a `TlsExporter` on its `Connection`, so that
+//! `Connection::export_keying_material` works on it from then on.
+//!
+//! native-tls has no exporter API, so the exporter goes to the session of its openssl backend.
+//! The material is exported once, at the handshake, for the `Export`s the server is set up
+//! with: the exporter doesn't keep the session, which goes away with the stream.
+
+use std::io::{self, Read, Write};
+use std::ptr;
+use std::sync::Arc;
+
+use libc::{c_char, c_int, c_uchar, size_t};
+use native_tls::{TlsAcceptor, TlsStream, HandshakeError};
+use native_tls::backend::openssl::TlsStreamExt;
+use openssl::ssl::SslRef;
+use openssl::types::OpenSslTypeRef;
+use openssl_sys::SSL;
+
+use super::connections::{Connection, Exporter};
+
+extern "C" {
+    // RFC 5705, in OpenSSL since 1.0.1; openssl 0.9 doesn't bind it.
+    fn SSL_export_keying_material(ssl: *mut SSL, out: *mut c_uchar, olen: size_t, label: *const c_char,
+                                  llen: size_t, context: *const c_uchar, contextlen: size_t,
+                                  use_context: c_int) -> c_int;
+}
+
+/// Keying material to export from every session, e.g. `EXPORTER-Token-Binding` with no context
+/// and 32 bytes for token binding (RFC 8471).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Export {
+    pub label: String,
+    pub context: Option<Vec<u8>>,
+    pub len: usize,
+}
+
+impl Export {
+    pub fn new(label: &str, context: Option<&[u8]>, len: usize) -> Export {
+        Export {
+            label: label.to_string(),
+            context: context.map(|context| context.to_vec()),
+            len: len,
+        }
+    }
+}
+
+/// The keying material of a TLS session, exported at the handshake.
+#[derive(Debug)]
+pub struct TlsExporter {
+    exported: Vec<(Export, Vec<u8>)>,
+}
+
+impl TlsExporter {
+    /// Exports `exports` from the session of `stream`, once its handshake is done. A tokio-tls
+    /// stream gives its native-tls one with `get_ref`.
+    pub fn new<S>(stream: &TlsStream<S>, exports: &[Export]) -> io::Result<TlsExporter> {
+        TlsExporter::from_ssl(stream.raw_stream().ssl(), exports)
+    }
+
+    pub fn from_ssl(ssl: &SslRef, exports: &[Export]) -> io::Result<TlsExporter> {
+        let mut exported = Vec::with_capacity(exports.len());
+        for export in exports {
+            let material = try!(export_keying_material(ssl, &export.label, export.context.as_ref().map(|c| &c[..]),
+                                                       export.len));
+            exported.push((export.clone(), material));
+        }
+        Ok(TlsExporter { exported: exported })
+    }
+}
+
+impl Exporter for TlsExporter {
+    /// Fails with `ErrorKind::InvalidInput` for what wasn't exported at the handshake.
+    fn export_keying_material(&self, label: &str, context: Option<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+        self.exported.iter()
+            .find(|&&(ref export, _)| {
+                export.label == label && export.context.as_ref().map(|c| &c[..]) == context && export.len == len
+            })
+            .map(|&(_, ref material)| material.clone())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "keying material wasn't exported at the handshake")
+            })
+    }
+}
+
+fn export_keying_material(ssl: &SslRef, label: &str, context: Option<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+    let mut out = vec![0; len];
+    let (context_ptr, context_len) = match context {
+        Some(context) => (context.as_ptr(), context.len()),
+        None => (ptr::null(), 0),
+    };
+    let ok = unsafe {
+        SSL_export_keying_material(ssl.as_ptr(), out.as_mut_ptr(), len, label.as_ptr() as *const c_char,
+                                   label.len(), context_ptr, context_len, context.is_some() as c_int)
+    };
+    if ok != 1 {
+        return Err(io::Error::new(io::ErrorKind::Other, "TLS session can't export keying material"));
+    }
+    Ok(out)
+}
+
+/// Performs the server side of the TLS handshake on `socket` and installs the exporter of
+/// `exports` on `connection`, the socket's entry in the server's `Connections`.
+pub fn accept<S: Read + Write>(acceptor: &TlsAcceptor, socket: S, connection: &Connection, exports: &[Export])
+                               -> io::Result<TlsStream<S>> {
+    let stream = try!(acceptor.accept(socket).map_err(|err| match err {
+        HandshakeError::Failure(err) => io::Error::new(io::ErrorKind::Other, err),
+        HandshakeError::Interrupted(_) => io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake interrupted"),
+    }));
+    connection.set_exporter(Arc::new(try!(TlsExporter::new(&stream, exports))));
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptorBuilder, SslConnectorBuilder, SslMethod, SSL_VERIFY_NONE};
+    use openssl::x509::{X509, X509Generator};
+
+    use http::connections::{Connections, Exporter};
+
+    use super::{Export, TlsExporter};
+
+    #[test]
+    fn test_exporter() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let cert = X509Generator::new()
+            .add_name("CN".to_string(), "localhost".to_string())
+            .set_sign_hash(MessageDigest::sha256())
+            .sign(&key)
+            .unwrap();
+        let acceptor = SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(), &key, &cert, Vec::<X509>::new())
+            .unwrap()
+            .build();
+        let exports = vec![Export::new("EXPORTER-Token-Binding", None, 32),
+                           Export::new("EXPORTER-test", Some(&b"ctx"[..]), 16)];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client_exports = exports.clone();
+        let client = thread::spawn(move || {
+            let mut connector = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+            connector.builder_mut().set_verify(SSL_VERIFY_NONE);
+            let mut stream = connector.build()
+                .danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(
+                    TcpStream::connect(addr).unwrap())
+                .unwrap();
+            let exporter = TlsExporter::from_ssl(stream.ssl(), &client_exports).unwrap();
+            stream.write_all(b"x").unwrap();
+            exporter
+        });
+
+        // The server's connection gets the same material as the client exported.
+        let (socket, peer) = listener.accept().unwrap();
+        let mut stream = acceptor.accept(socket).unwrap();
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+        let connections = Connections::new();
+        let registration = connections.register(peer);
+        let connection = registration.connection();
+        connection.set_exporter(Arc::new(TlsExporter::from_ssl(stream.ssl(), &exports).unwrap()));
+        let client = client.join().unwrap();
+
+        let binding = connection.export_keying_material("EXPORTER-Token-Binding", None, 32).unwrap();
+        assert_eq!(binding.len(), 32);
+        assert_eq!(binding, client.export_keying_material("EXPORTER-Token-Binding", None, 32).unwrap());
+        let test = connection.export_keying_material("EXPORTER-test", Some(&b"ctx"[..]), 16).unwrap();
+        assert_eq!(test, client.export_keying_material("EXPORTER-test", Some(&b"ctx"[..]), 16).unwrap());
+        assert!(binding[..16] != test[..]);
+
+        let err = connection.export_keying_material("EXPORTER-test", None, 16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
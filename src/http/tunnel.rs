@@ -0,0 +1,657 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CONNECT tunnels for forward proxies. `ConnectPolicy` decides which targets a CONNECT request
+//! may reach and opens the outbound connection; `bridge` then copies bytes both ways until both
+//! sides are done, propagating half-closes.
+//!
+//! In the server, `Connect` wraps the `Service` (built with `TcpServer::with_handle`, for the
+//! event loop) and answers CONNECT requests itself. It resolves the target on a `CpuPool`, as
+//! resolution blocks, and tries the addresses in turn; the 200 carries the outbound connection in
+//! `Response::tunnel`, and once it is written `Tunnel`, the server's transport, stops speaking
+//! HTTP and copies between the two sockets. Clients must wait for the 200 before sending
+//! through the tunnel, as RFC 9110 has them do. CONNECT streams of HTTP/2 connections are
+//! bridged by `http2::connect::ConnectBridge` instead.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures_cpupool::CpuPool;
+use tokio_core::io::Framed;
+use tokio_core::net::TcpStream as AsyncTcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+use http::{HttpCodec, Request, Response};
+use http::connections::Connection;
+use http::hints::HintQueue;
+use Method;
+use StatusCode;
+
+/// How far each direction of a server tunnel reads ahead of the side it writes to.
+const TUNNEL_BUFFER_SIZE: usize = 16 << 10;
+
+/// The `host:port` authority of a CONNECT request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Parses `host:port` or `[v6]:port`. CONNECT always carries a port.
+    pub fn parse(authority: &str) -> Option<Target> {
+        let authority = authority.trim();
+        let colon = match authority.rfind(':') {
+            Some(colon) => colon,
+            None => return None,
+        };
+        let (host, port) = (&authority[..colon], &authority[colon + 1..]);
+        let host = if host.starts_with('[') && host.ends_with(']') { &host[1..host.len() - 1] } else { host };
+        if host.is_empty() || host.contains(|c: char| c == '/' || c == '@' || c.is_whitespace()) {
+            return None;
+        }
+        match port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(Target { host: host.to_string(), port: port }),
+            _ => None,
+        }
+    }
+}
+
+/// Why a CONNECT request was not tunneled.
+#[derive(Debug)]
+pub enum Refusal {
+    /// The authority isn't `host:port`.
+    Malformed,
+    /// The policy doesn't allow the target.
+    Forbidden,
+    Unreachable(io::Error),
+    Timeout,
+}
+
+impl Refusal {
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            Refusal::Malformed => StatusCode::BadRequest,
+            Refusal::Forbidden => StatusCode::Forbidden,
+            Refusal::Unreachable(_) => StatusCode::BadGateway,
+            Refusal::Timeout => StatusCode::GatewayTimeout,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectPolicy {
+    /// Ports that may be tunneled to; empty allows any.
+    pub allowed_ports: Vec<u16>,
+    /// Refuse targets resolving to loopback, private or link-local addresses, so the proxy can't
+    /// be used to reach the network it runs in.
+    pub deny_private: bool,
+    pub connect_timeout: Duration,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> ConnectPolicy {
+        ConnectPolicy {
+            allowed_ports: vec![443],
+            deny_private: true,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectPolicy {
+    /// Whether a resolved address may be connected to.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&addr.port()) {
+            return false;
+        }
+        !self.deny_private || !is_internal(&addr.ip())
+    }
+
+    /// Resolves `authority` to the addresses that may be connected to. Every address is checked
+    /// after resolution, so DNS can't be used to get around `deny_private`. Blocking.
+    pub fn resolve(&self, authority: &str) -> Result<Vec<SocketAddr>, Refusal> {
+        let target = try!(Target::parse(authority).ok_or(Refusal::Malformed));
+        let addrs = try!((&target.host[..], target.port).to_socket_addrs().map_err(Refusal::Unreachable));
+        let addrs: Vec<SocketAddr> = addrs.filter(|addr| self.allows(addr)).collect();
+        if addrs.is_empty() {
+            return Err(Refusal::Forbidden);
+        }
+        Ok(addrs)
+    }
+
+    /// Resolves `authority` and connects to the first allowed address that answers.
+    pub fn open(&self, authority: &str) -> Result<TcpStream, Refusal> {
+        let mut refusal = Refusal::Forbidden;
+        for addr in try!(self.resolve(authority)) {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => refusal = Refusal::Timeout,
+                Err(err) => refusal = Refusal::Unreachable(err),
+            }
+        }
+        Err(refusal)
+    }
+}
+
+fn is_internal(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // 0.0.0.0/8 ("this network") and 100.64.0.0/10 (carrier-grade NAT).
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() ||
+                octets[0] == 0 || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // fc00::/7 (unique local) and fe80::/10 (link-local).
+            if ip.is_loopback() || ip.is_unspecified() || segments[0] & 0xfe00 == 0xfc00 ||
+                    segments[0] & 0xffc0 == 0xfe80 {
+                return true;
+            }
+            // 64:ff9b::/96 (NAT64) reaches the IPv4 address in its last 32 bits, as the
+            // IPv4-mapped and -compatible addresses do.
+            let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+            let embedded = if nat64 {
+                Some(::std::net::Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                               (segments[7] >> 8) as u8, segments[7] as u8))
+            } else {
+                ip.to_ipv4()
+            };
+            embedded.map_or(false, |v4| is_internal(&IpAddr::V4(v4)))
+        },
+    }
+}
+
+/// The outbound connection of an accepted CONNECT, on its way from `Connect` to the `Tunnel`
+/// transport in `Response::tunnel`.
+#[derive(Clone)]
+pub struct Upstream(Arc<Mutex<Option<AsyncTcpStream>>>);
+
+impl Upstream {
+    pub fn new(stream: AsyncTcpStream) -> Upstream {
+        Upstream(Arc::new(Mutex::new(Some(stream))))
+    }
+
+    /// The connection, unless a clone of this took it already.
+    pub fn take(&self) -> Option<AsyncTcpStream> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Upstream")
+    }
+}
+
+/// Wraps a service and tunnels the CONNECT requests that `ConnectPolicy` allows; every other
+/// request goes to the inner service.
+pub struct Connect<S> {
+    inner: S,
+    policy: Arc<ConnectPolicy>,
+    pool: CpuPool,
+    handle: Handle,
+}
+
+impl<S> Connect<S> {
+    /// Resolves the targets on `pool`. `handle` is the event loop of the connections the
+    /// service serves, which the outbound connections are opened on.
+    pub fn new(inner: S, policy: ConnectPolicy, pool: CpuPool, handle: Handle) -> Connect<S> {
+        Connect {
+            inner: inner,
+            policy: Arc::new(policy),
+            pool: pool,
+            handle: handle,
+        }
+    }
+}
+
+fn refuse(refusal: Refusal) -> Response {
+    Response::new().with_status(refusal.status()).with_header("Content-Length", "0")
+}
+
+/// Connects to `addr`, giving up after `timeout`.
+fn connect_timeout(addr: SocketAddr, timeout: Duration, handle: &Handle)
+                   -> Box<Future<Item = AsyncTcpStream, Error = Refusal>> {
+    let timeout = match Timeout::new(timeout, handle) {
+        Ok(timeout) => timeout,
+        Err(err) => return Box::new(future::err(Refusal::Unreachable(err))),
+    };
+    let connect = AsyncTcpStream::connect(&addr, handle).map_err(Refusal::Unreachable);
+    let timeout = timeout.then(|_| future::err::<AsyncTcpStream, Refusal>(Refusal::Timeout));
+    Box::new(connect.select(timeout).map(|(stream, _)| stream).map_err(|(refusal, _)| refusal))
+}
+
+/// Connects to the first of `addrs` that answers, each in turn, as `ConnectPolicy::open`
+/// does. Fails as the last one did.
+fn connect_any(addrs: Vec<SocketAddr>, timeout: Duration, handle: &Handle)
+               -> Box<Future<Item = AsyncTcpStream, Error = Refusal>> {
+    let first: Box<Future<Item = AsyncTcpStream, Error = Refusal>> = Box::new(future::err(Refusal::Forbidden));
+    addrs.into_iter().fold(first, |attempts, addr| {
+        let handle = handle.clone();
+        Box::new(attempts.or_else(move |_| connect_timeout(addr, timeout, &handle)))
+    })
+}
+
+impl<S> Service for Connect<S>
+        where S: Service<Request = Request, Response = Response>,
+              S::Future: 'static,
+              S::Error: 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Response, Error = S::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        if req.method() != Method::Connect {
+            return Box::new(self.inner.call(req));
+        }
+        // The request target of a CONNECT is the authority.
+        let (policy, authority) = (self.policy.clone(), req.path().to_string());
+        let resolve = self.pool.spawn_fn(move || policy.resolve(&authority));
+        let (timeout, handle) = (self.policy.connect_timeout, self.handle.clone());
+        let connect = resolve.and_then(move |addrs| connect_any(addrs, timeout, &handle));
+        Box::new(connect.then(|connected| Ok(match connected {
+            Ok(stream) => Response::new().with_tunnel(Upstream::new(stream)),
+            Err(refusal) => refuse(refusal),
+        })))
+    }
+}
+
+/// One direction of a server tunnel.
+struct CopyHalf {
+    from: Rc<AsyncTcpStream>,
+    to: Rc<AsyncTcpStream>,
+    buf: Vec<u8>,
+    /// What of `buf` is left to write.
+    pos: usize,
+    len: usize,
+    total: u64,
+}
+
+impl CopyHalf {
+    fn new(from: Rc<AsyncTcpStream>, to: Rc<AsyncTcpStream>) -> CopyHalf {
+        CopyHalf {
+            from: from,
+            to: to,
+            buf: vec![0; TUNNEL_BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            total: 0,
+        }
+    }
+}
+
+impl Future for CopyHalf {
+    type Item = u64;
+    type Error = io::Error;
+
+    /// Like `copy_half`, but resumes where it would block.
+    fn poll(&mut self) -> Poll<u64, io::Error> {
+        loop {
+            if self.pos == self.len {
+                let n = match (&*self.from).read(&mut self.buf) {
+                    Ok(n) => n,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(err) => return Err(err),
+                };
+                if n == 0 {
+                    let _ = self.to.shutdown(Shutdown::Write);
+                    return Ok(Async::Ready(self.total));
+                }
+                self.pos = 0;
+                self.len = n;
+            }
+            let n = match (&*self.to).write(&self.buf[self.pos..self.len]) {
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            };
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "tunnel peer stopped accepting data"));
+            }
+            self.pos += n;
+            self.total += n as u64;
+        }
+    }
+}
+
+enum TunnelState {
+    Http(Framed<AsyncTcpStream, HttpCodec>),
+    /// The response accepting a CONNECT is being written; the connection is bridged to the
+    /// upstream once it is flushed.
+    Accepting(Framed<AsyncTcpStream, HttpCodec>, AsyncTcpStream),
+    Bridging(future::Join<CopyHalf, CopyHalf>),
+    Done,
+}
+
+/// The server's transport: HTTP until a response with a `tunnel` was written, and a tunnel
+/// between the client and the upstream from then on, until both are done sending. The stream
+/// of requests ends with the tunnel. While HTTP, it also writes the 103s of `hints`, and drops
+/// the connection once `Connection::close` is called on it.
+pub struct Tunnel {
+    state: TunnelState,
+    hints: HintQueue,
+    /// The part of the 103s taken from `hints` not written yet.
+    early: Vec<u8>,
+    connection: Option<Arc<Connection>>,
+}
+
+impl Tunnel {
+    pub fn new(framed: Framed<AsyncTcpStream, HttpCodec>, hints: HintQueue,
+               connection: Option<Arc<Connection>>) -> Tunnel {
+        Tunnel {
+            state: TunnelState::Http(framed),
+            hints: hints,
+            early: Vec::new(),
+            connection: connection,
+        }
+    }
+
+    /// Writes the 103s that can go out, once the responses ahead of them are flushed. They go
+    /// straight to the socket rather than waiting for a response to be buffered with.
+    fn poll_hints(&mut self) -> Poll<(), io::Error> {
+        let framed = match self.state {
+            TunnelState::Http(ref mut framed) => framed,
+            _ => return Ok(Async::Ready(())),
+        };
+        loop {
+            if self.early.is_empty() {
+                if let Async::NotReady = try!(framed.poll_complete()) {
+                    return Ok(Async::NotReady);
+                }
+                match self.hints.take() {
+                    Some(hints) => self.early = hints,
+                    None => return Ok(Async::Ready(())),
+                }
+            }
+            let n = match framed.get_mut().write(&self.early) {
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            };
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "client stopped accepting data"));
+            }
+            self.early.drain(..n);
+        }
+    }
+
+    /// Polls the copies, if the tunnel is up.
+    fn poll_bridge(&mut self) -> Poll<(), io::Error> {
+        let result = match self.state {
+            TunnelState::Bridging(ref mut bridge) => bridge.poll(),
+            TunnelState::Done => return Ok(Async::Ready(())),
+            _ => return Ok(Async::NotReady),
+        };
+        match result {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(_)) => {
+                self.state = TunnelState::Done;
+                Ok(Async::Ready(()))
+            },
+            Err(err) => {
+                self.state = TunnelState::Done;
+                Err(err)
+            },
+        }
+    }
+}
+
+impl Stream for Tunnel {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        if let Some(ref connection) = self.connection {
+            connection.park();
+            if connection.is_closed() {
+                self.state = TunnelState::Done;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by the server"));
+            }
+        }
+        try!(self.poll_hints());
+        match self.state {
+            TunnelState::Http(ref mut framed) | TunnelState::Accepting(ref mut framed, _) => return framed.poll(),
+            _ => {},
+        }
+        match try!(self.poll_bridge()) {
+            Async::Ready(()) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Sink for Tunnel {
+    type SinkItem = Response;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, mut res: Response) -> StartSend<Response, io::Error> {
+        // The response's own hints, sent ahead of it, go first.
+        if let Async::NotReady = try!(self.poll_hints()) {
+            if !self.early.is_empty() {
+                return Ok(AsyncSink::NotReady(res));
+            }
+        }
+        let upstream = res.tunnel.take().and_then(|upstream| upstream.take());
+        let state = mem::replace(&mut self.state, TunnelState::Done);
+        let (state, sent) = match state {
+            TunnelState::Http(mut framed) => {
+                let sent = try!(framed.start_send(res));
+                match (sent, upstream) {
+                    (AsyncSink::Ready, Some(upstream)) => (TunnelState::Accepting(framed, upstream), AsyncSink::Ready),
+                    (sent, _) => (TunnelState::Http(framed), sent),
+                }
+            },
+            // Nothing more goes out as HTTP.
+            state => (state, AsyncSink::Ready),
+        };
+        self.state = state;
+        Ok(sent)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if let Async::NotReady = try!(self.poll_hints()) {
+            return Ok(Async::NotReady);
+        }
+        match self.state {
+            TunnelState::Http(ref mut framed) => return framed.poll_complete(),
+            TunnelState::Accepting(ref mut framed, _) => {
+                if let Async::NotReady = try!(framed.poll_complete()) {
+                    return Ok(Async::NotReady);
+                }
+            },
+            _ => return Ok(Async::Ready(())),
+        }
+        if let TunnelState::Accepting(framed, upstream) = mem::replace(&mut self.state, TunnelState::Done) {
+            let (client, upstream) = (Rc::new(framed.into_inner()), Rc::new(upstream));
+            let up = CopyHalf::new(client.clone(), upstream.clone());
+            let down = CopyHalf::new(upstream, client);
+            self.state = TunnelState::Bridging(up.join(down));
+            // The copies are polled from then on, which needs them to be waiting on the sockets.
+            try!(self.poll_bridge());
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Copies between `client` and `upstream` in both directions until both have finished sending,
+/// and returns the bytes sent upstream and downstream. Each direction reads at most
+/// `buffer_size` bytes ahead of what the other side has accepted, so a slow reader slows the
+/// sender down instead of growing a buffer. When one side shuts down its write half, the other
+/// side's write half is shut down too. Blocking.
+pub fn bridge(client: TcpStream, upstream: TcpStream, buffer_size: usize) -> io::Result<(u64, u64)> {
+    let (client_read, upstream_read) = (try!(client.try_clone()), try!(upstream.try_clone()));
+
+    let up = thread::spawn(move || copy_half(client_read, upstream, buffer_size));
+    let down = try!(copy_half(upstream_read, client, buffer_size));
+    let up = try!(try!(up.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "tunnel thread panicked"))));
+    Ok((up, down))
+}
+
+fn copy_half(mut from: TcpStream, mut to: TcpStream, buffer_size: usize) -> io::Result<u64> {
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0;
+    let result = loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => break Ok(total),
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        };
+        if let Err(err) = to.write_all(&buf[..n]) {
+            break Err(err);
+        }
+        total += n as u64;
+    };
+    // Half-close: the peer sees EOF but can keep sending the other way.
+    let _ = to.shutdown(Shutdown::Write);
+    if result.is_err() {
+        let _ = from.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{Future, Stream};
+    use tokio_core::net::TcpListener as AsyncTcpListener;
+    use tokio_core::reactor::Core;
+
+    use super::{bridge, connect_any, ConnectPolicy, CopyHalf, Refusal, Target};
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(Target::parse("example.com:443"), Some(Target { host: "example.com".to_string(), port: 443 }));
+        assert_eq!(Target::parse("[::1]:8443"), Some(Target { host: "::1".to_string(), port: 8443 }));
+        assert_eq!(Target::parse("example.com"), None);
+        assert_eq!(Target::parse("user@example.com:443"), None);
+
+        let policy = ConnectPolicy::default();
+        assert!(policy.allows(&"93.184.216.34:443".parse().unwrap()));
+        assert!(!policy.allows(&"93.184.216.34:25".parse().unwrap()));
+        assert!(!policy.allows(&"10.1.2.3:443".parse().unwrap()));
+        assert!(!policy.allows(&"[::ffff:127.0.0.1]:443".parse().unwrap()));
+        assert!(!policy.allows(&"0.1.2.3:443".parse().unwrap()));
+
+        // Carrier-grade NAT, and the NAT64 prefix mapping to internal IPv4 addresses.
+        assert!(!policy.allows(&"100.64.1.1:443".parse().unwrap()));
+        assert!(!policy.allows(&"100.127.255.255:443".parse().unwrap()));
+        assert!(policy.allows(&"100.128.0.1:443".parse().unwrap()));
+        assert!(!policy.allows(&"[64:ff9b::a00:1]:443".parse().unwrap()));
+        assert!(!policy.allows(&"[64:ff9b::7f00:1]:443".parse().unwrap()));
+        assert!(policy.allows(&"[64:ff9b::5db8:d822]:443".parse().unwrap()));
+        assert_eq!(policy.open("localhost:443").unwrap_err().status().to_u16(), 403);
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The address that refuses is skipped.
+        let timeout = Duration::from_secs(5);
+        let stream = core.run(connect_any(vec![refused, addr], timeout, &handle)).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        match core.run(connect_any(vec![refused], timeout, &handle)) {
+            Err(Refusal::Unreachable(_)) => {},
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_bridge() {
+        // Echoes everything once the client has half-closed.
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            stream.write_all(&data).unwrap();
+        });
+
+        let front = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(front.local_addr().unwrap()).unwrap();
+        let (inbound, _) = front.accept().unwrap();
+        let outbound = TcpStream::connect(origin_addr).unwrap();
+        let tunnel = thread::spawn(move || bridge(inbound, outbound, 4).unwrap());
+
+        client.write_all(b"hello tunnel").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, b"hello tunnel");
+        assert_eq!(tunnel.join().unwrap(), (12, 12));
+    }
+
+    #[test]
+    fn test_copy_half() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listen = || {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            (AsyncTcpListener::from_listener(listener, &addr, &handle).unwrap(), addr)
+        };
+        let (front, front_addr) = listen();
+        let (back, back_addr) = listen();
+
+        // The origin echoes everything once the client has half-closed.
+        thread::spawn(move || {
+            let mut origin = TcpStream::connect(back_addr).unwrap();
+            let mut data = Vec::new();
+            origin.read_to_end(&mut data).unwrap();
+            origin.write_all(&data).unwrap();
+        });
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(front_addr).unwrap();
+            // More than a buffer's worth, so the copies have to resume.
+            let sent = vec![7u8; 100_000];
+            client.write_all(&sent).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut echoed = Vec::new();
+            client.read_to_end(&mut echoed).unwrap();
+            echoed == sent
+        });
+
+        let accept = |listener: AsyncTcpListener| {
+            listener.incoming().into_future().map(|(accepted, _)| accepted.unwrap().0).map_err(|(err, _)| err)
+        };
+        let tunnel = accept(front).join(accept(back)).and_then(|(inbound, outbound)| {
+            let (inbound, outbound) = (Rc::new(inbound), Rc::new(outbound));
+            CopyHalf::new(inbound.clone(), outbound.clone()).join(CopyHalf::new(outbound, inbound))
+        });
+        assert_eq!(core.run(tunnel).unwrap(), (100_000, 100_000));
+        assert!(client.join().unwrap());
+    }
+}
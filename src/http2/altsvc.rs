@@ -0,0 +1,169 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Alternative services (RFC 7838), e.g. to tell clients an origin is also served over HTTP/3.
+//! A server sends an ALTSVC frame on stream 0 for any origin it is authoritative for, or on a
+//! stream for the origin of its request:
+//!
+//! ```rust,ignore
+//! let h3 = AltService::new("h3", ":443").max_age(86400);
+//! let frame = AltSvc::connection("https://example.com", &[h3]);
+//! ```
+
+use std::fmt;
+
+use http2::flag::Flag;
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::StreamIdentifier;
+
+/// An alternative of an `Alt-Svc` field value.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AltService {
+    /// The ALPN protocol id, e.g. `h3`.
+    pub protocol: String,
+    /// `host:port`, or `:port` for the same host.
+    pub authority: String,
+    /// Seconds the alternative may be cached for; 24 hours if `None`.
+    pub max_age: Option<u32>,
+    /// Whether clients should keep the alternative across network changes.
+    pub persist: bool,
+}
+
+impl AltService {
+    pub fn new(protocol: &str, authority: &str) -> AltService {
+        AltService {
+            protocol: protocol.to_string(),
+            authority: authority.to_string(),
+            max_age: None,
+            persist: false,
+        }
+    }
+
+    pub fn max_age(mut self, max_age: u32) -> AltService {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn persist(mut self) -> AltService {
+        self.persist = true;
+        self
+    }
+}
+
+impl fmt::Display for AltService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}=\"{}\"", self.protocol, self.authority));
+        if let Some(max_age) = self.max_age {
+            try!(write!(f, "; ma={}", max_age));
+        }
+        if self.persist {
+            try!(f.write_str("; persist=1"));
+        }
+        Ok(())
+    }
+}
+
+/// The `Alt-Svc` field value advertising `services`, or telling clients to forget the ones
+/// they have (`clear`) if there are none.
+pub fn field_value(services: &[AltService]) -> String {
+    if services.is_empty() {
+        return "clear".to_string();
+    }
+    services.iter().map(|service| service.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// An ALTSVC frame that owns its fields.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AltSvc {
+    pub id: StreamIdentifier,
+    /// Empty on a stream, where the origin is that of the request.
+    pub origin: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl AltSvc {
+    /// Advertises `services` for `origin` (`scheme://host[:port]`) on stream 0.
+    pub fn connection(origin: &str, services: &[AltService]) -> AltSvc {
+        AltSvc {
+            id: StreamIdentifier(0),
+            origin: origin.as_bytes().to_vec(),
+            value: field_value(services).into_bytes(),
+        }
+    }
+
+    /// Advertises `services` for the origin of the request on stream `id`.
+    pub fn stream(id: StreamIdentifier, services: &[AltService]) -> AltSvc {
+        AltSvc {
+            id: id,
+            origin: Vec::new(),
+            value: field_value(services).into_bytes(),
+        }
+    }
+
+    /// The ALTSVC of a frame, or `None` for another kind of frame and for the ALTSVC frames
+    /// RFC 7838 says to ignore: without an origin on stream 0, with one on another stream.
+    pub fn from_frame(frame: &Frame) -> Option<AltSvc> {
+        match frame.payload {
+            Payload::AltSvc { origin, value } if origin.is_empty() == (frame.header.id.0 != 0) => Some(AltSvc {
+                id: frame.header.id,
+                origin: origin.to_vec(),
+                value: value.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn frame(&self) -> Frame {
+        Frame::new(Flag::empty(), self.id, Payload::AltSvc {
+            origin: &self.origin,
+            value: &self.value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::{AltService, AltSvc};
+
+    #[test]
+    fn test_altsvc() {
+        let h3 = AltService::new("h3", ":443").max_age(3600);
+        let altsvc = AltSvc::connection("https://a.example", &[h3, AltService::new("h2", "b.example:8443").persist()]);
+        let mut buf = Vec::new();
+        altsvc.frame().serialize_into(&mut buf).unwrap();
+        assert_eq!(&buf[..11], &[0, 0, 69, 0xa, 0, 0, 0, 0, 0, 0, 17]);
+        assert_eq!(&buf[11..28], b"https://a.example");
+        assert_eq!(&buf[28..], &b"h3=\":443\"; ma=3600, h2=\"b.example:8443\"; persist=1"[..]);
+        assert_eq!(AltSvc::from_frame(&Frame::from_bytes(&buf).unwrap()), Some(altsvc));
+
+        let stream = AltSvc::stream(StreamIdentifier(1), &[]);
+        assert_eq!(stream.value, b"clear");
+        assert_eq!(AltSvc::from_frame(&stream.frame()), Some(stream.clone()));
+
+        // Ignored: no origin on stream 0, an origin on a stream.
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::AltSvc { origin: b"", value: b"clear" });
+        assert_eq!(AltSvc::from_frame(&frame), None);
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(1), Payload::AltSvc { origin: b"https://a", value: b"clear" });
+        assert_eq!(AltSvc::from_frame(&frame), None);
+        assert!(Frame::from_bytes(&[0, 0, 3, 0xa, 0, 0, 0, 0, 0, 0, 2, b'a']).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Receive windows sized to the bandwidth-delay product. A 64 KiB window lets a peer send
+//! only 64 KiB per round trip, far below what a long fat link carries. A `BdpEstimator` sends
+//! a PING when DATA starts coming and counts the DATA received until its ACK: about one
+//! window's worth means the window is what limits the peer, and it is doubled, up to
+//! `BdpConfig::max_window`, with `Connection::set_recv_window`, or by `BdpWindowUpdates`, the
+//! `WindowUpdateStrategy` that grows the windows with larger WINDOW_UPDATEs instead.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use http2::flag::{Flag, PingFlags};
+use http2::flow::MAX_WINDOW_SIZE;
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::window_update::{window_update, WindowUpdateConfig, WindowUpdateStrategy, WindowUpdates};
+use http2::window_update::DEFAULT_WINDOW_SIZE;
+use http2::{SizeIncrement, StreamIdentifier};
+
+/// The payload of the PINGs of a `BdpEstimator`; a `Pinger` sees their ACKs as unexpected.
+pub const BDP_PING_PAYLOAD: u64 = 0x6264_7020_7069_6e67;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BdpConfig {
+    /// The window to start from.
+    pub initial_window: u32,
+    /// The window is not grown past this.
+    pub max_window: u32,
+}
+
+impl Default for BdpConfig {
+    fn default() -> BdpConfig {
+        BdpConfig {
+            initial_window: DEFAULT_WINDOW_SIZE,
+            max_window: 16 << 20,
+        }
+    }
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Clone, Debug)]
+pub struct BdpEstimator {
+    max_window: u32,
+    window: u32,
+    /// When the PING of the current sample was sent.
+    ping_sent: Option<Instant>,
+    /// The DATA received since.
+    bytes: u64,
+    /// Smoothed as in `Rtt`.
+    rtt: Option<Duration>,
+    /// The highest delivery rate seen, in octets per second.
+    bandwidth: f64,
+}
+
+impl BdpEstimator {
+    pub fn new(config: BdpConfig) -> BdpEstimator {
+        BdpEstimator {
+            max_window: cmp::min(config.max_window, MAX_WINDOW_SIZE),
+            window: config.initial_window,
+            ping_sent: None,
+            bytes: 0,
+            rtt: None,
+            bandwidth: 0.0,
+        }
+    }
+
+    /// Takes in `len` octets of DATA received at `now`, on any stream. Returns the PING to
+    /// write at once when it starts a sample.
+    pub fn recv_data(&mut self, len: u32, now: Instant) -> Option<Frame<'static>> {
+        if self.window >= self.max_window {
+            return None;
+        }
+        self.bytes += len as u64;
+        if self.ping_sent.is_some() {
+            return None;
+        }
+        self.ping_sent = Some(now);
+        Some(Frame::ping(PingFlags::empty(), BDP_PING_PAYLOAD))
+    }
+
+    /// Takes in a PING frame read at `now`; others than the ACK of ours are ignored. Returns
+    /// the new window when the sample says the window should grow.
+    pub fn recv_ping(&mut self, frame: &Frame, now: Instant) -> Option<u32> {
+        match frame.payload {
+            Payload::Ping(BDP_PING_PAYLOAD) if frame.header.flag.contains(Flag::ack()) => {},
+            _ => return None,
+        }
+        let sent = match self.ping_sent.take() {
+            Some(sent) => sent,
+            None => return None,
+        };
+        let bytes = self.bytes;
+        self.bytes = 0;
+        let sample = now.duration_since(sent);
+        let rtt = match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        };
+        self.rtt = Some(rtt);
+
+        // A slower sample than before is the sender, not the window.
+        let bandwidth = bytes as f64 / secs(rtt).max(1e-6);
+        if bandwidth < self.bandwidth {
+            return None;
+        }
+        self.bandwidth = bandwidth;
+        if bytes < self.window as u64 * 2 / 3 {
+            return None;
+        }
+        self.window = cmp::min(bytes * 2, self.max_window as u64) as u32;
+        Some(self.window)
+    }
+
+    /// The receive window chosen, for streams and the connection alike.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// The smoothed round-trip time of the samples, `None` before the first.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// The highest delivery rate seen, in octets per second.
+    pub fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    /// The bandwidth-delay product estimated, in octets.
+    pub fn bdp(&self) -> u64 {
+        self.rtt.map_or(0, |rtt| (self.bandwidth * secs(rtt)) as u64)
+    }
+}
+
+/// Gives back consumed DATA as `WindowUpdates` does, and grows every window to the window of
+/// its `BdpEstimator` as it goes, by adding the difference to the next WINDOW_UPDATE.
+#[derive(Debug)]
+pub struct BdpWindowUpdates {
+    updates: WindowUpdates,
+    estimator: BdpEstimator,
+    /// The size each stream's window was grown to, if beyond `stream_window`.
+    streams: HashMap<StreamIdentifier, u32>,
+    stream_window: u32,
+    connection_window: u32,
+}
+
+impl BdpWindowUpdates {
+    /// `config` has the windows the connection starts with.
+    pub fn new(config: WindowUpdateConfig, bdp: BdpConfig) -> BdpWindowUpdates {
+        BdpWindowUpdates {
+            updates: WindowUpdates::new(config),
+            estimator: BdpEstimator::new(bdp),
+            streams: HashMap::new(),
+            stream_window: config.stream_window,
+            connection_window: config.connection_window,
+        }
+    }
+
+    pub fn estimator(&self) -> &BdpEstimator {
+        &self.estimator
+    }
+}
+
+/// Adds `increment` to the WINDOW_UPDATE of `id` among `frames`, or to a new one.
+fn grow(frames: &mut Vec<Frame<'static>>, id: StreamIdentifier, increment: u32) {
+    for frame in frames.iter_mut() {
+        if frame.header.id == id {
+            if let Payload::WindowUpdate(SizeIncrement(previous)) = frame.payload {
+                *frame = window_update(id, previous + increment);
+                return;
+            }
+        }
+    }
+    frames.push(window_update(id, increment));
+}
+
+impl WindowUpdateStrategy for BdpWindowUpdates {
+    fn consumed(&mut self, id: StreamIdentifier, len: u32, end_stream: bool) -> Vec<Frame<'static>> {
+        let mut frames = self.updates.consumed(id, len, end_stream);
+        let target = self.estimator.window();
+        if !end_stream {
+            let window = self.streams.entry(id).or_insert(self.stream_window);
+            if *window < target {
+                grow(&mut frames, id, target - *window);
+                *window = target;
+            }
+        }
+        if self.connection_window < target {
+            grow(&mut frames, StreamIdentifier(0), target - self.connection_window);
+            self.connection_window = target;
+        }
+        // The connection's update goes last, as `WindowUpdates` has it.
+        frames.sort_by_key(|frame| frame.header.id.0 == 0);
+        frames
+    }
+
+    fn close_stream(&mut self, id: StreamIdentifier) {
+        self.updates.close_stream(id);
+        self.streams.remove(&id);
+    }
+
+    fn flush(&mut self) -> Vec<Frame<'static>> {
+        self.updates.flush()
+    }
+
+    fn set_stream_window(&mut self, stream_window: u32) {
+        self.updates.set_stream_window(cmp::max(stream_window, self.estimator.window()));
+        self.stream_window = stream_window;
+    }
+
+    fn set_connection_window(&mut self, connection_window: u32) {
+        self.updates.set_connection_window(cmp::max(connection_window, self.estimator.window()));
+        self.connection_window = cmp::max(self.connection_window, connection_window);
+    }
+
+    fn recv_data(&mut self, len: u32, now: Instant) -> Option<Frame<'static>> {
+        self.estimator.recv_data(len, now)
+    }
+
+    fn recv_ping(&mut self, frame: &Frame, now: Instant) {
+        if let Some(window) = self.estimator.recv_ping(frame, now) {
+            // Hold back as much of the larger windows.
+            self.updates.set_stream_window(cmp::max(window, self.stream_window));
+            self.updates.set_connection_window(cmp::max(window, self.connection_window));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use http2::window_update::{window_update, WindowUpdateConfig, WindowUpdateStrategy};
+
+    use super::{BdpConfig, BdpEstimator, BdpWindowUpdates, BDP_PING_PAYLOAD};
+
+    #[test]
+    fn test_bdp() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(BDP_PING_PAYLOAD));
+        let mut bdp = BdpEstimator::new(BdpConfig { initial_window: 65535, max_window: 1 << 20 });
+
+        // 60000 octets in 100ms: the window held the peer back.
+        let ping = bdp.recv_data(10000, start).unwrap();
+        assert_eq!(ping.payload, Payload::Ping(BDP_PING_PAYLOAD));
+        assert!(bdp.recv_data(50000, ms(50)).is_none());
+        assert_eq!(bdp.recv_ping(&Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(1)), ms(100)), None);
+        assert_eq!(bdp.recv_ping(&ack, ms(100)), Some(120000));
+        assert_eq!(bdp.window(), 120000);
+        assert_eq!(bdp.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(bdp.bdp(), 60000);
+
+        // Less than the window: it stays.
+        bdp.recv_data(20000, ms(200)).unwrap();
+        assert_eq!(bdp.recv_ping(&ack, ms(300)), None);
+
+        // Up to the maximum, and no more samples then.
+        bdp.recv_data(900000, ms(400)).unwrap();
+        assert_eq!(bdp.recv_ping(&ack, ms(500)), Some(1 << 20));
+        assert!(bdp.recv_data(1, ms(600)).is_none());
+    }
+
+    #[test]
+    fn test_bdp_window_updates() {
+        let start = Instant::now();
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(BDP_PING_PAYLOAD));
+        let one = StreamIdentifier(1);
+        let mut updates = BdpWindowUpdates::new(WindowUpdateConfig::default(), BdpConfig::default());
+        assert!(updates.recv_data(60000, start).is_some());
+        updates.recv_ping(&ack, start + Duration::from_millis(100));
+        assert_eq!(updates.estimator().window(), 120000);
+
+        // The windows grow by the difference, stream and connection alike.
+        let growth = 120000 - 65535;
+        assert_eq!(updates.consumed(one, 100, false), vec![window_update(one, growth), window_update(StreamIdentifier(0), growth)]);
+        assert_eq!(updates.consumed(one, 100, false), vec![]);
+        assert_eq!(updates.consumed(one, 60000, false), vec![window_update(one, 60200), window_update(StreamIdentifier(0), 60200)]);
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! A `Codec` for HTTP/2 frames, so that `io.framed(Http2FrameCodec::new())` turns any `Io`
+//! into a stream and sink of frames, e.g. for proxies and test tools. The connection preface
+//! is not part of it: read or write it before framing the connection. `Framed` reads into an
+//! `EasyBuf`, out of which each payload is copied once; connections that read into a
+//! `BytesMut` themselves take frames out of it with `Http2FrameCodec::decode_bytes`, which
+//! doesn't copy.
+
+use std::cmp;
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use tokio_core::io::{Codec, EasyBuf};
+
+use http2::flag::Flag;
+use http2::frame::{Frame, FrameHeader};
+use http2::kind::Kind;
+use http2::parser::{DEFAULT_MAX_FRAME_SIZE, MAX_MAX_FRAME_SIZE};
+use http2::trace::TraceHook;
+use http2::{Error, StreamIdentifier, FRAME_HEADER_BYTES};
+
+/// A frame that owns its payload, as it is on the wire (padding included), so that it can go
+/// through a `Framed` transport. The payload is `Bytes`, so that frames parsed out of a
+/// `BytesMut` read buffer share its storage and bodies are sent without being copied into
+/// frames.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FrameBuf {
+    pub header: FrameHeader,
+    pub payload: Bytes,
+}
+
+impl FrameBuf {
+    /// Takes the next frame out of `buf`, the buffer the connection reads into, or returns
+    /// `None` until all of it was read. The payload is a slice of `buf`; nothing is copied.
+    pub fn parse(buf: &mut BytesMut, max_frame_size: u32) -> Result<Option<FrameBuf>, Error> {
+        if buf.len() < FRAME_HEADER_BYTES {
+            return Ok(None);
+        }
+        let header = try!(FrameHeader::parse(&buf[..]));
+        if header.length > max_frame_size {
+            return Err(Error::FrameTooLarge(header.length));
+        }
+        let len = FRAME_HEADER_BYTES + header.length as usize;
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let frame = FrameBuf {
+            header: header,
+            payload: buf.split_to(len).freeze().slice_from(FRAME_HEADER_BYTES),
+        };
+        try!(frame.frame());
+        Ok(Some(frame))
+    }
+
+    /// A DATA frame carrying `data`, e.g. a chunk of a file or of an upstream response.
+    pub fn data(id: StreamIdentifier, data: Bytes, end_stream: bool) -> FrameBuf {
+        FrameBuf {
+            header: FrameHeader {
+                length: data.len() as u32,
+                kind: Kind::Data,
+                flag: if end_stream { Flag::end_stream() } else { Flag::empty() },
+                id: id,
+            },
+            payload: data,
+        }
+    }
+
+    /// Parses the frame. Frames read by `Http2FrameCodec` were checked already, so that only
+    /// fails for frames built by hand.
+    pub fn frame(&self) -> Result<Frame, Error> {
+        Frame::parse(self.header, &self.payload)
+    }
+
+    /// The data of a DATA frame without its padding, as a slice of the payload, or `None` for
+    /// another kind of frame.
+    pub fn data_payload(&self) -> Option<Bytes> {
+        if self.header.kind != Kind::Data {
+            return None;
+        }
+        if !self.header.flag.contains(Flag::padded()) {
+            return Some(self.payload.clone());
+        }
+        match self.payload.first() {
+            Some(&pad_len) if (pad_len as usize) < self.payload.len() => {
+                Some(self.payload.slice(1, self.payload.len() - pad_len as usize))
+            },
+            _ => None,
+        }
+    }
+
+    /// Splits an unpadded DATA frame into frames of at most `max_frame_size` octets, which
+    /// share its payload; END_STREAM goes on the last one. Other frames are returned as is.
+    pub fn split(self, max_frame_size: usize) -> Vec<FrameBuf> {
+        assert!(max_frame_size > 0, "the maximum frame size must be at least one octet");
+        if self.payload.len() <= max_frame_size || self.header.kind != Kind::Data ||
+                self.header.flag.contains(Flag::padded()) {
+            return vec![self];
+        }
+        let end_stream = self.header.flag.contains(Flag::end_stream());
+        let mut frames = Vec::with_capacity(self.payload.len() / max_frame_size + 1);
+        let mut rest = self.payload;
+        while !rest.is_empty() {
+            let chunk = rest.split_to(cmp::min(rest.len(), max_frame_size));
+            frames.push(FrameBuf::data(self.header.id, chunk, end_stream && rest.is_empty()));
+        }
+        frames
+    }
+}
+
+impl<'a> From<Frame<'a>> for FrameBuf {
+    fn from(frame: Frame<'a>) -> FrameBuf {
+        let mut payload = vec![0; frame.payload.encoded_len()];
+        frame.payload.encode(&mut payload);
+        let mut header = frame.header;
+        header.length = payload.len() as u32;
+        FrameBuf {
+            header: header,
+            payload: Bytes::from(payload),
+        }
+    }
+}
+
+pub struct Http2FrameCodec {
+    max_frame_size: u32,
+    peer_max_frame_size: u32,
+    trace: Option<TraceHook>,
+}
+
+impl Http2FrameCodec {
+    pub fn new() -> Http2FrameCodec {
+        Http2FrameCodec {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            peer_max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            trace: None,
+        }
+    }
+
+    /// Sets the SETTINGS_MAX_FRAME_SIZE we announced; a longer frame fails the stream of frames.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Sets the peer's SETTINGS_MAX_FRAME_SIZE. Longer DATA frames are split to fit, unless
+    /// padded; other frames that don't fit fail the sink. Panics unless it is a valid
+    /// SETTINGS_MAX_FRAME_SIZE, 16384 to 16777215.
+    pub fn set_peer_max_frame_size(&mut self, max_frame_size: u32) {
+        assert!(max_frame_size >= DEFAULT_MAX_FRAME_SIZE && max_frame_size <= MAX_MAX_FRAME_SIZE,
+                "SETTINGS_MAX_FRAME_SIZE must be between 16384 and 16777215");
+        self.peer_max_frame_size = max_frame_size;
+    }
+
+    /// Reports every frame read and written to `trace`.
+    pub fn set_trace_hook(&mut self, trace: TraceHook) {
+        self.trace = Some(trace);
+    }
+
+    /// Takes the next frame out of `buf`, as `decode` does out of an `EasyBuf`, but with its
+    /// payload a slice of `buf` rather than a copy (see `FrameBuf::parse`).
+    pub fn decode_bytes(&mut self, buf: &mut BytesMut) -> Result<Option<FrameBuf>, Error> {
+        let frame = try!(FrameBuf::parse(buf, self.max_frame_size));
+        if let (Some(frame), Some(trace)) = (frame.as_ref(), self.trace.as_ref()) {
+            trace.received(&frame.header, &frame.payload);
+        }
+        Ok(frame)
+    }
+}
+
+fn encode_frame(mut header: FrameHeader, payload: &[u8], buf: &mut Vec<u8>) -> FrameHeader {
+    header.length = payload.len() as u32;
+    let start = buf.len();
+    buf.resize(start + FRAME_HEADER_BYTES, 0);
+    header.encode(&mut buf[start..]);
+    buf.extend_from_slice(payload);
+    header
+}
+
+fn invalid(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid frame: {:?}", e))
+}
+
+impl Codec for Http2FrameCodec {
+    type In = FrameBuf;
+    type Out = FrameBuf;
+
+    /// Errors are connection errors (see `http2::Error`), as `io::ErrorKind::InvalidData`. The
+    /// payload is copied out of `buf`, whose storage `Bytes` can't share.
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<FrameBuf>> {
+        if buf.len() < FRAME_HEADER_BYTES {
+            return Ok(None);
+        }
+        let header = try!(FrameHeader::parse(buf.as_slice()).map_err(invalid));
+        if header.length > self.max_frame_size {
+            return Err(invalid(Error::FrameTooLarge(header.length)));
+        }
+        let len = FRAME_HEADER_BYTES + header.length as usize;
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let bytes = buf.drain_to(len);
+        let frame = FrameBuf {
+            header: header,
+            payload: Bytes::from(&bytes.as_slice()[FRAME_HEADER_BYTES..]),
+        };
+        try!(frame.frame().map_err(invalid));
+        if let Some(ref trace) = self.trace {
+            trace.received(&frame.header, &frame.payload);
+        }
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, msg: FrameBuf, buf: &mut Vec<u8>) -> io::Result<()> {
+        let max = self.peer_max_frame_size as usize;
+        if msg.payload.len() > max && (msg.header.kind != Kind::Data || msg.header.flag.contains(Flag::padded())) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{:?} frame larger than the peer's maximum frame size", msg.header.kind)));
+        }
+        for frame in msg.split(max) {
+            let header = encode_frame(frame.header, &frame.payload, buf);
+            if let Some(ref trace) = self.trace {
+                trace.sent(&header, &frame.payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use http2::flag::{Flag, HeadersFlags};
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::{FrameBuf, Http2FrameCodec};
+
+    #[test]
+    fn test_codec() {
+        let mut codec = Http2FrameCodec::new();
+        let mut out = Vec::new();
+        let data = Frame::new(Flag::end_stream(), StreamIdentifier(1), Payload::Data { data: b"hi" });
+        codec.encode(FrameBuf::from(data), &mut out).unwrap();
+        codec.encode(FrameBuf::from(Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(7))), &mut out).unwrap();
+        assert_eq!(&out[..11], &[0, 0, 2, 0, 1, 0, 0, 0, 1, b'h', b'i']);
+
+        // Short of the last octet, then all of it.
+        let mut buf = EasyBuf::from(out[..out.len() - 1].to_vec());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().frame(), Ok(data));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        let mut buf = EasyBuf::from(out[11..].to_vec());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().frame().unwrap().payload, Payload::Ping(7));
+        assert_eq!(buf.len(), 0);
+
+        // PING on a stream.
+        let mut buf = EasyBuf::from(vec![0, 0, 8, 6, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(codec.decode(&mut buf).is_err());
+        codec.set_max_frame_size(1);
+        assert!(codec.decode(&mut EasyBuf::from(out)).is_err());
+    }
+
+    #[test]
+    fn test_peer_max_frame_size() {
+        let mut codec = Http2FrameCodec::new();
+        codec.set_peer_max_frame_size(16385);
+        let mut out = Vec::new();
+        let body = vec![7; 16386];
+        let data = Frame::new(Flag::end_stream(), StreamIdentifier(1), Payload::Data { data: &body });
+        codec.encode(FrameBuf::from(data), &mut out).unwrap();
+        assert_eq!(out.len(), 9 + 16385 + 9 + 1);
+        assert_eq!(&out[..9], &[0, 0x40, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&out[9 + 16385..], &[0, 0, 1, 0, 1, 0, 0, 0, 1, 7]);
+
+        let headers = Frame::headers(HeadersFlags::empty(), StreamIdentifier(1), None, &body);
+        assert!(codec.encode(FrameBuf::from(headers), &mut out).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "SETTINGS_MAX_FRAME_SIZE must be")]
+    fn test_peer_max_frame_size_too_small() {
+        Http2FrameCodec::new().set_peer_max_frame_size(16383);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one octet")]
+    fn test_split_zero() {
+        FrameBuf::data(StreamIdentifier(1), Bytes::from(&b"hi"[..]), true).split(0);
+    }
+
+    #[test]
+    fn test_zero_copy() {
+        // Long enough not to be stored inline.
+        let body = Bytes::from(vec![7; 100]);
+        let frames = FrameBuf::data(StreamIdentifier(1), body.clone(), true).split(40);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].payload.as_ptr(), body[40..].as_ptr());
+        assert!(!frames[1].header.flag.contains(Flag::end_stream()));
+        assert!(frames[2].header.flag.contains(Flag::end_stream()));
+        assert_eq!(frames[2].payload.len(), 20);
+
+        // A padded DATA frame and half a PING.
+        let mut buf = BytesMut::from(&[0, 0, 5, 0, 0x9, 0, 0, 0, 1, 2, b'h', b'i', 0, 0, 0, 0, 8, 6, 0, 0, 0, 0, 0][..]);
+        let frame = FrameBuf::parse(&mut buf, 16384).unwrap().unwrap();
+        assert_eq!(frame.data_payload(), Some(Bytes::from(&b"hi"[..])));
+        assert_eq!(FrameBuf::parse(&mut buf, 16384), Ok(None));
+        assert_eq!(buf.len(), 9);
+        assert!(FrameBuf::parse(&mut buf, 4).is_err());
+
+        // The codec's.
+        let mut codec = Http2FrameCodec::new();
+        let mut buf = BytesMut::from(vec![0; 9 + 100]);
+        buf[2] = 100;
+        buf[8] = 1;
+        let payload = buf[9..].as_ptr();
+        assert_eq!(codec.decode_bytes(&mut buf).unwrap().unwrap().payload.as_ptr(), payload);
+        assert_eq!(codec.decode_bytes(&mut buf), Ok(None));
+        codec.set_max_frame_size(1);
+        assert!(codec.decode_bytes(&mut BytesMut::from(&[0, 0, 2, 0, 0, 0, 0, 0, 1, 0, 0][..])).is_err());
+    }
+}
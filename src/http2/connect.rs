@@ -0,0 +1,241 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! CONNECT over HTTP/2 (RFC 7540 section 8.3). A server takes the target from `authority`,
+//! opens the outbound connection with a `ConnectPolicy` (see `http::tunnel`), answers with a
+//! 200 that doesn't end the stream, and then bridges the stream to the connection with a
+//! `ConnectBridge`.
+//!
+//! Both directions are flow controlled end to end: DATA from the client is only given back to
+//! its window once the upstream took it, and the upstream is only read while the stream has
+//! window to send it with, so a slow side holds the other back instead of the proxy buffering
+//! for it. END_STREAM and the upstream's EOF are passed on as half-closes.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use http2::codec::FrameBuf;
+use http2::connection::Connection;
+use http2::frame::Frame;
+use http2::{StreamIdentifier, CONNECT_ERROR};
+
+/// The target of a CONNECT request, from its decoded header fields: the `:authority` of a
+/// request whose `:method` is CONNECT and which has neither `:scheme` nor `:path`. Extended
+/// CONNECT (RFC 8441), which has them, isn't a tunnel to the authority.
+pub fn authority(fields: &[(Vec<u8>, Vec<u8>)]) -> Option<&str> {
+    let field = |name: &[u8]| fields.iter().find(|&&(ref n, _)| &n[..] == name).map(|&(_, ref v)| &v[..]);
+    if field(b":method") != Some(b"CONNECT") || field(b":scheme").is_some() || field(b":path").is_some() {
+        return None;
+    }
+    field(b":authority").and_then(|authority| ::std::str::from_utf8(authority).ok())
+}
+
+/// Bridges an accepted CONNECT stream of a server `Connection` to its outbound connection.
+/// The connection's DATA for the stream goes to `recv_data` instead of `Connection::consumed`;
+/// `write_upstream` and `read_upstream` are then called as the upstream becomes writable and
+/// readable, and `read_upstream` again when a `Recv::WindowUpdate` names the stream.
+#[derive(Debug)]
+pub struct ConnectBridge {
+    id: StreamIdentifier,
+    /// DATA from the client not written upstream yet; no more than the stream's window let in.
+    pending: Vec<u8>,
+    /// The client sent END_STREAM.
+    client_done: bool,
+    /// The upstream was half-closed after all of the client's DATA.
+    upstream_closed: bool,
+    /// The upstream's EOF went to the client as END_STREAM.
+    upstream_done: bool,
+    buf: Vec<u8>,
+}
+
+impl ConnectBridge {
+    pub fn new(id: StreamIdentifier) -> ConnectBridge {
+        ConnectBridge {
+            id: id,
+            pending: Vec::new(),
+            client_done: false,
+            upstream_closed: false,
+            upstream_done: false,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> StreamIdentifier {
+        self.id
+    }
+
+    /// Takes in the data of a `Recv::Data` of the stream, to write upstream.
+    pub fn recv_data(&mut self, data: &[u8], end_stream: bool) {
+        self.pending.extend_from_slice(data);
+        self.client_done = self.client_done || end_stream;
+    }
+
+    /// Writes what the client sent upstream, as far as `upstream` takes it without blocking,
+    /// and returns the WINDOW_UPDATEs that gives back. Half-closes `upstream` once the client's
+    /// END_STREAM is reached.
+    pub fn write_upstream(&mut self, conn: &mut Connection, upstream: &TcpStream)
+                          -> io::Result<Vec<Frame<'static>>> {
+        let mut written = 0;
+        while written < self.pending.len() {
+            match (&*upstream).write(&self.pending[written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "upstream stopped accepting data")),
+                Ok(n) => written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.pending.drain(..written);
+        if self.pending.is_empty() && self.client_done && !self.upstream_closed {
+            self.upstream_closed = true;
+            try!(upstream.shutdown(Shutdown::Write));
+        }
+        Ok(conn.consumed(self.id, written as u32))
+    }
+
+    /// Reads from `upstream` as much as the stream may send now without blocking, and returns
+    /// the DATA frames to write; the last carries END_STREAM once the upstream is done. Reads
+    /// nothing while the stream has no window.
+    pub fn read_upstream(&mut self, conn: &mut Connection, upstream: &TcpStream, now: Instant)
+                         -> io::Result<Vec<FrameBuf>> {
+        let mut frames = Vec::new();
+        while !self.upstream_done {
+            let capacity = cmp::min(conn.poll_capacity(self.id, now), conn.peer_max_frame_size()) as usize;
+            if capacity == 0 {
+                break;
+            }
+            self.buf.resize(capacity, 0);
+            let n = match (&*upstream).read(&mut self.buf) {
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            };
+            self.upstream_done = n == 0;
+            try!(conn.send_data(self.id, n as u32, self.upstream_done, now)
+                 .map_err(|error| io::Error::new(io::ErrorKind::Other, format!("{:?}", error))));
+            frames.push(FrameBuf::data(self.id, Bytes::from(&self.buf[..n]), self.upstream_done));
+        }
+        Ok(frames)
+    }
+
+    /// The RST_STREAM to send when the upstream failed or was reset.
+    pub fn reset(&self, conn: &mut Connection) -> Option<Frame<'static>> {
+        conn.send_reset(self.id, CONNECT_ERROR)
+    }
+
+    /// Both directions were closed: the stream is done with.
+    pub fn is_done(&self) -> bool {
+        self.upstream_closed && self.upstream_done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use http2::codec::FrameBuf;
+    use http2::connection::{Connection, ConnectionConfig, Recv};
+    use http2::flag::{DataFlags, Flag, SettingsFlags};
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::settings::Settings;
+    use http2::{SizeIncrement, StreamIdentifier};
+
+    use super::{authority, ConnectBridge};
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        fields.iter().map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    /// Reads until `len` octets went out as DATA, or the upstream's EOF did.
+    fn read(bridge: &mut ConnectBridge, conn: &mut Connection, upstream: &TcpStream, len: usize) -> Vec<FrameBuf> {
+        let mut frames = Vec::new();
+        for _ in 0..500 {
+            frames.extend(bridge.read_upstream(conn, upstream, Instant::now()).unwrap());
+            let read: usize = frames.iter().map(|frame| frame.payload.len()).sum();
+            if read >= len && (len > 0 || frames.iter().any(|frame| frame.header.flag.contains(Flag::end_stream()))) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+        frames
+    }
+
+    #[test]
+    fn test_authority() {
+        assert_eq!(authority(&fields(&[(":method", "CONNECT"), (":authority", "example.com:443")])),
+                   Some("example.com:443"));
+        assert_eq!(authority(&fields(&[(":method", "GET"), (":authority", "example.com:443")])), None);
+        assert_eq!(authority(&fields(&[(":method", "CONNECT"), (":protocol", "websocket"), (":scheme", "https"),
+                                       (":path", "/chat"), (":authority", "example.com:443")])), None);
+        assert_eq!(authority(&fields(&[(":method", "CONNECT")])), None);
+    }
+
+    #[test]
+    fn test_bridge() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = TcpStream::connect(origin.local_addr().unwrap()).unwrap();
+        upstream.set_nonblocking(true).unwrap();
+        let (mut origin, _) = origin.accept().unwrap();
+
+        // The client lets the server send 4 octets on the stream.
+        let mut conn = Connection::new(true, ConnectionConfig::default());
+        let settings = Settings { initial_window_size: Some(4), ..Settings::default() }.to_payload();
+        conn.recv(&Frame::settings(SettingsFlags::empty(), &settings), Instant::now()).unwrap();
+        let headers = Payload::Headers { priority: None, block: &[0x82] };
+        conn.recv(&Frame::new(Flag::end_headers(), StreamIdentifier(1), headers), Instant::now()).unwrap();
+        conn.send_headers(StreamIdentifier(1), false, Instant::now()).unwrap();
+        let mut bridge = ConnectBridge::new(StreamIdentifier(1));
+
+        // The client's DATA goes upstream.
+        let data = Frame::data(DataFlags::empty(), StreamIdentifier(1), b"ping");
+        assert_eq!(conn.recv(&data, Instant::now()), Ok(Recv::Data { id: StreamIdentifier(1), end_stream: false }));
+        bridge.recv_data(b"ping", false);
+        bridge.write_upstream(&mut conn, &upstream).unwrap();
+        let mut buf = [0; 4];
+        origin.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The upstream is read no further than the window goes, until it grows.
+        origin.write_all(b"pong pong").unwrap();
+        let frames = read(&mut bridge, &mut conn, &upstream, 4);
+        assert_eq!(frames.iter().map(|frame| &frame.payload[..]).collect::<Vec<_>>().concat(), b"pong");
+        assert!(bridge.read_upstream(&mut conn, &upstream, Instant::now()).unwrap().is_empty());
+        let update = Frame::window_update(StreamIdentifier(1), SizeIncrement(100));
+        assert_eq!(conn.recv(&update, Instant::now()), Ok(Recv::WindowUpdate(vec![StreamIdentifier(1)])));
+        let frames = read(&mut bridge, &mut conn, &upstream, 5);
+        assert_eq!(frames.iter().map(|frame| &frame.payload[..]).collect::<Vec<_>>().concat(), b" pong");
+
+        // Half-closes both ways.
+        origin.shutdown(Shutdown::Write).unwrap();
+        let frames = read(&mut bridge, &mut conn, &upstream, 0);
+        assert!(frames.last().unwrap().header.flag.contains(Flag::end_stream()));
+        assert!(!bridge.is_done());
+        let end = Frame::data(DataFlags::end_stream(), StreamIdentifier(1), b"");
+        assert_eq!(conn.recv(&end, Instant::now()), Ok(Recv::Data { id: StreamIdentifier(1), end_stream: true }));
+        bridge.recv_data(b"", true);
+        bridge.write_upstream(&mut conn, &upstream).unwrap();
+        assert_eq!(origin.read(&mut buf).unwrap(), 0);
+        assert!(bridge.is_done());
+        assert_eq!(bridge.reset(&mut conn), None);
+    }
+}
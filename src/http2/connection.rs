@@ -0,0 +1,2122 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The streams of a connection (RFC 7540 section 5.1). A `Connection` is handed every frame
+//! received, drives the state of its stream and says what the frame means for the caller; the
+//! frames we send go through it first, so that streams are opened and closed on both sides.
+//! It keeps the SETTINGS of both sides too, and the flow control windows. It does no I/O, and leaves HPACK and the other
+//! frames of the connection to the caller:
+//!
+//! ```rust,ignore
+//! match try!(connection.recv(&frame, now)) {
+//!     Recv::Headers(block) => { /* decode the block, handle the request */ },
+//!     Recv::StreamError(error, block) => { /* decode the block, write error.frame() */ },
+//!     ...
+//! }
+//! ```
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll};
+use futures::sync::oneshot;
+
+use http2::codec::FrameBuf;
+use http2::continuation::{HeaderBlock, Reassembler};
+use http2::extension::Extensions;
+use http2::flag::{Flag, SettingsFlags};
+use http2::flood::{FloodConfig, FloodGuard};
+use http2::flow::{Window, MAX_WINDOW_SIZE};
+use http2::frame::Frame;
+use http2::handshake::{Handshake, HandshakeConfig, HandshakeTimeout};
+use http2::idle::IdleTimer;
+use http2::keepalive::{Keepalive, KeepaliveTimeout, KEEPALIVE_PING_PAYLOAD};
+use http2::mode::Mode;
+use http2::parser::DEFAULT_MAX_FRAME_SIZE;
+use http2::payload::Payload;
+use http2::ping::{PingConfig, Pinger, Pong, Rtt};
+use http2::priority::{PriorityUpdate, Scheduler};
+use http2::priority_tree::{PriorityTree, PriorityTreeConfig};
+use http2::preface::{self, InvalidPreface};
+use http2::push::{AutoPush, Promise};
+use http2::registry::SettingsRegistry;
+use http2::schedule::SendScheduler;
+use http2::settings::{Settings, SettingsConfig, SettingsTracker};
+use http2::stall::{Stall, StallConfig, StallDetector};
+use http2::stats::{StatsRecorder, StreamStats};
+use http2::stream::{Pushes, State, Stream, StreamError};
+use http2::window_update::{WindowUpdateConfig, WindowUpdateStrategy, WindowUpdates, DEFAULT_WINDOW_SIZE};
+use http2::{Error, ErrorCode, SizeIncrement, StreamIdentifier};
+use http2::{CANCEL, FLOW_CONTROL_ERROR, NO_ERROR, PROTOCOL_ERROR, REFUSED_STREAM, STREAM_CLOSED};
+use audit::{AuditHook, Reason};
+use http::shed::LoadShedder;
+
+/// The payload of the PING sent with the first GOAWAY of a graceful shutdown.
+pub const SHUTDOWN_PING_PAYLOAD: u64 = 0x676f_6177_6179_2121;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// The rules of RFC 7540, or of RFC 9113, which ignores the stream priorities. The write
+    /// scheduler is a `PriorityTree` in the modes that honour them, and a `priority::Scheduler`
+    /// for RFC 9218's otherwise; see `Connection::set_scheduler`.
+    pub mode: Mode,
+    /// How many of the streams we reset are remembered, so that the frames the peer sent on
+    /// them before it got the RST_STREAM are ignored rather than a connection error.
+    pub max_reset_streams: usize,
+    /// Whether a stream `open_stream` can't open yet, because of the peer's
+    /// SETTINGS_MAX_CONCURRENT_STREAMS, is queued until it can. Otherwise it fails.
+    pub queue_streams: bool,
+    /// The largest header block the peer may send, before decompression, CONTINUATION frames
+    /// included; past it the connection fails with `Error::HeaderBlockTooLarge`. Our
+    /// SETTINGS_MAX_HEADER_LIST_SIZE lowers it once acknowledged: a block is never much
+    /// larger than the list it decodes to.
+    pub max_header_block_size: usize,
+    pub settings: SettingsConfig,
+    /// When the DATA given to `Connection::consumed` is given back, unless another
+    /// strategy is set with `Connection::set_window_update_strategy`. The connection's window
+    /// is raised to `connection_window` with the preface; `stream_window` follows our
+    /// SETTINGS_INITIAL_WINDOW_SIZE once it is acknowledged.
+    pub window_updates: WindowUpdateConfig,
+    /// How long a graceful shutdown waits between its two GOAWAYs, at most: the peer has until
+    /// then, or until it acknowledges the PING sent with the first, to get the requests it
+    /// already sent on their way.
+    pub shutdown_grace: Duration,
+    /// How long the connection may go without reading a frame before it sends a keepalive
+    /// PING; `None` sends none.
+    pub keepalive_interval: Option<Duration>,
+    /// How long a keepalive PING may go without its ACK before
+    /// `Connection::poll_keepalive` gives up on the connection.
+    pub keepalive_timeout: Duration,
+    /// How long the connection may go without streams before `Connection::poll_idle` closes
+    /// it; `None` keeps it open.
+    pub idle_timeout: Option<Duration>,
+    /// Whether PINGs from the peer keep an idle connection open, as streams do.
+    pub idle_ping_exempt: bool,
+    /// The rates of frames past which `Connection::recv` fails with `Error::Flood`.
+    pub flood: FloodConfig,
+    /// Our PINGs, keepalive and shutdown ones included, that are waited for.
+    pub ping: PingConfig,
+    /// How long the peer has from `Connection::preface` to complete the SETTINGS exchange,
+    /// its preface included, before `Connection::check_handshake` fails; `None` waits forever.
+    pub handshake: Option<HandshakeConfig>,
+    /// How many closed streams `Connection::stream_stats` still has the statistics of.
+    pub max_closed_stats: usize,
+    /// How slowly the peer may let our streams send before `Connection::poll_stalls` resets
+    /// them; `None` doesn't look.
+    pub stall: Option<StallConfig>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            mode: Mode::default(),
+            max_reset_streams: 32,
+            queue_streams: false,
+            max_header_block_size: 64 << 10,
+            settings: SettingsConfig::default(),
+            window_updates: WindowUpdateConfig::default(),
+            shutdown_grace: Duration::from_secs(1),
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(20),
+            idle_timeout: None,
+            idle_ping_exempt: false,
+            flood: FloodConfig::default(),
+            ping: PingConfig::default(),
+            handshake: Some(HandshakeConfig::default()),
+            max_closed_stats: 32,
+            stall: Some(StallConfig::default()),
+        }
+    }
+}
+
+/// What a received frame means for the caller.
+///
+/// The header block of `Ignored` and `StreamError` must still go through the HPACK decoder,
+/// to keep its dynamic table in step with the peer's.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recv {
+    /// Nothing yet: the frame is part of a header block that isn't complete.
+    Pending,
+    /// A whole header block, for the request, response or trailers of stream `id`, or for the
+    /// push it promised if `promised` is set.
+    Headers(HeaderBlock),
+    /// DATA for the stream; `end_stream` if the peer won't send on it again. Its length goes
+    /// to `Connection::consumed` once the application is done with it.
+    Data { id: StreamIdentifier, end_stream: bool },
+    /// The peer reset the stream.
+    Reset(StreamIdentifier, ErrorCode),
+    /// The peer changed these of its settings, which were applied; write the SETTINGS ACK
+    /// `Connection::settings_ack` returns. A larger SETTINGS_INITIAL_WINDOW_SIZE
+    /// may unblock streams: see `Connection::poll_unblocked`.
+    Settings(Settings),
+    /// The peer acknowledged a SETTINGS frame of ours: these of our settings were applied.
+    SettingsAcked(Settings),
+    /// A WINDOW_UPDATE, after which these streams, blocked before (see
+    /// `Connection::poll_capacity`), may send DATA again. Often none.
+    WindowUpdate(Vec<StreamIdentifier>),
+    /// The peer's PING: write this ACK, ahead of other frames.
+    Ping(Frame<'static>),
+    /// A frame for the connection rather than a stream, or one without an effect on the state
+    /// of its stream: a PING ACK, GOAWAY, PRIORITY, PRIORITY_UPDATE, ALTSVC, ORIGIN and extension
+    /// frames, which went to their handler in `Connection::extensions_mut` if they have one.
+    Connection,
+    /// A frame on a stream we reset, sent before the peer got our RST_STREAM. The length of
+    /// DATA still goes to `Connection::consumed`, right away, for the connection's window.
+    Ignored(Option<HeaderBlock>),
+    /// The frame broke the rules of its stream, which was reset: write `error.frame()`. The
+    /// other streams carry on. DATA goes to `Connection::consumed` as for `Ignored`.
+    StreamError(StreamError, Option<HeaderBlock>),
+}
+
+/// Why `open_stream` didn't open a stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OpenError {
+    /// A server opens streams with `Connection::push` only.
+    Server,
+    /// Stream ids ran out; requests need a new connection.
+    Exhausted,
+    /// As many streams are open as the peer's SETTINGS_MAX_CONCURRENT_STREAMS allows.
+    TooManyStreams,
+    /// The same, but the stream was queued: `Connection::poll_open` opens it once another one
+    /// closed.
+    Queued,
+    /// The connection is shutting down.
+    GoingAway,
+}
+
+/// Why `set_priority` didn't reprioritize a stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PriorityError {
+    /// Only a client sends PRIORITY_UPDATE frames.
+    Server,
+    /// The stream is closed.
+    Closed,
+}
+
+/// Where a graceful shutdown is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Shutdown {
+    Running,
+    /// The first GOAWAY was sent; the final one goes at `deadline`, or once the PING sent with
+    /// it was acknowledged.
+    Draining { deadline: Instant, acked: bool },
+    /// The final GOAWAY was sent, accepting the peer's streams up to this one.
+    Closing(StreamIdentifier),
+}
+
+/// Resolves once a graceful shutdown is done: the final GOAWAY was sent, and the streams it
+/// accepted completed. Fails with `Canceled` if the connection is dropped first.
+pub struct Drained(oneshot::Receiver<()>);
+
+impl fmt::Debug for Drained {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Drained")
+    }
+}
+
+impl Future for Drained {
+    type Item = ();
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<(), oneshot::Canceled> {
+        self.0.poll()
+    }
+}
+
+/// The other end of a `Drained`, completed by `check_drained`.
+struct DrainWaiter(oneshot::Sender<()>);
+
+impl fmt::Debug for DrainWaiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DrainWaiter")
+    }
+}
+
+/// The streams that count towards SETTINGS_MAX_CONCURRENT_STREAMS: the open and half closed
+/// ones, and our idle ones, which are only waiting for their HEADERS.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StreamCounts {
+    /// The streams we opened.
+    pub local: usize,
+    /// The streams the peer opened.
+    pub remote: usize,
+    /// The streams waiting to be opened; see `ConnectionConfig::queue_streams`.
+    pub queued: usize,
+}
+
+fn counts(state: State) -> bool {
+    match state {
+        State::ReservedLocal | State::ReservedRemote | State::Closed => false,
+        State::Idle | State::Open | State::HalfClosedLocal | State::HalfClosedRemote => true,
+    }
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    server: bool,
+    config: ConnectionConfig,
+    /// The streams that are not idle or closed, and our own idle ones.
+    streams: HashMap<StreamIdentifier, Stream>,
+    /// The highest ids of the streams we and the peer opened or reserved. Streams with lower
+    /// ids that aren't in `streams` are closed.
+    last_local: u32,
+    last_remote: u32,
+    counts: StreamCounts,
+    /// The streams we reset lately, the oldest first.
+    reset: VecDeque<StreamIdentifier>,
+    reassembler: Reassembler,
+    pushes: Pushes,
+    /// The peer's first SETTINGS frame, the end of its preface, was received.
+    preface_received: bool,
+    /// Ours, from being sent until they apply, and the peer's.
+    local_settings: SettingsTracker,
+    peer_settings: Settings,
+    /// The connection's windows, which DATA on every stream takes from.
+    send_window: Window,
+    recv_window: Window,
+    window_updates: Box<WindowUpdateStrategy + Send>,
+    /// The streams `poll_capacity` found without window, to name in `Recv::WindowUpdate`.
+    blocked: Vec<StreamIdentifier>,
+    shutdown: Shutdown,
+    drained: Vec<DrainWaiter>,
+    keepalive: Keepalive,
+    idle: Option<IdleTimer>,
+    flood: FloodGuard,
+    /// Every PING we send, to match the ACKs to.
+    pinger: Pinger,
+    /// Started by `preface`.
+    handshake: Option<Handshake>,
+    registry: SettingsRegistry,
+    extensions: Extensions,
+    /// Fed the streams with DATA, the DATA sent and the peer's priority signals.
+    scheduler: Box<SendScheduler + Send>,
+    /// The statistics of the streams in `streams`, from the first frame either way, and of the
+    /// last closed ones, the oldest first.
+    stats: HashMap<StreamIdentifier, StatsRecorder>,
+    closed_stats: VecDeque<(StreamIdentifier, StatsRecorder)>,
+    /// While it sheds, the peer's new streams are refused.
+    shedder: Option<LoadShedder>,
+    /// Told of the streams reset, with the peer's address.
+    audit: Option<(AuditHook, SocketAddr)>,
+    /// Fed the send side of every stream by `queued`, `poll_capacity` and `send_data`.
+    stall: Option<StallDetector>,
+}
+
+impl Connection {
+    pub fn new(server: bool, config: ConnectionConfig) -> Connection {
+        let mut reassembler = Reassembler::new();
+        reassembler.set_max_block_size(Some(config.max_header_block_size));
+        Connection {
+            server: server,
+            config: config,
+            streams: HashMap::new(),
+            last_local: 0,
+            last_remote: 0,
+            counts: StreamCounts::default(),
+            reset: VecDeque::new(),
+            reassembler: reassembler,
+            pushes: Pushes::new(server),
+            preface_received: false,
+            local_settings: SettingsTracker::new(config.settings),
+            peer_settings: Settings::default(),
+            send_window: Window::new(DEFAULT_WINDOW_SIZE),
+            recv_window: Window::new(DEFAULT_WINDOW_SIZE),
+            window_updates: Box::new(WindowUpdates::new(config.window_updates)),
+            blocked: Vec::new(),
+            shutdown: Shutdown::Running,
+            drained: Vec::new(),
+            keepalive: Keepalive::new(config.keepalive_interval, config.keepalive_timeout),
+            idle: config.idle_timeout.map(|timeout| IdleTimer::new(timeout, config.idle_ping_exempt)),
+            flood: if server { FloodGuard::new(config.flood) } else { FloodGuard::client(config.flood) },
+            pinger: Pinger::new(config.ping),
+            handshake: None,
+            registry: SettingsRegistry::new(),
+            extensions: Extensions::new(),
+            scheduler: if config.mode.honours_priority() {
+                Box::new(PriorityTree::new(PriorityTreeConfig::default()))
+            } else {
+                Box::new(Scheduler::new())
+            },
+            stats: HashMap::new(),
+            closed_stats: VecDeque::new(),
+            shedder: None,
+            audit: None,
+            stall: config.stall.map(StallDetector::new),
+        }
+    }
+
+    /// Our connection preface, with our first `settings`, to send first: the client's, or
+    /// the server's without waiting for the client's. A server reads the client's `PREFACE`
+    /// with a `PrefaceReader` before handing frames to `recv`, and tells the reader
+    /// `settings_received` once `recv` took the first one.
+    pub fn preface(&mut self, settings: &Settings, now: Instant) -> Vec<u8> {
+        let mut settings = settings.clone();
+        self.registry.add_local(&mut settings);
+        let settings = &settings;
+        self.local_settings.send_all(settings.clone(), now);
+        // TLS, if any, was done before the connection; a client sent the preface itself.
+        self.handshake = self.config.handshake.map(|config| {
+            let mut handshake = Handshake::new(config, now, false);
+            if !self.server {
+                handshake.preface_received();
+            }
+            handshake
+        });
+        let mut preface = if self.server {
+            preface::server_preface(settings)
+        } else {
+            preface::client_preface(settings)
+        };
+        // Only a WINDOW_UPDATE changes the connection's window.
+        let connection_window = self.config.window_updates.connection_window;
+        if connection_window > DEFAULT_WINDOW_SIZE && self.recv_window.increase(connection_window - DEFAULT_WINDOW_SIZE) {
+            let increment = SizeIncrement(connection_window - DEFAULT_WINDOW_SIZE);
+            Frame::window_update(StreamIdentifier(0), increment).serialize_into(&mut preface).unwrap();
+        }
+        preface
+    }
+
+    /// Returns the SETTINGS frame changing our settings to `settings`, to write now, or
+    /// `None` if they didn't change. They apply to the peer once it acknowledges the frame
+    /// (see `Recv::SettingsAcked`), which it has the `SettingsConfig` timeout to do.
+    pub fn update_settings(&mut self, settings: &Settings, now: Instant) -> Option<FrameBuf> {
+        let mut settings = settings.clone();
+        self.registry.add_local(&mut settings);
+        self.local_settings.send(&settings, now).map(|payload| {
+            FrameBuf::from(Frame::settings(SettingsFlags::empty(), &payload))
+        })
+    }
+
+    /// The custom settings: register them before `preface`, and their values go out with ours
+    /// and the peer's are read from its SETTINGS frames.
+    pub fn settings_registry_mut(&mut self) -> &mut SettingsRegistry {
+        &mut self.registry
+    }
+
+    /// The handlers of extension frames, which `recv` hands the frames of unknown types to, and
+    /// the extension frames queued to send, for the write path to take with `next_outbound`.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Replaces the write scheduler of the mode, before streams open.
+    pub fn set_scheduler(&mut self, scheduler: Box<SendScheduler + Send>) {
+        self.scheduler = scheduler;
+    }
+
+    /// The write scheduler, whose `next` is the stream to send DATA of next. The connection
+    /// tells it which streams have DATA (from `queued` until `poll_capacity` finds them blocked
+    /// or `send_data` ends them), what `send_data` sent, the streams that closed and the
+    /// priorities the peer sent; a stream whose DATA ran out before it ended is the
+    /// application's to `set_ready(id, false)`.
+    pub fn scheduler_mut(&mut self) -> &mut (SendScheduler + Send) {
+        &mut *self.scheduler
+    }
+
+    /// Refuses the peer's new streams with REFUSED_STREAM while `shedder` is shedding, as it
+    /// does those over MAX_CONCURRENT_STREAMS; the peer can retry them elsewhere.
+    pub fn set_load_shedder(&mut self, shedder: LoadShedder) {
+        self.shedder = Some(shedder);
+    }
+
+    /// Reports the streams reset to `audit`: those the peer's frames got reset for, and those
+    /// `send_reset` resets with a code other than NO_ERROR or CANCEL, which end streams that
+    /// are no longer wanted rather than refuse them.
+    pub fn set_audit(&mut self, audit: AuditHook, peer: SocketAddr) {
+        self.audit = Some((audit, peer));
+    }
+
+    /// Our settings the peer acknowledged, which apply to what it sends.
+    pub fn local_settings(&self) -> &Settings {
+        self.local_settings.acknowledged()
+    }
+
+    /// All the settings the peer sent so far; `None` for those still at their initial value.
+    pub fn peer_settings(&self) -> &Settings {
+        &self.peer_settings
+    }
+
+    /// The peer's SETTINGS_MAX_FRAME_SIZE, which frames we send must fit.
+    pub fn peer_max_frame_size(&self) -> u32 {
+        self.peer_settings.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// When `check` (and `check_handshake`, `poll_shutdown`, `poll_keepalive`, `poll_idle`)
+    /// should be called next, or `None` if nothing is waiting.
+    pub fn deadline(&self) -> Option<Instant> {
+        let shutdown = match self.shutdown {
+            Shutdown::Draining { deadline, .. } => Some(deadline),
+            _ => None,
+        };
+        let idle = match self.shutdown {
+            Shutdown::Running => self.idle.and_then(|idle| idle.deadline()),
+            _ => None,
+        };
+        let handshake = self.handshake.and_then(|handshake| handshake.deadline());
+        [self.local_settings.deadline(), shutdown, self.keepalive.deadline(), idle, handshake]
+            .iter().filter_map(|&deadline| deadline).min()
+    }
+
+    /// Fails with the error code to send a GOAWAY with and close the connection with once the
+    /// peer took too long, e.g. SETTINGS_TIMEOUT for its SETTINGS ACK.
+    pub fn check(&self, now: Instant) -> Result<(), ErrorCode> {
+        self.local_settings.check(now)
+    }
+
+    /// Fails once the peer took longer than `ConnectionConfig::handshake` to send its preface
+    /// and SETTINGS and acknowledge ours: the connection should be dropped, with a GOAWAY if
+    /// the timeout has an `error_code`.
+    pub fn check_handshake(&self, now: Instant) -> Result<(), HandshakeTimeout> {
+        self.handshake.map_or(Ok(()), |handshake| handshake.check(now))
+    }
+
+    /// Starts a graceful shutdown, and returns the frames to write: a GOAWAY with the highest
+    /// stream id there is, which stops the peer from opening streams without refusing those
+    /// on their way, and a PING. The final GOAWAY, with the last stream accepted, comes from
+    /// `poll_shutdown` once the PING's ACK came in or `ConnectionConfig::shutdown_grace`
+    /// passed; the streams it accepted still complete, which the `Drained` future waits for.
+    pub fn graceful_shutdown(&mut self, now: Instant) -> (Vec<Frame<'static>>, Drained) {
+        let (tx, rx) = oneshot::channel();
+        self.drained.push(DrainWaiter(tx));
+        let mut frames = Vec::new();
+        if self.shutdown == Shutdown::Running {
+            self.shutdown = Shutdown::Draining { deadline: now + self.config.shutdown_grace, acked: false };
+            frames.push(goaway(StreamIdentifier((1 << 31) - 1)));
+            frames.push(self.pinger.ping(SHUTDOWN_PING_PAYLOAD, now));
+        }
+        self.check_drained();
+        (frames, Drained(rx))
+    }
+
+    /// Returns the final GOAWAY of a graceful shutdown when it is due, to write at once.
+    pub fn poll_shutdown(&mut self, now: Instant) -> Option<Frame<'static>> {
+        match self.shutdown {
+            Shutdown::Draining { deadline, acked } if acked || now >= deadline => {
+                let last = StreamIdentifier(self.last_remote);
+                self.shutdown = Shutdown::Closing(last);
+                self.check_drained();
+                Some(goaway(last))
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a graceful shutdown sent its final GOAWAY and the streams completed: the
+    /// connection may be closed.
+    pub fn is_drained(&self) -> bool {
+        match self.shutdown {
+            Shutdown::Closing(_) => self.counts.local == 0 && self.counts.remote == 0,
+            _ => false,
+        }
+    }
+
+    fn check_drained(&mut self) {
+        if self.is_drained() {
+            for DrainWaiter(tx) in self.drained.drain(..) {
+                tx.complete(());
+            }
+        }
+    }
+
+    /// Returns a keepalive PING to write when nothing was read for
+    /// `ConnectionConfig::keepalive_interval`, and fails once one went without its ACK for
+    /// `keepalive_timeout`: the peer is gone and the connection should be closed. Called first
+    /// at the start, then at `deadline`, and best after reading.
+    pub fn poll_keepalive(&mut self, now: Instant) -> Result<Option<Frame<'static>>, KeepaliveTimeout> {
+        let ping = try!(self.keepalive.poll(now));
+        Ok(ping.map(|_| self.pinger.ping(KEEPALIVE_PING_PAYLOAD, now)))
+    }
+
+    /// Returns a PING with the opaque `payload` to write at `now`, to measure the round-trip
+    /// time with: see `rtt`. The payload must not be one of the connection's own
+    /// (`KEEPALIVE_PING_PAYLOAD`, `SHUTDOWN_PING_PAYLOAD`).
+    pub fn ping(&mut self, payload: u64, now: Instant) -> Frame<'static> {
+        self.pinger.ping(payload, now)
+    }
+
+    /// The round-trip time, as measured with every PING of ours that was acknowledged.
+    pub fn rtt(&self) -> Rtt {
+        self.pinger.rtt()
+    }
+
+    /// Returns a GOAWAY NO_ERROR to write, after which the connection can be closed, once it
+    /// went without streams for `ConnectionConfig::idle_timeout`. Called first at the start,
+    /// then at `deadline`, and best whenever streams closed.
+    pub fn poll_idle(&mut self, now: Instant) -> Option<Frame<'static>> {
+        if self.shutdown != Shutdown::Running {
+            return None;
+        }
+        let streams = self.counts.local + self.counts.remote + self.counts.queued;
+        let idle = match self.idle {
+            Some(ref mut idle) => idle.poll(now, streams),
+            None => false,
+        };
+        if !idle {
+            return None;
+        }
+        let last = StreamIdentifier(self.last_remote);
+        self.shutdown = Shutdown::Closing(last);
+        self.check_drained();
+        Some(goaway(last))
+    }
+
+    pub fn is_server(&self) -> bool {
+        self.server
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.config.mode
+    }
+
+    /// The state of stream `id`, idle or closed for the streams it doesn't keep.
+    pub fn state(&self, id: StreamIdentifier) -> State {
+        match self.streams.get(&id) {
+            Some(stream) => stream.state(),
+            None if id.0 <= self.last(id) => State::Closed,
+            None => State::Idle,
+        }
+    }
+
+    pub fn stream(&self, id: StreamIdentifier) -> Option<&Stream> {
+        self.streams.get(&id)
+    }
+
+    pub fn stream_mut(&mut self, id: StreamIdentifier) -> Option<&mut Stream> {
+        self.streams.get_mut(&id)
+    }
+
+    /// The push state, e.g. to apply the client's SETTINGS_ENABLE_PUSH.
+    pub fn pushes_mut(&mut self) -> &mut Pushes {
+        &mut self.pushes
+    }
+
+    pub fn stream_counts(&self) -> StreamCounts {
+        self.counts
+    }
+
+    /// Opens the next stream of ours, for a request; sending its HEADERS takes it out of the
+    /// idle state.
+    pub fn open_stream(&mut self) -> Result<StreamIdentifier, OpenError> {
+        if self.server {
+            return Err(OpenError::Server);
+        }
+        if self.shutdown != Shutdown::Running {
+            return Err(OpenError::GoingAway);
+        }
+        if self.counts.queued > 0 || !self.can_open() {
+            if !self.config.queue_streams {
+                return Err(OpenError::TooManyStreams);
+            }
+            self.counts.queued += 1;
+            return Err(OpenError::Queued);
+        }
+        self.open_next()
+    }
+
+    /// Opens the oldest queued stream, if the peer's SETTINGS_MAX_CONCURRENT_STREAMS allows it
+    /// now; to call until it returns `None` after streams closed or the peer raised it.
+    pub fn poll_open(&mut self) -> Option<Result<StreamIdentifier, OpenError>> {
+        if self.counts.queued == 0 || !self.can_open() {
+            return None;
+        }
+        if self.shutdown != Shutdown::Running {
+            self.counts.queued -= 1;
+            return Some(Err(OpenError::GoingAway));
+        }
+        self.counts.queued -= 1;
+        Some(self.open_next())
+    }
+
+    fn can_open(&self) -> bool {
+        self.peer_settings.max_concurrent_streams.map_or(true, |max| self.counts.local < max as usize)
+    }
+
+    fn open_next(&mut self) -> Result<StreamIdentifier, OpenError> {
+        let next = if self.last_local == 0 { 1 } else { self.last_local + 2 };
+        if next >= 1 << 31 {
+            return Err(OpenError::Exhausted);
+        }
+        self.last_local = next;
+        let id = StreamIdentifier(next);
+        let stream = self.new_stream(id);
+        self.insert(stream);
+        Ok(id)
+    }
+
+    /// Reserves a stream for a PUSH_PROMISE we send on `associated`; see `Pushes::promise`.
+    /// `None` too if the client's SETTINGS_MAX_CONCURRENT_STREAMS wouldn't let us answer on
+    /// it now.
+    pub fn push(&mut self, associated: StreamIdentifier) -> Option<StreamIdentifier> {
+        if !self.can_open() {
+            return None;
+        }
+        let mut stream = match self.streams.get(&associated) {
+            Some(stream) => match self.pushes.promise(stream) {
+                Some(stream) => stream,
+                None => return None,
+            },
+            None => return None,
+        };
+        self.init(&mut stream);
+        self.last_local = stream.id.0;
+        self.insert(stream);
+        Some(stream.id)
+    }
+
+    /// Reserves a push stream on `associated` for each resource `push` pushes with the response
+    /// to `route`, as far as the client's SETTINGS_ENABLE_PUSH and
+    /// SETTINGS_MAX_CONCURRENT_STREAMS let us. The promises' header blocks go out in
+    /// PUSH_PROMISE frames on `associated`, ahead of its response.
+    pub fn auto_push(&mut self, push: &AutoPush, associated: StreamIdentifier, route: &str, scheme: &str,
+                     authority: &str) -> Vec<(StreamIdentifier, Promise)> {
+        let available = self.peer_settings.max_concurrent_streams
+            .map_or(usize::max_value(), |max| (max as usize).saturating_sub(self.counts.local));
+        let promises = push.promises(route, scheme, authority, self.pushes.enable_push(), available);
+        let mut pushes = Vec::with_capacity(promises.len());
+        for promise in promises {
+            match self.push(associated) {
+                Some(id) => pushes.push((id, promise)),
+                None => break,
+            }
+        }
+        pushes
+    }
+
+    /// We send HEADERS on stream `id`, which must be one of ours, or a peer's stream we
+    /// answer on.
+    pub fn send_headers(&mut self, id: StreamIdentifier, end_stream: bool, now: Instant) -> Result<(), StreamError> {
+        // A server's HEADERS answer the request the stream's statistics started with.
+        let server = self.server;
+        self.record(id, now, |stats| if server { stats.response_started(now) });
+        self.update(id, |stream| stream.send_headers(end_stream))
+            .unwrap_or(Err(StreamError { id: id, code: STREAM_CLOSED }))
+    }
+
+    /// The octets of DATA stream `id` may send now, as far as both its window and the
+    /// connection's go. At 0 the stream is blocked until a `Recv::WindowUpdate` names it,
+    /// which its statistics count from `now`.
+    pub fn poll_capacity(&mut self, id: StreamIdentifier, now: Instant) -> u32 {
+        let capacity = match self.streams.get(&id) {
+            Some(stream) if stream.can_send() => self.capacity(stream),
+            _ => return 0,
+        };
+        if capacity == 0 && !self.blocked.contains(&id) {
+            self.blocked.push(id);
+        }
+        if capacity == 0 {
+            self.scheduler.set_ready(id, false);
+            if let Some(ref mut stall) = self.stall {
+                stall.blocked(id, now);
+            }
+        }
+        self.record(id, now, |stats| if capacity == 0 { stats.blocked(now) } else { stats.unblocked(now) });
+        capacity
+    }
+
+    /// We send `len` octets of DATA, padding included, on stream `id`. Fails with
+    /// FLOW_CONTROL_ERROR, leaving the stream as it was, if that is more than `poll_capacity`
+    /// allows: the DATA must not be sent then.
+    pub fn send_data(&mut self, id: StreamIdentifier, len: u32, end_stream: bool, now: Instant) -> Result<(), StreamError> {
+        let capacity = match self.streams.get(&id) {
+            Some(stream) if stream.can_send() => Some(self.capacity(stream)),
+            _ => None,
+        };
+        if len > capacity.unwrap_or(len) {
+            return Err(StreamError { id: id, code: FLOW_CONTROL_ERROR });
+        }
+        if capacity.is_some() {
+            self.record(id, now, |stats| stats.sent(len as usize, now));
+        }
+        let result = self.update(id, |stream| {
+            try!(stream.send_data(end_stream));
+            stream.send_window_mut().consume(len);
+            Ok(())
+        });
+        match result {
+            Some(Ok(())) => {
+                self.send_window.consume(len);
+                self.scheduler.sent(id, len as usize);
+                if end_stream {
+                    self.scheduler.set_ready(id, false);
+                }
+                if let Some(ref mut stall) = self.stall {
+                    stall.sent(id, len as u64);
+                    if end_stream {
+                        stall.drained(id);
+                    }
+                }
+                Ok(())
+            },
+            Some(Err(error)) => Err(error),
+            None => Err(StreamError { id: id, code: STREAM_CLOSED }),
+        }
+    }
+
+    /// The application handed `len` octets of DATA to send on stream `id` at `now`: their time
+    /// until `send_data` is the stream's queue latency.
+    pub fn queued(&mut self, id: StreamIdentifier, len: u32, now: Instant) {
+        self.record(id, now, |stats| stats.queued(len as usize, now));
+        if self.streams.contains_key(&id) {
+            self.scheduler.set_ready(id, true);
+            if let Some(ref mut stall) = self.stall {
+                stall.queued(id, now);
+            }
+        }
+    }
+
+    /// Returns RST_STREAM ENHANCE_YOUR_CALM for the streams `ConnectionConfig::stall` finds the
+    /// peer starves of window, or takes data from too slowly, and fails with ENHANCE_YOUR_CALM
+    /// once too many were: the connection should be closed with a GOAWAY of that code. Called
+    /// periodically, every `StallConfig::rate_period` or so, while streams have data queued.
+    pub fn poll_stalls(&mut self, now: Instant) -> Result<Vec<Frame<'static>>, ErrorCode> {
+        let stalls = match self.stall {
+            Some(ref mut stall) => stall.poll(now),
+            None => return Ok(Vec::new()),
+        };
+        let mut frames = Vec::new();
+        for stall in stalls {
+            match stall {
+                Stall::Stream(id) => frames.extend(self.reset(id, stall.error_code(), Reason::Stall)),
+                Stall::Connection => return Err(stall.error_code()),
+            }
+        }
+        Ok(frames)
+    }
+
+    /// The statistics of stream `id` at `now`, if it is open or one of the last
+    /// `ConnectionConfig::max_closed_stats` that closed.
+    pub fn stream_stats(&self, id: StreamIdentifier, now: Instant) -> Option<StreamStats> {
+        self.stats.get(&id)
+            .or_else(|| self.closed_stats.iter().find(|&&(closed, _)| closed == id).map(|&(_, ref stats)| stats))
+            .map(|stats| stats.stats(now))
+    }
+
+    fn record<F>(&mut self, id: StreamIdentifier, now: Instant, f: F) where F: FnOnce(&mut StatsRecorder) {
+        if self.streams.contains_key(&id) {
+            f(self.stats.entry(id).or_insert_with(|| StatsRecorder::new(now)));
+        }
+    }
+
+    /// The application is done with `len` octets of DATA received on stream `id` (see
+    /// `Recv::Data`). Returns the WINDOW_UPDATEs to write, if any are due.
+    pub fn consumed(&mut self, id: StreamIdentifier, len: u32) -> Vec<Frame<'static>> {
+        let end_stream = !self.streams.get(&id).map_or(false, |stream| stream.can_recv());
+        let frames = self.window_updates.consumed(id, len, end_stream);
+        self.credit(frames)
+    }
+
+    /// Replaces the `WindowUpdates` of the config, before DATA comes; the strategy starts
+    /// from the windows of `ConnectionConfig::window_updates`.
+    pub fn set_window_update_strategy(&mut self, strategy: Box<WindowUpdateStrategy + Send>) {
+        self.window_updates = strategy;
+    }
+
+    /// The strategy, to hand the DATA it wants: see `WindowUpdateStrategy::recv_data`. The
+    /// PINGs `recv` takes in go to its `recv_ping` already.
+    pub fn window_updates_mut(&mut self) -> &mut (WindowUpdateStrategy + Send) {
+        &mut *self.window_updates
+    }
+
+    /// Returns WINDOW_UPDATEs for all the consumed DATA not given back yet; see
+    /// `WindowUpdateStrategy::flush`.
+    pub fn flush_window_updates(&mut self) -> Vec<Frame<'static>> {
+        let frames = self.window_updates.flush();
+        self.credit(frames)
+    }
+
+    /// Our windows for the WINDOW_UPDATEs we send. Those it can't take whole are cut down to
+    /// what takes the window to `MAX_WINDOW_SIZE`, which a strategy that lost count would
+    /// otherwise make a FLOW_CONTROL_ERROR for the peer, and those with nothing left go.
+    fn credit(&mut self, frames: Vec<Frame<'static>>) -> Vec<Frame<'static>> {
+        let mut credited = Vec::with_capacity(frames.len());
+        for mut frame in frames {
+            if let Payload::WindowUpdate(SizeIncrement(increment)) = frame.payload {
+                let id = frame.header.id;
+                let window = if id.0 == 0 {
+                    Some(&mut self.recv_window)
+                } else {
+                    self.streams.get_mut(&id).map(|stream| stream.recv_window_mut())
+                };
+                let increment = match window {
+                    Some(window) => {
+                        let room = MAX_WINDOW_SIZE as i64 - window.size() as i64;
+                        let increment = cmp::min(increment as i64, room) as u32;
+                        window.increase(increment);
+                        increment
+                    },
+                    None => increment,
+                };
+                if increment == 0 {
+                    continue;
+                }
+                if let Some(stats) = self.stats.get_mut(&id) {
+                    stats.window_update_sent();
+                }
+                frame = Frame::window_update(id, SizeIncrement(increment));
+            }
+            credited.push(frame);
+        }
+        credited
+    }
+
+    /// Changes our receive windows to `window`, e.g. as a `BdpEstimator` chose: the
+    /// connection's at once, and the streams' with SETTINGS_INITIAL_WINDOW_SIZE once the peer
+    /// acknowledges it. Returns the WINDOW_UPDATE and SETTINGS frames to write.
+    pub fn set_recv_window(&mut self, window: u32, now: Instant) -> Vec<FrameBuf> {
+        let mut frames = Vec::new();
+        let connection_window = self.config.window_updates.connection_window;
+        if window > connection_window && self.recv_window.increase(window - connection_window) {
+            self.config.window_updates.connection_window = window;
+            self.window_updates.set_connection_window(window);
+            let increment = SizeIncrement(window - connection_window);
+            frames.push(FrameBuf::from(Frame::window_update(StreamIdentifier(0), increment)));
+        }
+        let settings = Settings { initial_window_size: Some(window), ..Settings::default() };
+        frames.extend(self.update_settings(&settings, now));
+        frames
+    }
+
+    /// The receive windows we give the peer: SETTINGS_INITIAL_WINDOW_SIZE, as acknowledged,
+    /// and the connection's.
+    pub fn recv_window_sizes(&self) -> (u32, u32) {
+        (self.local_settings().initial_window_size.unwrap_or(DEFAULT_WINDOW_SIZE),
+         self.config.window_updates.connection_window)
+    }
+
+    fn capacity(&self, stream: &Stream) -> u32 {
+        cmp::min(stream.send_window().available(), self.send_window.available())
+    }
+
+    pub fn send_window(&self) -> Window {
+        self.send_window
+    }
+
+    pub fn recv_window(&self) -> Window {
+        self.recv_window
+    }
+
+    /// The SETTINGS ACK to write for a `Recv::Settings`. The peer may not have more of them
+    /// waiting than `FloodConfig::max_pending_settings_acks`.
+    pub fn settings_ack(&mut self) -> Frame<'static> {
+        self.flood.settings_ack_sent();
+        Frame::settings(SettingsFlags::ack(), &[])
+    }
+
+    /// Changes the priority of our request on stream `id` while it is in flight and returns
+    /// the PRIORITY_UPDATE frame telling the server (RFC 9218), to write now. Urgency 0 is most
+    /// urgent, 7 least. Sent before the HEADERS of a stream `open_stream` returned, it sets the
+    /// stream's initial priority.
+    pub fn set_priority(&mut self, id: StreamIdentifier, urgency: u8, incremental: bool)
+                        -> Result<FrameBuf, PriorityError> {
+        if self.server {
+            return Err(PriorityError::Server);
+        }
+        if !self.streams.contains_key(&id) {
+            return Err(PriorityError::Closed);
+        }
+        let value = PriorityUpdate::new(id, urgency, incremental).priority.to_string();
+        Ok(FrameBuf::from(Frame::priority_update(id, value.as_bytes())))
+    }
+
+    /// Resets stream `id` and returns the RST_STREAM to send, or `None` if it is idle or
+    /// closed already.
+    pub fn send_reset(&mut self, id: StreamIdentifier, code: ErrorCode) -> Option<Frame<'static>> {
+        if code == NO_ERROR || code == CANCEL {
+            let frame = self.update(id, |stream| stream.send_reset(code)).and_then(|frame| frame);
+            if frame.is_some() {
+                self.remember_reset(id);
+            }
+            return frame;
+        }
+        self.reset(id, code, Reason::Protocol(code))
+    }
+
+    fn reset(&mut self, id: StreamIdentifier, code: ErrorCode, reason: Reason) -> Option<Frame<'static>> {
+        let frame = self.update(id, |stream| stream.send_reset(code)).and_then(|frame| frame);
+        if frame.is_some() {
+            self.remember_reset(id);
+            self.audit_reset(id, reason);
+        }
+        frame
+    }
+
+    /// Takes in every frame received, in order, at `now`.
+    ///
+    /// Errors are connection errors (see `Error`).
+    pub fn recv(&mut self, frame: &Frame, now: Instant) -> Result<Recv, Error> {
+        try!(self.flood.recv_frame(&frame.header, now).map_err(Error::Flood));
+        self.keepalive.recv(frame);
+        if let Some(ref mut idle) = self.idle {
+            idle.recv(frame);
+        }
+        if !self.preface_received {
+            match frame.payload {
+                Payload::Settings(_) if !frame.header.flag.contains(Flag::ack()) => {
+                    self.preface_received = true;
+                    if let Some(ref mut handshake) = self.handshake {
+                        // The `PrefaceReader` read the rest of the client's.
+                        handshake.preface_received();
+                        handshake.settings_received();
+                    }
+                },
+                _ => return Err(Error::InvalidPreface(InvalidPreface::NoSettings)),
+            }
+        }
+        if let Some(block) = try!(self.reassembler.recv(frame)) {
+            return self.recv_block(block, now);
+        }
+        if self.reassembler.in_block() {
+            return Ok(Recv::Pending);
+        }
+
+        if try!(self.extensions.recv(frame).map_err(Error::Extension)) {
+            return Ok(Recv::Connection);
+        }
+
+        let id = frame.header.id;
+        match frame.payload {
+            Payload::Data { .. } => {
+                // DATA takes from the connection's window even on the streams that are gone.
+                let len = frame.header.length;
+                if !self.recv_window.consume(len) {
+                    return Err(Error::WindowOverrun);
+                }
+                let end_stream = frame.header.flag.contains(Flag::end_stream());
+                self.record(id, now, |stats| stats.received(len as usize));
+                let result = self.update(id, |stream| {
+                    if stream.can_recv() && !stream.recv_window_mut().consume(len) {
+                        return Err(StreamError { id: id, code: FLOW_CONTROL_ERROR });
+                    }
+                    stream.recv_data(end_stream)
+                });
+                Ok(match result {
+                    Some(Ok(())) => Recv::Data { id: id, end_stream: end_stream },
+                    Some(Err(error)) => self.stream_error(error, None),
+                    None => return self.recv_closed(id, None),
+                })
+            },
+            Payload::Reset(code) => {
+                if !self.streams.contains_key(&id) {
+                    return match self.state(id) {
+                        State::Idle => Err(Error::InvalidReset),
+                        _ => Ok(Recv::Connection),
+                    };
+                }
+                try!(self.update(id, |stream| stream.recv_reset()).unwrap());
+                Ok(Recv::Reset(id, code))
+            },
+            Payload::Settings(settings) if frame.header.flag.contains(Flag::ack()) => {
+                let initial_window_size = self.local_settings().initial_window_size.unwrap_or(DEFAULT_WINDOW_SIZE);
+                Ok(match self.local_settings.recv_ack() {
+                    Some(settings) => {
+                        if let Some(ref mut handshake) = self.handshake {
+                            handshake.settings_acked();
+                        }
+                        if !self.server {
+                            if let Some(enable_push) = settings.enable_push {
+                                self.pushes.set_enable_push(enable_push);
+                            }
+                        }
+                        if let Some(new) = settings.initial_window_size {
+                            // The peer moved the windows of the open streams as much.
+                            let delta = new as i64 - initial_window_size as i64;
+                            for stream in self.streams.values_mut() {
+                                stream.recv_window_mut().adjust(delta);
+                            }
+                            self.window_updates.set_stream_window(new);
+                        }
+                        if let Some(max) = settings.max_header_list_size {
+                            let max = cmp::min(max as usize, self.config.max_header_block_size);
+                            self.reassembler.set_max_block_size(Some(max));
+                        }
+                        Recv::SettingsAcked(settings)
+                    },
+                    None => Recv::Connection,
+                })
+            },
+            Payload::Settings(settings) => {
+                let settings = try!(Settings::from_payload(settings).map_err(Error::InvalidSetting));
+                if let Some(new) = settings.initial_window_size {
+                    // Every send window moves by the change (RFC 7540 section 6.9.2), below
+                    // zero too; none may go over the maximum.
+                    let previous = self.peer_settings.initial_window_size.unwrap_or(DEFAULT_WINDOW_SIZE);
+                    let delta = new as i64 - previous as i64;
+                    let mut windows: Vec<_> = self.streams.values().map(|stream| stream.send_window()).collect();
+                    if !windows.iter_mut().all(|window| window.adjust(delta)) {
+                        return Err(Error::WindowOverflow);
+                    }
+                    for stream in self.streams.values_mut() {
+                        stream.send_window_mut().adjust(delta);
+                    }
+                }
+                self.peer_settings.merge(&settings);
+                self.registry.apply_remote(&settings);
+                if let Some(max_frame_size) = settings.max_frame_size {
+                    for stream in self.streams.values_mut() {
+                        stream.set_max_frame_size(max_frame_size);
+                    }
+                }
+                if self.server {
+                    if let Some(enable_push) = settings.enable_push {
+                        self.pushes.set_enable_push(enable_push);
+                    }
+                }
+                Ok(Recv::Settings(settings))
+            },
+            Payload::Ping(_) => {
+                match self.pinger.recv(frame, now) {
+                    Some(Pong::Reply(ack)) => return Ok(Recv::Ping(ack)),
+                    Some(Pong::Rtt(SHUTDOWN_PING_PAYLOAD, _)) => {
+                        if let Shutdown::Draining { ref mut acked, .. } = self.shutdown {
+                            *acked = true;
+                        }
+                    },
+                    Some(Pong::Rtt(KEEPALIVE_PING_PAYLOAD, _)) => self.keepalive.acked(),
+                    _ => {},
+                }
+                // The strategy may have sent PINGs of its own, as `BdpWindowUpdates` does.
+                self.window_updates.recv_ping(frame, now);
+                Ok(Recv::Connection)
+            },
+            Payload::WindowUpdate(SizeIncrement(0)) if id.0 == 0 => Err(Error::ZeroWindowUpdate),
+            Payload::WindowUpdate(SizeIncrement(increment)) if id.0 == 0 => {
+                if !self.send_window.increase(increment) {
+                    return Err(Error::WindowOverflow);
+                }
+                Ok(Recv::WindowUpdate(self.poll_unblocked()))
+            },
+            Payload::WindowUpdate(_) if self.state(id) == State::Idle => Err(Error::IdleStream),
+            Payload::WindowUpdate(SizeIncrement(increment)) => {
+                if increment == 0 && self.streams.contains_key(&id) {
+                    return Ok(self.stream_error(StreamError { id: id, code: PROTOCOL_ERROR }, None));
+                }
+                let increased = match self.streams.get_mut(&id) {
+                    Some(stream) => stream.send_window_mut().increase(increment),
+                    // It crossed our END_STREAM or RST_STREAM.
+                    None => return Ok(Recv::Connection),
+                };
+                if !increased {
+                    return Ok(self.stream_error(StreamError { id: id, code: FLOW_CONTROL_ERROR }, None));
+                }
+                self.record(id, now, |stats| stats.window_update_received());
+                Ok(Recv::WindowUpdate(self.poll_unblocked()))
+            },
+            Payload::Priority(ref priority) if self.config.mode.honours_priority() => {
+                if priority.dependency() != id {
+                    self.scheduler.recv_priority(id, priority);
+                } else if self.streams.contains_key(&id) {
+                    return Ok(self.stream_error(StreamError { id: id, code: PROTOCOL_ERROR }, None));
+                }
+                Ok(Recv::Connection)
+            },
+            Payload::PriorityUpdate { .. } if !self.server => Err(Error::InvalidPriorityUpdate),
+            Payload::PriorityUpdate { prioritized, .. } => {
+                // Those of idle streams are for the scheduler to keep, within bounds, until the
+                // streams open; a closed stream has no use for one.
+                if self.state(prioritized) != State::Closed {
+                    if let Some(update) = PriorityUpdate::from_frame(frame) {
+                        self.scheduler.recv_priority_update(&update);
+                    }
+                }
+                Ok(Recv::Connection)
+            },
+            _ => Ok(Recv::Connection),
+        }
+    }
+
+    fn recv_block(&mut self, mut block: HeaderBlock, now: Instant) -> Result<Recv, Error> {
+        let id = block.id;
+        if !self.config.mode.honours_priority() {
+            block.priority = None;
+        }
+        if let Some(promised) = block.promised {
+            let reserved = match self.streams.get(&id) {
+                Some(associated) => try!(self.pushes.recv_promise(associated, promised)),
+                None if self.reset.contains(&id) => {
+                    // We don't want the push of a request we gave up on either; it is checked
+                    // as if the request were still open.
+                    let mut associated = Stream::new(id);
+                    let _ = associated.send_headers(false);
+                    try!(self.pushes.recv_promise(&associated, promised));
+                    self.last_remote = promised.0;
+                    self.remember_reset(promised);
+                    return Ok(Recv::StreamError(StreamError { id: promised, code: CANCEL }, Some(block)));
+                },
+                None => return Err(Error::InvalidPushPromise),
+            };
+            let mut reserved = reserved;
+            self.init(&mut reserved);
+            self.last_remote = promised.0;
+            self.insert(reserved);
+            return Ok(Recv::Headers(block));
+        }
+
+        // The HEADERS of a stream the peer opens, or of a push, make it count.
+        let opens = match self.streams.get(&id) {
+            Some(stream) => stream.state() == State::ReservedRemote,
+            None => {
+                if !self.server || self.is_local(id) || id.0 <= self.last_remote {
+                    return self.recv_closed(id, Some(block));
+                }
+                self.last_remote = id.0;
+                if let Shutdown::Closing(last) = self.shutdown {
+                    if id.0 > last.0 {
+                        // Opened after our final GOAWAY, which told the peer it wouldn't be.
+                        self.remember_reset(id);
+                        return Ok(Recv::Ignored(Some(block)));
+                    }
+                }
+                true
+            },
+        };
+        let max = self.local_settings().max_concurrent_streams;
+        let full = max.map_or(false, |max| self.counts.remote >= max as usize);
+        if opens && (full || self.shedder.as_ref().map_or(false, |shedder| shedder.refuse())) {
+            return Ok(self.stream_error(StreamError { id: id, code: REFUSED_STREAM }, Some(block)));
+        }
+        if !self.streams.contains_key(&id) {
+            let stream = self.new_stream(id);
+            self.insert(stream);
+        }
+        // A client's response HEADERS answer the request its statistics started with.
+        let server = self.server;
+        self.record(id, now, |stats| if !server { stats.response_started(now) });
+        if let Some(priority) = block.priority {
+            if priority.dependency() == id {
+                // A stream can't depend on itself (RFC 7540 section 5.3.1).
+                return Ok(self.stream_error(StreamError { id: id, code: PROTOCOL_ERROR }, Some(block)));
+            }
+            self.scheduler.recv_priority(id, &priority);
+        }
+        Ok(match self.update(id, |stream| stream.recv_headers(block.end_stream)).unwrap() {
+            Ok(()) => Recv::Headers(block),
+            Err(error) => self.stream_error(error, Some(block)),
+        })
+    }
+
+    /// A HEADERS or DATA frame on a stream it doesn't keep.
+    fn recv_closed(&mut self, id: StreamIdentifier, block: Option<HeaderBlock>) -> Result<Recv, Error> {
+        match self.state(id) {
+            State::Idle => Err(Error::IdleStream),
+            _ if self.reset.contains(&id) => Ok(Recv::Ignored(block)),
+            _ => Err(Error::StreamClosed),
+        }
+    }
+
+    fn stream_error(&mut self, error: StreamError, block: Option<HeaderBlock>) -> Recv {
+        self.update(error.id, |stream| stream.reset());
+        self.remember_reset(error.id);
+        self.audit_reset(error.id, Reason::Protocol(error.code));
+        Recv::StreamError(error, block)
+    }
+
+    fn audit_reset(&self, id: StreamIdentifier, reason: Reason) {
+        if let Some((ref audit, peer)) = self.audit {
+            audit.stream_reset(peer, id, reason);
+        }
+    }
+
+    fn remember_reset(&mut self, id: StreamIdentifier) {
+        if self.config.max_reset_streams == 0 {
+            return;
+        }
+        if self.reset.len() == self.config.max_reset_streams {
+            self.reset.pop_front();
+        }
+        self.reset.push_back(id);
+    }
+
+    fn new_stream(&self, id: StreamIdentifier) -> Stream {
+        let mut stream = Stream::new(id);
+        self.init(&mut stream);
+        stream
+    }
+
+    /// Sets what the settings of both sides say for a new stream.
+    fn init(&self, stream: &mut Stream) {
+        stream.set_max_frame_size(self.peer_max_frame_size());
+        stream.set_initial_windows(self.peer_settings.initial_window_size.unwrap_or(DEFAULT_WINDOW_SIZE),
+                                   self.local_settings().initial_window_size.unwrap_or(DEFAULT_WINDOW_SIZE));
+    }
+
+    /// The blocked streams that have window again, which it forgets and the scheduler gets as
+    /// ready. `Recv::WindowUpdate` has them; a `Recv::Settings` raising
+    /// SETTINGS_INITIAL_WINDOW_SIZE may unblock streams too.
+    pub fn poll_unblocked(&mut self) -> Vec<StreamIdentifier> {
+        if self.send_window.available() == 0 {
+            return Vec::new();
+        }
+        let mut unblocked = Vec::new();
+        let streams = &self.streams;
+        self.blocked.retain(|id| match streams.get(id) {
+            Some(stream) if stream.send_window().available() == 0 => true,
+            Some(_) => {
+                unblocked.push(*id);
+                false
+            },
+            None => false,
+        });
+        // Blocked with DATA queued, they have DATA to send again.
+        for &id in &unblocked {
+            self.scheduler.set_ready(id, true);
+        }
+        unblocked
+    }
+
+    fn insert(&mut self, stream: Stream) {
+        if let Some(ref mut idle) = self.idle {
+            idle.stream_opened();
+        }
+        if counts(stream.state()) {
+            *self.count(stream.id) += 1;
+        }
+        self.streams.insert(stream.id, stream);
+    }
+
+    /// Runs `f` on stream `id`, if it keeps it, and then counts it or forgets it (once
+    /// closed) as its new state says.
+    fn update<T, F>(&mut self, id: StreamIdentifier, f: F) -> Option<T> where F: FnOnce(&mut Stream) -> T {
+        let (before, after, result) = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                let before = stream.state();
+                let result = f(stream);
+                (before, stream.state(), result)
+            },
+            None => return None,
+        };
+        match (counts(before), counts(after)) {
+            (false, true) => *self.count(id) += 1,
+            (true, false) => *self.count(id) -= 1,
+            _ => {},
+        }
+        if after == State::Closed {
+            self.streams.remove(&id);
+            if let Some(stats) = self.stats.remove(&id) {
+                if self.closed_stats.len() == self.config.max_closed_stats {
+                    self.closed_stats.pop_front();
+                }
+                if self.config.max_closed_stats > 0 {
+                    self.closed_stats.push_back((id, stats));
+                }
+            }
+            self.window_updates.close_stream(id);
+            self.scheduler.close(id);
+            if let Some(ref mut stall) = self.stall {
+                stall.drained(id);
+            }
+            self.blocked.retain(|&blocked| blocked != id);
+            self.check_drained();
+        }
+        Some(result)
+    }
+
+    fn count(&mut self, id: StreamIdentifier) -> &mut usize {
+        if self.is_local(id) { &mut self.counts.local } else { &mut self.counts.remote }
+    }
+
+    /// Whether we open the streams with the parity of `id`: clients the odd ones.
+    fn is_local(&self, id: StreamIdentifier) -> bool {
+        (id.0 % 2 == 0) == self.server
+    }
+
+    fn last(&self, id: StreamIdentifier) -> u32 {
+        if self.is_local(id) { self.last_local } else { self.last_remote }
+    }
+}
+
+fn goaway(last: StreamIdentifier) -> Frame<'static> {
+    Frame::goaway(last, NO_ERROR, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use futures::Future;
+
+    use http2::flag::SettingsFlags;
+    use http2::payload::{Setting, SettingIdentifier};
+    use http2::preface::InvalidPreface;
+    use http2::mode::Mode;
+    use http2::payload::Priority;
+    use http2::priority::PriorityUpdate;
+    use http2::settings::Settings;
+    use http2::stall::StallConfig;
+    use http2::bdp::{BdpConfig, BdpWindowUpdates, BDP_PING_PAYLOAD};
+    use http2::flood::{Flood, FloodConfig};
+    use http2::flow::MAX_WINDOW_SIZE;
+    use http2::handshake::Phase;
+    use http2::keepalive::{KeepaliveTimeout, KEEPALIVE_PING_PAYLOAD};
+    use http2::push::{AutoPush, PushManifest};
+    use http2::stream::{State, StreamError};
+    use http2::window_update::{window_update, WindowUpdateConfig, WindowUpdateStrategy};
+    use http2::{Error, SizeIncrement, StreamIdentifier, CANCEL, ENHANCE_YOUR_CALM, FLOW_CONTROL_ERROR, NO_ERROR,
+                PROTOCOL_ERROR, REFUSED_STREAM, SETTINGS_TIMEOUT, STREAM_CLOSED};
+
+    use audit::{AuditEvent, AuditHook, Reason};
+    use http::shed::{LoadShedder, ShedConfig};
+
+    use super::{Connection, ConnectionConfig, OpenError, PriorityError, Recv, StreamCounts, SHUTDOWN_PING_PAYLOAD};
+
+    /// A connection that got the peer's preface.
+    fn connection(server: bool) -> Connection {
+        let mut connection = Connection::new(server, ConnectionConfig::default());
+        let settings = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Settings(&[]));
+        assert_eq!(connection.recv(&settings, Instant::now()), Ok(Recv::Settings(Settings::default())));
+        connection
+    }
+
+    fn headers(id: u32, flag: Flag) -> Frame<'static> {
+        Frame::new(flag | Flag::end_headers(), StreamIdentifier(id), Payload::Headers { priority: None, block: &[0x82] })
+    }
+
+    fn data(id: u32, flag: Flag) -> Frame<'static> {
+        Frame::new(flag, StreamIdentifier(id), Payload::Data { data: b"hi" })
+    }
+
+    #[test]
+    fn test_server_streams() {
+        let mut server = Connection::new(true, ConnectionConfig::default());
+        assert_eq!(server.recv(&headers(1, Flag::empty()), Instant::now()), Err(Error::InvalidPreface(InvalidPreface::NoSettings)));
+        let mut server = connection(true);
+        let one = StreamIdentifier(1);
+        assert!(match server.recv(&headers(1, Flag::empty()), Instant::now()) { Ok(Recv::Headers(ref block)) => block.id == one, _ => false });
+        assert_eq!(server.state(one), State::Open);
+        assert_eq!(server.recv(&data(1, Flag::end_stream()), Instant::now()), Ok(Recv::Data { id: one, end_stream: true }));
+        assert_eq!(server.state(one), State::HalfClosedRemote);
+
+        // More DATA on the half closed stream resets it; what follows is ignored.
+        assert!(match server.recv(&data(1, Flag::empty()), Instant::now()) {
+            Ok(Recv::StreamError(error, None)) => error == StreamError { id: one, code: STREAM_CLOSED },
+            _ => false,
+        });
+        assert_eq!(server.state(one), State::Closed);
+        assert_eq!(server.recv(&data(1, Flag::empty()), Instant::now()), Ok(Recv::Ignored(None)));
+        assert_eq!(server.send_data(one, 2, false, Instant::now()), Err(StreamError { id: one, code: STREAM_CLOSED }));
+
+        // Opening stream 5 closes the idle stream 3.
+        server.recv(&headers(5, Flag::end_stream()), Instant::now()).unwrap();
+        assert_eq!(server.state(StreamIdentifier(3)), State::Closed);
+        assert_eq!(server.send_headers(StreamIdentifier(5), true, Instant::now()), Ok(()));
+        assert_eq!(server.state(StreamIdentifier(5)), State::Closed);
+        assert_eq!(server.recv(&data(5, Flag::empty()), Instant::now()), Err(Error::StreamClosed));
+        assert_eq!(server.recv(&headers(3, Flag::empty()), Instant::now()), Err(Error::StreamClosed));
+
+        // Idle streams, and streams the client may not open.
+        assert_eq!(server.recv(&data(7, Flag::empty()), Instant::now()), Err(Error::IdleStream));
+        assert_eq!(server.recv(&headers(2, Flag::empty()), Instant::now()), Err(Error::IdleStream));
+        let reset = Frame::new(Flag::empty(), StreamIdentifier(9), Payload::Reset(CANCEL));
+        assert_eq!(server.recv(&reset, Instant::now()), Err(Error::InvalidReset));
+        assert_eq!(server.open_stream(), Err(OpenError::Server));
+    }
+
+    #[test]
+    fn test_client_streams() {
+        let mut client = connection(false);
+        let one = client.open_stream().unwrap();
+        assert_eq!((one, client.state(one)), (StreamIdentifier(1), State::Idle));
+        assert_eq!(client.open_stream(), Ok(StreamIdentifier(3)));
+        client.send_headers(one, false, Instant::now()).unwrap();
+
+        // A push, then a reset of the request.
+        let promise = Frame::new(Flag::end_headers(), one, Payload::PushPromise { promised: StreamIdentifier(2), block: &[0x82] });
+        assert!(match client.recv(&promise, Instant::now()) { Ok(Recv::Headers(ref block)) => block.promised == Some(StreamIdentifier(2)), _ => false });
+        assert_eq!(client.state(StreamIdentifier(2)), State::ReservedRemote);
+        let reset = Frame::new(Flag::empty(), one, Payload::Reset(CANCEL));
+        assert_eq!(client.recv(&reset, Instant::now()), Ok(Recv::Reset(one, CANCEL)));
+        assert_eq!(client.state(one), State::Closed);
+        assert_eq!(client.recv(&reset, Instant::now()), Ok(Recv::Connection));
+
+        // Pushes of a request we reset are cancelled.
+        let three = StreamIdentifier(3);
+        client.send_headers(three, true, Instant::now()).unwrap();
+        assert!(client.send_reset(three, CANCEL).is_some());
+        let promise = Frame::new(Flag::end_headers(), three, Payload::PushPromise { promised: StreamIdentifier(4), block: &[0x82] });
+        assert!(match client.recv(&promise, Instant::now()) {
+            Ok(Recv::StreamError(error, Some(_))) => error == StreamError { id: StreamIdentifier(4), code: CANCEL },
+            _ => false,
+        });
+        assert_eq!(client.recv(&data(4, Flag::empty()), Instant::now()), Ok(Recv::Ignored(None)));
+
+        // A header block is pending until it ends, and nothing may come in its middle.
+        let open = Frame::new(Flag::empty(), StreamIdentifier(2), Payload::Headers { priority: None, block: &[0x88] });
+        assert_eq!(client.recv(&open, Instant::now()), Ok(Recv::Pending));
+        assert_eq!(client.recv(&data(2, Flag::empty()), Instant::now()), Err(Error::InvalidContinuation));
+    }
+
+    #[test]
+    fn test_settings() {
+        let now = Instant::now();
+        let mut client = Connection::new(false, ConnectionConfig::default());
+        client.preface(&Settings { enable_push: Some(false), ..Settings::default() }, now);
+        assert!(client.pushes_mut().enable_push());
+        let ack = Frame::settings(SettingsFlags::ack(), &[]);
+        let server_settings = [Setting::new(SettingIdentifier::MaxFrameSize, 20000)];
+        let expected = Settings { max_frame_size: Some(20000), ..Settings::default() };
+        assert_eq!(client.recv(&Frame::settings(SettingsFlags::empty(), &server_settings), now), Ok(Recv::Settings(expected.clone())));
+        assert_eq!(client.peer_max_frame_size(), 20000);
+        assert_eq!(client.peer_settings(), &expected);
+
+        // Our settings apply once acknowledged, and the peer has 10 seconds for that.
+        assert!(client.check(now + Duration::from_secs(10)).is_err());
+        assert!(match client.recv(&ack, now) { Ok(Recv::SettingsAcked(ref settings)) => settings.enable_push == Some(false), _ => false });
+        assert!(!client.pushes_mut().enable_push());
+        assert_eq!(client.local_settings().enable_push, Some(false));
+        assert_eq!(client.recv(&ack, now), Ok(Recv::Connection));
+
+        let update = Settings { initial_window_size: Some(1 << 20), ..Settings::default() };
+        let frame = client.update_settings(&update, now).unwrap();
+        assert_eq!(frame.header.length, 6);
+        assert_eq!(client.update_settings(&update, now), None);
+        assert_eq!(client.deadline(), Some(now + Duration::from_secs(10)));
+        assert_eq!(client.check(now + Duration::from_secs(10)), Err(SETTINGS_TIMEOUT));
+
+        let too_large = [Setting::new(SettingIdentifier::InitialWindowSize, 1 << 31)];
+        let error = client.recv(&Frame::settings(SettingsFlags::empty(), &too_large), now).unwrap_err();
+        assert_eq!(error.error_code(), FLOW_CONTROL_ERROR);
+    }
+
+    #[test]
+    fn test_max_concurrent_streams() {
+        let now = Instant::now();
+        let one = [Setting::new(SettingIdentifier::MaxConcurrentStreams, 1)];
+        let mut server = Connection::new(true, ConnectionConfig::default());
+        server.preface(&Settings { max_concurrent_streams: Some(1), ..Settings::default() }, now);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        server.recv(&Frame::settings(SettingsFlags::ack(), &[]), now).unwrap();
+        server.recv(&headers(1, Flag::empty()), now).unwrap();
+        assert!(match server.recv(&headers(3, Flag::empty()), now) {
+            Ok(Recv::StreamError(error, Some(_))) => error == StreamError { id: StreamIdentifier(3), code: REFUSED_STREAM },
+            _ => false,
+        });
+        assert_eq!(server.stream_counts(), StreamCounts { local: 0, remote: 1, queued: 0 });
+        server.send_headers(StreamIdentifier(1), true, Instant::now()).unwrap();
+        server.recv(&data(1, Flag::end_stream()), now).unwrap();
+        assert_eq!(server.stream_counts().remote, 0);
+        assert!(match server.recv(&headers(5, Flag::empty()), now) { Ok(Recv::Headers(_)) => true, _ => false });
+
+        let mut client = connection(false);
+        client.recv(&Frame::settings(SettingsFlags::empty(), &one), now).unwrap();
+        let first = client.open_stream().unwrap();
+        assert_eq!(client.open_stream(), Err(OpenError::TooManyStreams));
+
+        let mut client = Connection::new(false, ConnectionConfig { queue_streams: true, ..ConnectionConfig::default() });
+        client.recv(&Frame::settings(SettingsFlags::empty(), &one), now).unwrap();
+        assert_eq!(client.open_stream(), Ok(first));
+        assert_eq!(client.open_stream(), Err(OpenError::Queued));
+        assert_eq!(client.poll_open(), None);
+        assert_eq!(client.stream_counts(), StreamCounts { local: 1, remote: 0, queued: 1 });
+        client.send_headers(first, false, Instant::now()).unwrap();
+        client.send_reset(first, CANCEL).unwrap();
+        assert_eq!(client.poll_open(), Some(Ok(StreamIdentifier(3))));
+        assert_eq!(client.stream_counts(), StreamCounts { local: 1, remote: 0, queued: 0 });
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let window_update = |id, increment| Frame::new(Flag::empty(), StreamIdentifier(id), Payload::WindowUpdate(SizeIncrement(increment)));
+        let mut client = connection(false);
+        let one = client.open_stream().unwrap();
+        client.send_headers(one, false, Instant::now()).unwrap();
+        assert_eq!(client.poll_capacity(one, Instant::now()), 65535);
+        client.send_data(one, 65535, false, Instant::now()).unwrap();
+        assert_eq!(client.poll_capacity(one, Instant::now()), 0);
+        assert_eq!(client.send_data(one, 1, false, Instant::now()), Err(StreamError { id: one, code: FLOW_CONTROL_ERROR }));
+
+        // Blocked until both windows have room.
+        assert_eq!(client.recv(&window_update(1, 100), Instant::now()), Ok(Recv::WindowUpdate(vec![])));
+        assert_eq!(client.recv(&window_update(0, 50), Instant::now()), Ok(Recv::WindowUpdate(vec![one])));
+        assert_eq!(client.poll_capacity(one, Instant::now()), 50);
+        assert_eq!(client.recv(&window_update(0, MAX_WINDOW_SIZE), Instant::now()), Err(Error::WindowOverflow));
+        assert_eq!(client.recv(&window_update(1, MAX_WINDOW_SIZE), Instant::now()),
+                   Ok(Recv::StreamError(StreamError { id: one, code: FLOW_CONTROL_ERROR }, None)));
+
+        // An increment of 0 resets the stream, and fails the connection on stream 0.
+        let three = client.open_stream().unwrap();
+        client.send_headers(three, false, Instant::now()).unwrap();
+        assert_eq!(client.recv(&window_update(3, 0), Instant::now()),
+                   Ok(Recv::StreamError(StreamError { id: three, code: PROTOCOL_ERROR }, None)));
+        assert_eq!(client.recv(&window_update(0, 0), Instant::now()), Err(Error::ZeroWindowUpdate));
+        assert_eq!(Error::ZeroWindowUpdate.error_code(), PROTOCOL_ERROR);
+
+        let config = ConnectionConfig {
+            window_updates: WindowUpdateConfig { connection_window: 100000, ..WindowUpdateConfig::default() },
+            ..ConnectionConfig::default()
+        };
+        let mut server = Connection::new(true, config);
+        let preface = server.preface(&Settings::default(), Instant::now());
+        assert_eq!(&preface[preface.len() - 13..], &[0, 0, 4, 8, 0, 0, 0, 0, 0, 0, 0, 0x86, 0xa1]);
+        assert_eq!(server.recv_window().size(), 100000);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), Instant::now()).unwrap();
+        server.recv(&headers(1, Flag::empty()), Instant::now()).unwrap();
+        let big = vec![0; 60000];
+        let data = |len| Frame::new(Flag::empty(), one, Payload::Data { data: &big[..len] });
+        assert_eq!(server.recv(&data(60000), Instant::now()), Ok(Recv::Data { id: one, end_stream: false }));
+        assert_eq!(server.recv(&data(6000), Instant::now()), Ok(Recv::StreamError(StreamError { id: one, code: FLOW_CONTROL_ERROR }, None)));
+
+        // What went to a stream that is gone is given back on the connection only.
+        assert_eq!(server.consumed(one, 66000), vec![window_update(0, 66000)]);
+        assert_eq!(server.recv_window().size(), 100000);
+        assert_eq!(server.recv(&data(60000), Instant::now()), Ok(Recv::Ignored(None)));
+        assert_eq!(server.recv(&data(40001), Instant::now()), Err(Error::WindowOverrun));
+    }
+
+    #[test]
+    fn test_set_recv_window() {
+        let mut client = connection(false);
+        let one = client.open_stream().unwrap();
+        client.send_headers(one, false, Instant::now()).unwrap();
+        let frames = client.set_recv_window(1 << 20, Instant::now());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].payload[..], [0, 0x0f, 0, 1]);
+        assert_eq!(client.recv_window().size(), 1 << 20);
+        assert_eq!(client.stream(one).unwrap().recv_window().size(), 65535);
+
+        // The open stream grows with the setting.
+        client.recv(&Frame::settings(SettingsFlags::ack(), &[]), Instant::now()).unwrap();
+        assert_eq!(client.stream(one).unwrap().recv_window().size(), 1 << 20);
+        assert_eq!(client.recv_window_sizes(), (1 << 20, 1 << 20));
+        assert_eq!(client.set_recv_window(1 << 20, Instant::now()).len(), 0);
+    }
+
+    #[test]
+    fn test_window_update_strategy() {
+        let start = Instant::now();
+        let mut server = connection(true);
+        let config = WindowUpdateConfig::default();
+        server.set_window_update_strategy(Box::new(BdpWindowUpdates::new(config, BdpConfig::default())));
+        server.recv(&headers(1, Flag::empty()), Instant::now()).unwrap();
+        let big = vec![0; 60000];
+        let one = StreamIdentifier(1);
+        server.recv(&Frame::new(Flag::empty(), one, Payload::Data { data: &big }), Instant::now()).unwrap();
+        assert!(server.window_updates_mut().recv_data(60000, start).is_some());
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(BDP_PING_PAYLOAD));
+        server.window_updates_mut().recv_ping(&ack, start + Duration::from_millis(100));
+
+        // Both windows go from 5535 to the 120000 of the estimate.
+        assert_eq!(server.consumed(one, 60000).len(), 2);
+        assert_eq!(server.stream(one).unwrap().recv_window().size(), 120000);
+        assert_eq!(server.recv_window().size(), 120000);
+    }
+
+    /// Gives back `increment` on both windows, whatever was consumed.
+    #[derive(Debug)]
+    struct Miscounted {
+        increment: u32,
+    }
+
+    impl WindowUpdateStrategy for Miscounted {
+        fn consumed(&mut self, id: StreamIdentifier, _len: u32, _end_stream: bool) -> Vec<Frame<'static>> {
+            vec![window_update(id, self.increment), window_update(StreamIdentifier(0), self.increment)]
+        }
+
+        fn close_stream(&mut self, _id: StreamIdentifier) {}
+
+        fn flush(&mut self) -> Vec<Frame<'static>> {
+            vec![window_update(StreamIdentifier(0), 0)]
+        }
+
+        fn set_stream_window(&mut self, _stream_window: u32) {}
+
+        fn set_connection_window(&mut self, _connection_window: u32) {}
+    }
+
+    #[test]
+    fn test_window_update_credit() {
+        let mut server = connection(true);
+        server.set_window_update_strategy(Box::new(Miscounted { increment: MAX_WINDOW_SIZE }));
+        server.recv(&headers(1, Flag::empty()), Instant::now()).unwrap();
+        let one = StreamIdentifier(1);
+        server.recv(&data(1, Flag::empty()), Instant::now()).unwrap();
+
+        // Only as much as takes the windows to the largest they may be, whatever was consumed.
+        let room = MAX_WINDOW_SIZE - server.recv_window().available();
+        let frames = server.consumed(one, 1);
+        assert_eq!(frames, vec![window_update(one, room), window_update(StreamIdentifier(0), room)]);
+        assert_eq!(server.stream(one).unwrap().recv_window().available(), MAX_WINDOW_SIZE);
+        assert_eq!(server.recv_window().available(), MAX_WINDOW_SIZE);
+        assert_eq!(server.consumed(one, 1), Vec::new());
+        assert_eq!(server.flush_window_updates(), Vec::new());
+    }
+
+    #[test]
+    fn test_initial_window_size() {
+        let initial = |size| [Setting::new(SettingIdentifier::InitialWindowSize, size)];
+        let window_update = |increment| Frame::new(Flag::empty(), StreamIdentifier(1), Payload::WindowUpdate(SizeIncrement(increment)));
+        let mut client = connection(false);
+        let one = client.open_stream().unwrap();
+        client.send_headers(one, false, Instant::now()).unwrap();
+        client.send_data(one, 60000, false, Instant::now()).unwrap();
+
+        // 5535 - 64535 leaves the window below zero, and the stream blocked past a WINDOW_UPDATE.
+        client.recv(&Frame::settings(SettingsFlags::empty(), &initial(1000)), Instant::now()).unwrap();
+        assert_eq!(client.stream(one).unwrap().send_window().size(), -59000);
+        assert_eq!(client.poll_capacity(one, Instant::now()), 0);
+        assert_eq!(client.recv(&window_update(59000), Instant::now()), Ok(Recv::WindowUpdate(vec![])));
+        client.recv(&Frame::settings(SettingsFlags::empty(), &initial(70000)), Instant::now()).unwrap();
+        assert_eq!(client.stream(one).unwrap().send_window().size(), 69000);
+        assert_eq!(client.poll_unblocked(), vec![one]);
+        assert_eq!(client.poll_capacity(one, Instant::now()), 5535);
+
+        // New streams start from the new value.
+        let three = client.open_stream().unwrap();
+        assert_eq!(client.stream(three).unwrap().send_window().size(), 70000);
+
+        // Stream 1 is at 79000, above the initial 70000, so it would go over; nothing moves.
+        client.recv(&window_update(10000), Instant::now()).unwrap();
+        assert_eq!(client.recv(&Frame::settings(SettingsFlags::empty(), &initial(MAX_WINDOW_SIZE)), Instant::now()), Err(Error::WindowOverflow));
+        assert_eq!(client.stream(three).unwrap().send_window().size(), 70000);
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let start = Instant::now();
+        let goaway = |last| Frame::new(Flag::empty(), StreamIdentifier(0), Payload::GoAway { last: StreamIdentifier(last), error: NO_ERROR, data: &[] });
+        let mut server = connection(true);
+        server.recv(&headers(1, Flag::end_stream()), Instant::now()).unwrap();
+        let (frames, drained) = server.graceful_shutdown(start);
+        assert_eq!(frames, vec![goaway((1 << 31) - 1), Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(SHUTDOWN_PING_PAYLOAD))]);
+        assert_eq!(server.deadline(), Some(start + Duration::from_secs(1)));
+
+        // Streams on their way are still accepted until the PING comes back.
+        server.recv(&headers(3, Flag::end_stream()), Instant::now()).unwrap();
+        assert_eq!(server.poll_shutdown(start), None);
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(SHUTDOWN_PING_PAYLOAD));
+        assert_eq!(server.recv(&ack, start + Duration::from_millis(30)), Ok(Recv::Connection));
+        assert_eq!(server.rtt().latest, Some(Duration::from_millis(30)));
+        assert_eq!(server.poll_shutdown(start), Some(goaway(3)));
+        assert!(match server.recv(&headers(5, Flag::end_stream()), Instant::now()) { Ok(Recv::Ignored(Some(_))) => true, _ => false });
+        assert_eq!(server.stream_counts().remote, 2);
+
+        // Drained once the accepted streams complete.
+        server.send_headers(StreamIdentifier(1), true, Instant::now()).unwrap();
+        assert!(!server.is_drained());
+        server.send_headers(StreamIdentifier(3), true, Instant::now()).unwrap();
+        assert!(server.is_drained());
+        assert_eq!(drained.wait(), Ok(()));
+
+        // Without an ACK, the final GOAWAY goes when the grace period is over.
+        let mut client = connection(false);
+        client.graceful_shutdown(start);
+        assert_eq!(client.open_stream(), Err(OpenError::GoingAway));
+        assert_eq!(client.poll_shutdown(start + Duration::from_millis(999)), None);
+        assert_eq!(client.poll_shutdown(start + Duration::from_secs(1)), Some(goaway(0)));
+        assert!(client.is_drained());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let config = ConnectionConfig { keepalive_interval: Some(Duration::from_secs(10)), ..ConnectionConfig::default() };
+        let mut client = Connection::new(false, config);
+        assert_eq!(client.poll_keepalive(start), Ok(None));
+        assert_eq!(client.deadline(), Some(secs(10)));
+
+        // Reading puts the PING off, until it is polled for.
+        client.recv(&Frame::settings(SettingsFlags::empty(), &[]), Instant::now()).unwrap();
+        assert_eq!(client.poll_keepalive(secs(5)), Ok(None));
+        assert_eq!(client.poll_keepalive(secs(10)), Ok(None));
+        let ping = client.poll_keepalive(secs(15)).unwrap().unwrap();
+        assert_eq!(ping.payload, Payload::Ping(KEEPALIVE_PING_PAYLOAD));
+        assert_eq!(client.recv(&Frame::new(Flag::ack(), StreamIdentifier(0), ping.payload), secs(15)), Ok(Recv::Connection));
+        assert_eq!(client.poll_keepalive(secs(16)), Ok(None));
+
+        // Going without the ACK for keepalive_timeout.
+
+        assert!(client.poll_keepalive(secs(26)).unwrap().is_some());
+        assert_eq!(client.deadline(), Some(secs(46)));
+        assert_eq!(client.poll_keepalive(secs(45)), Ok(None));
+        assert_eq!(client.poll_keepalive(secs(46)), Err(KeepaliveTimeout { timeout: Duration::from_secs(20) }));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let config = ConnectionConfig { idle_timeout: Some(Duration::from_secs(60)), ..ConnectionConfig::default() };
+        let mut server = Connection::new(true, config);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), Instant::now()).unwrap();
+        assert_eq!(server.poll_idle(start), None);
+        assert_eq!(server.deadline(), Some(secs(60)));
+
+        server.recv(&headers(1, Flag::end_stream()), Instant::now()).unwrap();
+        assert_eq!(server.poll_idle(secs(60)), None);
+        assert_eq!(server.deadline(), None);
+        server.send_headers(StreamIdentifier(1), true, Instant::now()).unwrap();
+        assert_eq!(server.poll_idle(secs(70)), None);
+        let goaway = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::GoAway { last: StreamIdentifier(1), error: NO_ERROR, data: &[] });
+        assert_eq!(server.poll_idle(secs(130)), Some(goaway));
+        assert!(server.is_drained());
+        assert_eq!(server.poll_idle(secs(200)), None);
+    }
+
+    #[test]
+    fn test_max_header_block_size() {
+        let now = Instant::now();
+        let continuation = |flag| Frame::new(flag, StreamIdentifier(1), Payload::Continuation(&[0; 100]));
+        let config = ConnectionConfig { max_header_block_size: 250, ..ConnectionConfig::default() };
+        let mut server = Connection::new(true, config);
+        server.preface(&Settings { max_header_list_size: Some(150), ..Settings::default() }, now);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+
+        // CONTINUATION frames without END_HEADERS don't pile up past the limit.
+        let headers = Frame::new(Flag::empty(), StreamIdentifier(1), Payload::Headers { priority: None, block: &[0x82] });
+        assert_eq!(server.recv(&headers, now), Ok(Recv::Pending));
+        assert_eq!(server.recv(&continuation(Flag::empty()), now), Ok(Recv::Pending));
+        assert_eq!(server.recv(&continuation(Flag::empty()), now), Ok(Recv::Pending));
+        assert_eq!(server.recv(&continuation(Flag::empty()), now), Err(Error::HeaderBlockTooLarge));
+
+        // SETTINGS_MAX_HEADER_LIST_SIZE lowers it once acknowledged.
+        let mut server = Connection::new(true, config);
+        server.preface(&Settings { max_header_list_size: Some(150), ..Settings::default() }, now);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        server.recv(&Frame::settings(SettingsFlags::ack(), &[]), now).unwrap();
+        assert_eq!(server.recv(&headers, now), Ok(Recv::Pending));
+        assert_eq!(server.recv(&continuation(Flag::empty()), now), Ok(Recv::Pending));
+        assert_eq!(server.recv(&continuation(Flag::end_headers()), now), Err(Error::HeaderBlockTooLarge));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let start = Instant::now();
+        let late = start + Duration::from_secs(10);
+        let mut server = Connection::new(true, ConnectionConfig::default());
+        server.preface(&Settings::default(), start);
+        assert_eq!(server.check_handshake(late).unwrap_err().phase, Phase::Preface);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), start).unwrap();
+        assert_eq!(server.check_handshake(late).unwrap_err().error_code(), Some(SETTINGS_TIMEOUT));
+        server.recv(&Frame::settings(SettingsFlags::ack(), &[]), start).unwrap();
+        assert_eq!(server.check_handshake(late), Ok(()));
+        assert_eq!(server.deadline(), None);
+    }
+
+    #[test]
+    fn test_flood() {
+        let now = Instant::now();
+        let ping = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(1));
+        let pong = Recv::Ping(Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(1)));
+        let config = ConnectionConfig { flood: FloodConfig { max_pings_per_second: 2, ..FloodConfig::default() },
+                                        ..ConnectionConfig::default() };
+        let mut server = Connection::new(true, config);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        assert_eq!(server.recv(&ping, now), Ok(pong.clone()));
+        assert_eq!(server.recv(&ping, now), Ok(pong.clone()));
+        let err = server.recv(&ping, now).unwrap_err();
+        assert_eq!(err, Error::Flood(Flood::Ping));
+        assert_eq!(err.error_code(), ENHANCE_YOUR_CALM);
+        assert_eq!(server.recv(&ping, now + Duration::from_secs(1)), Ok(pong.clone()));
+
+        // The SETTINGS ACKs we owe count until they are written.
+        let config = ConnectionConfig { flood: FloodConfig { max_pending_settings_acks: 1, ..FloodConfig::default() },
+                                        ..ConnectionConfig::default() };
+        let settings = Frame::settings(SettingsFlags::empty(), &[]);
+        let mut client = Connection::new(false, config);
+        client.recv(&settings, now).unwrap();
+        assert_eq!(client.recv(&settings, now), Err(Error::Flood(Flood::Settings)));
+        let mut client = Connection::new(false, config);
+        client.recv(&settings, now).unwrap();
+        assert_eq!(client.settings_ack(), Frame::settings(SettingsFlags::ack(), &[]));
+        assert!(client.recv(&settings, now).is_ok());
+    }
+
+    #[test]
+    fn test_auto_push() {
+        let manifest = PushManifest::from_json(r#"{"/": ["/a.css", "/b.js"]}"#).unwrap();
+        let push = AutoPush::new(manifest);
+        let mut server = connection(true);
+        let one = StreamIdentifier(1);
+        server.recv(&headers(1, Flag::end_stream()), Instant::now()).unwrap();
+        let pushes = server.auto_push(&push, one, "/", "https", "example.com");
+        assert_eq!(pushes.iter().map(|&(id, ref promise)| (id.0, &promise.resource.path[..])).collect::<Vec<_>>(),
+                   vec![(2, "/a.css"), (4, "/b.js")]);
+        assert_eq!(server.state(StreamIdentifier(4)), State::ReservedLocal);
+
+        // Not once the response was sent, nor to a client that turned push off.
+        server.send_headers(one, true, Instant::now()).unwrap();
+        assert!(server.auto_push(&push, one, "/", "https", "example.com").is_empty());
+        let mut server = connection(true);
+        let off = [Setting::new(SettingIdentifier::EnablePush, 0)];
+        server.recv(&Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Settings(&off)), Instant::now()).unwrap();
+        server.recv(&headers(1, Flag::end_stream()), Instant::now()).unwrap();
+        assert!(server.auto_push(&push, one, "/", "https", "example.com").is_empty());
+    }
+
+    #[test]
+    fn test_settings_registry() {
+        let mut client = Connection::new(false, ConnectionConfig::default());
+        let key = client.settings_registry_mut().register::<u32>(0xf0f0, "EXPERIMENT").unwrap();
+        client.settings_registry_mut().set_local(key, 3);
+        let preface = client.preface(&Settings::default(), Instant::now());
+        assert!(preface.ends_with(&[0xf0, 0xf0, 0, 0, 0, 3]));
+        client.settings_registry_mut().set_local(key, 4);
+        let update = client.update_settings(&Settings::default(), Instant::now()).unwrap();
+        assert!(update.payload.ends_with(&[0xf0, 0xf0, 0, 0, 0, 4]));
+
+        let remote = [Setting::unregistered(0xf0f0, 7)];
+        client.recv(&Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Settings(&remote)), Instant::now()).unwrap();
+        assert_eq!(client.settings_registry_mut().remote(key), Some(7));
+    }
+
+    #[test]
+    fn test_stream_stats() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut server = connection(true);
+        let one = StreamIdentifier(1);
+        server.recv(&headers(1, Flag::empty()), start).unwrap();
+        server.recv(&data(1, Flag::end_stream()), ms(5)).unwrap();
+        server.send_headers(one, false, ms(40)).unwrap();
+        server.queued(one, 70000, ms(40));
+        server.send_data(one, 65535, false, ms(45)).unwrap();
+        assert_eq!(server.poll_capacity(one, ms(50)), 0);
+        let update = |id| Frame::new(Flag::empty(), StreamIdentifier(id), Payload::WindowUpdate(SizeIncrement(10000)));
+        server.recv(&update(0), ms(80)).unwrap();
+        server.recv(&update(1), ms(80)).unwrap();
+        assert_eq!(server.poll_capacity(one, ms(90)), 10000);
+        server.send_data(one, 4465, true, ms(90)).unwrap();
+        assert_eq!(server.state(one), State::Closed);
+
+        let stats = server.stream_stats(one, ms(100)).unwrap();
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (70000, 2));
+        assert_eq!(stats.handler_time, Some(Duration::from_millis(40)));
+        assert_eq!(stats.blocked, Duration::from_millis(40));
+        assert_eq!(stats.window_updates_received, 1);
+        assert_eq!(stats.max_queue_latency, Duration::from_millis(50));
+        assert_eq!(server.stream_stats(StreamIdentifier(3), ms(100)), None);
+    }
+
+    #[test]
+    fn test_load_shedding() {
+        let now = Instant::now();
+        let shedder = LoadShedder::new(ShedConfig { max_pending: Some(1), ..ShedConfig::default() });
+        let mut server = connection(true);
+        server.set_load_shedder(shedder.clone());
+        assert!(match server.recv(&headers(1, Flag::empty()), now) { Ok(Recv::Headers(_)) => true, _ => false });
+
+        // Over the limit, new streams are refused but the open one carries on.
+        let pending = (0..2).map(|_| shedder.request_started(now)).collect::<Vec<_>>();
+        assert!(match server.recv(&headers(3, Flag::empty()), now) {
+            Ok(Recv::StreamError(error, Some(_))) => error == StreamError { id: StreamIdentifier(3), code: REFUSED_STREAM },
+            _ => false,
+        });
+        assert!(match server.recv(&data(1, Flag::end_stream()), now) { Ok(Recv::Data { .. }) => true, _ => false });
+
+        drop(pending);
+        assert!(match server.recv(&headers(5, Flag::empty()), now) { Ok(Recv::Headers(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn test_audit() {
+        let now = Instant::now();
+        let peer = "192.0.2.1:40000".parse().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let mut server = Connection::new(true, ConnectionConfig::default());
+        server.set_audit(AuditHook::new(move |event: &AuditEvent| sink.lock().unwrap().push(*event)), peer);
+        server.preface(&Settings { max_concurrent_streams: Some(1), ..Settings::default() }, now);
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        server.recv(&Frame::settings(SettingsFlags::ack(), &[]), now).unwrap();
+        server.recv(&headers(1, Flag::empty()), now).unwrap();
+        server.recv(&headers(3, Flag::empty()), now).unwrap();
+        server.recv(&headers(5, Flag::empty()), now).unwrap();
+
+        // Cancelling isn't refusing.
+        assert!(server.send_reset(StreamIdentifier(1), CANCEL).is_some());
+        server.recv(&headers(7, Flag::empty()), now).unwrap();
+        assert!(server.send_reset(StreamIdentifier(7), PROTOCOL_ERROR).is_some());
+
+        let reasons = events.lock().unwrap().iter().map(|event| {
+            assert_eq!(event.peer, peer);
+            (event.stream.unwrap().0, event.reason)
+        }).collect::<Vec<_>>();
+        assert_eq!(reasons, vec![(3, Reason::Protocol(REFUSED_STREAM)),
+                                 (5, Reason::Protocol(REFUSED_STREAM)),
+                                 (7, Reason::Protocol(PROTOCOL_ERROR))]);
+        assert_eq!(events.lock().unwrap()[2].counters.streams_reset, 3);
+    }
+
+    #[test]
+    fn test_stalls() {
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+        let reset = |id| Frame::new(Flag::empty(), StreamIdentifier(id), Payload::Reset(ENHANCE_YOUR_CALM));
+        let stall = StallConfig {
+            max_blocked: Duration::from_secs(5),
+            max_stalled_streams: 1,
+            ..StallConfig::default()
+        };
+        let mut server = Connection::new(true, ConnectionConfig { stall: Some(stall), ..ConnectionConfig::default() });
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        for id in &[1, 3, 5] {
+            server.recv(&headers(*id, Flag::end_stream()), now).unwrap();
+            server.send_headers(StreamIdentifier(*id), false, now).unwrap();
+            server.queued(StreamIdentifier(*id), 1 << 20, now);
+        }
+
+        // Stream 1 keeps up, 3 is starved of window and 5 takes less than the minimum rate.
+        let capacity = server.poll_capacity(StreamIdentifier(1), now);
+        server.send_data(StreamIdentifier(1), capacity, false, now).unwrap();
+        assert_eq!(server.poll_capacity(StreamIdentifier(3), now), 0);
+        server.recv(&Frame::new(Flag::empty(), StreamIdentifier(0), Payload::WindowUpdate(SizeIncrement(1 << 20))),
+                    now).unwrap();
+        server.send_data(StreamIdentifier(5), 100, false, secs(1)).unwrap();
+        assert_eq!(server.poll_stalls(secs(5)), Ok(Vec::new()));
+        assert_eq!(server.poll_stalls(secs(6)), Ok(vec![reset(3)]));
+        assert_eq!(server.state(StreamIdentifier(3)), State::Closed);
+
+        // The second stalled stream is one too many.
+        assert_eq!(server.poll_stalls(secs(10)), Err(ENHANCE_YOUR_CALM));
+        assert_eq!(server.state(StreamIdentifier(5)), State::Closed);
+        assert_eq!(server.state(StreamIdentifier(1)), State::HalfClosedRemote);
+
+        // Streams done sending aren't looked at.
+        let mut server = connection(true);
+        server.recv(&headers(1, Flag::end_stream()), now).unwrap();
+        server.send_headers(StreamIdentifier(1), false, now).unwrap();
+        server.queued(StreamIdentifier(1), 10, now);
+        server.send_data(StreamIdentifier(1), 10, true, now).unwrap();
+        assert_eq!(server.poll_stalls(secs(100)), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_mode() {
+        let now = Instant::now();
+        let prioritised = |id, dependency| {
+            let priority = Priority::new(false, StreamIdentifier(dependency), 16);
+            Frame::new(Flag::end_headers() | Flag::priority(), StreamIdentifier(id),
+                       Payload::Headers { priority: Some(priority), block: &[0x82] })
+        };
+        let own = Frame::new(Flag::empty(), StreamIdentifier(1),
+                             Payload::Priority(Priority::new(false, StreamIdentifier(1), 16)));
+
+        // RFC 9113 ignores the priorities, bad ones included.
+        let mut server = connection(true);
+        assert!(match server.recv(&prioritised(1, 0), now) {
+            Ok(Recv::Headers(block)) => block.priority.is_none(),
+            _ => false,
+        });
+        server.recv(&own, now).unwrap();
+        assert_eq!(server.state(StreamIdentifier(1)), State::Open);
+
+        let mut server = Connection::new(true, ConnectionConfig { mode: Mode::Rfc7540, ..ConnectionConfig::default() });
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        assert_eq!(server.mode(), Mode::Rfc7540);
+        assert!(match server.recv(&prioritised(1, 0), now) {
+            Ok(Recv::Headers(block)) => block.priority.is_some(),
+            _ => false,
+        });
+        assert_eq!(server.recv(&own, now),
+                   Ok(Recv::StreamError(StreamError { id: StreamIdentifier(1), code: PROTOCOL_ERROR }, None)));
+        assert!(match server.recv(&prioritised(3, 3), now) {
+            Ok(Recv::StreamError(error, Some(_))) => error == StreamError { id: StreamIdentifier(3), code: PROTOCOL_ERROR },
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_extensions() {
+        let now = Instant::now();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let mut server = connection(true);
+        server.extensions_mut().on_frame(0xf0, move |frame| {
+            sink.lock().unwrap().push(frame.header.id);
+            Ok(())
+        }).unwrap();
+        let frame = Frame::from_bytes(&[0, 0, 2, 0xf0, 0, 0, 0, 0, 3, b'h', b'i']).unwrap();
+        assert_eq!(server.recv(&frame, now), Ok(Recv::Connection));
+        assert_eq!(*seen.lock().unwrap(), vec![StreamIdentifier(3)]);
+
+        // Other unknown types are ignored, until a handler takes them all.
+        let other = Frame::from_bytes(&[0, 0, 0, 0xf1, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(server.recv(&other, now), Ok(Recv::Connection));
+        server.extensions_mut().on_unknown(|_| Err(ENHANCE_YOUR_CALM));
+        assert_eq!(server.recv(&other, now), Err(Error::Extension(ENHANCE_YOUR_CALM)));
+        assert_eq!(Error::Extension(ENHANCE_YOUR_CALM).error_code(), ENHANCE_YOUR_CALM);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let now = Instant::now();
+        let id = StreamIdentifier;
+        let open = |server: &mut Connection| for n in &[1, 3] {
+            server.recv(&headers(*n, Flag::end_stream()), now).unwrap();
+            server.send_headers(id(*n), false, now).unwrap();
+            server.queued(id(*n), 100, now);
+        };
+
+        // RFC 9218: a PRIORITY_UPDATE makes stream 3 the more urgent.
+        let mut server = connection(true);
+        open(&mut server);
+        assert_eq!(server.scheduler_mut().next(), Some(id(1)));
+        let update = Frame::new(Flag::empty(), id(0), Payload::PriorityUpdate { prioritized: id(3), value: b"u=0" });
+        assert_eq!(server.recv(&update, now), Ok(Recv::Connection));
+        assert_eq!(server.scheduler_mut().next(), Some(id(3)));
+        server.send_data(id(3), 100, true, now).unwrap();
+        assert_eq!(server.scheduler_mut().next(), Some(id(1)));
+
+        // Streams blocked on the connection window wait for it.
+        assert_eq!(server.poll_capacity(id(1), now), 65435);
+        server.send_data(id(1), 65435, false, now).unwrap();
+        assert_eq!(server.poll_capacity(id(1), now), 0);
+        assert_eq!(server.scheduler_mut().next(), None);
+        let update = Frame::new(Flag::empty(), id(0), Payload::WindowUpdate(SizeIncrement(100)));
+        assert_eq!(server.recv(&update, now), Ok(Recv::WindowUpdate(vec![id(1)])));
+        assert_eq!(server.scheduler_mut().next(), Some(id(1)));
+
+        // RFC 7540: stream 1 depends on 3, which goes first until it is closed.
+        let mut server = Connection::new(true, ConnectionConfig { mode: Mode::Rfc7540, ..ConnectionConfig::default() });
+        server.recv(&Frame::settings(SettingsFlags::empty(), &[]), now).unwrap();
+        open(&mut server);
+        let priority = Frame::new(Flag::empty(), id(1), Payload::Priority(Priority::new(false, id(3), 16)));
+        assert_eq!(server.recv(&priority, now), Ok(Recv::Connection));
+        assert_eq!(server.scheduler_mut().next(), Some(id(3)));
+        server.send_reset(id(3), CANCEL).unwrap();
+        assert_eq!(server.scheduler_mut().next(), Some(id(1)));
+    }
+
+    #[test]
+    fn test_set_priority() {
+        let now = Instant::now();
+        let mut client = Connection::new(false, ConnectionConfig::default());
+        let stream = client.open_stream().unwrap();
+        assert!(client.set_priority(stream, 5, false).is_ok());
+        client.send_headers(stream, true, now).unwrap();
+        let frame = client.set_priority(stream, 0, true).unwrap();
+        assert_eq!(frame.header.id, StreamIdentifier(0));
+        let update = PriorityUpdate::from_frame(&frame.frame().unwrap()).unwrap();
+        assert_eq!(update, PriorityUpdate::new(stream, 0, true));
+
+        client.send_reset(stream, CANCEL).unwrap();
+        assert_eq!(client.set_priority(stream, 0, true), Err(PriorityError::Closed));
+
+        // Only a client sends them, and only a server takes them in.
+        let mut server = Connection::new(true, ConnectionConfig::default());
+        assert_eq!(server.set_priority(stream, 0, true), Err(PriorityError::Server));
+        let mut client = connection(false);
+        assert_eq!(client.recv(&frame.frame().unwrap(), now), Err(Error::InvalidPriorityUpdate));
+        let mut server = connection(true);
+        assert_eq!(server.recv(&frame.frame().unwrap(), now), Ok(Recv::Connection));
+    }
+}
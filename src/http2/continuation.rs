@@ -0,0 +1,238 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Header blocks that don't fit in a frame: split into a HEADERS frame and CONTINUATION frames
+//! on the way out, and put back together on the way in so that the HPACK decoder gets the
+//! whole block. Nothing else may come between the frames of a block (RFC 7540 section 6.10).
+
+use std::cmp;
+
+use http2::flag::{ContinuationFlags, Flag, HeadersFlags, PushPromiseFlags};
+use http2::frame::Frame;
+use http2::payload::{Payload, Priority};
+use http2::{Error, StreamIdentifier};
+
+const PRIORITY_BYTES: usize = 5;
+const PROMISED_ID_BYTES: usize = 4;
+
+/// Splits `block` into a HEADERS frame and as many CONTINUATION frames after it as it takes
+/// for none of their payloads to go over `max_frame_size`. The last frame gets END_HEADERS;
+/// END_STREAM, if `flag` has it, goes on the HEADERS frame.
+///
+/// Panics if `max_frame_size` leaves no room for the block in the first frame; the peer's
+/// SETTINGS_MAX_FRAME_SIZE is at least 16384.
+pub fn split_headers<'a>(id: StreamIdentifier, flag: Flag, priority: Option<Priority>, block: &'a [u8],
+                         max_frame_size: usize) -> Vec<Frame<'a>> {
+    let fixed = priority.map_or(0, |_| PRIORITY_BYTES);
+    assert!(max_frame_size > fixed, "max_frame_size {} leaves no room for a header block", max_frame_size);
+    let room = max_frame_size - fixed;
+    let flags = HeadersFlags::from_flag(flag) & HeadersFlags::end_stream();
+    split(id, block, room, max_frame_size, |first| Frame::headers(flags, id, priority, first))
+}
+
+/// Splits `block`, the request header block of a push, into a PUSH_PROMISE frame on the
+/// associated stream `id` and CONTINUATION frames, as `split_headers` does.
+pub fn split_push_promise<'a>(id: StreamIdentifier, promised: StreamIdentifier, block: &'a [u8],
+                              max_frame_size: usize) -> Vec<Frame<'a>> {
+    assert!(max_frame_size > PROMISED_ID_BYTES, "max_frame_size {} leaves no room for a header block", max_frame_size);
+    split(id, block, max_frame_size - PROMISED_ID_BYTES, max_frame_size, |first| {
+        Frame::push_promise(PushPromiseFlags::empty(), id, promised, first)
+    })
+}
+
+/// `first` builds the first frame from the octets of `block` that fit in `first_room`.
+fn split<'a, F>(id: StreamIdentifier, block: &'a [u8], first_room: usize, max_frame_size: usize,
+                first: F) -> Vec<Frame<'a>> where F: FnOnce(&'a [u8]) -> Frame<'a> {
+    let (head, mut rest) = block.split_at(cmp::min(block.len(), first_room));
+    let mut frames = vec![first(head)];
+    while !rest.is_empty() {
+        let (fragment, next) = rest.split_at(cmp::min(rest.len(), max_frame_size));
+        frames.push(Frame::continuation(ContinuationFlags::empty(), id, fragment));
+        rest = next;
+    }
+    frames.last_mut().unwrap().header.flag.insert(Flag::end_headers());
+    frames
+}
+
+/// A whole header block, with what the first frame said about it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderBlock {
+    pub id: StreamIdentifier,
+    /// END_STREAM was set on the HEADERS frame.
+    pub end_stream: bool,
+    pub priority: Option<Priority>,
+    /// The promised stream, if the block came in a PUSH_PROMISE frame.
+    pub promised: Option<StreamIdentifier>,
+    pub block: Vec<u8>,
+}
+
+/// Puts header blocks back together from HEADERS or PUSH_PROMISE frames and the CONTINUATION
+/// frames that follow them.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: Option<HeaderBlock>,
+    max_block_size: Option<usize>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Limits the size of a header block, before decompression; CONTINUATION frames would
+    /// otherwise make us buffer without end. `None` (the default here) means no limit: the
+    /// connection sets `ConnectionConfig::max_header_block_size`.
+    pub fn set_max_block_size(&mut self, max_block_size: Option<usize>) {
+        self.max_block_size = max_block_size;
+    }
+
+    /// Whether a header block was started and not ended yet, in which case only CONTINUATION
+    /// frames of its stream may come next.
+    pub fn in_block(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Takes in every frame received and returns the header block it completes, if any. Frames
+    /// outside of header blocks give `None`.
+    ///
+    /// Errors are connection errors (see `Error`).
+    pub fn recv(&mut self, frame: &Frame) -> Result<Option<HeaderBlock>, Error> {
+        let (block, fragment) = match (self.pending.take(), frame.payload) {
+            (None, Payload::Headers { priority, block }) => (HeaderBlock {
+                id: frame.header.id,
+                end_stream: frame.header.flag.contains(Flag::end_stream()),
+                priority: priority,
+                promised: None,
+                block: Vec::new(),
+            }, block),
+            (None, Payload::PushPromise { promised, block }) => (HeaderBlock {
+                id: frame.header.id,
+                end_stream: false,
+                priority: None,
+                promised: Some(promised),
+                block: Vec::new(),
+            }, block),
+            (Some(pending), Payload::Continuation(fragment)) if pending.id == frame.header.id => (pending, fragment),
+            (None, Payload::Continuation(_)) | (Some(_), _) => return Err(Error::InvalidContinuation),
+            (None, _) => return Ok(None),
+        };
+
+        let mut block = block;
+        if self.max_block_size.map_or(false, |max| block.block.len() + fragment.len() > max) {
+            return Err(Error::HeaderBlockTooLarge);
+        }
+        block.block.extend_from_slice(fragment);
+        if frame.header.flag.contains(Flag::end_headers()) {
+            Ok(Some(block))
+        } else {
+            self.pending = Some(block);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::kind::Kind;
+    use http2::payload::{Payload, Priority};
+    use http2::{Error, StreamIdentifier};
+
+    use super::{split_headers, split_push_promise, HeaderBlock, Reassembler};
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let block: Vec<u8> = (0..25).collect();
+        let priority = Priority::new(true, StreamIdentifier(1), 256);
+        let frames = split_headers(StreamIdentifier(3), Flag::end_stream(), Some(priority), &block, 10);
+        let kinds: Vec<_> = frames.iter().map(|f| (f.header.kind, f.header.length, f.header.flag)).collect();
+        // The priority fields take 5 octets of the first frame.
+        assert_eq!(kinds, vec![(Kind::Headers, 10, Flag::end_stream() | Flag::priority()),
+                               (Kind::Continuation, 10, Flag::empty()),
+                               (Kind::Continuation, 10, Flag::end_headers())]);
+
+        let mut reassembler = Reassembler::new();
+        for frame in &frames[..2] {
+            assert_eq!(reassembler.recv(frame), Ok(None));
+        }
+        assert!(reassembler.in_block());
+        assert_eq!(reassembler.recv(&frames[2]), Ok(Some(HeaderBlock {
+            id: StreamIdentifier(3),
+            end_stream: true,
+            priority: Some(priority),
+            promised: None,
+            block: block.clone(),
+        })));
+        assert!(!reassembler.in_block());
+
+        // One frame is enough.
+        let frames = split_headers(StreamIdentifier(5), Flag::empty(), None, &block, 16384);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].header.flag, Flag::end_headers());
+        assert_eq!(reassembler.recv(&frames[0]).unwrap().unwrap().block, block);
+        assert_eq!(priority.weight(), 256);
+    }
+
+    #[test]
+    fn test_push_promise() {
+        let block: Vec<u8> = (0..10).collect();
+        let frames = split_push_promise(StreamIdentifier(1), StreamIdentifier(2), &block, 8);
+        let kinds: Vec<_> = frames.iter().map(|f| (f.header.kind, f.header.length, f.header.flag)).collect();
+        assert_eq!(kinds, vec![(Kind::PushPromise, 8, Flag::empty()),
+                               (Kind::Continuation, 6, Flag::end_headers())]);
+
+        // Padded on the wire.
+        let mut buf = Vec::new();
+        frames[0].serialize_padded_into(3, &mut buf).unwrap();
+        assert_eq!(Frame::from_bytes(&buf).unwrap().payload, frames[0].payload);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.recv(&frames[0]), Ok(None));
+        let pushed = reassembler.recv(&frames[1]).unwrap().unwrap();
+        assert_eq!((pushed.id, pushed.promised), (StreamIdentifier(1), Some(StreamIdentifier(2))));
+        assert_eq!(pushed.block, block);
+    }
+
+    #[test]
+    fn test_errors() {
+        let ping = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(1));
+        let headers = Frame::new(Flag::empty(), StreamIdentifier(1), Payload::Headers { priority: None, block: b"ab" });
+        let continuation = Frame::new(Flag::end_headers(), StreamIdentifier(1), Payload::Continuation(b"cd"));
+        let other_stream = Frame::new(Flag::end_headers(), StreamIdentifier(3), Payload::Continuation(b"cd"));
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.recv(&ping), Ok(None));
+        assert_eq!(reassembler.recv(&continuation), Err(Error::InvalidContinuation));
+        reassembler.recv(&headers).unwrap();
+        assert_eq!(reassembler.recv(&ping), Err(Error::InvalidContinuation));
+        let mut reassembler = Reassembler::new();
+        reassembler.recv(&headers).unwrap();
+        assert_eq!(reassembler.recv(&other_stream), Err(Error::InvalidContinuation));
+
+        let mut reassembler = Reassembler::new();
+        reassembler.set_max_block_size(Some(3));
+        reassembler.recv(&headers).unwrap();
+        assert_eq!(reassembler.recv(&continuation), Err(Error::HeaderBlockTooLarge));
+    }
+
+    #[test]
+    #[should_panic(expected = "leaves no room")]
+    fn test_no_room() {
+        let priority = Priority::new(false, StreamIdentifier(1), 16);
+        split_headers(StreamIdentifier(3), Flag::empty(), Some(priority), b"ab", 5);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Extension frames (RFC 7540 section 5.5). Frames of types we don't know are ignored unless
+//! a handler was registered for their type, or for all of them, with the connection's
+//! `Extensions` (`Connection::extensions_mut`); intermediaries use that to forward frames and
+//! experimental extensions to implement themselves. The frames of an extension are sent by
+//! queueing them on it too.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use bytes::Bytes;
+
+use http2::codec::FrameBuf;
+use http2::flag::Flag;
+use http2::frame::{Frame, FrameHeader};
+use http2::kind::Kind;
+use http2::payload::Payload;
+use http2::{ErrorCode, StreamIdentifier};
+
+/// Called with every extension frame it was registered for. An error closes the connection
+/// with a GOAWAY carrying the error code.
+pub type ExtensionHandler = Box<FnMut(&Frame) -> Result<(), ErrorCode> + Send>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExtensionError {
+    /// The frame type is one the crate implements itself.
+    Standard(u8),
+    AlreadyRegistered(u8),
+}
+
+impl fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExtensionError::Standard(kind) => write!(f, "frame type {:#x} is a standard frame type", kind),
+            ExtensionError::AlreadyRegistered(kind) => write!(f, "frame type {:#x} is already registered", kind),
+        }
+    }
+}
+
+fn unregistered(kind: u8) -> Result<(), ExtensionError> {
+    match Kind::new(kind) {
+        Kind::Unregistered(_) => Ok(()),
+        _ => Err(ExtensionError::Standard(kind)),
+    }
+}
+
+#[derive(Default)]
+pub struct Extensions {
+    handlers: HashMap<u8, ExtensionHandler>,
+    fallback: Option<ExtensionHandler>,
+    outbound: VecDeque<FrameBuf>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Hands the received frames of type `kind` to `handler`.
+    pub fn on_frame<F>(&mut self, kind: u8, handler: F) -> Result<(), ExtensionError>
+            where F: FnMut(&Frame) -> Result<(), ErrorCode> + Send + 'static {
+        try!(unregistered(kind));
+        if self.handlers.contains_key(&kind) {
+            return Err(ExtensionError::AlreadyRegistered(kind));
+        }
+        self.handlers.insert(kind, Box::new(handler));
+        Ok(())
+    }
+
+    /// Hands the received frames of types without a handler of their own to `handler`, e.g.
+    /// to forward them; it replaces the previous one.
+    pub fn on_unknown<F>(&mut self, handler: F)
+            where F: FnMut(&Frame) -> Result<(), ErrorCode> + Send + 'static {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    /// Takes in every frame received, and hands extension frames to their handler. Returns
+    /// whether one took the frame; other frames are for the connection to process or ignore.
+    pub fn recv(&mut self, frame: &Frame) -> Result<bool, ErrorCode> {
+        let kind = match frame.header.kind {
+            Kind::Unregistered(kind) => kind,
+            _ => return Ok(false),
+        };
+        let handler = match self.handlers.get_mut(&kind) {
+            Some(handler) => handler,
+            None => match self.fallback {
+                Some(ref mut handler) => handler,
+                None => return Ok(false),
+            },
+        };
+        try!(handler(frame));
+        Ok(true)
+    }
+
+    /// Queues an extension frame for the connection to send.
+    pub fn send(&mut self, kind: u8, flag: Flag, id: StreamIdentifier, data: Vec<u8>) -> Result<(), ExtensionError> {
+        try!(unregistered(kind));
+        self.outbound.push_back(FrameBuf {
+            header: FrameHeader {
+                length: data.len() as u32,
+                kind: Kind::Unregistered(kind),
+                flag: flag,
+                id: id,
+            },
+            payload: Bytes::from(data),
+        });
+        Ok(())
+    }
+
+    /// The next queued extension frame, for the connection's write path.
+    pub fn next_outbound(&mut self) -> Option<FrameBuf> {
+        self.outbound.pop_front()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kinds: Vec<_> = self.handlers.keys().collect();
+        kinds.sort();
+        write!(f, "Extensions {{ kinds: {:?}, fallback: {}, outbound: {} }}",
+               kinds, self.fallback.is_some(), self.outbound.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::{StreamIdentifier, ENHANCE_YOUR_CALM};
+
+    use super::{ExtensionError, Extensions};
+
+    #[test]
+    fn test_extensions() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut extensions = Extensions::new();
+        {
+            let seen = seen.clone();
+            extensions.on_frame(0xf0, move |frame| {
+                if let Payload::Unregistered { data, .. } = frame.payload {
+                    seen.lock().unwrap().push((frame.header.flag.bits(), data.to_vec()));
+                }
+                Ok(())
+            }).unwrap();
+        }
+        assert_eq!(extensions.on_frame(0xf0, |_| Ok(())), Err(ExtensionError::AlreadyRegistered(0xf0)));
+        assert_eq!(extensions.on_frame(0x1, |_| Ok(())), Err(ExtensionError::Standard(0x1)));
+
+        // Flags without a meaning in RFC 7540 are kept for extension frames.
+        let frame = Frame::from_bytes(&[0, 0, 2, 0xf0, 0x82, 0, 0, 0, 3, b'h', b'i']).unwrap();
+        assert_eq!(extensions.recv(&frame), Ok(true));
+        assert_eq!(*seen.lock().unwrap(), vec![(0x82, b"hi".to_vec())]);
+        let other = Frame::from_bytes(&[0, 0, 0, 0xf1, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(extensions.recv(&other), Ok(false));
+        let ping = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(1));
+        assert_eq!(extensions.recv(&ping), Ok(false));
+        extensions.on_unknown(|_| Err(ENHANCE_YOUR_CALM));
+        assert_eq!(extensions.recv(&other), Err(ENHANCE_YOUR_CALM));
+
+        extensions.send(0xf0, Flag::empty(), StreamIdentifier(0), b"hi".to_vec()).unwrap();
+        let frame = extensions.next_outbound().unwrap();
+        assert_eq!(frame.frame().unwrap().payload, Payload::Unregistered { kind: 0xf0, data: b"hi" });
+        assert_eq!(extensions.next_outbound(), None);
+        assert_eq!(extensions.send(0x0, Flag::empty(), StreamIdentifier(1), Vec::new()), Err(ExtensionError::Standard(0x0)));
+    }
+}
@@ -14,13 +14,20 @@
 
 //! NB: This code is changing so please do not depend on it at this time!
 
+use http2::kind::Kind;
+
 bitflags! {
     pub flags Flag: u8 {
         const END_STREAM = 0x1,
         const ACK = 0x1,
         const END_HEADERS = 0x4,
         const PADDED = 0x8,
-        const PRIORITY = 0x20
+        const PRIORITY = 0x20,
+        // No frame type of RFC 7540 uses these, but extension frames may.
+        const UNDEFINED_0X2 = 0x2,
+        const UNDEFINED_0X10 = 0x10,
+        const UNDEFINED_0X40 = 0x40,
+        const UNDEFINED_0X80 = 0x80
     }
 }
 
@@ -39,4 +46,58 @@ impl Flag {
     pub fn end_headers() -> Flag { END_HEADERS }
     pub fn padded() -> Flag { PADDED }
     pub fn priority() -> Flag { PRIORITY }
+
+    /// The flags with a meaning on frames of type `kind`; the others must be ignored. All of
+    /// them for a type we don't know.
+    pub fn defined_for(kind: Kind) -> Flag {
+        match kind {
+            Kind::Data => END_STREAM | PADDED,
+            Kind::Headers => END_STREAM | END_HEADERS | PADDED | PRIORITY,
+            Kind::Settings | Kind::Ping => ACK,
+            Kind::PushPromise => END_HEADERS | PADDED,
+            Kind::Continuation => END_HEADERS,
+            Kind::Priority | Kind::Reset | Kind::GoAway | Kind::WindowUpdate | Kind::AltSvc |
+            Kind::Origin | Kind::PriorityUpdate => Flag::empty(),
+            Kind::Unregistered(_) => Flag::all(),
+        }
+    }
 }
+
+// The flags of each frame type that has some, so that END_STREAM can't end up on a SETTINGS
+// frame. PADDED and PRIORITY aren't among them: the frame sets those itself from its padding
+// and priority fields. Received flags go through `from_flag`, which drops the others.
+macro_rules! frame_flags {
+    ($module:ident, $name:ident, $($flag:ident => $fn:ident),+) => {
+        mod $module {
+            bitflags! {
+                pub flags $name: u8 {
+                    $(const $flag = super::$flag.bits),+
+                }
+            }
+
+            impl $name {
+                $(pub fn $fn() -> $name { $flag })+
+
+                /// The flags of `flag` with a meaning here.
+                pub fn from_flag(flag: super::Flag) -> $name {
+                    $name::from_bits_truncate(flag.bits())
+                }
+            }
+
+            impl From<$name> for super::Flag {
+                fn from(flags: $name) -> super::Flag {
+                    super::Flag::from_bits_truncate(flags.bits())
+                }
+            }
+        }
+
+        pub use self::$module::$name;
+    }
+}
+
+frame_flags!(data, DataFlags, END_STREAM => end_stream);
+frame_flags!(headers, HeadersFlags, END_STREAM => end_stream, END_HEADERS => end_headers);
+frame_flags!(settings, SettingsFlags, ACK => ack);
+frame_flags!(ping, PingFlags, ACK => ack);
+frame_flags!(push_promise, PushPromiseFlags, END_HEADERS => end_headers);
+frame_flags!(continuation, ContinuationFlags, END_HEADERS => end_headers);
@@ -0,0 +1,401 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Per-connection accounting that defends against the flood class of DoS attacks (CVE-2019-9515
+//! and friends). The connection feeds every relevant inbound frame into a `FloodGuard`; once a
+//! limit is crossed the guard returns a `Flood` and the connection must be closed with a GOAWAY
+//! carrying `Flood::error_code()` (ENHANCE_YOUR_CALM).
+//!
+//! That includes the rapid reset attack (CVE-2023-44487): a client that opens streams and
+//! resets them right away never has more than a few open, so SETTINGS_MAX_CONCURRENT_STREAMS
+//! doesn't stop it from having the server start a request for each.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use http2::ErrorCode;
+use http2::ENHANCE_YOUR_CALM;
+use http2::flag::Flag;
+use http2::frame::FrameHeader;
+use http2::kind::Kind;
+use http2::StreamIdentifier;
+
+/// Limits enforced by a `FloodGuard`. The defaults follow nghttp2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FloodConfig {
+    /// Maximum number of SETTINGS frames we may owe an ACK for at any time.
+    pub max_pending_settings_acks: usize,
+    /// Maximum number of non-ACK SETTINGS frames accepted per second.
+    pub max_settings_per_second: u32,
+    /// Maximum number of PINGs per second we are willing to answer.
+    pub max_pings_per_second: u32,
+    /// Maximum number of consecutive frames that do not make progress: DATA without payload and
+    /// without END_STREAM, or HEADERS/CONTINUATION with an empty block fragment and without
+    /// END_HEADERS.
+    pub max_empty_frames: u32,
+    /// Maximum number of WINDOW_UPDATE frames accepted per second.
+    pub max_window_updates_per_second: u32,
+    /// Maximum number of streams the client may open per second.
+    pub max_new_streams_per_second: u32,
+    /// Maximum number of streams per second the client may reset within a second of their
+    /// HEADERS.
+    pub max_rapid_resets_per_second: u32,
+}
+
+impl Default for FloodConfig {
+    fn default() -> FloodConfig {
+        FloodConfig {
+            max_pending_settings_acks: 32,
+            max_settings_per_second: 100,
+            max_pings_per_second: 100,
+            max_empty_frames: 10,
+            max_window_updates_per_second: 1000,
+            max_new_streams_per_second: 1000,
+            max_rapid_resets_per_second: 100,
+        }
+    }
+}
+
+/// The kind of flood that was detected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Flood {
+    /// Too many SETTINGS frames per second or too many outstanding ACK obligations.
+    Settings,
+    /// Too many PINGs per second.
+    Ping,
+    /// Too many consecutive frames that carry nothing.
+    EmptyFrames,
+    /// Too many WINDOW_UPDATE frames per second.
+    WindowUpdate,
+    /// Too many new streams per second.
+    StreamChurn,
+    /// Too many streams reset right after their HEADERS per second.
+    RapidReset,
+}
+
+impl Flood {
+    /// The error code the connection should be terminated with.
+    pub fn error_code(&self) -> ErrorCode {
+        ENHANCE_YOUR_CALM
+    }
+}
+
+impl fmt::Display for Flood {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Flood::Settings => "SETTINGS flood",
+            Flood::Ping => "PING flood",
+            Flood::EmptyFrames => "empty frame flood",
+            Flood::WindowUpdate => "WINDOW_UPDATE flood",
+            Flood::StreamChurn => "new stream flood",
+            Flood::RapidReset => "rapid reset flood",
+        })
+    }
+}
+
+/// Counts events within a fixed one second window.
+#[derive(Copy, Clone, Debug)]
+struct RateWindow {
+    start: Option<Instant>,
+    count: u32,
+}
+
+/// The window of the rates, and how soon after its HEADERS a stream has to be reset for the
+/// reset to count as rapid.
+fn second() -> Duration {
+    Duration::from_secs(1)
+}
+
+impl RateWindow {
+    fn new() -> RateWindow {
+        RateWindow { start: None, count: 0 }
+    }
+
+    /// Records one event at `now` and returns the number of events in the current window.
+    fn hit(&mut self, now: Instant) -> u32 {
+        let expired = match self.start {
+            Some(start) => now.duration_since(start) >= second(),
+            None => true,
+        };
+        if expired {
+            self.start = Some(now);
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Flood accounting for a single connection.
+#[derive(Clone, Debug)]
+pub struct FloodGuard {
+    config: FloodConfig,
+    /// Whether the peer opens the odd streams, which `recv_frame` then counts.
+    server: bool,
+    pending_settings_acks: usize,
+    settings: RateWindow,
+    pings: RateWindow,
+    window_updates: RateWindow,
+    empty_frames: u32,
+    new_streams: RateWindow,
+    rapid_resets: RateWindow,
+    /// The highest id of a stream the client opened.
+    last_stream: StreamIdentifier,
+    /// The streams opened within the last second, the oldest first.
+    opened: VecDeque<(StreamIdentifier, Instant)>,
+}
+
+impl FloodGuard {
+    pub fn new(config: FloodConfig) -> FloodGuard {
+        FloodGuard {
+            config: config,
+            server: true,
+            pending_settings_acks: 0,
+            settings: RateWindow::new(),
+            pings: RateWindow::new(),
+            window_updates: RateWindow::new(),
+            empty_frames: 0,
+            new_streams: RateWindow::new(),
+            rapid_resets: RateWindow::new(),
+            last_stream: StreamIdentifier(0),
+            opened: VecDeque::new(),
+        }
+    }
+
+    /// A guard for a client's connection, whose `recv_frame` doesn't count the HEADERS of the
+    /// streams the client opened itself as streams the peer opened, nor their resets as rapid.
+    pub fn client(config: FloodConfig) -> FloodGuard {
+        FloodGuard { server: false, ..FloodGuard::new(config) }
+    }
+
+    /// Accounts for any inbound frame, dispatching to the specific checks below. This is the
+    /// single entry point the connection needs to call.
+    pub fn recv_frame(&mut self, header: &FrameHeader, now: Instant) -> Result<(), Flood> {
+        let ack = header.flag.contains(Flag::ack());
+        match header.kind {
+            Kind::Settings if !ack => try!(self.recv_settings(now)),
+            Kind::Ping if !ack => try!(self.recv_ping(now)),
+            Kind::WindowUpdate => try!(self.recv_window_update(now)),
+            Kind::Headers if self.server && header.id.0 % 2 == 1 && header.id.0 > self.last_stream.0 => {
+                self.last_stream = header.id;
+                try!(self.recv_new_stream(header.id, now));
+            },
+            Kind::Reset => try!(self.recv_reset(header.id, now)),
+            _ => {},
+        }
+
+        let empty = match header.kind {
+            Kind::Data => header.length == 0 && !header.flag.contains(Flag::end_stream()),
+            Kind::Headers | Kind::Continuation => {
+                header.length == 0 && !header.flag.contains(Flag::end_headers())
+            },
+            _ => false,
+        };
+        if empty {
+            self.empty_frames += 1;
+            if self.empty_frames > self.config.max_empty_frames {
+                return Err(Flood::EmptyFrames);
+            }
+        } else if header.kind != Kind::WindowUpdate {
+            self.empty_frames = 0;
+        }
+        Ok(())
+    }
+
+    /// Must be called for every inbound PING that is not an ACK, before answering it.
+    pub fn recv_ping(&mut self, now: Instant) -> Result<(), Flood> {
+        if self.pings.hit(now) > self.config.max_pings_per_second {
+            return Err(Flood::Ping);
+        }
+        Ok(())
+    }
+
+    /// Must be called for every inbound WINDOW_UPDATE.
+    pub fn recv_window_update(&mut self, now: Instant) -> Result<(), Flood> {
+        if self.window_updates.hit(now) > self.config.max_window_updates_per_second {
+            return Err(Flood::WindowUpdate);
+        }
+        Ok(())
+    }
+
+    /// Must be called for the HEADERS of every stream the client opens. `recv_frame` takes
+    /// HEADERS on a new odd stream id for that, unless the guard is a `client` one.
+    pub fn recv_new_stream(&mut self, id: StreamIdentifier, now: Instant) -> Result<(), Flood> {
+        self.expire(now);
+        self.opened.push_back((id, now));
+        if self.new_streams.hit(now) > self.config.max_new_streams_per_second {
+            return Err(Flood::StreamChurn);
+        }
+        Ok(())
+    }
+
+    /// Must be called for every inbound RST_STREAM.
+    pub fn recv_reset(&mut self, id: StreamIdentifier, now: Instant) -> Result<(), Flood> {
+        self.expire(now);
+        if let Some(i) = self.opened.iter().position(|&(opened, _)| opened == id) {
+            self.opened.remove(i);
+            if self.rapid_resets.hit(now) > self.config.max_rapid_resets_per_second {
+                return Err(Flood::RapidReset);
+            }
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.opened.front().map_or(false, |&(_, opened)| now.duration_since(opened) >= second()) {
+            self.opened.pop_front();
+        }
+    }
+
+    /// Must be called for every inbound SETTINGS frame without the ACK flag, i.e. for every
+    /// SETTINGS frame that obliges us to answer with an ACK.
+    pub fn recv_settings(&mut self, now: Instant) -> Result<(), Flood> {
+        self.pending_settings_acks += 1;
+        if self.pending_settings_acks > self.config.max_pending_settings_acks {
+            return Err(Flood::Settings);
+        }
+        if self.settings.hit(now) > self.config.max_settings_per_second {
+            return Err(Flood::Settings);
+        }
+        Ok(())
+    }
+
+    /// Must be called once the ACK for an inbound SETTINGS frame has been written out.
+    pub fn settings_ack_sent(&mut self) {
+        if self.pending_settings_acks > 0 {
+            self.pending_settings_acks -= 1;
+        }
+    }
+
+    /// The number of SETTINGS ACKs we currently owe the peer.
+    pub fn pending_settings_acks(&self) -> usize {
+        self.pending_settings_acks
+    }
+}
+
+impl Default for FloodGuard {
+    fn default() -> FloodGuard {
+        FloodGuard::new(FloodConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::StreamIdentifier;
+    use http2::flag::Flag;
+    use http2::frame::FrameHeader;
+    use http2::kind::Kind;
+    use super::{Flood, FloodConfig, FloodGuard};
+
+    #[test]
+    fn test_settings_ack_obligations() {
+        let mut guard = FloodGuard::new(FloodConfig { max_pending_settings_acks: 2, .. FloodConfig::default() });
+        let now = Instant::now();
+        assert_eq!(guard.recv_settings(now), Ok(()));
+        assert_eq!(guard.recv_settings(now), Ok(()));
+        assert_eq!(guard.recv_settings(now), Err(Flood::Settings));
+
+        let mut guard = FloodGuard::new(FloodConfig { max_pending_settings_acks: 2, .. FloodConfig::default() });
+        for _ in 0..10 {
+            assert_eq!(guard.recv_settings(now), Ok(()));
+            guard.settings_ack_sent();
+        }
+        assert_eq!(guard.pending_settings_acks(), 0);
+    }
+
+    #[test]
+    fn test_settings_rate() {
+        let mut guard = FloodGuard::new(FloodConfig { max_settings_per_second: 3, .. FloodConfig::default() });
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(guard.recv_settings(now), Ok(()));
+            guard.settings_ack_sent();
+        }
+        assert_eq!(guard.recv_settings(now), Err(Flood::Settings));
+        guard.settings_ack_sent();
+
+        // A new window starts after a second.
+        assert_eq!(guard.recv_settings(now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn test_empty_frames() {
+        let mut guard = FloodGuard::new(FloodConfig { max_empty_frames: 2, .. FloodConfig::default() });
+        let now = Instant::now();
+        let empty = FrameHeader { length: 0, kind: Kind::Data, flag: Flag::empty(), id: StreamIdentifier(1) };
+        let full = FrameHeader { length: 10, .. empty };
+        let last = FrameHeader { flag: Flag::end_stream(), .. empty };
+
+        assert_eq!(guard.recv_frame(&empty, now), Ok(()));
+        assert_eq!(guard.recv_frame(&empty, now), Ok(()));
+        assert_eq!(guard.recv_frame(&full, now), Ok(()));
+        assert_eq!(guard.recv_frame(&empty, now), Ok(()));
+        assert_eq!(guard.recv_frame(&last, now), Ok(()));
+        assert_eq!(guard.recv_frame(&empty, now), Ok(()));
+        assert_eq!(guard.recv_frame(&empty, now), Ok(()));
+        assert_eq!(guard.recv_frame(&empty, now), Err(Flood::EmptyFrames));
+    }
+
+    #[test]
+    fn test_ping_rate() {
+        let mut guard = FloodGuard::new(FloodConfig { max_pings_per_second: 2, .. FloodConfig::default() });
+        let now = Instant::now();
+        let ping = FrameHeader { length: 8, kind: Kind::Ping, flag: Flag::empty(), id: StreamIdentifier(0) };
+        let ack = FrameHeader { flag: Flag::ack(), .. ping };
+
+        assert_eq!(guard.recv_frame(&ping, now), Ok(()));
+        assert_eq!(guard.recv_frame(&ack, now), Ok(()));
+        assert_eq!(guard.recv_frame(&ping, now), Ok(()));
+        assert_eq!(guard.recv_frame(&ping, now), Err(Flood::Ping));
+    }
+
+    #[test]
+    fn test_stream_churn() {
+        let mut guard = FloodGuard::new(FloodConfig { max_new_streams_per_second: 2, .. FloodConfig::default() });
+        let now = Instant::now();
+        let headers = |id| FrameHeader { length: 1, kind: Kind::Headers, flag: Flag::end_headers(), id: StreamIdentifier(id) };
+
+        assert_eq!(guard.recv_frame(&headers(1), now), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(3), now), Ok(()));
+        // Trailers, and a push's response, don't open streams.
+        assert_eq!(guard.recv_frame(&headers(3), now), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(2), now), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(5), now), Err(Flood::StreamChurn));
+        assert_eq!(guard.recv_frame(&headers(7), now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn test_rapid_reset() {
+        let mut guard = FloodGuard::new(FloodConfig { max_rapid_resets_per_second: 1, .. FloodConfig::default() });
+        let now = Instant::now();
+        let headers = |id| FrameHeader { length: 1, kind: Kind::Headers, flag: Flag::end_headers(), id: StreamIdentifier(id) };
+        let reset = |id| FrameHeader { length: 4, kind: Kind::Reset, flag: Flag::empty(), id: StreamIdentifier(id) };
+
+        assert_eq!(guard.recv_frame(&headers(1), now), Ok(()));
+        assert_eq!(guard.recv_frame(&reset(1), now), Ok(()));
+        // Reset twice, or a second after the HEADERS, doesn't count.
+        assert_eq!(guard.recv_frame(&reset(1), now), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(3), now), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(5), now + Duration::from_millis(500)), Ok(()));
+        assert_eq!(guard.recv_frame(&reset(3), now + Duration::from_secs(1)), Ok(()));
+        assert_eq!(guard.recv_frame(&reset(5), now + Duration::from_millis(1200)), Ok(()));
+        assert_eq!(guard.recv_frame(&headers(7), now + Duration::from_millis(1300)), Ok(()));
+        assert_eq!(guard.recv_frame(&reset(7), now + Duration::from_millis(1400)), Err(Flood::RapidReset));
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Flow control windows (RFC 7540 section 6.9). Every stream has one for each direction, and
+//! so has the connection; DATA, padding included, takes from both the stream's and the
+//! connection's, and WINDOW_UPDATE gives back to one of them.
+
+use std::cmp;
+use std::i32;
+
+/// The largest a window may grow (RFC 7540 section 6.9.1).
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// A flow control window. It is signed: lowering SETTINGS_INITIAL_WINDOW_SIZE can take the
+/// windows of open streams below zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Window(i32);
+
+impl Window {
+    pub fn new(size: u32) -> Window {
+        Window(size as i32)
+    }
+
+    pub fn size(&self) -> i32 {
+        self.0
+    }
+
+    /// The octets of DATA the window allows now, 0 while it is below zero.
+    pub fn available(&self) -> u32 {
+        if self.0 < 0 { 0 } else { self.0 as u32 }
+    }
+
+    /// Adds a WINDOW_UPDATE's increment. Returns false, leaving the window as it was, if it
+    /// would take the window over `MAX_WINDOW_SIZE`: a FLOW_CONTROL_ERROR.
+    pub fn increase(&mut self, increment: u32) -> bool {
+        let size = self.0 as i64 + increment as i64;
+        if size > MAX_WINDOW_SIZE as i64 {
+            return false;
+        }
+        self.0 = size as i32;
+        true
+    }
+
+    /// Moves the window by the change of a SETTINGS_INITIAL_WINDOW_SIZE, saturating below
+    /// zero. Returns false, leaving the window as it was, if it would go over
+    /// `MAX_WINDOW_SIZE`.
+    pub fn adjust(&mut self, delta: i64) -> bool {
+        let size = self.0 as i64 + delta;
+        if size > MAX_WINDOW_SIZE as i64 {
+            return false;
+        }
+        self.0 = cmp::max(size, i32::MIN as i64) as i32;
+        true
+    }
+
+    /// Takes `len` octets of DATA from the window. Returns false, leaving the window as it
+    /// was, if it doesn't allow that many.
+    pub fn consume(&mut self, len: u32) -> bool {
+        if len > self.available() {
+            return false;
+        }
+        self.0 -= len as i32;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::i32;
+
+    use super::{Window, MAX_WINDOW_SIZE};
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::new(100);
+        assert!(window.consume(60));
+        assert!(!window.consume(41));
+        assert_eq!(window.available(), 40);
+        assert!(window.consume(40));
+        assert_eq!(window.available(), 0);
+        assert!(window.increase(MAX_WINDOW_SIZE));
+        assert!(!window.increase(1));
+        assert_eq!(window.size(), MAX_WINDOW_SIZE as i32);
+        assert!(window.adjust(-(MAX_WINDOW_SIZE as i64) - 5));
+        assert_eq!(window.available(), 0);
+        assert!(!window.consume(1));
+        assert!(!window.adjust(MAX_WINDOW_SIZE as i64 + 6));
+        assert!(window.adjust(-(1 << 40)));
+        assert_eq!(window.size(), i32::MIN);
+    }
+}
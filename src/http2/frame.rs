@@ -14,6 +14,8 @@
 
 //! NB: This code is changing so please do not depend on it at this time!
 
+use std::io;
+
 use http2::kind::*;
 use http2::flag::*;
 use http2::payload::*;
@@ -32,7 +34,78 @@ pub struct Frame<'a> {
 }
 
 impl<'a> Frame<'a> {
+    /// A frame carrying `payload` on stream `id`. The kind and length come from the payload, and
+    /// so does the PRIORITY flag of HEADERS.
+    pub fn new(flag: Flag, id: StreamIdentifier, payload: Payload<'a>) -> Frame<'a> {
+        let mut flag = flag;
+        if let Payload::Headers { priority: Some(_), .. } = payload {
+            flag.insert(Flag::priority());
+        }
+        Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: payload.kind(),
+                flag: flag,
+                id: id,
+            },
+            payload: payload,
+        }
+    }
+
+    pub fn data(flags: DataFlags, id: StreamIdentifier, data: &'a [u8]) -> Frame<'a> {
+        Frame::new(flags.into(), id, Payload::Data { data: data })
+    }
+
+    pub fn headers(flags: HeadersFlags, id: StreamIdentifier, priority: Option<Priority>, block: &'a [u8]) -> Frame<'a> {
+        Frame::new(flags.into(), id, Payload::Headers { priority: priority, block: block })
+    }
+
+    pub fn settings(flags: SettingsFlags, settings: &'a [Setting]) -> Frame<'a> {
+        Frame::new(flags.into(), StreamIdentifier(0), Payload::Settings(settings))
+    }
+
+    pub fn ping(flags: PingFlags, data: u64) -> Frame<'a> {
+        Frame::new(flags.into(), StreamIdentifier(0), Payload::Ping(data))
+    }
+
+    pub fn push_promise(flags: PushPromiseFlags, id: StreamIdentifier, promised: StreamIdentifier,
+                        block: &'a [u8]) -> Frame<'a> {
+        Frame::new(flags.into(), id, Payload::PushPromise { promised: promised, block: block })
+    }
+
+    pub fn continuation(flags: ContinuationFlags, id: StreamIdentifier, block: &'a [u8]) -> Frame<'a> {
+        Frame::new(flags.into(), id, Payload::Continuation(block))
+    }
+
+    pub fn reset(id: StreamIdentifier, code: ErrorCode) -> Frame<'a> {
+        Frame::new(Flag::empty(), id, Payload::Reset(code))
+    }
+
+    pub fn goaway(last: StreamIdentifier, error: ErrorCode, data: &'a [u8]) -> Frame<'a> {
+        Frame::new(Flag::empty(), StreamIdentifier(0), Payload::GoAway { last: last, error: error, data: data })
+    }
+
+    pub fn window_update(id: StreamIdentifier, increment: SizeIncrement) -> Frame<'a> {
+        Frame::new(Flag::empty(), id, Payload::WindowUpdate(increment))
+    }
+
+    pub fn priority_update(prioritized: StreamIdentifier, value: &'a [u8]) -> Frame<'a> {
+        let payload = Payload::PriorityUpdate { prioritized: prioritized, value: value };
+        Frame::new(Flag::empty(), StreamIdentifier(0), payload)
+    }
+
+    /// Parses the frame at the start of `buf`, which must hold all of it: it takes
+    /// `FRAME_HEADER_BYTES + header.length` octets.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<Frame<'a>, Error> {
+        let header = try!(FrameHeader::parse(buf));
+        Frame::parse(header, &buf[FRAME_HEADER_BYTES..])
+    }
+
+    /// Parses the payload of a frame with `header`, whose flags without a meaning on its type
+    /// are dropped as `FrameHeader::parse` does, in case it was built by hand.
     pub fn parse(header: FrameHeader, buf: &[u8]) -> Result<Frame, Error> {
+        let mut header = header;
+        header.flag = header.flag & Flag::defined_for(header.kind);
         Ok(Frame {
             header: header,
             payload: try!(Payload::parse(header, buf))
@@ -49,6 +122,36 @@ impl<'a> Frame<'a> {
     pub fn encoded_len(&self) -> usize {
         FRAME_HEADER_BYTES + self.payload.encoded_len()
     }
+
+    /// Writes this Frame out. Unlike `encode`, the length written is that of the payload, whatever
+    /// the header says.
+    pub fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buf = vec![0; self.encoded_len()];
+        let mut header = self.header;
+        header.length = (buf.len() - FRAME_HEADER_BYTES) as u32;
+        header.encode(&mut buf);
+        self.payload.encode(&mut buf[FRAME_HEADER_BYTES..]);
+        writer.write_all(&buf)
+    }
+
+    /// Writes this Frame out like `serialize_into`, but with the PADDED flag and `pad_length`
+    /// octets of padding if it is a DATA, HEADERS or PUSH_PROMISE frame. The padding and the
+    /// pad length octet count against flow control and SETTINGS_MAX_FRAME_SIZE; see `Padding`.
+    pub fn serialize_padded_into<W: io::Write>(&self, pad_length: u8, writer: &mut W) -> io::Result<()> {
+        match self.payload.kind() {
+            Kind::Data | Kind::Headers | Kind::PushPromise => {},
+            _ => return self.serialize_into(writer),
+        }
+        let mut buf = vec![0; self.encoded_len() + 1 + pad_length as usize];
+        let mut header = self.header;
+        header.length = (buf.len() - FRAME_HEADER_BYTES) as u32;
+        header.flag.insert(Flag::padded());
+        header.encode(&mut buf);
+        buf[FRAME_HEADER_BYTES] = pad_length;
+        // What follows the payload is already zeros.
+        self.payload.encode(&mut buf[FRAME_HEADER_BYTES + 1..]);
+        writer.write_all(&buf)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -66,14 +169,45 @@ impl FrameHeader {
             return Err(Error::Short);
         }
 
+        let kind = Kind::new(buf[3]);
         Ok(FrameHeader {
             length: ((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | buf[2] as u32,
-            kind: Kind::new(buf[3]),
-            flag: try!(Flag::new(buf[4]).map_err(|()| { Error::BadFlag(buf[4]) })),
+            kind: kind,
+            // Flags without defined semantics must be ignored (RFC 7540 section 4.1).
+            flag: Flag::from_bits_truncate(buf[4]) & Flag::defined_for(kind),
             id: StreamIdentifier::parse(&buf[5..])
         })
     }
 
+    /// Checks the stream identifier and, for the frame types with a fixed size, the length.
+    /// `Payload::parse` does, before anything else.
+    pub fn validate(&self) -> Result<(), Error> {
+        let on_stream = self.id.0 != 0;
+        let id_allowed = match self.kind {
+            Kind::Data | Kind::Headers | Kind::Priority | Kind::Reset |
+            Kind::PushPromise | Kind::Continuation => on_stream,
+            Kind::Settings | Kind::Ping | Kind::GoAway | Kind::PriorityUpdate => !on_stream,
+            Kind::WindowUpdate | Kind::AltSvc | Kind::Unregistered(_) => true,
+            // On a stream it is ignored rather than an error (RFC 8336 section 2.1).
+            Kind::Origin => true,
+        };
+        if !id_allowed {
+            return Err(Error::InvalidStreamId);
+        }
+
+        let length = match self.kind {
+            Kind::Priority => Some(5),
+            Kind::Reset | Kind::WindowUpdate => Some(4),
+            Kind::Ping => Some(8),
+            Kind::Settings if self.flag.contains(Flag::ack()) => Some(0),
+            _ => None,
+        };
+        if length.map_or(false, |length| length != self.length) {
+            return Err(Error::InvalidPayloadLength);
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn encode(&self, buf: &mut [u8]) {
         encode_u24(buf, self.length);
@@ -82,3 +216,117 @@ impl FrameHeader {
         self.id.encode(&mut buf[5..]);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::{DataFlags, Flag, HeadersFlags, PingFlags, SettingsFlags};
+    use http2::kind::Kind;
+    use http2::payload::{Payload, Setting, SettingIdentifier};
+    use http2::{Error, ErrorCode, SizeIncrement, StreamIdentifier};
+
+    use super::Frame;
+
+    fn round_trip(frame: Frame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.serialize_into(&mut buf).unwrap();
+        assert_eq!(Frame::from_bytes(&buf), Ok(frame));
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let settings = [Setting::new(SettingIdentifier::EnablePush, 0), Setting::unregistered(0x0a0a, 7)];
+        let buf = round_trip(Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Settings(&settings)));
+        assert_eq!(buf, vec![0, 0, 12, 4, 0, 0, 0, 0, 0,
+                             0, 2, 0, 0, 0, 0, 0x0a, 0x0a, 0, 0, 0, 7]);
+
+        let buf = round_trip(Frame::new(Flag::end_stream(), StreamIdentifier(1), Payload::Data { data: b"hello" }));
+        assert_eq!(&buf[..9], &[0, 0, 5, 0, 1, 0, 0, 0, 1]);
+        round_trip(Frame::new(Flag::end_headers(), StreamIdentifier(3), Payload::Headers { priority: None, block: &[0x82] }));
+        round_trip(Frame::new(Flag::empty(), StreamIdentifier(3), Payload::Reset(ErrorCode::Cancel)));
+        round_trip(Frame::new(Flag::end_headers(), StreamIdentifier(1),
+                              Payload::PushPromise { promised: StreamIdentifier(2), block: &[0x82] }));
+        round_trip(Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(0x0102030405060708)));
+        round_trip(Frame::new(Flag::empty(), StreamIdentifier(0),
+                              Payload::GoAway { last: StreamIdentifier(5), error: ErrorCode::ProtocolError, data: b"bye" }));
+        round_trip(Frame::new(Flag::empty(), StreamIdentifier(0), Payload::WindowUpdate(SizeIncrement(1 << 20))));
+        round_trip(Frame::new(Flag::end_headers(), StreamIdentifier(3), Payload::Continuation(&[0x84])));
+
+        // PRIORITY, exclusive on stream 1 with weight 16, and the same in a HEADERS frame.
+        let buf = [0, 0, 5, 2, 0, 0, 0, 0, 3, 0x80, 0, 0, 1, 15];
+        let frame = Frame::from_bytes(&buf).unwrap();
+        assert_eq!(round_trip(frame), buf.to_vec());
+        let priority = *frame.payload.priority().unwrap();
+        let frame = Frame::new(Flag::end_headers(), StreamIdentifier(3), Payload::Headers { priority: Some(priority), block: &[0x82] });
+        assert!(frame.header.flag.contains(Flag::priority()));
+        round_trip(frame);
+    }
+
+    #[test]
+    fn test_padding() {
+        let frame = Frame::new(Flag::end_stream(), StreamIdentifier(1), Payload::Data { data: b"hello" });
+        let mut buf = Vec::new();
+        frame.serialize_padded_into(3, &mut buf).unwrap();
+        assert_eq!(buf, vec![0, 0, 9, 0, 0x9, 0, 0, 0, 1, 3, b'h', b'e', b'l', b'l', b'o', 0, 0, 0]);
+        let parsed = Frame::from_bytes(&buf).unwrap();
+        assert_eq!(parsed.payload, frame.payload);
+        assert_eq!(parsed.header.length, 9);
+
+        // Not a frame that can be padded.
+        let ping = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(1));
+        buf.clear();
+        ping.serialize_padded_into(3, &mut buf).unwrap();
+        assert_eq!(Frame::from_bytes(&buf), Ok(ping));
+
+        // As much padding as there is payload, and padding eating into the priority fields.
+        assert_eq!(Frame::from_bytes(&[0, 0, 2, 0, 0x8, 0, 0, 0, 1, 2, 0]), Err(Error::TooMuchPadding(2)));
+        assert_eq!(Frame::from_bytes(&[0, 0, 2, 0, 0x8, 0, 0, 0, 1, 1, 0]).unwrap().payload, Payload::Data { data: b"" });
+        assert_eq!(Frame::from_bytes(&[0, 0, 7, 1, 0x28, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0]),
+                   Err(Error::PayloadLengthTooShort));
+    }
+
+    #[test]
+    fn test_validation() {
+        // Undefined flags are dropped: PADDED on a PING.
+        let frame = Frame::from_bytes(&[0, 0, 8, 6, 0x9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]).unwrap();
+        assert_eq!(frame.header.flag, Flag::ack());
+
+        assert_eq!(Frame::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0, 0]), Err(Error::InvalidStreamId));
+        assert_eq!(Frame::from_bytes(&[0, 0, 0, 4, 0, 0, 0, 0, 1]), Err(Error::InvalidStreamId));
+        assert_eq!(Frame::from_bytes(&[0, 0, 4, 2, 0, 0, 0, 0, 1, 0, 0, 0, 0]), Err(Error::InvalidPayloadLength));
+        assert_eq!(Frame::from_bytes(&[0, 0, 6, 4, 1, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]), Err(Error::InvalidPayloadLength));
+        assert_eq!(Frame::from_bytes(&[0, 0, 5, 3, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]), Err(Error::InvalidPayloadLength));
+        assert_eq!(Frame::from_bytes(&[0, 0, 4, 0, 0, 0, 0, 0, 1, 0]), Err(Error::Short));
+        // Unknown types go through, whatever the stream.
+        assert_eq!(Frame::from_bytes(&[0, 0, 1, 0xf0, 0, 0, 0, 0, 0, 7]).unwrap().header.kind, Kind::Unregistered(0xf0));
+    }
+
+    #[test]
+    fn test_typed_flags() {
+        let headers = Frame::headers(HeadersFlags::end_stream() | HeadersFlags::end_headers(), StreamIdentifier(1), None, &[0x82]);
+        assert_eq!(headers.header.flag, Flag::end_stream() | Flag::end_headers());
+        assert_eq!(Frame::data(DataFlags::empty(), StreamIdentifier(1), b"hi").header.flag, Flag::empty());
+        let ping = Frame::ping(PingFlags::ack(), 1);
+        assert_eq!((ping.header.kind, ping.header.id), (Kind::Ping, StreamIdentifier(0)));
+        assert_eq!(round_trip(Frame::settings(SettingsFlags::ack(), &[]))[4], 0x1);
+
+        // On receipt, only the flags of the frame type are kept: END_HEADERS and PRIORITY go.
+        let flag = Flag::from_bits_truncate(0x25);
+        assert_eq!(DataFlags::from_flag(flag), DataFlags::end_stream());
+        assert_eq!(HeadersFlags::from_flag(flag), HeadersFlags::all());
+        assert_eq!(HeadersFlags::all().bits(), 0x5);
+
+        // Frames read keep only the flags of their type, as SETTINGS with all of them set.
+        let settings = Frame::from_bytes(&[0, 0, 0, 4, 0xff, 0, 0, 0, 0]).unwrap();
+        assert_eq!(settings.header.flag, Flag::ack());
+        let mut header = Frame::data(DataFlags::empty(), StreamIdentifier(1), b"hi").header;
+        header.flag = Flag::from_bits_truncate(0x25);
+        assert_eq!(Frame::parse(header, b"hi").unwrap().header.flag, Flag::end_stream());
+
+        let reset = Frame::reset(StreamIdentifier(1), ErrorCode::Cancel);
+        assert_eq!((reset.header.kind, reset.payload), (Kind::Reset, Payload::Reset(ErrorCode::Cancel)));
+        assert_eq!(round_trip(Frame::window_update(StreamIdentifier(0), SizeIncrement(1)))[..4], [0, 0, 4, 8]);
+        let goaway = Frame::goaway(StreamIdentifier(3), ErrorCode::NoError, b"bye");
+        assert_eq!((goaway.header.id, goaway.header.length), (StreamIdentifier(0), 11));
+    }
+}
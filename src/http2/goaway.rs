@@ -0,0 +1,130 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! GOAWAY frames that own their debug data, so that the application can give a reason when it
+//! closes a connection (`GoAway::new(last, NO_ERROR).reason("restarting")`) and read the one
+//! the peer gave.
+
+use std::fmt;
+
+use http2::flag::Flag;
+use http2::frame::Frame;
+use http2::parser::DEFAULT_MAX_FRAME_SIZE;
+use http2::payload::Payload;
+use http2::{ErrorCode, StreamIdentifier};
+
+/// The last stream id and error code of a GOAWAY take 8 octets of its payload.
+const GOAWAY_FIXED_BYTES: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GoAway {
+    /// The highest stream id the sender may have processed (or will process).
+    pub last: StreamIdentifier,
+    pub error: ErrorCode,
+    /// Opaque, for diagnostics only; often a human-readable reason.
+    pub debug_data: Vec<u8>,
+}
+
+impl GoAway {
+    pub fn new(last: StreamIdentifier, error: ErrorCode) -> GoAway {
+        GoAway {
+            last: last,
+            error: error,
+            debug_data: Vec::new(),
+        }
+    }
+
+    /// Sets the debug data to `reason`, cut short (at a char boundary) so that the frame fits
+    /// the smallest SETTINGS_MAX_FRAME_SIZE a peer may have.
+    pub fn reason(mut self, reason: &str) -> GoAway {
+        let mut len = DEFAULT_MAX_FRAME_SIZE as usize - GOAWAY_FIXED_BYTES;
+        if reason.len() > len {
+            while !reason.is_char_boundary(len) {
+                len -= 1;
+            }
+        } else {
+            len = reason.len();
+        }
+        self.debug_data = reason.as_bytes()[..len].to_vec();
+        self
+    }
+
+    /// The debug data if it is UTF-8, e.g. a reason set with `reason`.
+    pub fn reason_str(&self) -> Option<&str> {
+        ::std::str::from_utf8(&self.debug_data).ok()
+    }
+
+    /// The GOAWAY of a frame, or `None` for another kind of frame.
+    pub fn from_frame(frame: &Frame) -> Option<GoAway> {
+        match frame.payload {
+            Payload::GoAway { last, error, data } => Some(GoAway {
+                last: last,
+                error: error,
+                debug_data: data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn frame(&self) -> Frame {
+        Frame::goaway(self.last, self.error, &self.debug_data)
+    }
+}
+
+impl fmt::Display for GoAway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "GOAWAY {} after stream {}", self.error, self.last.0));
+        if !self.debug_data.is_empty() {
+            try!(write!(f, ": {}", String::from_utf8_lossy(&self.debug_data)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::frame::Frame;
+    use http2::parser::DEFAULT_MAX_FRAME_SIZE;
+    use http2::{ErrorCode, StreamIdentifier, NO_ERROR};
+
+    use super::GoAway;
+
+    #[test]
+    fn test_goaway() {
+        let goaway = GoAway::new(StreamIdentifier(7), NO_ERROR).reason("restarting");
+        let mut buf = Vec::new();
+        goaway.frame().serialize_into(&mut buf).unwrap();
+        assert_eq!(buf, [&[0, 0, 18, 7, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0][..], b"restarting"].concat());
+
+        let parsed = GoAway::from_frame(&Frame::from_bytes(&buf).unwrap()).unwrap();
+        assert_eq!(parsed, goaway);
+        assert_eq!(parsed.reason_str(), Some("restarting"));
+        assert_eq!(parsed.to_string(), "GOAWAY NO_ERROR after stream 7: restarting");
+
+        // Unknown codes survive the round trip.
+        buf[16] = 0xff;
+        let parsed = GoAway::from_frame(&Frame::from_bytes(&buf).unwrap()).unwrap();
+        assert_eq!(parsed.error, ErrorCode::Unknown(0xff));
+        assert_eq!(u32::from(parsed.error), 0xff);
+        assert_eq!(ErrorCode::from(0xd), ErrorCode::Http11Required);
+
+        // Cut short at a char boundary: 16376 octets is not a whole number of "€".
+        let long = "€".repeat(DEFAULT_MAX_FRAME_SIZE as usize);
+        let goaway = GoAway::new(StreamIdentifier(0), ErrorCode::EnhanceYourCalm).reason(&long);
+        assert_eq!(goaway.debug_data.len(), DEFAULT_MAX_FRAME_SIZE as usize - 10);
+        assert!(goaway.reason_str().is_some());
+    }
+}
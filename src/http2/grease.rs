@@ -0,0 +1,136 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! GREASE for HTTP/2 (draft-bishop-httpbis-grease). Peers are required to ignore unknown setting
+//! identifiers and frame types, but that requirement only holds if it is exercised. An endpoint
+//! with `Grease` enabled adds a reserved setting to its SETTINGS frames and sends the occasional
+//! reserved extension frame so that intolerant implementations are found early.
+
+use rand::{self, Rng};
+
+use http2::FRAME_HEADER_BYTES;
+use http2::StreamIdentifier;
+use http2::encode_u24;
+use http2::payload::Setting;
+
+/// Largest payload of a grease frame.
+pub const MAX_GREASE_PAYLOAD: usize = 16;
+
+/// Whether `identifier` is a reserved grease setting identifier (`0x?a?a`).
+#[inline]
+pub fn is_grease_setting(identifier: u16) -> bool {
+    identifier & 0x0f0f == 0x0a0a
+}
+
+/// Whether `kind` is a reserved grease frame type (`0x0b + 0x1f * N`).
+#[inline]
+pub fn is_grease_frame_type(kind: u8) -> bool {
+    kind >= 0x0b && (kind - 0x0b) % 0x1f == 0
+}
+
+/// What to grease. Everything is off by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Grease {
+    /// Add a reserved setting with a random value to every SETTINGS frame sent.
+    pub settings: bool,
+    /// Send a reserved extension frame with random flags and payload after the preface.
+    pub frames: bool,
+}
+
+impl Grease {
+    /// Greases both settings and frames.
+    pub fn enabled() -> Grease {
+        Grease { settings: true, frames: true }
+    }
+
+    /// A random grease setting to append to an outgoing SETTINGS frame, if enabled.
+    pub fn setting(&self) -> Option<Setting> {
+        if !self.settings {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let n = rng.gen_range(0u16, 16);
+        Some(Setting::unregistered(0x0a0a | (n << 12) | (n << 4), rng.gen()))
+    }
+
+    /// Encodes a random grease frame on stream 0 into `buf` and returns the number of bytes
+    /// written, or 0 if frames are not greased. `buf` must hold at least
+    /// `FRAME_HEADER_BYTES + MAX_GREASE_PAYLOAD` bytes.
+    pub fn encode_frame(&self, buf: &mut [u8]) -> usize {
+        if !self.frames {
+            return 0;
+        }
+        let mut rng = rand::thread_rng();
+        let length = rng.gen_range(0, MAX_GREASE_PAYLOAD + 1);
+
+        encode_u24(buf, length as u32);
+        buf[3] = 0x0b + 0x1f * rng.gen_range(0u8, 8);
+        buf[4] = rng.gen();
+        StreamIdentifier(0).encode(&mut buf[5..]);
+        rng.fill_bytes(&mut buf[FRAME_HEADER_BYTES..FRAME_HEADER_BYTES + length]);
+
+        FRAME_HEADER_BYTES + length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use http2::flood::FloodGuard;
+    use http2::frame::{Frame, FrameHeader};
+    use http2::kind::Kind;
+    use http2::payload::Payload;
+    use http2::FRAME_HEADER_BYTES;
+    use super::{Grease, MAX_GREASE_PAYLOAD, is_grease_frame_type, is_grease_setting};
+
+    #[test]
+    fn test_grease_frames_are_ignored() {
+        let grease = Grease::enabled();
+        let mut guard = FloodGuard::default();
+        let mut buf = [0u8; FRAME_HEADER_BYTES + MAX_GREASE_PAYLOAD];
+
+        for _ in 0..64 {
+            let len = grease.encode_frame(&mut buf);
+            assert!(is_grease_frame_type(buf[3]));
+
+            let header = FrameHeader::parse(&buf).unwrap();
+            assert_eq!(header.kind, Kind::Unregistered(buf[3]));
+            assert_eq!(header.length as usize + FRAME_HEADER_BYTES, len);
+
+            let frame = Frame::parse(header, &buf[FRAME_HEADER_BYTES..]).unwrap();
+            assert_eq!(frame.payload, Payload::Unregistered { kind: buf[3], data: &buf[FRAME_HEADER_BYTES..len] });
+            // Random flags included.
+            assert_eq!(header.flag.bits(), buf[4]);
+            assert_eq!(guard.recv_frame(&header, Instant::now()), Ok(()));
+        }
+
+        assert_eq!(Grease::default().encode_frame(&mut buf), 0);
+    }
+
+    #[test]
+    fn test_grease_settings_are_ignored() {
+        for _ in 0..64 {
+            let setting = Grease::enabled().setting().unwrap();
+            assert!(is_grease_setting(setting.raw_identifier()));
+            assert_eq!(setting.identifier(), None);
+        }
+
+        assert!(Grease::default().setting().is_none());
+        assert!(!is_grease_setting(0x4));
+        assert!(!is_grease_frame_type(0x9));
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! A single deadline covering TLS negotiation, receipt of the client preface and the first
+//! SETTINGS exchange. Clients that connect and go silent would otherwise hold a socket forever;
+//! once `Handshake::check` fails the connection should be dropped and counted. A `Connection`
+//! starts one, past TLS, with its `preface` (see `Connection::check_handshake`).
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use http2::ErrorCode;
+use http2::SETTINGS_TIMEOUT;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// Time allowed from accept until the SETTINGS exchange has completed in both directions.
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> HandshakeConfig {
+        HandshakeConfig { timeout: Duration::from_secs(10) }
+    }
+}
+
+/// What the handshake is waiting for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// TLS negotiation.
+    Tls,
+    /// The client connection preface.
+    Preface,
+    /// The peer's first SETTINGS frame and/or the ACK of ours.
+    Settings,
+    /// Done; the deadline no longer applies.
+    Complete,
+}
+
+/// The handshake did not complete in time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HandshakeTimeout {
+    /// The phase the handshake was stuck in.
+    pub phase: Phase,
+}
+
+impl HandshakeTimeout {
+    /// The GOAWAY error code, if the connection got far enough to send one.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self.phase {
+            Phase::Settings => Some(SETTINGS_TIMEOUT),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.phase {
+            Phase::Tls => "handshake timed out during TLS negotiation",
+            Phase::Preface => "handshake timed out waiting for the preface",
+            Phase::Settings => "handshake timed out during the SETTINGS exchange",
+            Phase::Complete => "handshake completed",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Handshake {
+    deadline: Instant,
+    phase: Phase,
+    settings_received: bool,
+    settings_acked: bool,
+}
+
+impl Handshake {
+    /// Starts the deadline for a connection accepted at `now`. `tls` tells whether TLS has to be
+    /// negotiated before the preface.
+    pub fn new(config: HandshakeConfig, now: Instant, tls: bool) -> Handshake {
+        Handshake {
+            deadline: now + config.timeout,
+            phase: if tls { Phase::Tls } else { Phase::Preface },
+            settings_received: false,
+            settings_acked: false,
+        }
+    }
+
+    pub fn tls_done(&mut self) {
+        if self.phase == Phase::Tls {
+            self.phase = Phase::Preface;
+        }
+    }
+
+    pub fn preface_received(&mut self) {
+        if self.phase == Phase::Preface {
+            self.phase = Phase::Settings;
+            self.advance();
+        }
+    }
+
+    /// The peer's first SETTINGS frame was received.
+    pub fn settings_received(&mut self) {
+        self.settings_received = true;
+        self.advance();
+    }
+
+    /// The peer acknowledged our first SETTINGS frame.
+    pub fn settings_acked(&mut self) {
+        self.settings_acked = true;
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        if self.phase == Phase::Settings && self.settings_received && self.settings_acked {
+            self.phase = Phase::Complete;
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.phase == Phase::Complete
+    }
+
+    /// When the connection's timer should fire next, or `None` once complete.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.is_complete() { None } else { Some(self.deadline) }
+    }
+
+    pub fn check(&self, now: Instant) -> Result<(), HandshakeTimeout> {
+        if !self.is_complete() && now >= self.deadline {
+            return Err(HandshakeTimeout { phase: self.phase });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::SETTINGS_TIMEOUT;
+    use super::{Handshake, HandshakeConfig, HandshakeTimeout, Phase};
+
+    #[test]
+    fn test_handshake_deadline() {
+        let now = Instant::now();
+        let late = now + Duration::from_secs(11);
+
+        let mut handshake = Handshake::new(HandshakeConfig::default(), now, true);
+        assert_eq!(handshake.check(late), Err(HandshakeTimeout { phase: Phase::Tls }));
+        handshake.tls_done();
+        handshake.preface_received();
+        handshake.settings_received();
+        let timeout = handshake.check(late).unwrap_err();
+        assert_eq!(timeout.phase, Phase::Settings);
+        assert_eq!(timeout.error_code(), Some(SETTINGS_TIMEOUT));
+
+        // The ACK may arrive before the peer's own SETTINGS.
+        let mut handshake = Handshake::new(HandshakeConfig::default(), now, false);
+        handshake.preface_received();
+        handshake.settings_acked();
+        assert_eq!(handshake.phase(), Phase::Settings);
+        handshake.settings_received();
+        assert!(handshake.is_complete());
+        assert_eq!(handshake.deadline(), None);
+        assert_eq!(handshake.check(late), Ok(()));
+    }
+}
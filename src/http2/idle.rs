@@ -0,0 +1,122 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Idle connection timeout. Each open connection holds a socket whether or not it carries
+//! streams; one that had none for a while is closed with a GOAWAY NO_ERROR, to bound the
+//! descriptors that clients parked on a server (or a pool holds) can take. The connection tells
+//! an `IdleTimer` when streams open and polls it with the number that are open.
+
+use std::time::{Duration, Instant};
+
+use http2::flag::Flag;
+use http2::frame::Frame;
+use http2::payload::Payload;
+
+#[derive(Copy, Clone, Debug)]
+pub struct IdleTimer {
+    timeout: Duration,
+    /// Whether the peer's PINGs count as activity, which keeps connections it pings to check
+    /// on them open.
+    ping_exempt: bool,
+    /// Since when the connection has had no streams, as of the last `poll`.
+    since: Option<Instant>,
+    /// A stream was opened, or a PING that counts was read, since the last `poll`.
+    active: bool,
+}
+
+impl IdleTimer {
+    /// Times out once the connection had no open streams for `timeout`, and, with
+    /// `ping_exempt`, didn't read a PING from the peer for as long.
+    pub fn new(timeout: Duration, ping_exempt: bool) -> IdleTimer {
+        IdleTimer {
+            timeout: timeout,
+            ping_exempt: ping_exempt,
+            since: None,
+            active: false,
+        }
+    }
+
+    /// Takes in every frame read.
+    pub fn recv(&mut self, frame: &Frame) {
+        if let Payload::Ping(_) = frame.payload {
+            if self.ping_exempt && !frame.header.flag.contains(Flag::ack()) {
+                self.active = true;
+            }
+        }
+    }
+
+    /// A stream was opened, which ends the idle time even if it closed before the next `poll`.
+    pub fn stream_opened(&mut self) {
+        self.active = true;
+    }
+
+    /// Returns whether the connection has been idle for the timeout, with `streams` open at
+    /// `now`. Activity since the last poll counts as being at `now`.
+    pub fn poll(&mut self, now: Instant, streams: usize) -> bool {
+        if streams > 0 {
+            self.since = None;
+        } else if self.active || self.since.is_none() {
+            self.since = Some(now);
+        }
+        self.active = false;
+        self.since.map_or(false, |since| now >= since + self.timeout)
+    }
+
+    /// When `poll` should be called next if nothing opens, or `None` while streams are open.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::IdleTimer;
+
+    #[test]
+    fn test_idle() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let ping = |flag| Frame::new(flag, StreamIdentifier(0), Payload::Ping(1));
+        let mut idle = IdleTimer::new(Duration::from_secs(60), false);
+        assert!(!idle.poll(start, 0));
+        assert_eq!(idle.deadline(), Some(secs(60)));
+        assert!(!idle.poll(secs(30), 1));
+        assert_eq!(idle.deadline(), None);
+
+        // A stream that came and went restarts the clock; PINGs don't.
+        idle.stream_opened();
+        assert!(!idle.poll(secs(40), 0));
+        idle.recv(&ping(Flag::empty()));
+        assert!(!idle.poll(secs(99), 0));
+        assert!(idle.poll(secs(100), 0));
+
+        // Unless they are exempt, and only the peer's.
+        let mut idle = IdleTimer::new(Duration::from_secs(60), true);
+        assert!(!idle.poll(start, 0));
+        idle.recv(&ping(Flag::empty()));
+        assert!(!idle.poll(secs(50), 0));
+        idle.recv(&ping(Flag::ack()));
+        assert!(!idle.poll(secs(80), 0));
+        assert!(idle.poll(secs(110), 0));
+    }
+}
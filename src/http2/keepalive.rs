@@ -0,0 +1,165 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Keepalive PINGs. A connection that has read nothing for a while sends a PING, and is given
+//! up on if the ACK doesn't come back in time: a NAT or a peer that went away without a FIN
+//! would otherwise leave it open forever. The connection hands a `Keepalive` every frame it
+//! reads, tells it when the ACK of its PING came (which its `Pinger` matches) and polls it at
+//! its `deadline`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use http2::flag::PingFlags;
+use http2::frame::Frame;
+
+/// The payload of keepalive PINGs, to tell their ACKs from those of other PINGs.
+pub const KEEPALIVE_PING_PAYLOAD: u64 = 0x6b65_6570_616c_6976;
+
+/// The peer didn't acknowledge a keepalive PING in time: the connection should be closed
+/// without waiting on it any more.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct KeepaliveTimeout {
+    /// How long the PING went without its ACK.
+    pub timeout: Duration,
+}
+
+impl fmt::Display for KeepaliveTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "keepalive PING not acknowledged within {:?}", self.timeout)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Keepalive {
+    /// `None` turns keepalive PINGs off.
+    interval: Option<Duration>,
+    timeout: Duration,
+    /// When a frame was last read, as of the last `poll`; `None` before the first.
+    last_read: Option<Instant>,
+    /// A frame was read since the last `poll`.
+    read: bool,
+    /// When our PING waiting for its ACK was sent.
+    ping_sent: Option<Instant>,
+}
+
+impl Keepalive {
+    /// Sends a PING once nothing was read for `interval`, and times out once it went `timeout`
+    /// without its ACK.
+    pub fn new(interval: Option<Duration>, timeout: Duration) -> Keepalive {
+        Keepalive {
+            interval: interval,
+            timeout: timeout,
+            last_read: None,
+            read: false,
+            ping_sent: None,
+        }
+    }
+
+    /// Takes in every frame read, which puts off the next PING.
+    pub fn recv(&mut self, _frame: &Frame) {
+        self.read = true;
+    }
+
+    /// The ACK of our PING came, which stops the timeout.
+    pub fn acked(&mut self) {
+        self.ping_sent = None;
+    }
+
+    /// Returns a PING to write when the connection has been quiet for the interval, and fails
+    /// once the PING went without its ACK for the timeout. Frames read since the last poll
+    /// count as read at `now`.
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Frame<'static>>, KeepaliveTimeout> {
+        if self.read || self.last_read.is_none() {
+            self.read = false;
+            self.last_read = Some(now);
+        }
+        if let Some(sent) = self.ping_sent {
+            if now >= sent + self.timeout {
+                return Err(KeepaliveTimeout { timeout: self.timeout });
+            }
+            return Ok(None);
+        }
+        match (self.interval, self.last_read) {
+            (Some(interval), Some(last_read)) if now >= last_read + interval => {
+                self.ping_sent = Some(now);
+                Ok(Some(Frame::ping(PingFlags::empty(), KEEPALIVE_PING_PAYLOAD)))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// When `poll` should be called next, or `None` if keepalive is off or not started.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.ping_sent {
+            Some(sent) => Some(sent + self.timeout),
+            None => match (self.interval, self.last_read) {
+                (Some(interval), Some(last_read)) => Some(last_read + interval),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether a PING is waiting for its ACK.
+    pub fn is_pinging(&self) -> bool {
+        self.ping_sent.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::{Keepalive, KeepaliveTimeout, KEEPALIVE_PING_PAYLOAD};
+
+    #[test]
+    fn test_keepalive() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let ping = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(KEEPALIVE_PING_PAYLOAD));
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(KEEPALIVE_PING_PAYLOAD));
+        let data = Frame::new(Flag::empty(), StreamIdentifier(1), Payload::Data { data: b"hi" });
+        let mut keepalive = Keepalive::new(Some(Duration::from_secs(10)), Duration::from_secs(5));
+        assert_eq!(keepalive.poll(start), Ok(None));
+        assert_eq!(keepalive.deadline(), Some(secs(10)));
+
+        // Reading puts the PING off.
+        keepalive.recv(&data);
+        assert_eq!(keepalive.poll(secs(8)), Ok(None));
+        assert_eq!(keepalive.poll(secs(17)), Ok(None));
+        assert_eq!(keepalive.poll(secs(18)), Ok(Some(ping)));
+        assert!(keepalive.is_pinging());
+
+        // Acknowledged in time, then not.
+        keepalive.recv(&ack);
+        keepalive.acked();
+        assert_eq!(keepalive.poll(secs(20)), Ok(None));
+        assert_eq!(keepalive.poll(secs(30)), Ok(Some(ping)));
+        keepalive.recv(&data);
+        assert_eq!(keepalive.poll(secs(34)), Ok(None));
+        assert_eq!(keepalive.deadline(), Some(secs(35)));
+        assert_eq!(keepalive.poll(secs(35)), Err(KeepaliveTimeout { timeout: Duration::from_secs(5) }));
+
+        let mut off = Keepalive::new(None, Duration::from_secs(5));
+        assert_eq!(off.poll(secs(100)), Ok(None));
+        assert_eq!(off.deadline(), None);
+    }
+}
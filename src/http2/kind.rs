@@ -14,20 +14,27 @@
 
 //! NB: This code is changing so please do not depend on it at this time!
 
-#[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Kind {
-    Data = 0,
-    Headers = 1,
-    Priority = 2,
-    Reset = 3,
-    Settings = 4,
-    PushPromise = 5,
-    Ping = 6,
-    GoAway = 7,
-    WindowUpdate = 8,
-    Continuation = 9,
-    Unregistered
+    Data,
+    Headers,
+    Priority,
+    Reset,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    /// RFC 7838.
+    AltSvc,
+    /// RFC 8336.
+    Origin,
+    /// RFC 9218, for request streams.
+    PriorityUpdate,
+    /// A frame type we don't know, e.g. of an extension; such frames must be ignored unless
+    /// something handles them (see `Extensions`).
+    Unregistered(u8)
 }
 
 impl Kind {
@@ -43,7 +50,10 @@ impl Kind {
             7 => Kind::GoAway,
             8 => Kind::WindowUpdate,
             9 => Kind::Continuation,
-            _ => Kind::Unregistered
+            0xa => Kind::AltSvc,
+            0xc => Kind::Origin,
+            0x10 => Kind::PriorityUpdate,
+            kind => Kind::Unregistered(kind)
         }
     }
 
@@ -59,14 +69,40 @@ impl Kind {
             Kind::GoAway => 7,
             Kind::WindowUpdate => 8,
             Kind::Continuation => 9,
-            Kind::Unregistered => 255
+            Kind::AltSvc => 0xa,
+            Kind::Origin => 0xc,
+            Kind::PriorityUpdate => 0x10,
+            Kind::Unregistered(kind) => kind
+        }
+    }
+
+    /// The name the RFCs give the frame type, e.g. `RST_STREAM`, or `UNKNOWN`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Data => "DATA",
+            Kind::Headers => "HEADERS",
+            Kind::Priority => "PRIORITY",
+            Kind::Reset => "RST_STREAM",
+            Kind::Settings => "SETTINGS",
+            Kind::PushPromise => "PUSH_PROMISE",
+            Kind::Ping => "PING",
+            Kind::GoAway => "GOAWAY",
+            Kind::WindowUpdate => "WINDOW_UPDATE",
+            Kind::Continuation => "CONTINUATION",
+            Kind::AltSvc => "ALTSVC",
+            Kind::Origin => "ORIGIN",
+            Kind::PriorityUpdate => "PRIORITY_UPDATE",
+            Kind::Unregistered(_) => "UNKNOWN"
         }
     }
 }
 
 #[test]
 fn test_encode() {
-    for n in 0..10 {
+    for n in 0..11 {
         assert_eq!(Kind::new(n), Kind::new(Kind::new(n).encode()));
     }
+    assert_eq!(Kind::new(0xb).encode(), 0xb);
+    assert_eq!(Kind::new(0xc), Kind::Origin);
+    assert_eq!(Kind::new(0x10).encode(), 0x10);
 }
@@ -23,6 +23,8 @@
 
 pub const FRAME_HEADER_BYTES: usize = 9;
 
+use std::fmt;
+
 use byteorder::ByteOrder;
 use byteorder;
 
@@ -30,12 +32,68 @@ pub mod kind;
 pub mod flag;
 pub mod payload;
 pub mod frame;
+pub mod parser;
+pub mod codec;
+pub mod writer;
+pub mod trace;
+pub mod padding;
+pub mod continuation;
+pub mod settings;
+pub mod ping;
+pub mod keepalive;
+pub mod idle;
+pub mod goaway;
+pub mod window_update;
+pub mod flow;
+pub mod bdp;
+pub mod extension;
+pub mod altsvc;
+pub mod origin;
+pub mod mode;
+pub mod flood;
+pub mod stall;
+pub mod grease;
+pub mod preface;
+pub mod handshake;
+pub mod push;
+pub mod priority;
+pub mod priority_tree;
+pub mod schedule;
+pub mod registry;
+pub mod stats;
+pub mod stream;
+pub mod connection;
+pub mod connect;
 
 use self::kind::*;
 use self::flag::*;
 use self::frame::*;
 use self::payload::*;
 
+pub use self::mode::Mode;
+pub use self::parser::FrameParser;
+pub use self::codec::Http2FrameCodec;
+pub use self::writer::FrameWriter;
+pub use self::trace::TraceHook;
+pub use self::padding::Padding;
+pub use self::settings::{Settings, SettingsTracker};
+pub use self::ping::{Pinger, Rtt};
+pub use self::keepalive::{Keepalive, KeepaliveTimeout};
+pub use self::idle::IdleTimer;
+pub use self::goaway::GoAway;
+pub use self::window_update::{WindowUpdateStrategy, WindowUpdates};
+pub use self::flow::Window;
+pub use self::bdp::{BdpEstimator, BdpWindowUpdates};
+pub use self::extension::Extensions;
+pub use self::altsvc::{AltService, AltSvc};
+pub use self::origin::{OriginFrame, OriginSet};
+pub use self::priority::PriorityUpdate;
+pub use self::priority_tree::PriorityTree;
+pub use self::schedule::{FairScheduler, SendScheduler};
+pub use self::preface::InvalidPreface;
+pub use self::connection::{Connection, Drained, OpenError, PriorityError, Recv, StreamCounts};
+pub use self::flood::{Flood, FloodGuard};
+
 /// Errors that can occur during parsing an HTTP/2 frame.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Error {
@@ -67,7 +125,117 @@ pub enum Error {
 
     /// The payload length specified by the frame header was not the
     /// value necessary for the specific frame type.
-    InvalidPayloadLength
+    ///
+    /// `InvalidPayloadLength` should be treated as a connection error of type FRAME_SIZE_ERROR.
+    InvalidPayloadLength,
+
+    /// The stream identifier was zero on a frame type that belongs to a stream, or not zero on
+    /// one that belongs to the connection.
+    ///
+    /// `InvalidStreamId` should be treated as a connection error of type PROTOCOL_ERROR.
+    InvalidStreamId,
+
+    /// The frame header announced a payload longer than our SETTINGS_MAX_FRAME_SIZE.
+    ///
+    /// `FrameTooLarge` should be treated as a connection error of type FRAME_SIZE_ERROR.
+    FrameTooLarge(u32),
+
+    /// A CONTINUATION frame did not continue a header block, or another frame came in the
+    /// middle of one (RFC 7540 section 6.10).
+    ///
+    /// `InvalidContinuation` should be treated as a connection error of type PROTOCOL_ERROR.
+    InvalidContinuation,
+
+    /// A header block went over the size allowed by `Reassembler::set_max_block_size`.
+    ///
+    /// `HeaderBlockTooLarge` should be treated as a connection error, e.g. of type
+    /// ENHANCE_YOUR_CALM: the block can't be skipped without decoding it.
+    HeaderBlockTooLarge,
+
+    /// A PUSH_PROMISE was received by a server, while SETTINGS_ENABLE_PUSH was off, on a
+    /// stream that was not open, or promising a stream id that is not even and new.
+    ///
+    /// `InvalidPushPromise` should be treated as a connection error of type PROTOCOL_ERROR.
+    InvalidPushPromise,
+
+    /// A PRIORITY_UPDATE was received by a client (RFC 9218 section 7.1).
+    ///
+    /// `InvalidPriorityUpdate` should be treated as a connection error of type PROTOCOL_ERROR.
+    InvalidPriorityUpdate,
+
+    /// A RST_STREAM was received for a stream in the idle state.
+    ///
+    /// `InvalidReset` should be treated as a connection error of type PROTOCOL_ERROR.
+    InvalidReset,
+
+    /// A frame other than HEADERS or PRIORITY came on an idle stream, or a HEADERS frame on
+    /// a stream the sender may not open: one of ours, or one with an id not above those it
+    /// opened before (RFC 7540 section 5.1.1).
+    ///
+    /// `IdleStream` should be treated as a connection error of type PROTOCOL_ERROR.
+    IdleStream,
+
+    /// A HEADERS or DATA frame came on a stream that was closed, other than one we reset
+    /// recently (whose frames may still be on their way).
+    ///
+    /// `StreamClosed` should be treated as a connection error of type STREAM_CLOSED.
+    StreamClosed,
+
+    /// A SETTINGS frame carried a value out of range for its setting.
+    ///
+    /// `InvalidSetting` should be treated as a connection error of the type it carries:
+    /// FLOW_CONTROL_ERROR for SETTINGS_INITIAL_WINDOW_SIZE, PROTOCOL_ERROR otherwise.
+    InvalidSetting(ErrorCode),
+
+    /// A WINDOW_UPDATE on stream 0 took the connection's send window over 2^31-1 octets, or a
+    /// change of SETTINGS_INITIAL_WINDOW_SIZE took the send window of a stream over it.
+    ///
+    /// `WindowOverflow` should be treated as a connection error of type FLOW_CONTROL_ERROR.
+    WindowOverflow,
+
+    /// A WINDOW_UPDATE on stream 0 had an increment of 0; on other streams that is a stream
+    /// error (RFC 7540 section 6.9).
+    ///
+    /// `ZeroWindowUpdate` should be treated as a connection error of type PROTOCOL_ERROR.
+    ZeroWindowUpdate,
+
+    /// The peer sent more DATA than the connection's receive window allowed.
+    ///
+    /// `WindowOverrun` should be treated as a connection error of type FLOW_CONTROL_ERROR.
+    WindowOverrun,
+
+    /// The client connection preface did not match; see `InvalidPreface` for what the peer
+    /// appears to be.
+    InvalidPreface(InvalidPreface),
+
+    /// The peer sent frames faster than the connection's `FloodGuard` allows.
+    ///
+    /// `Flood` should be treated as a connection error of type ENHANCE_YOUR_CALM.
+    Flood(Flood),
+
+    /// The handler of an extension frame failed.
+    ///
+    /// `Extension` should be treated as a connection error of the type it carries.
+    Extension(ErrorCode),
+}
+
+impl Error {
+    /// The error code of the GOAWAY to answer the error with.
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            Error::Short | Error::PartialSettingLength | Error::InvalidPayloadLength |
+            Error::FrameTooLarge(_) => FRAME_SIZE_ERROR,
+            Error::HeaderBlockTooLarge => ENHANCE_YOUR_CALM,
+            Error::BadFlag(_) | Error::BadKind(_) | Error::TooMuchPadding(_) |
+            Error::PayloadLengthTooShort | Error::InvalidStreamId | Error::InvalidContinuation |
+            Error::InvalidPushPromise | Error::InvalidPriorityUpdate | Error::InvalidReset | Error::IdleStream |
+            Error::ZeroWindowUpdate | Error::InvalidPreface(_) => PROTOCOL_ERROR,
+            Error::StreamClosed => STREAM_CLOSED,
+            Error::WindowOverflow | Error::WindowOverrun => FLOW_CONTROL_ERROR,
+            Error::InvalidSetting(code) | Error::Extension(code) => code,
+            Error::Flood(flood) => flood.error_code(),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -91,8 +259,57 @@ impl StreamIdentifier {
     }
 }
 
+/// The error codes of RST_STREAM and GOAWAY frames (RFC 7540 section 7). Codes we don't know
+/// are kept as `Unknown`, so that they can be reported, and must be treated as INTERNAL_ERROR.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct ErrorCode(pub u32);
+pub enum ErrorCode {
+    /// The condition is not a result of an error, e.g. a graceful shutdown.
+    NoError,
+    /// The endpoint detected an unspecific protocol error.
+    ProtocolError,
+    /// The endpoint encountered an unexpected internal error.
+    InternalError,
+    /// The endpoint detected that its peer violated the flow-control protocol.
+    FlowControlError,
+    /// The endpoint sent a SETTINGS frame but did not receive a response in a timely manner.
+    SettingsTimeout,
+    /// The endpoint received a frame after a stream was half-closed.
+    StreamClosed,
+    /// The endpoint received a frame with an invalid size.
+    FrameSizeError,
+    /// The endpoint refused the stream prior to performing any application processing.
+    RefusedStream,
+    /// The endpoint no longer needs the stream.
+    Cancel,
+    /// The endpoint is unable to maintain the header compression context.
+    CompressionError,
+    /// The connection established in response to a CONNECT request was reset or abnormally
+    /// closed.
+    ConnectError,
+    /// The endpoint detected that its peer is exhibiting a behavior that might be generating
+    /// excessive load.
+    EnhanceYourCalm,
+    /// The underlying transport has properties that do not meet minimum security requirements.
+    InadequateSecurity,
+    /// The endpoint requires that HTTP/1.1 be used instead of HTTP/2.
+    Http11Required,
+    Unknown(u32),
+}
+
+pub const NO_ERROR: ErrorCode = ErrorCode::NoError;
+pub const PROTOCOL_ERROR: ErrorCode = ErrorCode::ProtocolError;
+pub const INTERNAL_ERROR: ErrorCode = ErrorCode::InternalError;
+pub const FLOW_CONTROL_ERROR: ErrorCode = ErrorCode::FlowControlError;
+pub const SETTINGS_TIMEOUT: ErrorCode = ErrorCode::SettingsTimeout;
+pub const STREAM_CLOSED: ErrorCode = ErrorCode::StreamClosed;
+pub const FRAME_SIZE_ERROR: ErrorCode = ErrorCode::FrameSizeError;
+pub const REFUSED_STREAM: ErrorCode = ErrorCode::RefusedStream;
+pub const CANCEL: ErrorCode = ErrorCode::Cancel;
+pub const COMPRESSION_ERROR: ErrorCode = ErrorCode::CompressionError;
+pub const CONNECT_ERROR: ErrorCode = ErrorCode::ConnectError;
+pub const ENHANCE_YOUR_CALM: ErrorCode = ErrorCode::EnhanceYourCalm;
+pub const INADEQUATE_SECURITY: ErrorCode = ErrorCode::InadequateSecurity;
+pub const HTTP_1_1_REQUIRED: ErrorCode = ErrorCode::Http11Required;
 
 pub enum HttpError {
     Protocol,
@@ -102,12 +319,94 @@ pub enum HttpError {
 }
 
 impl ErrorCode {
+    pub fn from_u32(code: u32) -> ErrorCode {
+        match code {
+            0x0 => ErrorCode::NoError,
+            0x1 => ErrorCode::ProtocolError,
+            0x2 => ErrorCode::InternalError,
+            0x3 => ErrorCode::FlowControlError,
+            0x4 => ErrorCode::SettingsTimeout,
+            0x5 => ErrorCode::StreamClosed,
+            0x6 => ErrorCode::FrameSizeError,
+            0x7 => ErrorCode::RefusedStream,
+            0x8 => ErrorCode::Cancel,
+            0x9 => ErrorCode::CompressionError,
+            0xa => ErrorCode::ConnectError,
+            0xb => ErrorCode::EnhanceYourCalm,
+            0xc => ErrorCode::InadequateSecurity,
+            0xd => ErrorCode::Http11Required,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            ErrorCode::NoError => 0x0,
+            ErrorCode::ProtocolError => 0x1,
+            ErrorCode::InternalError => 0x2,
+            ErrorCode::FlowControlError => 0x3,
+            ErrorCode::SettingsTimeout => 0x4,
+            ErrorCode::StreamClosed => 0x5,
+            ErrorCode::FrameSizeError => 0x6,
+            ErrorCode::RefusedStream => 0x7,
+            ErrorCode::Cancel => 0x8,
+            ErrorCode::CompressionError => 0x9,
+            ErrorCode::ConnectError => 0xa,
+            ErrorCode::EnhanceYourCalm => 0xb,
+            ErrorCode::InadequateSecurity => 0xc,
+            ErrorCode::Http11Required => 0xd,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    /// The name of the code in RFC 7540, or `None` for an unknown one.
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match *self {
+            ErrorCode::NoError => "NO_ERROR",
+            ErrorCode::ProtocolError => "PROTOCOL_ERROR",
+            ErrorCode::InternalError => "INTERNAL_ERROR",
+            ErrorCode::FlowControlError => "FLOW_CONTROL_ERROR",
+            ErrorCode::SettingsTimeout => "SETTINGS_TIMEOUT",
+            ErrorCode::StreamClosed => "STREAM_CLOSED",
+            ErrorCode::FrameSizeError => "FRAME_SIZE_ERROR",
+            ErrorCode::RefusedStream => "REFUSED_STREAM",
+            ErrorCode::Cancel => "CANCEL",
+            ErrorCode::CompressionError => "COMPRESSION_ERROR",
+            ErrorCode::ConnectError => "CONNECT_ERROR",
+            ErrorCode::EnhanceYourCalm => "ENHANCE_YOUR_CALM",
+            ErrorCode::InadequateSecurity => "INADEQUATE_SECURITY",
+            ErrorCode::Http11Required => "HTTP_1_1_REQUIRED",
+            ErrorCode::Unknown(_) => return None,
+        })
+    }
+
     pub fn parse(buf: &[u8]) -> ErrorCode {
-        ErrorCode(byteorder::BigEndian::read_u32(buf))
+        ErrorCode::from_u32(byteorder::BigEndian::read_u32(buf))
     }
 
     pub fn encode(&self, buf: &mut [u8]) -> usize {
-        encode_u32(buf, self.0)
+        encode_u32(buf, self.as_u32())
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> ErrorCode {
+        ErrorCode::from_u32(code)
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> u32 {
+        code.as_u32()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown error code {:#x}", self.as_u32()),
+        }
     }
 }
 
@@ -116,7 +415,8 @@ pub struct SizeIncrement(pub u32);
 
 impl SizeIncrement {
     pub fn parse(buf: &[u8]) -> SizeIncrement {
-        SizeIncrement(byteorder::BigEndian::read_u32(buf))
+        // The most significant bit is reserved.
+        SizeIncrement(byteorder::BigEndian::read_u32(buf) & ((1 << 31) - 1))
     }
 
     pub fn encode(&self, buf: &mut [u8]) -> usize {
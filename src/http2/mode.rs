@@ -0,0 +1,82 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Connection-level protocol mode. RFC 9113 obsoletes RFC 7540: the priority tree is deprecated
+//! and the `Upgrade: h2c` mechanism is gone. A connection picks one of the two rule sets with
+//! `ConnectionConfig::mode`. The field rules are the decoder's, which checks those of RFC 9113
+//! with `Decoder::set_validate_fields` in either mode: they only spell out what RFC 7540 left
+//! to the endpoints.
+
+use http2::payload::{Payload, Priority};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    /// Original RFC 7540 semantics.
+    Rfc7540,
+    /// RFC 9113 semantics. PRIORITY frames and the priority fields of HEADERS are still parsed
+    /// but ignored unless `priority_compat` is set.
+    Rfc9113 { priority_compat: bool },
+}
+
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode::Rfc9113 { priority_compat: false }
+    }
+}
+
+impl Mode {
+    /// Whether stream dependencies and weights should be acted upon.
+    #[inline]
+    pub fn honours_priority(&self) -> bool {
+        match *self {
+            Mode::Rfc7540 => true,
+            Mode::Rfc9113 { priority_compat } => priority_compat,
+        }
+    }
+
+    /// The priority signal carried by `payload`, or `None` when the mode ignores it.
+    #[inline]
+    pub fn priority<'a>(&self, payload: &'a Payload) -> Option<&'a Priority> {
+        if self.honours_priority() {
+            payload.priority()
+        } else {
+            None
+        }
+    }
+
+    /// RFC 9113 section 3.1 removed the HTTP/1.1 `Upgrade: h2c` mechanism; only prior knowledge
+    /// and ALPN remain.
+    #[inline]
+    pub fn allows_h2c_upgrade(&self) -> bool {
+        match *self {
+            Mode::Rfc7540 => true,
+            Mode::Rfc9113 { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mode;
+
+    #[test]
+    fn test_modes() {
+        assert!(Mode::Rfc7540.allows_h2c_upgrade());
+        assert!(!Mode::default().allows_h2c_upgrade());
+        assert!(!Mode::default().honours_priority());
+        assert!(Mode::Rfc9113 { priority_compat: true }.honours_priority());
+    }
+}
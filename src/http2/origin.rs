@@ -0,0 +1,199 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The ORIGIN frame (RFC 8336). A server lists the origins a connection is authoritative for,
+//! and a client keeps them in the connection's `OriginSet` to decide which requests it may
+//! send on the connection instead of opening another one.
+
+use std::str;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use http2::flag::Flag;
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::StreamIdentifier;
+
+/// An ORIGIN frame that owns its Origin-Entry fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OriginFrame {
+    entries: Vec<u8>,
+}
+
+impl OriginFrame {
+    /// `origins` are ASCII serialized origins, `scheme://host[:port]`.
+    pub fn new(origins: &[&str]) -> OriginFrame {
+        let mut frame = OriginFrame::default();
+        for origin in origins {
+            frame.push(origin);
+        }
+        frame
+    }
+
+    pub fn push(&mut self, origin: &str) {
+        let start = self.entries.len();
+        self.entries.resize(start + 2, 0);
+        BigEndian::write_u16(&mut self.entries[start..], origin.len() as u16);
+        self.entries.extend_from_slice(origin.as_bytes());
+    }
+
+    /// The ORIGIN of a frame, or `None` for another kind of frame and for the ORIGIN frames to
+    /// ignore: on a stream other than 0, or with entries that don't add up to the payload or
+    /// aren't ASCII.
+    pub fn from_frame(frame: &Frame) -> Option<OriginFrame> {
+        match frame.payload {
+            Payload::Origin(entries) if frame.header.id.0 == 0 => {
+                let mut origins = Origins { entries: entries };
+                while let Some(_) = origins.next() {}
+                if origins.entries.is_empty() {
+                    Some(OriginFrame { entries: entries.to_vec() })
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn origins(&self) -> Origins {
+        Origins { entries: &self.entries }
+    }
+
+    pub fn frame(&self) -> Frame {
+        Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Origin(&self.entries))
+    }
+}
+
+/// The origins of an ORIGIN frame. It stops at a malformed entry, leaving it in `entries`.
+pub struct Origins<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> Iterator for Origins<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.entries.len() < 2 {
+            return None;
+        }
+        let len = BigEndian::read_u16(self.entries) as usize;
+        if len > self.entries.len() - 2 {
+            return None;
+        }
+        let origin = &self.entries[2..2 + len];
+        if !origin.iter().all(|&b| b < 0x80) {
+            return None;
+        }
+        self.entries = &self.entries[2 + len..];
+        str::from_utf8(origin).ok()
+    }
+}
+
+/// `origin` lowercased, without the default port of its scheme, so that origins compare equal
+/// however they were written.
+pub fn normalize(origin: &str) -> String {
+    let origin = origin.trim_right_matches('/').to_ascii_lowercase();
+    for &(scheme, port) in &[("https://", ":443"), ("http://", ":80")] {
+        if origin.starts_with(scheme) && origin.ends_with(port) {
+            return origin[..origin.len() - port.len()].to_string();
+        }
+    }
+    origin
+}
+
+/// The origin set of a client connection (RFC 8336 section 2.3): at first the origin it was
+/// opened for, then also every origin of the ORIGIN frames the server sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginSet {
+    origins: Vec<String>,
+    received: bool,
+}
+
+impl OriginSet {
+    pub fn new(origin: &str) -> OriginSet {
+        OriginSet {
+            origins: vec![normalize(origin)],
+            received: false,
+        }
+    }
+
+    /// Takes in an ORIGIN frame from the server.
+    pub fn recv(&mut self, frame: &OriginFrame) {
+        self.received = true;
+        for origin in frame.origins() {
+            let origin = normalize(origin);
+            if !self.origins.contains(&origin) {
+                self.origins.push(origin);
+            }
+        }
+    }
+
+    /// Whether the server sent an ORIGIN frame. Until it does, a client may coalesce as RFC
+    /// 7540 section 9.1.1 allows, i.e. on DNS and the certificate.
+    pub fn received(&self) -> bool {
+        self.received
+    }
+
+    pub fn contains(&self, origin: &str) -> bool {
+        self.origins.contains(&normalize(origin))
+    }
+
+    /// Whether a request for `origin` may use the connection, once an ORIGIN frame arrived.
+    /// `authoritative` is whether the server's certificate is valid for the origin's host,
+    /// which is still required.
+    pub fn may_coalesce(&self, origin: &str, authoritative: bool) -> bool {
+        authoritative && self.contains(origin)
+    }
+
+    pub fn origins(&self) -> &[String] {
+        &self.origins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::{OriginFrame, OriginSet};
+
+    #[test]
+    fn test_origin() {
+        let origin = OriginFrame::new(&["https://a.example", "https://B.example:443"]);
+        let mut buf = Vec::new();
+        origin.frame().serialize_into(&mut buf).unwrap();
+        assert_eq!(&buf[..11], &[0, 0, 42, 0xc, 0, 0, 0, 0, 0, 0, 17]);
+        let parsed = OriginFrame::from_frame(&Frame::from_bytes(&buf).unwrap()).unwrap();
+        assert_eq!(parsed.origins().collect::<Vec<_>>(), vec!["https://a.example", "https://B.example:443"]);
+
+        let mut set = OriginSet::new("https://a.example/");
+        assert!(!set.received() && set.contains("https://a.example:443"));
+        set.recv(&parsed);
+        assert!(set.received());
+        assert!(set.may_coalesce("https://b.example", true));
+        assert!(!set.may_coalesce("https://b.example", false));
+        assert!(!set.may_coalesce("http://b.example", true));
+        assert_eq!(set.origins().len(), 2);
+
+        // Ignored: on a stream, cut short.
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(1), Payload::Origin(&buf[9..]));
+        assert_eq!(OriginFrame::from_frame(&frame), None);
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Origin(&buf[9..30]));
+        assert_eq!(OriginFrame::from_frame(&frame), None);
+    }
+}
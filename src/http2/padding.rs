@@ -0,0 +1,75 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! How much padding frames get, to hide the size of what they carry from traffic analysis
+//! (RFC 7540 section 10.7). Padding costs bandwidth and flow control window like data does.
+
+use std::cmp;
+
+use rand::{self, Rng};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Padding {
+    /// No padding, and no PADDED flag (the default).
+    None,
+    /// This many octets on every frame.
+    Fixed(u8),
+    /// A random number of octets on every frame, from none to this many.
+    Random(u8),
+}
+
+impl Default for Padding {
+    fn default() -> Padding {
+        Padding::None
+    }
+}
+
+impl Padding {
+    /// The pad length for the next frame (see `Frame::serialize_padded_into`), or `None` to send
+    /// it unpadded. `room` is how many octets the frame can grow by without going over
+    /// SETTINGS_MAX_FRAME_SIZE or the flow control window; the pad length octet takes one.
+    pub fn pad_length(&self, room: usize) -> Option<u8> {
+        if room == 0 {
+            return None;
+        }
+        let max = cmp::min(room - 1, u8::max_value() as usize) as u8;
+        match *self {
+            Padding::None => None,
+            Padding::Fixed(len) => Some(cmp::min(len, max)),
+            Padding::Random(len) => {
+                let len = cmp::min(len, max);
+                Some(rand::thread_rng().gen_range(0, len as u16 + 1) as u8)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Padding;
+
+    #[test]
+    fn test_pad_length() {
+        assert_eq!(Padding::default().pad_length(100), None);
+        assert_eq!(Padding::Fixed(16).pad_length(100), Some(16));
+        assert_eq!(Padding::Fixed(16).pad_length(10), Some(9));
+        assert_eq!(Padding::Fixed(16).pad_length(0), None);
+        for _ in 0..100 {
+            assert!(Padding::Random(8).pad_length(100).unwrap() <= 8);
+        }
+        assert_eq!(Padding::Random(255).pad_length(1), Some(0));
+    }
+}
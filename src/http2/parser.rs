@@ -0,0 +1,155 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Incremental frame parsing. Bytes are handed over as they are read from the socket, however
+//! they are split; the parser keeps the frame in progress, header first and then payload, and
+//! hands out each frame once it is complete.
+
+use std::cmp;
+
+use http2::frame::{Frame, FrameHeader};
+use http2::{Error, FRAME_HEADER_BYTES};
+
+/// The initial SETTINGS_MAX_FRAME_SIZE, also the smallest allowed.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+
+/// The largest SETTINGS_MAX_FRAME_SIZE allowed.
+pub const MAX_MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Header,
+    Payload(FrameHeader),
+    /// The frame was handed out; its bytes go once the next one starts.
+    Done,
+}
+
+#[derive(Debug)]
+pub struct FrameParser {
+    max_frame_size: u32,
+    state: State,
+    /// The header or the payload read so far.
+    buf: Vec<u8>,
+}
+
+impl FrameParser {
+    pub fn new() -> FrameParser {
+        FrameParser {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            state: State::Header,
+            buf: Vec::with_capacity(FRAME_HEADER_BYTES),
+        }
+    }
+
+    /// Sets the SETTINGS_MAX_FRAME_SIZE we announced; a longer frame fails with
+    /// `Error::FrameTooLarge` as soon as its header is read.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// The number of octets still missing from the frame header or payload being read, i.e. the
+    /// least the next read should ask for.
+    pub fn needed(&self) -> usize {
+        match self.state {
+            State::Header => FRAME_HEADER_BYTES - self.buf.len(),
+            State::Payload(header) => header.length as usize - self.buf.len(),
+            State::Done => FRAME_HEADER_BYTES,
+        }
+    }
+
+    /// Takes octets off the front of `data` until a frame is complete and returns it, or returns
+    /// `None` after taking all of them. Call it again, until it returns `None`, to get the other
+    /// frames in `data`.
+    ///
+    /// Errors are connection errors (see `Error`): the parser is of no use afterwards.
+    pub fn next_frame<'p>(&'p mut self, data: &mut &[u8]) -> Result<Option<Frame<'p>>, Error> {
+        if self.state == State::Done {
+            self.buf.clear();
+            self.state = State::Header;
+        }
+        loop {
+            let needed = self.needed();
+            let take = cmp::min(needed, data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            *data = &data[take..];
+            if take < needed {
+                return Ok(None);
+            }
+
+            match self.state {
+                State::Header => {
+                    let header = try!(FrameHeader::parse(&self.buf));
+                    if header.length > self.max_frame_size {
+                        return Err(Error::FrameTooLarge(header.length));
+                    }
+                    self.buf.clear();
+                    self.buf.reserve(header.length as usize);
+                    self.state = State::Payload(header);
+                },
+                State::Payload(header) => {
+                    self.state = State::Done;
+                    return Frame::parse(header, &self.buf).map(Some);
+                },
+                State::Done => unreachable!(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::payload::Payload;
+    use http2::{Error, StreamIdentifier};
+
+    use super::FrameParser;
+
+    #[test]
+    fn test_next_frame() {
+        // An empty SETTINGS, then DATA "hi" on stream 1, then the start of a PING.
+        let bytes = [0, 0, 0, 4, 0, 0, 0, 0, 0,
+                     0, 0, 2, 0, 1, 0, 0, 0, 1, b'h', b'i',
+                     0, 0, 8, 6];
+        let mut parser = FrameParser::new();
+        let mut frames = Vec::new();
+        for chunk in bytes.chunks(1) {
+            let mut data = chunk;
+            while let Some(frame) = parser.next_frame(&mut data).unwrap() {
+                frames.push((frame.header.id, format!("{:?}", frame.payload)));
+            }
+            assert!(data.is_empty());
+        }
+        assert_eq!(frames, vec![(StreamIdentifier(0), format!("{:?}", Payload::Settings(&[]))),
+                                (StreamIdentifier(1), format!("{:?}", Payload::Data { data: b"hi" }))]);
+        assert_eq!(parser.needed(), 5);
+
+        // All at once.
+        let mut parser = FrameParser::new();
+        let mut data = &bytes[..];
+        assert!(parser.next_frame(&mut data).unwrap().is_some());
+        assert_eq!(parser.needed(), 9);
+        assert_eq!(parser.next_frame(&mut data).unwrap().map(|f| f.payload), Some(Payload::Data { data: b"hi" }));
+        assert_eq!(parser.next_frame(&mut data), Ok(None));
+        assert!(data.is_empty());
+
+        let mut parser = FrameParser::new();
+        parser.set_max_frame_size(1);
+        assert_eq!(parser.next_frame(&mut &bytes[9..]), Err(Error::FrameTooLarge(2)));
+    }
+}
@@ -53,7 +53,25 @@ pub enum Payload<'a> {
     },
     WindowUpdate(SizeIncrement),
     Continuation(&'a [u8]),
-    Unregistered(&'a [u8])
+    /// An `Alt-Svc` field value, for `origin` on stream 0 and for the origin of the stream
+    /// otherwise.
+    AltSvc {
+        origin: &'a [u8],
+        value: &'a [u8]
+    },
+    /// Origin-Entry fields, each a length and an ASCII origin; see `OriginFrame`.
+    Origin(&'a [u8]),
+    /// The new `priority` field value (see `priority::Priority`) of the request stream
+    /// `prioritized`.
+    PriorityUpdate {
+        prioritized: StreamIdentifier,
+        value: &'a [u8]
+    },
+    /// A frame of a type we don't know, with its type.
+    Unregistered {
+        kind: u8,
+        data: &'a [u8]
+    }
 }
 
 const PRIORITY_BYTES: u32 = 5;
@@ -75,15 +93,24 @@ impl<'a> Payload<'a> {
             GoAway { .. } => Kind::GoAway,
             WindowUpdate(_) => Kind::WindowUpdate,
             Continuation(_) => Kind::Continuation,
-            Unregistered(_) => Kind::Unregistered
+            AltSvc { .. } => Kind::AltSvc,
+            Origin(_) => Kind::Origin,
+            PriorityUpdate { .. } => Kind::PriorityUpdate,
+            Unregistered { kind, .. } => Kind::Unregistered(kind)
         }
     }
 
     #[inline]
     pub fn parse(header: FrameHeader, mut buf: &'a [u8]) -> Result<Payload<'a>, Error> {
+        try!(header.validate());
+
+        // PADDED and PRIORITY only mean something on the frame types that define them.
         let settings = ParserSettings {
-            padding: header.flag.contains(Flag::padded()),
-            priority: header.flag.contains(Flag::priority())
+            padding: header.flag.contains(Flag::padded()) && match header.kind {
+                Kind::Data | Kind::Headers | Kind::PushPromise => true,
+                _ => false,
+            },
+            priority: header.flag.contains(Flag::priority()) && header.kind == Kind::Headers
         };
 
         if buf.len() < header.length as usize {
@@ -121,7 +148,10 @@ impl<'a> Payload<'a> {
             Kind::WindowUpdate => Payload::parse_window_update(header, buf),
             Kind::PushPromise => Payload::parse_push_promise(header, buf, settings),
             Kind::Continuation => Ok(Payload::Continuation(buf)),
-            Kind::Unregistered => Ok(Payload::Unregistered(buf))
+            Kind::AltSvc => Payload::parse_altsvc(header, buf),
+            Kind::Origin => Ok(Payload::Origin(buf)),
+            Kind::PriorityUpdate => Payload::parse_priority_update(header, buf),
+            Kind::Unregistered(kind) => Ok(Payload::Unregistered { kind: kind, data: buf })
         }
     }
 
@@ -155,7 +185,17 @@ impl<'a> Payload<'a> {
             },
             Payload::Priority(ref priority) => { priority.encode(buf) },
             Payload::Continuation(ref block) => { encode_memory(block, buf) },
-            Payload::Unregistered(ref block) => { encode_memory(block, buf) }
+            Payload::AltSvc { ref origin, ref value } => {
+                ::byteorder::BigEndian::write_u16(buf, origin.len() as u16);
+                let origin_wrote = encode_memory(origin, &mut buf[2..]);
+                2 + origin_wrote + encode_memory(value, &mut buf[2 + origin_wrote..])
+            },
+            Payload::Origin(ref entries) => { encode_memory(entries, buf) },
+            Payload::PriorityUpdate { ref prioritized, ref value } => {
+                prioritized.encode(buf);
+                encode_memory(value, &mut buf[4..]) + 4
+            },
+            Payload::Unregistered { ref data, .. } => { encode_memory(data, buf) }
         }
     }
 
@@ -178,7 +218,10 @@ impl<'a> Payload<'a> {
             PushPromise { ref block, .. } => 4 + block.len(),
             Priority(_) => 5,
             Continuation(ref block) => block.len(),
-            Unregistered(ref block) => block.len()
+            AltSvc { ref origin, ref value } => 2 + origin.len() + value.len(),
+            Origin(ref entries) => entries.len(),
+            PriorityUpdate { ref value, .. } => 4 + value.len(),
+            Unregistered { ref data, .. } => data.len()
         }
     }
 
@@ -208,6 +251,9 @@ impl<'a> Payload<'a> {
     fn parse_headers(header: FrameHeader, mut buf: &'a [u8],
                      settings: ParserSettings) -> Result<Payload<'a>, Error> {
         buf = try!(trim_padding(settings, header, buf));
+        if settings.priority && buf.len() < PRIORITY_BYTES as usize {
+            return Err(Error::PayloadLengthTooShort)
+        }
         let (buf, priority) = try!(Priority::parse(settings.priority, buf));
         Ok(Payload::Headers {
             priority: priority,
@@ -264,6 +310,42 @@ impl<'a> Payload<'a> {
         })
     }
 
+    #[inline]
+    fn parse_altsvc(header: FrameHeader,
+                    buf: &'a [u8]) -> Result<Payload<'a>, Error> {
+        if header.length < 2 {
+            return Err(Error::PayloadLengthTooShort)
+        }
+
+        let origin_len = ::byteorder::BigEndian::read_u16(buf) as usize;
+        if buf.len() < 2 + origin_len {
+            return Err(Error::PayloadLengthTooShort)
+        }
+
+        Ok(Payload::AltSvc {
+            origin: &buf[2..2 + origin_len],
+            value: &buf[2 + origin_len..]
+        })
+    }
+
+    #[inline]
+    fn parse_priority_update(header: FrameHeader,
+                             buf: &'a [u8]) -> Result<Payload<'a>, Error> {
+        if header.length < 4 {
+            return Err(Error::InvalidPayloadLength)
+        }
+
+        let prioritized = StreamIdentifier::parse(buf);
+        if prioritized.0 == 0 {
+            return Err(Error::InvalidStreamId)
+        }
+
+        Ok(Payload::PriorityUpdate {
+            prioritized: prioritized,
+            value: &buf[4..]
+        })
+    }
+
     #[inline]
     fn parse_window_update(header: FrameHeader,
                            buf: &'a [u8]) -> Result<Payload<'a>, Error> {
@@ -301,6 +383,33 @@ pub struct Priority {
 }
 
 impl Priority {
+    /// A dependency on stream `dependency` with a weight from 1 to 256 (clamped), sharing the
+    /// parent with its other dependencies unless `exclusive`.
+    pub fn new(exclusive: bool, dependency: StreamIdentifier, weight: u16) -> Priority {
+        Priority {
+            exclusive: exclusive,
+            dependency: dependency,
+            // Sent as the weight minus one.
+            weight: (::std::cmp::max(1, ::std::cmp::min(weight, 256)) - 1) as u8
+        }
+    }
+
+    #[inline]
+    pub fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    #[inline]
+    pub fn dependency(&self) -> StreamIdentifier {
+        self.dependency
+    }
+
+    /// The weight, from 1 to 256.
+    #[inline]
+    pub fn weight(&self) -> u16 {
+        self.weight as u16 + 1
+    }
+
     #[inline]
     pub fn parse(present: bool, buf: &[u8]) -> Result<(&[u8], Option<Priority>), Error> {
         if present {
@@ -327,12 +436,13 @@ impl Priority {
     }
 }
 
-// Settings are (u16, u32) in memory.
-#[repr(packed)]
+// Settings are kept as they are on the wire, a big endian u16 and u32, so that a SETTINGS
+// payload can be read and written in place.
+#[repr(C)]
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Setting {
-    identifier: u16,
-    value: u32
+    identifier: [u8; 2],
+    value: [u8; 4]
 }
 
 impl fmt::Debug for Setting {
@@ -345,27 +455,45 @@ impl fmt::Debug for Setting {
 impl Setting {
     #[inline]
     pub fn new(identifier: SettingIdentifier, value: u32) -> Setting {
-        Setting {
-            identifier: identifier as u16,
-            value: value,
-        }
+        Setting::unregistered(identifier as u16, value)
+    }
+
+    /// A setting with an identifier that is not (necessarily) known to us, e.g. an extension
+    /// or grease setting.
+    #[inline]
+    pub fn unregistered(identifier: u16, value: u32) -> Setting {
+        let mut setting = Setting {
+            identifier: [0; 2],
+            value: [0; 4],
+        };
+        ::byteorder::BigEndian::write_u16(&mut setting.identifier, identifier);
+        ::byteorder::BigEndian::write_u32(&mut setting.value, value);
+        setting
+    }
+
+    #[inline]
+    pub fn raw_identifier(&self) -> u16 {
+        ::byteorder::BigEndian::read_u16(&self.identifier)
     }
 
     #[inline]
     pub fn identifier(&self) -> Option<SettingIdentifier> {
-        match self.identifier {
+        match self.raw_identifier() {
             0x1 => Some(SettingIdentifier::HeaderTableSize),
             0x2 => Some(SettingIdentifier::EnablePush),
             0x3 => Some(SettingIdentifier::MaxConcurrentStreams),
             0x4 => Some(SettingIdentifier::InitialWindowSize),
             0x5 => Some(SettingIdentifier::MaxFrameSize),
+            0x6 => Some(SettingIdentifier::MaxHeaderListSize),
+            0x8 => Some(SettingIdentifier::EnableConnectProtocol),
+            0x9 => Some(SettingIdentifier::NoRfc7540Priorities),
             _ => None
         }
     }
 
     #[inline]
     pub fn value(&self) -> u32 {
-        self.value
+        ::byteorder::BigEndian::read_u32(&self.value)
     }
 
     #[inline]
@@ -394,7 +522,10 @@ pub enum SettingIdentifier {
     EnablePush = 0x2,
     MaxConcurrentStreams = 0x3,
     InitialWindowSize = 0x4,
-    MaxFrameSize = 0x5
+    MaxFrameSize = 0x5,
+    MaxHeaderListSize = 0x6,
+    EnableConnectProtocol = 0x8,
+    NoRfc7540Priorities = 0x9
 }
 
 /*
@@ -410,7 +541,8 @@ fn trim_padding(settings: ParserSettings, header: FrameHeader,
                 buf: &[u8]) -> Result<&[u8], Error> {
     if settings.padding {
         let pad_length = buf[0];
-        if pad_length as u32 > header.length {
+        // The padding must leave room for the pad length octet.
+        if pad_length as u32 >= header.length {
             Err(Error::TooMuchPadding(pad_length))
         } else {
             Ok(&buf[1..header.length as usize - pad_length as usize])
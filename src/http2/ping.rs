@@ -0,0 +1,169 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! PINGs and round-trip time. The connection keeps a `Pinger`, sends the frames `ping` builds
+//! and hands it every PING it reads: a peer's PING comes back as the ACK to write, and an ACK
+//! of one of ours as a round-trip time sample, also folded into the connection's `Rtt`.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use http2::flag::{Flag, PingFlags};
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::StreamIdentifier;
+
+/// The round-trip time as measured with PINGs, `None` until the first ACK.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rtt {
+    pub latest: Option<Duration>,
+    /// Exponentially weighted, as in TCP (RFC 6298): 7/8 of the previous value and 1/8 of the
+    /// new sample.
+    pub smoothed: Option<Duration>,
+    pub min: Option<Duration>,
+}
+
+impl Rtt {
+    fn sample(&mut self, rtt: Duration) {
+        self.latest = Some(rtt);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+        if self.min.map_or(true, |min| rtt < min) {
+            self.min = Some(rtt);
+        }
+    }
+}
+
+/// What to do about a PING that was read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pong {
+    /// The peer's PING: write this ACK, ahead of other frames.
+    Reply(Frame<'static>),
+    /// The ACK of our PING with this payload, after this long.
+    Rtt(u64, Duration),
+    /// An ACK of a PING we didn't send (or gave up on); it can be ignored.
+    Unexpected(u64),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PingConfig {
+    /// PINGs we have waiting for their ACK at most; `ping` forgets the oldest beyond that.
+    pub max_outstanding: usize,
+}
+
+impl Default for PingConfig {
+    fn default() -> PingConfig {
+        PingConfig { max_outstanding: 8 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pinger {
+    max_outstanding: usize,
+    /// Our PINGs waiting for their ACK, oldest first.
+    outstanding: VecDeque<(u64, Instant)>,
+    rtt: Rtt,
+}
+
+impl Pinger {
+    pub fn new(config: PingConfig) -> Pinger {
+        Pinger {
+            max_outstanding: config.max_outstanding,
+            outstanding: VecDeque::new(),
+            rtt: Rtt::default(),
+        }
+    }
+
+    /// Returns a PING with the opaque `payload`, sent at `now`. Payloads should differ between
+    /// PINGs in flight, or an ACK is matched to the oldest PING with its payload.
+    pub fn ping(&mut self, payload: u64, now: Instant) -> Frame<'static> {
+        if self.outstanding.len() >= self.max_outstanding {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back((payload, now));
+        Frame::ping(PingFlags::empty(), payload)
+    }
+
+    /// Takes in a PING frame, with or without ACK, read at `now`; other frames are ignored.
+    pub fn recv(&mut self, frame: &Frame, now: Instant) -> Option<Pong> {
+        let payload = match frame.payload {
+            Payload::Ping(payload) => payload,
+            _ => return None,
+        };
+        if !frame.header.flag.contains(Flag::ack()) {
+            return Some(Pong::Reply(Frame::ping(PingFlags::ack(), payload)));
+        }
+
+        match self.outstanding.iter().position(|&(sent, _)| sent == payload) {
+            Some(i) => {
+                let (_, sent) = self.outstanding.remove(i).unwrap();
+                let rtt = now.duration_since(sent);
+                self.rtt.sample(rtt);
+                Some(Pong::Rtt(payload, rtt))
+            },
+            None => Some(Pong::Unexpected(payload)),
+        }
+    }
+
+    /// The round-trip time so far.
+    pub fn rtt(&self) -> Rtt {
+        self.rtt
+    }
+
+    /// The number of our PINGs waiting for their ACK.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::{PingConfig, Pinger, Pong};
+
+    #[test]
+    fn test_pinger() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let ack = |payload| Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(payload));
+        let mut pinger = Pinger::new(PingConfig { max_outstanding: 2 });
+
+        let peer = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(42));
+        assert_eq!(pinger.recv(&peer, start), Some(Pong::Reply(ack(42))));
+
+        assert_eq!(pinger.ping(1, start).payload, Payload::Ping(1));
+        pinger.ping(2, ms(10));
+        pinger.ping(3, ms(20));
+        assert_eq!(pinger.outstanding(), 2);
+        assert_eq!(pinger.recv(&ack(1), ms(30)), Some(Pong::Unexpected(1)));
+        assert_eq!(pinger.recv(&ack(3), ms(60)), Some(Pong::Rtt(3, Duration::from_millis(40))));
+        assert_eq!(pinger.recv(&ack(2), ms(90)), Some(Pong::Rtt(2, Duration::from_millis(80))));
+
+        let rtt = pinger.rtt();
+        assert_eq!(rtt.latest, Some(Duration::from_millis(80)));
+        assert_eq!(rtt.smoothed, Some(Duration::from_millis(45)));
+        assert_eq!(rtt.min, Some(Duration::from_millis(40)));
+        assert_eq!(pinger.outstanding(), 0);
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The client connection preface (RFC 7540 section 3.5). When it doesn't match, the bytes are
+//! classified so the error (and the log line) says what the peer most likely is, and so a
+//! misconfigured HTTP/1.1 client can be given a hint before the connection is closed.
+//!
+//! A client starts the connection with `client_preface`, a server answers with
+//! `server_preface` and reads the client's with a `PrefaceReader`, which also gives up on
+//! clients that take too long to send it, the SETTINGS frame that ends it included.
+
+use std::cmp;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use http2::flag::SettingsFlags;
+use http2::frame::Frame;
+use http2::settings::Settings;
+
+/// The fixed part of the client connection preface. It is followed by a SETTINGS frame.
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Minimal response sent to an HTTP/1.1 client that connected to an HTTP/2-only endpoint.
+pub const HTTP1_RESPONSE: &'static [u8] =
+    b"HTTP/1.1 505 HTTP Version Not Supported\r\n\
+      Connection: close\r\n\
+      Content-Type: text/plain\r\n\
+      Content-Length: 25\r\n\
+      \r\n\
+      This server speaks HTTP/2";
+
+/// What a peer that sent an invalid preface appears to be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InvalidPreface {
+    /// An HTTP/1.x request line, up to its version.
+    Http1,
+    /// A TLS ClientHello sent to a cleartext port.
+    Tls,
+    /// Anything else.
+    Garbage,
+    /// The preface was not followed by a SETTINGS frame; the server's, which is only that
+    /// frame, didn't start with one.
+    NoSettings,
+}
+
+impl InvalidPreface {
+    /// Classifies bytes that are known not to be a (prefix of the) preface.
+    pub fn classify(buf: &[u8]) -> InvalidPreface {
+        // TLS record header: handshake content type followed by a 3.x version.
+        if buf.len() >= 2 && buf[0] == 0x16 && buf[1] == 0x03 {
+            return InvalidPreface::Tls;
+        }
+
+        if is_request_line(buf) {
+            return InvalidPreface::Http1;
+        }
+
+        InvalidPreface::Garbage
+    }
+
+    /// The bytes to write before closing the connection, if any hint can be given at all.
+    pub fn response(&self) -> Option<&'static [u8]> {
+        match *self {
+            InvalidPreface::Http1 => Some(HTTP1_RESPONSE),
+            InvalidPreface::Tls | InvalidPreface::Garbage | InvalidPreface::NoSettings => None,
+        }
+    }
+}
+
+impl fmt::Display for InvalidPreface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InvalidPreface::Http1 => "invalid preface: peer sent an HTTP/1.x request",
+            InvalidPreface::Tls => "invalid preface: peer sent a TLS handshake on a cleartext port",
+            InvalidPreface::Garbage => "invalid preface: unrecognised bytes",
+            InvalidPreface::NoSettings => "invalid preface: the first frame was not SETTINGS",
+        })
+    }
+}
+
+/// Whether `buf` starts with an HTTP/1.x request line: a method, a request target and
+/// `HTTP/1.` with the minor version. A mangled preface, `PRI * HTTP/2.0` and all, isn't one.
+fn is_request_line(buf: &[u8]) -> bool {
+    let method = buf.iter().take_while(|&&b| b >= b'A' && b <= b'Z').count();
+    if method == 0 || buf.get(method) != Some(&b' ') {
+        return false;
+    }
+    let rest = &buf[method + 1..];
+    let target = rest.iter().take_while(|&&b| b > b' ' && b < 0x7f).count();
+    if target == 0 || rest.get(target) != Some(&b' ') {
+        return false;
+    }
+    let version = &rest[target + 1..];
+    version.len() > 7 && version.starts_with(b"HTTP/1.") && version[7] >= b'0' && version[7] <= b'9'
+}
+
+/// Checks the bytes received so far against the preface. Returns `Ok(true)` once the whole
+/// preface has been received, `Ok(false)` if more bytes are needed.
+pub fn check(buf: &[u8]) -> Result<bool, InvalidPreface> {
+    if buf.len() >= PREFACE.len() {
+        if &buf[..PREFACE.len()] == PREFACE {
+            Ok(true)
+        } else {
+            Err(InvalidPreface::classify(buf))
+        }
+    } else if PREFACE.starts_with(buf) {
+        Ok(false)
+    } else {
+        Err(InvalidPreface::classify(buf))
+    }
+}
+
+/// The client connection preface: `PREFACE` and a SETTINGS frame carrying `settings`.
+pub fn client_preface(settings: &Settings) -> Vec<u8> {
+    let mut buf = PREFACE.to_vec();
+    buf.extend_from_slice(&server_preface(settings));
+    buf
+}
+
+/// The server connection preface, a SETTINGS frame carrying `settings`, to send right away
+/// rather than after reading the client's.
+pub fn server_preface(settings: &Settings) -> Vec<u8> {
+    let payload = settings.to_payload();
+    let mut buf = Vec::new();
+    Frame::settings(SettingsFlags::empty(), &payload).serialize_into(&mut buf).unwrap();
+    buf
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PrefaceConfig {
+    /// Time the client has to send the preface, from the moment the connection is ready for
+    /// it (after TLS, if any).
+    pub timeout: Duration,
+}
+
+impl Default for PrefaceConfig {
+    fn default() -> PrefaceConfig {
+        PrefaceConfig { timeout: Duration::from_secs(5) }
+    }
+}
+
+/// Why the client preface could not be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PrefaceError {
+    Invalid(InvalidPreface),
+    /// The preface was not received in time.
+    Timeout,
+}
+
+impl fmt::Display for PrefaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PrefaceError::Invalid(ref invalid) => invalid.fmt(f),
+            PrefaceError::Timeout => f.write_str("timed out waiting for the preface"),
+        }
+    }
+}
+
+/// Reads the client connection preface on a server, from reads of any size. It reads `PREFACE`
+/// itself and leaves the SETTINGS frame after it to the connection, but its deadline holds
+/// until `settings_received`: a client must not get to hold the connection open by sending
+/// `PREFACE` alone.
+#[derive(Clone, Debug)]
+pub struct PrefaceReader {
+    received: Vec<u8>,
+    deadline: Instant,
+    done: bool,
+    /// The connection read the SETTINGS frame that follows `PREFACE`.
+    settings: bool,
+    /// Why the connection was given up on, logged the first time it was.
+    rejected: Option<PrefaceError>,
+}
+
+impl PrefaceReader {
+    pub fn new(config: PrefaceConfig, now: Instant) -> PrefaceReader {
+        PrefaceReader {
+            received: Vec::with_capacity(PREFACE.len()),
+            deadline: now + config.timeout,
+            done: false,
+            settings: false,
+            rejected: None,
+        }
+    }
+
+    /// Takes in the octets read from the connection. Once the preface is complete, returns
+    /// how many of `buf` were part of it; the rest are frames.
+    pub fn recv(&mut self, buf: &[u8]) -> Result<Option<usize>, PrefaceError> {
+        if self.done {
+            return Ok(Some(0));
+        }
+        let take = cmp::min(buf.len(), PREFACE.len() - self.received.len());
+        self.received.extend_from_slice(&buf[..take]);
+        match check(&self.received) {
+            Ok(true) => {
+                self.done = true;
+                Ok(Some(take))
+            },
+            Ok(false) => Ok(None),
+            // What follows counts too, to tell what the peer is: the whole request line.
+            Err(_) => {
+                let invalid = InvalidPreface::classify(&[&self.received[..], &buf[take..]].concat());
+                Err(self.reject(PrefaceError::Invalid(invalid)))
+            },
+        }
+    }
+
+    /// Whether `PREFACE` was read, after which the octets read are frames.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The connection's first frame, which `Connection::recv` only takes if it is SETTINGS, was
+    /// read: the preface is complete and the deadline disarmed.
+    pub fn settings_received(&mut self) {
+        self.settings = true;
+    }
+
+    /// When the connection's timer should fire next, or `None` once the SETTINGS frame was
+    /// read.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.settings { None } else { Some(self.deadline) }
+    }
+
+    pub fn check(&mut self, now: Instant) -> Result<(), PrefaceError> {
+        if !self.settings && now >= self.deadline {
+            return Err(self.reject(PrefaceError::Timeout));
+        }
+        Ok(())
+    }
+
+    /// Why the connection was given up on, if it was.
+    pub fn rejected(&self) -> Option<PrefaceError> {
+        self.rejected
+    }
+
+    fn reject(&mut self, err: PrefaceError) -> PrefaceError {
+        if self.rejected.is_none() {
+            log::info!("rejecting connection: {}", err);
+            self.rejected = Some(err);
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::frame::Frame;
+    use http2::kind::Kind;
+    use http2::settings::Settings;
+
+    use super::{client_preface, InvalidPreface, PrefaceConfig, PrefaceError, PrefaceReader, PREFACE, check};
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(b""), Ok(false));
+        assert_eq!(check(b"PRI * HTTP/2"), Ok(false));
+        assert_eq!(check(PREFACE), Ok(true));
+        assert_eq!(check(b"PRI * HTTP/2.0\r\n\r\nXX\r\n\r\n"), Err(InvalidPreface::Garbage));
+        assert_eq!(check(b"POST / HTTP/1.1\r\n"), Err(InvalidPreface::Http1));
+        assert_eq!(check(b"GET / HTTP/2.0\r\n"), Err(InvalidPreface::Garbage));
+        assert_eq!(check(b"GET /a b HTTP/1.1\r\n"), Err(InvalidPreface::Garbage));
+        assert_eq!(check(b"HELLO\r\n"), Err(InvalidPreface::Garbage));
+        assert_eq!(check(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"), Err(InvalidPreface::Http1));
+        assert_eq!(check(&[0x16, 0x03, 0x01, 0x02, 0x00]), Err(InvalidPreface::Tls));
+        assert_eq!(check(&[0x00, 0x00, 0x12, 0x04]), Err(InvalidPreface::Garbage));
+    }
+
+    #[test]
+    fn test_response() {
+        assert!(InvalidPreface::Http1.response().unwrap().starts_with(b"HTTP/1.1 505 "));
+        assert_eq!(InvalidPreface::Tls.response(), None);
+    }
+
+    #[test]
+    fn test_reader() {
+        let settings = Settings { max_concurrent_streams: Some(100), ..Settings::default() };
+        let preface = client_preface(&settings);
+        assert_eq!(&preface[..PREFACE.len()], PREFACE);
+        let frame = Frame::from_bytes(&preface[PREFACE.len()..]).unwrap();
+        assert_eq!((frame.header.kind, frame.header.length), (Kind::Settings, 6));
+
+        let now = Instant::now();
+        let mut reader = PrefaceReader::new(PrefaceConfig::default(), now);
+        assert_eq!(reader.recv(&preface[..10]), Ok(None));
+        assert_eq!(reader.recv(&preface[10..]), Ok(Some(PREFACE.len() - 10)));
+        assert!(reader.is_done());
+        // Until the SETTINGS frame after it was read too.
+        assert_eq!(reader.deadline(), Some(now + Duration::from_secs(5)));
+        assert_eq!(reader.check(now + Duration::from_secs(5)), Err(PrefaceError::Timeout));
+        reader.settings_received();
+        assert_eq!(reader.deadline(), None);
+        assert_eq!(reader.check(now + Duration::from_secs(60)), Ok(()));
+
+        let mut reader = PrefaceReader::new(PrefaceConfig::default(), now);
+        assert_eq!(reader.recv(b"PRI"), Ok(None));
+        assert_eq!(reader.check(now + Duration::from_secs(5)), Err(PrefaceError::Timeout));
+        let mut reader = PrefaceReader::new(PrefaceConfig::default(), now);
+        assert_eq!(reader.recv(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), Err(PrefaceError::Invalid(InvalidPreface::Http1)));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let now = Instant::now();
+        let late = now + Duration::from_secs(5);
+        let mut reader = PrefaceReader::new(PrefaceConfig::default(), now);
+        assert_eq!(reader.rejected(), None);
+        assert_eq!(reader.check(late), Err(PrefaceError::Timeout));
+        assert_eq!(reader.rejected(), Some(PrefaceError::Timeout));
+        // Checking again doesn't count as another rejection, so it isn't logged again.
+        assert_eq!(reader.check(late + Duration::from_secs(1)), Err(PrefaceError::Timeout));
+        let invalid = reader.recv(b"GET / HTTP/1.1\r\n");
+        assert_eq!(invalid, Err(PrefaceError::Invalid(InvalidPreface::Http1)));
+        assert_eq!(reader.rejected(), Some(PrefaceError::Timeout));
+
+        let mut reader = PrefaceReader::new(PrefaceConfig::default(), now);
+        let invalid = PrefaceError::Invalid(InvalidPreface::Tls);
+        assert_eq!(reader.recv(&[0x16, 0x03, 0x01]), Err(invalid));
+        assert_eq!(reader.rejected(), Some(invalid));
+        assert_eq!(reader.check(late), Err(PrefaceError::Timeout));
+        assert_eq!(reader.rejected(), Some(invalid));
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extensible priorities (RFC 9218): the `priority` header, PRIORITY_UPDATE frames which let a
+//! client change the priority of a request that is already in flight, and the `Scheduler` that
+//! orders the responses of a connection by them.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::schedule::SendScheduler;
+use http2::{encode_u24, StreamIdentifier, FRAME_HEADER_BYTES};
+use sf::{self, BareItem, Dictionary, Item, Member};
+
+/// Frame type of PRIORITY_UPDATE for request streams.
+pub const PRIORITY_UPDATE: u8 = 0x10;
+
+/// PRIORITY_UPDATEs a `Scheduler` keeps for streams that aren't open yet; the oldest are
+/// dropped beyond it.
+pub const MAX_IDLE_UPDATES: usize = 16;
+
+/// Urgency (0 is most urgent, 7 least) and whether the response can be delivered incrementally.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Priority {
+    pub urgency: u8,
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Parses a `priority` field value. Unknown parameters and out-of-range values are ignored,
+    /// and a value that isn't a valid dictionary gives the defaults, as the RFC requires.
+    pub fn parse(value: &str) -> Priority {
+        let mut priority = Priority::default();
+        let dict = match sf::parse_dictionary(value) {
+            Ok(dict) => dict,
+            Err(_) => return priority,
+        };
+        if let Some(&BareItem::Integer(u)) = dict.get("u").and_then(|m| m.item()).map(|i| &i.bare) {
+            if u >= 0 && u <= 7 {
+                priority.urgency = u as u8;
+            }
+        }
+        if let Some(&BareItem::Boolean(i)) = dict.get("i").and_then(|m| m.item()).map(|i| &i.bare) {
+            priority.incremental = i;
+        }
+        priority
+    }
+}
+
+/// Serializes only what differs from the defaults, so the default priority is an empty value.
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut dict = Dictionary::default();
+        if self.urgency != 3 {
+            dict.insert("u", Member::Item(Item::new(BareItem::Integer(self.urgency as i64))));
+        }
+        if self.incremental {
+            dict.insert("i", Member::Item(Item::new(BareItem::Boolean(true))));
+        }
+        f.write_str(&try!(sf::serialize_dictionary(&dict).map_err(|_| fmt::Error)))
+    }
+}
+
+/// A PRIORITY_UPDATE frame: the new priority of `stream`. Always sent on stream 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PriorityUpdate {
+    pub stream: StreamIdentifier,
+    pub priority: Priority,
+}
+
+impl PriorityUpdate {
+    pub fn new(stream: StreamIdentifier, urgency: u8, incremental: bool) -> PriorityUpdate {
+        PriorityUpdate {
+            stream: stream,
+            priority: Priority {
+                urgency: if urgency > 7 { 7 } else { urgency },
+                incremental: incremental,
+            },
+        }
+    }
+
+    /// Parses the payload of a PRIORITY_UPDATE frame.
+    pub fn parse(payload: &[u8]) -> Option<PriorityUpdate> {
+        if payload.len() < 4 {
+            return None;
+        }
+        let value = match ::std::str::from_utf8(&payload[4..]) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        Some(PriorityUpdate {
+            stream: StreamIdentifier::parse(payload),
+            priority: Priority::parse(value),
+        })
+    }
+
+    /// The PRIORITY_UPDATE of a frame, or `None` for another kind of frame or a field value
+    /// that isn't UTF-8.
+    pub fn from_frame(frame: &Frame) -> Option<PriorityUpdate> {
+        match frame.payload {
+            Payload::PriorityUpdate { prioritized, value } => {
+                ::std::str::from_utf8(value).ok().map(|value| PriorityUpdate {
+                    stream: prioritized,
+                    priority: Priority::parse(value),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Length of the whole frame, header included.
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER_BYTES + 4 + self.priority.to_string().len()
+    }
+
+    /// Writes the whole frame, header included, and returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let value = self.priority.to_string();
+        encode_u24(buf, 4 + value.len() as u32);
+        buf[3] = PRIORITY_UPDATE;
+        buf[4] = 0;
+        StreamIdentifier(0).encode(&mut buf[5..]);
+        self.stream.encode(&mut buf[FRAME_HEADER_BYTES..]);
+        buf[FRAME_HEADER_BYTES + 4..FRAME_HEADER_BYTES + 4 + value.len()].copy_from_slice(value.as_bytes());
+        FRAME_HEADER_BYTES + 4 + value.len()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct Entry {
+    priority: Priority,
+    /// The stream has data to send.
+    ready: bool,
+}
+
+/// Picks the stream to send next by extensible priorities: the lowest urgency first; within an
+/// urgency, non-incremental responses one at a time in stream id order, then incremental ones
+/// in turn. The connection sets the priority from the `priority` header and PRIORITY_UPDATE
+/// frames, which may come before the stream's HEADERS, and reports which streams have data.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    /// The open streams.
+    streams: HashMap<u32, Entry>,
+    /// The ready streams that aren't incremental, per urgency.
+    sequential: [BTreeSet<u32>; 8],
+    /// Incremental streams that were ready, per urgency, in the order they take turns; it may
+    /// hold streams that are no longer, which are skipped.
+    turns: [VecDeque<u32>; 8],
+    /// PRIORITY_UPDATEs of streams that aren't open yet, oldest first.
+    idle: VecDeque<(u32, Priority)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Sets the priority of stream `id`, which is open from then on.
+    pub fn set_priority(&mut self, id: StreamIdentifier, priority: Priority) {
+        let entry = self.streams.get(&id.0).cloned().unwrap_or(Entry { priority: priority, ready: false });
+        self.update(id.0, Entry { priority: priority, ..entry });
+    }
+
+    /// Takes in a PRIORITY_UPDATE received from the client. The update of a stream that isn't
+    /// open yet is kept for when it is, up to `MAX_IDLE_UPDATES` of them.
+    pub fn recv_update(&mut self, update: &PriorityUpdate) {
+        let id = update.stream.0;
+        if self.streams.contains_key(&id) {
+            return self.set_priority(update.stream, update.priority);
+        }
+        self.idle.retain(|&(idle, _)| idle != id);
+        self.idle.push_back((id, update.priority));
+        while self.idle.len() > MAX_IDLE_UPDATES {
+            self.idle.pop_front();
+        }
+    }
+
+    pub fn priority(&self, id: StreamIdentifier) -> Priority {
+        match self.streams.get(&id.0) {
+            Some(entry) => entry.priority,
+            None => self.idle.iter().find(|&&(idle, _)| idle == id.0).map_or(Priority::default(), |&(_, p)| p),
+        }
+    }
+
+    /// Whether stream `id` has data to send; new streams have the default priority, or the one
+    /// a PRIORITY_UPDATE gave them.
+    pub fn set_ready(&mut self, id: StreamIdentifier, ready: bool) {
+        let entry = match self.streams.get(&id.0) {
+            Some(&entry) => entry,
+            None => Entry { priority: self.priority(id), ready: false },
+        };
+        self.idle.retain(|&(idle, _)| idle != id.0);
+        self.update(id.0, Entry { ready: ready, ..entry });
+    }
+
+    /// Forgets a stream that was closed.
+    pub fn remove(&mut self, id: StreamIdentifier) {
+        if let Some(entry) = self.streams.remove(&id.0) {
+            self.sequential[entry.priority.urgency as usize].remove(&id.0);
+        }
+        self.idle.retain(|&(idle, _)| idle != id.0);
+    }
+
+    /// The stream to send a frame of next, if any has data. An incremental stream goes to the
+    /// back of its urgency's turns.
+    pub fn next(&mut self) -> Option<StreamIdentifier> {
+        for urgency in 0..8u8 {
+            if let Some(&id) = self.sequential[urgency as usize].iter().next() {
+                return Some(StreamIdentifier(id));
+            }
+
+            let turns = &mut self.turns[urgency as usize];
+            while let Some(id) = turns.pop_front() {
+                let current = self.streams.get(&id).map_or(false, |entry| {
+                    entry.ready && entry.priority.incremental && entry.priority.urgency == urgency
+                });
+                if current {
+                    turns.push_back(id);
+                    return Some(StreamIdentifier(id));
+                }
+            }
+        }
+        None
+    }
+
+    fn update(&mut self, id: u32, entry: Entry) {
+        if let Some(old) = self.streams.insert(id, entry) {
+            self.sequential[old.priority.urgency as usize].remove(&id);
+        }
+        if !entry.ready {
+            return;
+        }
+        if !entry.priority.incremental {
+            self.sequential[entry.priority.urgency as usize].insert(id);
+            return;
+        }
+        let turns = &mut self.turns[entry.priority.urgency as usize];
+        if !turns.contains(&id) {
+            turns.push_back(id);
+        }
+    }
+}
+
+impl SendScheduler for Scheduler {
+    fn set_ready(&mut self, id: StreamIdentifier, ready: bool) {
+        Scheduler::set_ready(self, id, ready)
+    }
+
+    fn close(&mut self, id: StreamIdentifier) {
+        self.remove(id)
+    }
+
+    fn next(&mut self) -> Option<StreamIdentifier> {
+        Scheduler::next(self)
+    }
+
+    fn recv_priority_update(&mut self, update: &PriorityUpdate) {
+        self.recv_update(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::frame::Frame;
+    use http2::{Error, StreamIdentifier, FRAME_HEADER_BYTES};
+    use super::{Priority, PriorityUpdate, Scheduler, MAX_IDLE_UPDATES, PRIORITY_UPDATE};
+
+    #[test]
+    fn test_priority() {
+        assert_eq!(Priority::parse("u=1, i"), Priority { urgency: 1, incremental: true });
+        assert_eq!(Priority::parse("u=9, x=?1"), Priority::default());
+        assert_eq!(Priority::parse("u=(1 2)"), Priority::default());
+        assert_eq!(Priority::parse("not a dictionary;"), Priority::default());
+        assert_eq!(Priority { urgency: 0, incremental: true }.to_string(), "u=0, i");
+        assert_eq!(Priority::default().to_string(), "");
+    }
+
+    #[test]
+    fn test_priority_update() {
+        let update = PriorityUpdate::new(StreamIdentifier(5), 0, true);
+        let mut buf = vec![0u8; update.encoded_len()];
+        assert_eq!(update.encode(&mut buf), buf.len());
+        assert_eq!(&buf[..9], &[0, 0, 10, PRIORITY_UPDATE, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[9..], b"\0\0\0\x05u=0, i");
+        assert_eq!(PriorityUpdate::parse(&buf[FRAME_HEADER_BYTES..]), Some(update));
+        assert_eq!(PriorityUpdate::from_frame(&Frame::from_bytes(&buf).unwrap()), Some(update));
+
+        // On a stream, or for stream 0.
+        buf[8] = 1;
+        assert_eq!(Frame::from_bytes(&buf), Err(Error::InvalidStreamId));
+        buf[8] = 0;
+        buf[12] = 0;
+        assert_eq!(Frame::from_bytes(&buf), Err(Error::InvalidStreamId));
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::new();
+        let id = StreamIdentifier;
+        for n in &[1, 3, 5, 7, 9] {
+            scheduler.set_ready(id(*n), true);
+        }
+        scheduler.set_priority(id(3), Priority { urgency: 3, incremental: true });
+        scheduler.set_priority(id(5), Priority { urgency: 3, incremental: true });
+        scheduler.recv_update(&PriorityUpdate::new(id(9), 1, false));
+        // An update for a stream that doesn't exist yet.
+        scheduler.recv_update(&PriorityUpdate::new(id(11), 0, false));
+
+        assert_eq!(scheduler.next(), Some(id(9)));
+        scheduler.remove(id(9));
+        assert_eq!(scheduler.next(), Some(id(1)));
+        scheduler.set_ready(id(1), false);
+        assert_eq!(scheduler.next(), Some(id(7)));
+        scheduler.remove(id(7));
+        // Incremental streams take turns.
+        assert_eq!((scheduler.next(), scheduler.next(), scheduler.next()), (Some(id(3)), Some(id(5)), Some(id(3))));
+        scheduler.set_ready(id(11), true);
+        assert_eq!(scheduler.next(), Some(id(11)));
+        assert_eq!(scheduler.priority(id(11)).urgency, 0);
+        for n in &[3, 5, 11] {
+            scheduler.set_ready(id(*n), false);
+        }
+        assert_eq!(scheduler.next(), None);
+    }
+
+    #[test]
+    fn test_idle_updates() {
+        let mut scheduler = Scheduler::new();
+        let id = StreamIdentifier;
+        for n in 0..MAX_IDLE_UPDATES as u32 + 1 {
+            scheduler.recv_update(&PriorityUpdate::new(id(2 * n + 1), 0, false));
+        }
+        // Only the latest are kept, and only until the stream opens or closes.
+        assert_eq!(scheduler.priority(id(1)), Priority::default());
+        assert_eq!(scheduler.priority(id(3)).urgency, 0);
+        scheduler.remove(id(3));
+        assert_eq!(scheduler.priority(id(3)), Priority::default());
+        scheduler.set_ready(id(1), true);
+        scheduler.set_ready(id(5), true);
+        assert_eq!(scheduler.next(), Some(id(5)));
+        assert_eq!(scheduler.idle.len(), MAX_IDLE_UPDATES - 2);
+
+        // One for an open stream applies to it.
+        scheduler.recv_update(&PriorityUpdate::new(id(1), 0, false));
+        scheduler.recv_update(&PriorityUpdate::new(id(5), 7, false));
+        assert_eq!(scheduler.next(), Some(id(1)));
+        assert_eq!(scheduler.idle.len(), MAX_IDLE_UPDATES - 2);
+    }
+}
@@ -0,0 +1,409 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The stream dependencies and weights of RFC 7540 section 5.3, for the peers that still send
+//! them (see `priority` for RFC 9218's). A `PriorityTree` takes the priority of every HEADERS
+//! and PRIORITY frame and picks the stream to send DATA of next: a stream before the streams
+//! that depend on it, and siblings in proportion to their weights, by the octets sent.
+//!
+//! Closed streams stay in the tree for a while, so that their dependents keep their place
+//! and later PRIORITY frames can still refer to them, and so may idle streams that PRIORITY
+//! frames placed; `PriorityTreeConfig::max_retired` bounds both.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+
+use http2::payload::Priority;
+use http2::schedule::SendScheduler;
+use http2::stream::StreamError;
+use http2::{StreamIdentifier, PROTOCOL_ERROR};
+
+/// The weight of a stream without a priority (RFC 7540 section 5.3.5).
+pub const DEFAULT_WEIGHT: u16 = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PriorityTreeConfig {
+    /// Streams kept in the tree while they have no DATA to send again: closed ones, and idle
+    /// ones PRIORITY frames placed. The oldest are dropped beyond it, their dependents going
+    /// to their parents.
+    pub max_retired: usize,
+}
+
+impl Default for PriorityTreeConfig {
+    fn default() -> PriorityTreeConfig {
+        PriorityTreeConfig { max_retired: 32 }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    parent: u32,
+    weight: u16,
+    children: Vec<u32>,
+    ready: bool,
+    /// How far the stream got among its siblings: the octets sent in its subtree, scaled by
+    /// its weight.
+    pass: u64,
+    /// The pass of the child chosen last, which children that were idle start again from.
+    vtime: u64,
+}
+
+impl Node {
+    fn new(parent: u32, weight: u16) -> Node {
+        Node {
+            parent: parent,
+            weight: weight,
+            children: Vec::new(),
+            ready: false,
+            pass: 0,
+            vtime: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PriorityTree {
+    max_retired: usize,
+    /// Stream 0 is the root.
+    nodes: HashMap<u32, Node>,
+    /// Oldest first.
+    retired: VecDeque<u32>,
+}
+
+impl PriorityTree {
+    pub fn new(config: PriorityTreeConfig) -> PriorityTree {
+        let mut nodes = HashMap::new();
+        nodes.insert(0, Node::new(0, DEFAULT_WEIGHT));
+        PriorityTree {
+            max_retired: config.max_retired,
+            nodes: nodes,
+            retired: VecDeque::new(),
+        }
+    }
+
+    /// Takes in the priority of a HEADERS or PRIORITY frame of stream `id`. A dependency on a
+    /// stream that isn't in the tree gives the default priority. Fails with the stream error
+    /// to answer with for a stream that depends on itself.
+    pub fn set_priority(&mut self, id: StreamIdentifier, priority: &Priority) -> Result<(), StreamError> {
+        let (id, dependency) = (id.0, priority.dependency().0);
+        if id == dependency {
+            return Err(StreamError { id: StreamIdentifier(id), code: PROTOCOL_ERROR });
+        }
+        let (parent, weight, exclusive) = if self.nodes.contains_key(&dependency) {
+            (dependency, priority.weight(), priority.exclusive())
+        } else {
+            (0, DEFAULT_WEIGHT, false)
+        };
+        let idle = !self.nodes.contains_key(&id);
+        if idle {
+            self.insert(id);
+        }
+
+        // A stream made to depend on one of its dependents takes its place first (RFC 7540
+        // section 5.3.3).
+        if self.is_descendant(parent, id) {
+            let up = self.nodes[&id].parent;
+            self.move_node(parent, up, false);
+        }
+        self.move_node(id, parent, exclusive);
+        self.nodes.get_mut(&id).unwrap().weight = weight;
+        // Only once placed: retiring may drop the stream or its parent.
+        if idle {
+            self.retire(id);
+        }
+        Ok(())
+    }
+
+    /// The dependency and weight of stream `id`, if it is in the tree.
+    pub fn priority(&self, id: StreamIdentifier) -> Option<(StreamIdentifier, u16)> {
+        match id.0 {
+            0 => None,
+            id => self.nodes.get(&id).map(|node| (StreamIdentifier(node.parent), node.weight)),
+        }
+    }
+
+    /// Whether stream `id` has DATA to send; new streams have the default priority.
+    pub fn set_ready(&mut self, id: StreamIdentifier, ready: bool) {
+        if id.0 == 0 {
+            return;
+        }
+        if self.nodes.contains_key(&id.0) {
+            if ready {
+                self.retired.retain(|&retired| retired != id.0);
+            }
+        } else {
+            self.insert(id.0);
+        }
+        self.nodes.get_mut(&id.0).unwrap().ready = ready;
+    }
+
+    /// The stream was closed; it stays in the tree for its dependents until it is among the
+    /// oldest past `max_retired`.
+    pub fn close(&mut self, id: StreamIdentifier) {
+        if id.0 == 0 || !self.nodes.contains_key(&id.0) {
+            return;
+        }
+        self.nodes.get_mut(&id.0).unwrap().ready = false;
+        self.retired.retain(|&retired| retired != id.0);
+        self.retire(id.0);
+    }
+
+    /// The number of streams in the tree, retired ones included.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The stream to send DATA of next, if any has some. What was sent is reported with
+    /// `sent`, which the share of the stream and of its ancestors is counted from.
+    pub fn next(&mut self) -> Option<StreamIdentifier> {
+        let mut active = HashSet::new();
+        for (&id, node) in &self.nodes {
+            if node.ready {
+                let mut id = id;
+                while active.insert(id) && id != 0 {
+                    id = self.nodes[&id].parent;
+                }
+            }
+        }
+        if active.is_empty() {
+            return None;
+        }
+
+        let mut id = 0;
+        loop {
+            if id != 0 && self.nodes[&id].ready {
+                return Some(StreamIdentifier(id));
+            }
+            let (vtime, children) = {
+                let node = &self.nodes[&id];
+                (node.vtime, node.children.clone())
+            };
+            let mut best: Option<(u64, u32)> = None;
+            for child in children.into_iter().filter(|child| active.contains(child)) {
+                let node = self.nodes.get_mut(&child).unwrap();
+                if node.pass < vtime {
+                    node.pass = vtime;
+                }
+                if best.map_or(true, |best| (node.pass, child) < best) {
+                    best = Some((node.pass, child));
+                }
+            }
+            // An active stream that isn't ready has an active dependent.
+            let (pass, child) = best.unwrap();
+            self.nodes.get_mut(&id).unwrap().vtime = pass;
+            id = child;
+        }
+    }
+
+    /// `len` octets of DATA were sent on stream `id`.
+    pub fn sent(&mut self, id: StreamIdentifier, len: usize) {
+        let mut id = id.0;
+        while id != 0 {
+            let node = match self.nodes.get_mut(&id) {
+                Some(node) => node,
+                None => return,
+            };
+            node.pass += len as u64 * 256 / node.weight as u64;
+            id = node.parent;
+        }
+    }
+
+    fn insert(&mut self, id: u32) {
+        self.nodes.insert(id, Node::new(0, DEFAULT_WEIGHT));
+        self.nodes.get_mut(&0).unwrap().children.push(id);
+    }
+
+    fn retire(&mut self, id: u32) {
+        self.retired.push_back(id);
+        while self.retired.len() > self.max_retired {
+            let oldest = self.retired.pop_front().unwrap();
+            self.remove(oldest);
+        }
+    }
+
+    /// Takes stream `id` out of the tree; its dependents share its weight in proportion to
+    /// theirs, under its parent (RFC 7540 section 5.3.4).
+    fn remove(&mut self, id: u32) {
+        let node = self.nodes.remove(&id).unwrap();
+        let total: u32 = node.children.iter().map(|child| self.nodes[child].weight as u32).sum();
+        for child in &node.children {
+            let child = self.nodes.get_mut(child).unwrap();
+            child.parent = node.parent;
+            child.weight = ::std::cmp::max(1, node.weight as u32 * child.weight as u32 / total) as u16;
+        }
+        let parent = self.nodes.get_mut(&node.parent).unwrap();
+        parent.children.retain(|&child| child != id);
+        parent.children.extend(node.children);
+    }
+
+    fn move_node(&mut self, id: u32, parent: u32, exclusive: bool) {
+        let old = self.nodes[&id].parent;
+        self.nodes.get_mut(&old).unwrap().children.retain(|&child| child != id);
+        if exclusive {
+            let children = mem::replace(&mut self.nodes.get_mut(&parent).unwrap().children, Vec::new());
+            for child in &children {
+                self.nodes.get_mut(child).unwrap().parent = id;
+            }
+            self.nodes.get_mut(&id).unwrap().children.extend(children);
+        }
+        self.nodes.get_mut(&parent).unwrap().children.push(id);
+        self.nodes.get_mut(&id).unwrap().parent = parent;
+    }
+
+    /// Whether `ancestor` is above `id`.
+    fn is_descendant(&self, id: u32, ancestor: u32) -> bool {
+        let mut id = id;
+        while id != 0 {
+            id = self.nodes[&id].parent;
+            if id == ancestor {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl SendScheduler for PriorityTree {
+    fn set_ready(&mut self, id: StreamIdentifier, ready: bool) {
+        PriorityTree::set_ready(self, id, ready)
+    }
+
+    fn close(&mut self, id: StreamIdentifier) {
+        PriorityTree::close(self, id)
+    }
+
+    fn next(&mut self) -> Option<StreamIdentifier> {
+        PriorityTree::next(self)
+    }
+
+    fn sent(&mut self, id: StreamIdentifier, len: usize) {
+        PriorityTree::sent(self, id, len)
+    }
+
+    fn recv_priority(&mut self, id: StreamIdentifier, priority: &Priority) {
+        // Only fails for a stream that depends on itself, which the connection resets first.
+        let _ = self.set_priority(id, priority);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::payload::Priority;
+    use http2::stream::StreamError;
+    use http2::{StreamIdentifier, PROTOCOL_ERROR};
+
+    use super::{PriorityTree, PriorityTreeConfig};
+
+    fn depend(tree: &mut PriorityTree, id: u32, dependency: u32, weight: u16, exclusive: bool) {
+        tree.set_priority(StreamIdentifier(id), &Priority::new(exclusive, StreamIdentifier(dependency), weight)).unwrap();
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let id = StreamIdentifier;
+        let mut tree = PriorityTree::new(PriorityTreeConfig::default());
+        for n in &[1, 3, 5] {
+            tree.set_ready(id(*n), true);
+        }
+        depend(&mut tree, 5, 1, 16, false);
+        // The parent goes first.
+        assert_eq!((0..4).map(|_| tree.next()).filter(|&next| next == Some(id(5))).count(), 0);
+        tree.set_ready(id(1), false);
+        tree.set_ready(id(3), false);
+        assert_eq!(tree.next(), Some(id(5)));
+
+        // Exclusive: 1 and 3 move under 7. Then 7 under its dependent 1, which moves up.
+        depend(&mut tree, 7, 0, 16, true);
+        assert_eq!(tree.priority(id(1)), Some((id(7), 16)));
+        depend(&mut tree, 7, 1, 32, false);
+        assert_eq!(tree.priority(id(1)), Some((id(0), 16)));
+        assert_eq!(tree.priority(id(7)), Some((id(1), 32)));
+        assert_eq!(tree.set_priority(id(9), &Priority::new(false, id(9), 16)),
+                   Err(StreamError { id: id(9), code: PROTOCOL_ERROR }));
+        // A dependency on a stream not in the tree gives the default priority.
+        depend(&mut tree, 11, 101, 200, false);
+        assert_eq!(tree.priority(id(11)), Some((id(0), 16)));
+    }
+
+    #[test]
+    fn test_weights() {
+        let id = StreamIdentifier;
+        let mut tree = PriorityTree::new(PriorityTreeConfig::default());
+        depend(&mut tree, 1, 0, 32, false);
+        depend(&mut tree, 3, 0, 16, false);
+        tree.set_ready(id(1), true);
+        tree.set_ready(id(3), true);
+        let mut counts = [0, 0];
+        for _ in 0..300 {
+            let next = tree.next().unwrap();
+            counts[(next.0 / 2) as usize] += 1;
+            tree.sent(next, 1000);
+        }
+        assert_eq!(counts, [200, 100]);
+    }
+
+    #[test]
+    fn test_retired() {
+        let id = StreamIdentifier;
+        let mut tree = PriorityTree::new(PriorityTreeConfig { max_retired: 2 });
+        for n in &[1, 3, 5] {
+            tree.set_ready(id(*n), true);
+        }
+        depend(&mut tree, 1, 0, 64, false);
+        depend(&mut tree, 3, 1, 30, false);
+        depend(&mut tree, 5, 1, 10, false);
+        tree.close(id(1));
+        assert_eq!(tree.priority(id(3)), Some((id(1), 30)));
+
+        // Idle streams placed by PRIORITY frames push the closed one out; its dependents share
+        // its weight.
+        depend(&mut tree, 101, 0, 16, false);
+        depend(&mut tree, 103, 0, 16, false);
+        assert_eq!(tree.priority(id(1)), None);
+        assert_eq!(tree.priority(id(3)), Some((id(0), 48)));
+        assert_eq!(tree.priority(id(5)), Some((id(0), 16)));
+        for n in 105..200 {
+            depend(&mut tree, n, 0, 16, false);
+        }
+        assert_eq!(tree.len(), 4);
+    }
+
+    #[test]
+    fn test_retired_dependency() {
+        let id = StreamIdentifier;
+        // Placing 5 drops its dependency, 1; it goes to the root in its place.
+        let mut tree = PriorityTree::new(PriorityTreeConfig { max_retired: 2 });
+        depend(&mut tree, 1, 0, 16, false);
+        depend(&mut tree, 3, 0, 16, false);
+        depend(&mut tree, 5, 1, 16, false);
+        assert_eq!(tree.priority(id(1)), None);
+        assert_eq!(tree.priority(id(5)), Some((id(0), 16)));
+        assert_eq!(tree.len(), 2);
+
+        // Nothing idle is kept: the stream placed is dropped right away.
+        let mut tree = PriorityTree::new(PriorityTreeConfig { max_retired: 0 });
+        depend(&mut tree, 1, 0, 32, false);
+        assert_eq!(tree.priority(id(1)), None);
+        assert!(tree.is_empty());
+        tree.set_ready(id(3), true);
+        depend(&mut tree, 3, 0, 32, false);
+        assert_eq!(tree.priority(id(3)), Some((id(0), 32)));
+    }
+}
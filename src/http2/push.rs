@@ -0,0 +1,283 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Manifest-driven server push. A `PushManifest` maps routes to the resources they depend on
+//! (`/index.html` to its stylesheets and scripts), and `AutoPush` turns a served route into the
+//! PUSH_PROMISEs to send, honouring the client's SETTINGS_ENABLE_PUSH, the streams it still
+//! allows and a decline hook; `Connection::auto_push` reserves their streams. The same manifest
+//! drives `link: rel=preload` for Early Hints (see `http::hints::EarlyHints`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use rustc_serialize::json::Json;
+
+/// What a resource is used as, i.e. the `as` of a preload link.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Style,
+    Script,
+    Font,
+    Image,
+    Fetch,
+}
+
+impl Destination {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Destination::Style => "style",
+            Destination::Script => "script",
+            Destination::Font => "font",
+            Destination::Image => "image",
+            Destination::Fetch => "fetch",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Destination> {
+        match value {
+            "style" => Some(Destination::Style),
+            "script" => Some(Destination::Script),
+            "font" => Some(Destination::Font),
+            "image" => Some(Destination::Image),
+            "fetch" => Some(Destination::Fetch),
+            _ => None,
+        }
+    }
+
+    /// Guesses the destination from the extension of `path`.
+    pub fn from_path(path: &str) -> Destination {
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+        let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+        match &extension[..] {
+            "css" => Destination::Style,
+            "js" | "mjs" => Destination::Script,
+            "woff" | "woff2" | "ttf" | "otf" => Destination::Font,
+            "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "svg" | "ico" => Destination::Image,
+            _ => Destination::Fetch,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Resource {
+    pub path: String,
+    pub destination: Destination,
+    /// Fetched in CORS mode; always the case for fonts.
+    pub crossorigin: bool,
+}
+
+impl Resource {
+    pub fn new(path: &str) -> Resource {
+        let destination = Destination::from_path(path);
+        Resource {
+            path: path.to_string(),
+            destination: destination,
+            crossorigin: destination == Destination::Font,
+        }
+    }
+
+    /// The value of a `link` header preloading the resource.
+    pub fn link(&self) -> String {
+        let mut link = format!("<{}>; rel=preload; as={}", self.path, self.destination.as_str());
+        if self.crossorigin {
+            link.push_str("; crossorigin");
+        }
+        link
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ManifestError {
+    /// Not valid JSON.
+    Syntax,
+    /// Valid JSON, but not an object of routes to arrays of resources.
+    Format,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestError::Syntax => f.write_str("push manifest is not valid JSON"),
+            ManifestError::Format => f.write_str("push manifest must map routes to arrays of resources"),
+        }
+    }
+}
+
+/// Routes and the resources each of them depends on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PushManifest {
+    routes: HashMap<String, Vec<Resource>>,
+}
+
+impl PushManifest {
+    pub fn new() -> PushManifest {
+        PushManifest::default()
+    }
+
+    /// Parses a manifest such as
+    ///
+    /// ```json
+    /// { "/index.html": ["/site.css", { "path": "/font.woff2", "as": "font" }] }
+    /// ```
+    ///
+    /// where a resource is either its path, with the destination guessed from the extension,
+    /// or an object with `path` and optional `as` and `crossorigin`.
+    pub fn from_json(json: &str) -> Result<PushManifest, ManifestError> {
+        let json = try!(Json::from_str(json).map_err(|_| ManifestError::Syntax));
+        let routes = try!(json.as_object().ok_or(ManifestError::Format));
+
+        let mut manifest = PushManifest::new();
+        for (route, resources) in routes {
+            let resources = try!(resources.as_array().ok_or(ManifestError::Format));
+            let mut parsed = Vec::with_capacity(resources.len());
+            for resource in resources {
+                parsed.push(try!(parse_resource(resource)));
+            }
+            manifest.insert(route, parsed);
+        }
+        Ok(manifest)
+    }
+
+    pub fn insert(&mut self, route: &str, resources: Vec<Resource>) {
+        self.routes.insert(route.to_string(), resources);
+    }
+
+    /// The resources of `route`; the query string is ignored.
+    pub fn resources(&self, route: &str) -> &[Resource] {
+        let route = route.split('?').next().unwrap_or("");
+        self.routes.get(route).map(|resources| &resources[..]).unwrap_or(&[])
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+}
+
+fn parse_resource(json: &Json) -> Result<Resource, ManifestError> {
+    if let Some(path) = json.as_string() {
+        return Ok(Resource::new(path));
+    }
+    let object = try!(json.as_object().ok_or(ManifestError::Format));
+    let path = try!(object.get("path").and_then(|path| path.as_string()).ok_or(ManifestError::Format));
+    let mut resource = Resource::new(path);
+    if let Some(destination) = object.get("as") {
+        let destination = try!(destination.as_string().ok_or(ManifestError::Format));
+        resource.destination = try!(Destination::from_str(destination).ok_or(ManifestError::Format));
+        resource.crossorigin = resource.destination == Destination::Font;
+    }
+    if let Some(crossorigin) = object.get("crossorigin") {
+        resource.crossorigin = try!(crossorigin.as_boolean().ok_or(ManifestError::Format));
+    }
+    Ok(resource)
+}
+
+/// A push to promise: the resource and the request header block of its PUSH_PROMISE.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Promise {
+    pub resource: Resource,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Decides per served route which resources of the manifest to push.
+#[derive(Clone)]
+pub struct AutoPush {
+    manifest: Arc<PushManifest>,
+    /// At most this many pushes per response.
+    pub max_pushes: usize,
+    decline: Option<Arc<Fn(&str, &Resource) -> bool + Send + Sync>>,
+}
+
+impl AutoPush {
+    pub fn new(manifest: PushManifest) -> AutoPush {
+        AutoPush {
+            manifest: Arc::new(manifest),
+            max_pushes: 8,
+            decline: None,
+        }
+    }
+
+    /// `decline(route, resource)` returning true skips the push, e.g. when a cookie says the
+    /// client already has the resource cached.
+    pub fn with_decline<F>(mut self, decline: F) -> AutoPush
+            where F: Fn(&str, &Resource) -> bool + Send + Sync + 'static {
+        self.decline = Some(Arc::new(decline));
+        self
+    }
+
+    pub fn manifest(&self) -> &PushManifest {
+        &self.manifest
+    }
+
+    /// The promises to send before the response for `route`. `enable_push` is the client's
+    /// SETTINGS_ENABLE_PUSH and `available_streams` how many more streams its
+    /// SETTINGS_MAX_CONCURRENT_STREAMS allows the server to open.
+    pub fn promises(&self, route: &str, scheme: &str, authority: &str, enable_push: bool,
+                    available_streams: usize) -> Vec<Promise> {
+        if !enable_push {
+            return Vec::new();
+        }
+        let limit = if available_streams < self.max_pushes { available_streams } else { self.max_pushes };
+        self.manifest.resources(route).iter()
+            .filter(|resource| resource.path != route)
+            .filter(|resource| self.decline.as_ref().map_or(true, |decline| !decline(route, resource)))
+            .take(limit)
+            .map(|resource| Promise {
+                resource: resource.clone(),
+                headers: vec![(":method".to_string(), "GET".to_string()),
+                              (":scheme".to_string(), scheme.to_string()),
+                              (":authority".to_string(), authority.to_string()),
+                              (":path".to_string(), resource.path.clone())],
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for AutoPush {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AutoPush {{ routes: {}, max_pushes: {} }}", self.manifest.len(), self.max_pushes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoPush, Destination, ManifestError, PushManifest};
+
+    #[test]
+    fn test_manifest() {
+        let manifest = PushManifest::from_json(r#"{
+            "/index.html": ["/site.css", "/app.js", {"path": "/logo", "as": "image"}, "/font.woff2"]
+        }"#).unwrap();
+        let resources = manifest.resources("/index.html?x=1");
+        assert_eq!(resources.len(), 4);
+        assert_eq!(resources[1].destination, Destination::Script);
+        assert_eq!(resources[2].destination, Destination::Image);
+        assert_eq!(resources[3].link(), "</font.woff2>; rel=preload; as=font; crossorigin");
+        assert!(manifest.resources("/other").is_empty());
+        assert_eq!(PushManifest::from_json(r#"{"/": "/a.css"}"#), Err(ManifestError::Format));
+        assert_eq!(PushManifest::from_json("{"), Err(ManifestError::Syntax));
+    }
+
+    #[test]
+    fn test_promises() {
+        let manifest = PushManifest::from_json(r#"{"/": ["/a.css", "/b.js", "/c.png"]}"#).unwrap();
+        let push = AutoPush::new(manifest).with_decline(|_, resource| resource.path == "/b.js");
+
+        let promises = push.promises("/", "https", "example.com", true, 100);
+        assert_eq!(promises.iter().map(|p| &p.resource.path[..]).collect::<Vec<_>>(), vec!["/a.css", "/c.png"]);
+        assert_eq!(promises[0].headers[3], (":path".to_string(), "/a.css".to_string()));
+        assert_eq!(push.promises("/", "https", "example.com", true, 1).len(), 1);
+        assert!(push.promises("/", "https", "example.com", false, 100).is_empty());
+    }
+}
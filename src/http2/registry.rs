@@ -0,0 +1,293 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Custom SETTINGS parameters. Extensions register their identifier with a `SettingsRegistry`
+//! and get a typed `SettingKey` back; the registry adds its values to the `Settings` of our
+//! SETTINGS frames, keeps the values the peer sent for registered identifiers and notifies
+//! listeners when they change. Identifiers nobody registered are ignored, as RFC 9113 requires.
+//! A `Connection` keeps one, see `Connection::settings_registry_mut`.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use http2::grease;
+use http2::payload::Setting;
+use http2::settings::Settings;
+
+/// Identifiers defined by RFC 9113, RFC 8441 and RFC 9218, which can't be registered.
+pub const STANDARD_SETTINGS: &'static [u16] = &[0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x8, 0x9];
+
+/// A type a setting's 32-bit value can be read as.
+pub trait SettingValue: Sized {
+    /// `None` for values the setting doesn't allow; the peer's value is then ignored.
+    fn from_wire(value: u32) -> Option<Self>;
+    fn to_wire(&self) -> u32;
+}
+
+impl SettingValue for u32 {
+    fn from_wire(value: u32) -> Option<u32> {
+        Some(value)
+    }
+
+    fn to_wire(&self) -> u32 {
+        *self
+    }
+}
+
+impl SettingValue for bool {
+    fn from_wire(value: u32) -> Option<bool> {
+        match value {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn to_wire(&self) -> u32 {
+        *self as u32
+    }
+}
+
+/// Typed handle of a registered setting.
+pub struct SettingKey<T> {
+    id: u16,
+    marker: PhantomData<T>,
+}
+
+impl<T> SettingKey<T> {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+}
+
+impl<T> Clone for SettingKey<T> {
+    fn clone(&self) -> SettingKey<T> {
+        *self
+    }
+}
+
+impl<T> Copy for SettingKey<T> {}
+
+impl<T> fmt::Debug for SettingKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SettingKey({:#x})", self.id)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryError {
+    /// The identifier belongs to a setting the crate implements itself.
+    Standard(u16),
+    /// The identifier is reserved for GREASE.
+    Grease(u16),
+    AlreadyRegistered(u16),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegistryError::Standard(id) => write!(f, "setting {:#x} is a standard setting", id),
+            RegistryError::Grease(id) => write!(f, "setting {:#x} is reserved for GREASE", id),
+            RegistryError::AlreadyRegistered(id) => write!(f, "setting {:#x} is already registered", id),
+        }
+    }
+}
+
+/// A change of a registered setting announced by the peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Change {
+    pub id: u16,
+    pub name: &'static str,
+    /// `None` the first time the peer sends the setting.
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+struct Entry {
+    id: u16,
+    name: &'static str,
+    /// Whether the peer's raw value is acceptable for the registered type.
+    valid: fn(u32) -> bool,
+    local: Option<u32>,
+    remote: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct SettingsRegistry {
+    entries: Vec<Entry>,
+    listeners: Vec<Arc<Fn(&Change) + Send + Sync>>,
+}
+
+fn valid<T: SettingValue>(value: u32) -> bool {
+    T::from_wire(value).is_some()
+}
+
+impl SettingsRegistry {
+    pub fn new() -> SettingsRegistry {
+        SettingsRegistry::default()
+    }
+
+    /// Registers setting `id`. Until `set_local` is called nothing is sent for it.
+    pub fn register<T: SettingValue>(&mut self, id: u16, name: &'static str) -> Result<SettingKey<T>, RegistryError> {
+        if STANDARD_SETTINGS.contains(&id) {
+            return Err(RegistryError::Standard(id));
+        }
+        if grease::is_grease_setting(id) {
+            return Err(RegistryError::Grease(id));
+        }
+        if self.entry(id).is_some() {
+            return Err(RegistryError::AlreadyRegistered(id));
+        }
+        self.entries.push(Entry {
+            id: id,
+            name: name,
+            valid: valid::<T>,
+            local: None,
+            remote: None,
+        });
+        Ok(SettingKey { id: id, marker: PhantomData })
+    }
+
+    /// Calls `listener` for every change the peer makes to a registered setting.
+    pub fn on_change<F>(&mut self, listener: F) where F: Fn(&Change) + Send + Sync + 'static {
+        self.listeners.push(Arc::new(listener));
+    }
+
+    /// Sets the value we announce in our SETTINGS frame.
+    pub fn set_local<T: SettingValue>(&mut self, key: SettingKey<T>, value: T) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == key.id) {
+            entry.local = Some(value.to_wire());
+        }
+    }
+
+    /// The settings to append to our SETTINGS frame.
+    pub fn local_settings(&self) -> Vec<Setting> {
+        self.entries.iter()
+            .filter_map(|entry| entry.local.map(|value| Setting::unregistered(entry.id, value)))
+            .collect()
+    }
+
+    /// Adds our values to `settings`, among its unknown ones, for `Settings::to_payload` to send.
+    pub fn add_local(&self, settings: &mut Settings) {
+        for entry in &self.entries {
+            if let Some(value) = entry.local {
+                settings.unknown.retain(|&(id, _)| id != entry.id);
+                settings.unknown.push((entry.id, value));
+            }
+        }
+    }
+
+    /// The peer's value, or `None` if it hasn't sent the setting (i.e. doesn't support it).
+    pub fn remote<T: SettingValue>(&self, key: SettingKey<T>) -> Option<T> {
+        self.entry(key.id).and_then(|entry| entry.remote).and_then(T::from_wire)
+    }
+
+    /// The values the peer sent for registered settings, as (identifier, name, value).
+    pub fn remote_settings(&self) -> Vec<(u16, &'static str, u32)> {
+        self.entries.iter()
+            .filter_map(|entry| entry.remote.map(|value| (entry.id, entry.name, value)))
+            .collect()
+    }
+
+    /// Takes in the settings of a SETTINGS frame from the peer, as `Settings::from_payload` read
+    /// them, and returns the changes to registered settings, after notifying the listeners.
+    /// Values out of range for the registered type are ignored.
+    pub fn apply_remote(&mut self, settings: &Settings) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for &(id, value) in &settings.unknown {
+            let entry = match self.entries.iter_mut().find(|entry| entry.id == id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !(entry.valid)(value) || entry.remote == Some(value) {
+                continue;
+            }
+            changes.push(Change {
+                id: id,
+                name: entry.name,
+                old: entry.remote,
+                new: value,
+            });
+            entry.remote = Some(value);
+        }
+        for change in &changes {
+            for listener in &self.listeners {
+                listener(change);
+            }
+        }
+        changes
+    }
+
+    fn entry(&self, id: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+}
+
+impl fmt::Debug for SettingsRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<String> = self.entries.iter().map(|entry| format!("{}={:#x}", entry.name, entry.id)).collect();
+        write!(f, "SettingsRegistry {{ {} }}", ids.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http2::payload::Setting;
+    use http2::settings::Settings;
+    use super::{RegistryError, SettingsRegistry};
+
+    #[test]
+    fn test_registry() {
+        let mut registry = SettingsRegistry::new();
+        let enable = registry.register::<bool>(0xf0f0, "ENABLE_EXPERIMENT").unwrap();
+        let limit = registry.register::<u32>(0xf0f1, "EXPERIMENT_LIMIT").unwrap();
+        assert_eq!(registry.register::<bool>(0x8, "CONNECT").unwrap_err(), RegistryError::Standard(0x8));
+        assert_eq!(registry.register::<bool>(0x1a2a, "X").unwrap_err(), RegistryError::Grease(0x1a2a));
+        assert_eq!(registry.register::<u32>(0xf0f0, "X").unwrap_err(), RegistryError::AlreadyRegistered(0xf0f0));
+
+        registry.set_local(enable, true);
+        let local = registry.local_settings();
+        assert_eq!(local.len(), 1);
+        assert_eq!((local[0].raw_identifier(), local[0].value()), (0xf0f0, 1));
+        let mut settings = Settings { max_frame_size: Some(20000), unknown: vec![(0xf0f0, 0)], ..Settings::default() };
+        registry.add_local(&mut settings);
+        let payload = settings.to_payload();
+        assert_eq!(payload.iter().map(|s| (s.raw_identifier(), s.value())).collect::<Vec<_>>(),
+                   vec![(0x5, 20000), (0xf0f0, 1)]);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        registry.on_change(move |change| log.lock().unwrap().push((change.name, change.old, change.new)));
+
+        assert_eq!(registry.remote(enable), None);
+        let remote = |settings: &[Setting]| Settings::from_payload(settings).unwrap();
+        let changes = registry.apply_remote(&remote(&[Setting::unregistered(0xf0f0, 1),
+                                                      Setting::unregistered(0xf0f1, 7),
+                                                      Setting::unregistered(0xbeef, 1)]));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(registry.remote(enable), Some(true));
+        assert_eq!(registry.remote(limit), Some(7));
+
+        // Out of range for a bool: ignored. Unchanged: not reported.
+        assert!(registry.apply_remote(&remote(&[Setting::unregistered(0xf0f0, 2), Setting::unregistered(0xf0f1, 7)])).is_empty());
+        registry.apply_remote(&remote(&[Setting::unregistered(0xf0f1, 9)]));
+        assert_eq!(registry.remote_settings(), vec![(0xf0f0, "ENABLE_EXPERIMENT", 1), (0xf0f1, "EXPERIMENT_LIMIT", 9)]);
+        assert_eq!(*seen.lock().unwrap(), vec![("ENABLE_EXPERIMENT", None, 1),
+                                               ("EXPERIMENT_LIMIT", None, 7),
+                                               ("EXPERIMENT_LIMIT", Some(7), 9)]);
+    }
+}
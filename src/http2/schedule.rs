@@ -0,0 +1,240 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The write scheduler a connection picks the stream to send DATA of with. A `SendScheduler`
+//! is told which streams have DATA, what was sent and the priority signals the peer sent, which
+//! each scheduler takes the ones it understands of; besides the priority schedulers
+//! (`priority::Scheduler`, `priority_tree::PriorityTree`) there is `FairScheduler`, for the
+//! servers that ignore what clients ask for: deficit round robin, so that every stream with
+//! DATA gets its share of each round and a large download can't hold small responses back.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use http2::payload::Priority;
+use http2::priority::PriorityUpdate;
+use http2::priority_tree::DEFAULT_WEIGHT;
+use http2::StreamIdentifier;
+
+pub trait SendScheduler: fmt::Debug {
+    /// Whether stream `id` has DATA to send.
+    fn set_ready(&mut self, id: StreamIdentifier, ready: bool);
+
+    /// Forgets a stream that was closed.
+    fn close(&mut self, id: StreamIdentifier);
+
+    /// The stream to send a frame of next, if any has DATA.
+    fn next(&mut self) -> Option<StreamIdentifier>;
+
+    /// `len` octets of DATA were sent on stream `id`, the one `next` returned.
+    fn sent(&mut self, _id: StreamIdentifier, _len: usize) {}
+
+    /// The RFC 7540 priority of a HEADERS or PRIORITY frame of stream `id`, which doesn't
+    /// depend on itself.
+    fn recv_priority(&mut self, _id: StreamIdentifier, _priority: &Priority) {}
+
+    /// A PRIORITY_UPDATE frame (RFC 9218).
+    fn recv_priority_update(&mut self, _update: &PriorityUpdate) {}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FairConfig {
+    /// The octets a stream of the default weight may send per round.
+    pub quantum: usize,
+}
+
+impl Default for FairConfig {
+    fn default() -> FairConfig {
+        FairConfig { quantum: 16384 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Flow {
+    weight: u16,
+    /// What is left of its share of the round; below zero once a frame went over it.
+    deficit: i64,
+    ready: bool,
+    queued: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct FairScheduler {
+    quantum: usize,
+    flows: HashMap<StreamIdentifier, Flow>,
+    /// The streams that were ready, in turn; the ones that no longer are are skipped.
+    turns: VecDeque<StreamIdentifier>,
+}
+
+impl FairScheduler {
+    /// Panics if `config.quantum` is 0, which would give no stream a share of the rounds.
+    pub fn new(config: FairConfig) -> FairScheduler {
+        assert!(config.quantum > 0, "the quantum must be at least one octet");
+        FairScheduler {
+            quantum: config.quantum,
+            flows: HashMap::new(),
+            turns: VecDeque::new(),
+        }
+    }
+
+    /// Gives stream `id` `weight` (1 to 256) times `DEFAULT_WEIGHT`'s share of the rounds.
+    /// Panics if `weight` is 0.
+    pub fn set_weight(&mut self, id: StreamIdentifier, weight: u16) {
+        assert!(weight > 0, "stream weights start at 1");
+        self.flow(id).weight = weight;
+    }
+
+    fn flow(&mut self, id: StreamIdentifier) -> &mut Flow {
+        self.flows.entry(id).or_insert(Flow { weight: DEFAULT_WEIGHT, deficit: 0, ready: false, queued: false })
+    }
+
+    /// At least an octet, or a light stream with a small quantum would never get a turn.
+    fn share(&self, flow: &Flow) -> i64 {
+        ::std::cmp::max(1, self.quantum as u64 * flow.weight as u64 / DEFAULT_WEIGHT as u64) as i64
+    }
+}
+
+impl SendScheduler for FairScheduler {
+    fn set_ready(&mut self, id: StreamIdentifier, ready: bool) {
+        let queue = {
+            let flow = self.flow(id);
+            flow.ready = ready;
+            let queue = ready && !flow.queued;
+            if queue {
+                flow.queued = true;
+            }
+            queue
+        };
+        if queue {
+            self.turns.push_back(id);
+        }
+    }
+
+    fn close(&mut self, id: StreamIdentifier) {
+        if let Some(flow) = self.flows.remove(&id) {
+            // Or a stream opened again with the same flow would be in `turns` twice.
+            if flow.queued {
+                self.turns.retain(|&turn| turn != id);
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<StreamIdentifier> {
+        while let Some(&id) = self.turns.front() {
+            let flow = match self.flows.get(&id) {
+                Some(flow) if flow.ready => *flow,
+                Some(_) => {
+                    let flow = self.flows.get_mut(&id).unwrap();
+                    flow.queued = false;
+                    flow.deficit = 0;
+                    self.turns.pop_front();
+                    continue;
+                },
+                None => {
+                    self.turns.pop_front();
+                    continue;
+                },
+            };
+            // A new round for the stream: its share is added to what is left, going to the back
+            // while that isn't above zero.
+            if flow.deficit <= 0 {
+                let share = self.share(&flow);
+                let deficit = flow.deficit + share;
+                self.flows.get_mut(&id).unwrap().deficit = deficit;
+                if deficit <= 0 {
+                    self.turns.pop_front();
+                    self.turns.push_back(id);
+                    continue;
+                }
+            }
+            return Some(id);
+        }
+        None
+    }
+
+    fn sent(&mut self, id: StreamIdentifier, len: usize) {
+        let used_up = match self.flows.get_mut(&id) {
+            Some(flow) => {
+                flow.deficit -= len as i64;
+                flow.deficit <= 0
+            },
+            None => return,
+        };
+        if used_up && self.turns.front() == Some(&id) {
+            self.turns.pop_front();
+            self.turns.push_back(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::StreamIdentifier;
+
+    use super::{FairConfig, FairScheduler, SendScheduler};
+
+    #[test]
+    fn test_fair() {
+        let id = StreamIdentifier;
+        let mut scheduler = FairScheduler::new(FairConfig { quantum: 1000 });
+        scheduler.set_ready(id(1), true);
+        let mut sent = Vec::new();
+        let mut send = |scheduler: &mut FairScheduler, len| {
+            let next = scheduler.next().unwrap();
+            scheduler.sent(next, len);
+            sent.push(next.0);
+        };
+
+        // The download sends its round, then the small response gets a turn.
+        send(&mut scheduler, 600);
+        scheduler.set_ready(id(3), true);
+        send(&mut scheduler, 600);
+        send(&mut scheduler, 100);
+        scheduler.set_ready(id(3), false);
+        send(&mut scheduler, 600);
+        send(&mut scheduler, 600);
+
+        // A stream of twice the weight gets twice the rounds.
+        scheduler.set_weight(id(5), 32);
+        scheduler.set_ready(id(5), true);
+        for _ in 0..6 {
+            send(&mut scheduler, 500);
+        }
+        assert_eq!(sent, vec![1, 1, 3, 1, 1, 1, 1, 5, 5, 5, 5]);
+
+        scheduler.close(id(5));
+        scheduler.set_ready(id(1), false);
+        assert_eq!(scheduler.next(), None);
+
+        // Closed while queued, then ready again: one turn, not two.
+        scheduler.set_ready(id(7), true);
+        scheduler.close(id(7));
+        scheduler.set_ready(id(7), true);
+        assert_eq!(scheduler.turns.len(), 1);
+
+        // A weight whose share rounds down to nothing still gets turns.
+        let mut scheduler = FairScheduler::new(FairConfig { quantum: 1 });
+        scheduler.set_weight(id(1), 1);
+        scheduler.set_ready(id(1), true);
+        assert_eq!(scheduler.next(), Some(id(1)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_weight() {
+        FairScheduler::new(FairConfig::default()).set_weight(StreamIdentifier(1), 0);
+    }
+}
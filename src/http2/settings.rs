@@ -0,0 +1,307 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Typed SETTINGS, and the tracking of the SETTINGS frames we sent until the peer acknowledges
+//! them. A peer that doesn't acknowledge in time gets a GOAWAY with SETTINGS_TIMEOUT.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use http2::parser::{DEFAULT_MAX_FRAME_SIZE, MAX_MAX_FRAME_SIZE};
+use http2::payload::{Setting, SettingIdentifier};
+use http2::{ErrorCode, FLOW_CONTROL_ERROR, PROTOCOL_ERROR, SETTINGS_TIMEOUT};
+
+/// The values of a SETTINGS frame, or of several merged. `None` is a setting that was not sent,
+/// so that its previous (at first, initial) value still applies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Settings {
+    pub header_table_size: Option<u32>,
+    pub enable_push: Option<bool>,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: Option<u32>,
+    pub max_frame_size: Option<u32>,
+    pub max_header_list_size: Option<u32>,
+    /// RFC 8441.
+    pub enable_connect_protocol: Option<bool>,
+    /// RFC 9113.
+    pub no_rfc7540_priorities: Option<bool>,
+    /// Settings with other identifiers, e.g. extensions (see `SettingsRegistry`) and GREASE,
+    /// passed through as they are.
+    pub unknown: Vec<(u16, u32)>,
+}
+
+fn flag(value: u32) -> Result<bool, ErrorCode> {
+    match value {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(PROTOCOL_ERROR),
+    }
+}
+
+impl Settings {
+    /// Reads the settings of a SETTINGS frame, later values of an identifier replacing earlier
+    /// ones. Fails with the error code to close the connection with if a value is out of range.
+    pub fn from_payload(settings: &[Setting]) -> Result<Settings, ErrorCode> {
+        let mut result = Settings::default();
+        for setting in settings {
+            let value = setting.value();
+            match setting.identifier() {
+                Some(SettingIdentifier::HeaderTableSize) => result.header_table_size = Some(value),
+                Some(SettingIdentifier::EnablePush) => result.enable_push = Some(try!(flag(value))),
+                Some(SettingIdentifier::MaxConcurrentStreams) => result.max_concurrent_streams = Some(value),
+                Some(SettingIdentifier::InitialWindowSize) => {
+                    if value > (1 << 31) - 1 {
+                        return Err(FLOW_CONTROL_ERROR);
+                    }
+                    result.initial_window_size = Some(value);
+                },
+                Some(SettingIdentifier::MaxFrameSize) => {
+                    if value < DEFAULT_MAX_FRAME_SIZE || value > MAX_MAX_FRAME_SIZE {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    result.max_frame_size = Some(value);
+                },
+                Some(SettingIdentifier::MaxHeaderListSize) => result.max_header_list_size = Some(value),
+                Some(SettingIdentifier::EnableConnectProtocol) => {
+                    result.enable_connect_protocol = Some(try!(flag(value)))
+                },
+                Some(SettingIdentifier::NoRfc7540Priorities) => {
+                    result.no_rfc7540_priorities = Some(try!(flag(value)))
+                },
+                None => {
+                    let id = setting.raw_identifier();
+                    result.unknown.retain(|&(other, _)| other != id);
+                    result.unknown.push((id, value));
+                },
+            }
+        }
+        Ok(result)
+    }
+
+    /// The settings to send, standard ones first.
+    pub fn to_payload(&self) -> Vec<Setting> {
+        let standard = [
+            (SettingIdentifier::HeaderTableSize, self.header_table_size),
+            (SettingIdentifier::EnablePush, self.enable_push.map(|b| b as u32)),
+            (SettingIdentifier::MaxConcurrentStreams, self.max_concurrent_streams),
+            (SettingIdentifier::InitialWindowSize, self.initial_window_size),
+            (SettingIdentifier::MaxFrameSize, self.max_frame_size),
+            (SettingIdentifier::MaxHeaderListSize, self.max_header_list_size),
+            (SettingIdentifier::EnableConnectProtocol, self.enable_connect_protocol.map(|b| b as u32)),
+            (SettingIdentifier::NoRfc7540Priorities, self.no_rfc7540_priorities.map(|b| b as u32)),
+        ];
+        standard.iter()
+            .filter_map(|&(id, value)| value.map(|value| Setting::new(id, value)))
+            .chain(self.unknown.iter().map(|&(id, value)| Setting::unregistered(id, value)))
+            .collect()
+    }
+
+    /// Applies the settings of `update` on top of these.
+    pub fn merge(&mut self, update: &Settings) {
+        fn merge<T: Copy>(value: &mut Option<T>, update: Option<T>) {
+            if update.is_some() {
+                *value = update;
+            }
+        }
+        merge(&mut self.header_table_size, update.header_table_size);
+        merge(&mut self.enable_push, update.enable_push);
+        merge(&mut self.max_concurrent_streams, update.max_concurrent_streams);
+        merge(&mut self.initial_window_size, update.initial_window_size);
+        merge(&mut self.max_frame_size, update.max_frame_size);
+        merge(&mut self.max_header_list_size, update.max_header_list_size);
+        merge(&mut self.enable_connect_protocol, update.enable_connect_protocol);
+        merge(&mut self.no_rfc7540_priorities, update.no_rfc7540_priorities);
+        for &(id, value) in &update.unknown {
+            self.unknown.retain(|&(other, _)| other != id);
+            self.unknown.push((id, value));
+        }
+    }
+
+    /// The settings of these that `previous` doesn't have with the same value, i.e. what a
+    /// SETTINGS frame has to carry to go from `previous` to these.
+    pub fn diff(&self, previous: &Settings) -> Settings {
+        fn diff<T: Copy + PartialEq>(value: Option<T>, previous: Option<T>) -> Option<T> {
+            if value == previous { None } else { value }
+        }
+        Settings {
+            header_table_size: diff(self.header_table_size, previous.header_table_size),
+            enable_push: diff(self.enable_push, previous.enable_push),
+            max_concurrent_streams: diff(self.max_concurrent_streams, previous.max_concurrent_streams),
+            initial_window_size: diff(self.initial_window_size, previous.initial_window_size),
+            max_frame_size: diff(self.max_frame_size, previous.max_frame_size),
+            max_header_list_size: diff(self.max_header_list_size, previous.max_header_list_size),
+            enable_connect_protocol: diff(self.enable_connect_protocol, previous.enable_connect_protocol),
+            no_rfc7540_priorities: diff(self.no_rfc7540_priorities, previous.no_rfc7540_priorities),
+            unknown: self.unknown.iter()
+                .filter(|setting| !previous.unknown.contains(setting))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Settings::default()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SettingsConfig {
+    /// Time the peer has to acknowledge each SETTINGS frame.
+    pub timeout: Duration,
+}
+
+impl Default for SettingsConfig {
+    fn default() -> SettingsConfig {
+        SettingsConfig { timeout: Duration::from_secs(10) }
+    }
+}
+
+/// Our SETTINGS frames, from being sent until the peer acknowledges them.
+#[derive(Clone, Debug)]
+pub struct SettingsTracker {
+    timeout: Duration,
+    /// Everything sent so far, acknowledged or not.
+    sent: Settings,
+    /// Everything the peer acknowledged, which is what applies to what it sends.
+    acknowledged: Settings,
+    /// Oldest first, with their deadlines.
+    unacked: VecDeque<(Settings, Instant)>,
+}
+
+impl SettingsTracker {
+    pub fn new(config: SettingsConfig) -> SettingsTracker {
+        SettingsTracker {
+            timeout: config.timeout,
+            sent: Settings::default(),
+            acknowledged: Settings::default(),
+            unacked: VecDeque::new(),
+        }
+    }
+
+    /// Returns the payload of a SETTINGS frame moving the peer to `settings`, with only what
+    /// changed since the previous frames, and starts waiting for its ACK. `None` if nothing
+    /// changed; the connection preface needs a SETTINGS frame regardless, see `send_all`.
+    pub fn send(&mut self, settings: &Settings, now: Instant) -> Option<Vec<Setting>> {
+        let diff = settings.diff(&self.sent);
+        if diff.is_empty() {
+            return None;
+        }
+        Some(self.send_all(diff, now))
+    }
+
+    /// Returns the payload of a SETTINGS frame with all of `settings`, and starts waiting for
+    /// its ACK.
+    pub fn send_all(&mut self, settings: Settings, now: Instant) -> Vec<Setting> {
+        let payload = settings.to_payload();
+        self.sent.merge(&settings);
+        self.unacked.push_back((settings, now + self.timeout));
+        payload
+    }
+
+    /// Takes in a SETTINGS ACK. Returns the settings of the frame it acknowledges, which apply
+    /// from now on, or `None` if none was waiting for one.
+    pub fn recv_ack(&mut self) -> Option<Settings> {
+        let (settings, _) = match self.unacked.pop_front() {
+            Some(unacked) => unacked,
+            None => return None,
+        };
+        self.acknowledged.merge(&settings);
+        Some(settings)
+    }
+
+    /// Everything the peer acknowledged so far.
+    pub fn acknowledged(&self) -> &Settings {
+        &self.acknowledged
+    }
+
+    /// The number of SETTINGS frames waiting for their ACK.
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// When the connection's timer should fire next, or `None` if nothing waits for an ACK.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.unacked.front().map(|&(_, deadline)| deadline)
+    }
+
+    /// Fails with SETTINGS_TIMEOUT, to close the connection with, once the oldest SETTINGS
+    /// frame waited more than the timeout for its ACK.
+    pub fn check(&self, now: Instant) -> Result<(), ErrorCode> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => Err(SETTINGS_TIMEOUT),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http2::payload::{Setting, SettingIdentifier};
+    use http2::{FLOW_CONTROL_ERROR, PROTOCOL_ERROR, SETTINGS_TIMEOUT};
+
+    use super::{Settings, SettingsConfig, SettingsTracker};
+
+    #[test]
+    fn test_payload() {
+        let payload = [Setting::new(SettingIdentifier::EnablePush, 0),
+                       Setting::new(SettingIdentifier::MaxConcurrentStreams, 100),
+                       Setting::unregistered(0x0a0a, 7),
+                       Setting::new(SettingIdentifier::MaxConcurrentStreams, 50)];
+        let settings = Settings::from_payload(&payload).unwrap();
+        assert_eq!(settings, Settings {
+            enable_push: Some(false),
+            max_concurrent_streams: Some(50),
+            unknown: vec![(0x0a0a, 7)],
+            ..Settings::default()
+        });
+        assert_eq!(settings.to_payload(), vec![payload[0], payload[3], payload[2]]);
+
+        assert_eq!(Settings::from_payload(&[Setting::new(SettingIdentifier::EnablePush, 2)]), Err(PROTOCOL_ERROR));
+        assert_eq!(Settings::from_payload(&[Setting::new(SettingIdentifier::InitialWindowSize, 1 << 31)]),
+                   Err(FLOW_CONTROL_ERROR));
+        assert_eq!(Settings::from_payload(&[Setting::new(SettingIdentifier::MaxFrameSize, 16383)]), Err(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn test_tracker() {
+        let now = Instant::now();
+        let mut tracker = SettingsTracker::new(SettingsConfig::default());
+        let mut settings = Settings {
+            max_concurrent_streams: Some(100),
+            initial_window_size: Some(1 << 20),
+            ..Settings::default()
+        };
+        assert_eq!(tracker.send_all(settings.clone(), now).len(), 2);
+        assert_eq!(tracker.send(&settings, now), None);
+        settings.initial_window_size = Some(1 << 16);
+        assert_eq!(tracker.send(&settings, now + Duration::from_secs(5)),
+                   Some(vec![Setting::new(SettingIdentifier::InitialWindowSize, 1 << 16)]));
+        assert_eq!(tracker.unacked(), 2);
+
+        assert_eq!(tracker.check(now + Duration::from_secs(9)), Ok(()));
+        assert_eq!(tracker.recv_ack().unwrap().max_concurrent_streams, Some(100));
+        assert_eq!(tracker.acknowledged().initial_window_size, Some(1 << 20));
+        // The deadline is now that of the second frame.
+        assert_eq!(tracker.check(now + Duration::from_secs(12)), Ok(()));
+        assert_eq!(tracker.check(now + Duration::from_secs(15)), Err(SETTINGS_TIMEOUT));
+        tracker.recv_ack();
+        assert_eq!(tracker.acknowledged(), &settings);
+        assert_eq!(tracker.deadline(), None);
+        assert_eq!(tracker.recv_ack(), None);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Slow-read detection. A peer can hold a server's response data hostage by never granting flow
+//! control window, or by granting it a few bytes at a time (slowloris over flow control). The
+//! `Connection` reports the send side of every stream to a `StallDetector` and polls it in
+//! `poll_stalls`; stalled streams are reset and, once too many were, the whole connection closed.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use http2::ErrorCode;
+use http2::StreamIdentifier;
+use http2::ENHANCE_YOUR_CALM;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StallConfig {
+    /// How long a stream with queued data may wait for window before it is reset.
+    pub max_blocked: Duration,
+    /// Minimum number of bytes a stream with queued data has to move per `rate_period`.
+    pub min_bytes_per_period: u64,
+    pub rate_period: Duration,
+    /// Number of stalled streams after which the connection itself is given up on.
+    pub max_stalled_streams: usize,
+}
+
+impl Default for StallConfig {
+    fn default() -> StallConfig {
+        StallConfig {
+            max_blocked: Duration::from_secs(30),
+            min_bytes_per_period: 1024,
+            rate_period: Duration::from_secs(10),
+            max_stalled_streams: 8,
+        }
+    }
+}
+
+/// Verdict returned by `StallDetector::poll`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stall {
+    /// The stream should be reset.
+    Stream(StreamIdentifier),
+    /// Too many streams stalled: the connection should be closed.
+    Connection,
+}
+
+impl Stall {
+    /// Error code for the RST_STREAM or GOAWAY.
+    pub fn error_code(&self) -> ErrorCode {
+        ENHANCE_YOUR_CALM
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Progress {
+    blocked_since: Option<Instant>,
+    period_start: Instant,
+    period_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct StallDetector {
+    config: StallConfig,
+    // Only streams that currently have data queued for sending are tracked.
+    streams: HashMap<StreamIdentifier, Progress>,
+    stalled: usize,
+}
+
+impl StallDetector {
+    pub fn new(config: StallConfig) -> StallDetector {
+        StallDetector {
+            config: config,
+            streams: HashMap::new(),
+            stalled: 0,
+        }
+    }
+
+    /// The stream has data queued for sending.
+    pub fn queued(&mut self, id: StreamIdentifier, now: Instant) {
+        self.streams.entry(id).or_insert(Progress {
+            blocked_since: None,
+            period_start: now,
+            period_bytes: 0,
+        });
+    }
+
+    /// The stream can't send because its (or the connection's) window is exhausted.
+    pub fn blocked(&mut self, id: StreamIdentifier, now: Instant) {
+        self.queued(id, now);
+        if let Some(progress) = self.streams.get_mut(&id) {
+            if progress.blocked_since.is_none() {
+                progress.blocked_since = Some(now);
+            }
+        }
+    }
+
+    /// `bytes` of DATA were written for the stream.
+    pub fn sent(&mut self, id: StreamIdentifier, bytes: u64) {
+        if let Some(progress) = self.streams.get_mut(&id) {
+            progress.blocked_since = None;
+            progress.period_bytes += bytes;
+        }
+    }
+
+    /// The stream has nothing queued any more (drained, reset or closed).
+    pub fn drained(&mut self, id: StreamIdentifier) {
+        self.streams.remove(&id);
+    }
+
+    /// Number of streams found stalled so far on this connection.
+    pub fn stalled_streams(&self) -> usize {
+        self.stalled
+    }
+
+    /// Returns the streams found stalled at `now`, and `Stall::Connection` once
+    /// `max_stalled_streams` is exceeded. Stalled streams stop being tracked.
+    pub fn poll(&mut self, now: Instant) -> Vec<Stall> {
+        let config = self.config;
+        let mut stalls = Vec::new();
+
+        for (id, progress) in self.streams.iter_mut() {
+            let blocked_too_long = match progress.blocked_since {
+                Some(since) => now.duration_since(since) > config.max_blocked,
+                None => false,
+            };
+            let mut too_slow = false;
+            if now.duration_since(progress.period_start) >= config.rate_period {
+                too_slow = progress.period_bytes < config.min_bytes_per_period;
+                progress.period_start = now;
+                progress.period_bytes = 0;
+            }
+            if blocked_too_long || too_slow {
+                stalls.push(Stall::Stream(*id));
+            }
+        }
+
+        for stall in &stalls {
+            if let Stall::Stream(ref id) = *stall {
+                self.streams.remove(id);
+            }
+        }
+        self.stalled += stalls.len();
+        if !stalls.is_empty() && self.stalled > config.max_stalled_streams {
+            stalls.push(Stall::Connection);
+        }
+        stalls
+    }
+}
+
+impl Default for StallDetector {
+    fn default() -> StallDetector {
+        StallDetector::new(StallConfig::default())
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Per-stream flow control and timing statistics. The connection keeps a `StatsRecorder` for
+//! every stream and reports what happens to it; `Connection::stream_stats` hands out the
+//! `StreamStats` snapshot, which tells whether a slow request spent its time in the handler,
+//! waiting for send window or sitting in the write queue (as `Connection::queued` reports it).
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Total time data was queued but the send window (stream or connection) was exhausted.
+    pub blocked: Duration,
+    /// WINDOW_UPDATE frames received from the peer for this stream.
+    pub window_updates_received: u32,
+    /// WINDOW_UPDATE frames we sent for this stream.
+    pub window_updates_sent: u32,
+    /// Time from the request headers arriving to the response headers being queued.
+    pub handler_time: Option<Duration>,
+    /// Average and worst time between data being queued and being written to the connection.
+    pub mean_queue_latency: Duration,
+    pub max_queue_latency: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct StatsRecorder {
+    stats: StreamStats,
+    opened: Instant,
+    blocked_since: Option<Instant>,
+    // Data waiting to be written: when each piece was queued and how much of it is left.
+    queue: VecDeque<(Instant, usize)>,
+    queue_latency_total: Duration,
+    queue_latency_samples: u32,
+}
+
+impl StatsRecorder {
+    /// Starts recording for a stream whose request headers arrived (or were sent) at `now`.
+    pub fn new(now: Instant) -> StatsRecorder {
+        StatsRecorder {
+            stats: StreamStats::default(),
+            opened: now,
+            blocked_since: None,
+            queue: VecDeque::new(),
+            queue_latency_total: Duration::from_secs(0),
+            queue_latency_samples: 0,
+        }
+    }
+
+    pub fn response_started(&mut self, now: Instant) {
+        if self.stats.handler_time.is_none() {
+            self.stats.handler_time = Some(now.duration_since(self.opened));
+        }
+    }
+
+    /// `len` bytes of DATA were handed to the stream for sending.
+    pub fn queued(&mut self, len: usize, now: Instant) {
+        if len > 0 {
+            self.queue.push_back((now, len));
+        }
+    }
+
+    /// `len` bytes of queued DATA were written to the connection.
+    pub fn sent(&mut self, mut len: usize, now: Instant) {
+        self.stats.bytes_sent += len as u64;
+        while len > 0 {
+            let (since, left) = match self.queue.front_mut() {
+                Some(front) => {
+                    let taken = if front.1 < len { front.1 } else { len };
+                    front.1 -= taken;
+                    len -= taken;
+                    (front.0, front.1)
+                }
+                None => break,
+            };
+            if left == 0 {
+                self.queue.pop_front();
+                let latency = now.duration_since(since);
+                self.queue_latency_total += latency;
+                self.queue_latency_samples += 1;
+                if latency > self.stats.max_queue_latency {
+                    self.stats.max_queue_latency = latency;
+                }
+            }
+        }
+    }
+
+    pub fn received(&mut self, len: usize) {
+        self.stats.bytes_received += len as u64;
+    }
+
+    /// Data is queued but there is no window to send it.
+    pub fn blocked(&mut self, now: Instant) {
+        if self.blocked_since.is_none() {
+            self.blocked_since = Some(now);
+        }
+    }
+
+    /// Window became available again.
+    pub fn unblocked(&mut self, now: Instant) {
+        if let Some(since) = self.blocked_since.take() {
+            self.stats.blocked += now.duration_since(since);
+        }
+    }
+
+    pub fn window_update_received(&mut self) {
+        self.stats.window_updates_received += 1;
+    }
+
+    pub fn window_update_sent(&mut self) {
+        self.stats.window_updates_sent += 1;
+    }
+
+    /// The statistics so far; time blocked includes a block still in progress.
+    pub fn stats(&self, now: Instant) -> StreamStats {
+        let mut stats = self.stats;
+        if let Some(since) = self.blocked_since {
+            stats.blocked += now.duration_since(since);
+        }
+        if self.queue_latency_samples > 0 {
+            stats.mean_queue_latency = self.queue_latency_total / self.queue_latency_samples;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::StatsRecorder;
+
+    #[test]
+    fn test_stats() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut recorder = StatsRecorder::new(start);
+        recorder.received(100);
+        recorder.response_started(ms(40));
+        recorder.queued(1000, ms(40));
+        recorder.queued(500, ms(50));
+        recorder.sent(600, ms(60));
+        recorder.blocked(ms(60));
+        assert_eq!(recorder.stats(ms(70)).blocked, Duration::from_millis(10));
+        recorder.window_update_received();
+        recorder.unblocked(ms(100));
+        recorder.sent(900, ms(110));
+
+        let stats = recorder.stats(ms(200));
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (1500, 100));
+        assert_eq!(stats.handler_time, Some(Duration::from_millis(40)));
+        assert_eq!(stats.blocked, Duration::from_millis(40));
+        assert_eq!(stats.window_updates_received, 1);
+        assert_eq!(stats.max_queue_latency, Duration::from_millis(70));
+        assert_eq!(stats.mean_queue_latency, Duration::from_millis(65));
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Stream states (RFC 9113 section 5.1). The two directions of a stream close independently:
+//! `SendBody::finish` ends our half with END_STREAM while the peer can keep sending, which is
+//! what long polls and streaming RPCs rely on (a client finishing the request and reading a
+//! long response, or a server answering before the upload is done).
+
+use std::cmp;
+
+use http2::flow::Window;
+use http2::frame::{Frame, FrameHeader};
+use http2::flag::{DataFlags, Flag};
+use http2::kind::Kind;
+use http2::parser::DEFAULT_MAX_FRAME_SIZE;
+use http2::payload::Payload;
+use http2::window_update::DEFAULT_WINDOW_SIZE;
+use http2::{Error, ErrorCode};
+use http2::StreamIdentifier;
+use http2::{PROTOCOL_ERROR, STREAM_CLOSED};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum State {
+    Idle,
+    ReservedLocal,
+    ReservedRemote,
+    Open,
+    /// We sent END_STREAM; the peer may still send.
+    HalfClosedLocal,
+    /// The peer sent END_STREAM; we may still send.
+    HalfClosedRemote,
+    Closed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamError {
+    pub id: StreamIdentifier,
+    /// Error code of the RST_STREAM to answer a frame received in the wrong state with.
+    pub code: ErrorCode,
+}
+
+impl StreamError {
+    /// The RST_STREAM to answer with.
+    pub fn frame(&self) -> Frame<'static> {
+        Frame::reset(self.id, self.code)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Stream {
+    pub id: StreamIdentifier,
+    state: State,
+    /// The peer's SETTINGS_MAX_FRAME_SIZE.
+    max_frame_size: u32,
+    send_window: Window,
+    recv_window: Window,
+}
+
+impl Stream {
+    pub fn new(id: StreamIdentifier) -> Stream {
+        Stream {
+            id: id,
+            state: State::Idle,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            send_window: Window::new(DEFAULT_WINDOW_SIZE),
+            recv_window: Window::new(DEFAULT_WINDOW_SIZE),
+        }
+    }
+
+    /// A stream reserved by a PUSH_PROMISE we sent (`local`) or received.
+    pub fn reserved(id: StreamIdentifier, local: bool) -> Stream {
+        Stream {
+            id: id,
+            state: if local { State::ReservedLocal } else { State::ReservedRemote },
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            send_window: Window::new(DEFAULT_WINDOW_SIZE),
+            recv_window: Window::new(DEFAULT_WINDOW_SIZE),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Sets the peer's SETTINGS_MAX_FRAME_SIZE, which `SendBody` splits DATA by. Panics if it
+    /// is zero.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        assert!(max_frame_size > 0, "the maximum frame size must be at least one octet");
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// How much DATA we may send on the stream, as far as its own window goes.
+    pub fn send_window(&self) -> Window {
+        self.send_window
+    }
+
+    pub fn send_window_mut(&mut self) -> &mut Window {
+        &mut self.send_window
+    }
+
+    /// How much DATA the peer may send on the stream.
+    pub fn recv_window(&self) -> Window {
+        self.recv_window
+    }
+
+    pub fn recv_window_mut(&mut self) -> &mut Window {
+        &mut self.recv_window
+    }
+
+    /// Sets both windows to the SETTINGS_INITIAL_WINDOW_SIZE of their sides: the peer's for
+    /// sending and ours for receiving.
+    pub fn set_initial_windows(&mut self, send: u32, recv: u32) {
+        self.send_window = Window::new(send);
+        self.recv_window = Window::new(recv);
+    }
+
+    pub fn can_send(&self) -> bool {
+        match self.state {
+            State::Open | State::HalfClosedRemote => true,
+            _ => false,
+        }
+    }
+
+    pub fn can_recv(&self) -> bool {
+        match self.state {
+            State::Open | State::HalfClosedLocal => true,
+            _ => false,
+        }
+    }
+
+    pub fn send_headers(&mut self, end_stream: bool) -> Result<(), StreamError> {
+        self.state = match self.state {
+            State::Idle => State::Open,
+            State::ReservedLocal => State::HalfClosedRemote,
+            State::Open | State::HalfClosedRemote => self.state,
+            _ => return Err(self.error(STREAM_CLOSED)),
+        };
+        if end_stream {
+            self.close_local();
+        }
+        Ok(())
+    }
+
+    pub fn recv_headers(&mut self, end_stream: bool) -> Result<(), StreamError> {
+        self.state = match self.state {
+            State::Idle => State::Open,
+            State::ReservedRemote => State::HalfClosedLocal,
+            State::Open | State::HalfClosedLocal => self.state,
+            State::ReservedLocal => return Err(self.error(PROTOCOL_ERROR)),
+            _ => return Err(self.error(STREAM_CLOSED)),
+        };
+        if end_stream {
+            self.close_remote();
+        }
+        Ok(())
+    }
+
+    pub fn send_data(&mut self, end_stream: bool) -> Result<(), StreamError> {
+        if !self.can_send() {
+            return Err(self.error(STREAM_CLOSED));
+        }
+        if end_stream {
+            self.close_local();
+        }
+        Ok(())
+    }
+
+    pub fn recv_data(&mut self, end_stream: bool) -> Result<(), StreamError> {
+        if !self.can_recv() {
+            return Err(self.error(match self.state {
+                State::Idle => PROTOCOL_ERROR,
+                _ => STREAM_CLOSED,
+            }));
+        }
+        if end_stream {
+            self.close_remote();
+        }
+        Ok(())
+    }
+
+    /// RST_STREAM sent or received: both halves are closed at once.
+    pub fn reset(&mut self) {
+        self.state = State::Closed;
+    }
+
+    /// Closes the stream and returns the RST_STREAM to send, or `None` if the stream is idle:
+    /// it can't be reset before it was opened.
+    pub fn send_reset(&mut self, code: ErrorCode) -> Option<Frame<'static>> {
+        if self.state == State::Idle {
+            return None;
+        }
+        self.reset();
+        Some(self.error(code).frame())
+    }
+
+    /// Takes in a RST_STREAM received for the stream, which closes it.
+    ///
+    /// Errors are connection errors (see `Error`).
+    pub fn recv_reset(&mut self) -> Result<(), Error> {
+        if self.state == State::Idle {
+            return Err(Error::InvalidReset);
+        }
+        self.reset();
+        Ok(())
+    }
+
+    /// The sending half of an open stream, for writing the body after the headers were sent.
+    pub fn send_body(&mut self) -> Result<SendBody, StreamError> {
+        if !self.can_send() {
+            return Err(self.error(STREAM_CLOSED));
+        }
+        Ok(SendBody { stream: self })
+    }
+
+    fn close_local(&mut self) {
+        self.state = match self.state {
+            State::HalfClosedRemote => State::Closed,
+            _ => State::HalfClosedLocal,
+        };
+    }
+
+    fn close_remote(&mut self) {
+        self.state = match self.state {
+            State::HalfClosedLocal => State::Closed,
+            _ => State::HalfClosedRemote,
+        };
+    }
+
+    fn error(&self, code: ErrorCode) -> StreamError {
+        StreamError {
+            id: self.id,
+            code: code,
+        }
+    }
+}
+
+/// The streams reserved by PUSH_PROMISE on a connection, from either side: a server promises
+/// even stream ids in increasing order, on streams the client opened, and only while the
+/// client's SETTINGS_ENABLE_PUSH allows it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Pushes {
+    server: bool,
+    enable_push: bool,
+    last_promised: u32,
+}
+
+impl Pushes {
+    /// Push starts enabled, as SETTINGS_ENABLE_PUSH does.
+    pub fn new(server: bool) -> Pushes {
+        Pushes {
+            server: server,
+            enable_push: true,
+            last_promised: 0,
+        }
+    }
+
+    /// The SETTINGS_ENABLE_PUSH of the client: the one it sent, on a server, or the one it
+    /// sent and got acknowledged, on a client.
+    pub fn set_enable_push(&mut self, enable_push: bool) {
+        self.enable_push = enable_push;
+    }
+
+    pub fn enable_push(&self) -> bool {
+        self.enable_push
+    }
+
+    /// The highest stream id promised so far, 0 if none.
+    pub fn last_promised(&self) -> StreamIdentifier {
+        StreamIdentifier(self.last_promised)
+    }
+
+    /// Reserves the next push stream for a PUSH_PROMISE we send on `associated`. `None` on a
+    /// client, if push is disabled, if `associated` is not open for sending or if stream ids
+    /// ran out.
+    pub fn promise(&mut self, associated: &Stream) -> Option<Stream> {
+        let next = self.last_promised + 2;
+        if !self.server || !self.enable_push || !associated.can_send() || next >= 1 << 31 {
+            return None;
+        }
+        self.last_promised = next;
+        Some(Stream::reserved(StreamIdentifier(next), true))
+    }
+
+    /// Takes in a PUSH_PROMISE received on `associated`, and returns the stream it reserves.
+    ///
+    /// Errors are connection errors (see `Error`).
+    pub fn recv_promise(&mut self, associated: &Stream, promised: StreamIdentifier) -> Result<Stream, Error> {
+        let valid_state = match associated.state() {
+            State::Open | State::HalfClosedLocal => true,
+            _ => false,
+        };
+        if self.server || !self.enable_push || !valid_state || promised.0 % 2 != 0 ||
+                promised.0 <= self.last_promised {
+            return Err(Error::InvalidPushPromise);
+        }
+        self.last_promised = promised.0;
+        Ok(Stream::reserved(promised, false))
+    }
+}
+
+/// Writes DATA frames for a stream into `out`. Dropping it without `finish` leaves the stream
+/// open, e.g. to send trailers instead.
+pub struct SendBody<'a> {
+    stream: &'a mut Stream,
+}
+
+impl<'a> SendBody<'a> {
+    /// Appends DATA frames carrying `data`, as many as the peer's maximum frame size takes;
+    /// the caller is responsible for flow control.
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(), StreamError> {
+        try!(self.stream.send_data(false));
+        for frame in split_data(self.stream.id, data, false, self.stream.max_frame_size as usize) {
+            encode_frame(&frame, out);
+        }
+        Ok(())
+    }
+
+    /// Appends an empty DATA frame with END_STREAM, closing only our half: the peer's data can
+    /// still be received. Returns the new state, `Closed` if the peer had already finished.
+    pub fn finish(self, out: &mut Vec<u8>) -> Result<State, StreamError> {
+        try!(self.stream.send_data(true));
+        encode_data(self.stream.id, &[], Flag::end_stream(), out);
+        Ok(self.stream.state)
+    }
+}
+
+/// Splits `data` into DATA frames with payloads of at most `max_frame_size`, the peer's
+/// SETTINGS_MAX_FRAME_SIZE; with `end_stream`, the last one gets END_STREAM. Empty `data`
+/// still takes a frame. Panics if `max_frame_size` is zero.
+pub fn split_data<'a>(id: StreamIdentifier, data: &'a [u8], end_stream: bool, max_frame_size: usize) -> Vec<Frame<'a>> {
+    assert!(max_frame_size > 0, "the maximum frame size must be at least one octet");
+    let mut frames = Vec::with_capacity(data.len() / max_frame_size + 1);
+    let mut rest = data;
+    loop {
+        let (chunk, next) = rest.split_at(cmp::min(rest.len(), max_frame_size));
+        let flags = if end_stream && next.is_empty() { DataFlags::end_stream() } else { DataFlags::empty() };
+        frames.push(Frame::data(flags, id, chunk));
+        rest = next;
+        if rest.is_empty() {
+            return frames;
+        }
+    }
+}
+
+fn encode_data(id: StreamIdentifier, data: &[u8], flag: Flag, out: &mut Vec<u8>) {
+    let frame = Frame {
+        header: FrameHeader {
+            length: data.len() as u32,
+            kind: Kind::Data,
+            flag: flag,
+            id: id,
+        },
+        payload: Payload::Data { data: data },
+    };
+    encode_frame(&frame, out);
+}
+
+fn encode_frame(frame: &Frame, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.resize(start + frame.encoded_len(), 0);
+    frame.encode(&mut out[start..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::{Error, ErrorCode, StreamIdentifier};
+    use http2::{CANCEL, STREAM_CLOSED};
+    use super::{split_data, Pushes, State, Stream};
+
+    #[test]
+    fn test_half_close() {
+        // Client: request headers, a body, then END_STREAM while the response keeps coming.
+        let mut stream = Stream::new(StreamIdentifier(1));
+        stream.send_headers(false).unwrap();
+        let mut out = Vec::new();
+        {
+            let mut body = stream.send_body().unwrap();
+            body.write(b"hello", &mut out).unwrap();
+            assert_eq!(body.finish(&mut out).unwrap(), State::HalfClosedLocal);
+        }
+        assert_eq!(out, b"\0\0\x05\0\0\0\0\0\x01hello\0\0\0\0\x01\0\0\0\x01".to_vec());
+        assert!(stream.can_recv() && !stream.can_send());
+        assert_eq!(stream.send_data(false).unwrap_err().code, STREAM_CLOSED);
+
+        stream.recv_headers(false).unwrap();
+        stream.recv_data(false).unwrap();
+        stream.recv_data(true).unwrap();
+        assert_eq!(stream.state(), State::Closed);
+
+        // Server: the request is done, the response is still being streamed.
+        let mut stream = Stream::new(StreamIdentifier(3));
+        stream.recv_headers(true).unwrap();
+        assert_eq!(stream.state(), State::HalfClosedRemote);
+        stream.send_headers(false).unwrap();
+        assert_eq!(stream.recv_data(false).unwrap_err().code, STREAM_CLOSED);
+        assert_eq!(stream.send_body().unwrap().finish(&mut Vec::new()).unwrap(), State::Closed);
+    }
+
+    #[test]
+    fn test_push() {
+        // Server: promises on the client's request stream, then the pushed response.
+        let mut request = Stream::new(StreamIdentifier(1));
+        request.recv_headers(true).unwrap();
+        let mut server = Pushes::new(true);
+        let mut pushed = server.promise(&request).unwrap();
+        assert_eq!((pushed.id, pushed.state()), (StreamIdentifier(2), State::ReservedLocal));
+        assert_eq!(server.promise(&request).unwrap().id, StreamIdentifier(4));
+        pushed.send_headers(false).unwrap();
+        assert_eq!(pushed.state(), State::HalfClosedRemote);
+        assert!(pushed.recv_data(false).is_err());
+        server.set_enable_push(false);
+        assert_eq!(server.promise(&request), None);
+
+        // Client.
+        let mut request = Stream::new(StreamIdentifier(1));
+        request.send_headers(true).unwrap();
+        let mut client = Pushes::new(false);
+        assert_eq!(client.promise(&request), None);
+        let mut pushed = client.recv_promise(&request, StreamIdentifier(2)).unwrap();
+        assert_eq!(pushed.state(), State::ReservedRemote);
+        pushed.recv_headers(false).unwrap();
+        assert_eq!(pushed.state(), State::HalfClosedLocal);
+        assert_eq!(client.last_promised(), StreamIdentifier(2));
+        assert_eq!(client.recv_promise(&request, StreamIdentifier(2)), Err(Error::InvalidPushPromise));
+        assert_eq!(client.recv_promise(&request, StreamIdentifier(5)), Err(Error::InvalidPushPromise));
+        request.reset();
+        assert_eq!(client.recv_promise(&request, StreamIdentifier(6)), Err(Error::InvalidPushPromise));
+        assert_eq!(server.recv_promise(&request, StreamIdentifier(6)), Err(Error::InvalidPushPromise));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut stream = Stream::new(StreamIdentifier(1));
+        assert_eq!(stream.send_reset(CANCEL), None);
+        assert_eq!(stream.recv_reset(), Err(Error::InvalidReset));
+        assert_eq!(Error::InvalidReset.error_code(), ErrorCode::ProtocolError);
+
+        stream.send_headers(false).unwrap();
+        let reset = stream.send_reset(CANCEL).unwrap();
+        assert_eq!(stream.state(), State::Closed);
+        let mut buf = Vec::new();
+        reset.serialize_into(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 4, 3, 0, 0, 0, 0, 1, 0, 0, 0, 8]);
+        assert_eq!(Frame::from_bytes(&buf).unwrap().payload, Payload::Reset(CANCEL));
+
+        // A stream error answered with RST_STREAM; unknown codes are kept.
+        let error = stream.send_data(false).unwrap_err();
+        assert_eq!(error.frame().payload, Payload::Reset(STREAM_CLOSED));
+        buf[12] = 0xee;
+        assert_eq!(Frame::from_bytes(&buf).unwrap().payload, Payload::Reset(ErrorCode::Unknown(0xee)));
+
+        let mut stream = Stream::new(StreamIdentifier(3));
+        stream.recv_headers(true).unwrap();
+        assert_eq!(stream.recv_reset(), Ok(()));
+        assert_eq!(stream.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let frames = split_data(StreamIdentifier(1), &[0; 40000], true, 16384);
+        let lengths: Vec<_> = frames.iter().map(|f| (f.header.length, f.header.flag)).collect();
+        assert_eq!(lengths, vec![(16384, Flag::empty()), (16384, Flag::empty()), (7232, Flag::end_stream())]);
+        assert_eq!(split_data(StreamIdentifier(1), b"", true, 16384).len(), 1);
+
+        let mut stream = Stream::new(StreamIdentifier(1));
+        stream.send_headers(false).unwrap();
+        stream.set_max_frame_size(2);
+        let mut out = Vec::new();
+        stream.send_body().unwrap().write(b"hello", &mut out).unwrap();
+        assert_eq!(out, b"\0\0\x02\0\0\0\0\0\x01he\0\0\x02\0\0\0\0\0\x01ll\0\0\x01\0\0\0\0\0\x01o".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one octet")]
+    fn test_zero_max_frame_size() {
+        Stream::new(StreamIdentifier(1)).set_max_frame_size(0);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Frame tracing. A connection given a `TraceHook` calls it with every frame it sends and
+//! receives, after TLS, so that protocol issues can be debugged in production without a packet
+//! capture and the keys to decrypt it. `TraceHook::log()` writes the frames to the `log` target
+//! `tokio_http2::frames` at the trace level:
+//!
+//! ```text
+//! recv HEADERS stream=1 flags=0x5 length=13 | 82 86 84 41 8a 08 9d 5c 0b 81 70 dc 78 ...
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use http2::flag::Flag;
+use http2::frame::FrameHeader;
+use http2::kind::Kind;
+use http2::StreamIdentifier;
+
+/// The `log` target of `TraceHook::log()`.
+pub const LOG_TARGET: &'static str = "tokio_http2::frames";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A frame that was sent or received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FrameEvent<'a> {
+    pub direction: Direction,
+    pub kind: Kind,
+    pub id: StreamIdentifier,
+    pub flag: Flag,
+    pub length: u32,
+    /// The start of the payload, as much of it as the hook was set to dump; empty by default.
+    pub dump: &'a [u8],
+}
+
+impl<'a> fmt::Display for FrameEvent<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Sent => "send",
+            Direction::Received => "recv",
+        };
+        try!(match self.kind {
+            Kind::Unregistered(kind) => write!(f, "{} {:#x}", direction, kind),
+            kind => write!(f, "{} {}", direction, kind.name()),
+        });
+        try!(write!(f, " stream={} flags={:#x} length={}", self.id.0, self.flag.bits(), self.length));
+        if !self.dump.is_empty() {
+            try!(write!(f, " | {}", hex_dump(self.dump)));
+            if (self.dump.len() as u32) < self.length {
+                try!(f.write_str(" ..."));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `data` as space separated hex octets.
+pub fn hex_dump(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// Shared by the connections it is given to; cheap to clone.
+#[derive(Clone)]
+pub struct TraceHook {
+    callback: Arc<Fn(&FrameEvent) + Send + Sync>,
+    dump_len: usize,
+}
+
+impl TraceHook {
+    pub fn new<F>(callback: F) -> TraceHook
+            where F: Fn(&FrameEvent) + Send + Sync + 'static {
+        TraceHook {
+            callback: Arc::new(callback),
+            dump_len: 0,
+        }
+    }
+
+    /// A hook that logs every frame to `LOG_TARGET`.
+    pub fn log() -> TraceHook {
+        TraceHook::new(|event| trace!(target: LOG_TARGET, "{}", event))
+    }
+
+    /// Dumps up to `dump_len` octets of every payload. Payloads carry header blocks (cookies
+    /// and authorization included) and bodies, so keep it off where that is a concern.
+    pub fn dump(mut self, dump_len: usize) -> TraceHook {
+        self.dump_len = dump_len;
+        self
+    }
+
+    /// Reports a frame written to the peer; `payload` is its payload as on the wire.
+    pub fn sent(&self, header: &FrameHeader, payload: &[u8]) {
+        self.report(Direction::Sent, header, payload);
+    }
+
+    /// Reports a frame read from the peer.
+    pub fn received(&self, header: &FrameHeader, payload: &[u8]) {
+        self.report(Direction::Received, header, payload);
+    }
+
+    fn report(&self, direction: Direction, header: &FrameHeader, payload: &[u8]) {
+        let dump_len = if payload.len() < self.dump_len { payload.len() } else { self.dump_len };
+        (self.callback)(&FrameEvent {
+            direction: direction,
+            kind: header.kind,
+            id: header.id,
+            flag: header.flag,
+            length: header.length,
+            dump: &payload[..dump_len],
+        });
+    }
+}
+
+impl fmt::Debug for TraceHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TraceHook {{ dump_len: {} }}", self.dump_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http2::codec::FrameBuf;
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::TraceHook;
+
+    #[test]
+    fn test_trace() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let lines = lines.clone();
+            TraceHook::new(move |event| lines.lock().unwrap().push(event.to_string())).dump(4)
+        };
+        let data = FrameBuf::from(Frame::new(Flag::end_stream(), StreamIdentifier(1), Payload::Data { data: b"hello" }));
+        hook.sent(&data.header, &data.payload);
+        let ping = FrameBuf::from(Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(0xff)));
+        hook.received(&ping.header, &ping.payload[..0]);
+        let unknown = Frame::from_bytes(&[0, 0, 1, 0xf0, 0, 0, 0, 0, 0, 0xab]).unwrap();
+        hook.received(&unknown.header, &[0xab]);
+
+        assert_eq!(*lines.lock().unwrap(), vec!["send DATA stream=1 flags=0x1 length=5 | 68 65 6c 6c ...",
+                                                "recv PING stream=0 flags=0x1 length=8",
+                                                "recv 0xf0 stream=0 flags=0x0 length=1 | ab"]);
+    }
+}
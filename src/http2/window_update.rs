@@ -0,0 +1,241 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! Coalescing of the WINDOW_UPDATEs we send. The connection reports the DATA the application
+//! consumed to a `WindowUpdates`, which holds the credit back until a stream (or the
+//! connection) consumed a good part of its window, so that a busy download costs a
+//! WINDOW_UPDATE every few frames rather than two for every frame.
+//!
+//! How much to hold back suits some applications better than others: a `Connection` takes any
+//! `WindowUpdateStrategy`, `WindowUpdates` by default or e.g. a `BdpWindowUpdates`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+use http2::flag::Flag;
+use http2::frame::Frame;
+use http2::payload::Payload;
+use http2::{SizeIncrement, StreamIdentifier};
+
+/// The initial window size of streams and of the connection (RFC 7540 section 6.9.2).
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WindowUpdateConfig {
+    /// The receive window of each stream, i.e. our SETTINGS_INITIAL_WINDOW_SIZE.
+    pub stream_window: u32,
+    /// The receive window of the connection.
+    pub connection_window: u32,
+    /// How much of a window, in percent, has to be consumed before it is updated; 0 sends a
+    /// WINDOW_UPDATE for every consumed frame. At most 100: past that, the peer would run out
+    /// of window before we sent any.
+    pub threshold_percent: u8,
+}
+
+impl Default for WindowUpdateConfig {
+    fn default() -> WindowUpdateConfig {
+        WindowUpdateConfig {
+            stream_window: DEFAULT_WINDOW_SIZE,
+            connection_window: DEFAULT_WINDOW_SIZE,
+            threshold_percent: 50,
+        }
+    }
+}
+
+fn threshold(window: u32, percent: u8) -> u32 {
+    let threshold = window as u64 * percent as u64 / 100;
+    if threshold == 0 { 1 } else { threshold as u32 }
+}
+
+pub fn window_update(id: StreamIdentifier, increment: u32) -> Frame<'static> {
+    Frame::window_update(id, SizeIncrement(increment))
+}
+
+/// Decides when, and by how much, the receive windows are given back.
+pub trait WindowUpdateStrategy: fmt::Debug {
+    /// The application consumed `len` octets of DATA (padding included) of stream `id`;
+    /// `end_stream` if the peer won't send on it again. Returns the WINDOW_UPDATEs to send
+    /// now, at most one for the stream and one for the connection.
+    fn consumed(&mut self, id: StreamIdentifier, len: u32, end_stream: bool) -> Vec<Frame<'static>>;
+
+    /// Forgets a stream that was closed or reset.
+    fn close_stream(&mut self, id: StreamIdentifier);
+
+    /// Returns WINDOW_UPDATEs for everything held back.
+    fn flush(&mut self) -> Vec<Frame<'static>>;
+
+    /// Our SETTINGS_INITIAL_WINDOW_SIZE changed, once acknowledged.
+    fn set_stream_window(&mut self, stream_window: u32);
+
+    /// The connection's window changed.
+    fn set_connection_window(&mut self, connection_window: u32);
+
+    /// DATA of `len` octets was received at `now`, before it was consumed. May return a frame
+    /// to write, e.g. a PING to measure the connection with.
+    fn recv_data(&mut self, _len: u32, _now: Instant) -> Option<Frame<'static>> {
+        None
+    }
+
+    /// A PING was read at `now`, with or without ACK.
+    fn recv_ping(&mut self, _frame: &Frame, _now: Instant) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct WindowUpdates {
+    config: WindowUpdateConfig,
+    stream_threshold: u32,
+    connection_threshold: u32,
+    /// Consumed and not given back yet, per open stream and for the connection.
+    streams: HashMap<StreamIdentifier, u32>,
+    connection: u32,
+}
+
+impl WindowUpdates {
+    pub fn new(config: WindowUpdateConfig) -> WindowUpdates {
+        assert!(config.threshold_percent <= 100, "threshold_percent {} is over 100", config.threshold_percent);
+        WindowUpdates {
+            config: config,
+            stream_threshold: threshold(config.stream_window, config.threshold_percent),
+            connection_threshold: threshold(config.connection_window, config.threshold_percent),
+            streams: HashMap::new(),
+            connection: 0,
+        }
+    }
+
+    /// Changes the stream window, when a new SETTINGS_INITIAL_WINDOW_SIZE of ours was
+    /// acknowledged.
+    pub fn set_stream_window(&mut self, stream_window: u32) {
+        self.config.stream_window = stream_window;
+        self.stream_threshold = threshold(stream_window, self.config.threshold_percent);
+    }
+
+    /// Changes the connection window; the connection must send a WINDOW_UPDATE on stream 0
+    /// for the difference itself if it grew.
+    pub fn set_connection_window(&mut self, connection_window: u32) {
+        self.config.connection_window = connection_window;
+        self.connection_threshold = threshold(connection_window, self.config.threshold_percent);
+    }
+
+    /// The application consumed `len` octets of DATA (padding included) of stream `id`.
+    /// Returns the WINDOW_UPDATEs to send now, if a threshold was reached: at most one for the
+    /// stream, unless `end_stream` says the peer won't send on it again, and one for the
+    /// connection.
+    pub fn consumed(&mut self, id: StreamIdentifier, len: u32, end_stream: bool) -> Vec<Frame<'static>> {
+        let mut frames = Vec::new();
+        if end_stream {
+            self.streams.remove(&id);
+        } else if len > 0 {
+            let pending = {
+                let pending = self.streams.entry(id).or_insert(0);
+                *pending += len;
+                *pending
+            };
+            if pending >= self.stream_threshold {
+                self.streams.remove(&id);
+                frames.push(window_update(id, pending));
+            }
+        }
+
+        self.connection += len;
+        if self.connection > 0 && self.connection >= self.connection_threshold {
+            frames.push(window_update(StreamIdentifier(0), self.connection));
+            self.connection = 0;
+        }
+        frames
+    }
+
+    /// Forgets the credit held back for a stream that was closed or reset.
+    pub fn close_stream(&mut self, id: StreamIdentifier) {
+        self.streams.remove(&id);
+    }
+
+    /// Returns WINDOW_UPDATEs for all the credit held back, e.g. before the connection goes
+    /// idle, so that the peer isn't kept waiting for a threshold that won't be reached.
+    pub fn flush(&mut self) -> Vec<Frame<'static>> {
+        let mut frames: Vec<_> = self.streams.drain()
+            .map(|(id, pending)| window_update(id, pending))
+            .collect();
+        frames.sort_by_key(|frame| frame.header.id.0);
+        if self.connection > 0 {
+            frames.push(window_update(StreamIdentifier(0), self.connection));
+            self.connection = 0;
+        }
+        frames
+    }
+}
+
+impl WindowUpdateStrategy for WindowUpdates {
+    fn consumed(&mut self, id: StreamIdentifier, len: u32, end_stream: bool) -> Vec<Frame<'static>> {
+        WindowUpdates::consumed(self, id, len, end_stream)
+    }
+
+    fn close_stream(&mut self, id: StreamIdentifier) {
+        WindowUpdates::close_stream(self, id)
+    }
+
+    fn flush(&mut self) -> Vec<Frame<'static>> {
+        WindowUpdates::flush(self)
+    }
+
+    fn set_stream_window(&mut self, stream_window: u32) {
+        WindowUpdates::set_stream_window(self, stream_window)
+    }
+
+    fn set_connection_window(&mut self, connection_window: u32) {
+        WindowUpdates::set_connection_window(self, connection_window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http2::frame::Frame;
+    use http2::StreamIdentifier;
+
+    use super::{window_update, WindowUpdateConfig, WindowUpdates};
+
+    #[test]
+    fn test_coalescing() {
+        let mut updates = WindowUpdates::new(WindowUpdateConfig {
+            stream_window: 100,
+            connection_window: 200,
+            threshold_percent: 50,
+        });
+        let (one, three) = (StreamIdentifier(1), StreamIdentifier(3));
+        let none: Vec<Frame> = Vec::new();
+
+        assert_eq!(updates.consumed(one, 30, false), none);
+        assert_eq!(updates.consumed(three, 30, false), none);
+        assert_eq!(updates.consumed(one, 20, false), vec![window_update(one, 50)]);
+        // 110 octets on the connection.
+        assert_eq!(updates.consumed(three, 30, false), vec![window_update(three, 60),
+                                                            window_update(StreamIdentifier(0), 110)]);
+        // The end of a stream needs no stream update.
+        assert_eq!(updates.consumed(one, 10, true), none);
+        updates.consumed(three, 10, false);
+        assert_eq!(updates.flush(), vec![window_update(three, 10), window_update(StreamIdentifier(0), 20)]);
+        assert_eq!(updates.flush(), none);
+
+        let mut every_frame = WindowUpdates::new(WindowUpdateConfig { threshold_percent: 0, ..WindowUpdateConfig::default() });
+        assert_eq!(every_frame.consumed(one, 1, false).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "is over 100")]
+    fn test_threshold_over_100() {
+        WindowUpdates::new(WindowUpdateConfig { threshold_percent: 101, ..WindowUpdateConfig::default() });
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The write side of a connection without copies: queued frames are written with
+//! `write_vectored`, each frame header and payload as its own `IoSlice`, so that the payload
+//! of a DATA frame goes from its `Bytes` to the socket as is.
+
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, Write};
+
+use bytes::Bytes;
+
+use http2::codec::FrameBuf;
+use http2::FRAME_HEADER_BYTES;
+
+/// The most slices handed to one `write_vectored` call, well under the `IOV_MAX` of the
+/// platforms we support.
+pub const MAX_IO_SLICES: usize = 64;
+
+struct Queued {
+    header: [u8; FRAME_HEADER_BYTES],
+    payload: Bytes,
+}
+
+impl Queued {
+    fn len(&self) -> usize {
+        FRAME_HEADER_BYTES + self.payload.len()
+    }
+}
+
+#[derive(Default)]
+pub struct FrameWriter {
+    frames: VecDeque<Queued>,
+    /// Octets of the first frame written already.
+    written: usize,
+    buffered: usize,
+}
+
+impl FrameWriter {
+    pub fn new() -> FrameWriter {
+        FrameWriter::default()
+    }
+
+    /// Queues `frame`; its length is taken from its payload.
+    pub fn push(&mut self, frame: FrameBuf) {
+        let mut header = frame.header;
+        header.length = frame.payload.len() as u32;
+        let mut queued = Queued {
+            header: [0; FRAME_HEADER_BYTES],
+            payload: frame.payload,
+        };
+        header.encode(&mut queued.header);
+        self.buffered += queued.len();
+        self.frames.push_back(queued);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The octets queued and not written yet.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Makes one `write_vectored` call with as many queued frames as fit in `MAX_IO_SLICES`
+    /// slices, and returns the octets written. Frames that were written in part are resumed
+    /// by the next call.
+    pub fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<usize> {
+        let n = {
+            let mut slices = Vec::with_capacity(MAX_IO_SLICES);
+            let mut skip = self.written;
+            for frame in &self.frames {
+                if slices.len() + 2 > MAX_IO_SLICES {
+                    break;
+                }
+                if skip < FRAME_HEADER_BYTES {
+                    slices.push(IoSlice::new(&frame.header[skip..]));
+                    skip = 0;
+                } else {
+                    skip -= FRAME_HEADER_BYTES;
+                }
+                if skip < frame.payload.len() {
+                    slices.push(IoSlice::new(&frame.payload[skip..]));
+                }
+                skip = 0;
+            }
+            if slices.is_empty() {
+                return Ok(0);
+            }
+            try!(w.write_vectored(&slices))
+        };
+        self.advance(n);
+        Ok(n)
+    }
+
+    /// Writes until the queue is empty. On an error, `WouldBlock` included, what was not written
+    /// stays queued.
+    pub fn flush_to<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        while !self.is_empty() {
+            if try!(self.write_to(w)) == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frames"));
+            }
+        }
+        w.flush()
+    }
+
+    fn advance(&mut self, mut n: usize) {
+        self.buffered -= n;
+        while n > 0 {
+            let left = self.frames[0].len() - self.written;
+            if n < left {
+                self.written += n;
+                return;
+            }
+            n -= left;
+            self.written = 0;
+            self.frames.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, IoSlice, Write};
+
+    use bytes::Bytes;
+
+    use http2::codec::FrameBuf;
+    use http2::flag::Flag;
+    use http2::frame::Frame;
+    use http2::payload::Payload;
+    use http2::StreamIdentifier;
+
+    use super::FrameWriter;
+
+    /// Takes at most `limit` octets per call, and records how many slices it was handed.
+    struct Short {
+        out: Vec<u8>,
+        limit: usize,
+        slices: Vec<usize>,
+    }
+
+    impl Write for Short {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.slices.push(bufs.len());
+            let mut n = 0;
+            for buf in bufs {
+                let take = cmp::min(buf.len(), self.limit - n);
+                self.out.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vectored() {
+        let mut writer = FrameWriter::new();
+        writer.push(FrameBuf::data(StreamIdentifier(1), Bytes::from(&b"hello"[..]), false));
+        writer.push(FrameBuf::from(Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Settings(&[]))));
+        writer.push(FrameBuf::data(StreamIdentifier(1), Bytes::from(&b"!"[..]), true));
+        assert_eq!(writer.buffered(), 33);
+
+        let mut out = Short { out: Vec::new(), limit: 12, slices: Vec::new() };
+        assert_eq!(writer.write_to(&mut out).unwrap(), 12);
+        assert_eq!(writer.buffered(), 21);
+        writer.flush_to(&mut out).unwrap();
+        assert!(writer.is_empty());
+        // Header and payload, an empty SETTINGS, header and payload; then resumed twice.
+        assert_eq!(out.slices, vec![5, 4, 2]);
+        assert_eq!(out.out, [&[0, 0, 5, 0, 0, 0, 0, 0, 1][..], b"hello",
+                             &[0, 0, 0, 4, 1, 0, 0, 0, 0], &[0, 0, 1, 0, 1, 0, 0, 0, 1], b"!"].concat());
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Leak detection
+//!
+//! Every in-flight exchange (a pipelined HTTP/1.1 request today, an HTTP/2 stream later) is
+//! represented by a `Tracked` handle. With the `leak-detect` feature enabled the tracker remembers
+//! where each handle was created and warns when:
+//!
+//! - a handle is dropped without `complete()` being called, i.e. the protocol exchange was
+//!   abandoned half way, or
+//! - a handle was `close()`d but is still alive after the configured threshold, i.e. the state or
+//!   buffers of a logically closed exchange are being retained.
+//!
+//! The HTTP/1 codec closes the exchanges still in flight when its connection fails, so a transport
+//! kept alive after that is caught too.
+//!
+//! Without the feature all of this compiles down to nothing so callers never need `cfg`s.
+
+use std::time::Duration;
+
+/// Default time a closed exchange may be retained before it is reported.
+pub const DEFAULT_THRESHOLD_SECS: u64 = 30;
+
+#[cfg(feature = "leak-detect")]
+pub use self::imp::{LeakTracker, Tracked};
+
+#[cfg(not(feature = "leak-detect"))]
+pub use self::noop::{LeakTracker, Tracked};
+
+#[cfg(feature = "leak-detect")]
+mod imp {
+    use std::collections::HashMap;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use backtrace::Backtrace;
+
+    use Logger;
+    use LoggerLevel;
+
+    struct Entry {
+        kind: &'static str,
+        created: Instant,
+        closed: Option<Instant>,
+        reported: bool,
+        backtrace: Backtrace,
+    }
+
+    struct Registry {
+        next_id: u64,
+        entries: HashMap<u64, Entry>,
+    }
+
+    #[derive(Clone)]
+    pub struct LeakTracker {
+        registry: Arc<Mutex<Registry>>,
+        threshold: Duration,
+        logger: Option<Logger>,
+    }
+
+    impl LeakTracker {
+        pub fn new(threshold: Duration, logger: Option<Logger>) -> LeakTracker {
+            LeakTracker {
+                registry: Arc::new(Mutex::new(Registry { next_id: 0, entries: HashMap::new() })),
+                threshold: threshold,
+                logger: logger,
+            }
+        }
+
+        /// Starts tracking a new exchange of the given kind (e.g. `"request"`).
+        pub fn track(&self, kind: &'static str) -> Tracked {
+            let mut registry = self.registry.lock().unwrap();
+            let id = registry.next_id;
+            registry.next_id += 1;
+            registry.entries.insert(id, Entry {
+                kind: kind,
+                created: Instant::now(),
+                closed: None,
+                reported: false,
+                backtrace: Backtrace::new_unresolved(),
+            });
+
+            Tracked { id: id, tracker: self.clone(), completed: false }
+        }
+
+        /// Number of handles currently alive.
+        pub fn outstanding(&self) -> usize {
+            self.registry.lock().unwrap().entries.len()
+        }
+
+        /// Reports every closed exchange retained for longer than the threshold. Each one is only
+        /// reported once. Returns the number reported by this call.
+        pub fn sweep(&self) -> usize {
+            let now = Instant::now();
+            let mut reported = Vec::new();
+            {
+                let mut registry = self.registry.lock().unwrap();
+                for entry in registry.entries.values_mut() {
+                    let retained = match entry.closed {
+                        Some(closed) => now.duration_since(closed) > self.threshold,
+                        None => false,
+                    };
+                    if retained && !entry.reported {
+                        entry.reported = true;
+                        entry.backtrace.resolve();
+                        reported.push(format!("{} closed but still retained after {:?} (created {:?} ago at:\n{:?})",
+                                              entry.kind,
+                                              self.threshold,
+                                              now.duration_since(entry.created),
+                                              entry.backtrace));
+                    }
+                }
+            }
+
+            for line in &reported {
+                self.warn(line.clone());
+            }
+            reported.len()
+        }
+
+        fn release(&self, id: u64, completed: bool) {
+            let entry = self.registry.lock().unwrap().entries.remove(&id);
+            if let Some(mut entry) = entry {
+                if !completed {
+                    entry.backtrace.resolve();
+                    self.warn(format!("{} dropped without completing the protocol exchange (created {:?} ago at:\n{:?})",
+                                      entry.kind,
+                                      entry.created.elapsed(),
+                                      entry.backtrace));
+                }
+            }
+        }
+
+        fn close(&self, id: u64) {
+            if let Some(entry) = self.registry.lock().unwrap().entries.get_mut(&id) {
+                if entry.closed.is_none() {
+                    entry.closed = Some(Instant::now());
+                }
+            }
+        }
+
+        fn warn(&self, line: String) {
+            match self.logger {
+                Some(ref logger) => logger.write(LoggerLevel::Warn, format!("leak-detect: {}", line)),
+                None => { let _ = writeln!(io::stderr(), "leak-detect: {}", line); },
+            }
+        }
+    }
+
+    /// Handle for a single tracked exchange.
+    pub struct Tracked {
+        id: u64,
+        tracker: LeakTracker,
+        completed: bool,
+    }
+
+    impl Tracked {
+        /// Marks the exchange as logically closed. The handle itself is expected to be released
+        /// shortly afterwards.
+        pub fn close(&mut self) {
+            self.tracker.close(self.id);
+        }
+
+        /// Marks the exchange as completed and releases the handle.
+        pub fn complete(mut self) {
+            self.completed = true;
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.tracker.release(self.id, self.completed);
+        }
+    }
+}
+
+#[cfg(not(feature = "leak-detect"))]
+mod noop {
+    use std::time::Duration;
+
+    use Logger;
+
+    #[derive(Clone)]
+    pub struct LeakTracker;
+
+    impl LeakTracker {
+        pub fn new(threshold: Duration, logger: Option<Logger>) -> LeakTracker {
+            LeakTracker
+        }
+
+        pub fn track(&self, kind: &'static str) -> Tracked {
+            Tracked
+        }
+
+        pub fn outstanding(&self) -> usize {
+            0
+        }
+
+        pub fn sweep(&self) -> usize {
+            0
+        }
+    }
+
+    pub struct Tracked;
+
+    impl Tracked {
+        pub fn close(&mut self) {}
+        pub fn complete(self) {}
+    }
+}
+
+impl Default for LeakTracker {
+    fn default() -> LeakTracker {
+        LeakTracker::new(Duration::from_secs(DEFAULT_THRESHOLD_SECS), None)
+    }
+}
+
+impl ::std::fmt::Debug for LeakTracker {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "LeakTracker {{ outstanding: {} }}", self.outstanding())
+    }
+}
+
+#[cfg(all(test, feature = "leak-detect"))]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::LeakTracker;
+
+    #[test]
+    fn test_retained() {
+        let tracker = LeakTracker::new(Duration::from_millis(1), None);
+        let mut open = tracker.track("request");
+        let mut closed = tracker.track("request");
+        closed.close();
+        assert_eq!(tracker.outstanding(), 2);
+
+        // Only the closed handle is reported, and only once.
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(tracker.sweep(), 1);
+        assert_eq!(tracker.sweep(), 0);
+
+        open.close();
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(tracker.sweep(), 1);
+        open.complete();
+        drop(closed);
+        assert_eq!(tracker.outstanding(), 0);
+    }
+}
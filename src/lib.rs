@@ -38,10 +38,12 @@
 //! compression that includes the Huffman encoding/decoding features. This version will support
 //! Multiplexing which is required for HTTP/2.
 
+// slog exports macros under the log crate's names; whichever `macro_use` comes last wins, so
+// slog goes first and its own logging goes through the `slog_*` aliases.
+#[macro_use] extern crate slog;
 #[macro_use] extern crate log;
 #[macro_use] extern crate bitflags;
 #[macro_use] extern crate url;
-#[macro_use] extern crate slog;
 extern crate pretty_env_logger;
 extern crate slog_term;
 extern crate slog_json;
@@ -50,6 +52,7 @@ extern crate slog_syslog;
 extern crate unicase;
 extern crate rustc_serialize;
 extern crate byteorder;
+extern crate bytes;
 extern crate mime;
 extern crate mime_guess;
 extern crate rand;
@@ -66,15 +69,34 @@ extern crate time;
 extern crate chrono;
 extern crate libc;
 extern crate native_tls;
+// native-tls's backend there, for `http::tls`.
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+extern crate openssl;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+extern crate openssl_sys;
 
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_tls;
 
-// For now...
-// pub mod http2;
-// pub mod hpack;
+#[cfg(feature = "metrics")]
+#[macro_use] extern crate metrics as metrics_crate;
+#[cfg(feature = "leak-detect")]
+extern crate backtrace;
+#[cfg(feature = "http")]
+extern crate http as http_crate;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "compression")]
+extern crate brotli;
+
+// NB: Still changing so please do not depend on them at this time!
+pub mod http2;
+pub mod hpack;
+pub mod qpack;
 
 pub mod http;
 pub mod version;
@@ -83,6 +105,13 @@ pub mod status;
 pub mod method;
 pub mod router;
 pub mod logger;
+pub mod metrics;
+pub mod leak;
+pub mod audit;
+pub mod sf;
+pub mod exchange;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 
 pub use status::StatusCode::{self, Ok, BadRequest, NotFound};
 pub use version::HttpVersion;
@@ -90,10 +119,13 @@ pub use error::{Result, Error};
 pub use url::Url;
 pub use method::Method;
 pub use http::{Request, Response};
+pub use exchange::{HeaderFields, RequestHead, ResponseHead};
 pub use router::route::route::Route;
 pub use router::Router;
 pub use router::builder::RouterBuilder;
 pub use logger::{Logger, LoggerLevel};
+pub use metrics::{Metrics, MetricsSink};
+pub use audit::{AuditEvent, AuditHook};
 
 pub type Body = Vec<u8>;
 pub type ContentType = String;
@@ -130,10 +130,10 @@ impl Logger {
 
     pub fn write(&self, logger_level: LoggerLevel, line: String) {
         match logger_level {
-            LoggerLevel::Error => error!(self.logger, line),
-            LoggerLevel::Debug => debug!(self.logger, line),
-            LoggerLevel::Warn => warn!(self.logger, line),
-            _ => info!(self.logger, line),
+            LoggerLevel::Error => slog_error!(self.logger, line),
+            LoggerLevel::Debug => slog_debug!(self.logger, line),
+            LoggerLevel::Warn => slog_warn!(self.logger, line),
+            _ => slog_info!(self.logger, line),
         }
     }
 }
@@ -0,0 +1,133 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics
+//!
+//! A small facade the server (and later the HTTP/2 connection) reports into. The library never
+//! picks an exporter: implement `MetricsSink` for whatever telemetry stack is in use, or enable
+//! the `metrics` feature to forward everything to the `metrics` crate.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Names of the metrics reported by this crate.
+pub mod names {
+    /// Counter: connections accepted by the server.
+    pub const CONNECTIONS_ACCEPTED: &'static str = "tokio_http2.server.connections_accepted";
+    /// Counter: connections refused or dropped by the accept limits.
+    pub const CONNECTIONS_REJECTED: &'static str = "tokio_http2.server.connections_rejected";
+    /// Counter: connections dropped for not completing their handshake in time.
+    pub const HANDSHAKE_TIMEOUTS: &'static str = "tokio_http2.server.handshake_timeouts";
+    /// Counter: requests refused with 503 by a `LoadShedder`.
+    pub const LOAD_SHED: &'static str = "tokio_http2.server.load_shed";
+    /// Counter: requests refused with 429 by a `RateLimiter`.
+    pub const RATE_LIMITED: &'static str = "tokio_http2.server.rate_limited";
+    /// Counter: requests decoded by the server.
+    pub const REQUESTS: &'static str = "tokio_http2.server.requests";
+    /// Counter: requests that failed to decode.
+    pub const REQUEST_ERRORS: &'static str = "tokio_http2.server.request_errors";
+    /// Counter: responses encoded by the server.
+    pub const RESPONSES: &'static str = "tokio_http2.server.responses";
+    /// Histogram: size of the encoded response (head and body) in bytes.
+    pub const RESPONSE_BYTES: &'static str = "tokio_http2.server.response_bytes";
+}
+
+/// Receives the counters, gauges and histograms the crate reports.
+///
+/// Names are always `'static` so implementations can key on them without allocating.
+pub trait MetricsSink: Send + Sync {
+    /// Increments the counter `name` by `value`.
+    fn counter(&self, name: &'static str, value: u64);
+
+    /// Sets the gauge `name` to `value`.
+    fn gauge(&self, name: &'static str, value: f64);
+
+    /// Records `value` into the histogram `name`.
+    fn histogram(&self, name: &'static str, value: f64);
+}
+
+/// Default sink that throws everything away.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn counter(&self, _name: &'static str, _value: u64) {}
+    fn gauge(&self, _name: &'static str, _value: f64) {}
+    fn histogram(&self, _name: &'static str, _value: f64) {}
+}
+
+/// Forwards to the global recorder of the `metrics` crate.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsCrateSink;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for MetricsCrateSink {
+    fn counter(&self, name: &'static str, value: u64) {
+        counter!(name, value);
+    }
+
+    fn gauge(&self, name: &'static str, value: f64) {
+        gauge!(name, value);
+    }
+
+    fn histogram(&self, name: &'static str, value: f64) {
+        histogram!(name, value);
+    }
+}
+
+/// Cheaply cloneable handle to a `MetricsSink`, passed around like `Logger`.
+#[derive(Clone)]
+pub struct Metrics {
+    sink: Arc<MetricsSink>,
+}
+
+impl Metrics {
+    pub fn new<S: MetricsSink + 'static>(sink: S) -> Metrics {
+        Metrics { sink: Arc::new(sink) }
+    }
+
+    /// Forwards to the `metrics` crate.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_crate() -> Metrics {
+        Metrics::new(MetricsCrateSink)
+    }
+
+    #[inline]
+    pub fn counter(&self, name: &'static str, value: u64) {
+        self.sink.counter(name, value);
+    }
+
+    #[inline]
+    pub fn gauge(&self, name: &'static str, value: f64) {
+        self.sink.gauge(name, value);
+    }
+
+    #[inline]
+    pub fn histogram(&self, name: &'static str, value: f64) {
+        self.sink.histogram(name, value);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new(NoopMetrics)
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
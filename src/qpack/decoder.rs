@@ -0,0 +1,396 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The QPACK decoder. A field section whose Required Insert Count is ahead of the inserts
+//! received so far is kept until the encoder stream catches up; `unblocked` hands out those
+//! that can be decoded after a call to `encoder_instructions`.
+
+use super::{decode_required_insert_count, read_integer, read_string, write_integer, write_u64};
+use super::{Error, ReadError, Table, STATIC_TABLE};
+
+/// The most field sections one stream may have blocked at a time: its headers, trailers and
+/// a few interim responses.
+pub const MAX_BLOCKED_SECTIONS: usize = 4;
+
+/// The outcome of decoding a field section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    Headers(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The field section refers to entries not received yet; it was kept.
+    Blocked,
+}
+
+/// A field section waiting for inserts.
+#[derive(Debug)]
+struct BlockedSection {
+    stream_id: u64,
+    required_insert_count: usize,
+    block: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    table: Table,
+    /// Our SETTINGS_QPACK_MAX_TABLE_CAPACITY.
+    max_capacity: usize,
+    /// Our SETTINGS_QPACK_BLOCKED_STREAMS.
+    max_blocked_streams: usize,
+    /// The number of inserts the encoder knows we received, from Section Acknowledgment and
+    /// Insert Count Increment instructions.
+    acknowledged: usize,
+    blocked: Vec<BlockedSection>,
+}
+
+impl Decoder {
+    /// A decoder for the SETTINGS_QPACK_MAX_TABLE_CAPACITY and SETTINGS_QPACK_BLOCKED_STREAMS
+    /// we announce. The table starts with no capacity until the encoder sets one.
+    pub fn new(max_capacity: usize, max_blocked_streams: usize) -> Decoder {
+        Decoder {
+            table: Table::default(),
+            max_capacity: max_capacity,
+            max_blocked_streams: max_blocked_streams,
+            acknowledged: 0,
+            blocked: Vec::new(),
+        }
+    }
+
+    /// The number of entries inserted so far.
+    pub fn insert_count(&self) -> usize {
+        self.table.insert_count()
+    }
+
+    /// Processes instructions received on the encoder stream and returns the number of octets
+    /// consumed; an instruction cut short by the end of `buf` is left for the next call.
+    pub fn encoder_instructions(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            match self.encoder_instruction(&buf[consumed..]) {
+                Ok(len) => consumed += len,
+                Err(ReadError::Incomplete) => break,
+                Err(ReadError::Invalid) => return Err(Error::EncoderStream),
+            }
+        }
+        Ok(consumed)
+    }
+
+    fn encoder_instruction(&mut self, buf: &[u8]) -> Result<usize, ReadError> {
+        let insert_count = self.table.insert_count();
+        // Parses the whole instruction before changing anything.
+        let (name, value, len) = if buf[0] & 0x80 != 0 {
+            // Insert with Name Reference.
+            let (index, len) = try!(read_integer(buf, 6));
+            let name = if buf[0] & 0x40 != 0 {
+                try!(STATIC_TABLE.get(index).ok_or(ReadError::Invalid)).0.to_vec()
+            } else {
+                try!(self.relative(index, insert_count)).0.clone()
+            };
+            let (value, value_len) = try!(read_string(&buf[len..], 7));
+            (name, value, len + value_len)
+        } else if buf[0] & 0x40 != 0 {
+            // Insert with Literal Name.
+            let (name, len) = try!(read_string(buf, 5));
+            let (value, value_len) = try!(read_string(&buf[len..], 7));
+            (name, value, len + value_len)
+        } else if buf[0] & 0x20 != 0 {
+            // Set Dynamic Table Capacity.
+            let (capacity, len) = try!(read_integer(buf, 5));
+            if capacity > self.max_capacity {
+                return Err(ReadError::Invalid);
+            }
+            self.table.set_capacity(capacity);
+            return Ok(len);
+        } else {
+            // Duplicate.
+            let (index, len) = try!(read_integer(buf, 5));
+            let (name, value) = try!(self.relative(index, insert_count)).clone();
+            (name, value, len)
+        };
+        if !self.table.insert(name, value) {
+            return Err(ReadError::Invalid);
+        }
+        Ok(len)
+    }
+
+    /// The entry `index` places before `base`.
+    fn relative(&self, index: usize, base: usize) -> Result<&(Vec<u8>, Vec<u8>), ReadError> {
+        if index >= base {
+            return Err(ReadError::Invalid);
+        }
+        self.table.get(base - 1 - index).ok_or(ReadError::Invalid)
+    }
+
+    /// Decodes the field section of `stream_id`, appending a Section Acknowledgment to
+    /// `decoder_stream` if it referred to the dynamic table. A field section that has to wait
+    /// for inserts is kept, unless SETTINGS_QPACK_BLOCKED_STREAMS streams already wait.
+    pub fn decode(&mut self, stream_id: u64, block: &[u8], decoder_stream: &mut Vec<u8>)
+            -> Result<Decoded, Error> {
+        let (required_insert_count, _) = try!(self.prefix(block));
+        if required_insert_count > self.table.insert_count() {
+            let mut streams: Vec<u64> = self.blocked.iter().map(|section| section.stream_id).collect();
+            streams.sort();
+            streams.dedup();
+            if !streams.contains(&stream_id) && streams.len() >= self.max_blocked_streams {
+                return Err(Error::DecompressionFailed);
+            }
+            let sections = self.blocked.iter().filter(|section| section.stream_id == stream_id).count();
+            if sections >= MAX_BLOCKED_SECTIONS {
+                return Err(Error::DecompressionFailed);
+            }
+            self.blocked.push(BlockedSection {
+                stream_id: stream_id,
+                required_insert_count: required_insert_count,
+                block: block.to_vec(),
+            });
+            return Ok(Decoded::Blocked);
+        }
+        self.decode_section(stream_id, block, decoder_stream).map(Decoded::Headers)
+    }
+
+    /// Decodes the blocked field sections the inserts received since allow, oldest first.
+    pub fn unblocked(&mut self, decoder_stream: &mut Vec<u8>) -> Vec<(u64, Result<Vec<(Vec<u8>, Vec<u8>)>, Error>)> {
+        let insert_count = self.table.insert_count();
+        let (ready, blocked): (Vec<_>, Vec<_>) = self.blocked.drain(..)
+            .partition(|section| section.required_insert_count <= insert_count);
+        self.blocked = blocked;
+        ready.into_iter()
+            .map(|section| (section.stream_id, self.decode_section(section.stream_id, &section.block, decoder_stream)))
+            .collect()
+    }
+
+    /// Drops the blocked field sections of a reset stream and tells the encoder with a Stream
+    /// Cancellation, so that it stops counting on their acknowledgment.
+    pub fn cancel_stream(&mut self, stream_id: u64, decoder_stream: &mut Vec<u8>) {
+        self.blocked.retain(|section| section.stream_id != stream_id);
+        if self.max_capacity > 0 {
+            write_u64(stream_id, 6, 0x40, decoder_stream);
+        }
+    }
+
+    /// Appends an Insert Count Increment for the inserts the encoder doesn't know we received,
+    /// if any. Calling it after `encoder_instructions` lets the encoder refer to new entries
+    /// without blocking sooner than waiting for field sections to acknowledge them.
+    pub fn acknowledge_inserts(&mut self, decoder_stream: &mut Vec<u8>) {
+        let insert_count = self.table.insert_count();
+        if insert_count > self.acknowledged {
+            write_integer(insert_count - self.acknowledged, 6, 0, decoder_stream);
+            self.acknowledged = insert_count;
+        }
+    }
+
+    /// Reads the Required Insert Count and the base of a field section, returning them with
+    /// the length of the prefix.
+    fn prefix(&self, block: &[u8]) -> Result<(usize, (usize, usize)), Error> {
+        let malformed = |_| Error::DecompressionFailed;
+        let (encoded, len) = try!(read_integer(block, 8).map_err(&malformed));
+        let required = try!(decode_required_insert_count(encoded, self.max_capacity, self.table.insert_count()));
+        if len == block.len() {
+            return Err(Error::DecompressionFailed);
+        }
+        let (delta, delta_len) = try!(read_integer(&block[len..], 7).map_err(&malformed));
+        let base = if block[len] & 0x80 == 0 {
+            required.checked_add(delta)
+        } else {
+            required.checked_sub(delta).and_then(|base| base.checked_sub(1))
+        };
+        let base = try!(base.ok_or(Error::DecompressionFailed));
+        Ok((required, (base, len + delta_len)))
+    }
+
+    fn decode_section(&mut self, stream_id: u64, block: &[u8], decoder_stream: &mut Vec<u8>)
+            -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let (required, (base, mut consumed)) = try!(self.prefix(block));
+        let mut headers = Vec::new();
+        while consumed < block.len() {
+            let (header, len) = try!(self.field_line(&block[consumed..], required, base)
+                .map_err(|_| Error::DecompressionFailed));
+            headers.push(header);
+            consumed += len;
+        }
+        if required > 0 {
+            write_u64(stream_id, 7, 0x80, decoder_stream);
+            if required > self.acknowledged {
+                self.acknowledged = required;
+            }
+        }
+        Ok(headers)
+    }
+
+    fn field_line(&self, buf: &[u8], required: usize, base: usize)
+            -> Result<((Vec<u8>, Vec<u8>), usize), ReadError> {
+        // Entries at or after the Required Insert Count can't be referred to.
+        let dynamic = |index: usize| -> Result<&(Vec<u8>, Vec<u8>), ReadError> {
+            if index >= required {
+                return Err(ReadError::Invalid);
+            }
+            self.table.get(index).ok_or(ReadError::Invalid)
+        };
+        let first = buf[0];
+        if first & 0x80 != 0 {
+            // Indexed Field Line.
+            let (index, len) = try!(read_integer(buf, 6));
+            let header = if first & 0x40 != 0 {
+                let &(name, value) = try!(STATIC_TABLE.get(index).ok_or(ReadError::Invalid));
+                (name.to_vec(), value.to_vec())
+            } else if index < base {
+                try!(dynamic(base - 1 - index)).clone()
+            } else {
+                return Err(ReadError::Invalid);
+            };
+            Ok((header, len))
+        } else if first & 0x40 != 0 {
+            // Literal Field Line with Name Reference.
+            let (index, len) = try!(read_integer(buf, 4));
+            let name = if first & 0x10 != 0 {
+                try!(STATIC_TABLE.get(index).ok_or(ReadError::Invalid)).0.to_vec()
+            } else if index < base {
+                try!(dynamic(base - 1 - index)).0.clone()
+            } else {
+                return Err(ReadError::Invalid);
+            };
+            let (value, value_len) = try!(read_string(&buf[len..], 7));
+            Ok(((name, value), len + value_len))
+        } else if first & 0x20 != 0 {
+            // Literal Field Line with Literal Name.
+            let (name, len) = try!(read_string(buf, 3));
+            let (value, value_len) = try!(read_string(&buf[len..], 7));
+            Ok(((name, value), len + value_len))
+        } else if first & 0x10 != 0 {
+            // Indexed Field Line with Post-Base Index.
+            let (index, len) = try!(read_integer(buf, 4));
+            let index = try!(base.checked_add(index).ok_or(ReadError::Invalid));
+            Ok((try!(dynamic(index)).clone(), len))
+        } else {
+            // Literal Field Line with Post-Base Name Reference.
+            let (index, len) = try!(read_integer(buf, 3));
+            let index = try!(base.checked_add(index).ok_or(ReadError::Invalid));
+            let name = try!(dynamic(index)).0.clone();
+            let (value, value_len) = try!(read_string(&buf[len..], 7));
+            Ok(((name, value), len + value_len))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qpack::{Decoded, Decoder, Encoder, Error};
+
+    use super::MAX_BLOCKED_SECTIONS;
+
+    fn headers(list: &[(&str, &str)]) -> Decoded {
+        Decoded::Headers(list.iter().map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect())
+    }
+
+    #[test]
+    fn test_rfc9204_examples() {
+        let mut decoder = Decoder::new(220, 1);
+        let mut decoder_stream = Vec::new();
+
+        // B.1: a literal with a static name, no dynamic table.
+        assert_eq!(decoder.decode(0, b"\x00\x00\x51\x0b/index.html", &mut decoder_stream),
+                   Ok(headers(&[(":path", "/index.html")])));
+
+        // B.2: the field section arrives before the inserts it refers to (post-base).
+        let block = b"\x03\x81\x10\x11";
+        assert_eq!(decoder.decode(4, block, &mut decoder_stream), Ok(Decoded::Blocked));
+        assert_eq!(decoder.decode(8, block, &mut decoder_stream), Err(Error::DecompressionFailed));
+        let encoder_stream = b"\x3f\xbd\x01\xc0\x0fwww.example.com\xc1\x0c/sample/path";
+        assert_eq!(decoder.encoder_instructions(&encoder_stream[..10]), Ok(3));
+        assert!(decoder.unblocked(&mut decoder_stream).is_empty());
+        assert_eq!(decoder.encoder_instructions(&encoder_stream[3..]), Ok(encoder_stream.len() - 3));
+        let unblocked = decoder.unblocked(&mut decoder_stream);
+        assert_eq!(unblocked.len(), 1);
+        assert_eq!(Decoded::Headers(unblocked[0].1.clone().unwrap()),
+                   headers(&[(":authority", "www.example.com"), (":path", "/sample/path")]));
+        assert_eq!(decoder_stream, b"\x84");
+
+        // B.3: a speculative insert, acknowledged with an Insert Count Increment.
+        decoder.encoder_instructions(b"\x4a\x63\x75\x73\x74\x6f\x6d\x2d\x6b\x65\x79\x0ccustom-value").unwrap();
+        decoder_stream.clear();
+        decoder.acknowledge_inserts(&mut decoder_stream);
+        assert_eq!(decoder_stream, b"\x01");
+
+        // B.4: a duplicate, referred to with the static table and another entry.
+        decoder.encoder_instructions(b"\x02").unwrap();
+        decoder_stream.clear();
+        assert_eq!(decoder.decode(8, b"\x05\x00\x80\xc1\x81", &mut decoder_stream),
+                   Ok(headers(&[(":authority", "www.example.com"), (":path", "/"), ("custom-key", "custom-value")])));
+        assert_eq!(decoder_stream, b"\x88");
+        decoder_stream.clear();
+        decoder.cancel_stream(8, &mut decoder_stream);
+        assert_eq!(decoder_stream, b"\x48");
+
+        // B.5: an insert with a dynamic name evicts the oldest entry.
+        decoder.encoder_instructions(b"\x81\x0dcustom-value2").unwrap();
+        assert_eq!(decoder.insert_count(), 5);
+        assert_eq!(decoder.decode(12, b"\x02\x00\x80", &mut Vec::new()), Err(Error::DecompressionFailed));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new(4096, 1);
+        encoder.set_peer_settings(4096, 1);
+        let mut encoder_stream = Vec::new();
+        let mut decoder_stream = Vec::new();
+        assert!(encoder.set_capacity(1024, &mut encoder_stream));
+
+        let list = vec![(&b":method"[..], &b"GET"[..]), (b":path", b"/index.html"), (b"x-trace", b"abc")];
+        let expected = Decoded::Headers(list.iter().map(|&(n, v)| (n.to_vec(), v.to_vec())).collect());
+
+        // Stream 0 may block: it refers to its inserts and waits for them.
+        let block = encoder.encode(0, list.clone(), &mut encoder_stream);
+        assert_eq!(encoder.blocked_streams(), 1);
+        assert_eq!(decoder.decode(0, &block, &mut decoder_stream), Ok(Decoded::Blocked));
+        // Another stream may not: it is sent literals.
+        let other = encoder.encode(4, list.clone(), &mut encoder_stream);
+        assert_eq!(decoder.decode(4, &other, &mut decoder_stream), Ok(expected.clone()));
+        assert_eq!(encoder.insert_count(), 2);
+
+        let len = encoder_stream.len();
+        assert_eq!(decoder.encoder_instructions(&encoder_stream), Ok(len));
+        assert_eq!(decoder.unblocked(&mut decoder_stream)[0], (0, Ok(list.iter().map(|&(n, v)| (n.to_vec(), v.to_vec())).collect())));
+        let len = decoder_stream.len();
+        assert_eq!(encoder.decoder_instructions(&decoder_stream), Ok(len));
+        assert_eq!(encoder.blocked_streams(), 0);
+
+        // Now everything is acknowledged and tiny.
+        let block = encoder.encode(8, list.clone(), &mut encoder_stream);
+        assert_eq!(block.len(), 2 + 3);
+        assert_eq!(decoder.decode(8, &block, &mut Vec::new()), Ok(expected));
+
+        // An Insert Count Increment that would wrap the Known Received Count.
+        let increment = b"\x3f\xc0\xff\xff\xff\xff\xff\xff\xff\xff\x01";
+        assert_eq!(encoder.decoder_instructions(increment), Err(Error::DecoderStream));
+    }
+
+    #[test]
+    fn test_hostile_prefix() {
+        let mut decoder = Decoder::new(220, 1);
+        decoder.encoder_instructions(b"\x3f\xbd\x01\xc0\x0fwww.example.com\xc1\x0c/sample/path").unwrap();
+
+        // A Delta Base of 2^64 - 1, and a post-base index that takes a base past usize.
+        let block = [0x02, 0x7f, 0x80, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x10];
+        assert_eq!(decoder.decode(0, &block, &mut Vec::new()), Err(Error::DecompressionFailed));
+        let block = [0x02, 0x00, 0x1f, 0xf0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(decoder.decode(0, &block, &mut Vec::new()), Err(Error::DecompressionFailed));
+
+        // A stream may only have so many field sections blocked.
+        for _ in 0..MAX_BLOCKED_SECTIONS {
+            assert_eq!(decoder.decode(4, b"\x04\x00\x80", &mut Vec::new()), Ok(Decoded::Blocked));
+        }
+        assert_eq!(decoder.decode(4, b"\x04\x00\x80", &mut Vec::new()), Err(Error::DecompressionFailed));
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! The QPACK encoder. Headers not found in the tables are inserted into the dynamic table when
+//! they fit; a field section refers to entries the decoder may not have yet only while fewer
+//! streams than SETTINGS_QPACK_BLOCKED_STREAMS allows are blocked, and sends a literal
+//! otherwise.
+
+use std::collections::VecDeque;
+use std::cmp;
+
+use hpack::entry_size;
+use super::{encode_required_insert_count, find_static, read_integer, read_u64, write_integer, write_string};
+use super::{Error, ReadError, Table};
+
+/// A field section the decoder hasn't acknowledged yet.
+#[derive(Copy, Clone, Debug)]
+struct Section {
+    stream_id: u64,
+    required_insert_count: usize,
+    /// The oldest entry it refers to, which can't be evicted until it is acknowledged.
+    oldest_reference: usize,
+}
+
+/// How a field line refers to the tables.
+enum Line<'b> {
+    Static(usize),
+    Dynamic(usize),
+    StaticName(usize, &'b [u8]),
+    DynamicName(usize, &'b [u8]),
+    Literal(&'b [u8], &'b [u8]),
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    table: Table,
+    /// The peer's SETTINGS_QPACK_MAX_TABLE_CAPACITY.
+    max_capacity: usize,
+    /// The peer's SETTINGS_QPACK_BLOCKED_STREAMS.
+    max_blocked_streams: usize,
+    /// The number of inserts the decoder is known to have received.
+    known_received_count: usize,
+    /// Oldest first.
+    unacknowledged: VecDeque<Section>,
+}
+
+impl Encoder {
+    /// An encoder without a dynamic table, until the peer's settings allow one and
+    /// `set_capacity` sets it up.
+    pub fn new() -> Encoder {
+        Encoder {
+            table: Table::default(),
+            max_capacity: 0,
+            max_blocked_streams: 0,
+            known_received_count: 0,
+            unacknowledged: VecDeque::new(),
+        }
+    }
+
+    /// Takes in the peer's SETTINGS_QPACK_MAX_TABLE_CAPACITY and SETTINGS_QPACK_BLOCKED_STREAMS.
+    /// The capacity is also needed to encode the Required Insert Count of field sections.
+    pub fn set_peer_settings(&mut self, max_capacity: usize, max_blocked_streams: usize) {
+        self.max_capacity = max_capacity;
+        self.max_blocked_streams = max_blocked_streams;
+    }
+
+    /// Sets the capacity of the dynamic table, capped by the peer's maximum, and appends the
+    /// Set Dynamic Table Capacity instruction to `encoder_stream`. Returns `false`, changing
+    /// nothing, if that would evict entries unacknowledged field sections refer to.
+    pub fn set_capacity(&mut self, capacity: usize, encoder_stream: &mut Vec<u8>) -> bool {
+        let capacity = cmp::min(capacity, self.max_capacity);
+        // Shrinking it evicts entries as inserting the difference would.
+        if capacity < self.table.size && !self.table.has_room(self.table.capacity - capacity, self.pinned()) {
+            return false;
+        }
+        self.table.set_capacity(capacity);
+        write_integer(capacity, 5, 0x20, encoder_stream);
+        true
+    }
+
+    /// The number of entries inserted so far.
+    pub fn insert_count(&self) -> usize {
+        self.table.insert_count()
+    }
+
+    /// The number of streams whose field sections refer to entries the decoder may not have.
+    pub fn blocked_streams(&self) -> usize {
+        let mut streams: Vec<u64> = self.unacknowledged.iter()
+            .filter(|section| section.required_insert_count > self.known_received_count)
+            .map(|section| section.stream_id)
+            .collect();
+        streams.sort();
+        streams.dedup();
+        streams.len()
+    }
+
+    /// Encodes the field section of `stream_id`, appending the instructions inserting new
+    /// entries to `encoder_stream`; they must be sent before the field section.
+    pub fn encode<'b, I>(&mut self, stream_id: u64, headers: I, encoder_stream: &mut Vec<u8>) -> Vec<u8>
+            where I: IntoIterator<Item=(&'b [u8], &'b [u8])> {
+        let may_block = self.unacknowledged.iter().any(|section| {
+            section.stream_id == stream_id && section.required_insert_count > self.known_received_count
+        }) || self.blocked_streams() < self.max_blocked_streams;
+        let mut pinned = self.pinned();
+        let known_received_count = self.known_received_count;
+        let usable = |index: usize| may_block || index < known_received_count;
+        let mut lines = Vec::new();
+        let mut oldest_reference = usize::max_value();
+        let mut newest_reference = None;
+
+        for (name, value) in headers {
+            let found_static = find_static(name, value);
+            if let Some((index, true)) = found_static {
+                lines.push(Line::Static(index));
+                continue;
+            }
+            let found = self.table.find(name, value);
+            let line = match found {
+                Some((index, true)) if usable(index) => Line::Dynamic(index),
+                _ => {
+                    // Insert it for the next sections, and refer to it now if allowed. An entry
+                    // the decoder may not have yet is not inserted again.
+                    let size = entry_size(name, value);
+                    let inserted = match found {
+                        Some((_, true)) => false,
+                        _ => size <= self.table.capacity && self.table.has_room(size, pinned),
+                    };
+                    if inserted {
+                        match found_static {
+                            Some((index, _)) => {
+                                write_integer(index, 6, 0xc0, encoder_stream);
+                            },
+                            None => {
+                                write_string(name, 5, 0x40, encoder_stream);
+                            },
+                        }
+                        write_string(value, 7, 0, encoder_stream);
+                        self.table.insert(name.to_vec(), value.to_vec());
+                    }
+                    match (self.table.find(name, value), found_static) {
+                        (Some((index, true)), _) if usable(index) => Line::Dynamic(index),
+                        (_, Some((index, _))) => Line::StaticName(index, value),
+                        (Some((index, false)), _) if usable(index) => Line::DynamicName(index, value),
+                        _ => Line::Literal(name, value),
+                    }
+                },
+            };
+            match line {
+                Line::Dynamic(index) | Line::DynamicName(index, _) => {
+                    pinned = cmp::min(pinned, index);
+                    oldest_reference = cmp::min(oldest_reference, index);
+                    newest_reference = cmp::max(newest_reference, Some(index));
+                },
+                _ => {},
+            }
+            lines.push(line);
+        }
+
+        let required_insert_count = newest_reference.map_or(0, |index| index + 1);
+        let mut block = Vec::new();
+        // The base is the Required Insert Count, so relative indices never go past it.
+        let base = required_insert_count;
+        write_integer(encode_required_insert_count(required_insert_count, self.max_capacity), 8, 0, &mut block);
+        write_integer(0, 7, 0, &mut block);
+        for line in lines {
+            match line {
+                Line::Static(index) => write_integer(index, 6, 0xc0, &mut block),
+                Line::Dynamic(index) => write_integer(base - 1 - index, 6, 0x80, &mut block),
+                Line::StaticName(index, value) => {
+                    write_integer(index, 4, 0x50, &mut block);
+                    write_string(value, 7, 0, &mut block);
+                },
+                Line::DynamicName(index, value) => {
+                    write_integer(base - 1 - index, 4, 0x40, &mut block);
+                    write_string(value, 7, 0, &mut block);
+                },
+                Line::Literal(name, value) => {
+                    write_string(name, 3, 0x20, &mut block);
+                    write_string(value, 7, 0, &mut block);
+                },
+            }
+        }
+
+        if required_insert_count > 0 {
+            self.unacknowledged.push_back(Section {
+                stream_id: stream_id,
+                required_insert_count: required_insert_count,
+                oldest_reference: oldest_reference,
+            });
+        }
+        block
+    }
+
+    /// Processes instructions received on the decoder stream and returns the number of octets
+    /// consumed; an instruction cut short by the end of `buf` is left for the next call.
+    pub fn decoder_instructions(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            match self.decoder_instruction(&buf[consumed..]) {
+                Ok(len) => consumed += len,
+                Err(ReadError::Incomplete) => break,
+                Err(ReadError::Invalid) => return Err(Error::DecoderStream),
+            }
+        }
+        Ok(consumed)
+    }
+
+    fn decoder_instruction(&mut self, buf: &[u8]) -> Result<usize, ReadError> {
+        if buf[0] & 0x80 != 0 {
+            // Section Acknowledgment: of the oldest unacknowledged section of the stream.
+            let (stream_id, len) = try!(read_u64(buf, 7));
+            let position = try!(self.unacknowledged.iter()
+                .position(|section| section.stream_id == stream_id)
+                .ok_or(ReadError::Invalid));
+            let section = self.unacknowledged.remove(position).unwrap();
+            self.known_received_count = cmp::max(self.known_received_count, section.required_insert_count);
+            Ok(len)
+        } else if buf[0] & 0x40 != 0 {
+            // Stream Cancellation.
+            let (stream_id, len) = try!(read_u64(buf, 6));
+            self.unacknowledged.retain(|section| section.stream_id != stream_id);
+            Ok(len)
+        } else {
+            // Insert Count Increment.
+            let (increment, len) = try!(read_integer(buf, 6));
+            let known_received_count = self.known_received_count.checked_add(increment);
+            match known_received_count {
+                Some(count) if increment > 0 && count <= self.table.insert_count() => self.known_received_count = count,
+                _ => return Err(ReadError::Invalid),
+            }
+            Ok(len)
+        }
+    }
+
+    /// Entries from this absolute index on must stay in the table.
+    fn pinned(&self) -> usize {
+        self.unacknowledged.iter()
+            .map(|section| section.oldest_reference)
+            .min()
+            .unwrap_or(usize::max_value())
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NB: This code is changing so please do not depend on it at this time!
+//!
+//! QPACK (RFC 9204), the header compression of HTTP/3, for experimenting with HTTP/3 on top of
+//! the crate's HPACK primitives: the integer and Huffman codings are HPACK's.
+//!
+//! Unlike HPACK, the dynamic table is only changed through instructions on the unidirectional
+//! encoder stream, and the decoder tells the encoder what it received on the decoder stream.
+//! A field section referring to entries the decoder hasn't received yet is blocked until they
+//! arrive; the encoder limits how many streams may be blocked at once. The `Encoder` and the
+//! `Decoder` are sans-IO: they append the bytes to send on their instruction stream to a `Vec`
+//! and are handed the bytes received on the other one.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use hpack::decoder::{decode_integer_u64, DecoderError, IntegerDecodingError};
+use hpack::encoder::encode_integer_u64_into;
+use hpack::entry_size;
+use hpack::huffman::{self, HuffmanDecoder};
+
+pub mod encoder;
+pub mod decoder;
+
+pub use self::decoder::{Decoded, Decoder};
+pub use self::encoder::Encoder;
+
+/// Errors closing the HTTP/3 connection, named after their error codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Error {
+    /// A field section could not be decoded (QPACK_DECOMPRESSION_FAILED).
+    DecompressionFailed,
+    /// An instruction received on the encoder stream was invalid (QPACK_ENCODER_STREAM_ERROR).
+    EncoderStream,
+    /// An instruction received on the decoder stream was invalid (QPACK_DECODER_STREAM_ERROR).
+    DecoderStream,
+}
+
+impl Error {
+    /// The HTTP/3 error code to close the connection with.
+    pub fn code(&self) -> u64 {
+        match *self {
+            Error::DecompressionFailed => 0x200,
+            Error::EncoderStream => 0x201,
+            Error::DecoderStream => 0x202,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::DecompressionFailed => "QPACK field section could not be decoded",
+            Error::EncoderStream => "invalid QPACK encoder stream instruction",
+            Error::DecoderStream => "invalid QPACK decoder stream instruction",
+        })
+    }
+}
+
+/// The static table of RFC 9204 Appendix A. Unlike HPACK's, it is indexed from 0.
+pub static STATIC_TABLE: &'static [(&'static [u8], &'static [u8])] = &[
+    (b":authority", b""),
+    (b":path", b"/"),
+    (b"age", b"0"),
+    (b"content-disposition", b""),
+    (b"content-length", b"0"),
+    (b"cookie", b""),
+    (b"date", b""),
+    (b"etag", b""),
+    (b"if-modified-since", b""),
+    (b"if-none-match", b""),
+    (b"last-modified", b""),
+    (b"link", b""),
+    (b"location", b""),
+    (b"referer", b""),
+    (b"set-cookie", b""),
+    (b":method", b"CONNECT"),
+    (b":method", b"DELETE"),
+    (b":method", b"GET"),
+    (b":method", b"HEAD"),
+    (b":method", b"OPTIONS"),
+    (b":method", b"POST"),
+    (b":method", b"PUT"),
+    (b":scheme", b"http"),
+    (b":scheme", b"https"),
+    (b":status", b"103"),
+    (b":status", b"200"),
+    (b":status", b"304"),
+    (b":status", b"404"),
+    (b":status", b"503"),
+    (b"accept", b"*/*"),
+    (b"accept", b"application/dns-message"),
+    (b"accept-encoding", b"gzip, deflate, br"),
+    (b"accept-ranges", b"bytes"),
+    (b"access-control-allow-headers", b"cache-control"),
+    (b"access-control-allow-headers", b"content-type"),
+    (b"access-control-allow-origin", b"*"),
+    (b"cache-control", b"max-age=0"),
+    (b"cache-control", b"max-age=2592000"),
+    (b"cache-control", b"max-age=604800"),
+    (b"cache-control", b"no-cache"),
+    (b"cache-control", b"no-store"),
+    (b"cache-control", b"public, max-age=31536000"),
+    (b"content-encoding", b"br"),
+    (b"content-encoding", b"gzip"),
+    (b"content-type", b"application/dns-message"),
+    (b"content-type", b"application/javascript"),
+    (b"content-type", b"application/json"),
+    (b"content-type", b"application/x-www-form-urlencoded"),
+    (b"content-type", b"image/gif"),
+    (b"content-type", b"image/jpeg"),
+    (b"content-type", b"image/png"),
+    (b"content-type", b"text/css"),
+    (b"content-type", b"text/html; charset=utf-8"),
+    (b"content-type", b"text/plain"),
+    (b"content-type", b"text/plain;charset=utf-8"),
+    (b"range", b"bytes=0-"),
+    (b"strict-transport-security", b"max-age=31536000"),
+    (b"strict-transport-security", b"max-age=31536000; includesubdomains"),
+    (b"strict-transport-security", b"max-age=31536000; includesubdomains; preload"),
+    (b"vary", b"accept-encoding"),
+    (b"vary", b"origin"),
+    (b"x-content-type-options", b"nosniff"),
+    (b"x-xss-protection", b"1; mode=block"),
+    (b":status", b"100"),
+    (b":status", b"204"),
+    (b":status", b"206"),
+    (b":status", b"302"),
+    (b":status", b"400"),
+    (b":status", b"403"),
+    (b":status", b"421"),
+    (b":status", b"425"),
+    (b":status", b"500"),
+    (b"accept-language", b""),
+    (b"access-control-allow-credentials", b"FALSE"),
+    (b"access-control-allow-credentials", b"TRUE"),
+    (b"access-control-allow-headers", b"*"),
+    (b"access-control-allow-methods", b"get"),
+    (b"access-control-allow-methods", b"get, post, options"),
+    (b"access-control-allow-methods", b"options"),
+    (b"access-control-expose-headers", b"content-length"),
+    (b"access-control-request-headers", b"content-type"),
+    (b"access-control-request-method", b"get"),
+    (b"access-control-request-method", b"post"),
+    (b"alt-svc", b"clear"),
+    (b"authorization", b""),
+    (b"content-security-policy", b"script-src 'none'; object-src 'none'; base-uri 'none'"),
+    (b"early-data", b"1"),
+    (b"expect-ct", b""),
+    (b"forwarded", b""),
+    (b"if-range", b""),
+    (b"origin", b""),
+    (b"purpose", b"prefetch"),
+    (b"server", b""),
+    (b"timing-allow-origin", b"*"),
+    (b"upgrade-insecure-requests", b"1"),
+    (b"user-agent", b""),
+    (b"x-forwarded-for", b""),
+    (b"x-frame-options", b"deny"),
+    (b"x-frame-options", b"sameorigin"),
+];
+
+/// Looks `header` up in the static table, returning the index of the entry matching both the
+/// name and the value, or else of the first one matching the name.
+fn find_static(name: &[u8], value: &[u8]) -> Option<(usize, bool)> {
+    let mut matching_name = None;
+    for (i, &(n, v)) in STATIC_TABLE.iter().enumerate() {
+        if n == name {
+            if v == value {
+                return Some((i, true));
+            }
+            if matching_name.is_none() {
+                matching_name = Some(i);
+            }
+        }
+    }
+    matching_name.map(|i| (i, false))
+}
+
+/// Why an instruction or a field line could not be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ReadError {
+    /// The buffer ends before it does; instructions continue in the next read of the stream.
+    Incomplete,
+    Invalid,
+}
+
+fn read_integer(buf: &[u8], prefix_size: u8) -> Result<(usize, usize), ReadError> {
+    let (value, consumed) = try!(read_u64(buf, prefix_size));
+    if value > usize::max_value() as u64 {
+        return Err(ReadError::Invalid);
+    }
+    Ok((value as usize, consumed))
+}
+
+/// Reads an integer that may not fit in a `usize`, i.e. a stream ID.
+fn read_u64(buf: &[u8], prefix_size: u8) -> Result<(u64, usize), ReadError> {
+    decode_integer_u64(buf, prefix_size).map_err(|e| match e {
+        DecoderError::IntegerDecodingError(IntegerDecodingError::NotEnoughOctets) => ReadError::Incomplete,
+        _ => ReadError::Invalid,
+    })
+}
+
+/// Reads a string literal whose length has a `prefix_size`-bit prefix, with the Huffman flag
+/// right above it.
+fn read_string(buf: &[u8], prefix_size: u8) -> Result<(Vec<u8>, usize), ReadError> {
+    let (len, consumed) = try!(read_integer(buf, prefix_size));
+    if buf.len() - consumed < len {
+        return Err(ReadError::Incomplete);
+    }
+    let raw = &buf[consumed..consumed + len];
+    let string = if buf[0] & (1 << prefix_size) != 0 {
+        try!(HuffmanDecoder::new().decode(raw).map_err(|_| ReadError::Invalid))
+    } else {
+        raw.to_vec()
+    };
+    Ok((string, consumed + len))
+}
+
+fn write_integer(value: usize, prefix_size: u8, leading_bits: u8, out: &mut Vec<u8>) {
+    write_u64(value as u64, prefix_size, leading_bits, out);
+}
+
+fn write_u64(value: u64, prefix_size: u8, leading_bits: u8, out: &mut Vec<u8>) {
+    encode_integer_u64_into(value, prefix_size, leading_bits, out).unwrap();
+}
+
+/// Writes a string literal, Huffman coded when that is shorter, with the length in a
+/// `prefix_size`-bit prefix under `leading_bits`.
+fn write_string(s: &[u8], prefix_size: u8, leading_bits: u8, out: &mut Vec<u8>) {
+    let huffman_len = huffman::encoded_len(s);
+    if huffman_len < s.len() {
+        write_integer(huffman_len, prefix_size, leading_bits | (1 << prefix_size), out);
+        huffman::encode(s, out);
+    } else {
+        write_integer(s.len(), prefix_size, leading_bits, out);
+        out.extend_from_slice(s);
+    }
+}
+
+/// The dynamic table. Entries are addressed by their absolute index: 0 for the first entry
+/// ever inserted, and so on; instructions and field lines carry indices relative to the
+/// number of inserts or to a field section's base.
+#[derive(Debug, Default)]
+struct Table {
+    /// Oldest first.
+    entries: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// The number of evicted entries, i.e. the absolute index of the oldest entry.
+    dropped: usize,
+    size: usize,
+    capacity: usize,
+}
+
+impl Table {
+    fn insert_count(&self) -> usize {
+        self.dropped + self.entries.len()
+    }
+
+    fn get(&self, index: usize) -> Option<&(Vec<u8>, Vec<u8>)> {
+        if index < self.dropped {
+            return None;
+        }
+        self.entries.get(index - self.dropped)
+    }
+
+    /// The absolute index of the newest entry matching both the name and the value, or else
+    /// of the newest one matching the name.
+    fn find(&self, name: &[u8], value: &[u8]) -> Option<(usize, bool)> {
+        let mut matching_name = None;
+        for (i, entry) in self.entries.iter().enumerate().rev() {
+            if entry.0 == name {
+                if entry.1 == value {
+                    return Some((self.dropped + i, true));
+                }
+                if matching_name.is_none() {
+                    matching_name = Some(self.dropped + i);
+                }
+            }
+        }
+        matching_name.map(|i| (i, false))
+    }
+
+    /// Whether evicting only entries below absolute index `pinned` makes `size` octets fit.
+    fn has_room(&self, size: usize, pinned: usize) -> bool {
+        let mut available = self.capacity - self.size;
+        let mut index = self.dropped;
+        for entry in &self.entries {
+            if available >= size || index >= pinned {
+                break;
+            }
+            available += entry_size(&entry.0, &entry.1);
+            index += 1;
+        }
+        available >= size
+    }
+
+    /// Evicts the oldest entries until the table has `size` free octets.
+    fn make_room(&mut self, size: usize) {
+        while self.capacity - self.size < size {
+            let entry = self.entries.pop_front().expect("room within the capacity");
+            self.size -= entry_size(&entry.0, &entry.1);
+            self.dropped += 1;
+        }
+    }
+
+    /// Inserts an entry, evicting as needed; `false` if it is larger than the table.
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) -> bool {
+        let size = entry_size(&name, &value);
+        if size > self.capacity {
+            return false;
+        }
+        self.make_room(size);
+        self.size += size;
+        self.entries.push_back((name, value));
+        true
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        while self.size > capacity {
+            let entry = self.entries.pop_front().unwrap();
+            self.size -= entry_size(&entry.0, &entry.1);
+            self.dropped += 1;
+        }
+        self.capacity = capacity;
+    }
+}
+
+/// Encodes a field section's Required Insert Count modulo twice the number of entries the
+/// largest allowed table can hold (RFC 9204 section 4.5.1.1).
+fn encode_required_insert_count(required: usize, max_capacity: usize) -> usize {
+    if required == 0 {
+        return 0;
+    }
+    required % (2 * (max_capacity / 32)) + 1
+}
+
+fn decode_required_insert_count(encoded: usize, max_capacity: usize, insert_count: usize)
+        -> Result<usize, Error> {
+    if encoded == 0 {
+        return Ok(0);
+    }
+    let max_entries = max_capacity / 32;
+    let full_range = 2 * max_entries;
+    if encoded > full_range {
+        return Err(Error::DecompressionFailed);
+    }
+    let max_value = insert_count + max_entries;
+    let max_wrapped = max_value / full_range * full_range;
+    let mut required = max_wrapped + encoded - 1;
+    if required > max_value {
+        if required <= full_range {
+            return Err(Error::DecompressionFailed);
+        }
+        required -= full_range;
+    }
+    if required == 0 {
+        return Err(Error::DecompressionFailed);
+    }
+    Ok(required)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_required_insert_count, encode_required_insert_count, find_static, STATIC_TABLE};
+
+    #[test]
+    fn test_required_insert_count() {
+        assert_eq!(STATIC_TABLE.len(), 99);
+        assert_eq!(find_static(b":status", b"200"), Some((25, true)));
+        assert_eq!(find_static(b":status", b"201"), Some((24, false)));
+
+        // A 220 octet table holds 6 entries: the count wraps around every 12.
+        for &(required, insert_count) in &[(0, 0), (2, 2), (11, 9), (12, 12), (13, 10), (30, 30)] {
+            let encoded = encode_required_insert_count(required, 220);
+            assert!(encoded <= 12);
+            assert_eq!(decode_required_insert_count(encoded, 220, insert_count), Ok(required));
+        }
+        assert!(decode_required_insert_count(13, 220, 0).is_err());
+    }
+}
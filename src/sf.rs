@@ -0,0 +1,601 @@
+// Copyright 2016 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structured Field Values (RFC 8941): items, lists and dictionaries with parameters, as used by
+//! `priority`, client hints, `signature-input` and other recent headers.
+//!
+//! ```rust
+//! use tokio_http2::sf::{self, BareItem};
+//!
+//! let dict = sf::parse_dictionary("u=1, i").unwrap();
+//! assert_eq!(dict.get("u").and_then(|m| m.item()).map(|i| &i.bare), Some(&BareItem::Integer(1)));
+//! assert_eq!(sf::serialize_dictionary(&dict).unwrap(), "u=1, i");
+//! ```
+
+use std::fmt;
+
+use rustc_serialize::base64::{self, FromBase64, ToBase64};
+
+const MAX_INTEGER: i64 = 999_999_999_999_999;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Error {
+    /// The field value doesn't parse; the byte offset where parsing failed.
+    Parse(usize),
+    /// The value can't be serialized, e.g. a token with invalid characters or an integer out of
+    /// range.
+    Serialize(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(offset) => write!(f, "invalid structured field value at byte {}", offset),
+            Error::Serialize(reason) => write!(f, "can't serialize structured field value: {}", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BareItem {
+    Integer(i64),
+    Decimal(f64),
+    String(String),
+    Token(String),
+    ByteSequence(Vec<u8>),
+    Boolean(bool),
+}
+
+/// Ordered key/value pairs with unique keys.
+pub type Parameters = Vec<(String, BareItem)>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    pub bare: BareItem,
+    pub params: Parameters,
+}
+
+impl Item {
+    pub fn new(bare: BareItem) -> Item {
+        Item {
+            bare: bare,
+            params: Parameters::new(),
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&BareItem> {
+        self.params.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+    }
+}
+
+/// A member of a list or dictionary.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Member {
+    Item(Item),
+    InnerList(Vec<Item>, Parameters),
+}
+
+impl Member {
+    pub fn item(&self) -> Option<&Item> {
+        match *self {
+            Member::Item(ref item) => Some(item),
+            Member::InnerList(..) => None,
+        }
+    }
+}
+
+pub type List = Vec<Member>;
+
+/// An ordered dictionary; keys are unique, a repeated key keeps its first position and last value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dictionary {
+    pub members: Vec<(String, Member)>,
+}
+
+impl Dictionary {
+    pub fn get(&self, key: &str) -> Option<&Member> {
+        self.members.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+    }
+
+    pub fn insert(&mut self, key: &str, member: Member) {
+        match self.members.iter().position(|&(ref k, _)| k == key) {
+            Some(i) => self.members[i].1 = member,
+            None => self.members.push((key.to_string(), member)),
+        }
+    }
+}
+
+pub fn parse_item(value: &str) -> Result<Item, Error> {
+    let mut parser = Parser::new(value);
+    parser.skip_sp();
+    let item = try!(parser.item());
+    parser.finish(item)
+}
+
+pub fn parse_list(value: &str) -> Result<List, Error> {
+    let mut parser = Parser::new(value);
+    parser.skip_sp();
+    let mut list = List::new();
+    while !parser.done() {
+        list.push(try!(parser.member()));
+        if !try!(parser.next_member()) {
+            break;
+        }
+    }
+    parser.finish(list)
+}
+
+pub fn parse_dictionary(value: &str) -> Result<Dictionary, Error> {
+    let mut parser = Parser::new(value);
+    parser.skip_sp();
+    let mut dict = Dictionary::default();
+    while !parser.done() {
+        let key = try!(parser.key());
+        let member = if parser.peek() == Some(b'=') {
+            parser.pos += 1;
+            try!(parser.member())
+        } else {
+            Member::Item(Item { bare: BareItem::Boolean(true), params: try!(parser.params()) })
+        };
+        dict.insert(&key, member);
+        if !try!(parser.next_member()) {
+            break;
+        }
+    }
+    parser.finish(dict)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Parser<'a> {
+        Parser {
+            input: input.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn error<T>(&self) -> Result<T, Error> {
+        Err(Error::Parse(self.pos))
+    }
+
+    fn skip_sp(&mut self) {
+        while self.peek() == Some(b' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_ows(&mut self) {
+        while self.peek() == Some(b' ') || self.peek() == Some(b'\t') {
+            self.pos += 1;
+        }
+    }
+
+    fn finish<T>(&mut self, value: T) -> Result<T, Error> {
+        self.skip_sp();
+        if self.done() { Ok(value) } else { self.error() }
+    }
+
+    /// After a list or dictionary member: whether another one follows.
+    fn next_member(&mut self) -> Result<bool, Error> {
+        self.skip_ows();
+        if self.done() {
+            return Ok(false);
+        }
+        if self.peek() != Some(b',') {
+            return self.error();
+        }
+        self.pos += 1;
+        self.skip_ows();
+        // A trailing comma is an error.
+        if self.done() { self.error() } else { Ok(true) }
+    }
+
+    fn member(&mut self) -> Result<Member, Error> {
+        if self.peek() != Some(b'(') {
+            return self.item().map(Member::Item);
+        }
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_sp();
+            match self.peek() {
+                Some(b')') => {
+                    self.pos += 1;
+                    let params = try!(self.params());
+                    return Ok(Member::InnerList(items, params));
+                },
+                Some(_) => {
+                    items.push(try!(self.item()));
+                    match self.peek() {
+                        Some(b' ') | Some(b')') => {},
+                        _ => return self.error(),
+                    }
+                },
+                None => return self.error(),
+            }
+        }
+    }
+
+    fn item(&mut self) -> Result<Item, Error> {
+        let bare = try!(self.bare_item());
+        let params = try!(self.params());
+        Ok(Item { bare: bare, params: params })
+    }
+
+    fn params(&mut self) -> Result<Parameters, Error> {
+        let mut params = Parameters::new();
+        while self.peek() == Some(b';') {
+            self.pos += 1;
+            self.skip_sp();
+            let key = try!(self.key());
+            let value = if self.peek() == Some(b'=') {
+                self.pos += 1;
+                try!(self.bare_item())
+            } else {
+                BareItem::Boolean(true)
+            };
+            match params.iter().position(|&(ref k, _)| *k == key) {
+                Some(i) => params[i].1 = value,
+                None => params.push((key, value)),
+            }
+        }
+        Ok(params)
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'a'...b'z') | Some(b'*') => self.pos += 1,
+            _ => return self.error(),
+        }
+        while let Some(b) = self.peek() {
+            match b {
+                b'a'...b'z' | b'0'...b'9' | b'_' | b'-' | b'.' | b'*' => self.pos += 1,
+                _ => break,
+            }
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.pos]).into_owned())
+    }
+
+    fn bare_item(&mut self) -> Result<BareItem, Error> {
+        match self.peek() {
+            Some(b'-') | Some(b'0'...b'9') => self.number(),
+            Some(b'"') => self.string(),
+            Some(b':') => self.byte_sequence(),
+            Some(b'?') => self.boolean(),
+            Some(b'*') | Some(b'a'...b'z') | Some(b'A'...b'Z') => Ok(self.token()),
+            _ => self.error(),
+        }
+    }
+
+    fn number(&mut self) -> Result<BareItem, Error> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        let mut dot = None;
+        while let Some(b) = self.peek() {
+            match b {
+                b'0'...b'9' => self.pos += 1,
+                b'.' if dot.is_none() && self.pos > digits_start => {
+                    dot = Some(self.pos);
+                    self.pos += 1;
+                },
+                _ => break,
+            }
+        }
+        let digits = &self.input[digits_start..self.pos];
+        let text = String::from_utf8_lossy(&self.input[start..self.pos]).into_owned();
+        match dot {
+            None => {
+                if digits.is_empty() || digits.len() > 15 {
+                    return Err(Error::Parse(start));
+                }
+                text.parse::<i64>().map(BareItem::Integer).map_err(|_| Error::Parse(start))
+            },
+            Some(dot) => {
+                let (integer, fraction) = (dot - digits_start, self.pos - dot - 1);
+                if integer > 12 || fraction == 0 || fraction > 3 {
+                    return Err(Error::Parse(start));
+                }
+                text.parse::<f64>().map(BareItem::Decimal).map_err(|_| Error::Parse(start))
+            },
+        }
+    }
+
+    fn string(&mut self) -> Result<BareItem, Error> {
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b) if b == b'"' || b == b'\\' => value.push(b as char),
+                        _ => return self.error(),
+                    }
+                },
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(BareItem::String(value));
+                },
+                Some(b) if b >= 0x20 && b < 0x7f => value.push(b as char),
+                _ => return self.error(),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn token(&mut self) -> BareItem {
+        let start = self.pos;
+        self.pos += 1;
+        while let Some(b) = self.peek() {
+            if is_tchar(b) || b == b':' || b == b'/' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        BareItem::Token(String::from_utf8_lossy(&self.input[start..self.pos]).into_owned())
+    }
+
+    fn byte_sequence(&mut self) -> Result<BareItem, Error> {
+        let start = self.pos;
+        self.pos += 1;
+        let end = match self.input[self.pos..].iter().position(|&b| b == b':') {
+            Some(len) => self.pos + len,
+            None => return self.error(),
+        };
+        let encoded = &self.input[self.pos..end];
+        let valid = encoded.iter().all(|&b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'+' | b'/' | b'=' => true,
+            _ => false,
+        });
+        if !valid {
+            return Err(Error::Parse(start));
+        }
+        let bytes = try!(encoded.from_base64().map_err(|_| Error::Parse(start)));
+        self.pos = end + 1;
+        Ok(BareItem::ByteSequence(bytes))
+    }
+
+    fn boolean(&mut self) -> Result<BareItem, Error> {
+        self.pos += 1;
+        let value = match self.peek() {
+            Some(b'0') => false,
+            Some(b'1') => true,
+            _ => return self.error(),
+        };
+        self.pos += 1;
+        Ok(BareItem::Boolean(value))
+    }
+}
+
+/// A `tchar` of RFC 9110 section 5.6.2, of which tokens, methods and field names are made.
+pub fn is_tchar(b: u8) -> bool {
+    match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' |
+        b'~' | b'0'...b'9' | b'a'...b'z' | b'A'...b'Z' => true,
+        _ => false,
+    }
+}
+
+pub fn serialize_item(item: &Item) -> Result<String, Error> {
+    let mut out = String::new();
+    try!(write_item(&mut out, item));
+    Ok(out)
+}
+
+pub fn serialize_list(list: &List) -> Result<String, Error> {
+    let mut out = String::new();
+    for (i, member) in list.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        try!(write_member(&mut out, member));
+    }
+    Ok(out)
+}
+
+pub fn serialize_dictionary(dict: &Dictionary) -> Result<String, Error> {
+    let mut out = String::new();
+    for (i, &(ref key, ref member)) in dict.members.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        try!(write_key(&mut out, key));
+        match *member {
+            Member::Item(Item { bare: BareItem::Boolean(true), ref params }) => try!(write_params(&mut out, params)),
+            _ => {
+                out.push('=');
+                try!(write_member(&mut out, member));
+            },
+        }
+    }
+    Ok(out)
+}
+
+fn write_member(out: &mut String, member: &Member) -> Result<(), Error> {
+    match *member {
+        Member::Item(ref item) => write_item(out, item),
+        Member::InnerList(ref items, ref params) => {
+            out.push('(');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                try!(write_item(out, item));
+            }
+            out.push(')');
+            write_params(out, params)
+        },
+    }
+}
+
+fn write_item(out: &mut String, item: &Item) -> Result<(), Error> {
+    try!(write_bare_item(out, &item.bare));
+    write_params(out, &item.params)
+}
+
+fn write_params(out: &mut String, params: &Parameters) -> Result<(), Error> {
+    for &(ref key, ref value) in params {
+        out.push(';');
+        try!(write_key(out, key));
+        if *value != BareItem::Boolean(true) {
+            out.push('=');
+            try!(write_bare_item(out, value));
+        }
+    }
+    Ok(())
+}
+
+fn write_key(out: &mut String, key: &str) -> Result<(), Error> {
+    let bytes = key.as_bytes();
+    let valid = match bytes.first() {
+        Some(&b) => b == b'*' || (b >= b'a' && b <= b'z'),
+        None => false,
+    } && bytes.iter().all(|&b| match b {
+        b'a'...b'z' | b'0'...b'9' | b'_' | b'-' | b'.' | b'*' => true,
+        _ => false,
+    });
+    if !valid {
+        return Err(Error::Serialize("invalid key"));
+    }
+    out.push_str(key);
+    Ok(())
+}
+
+fn write_bare_item(out: &mut String, bare: &BareItem) -> Result<(), Error> {
+    match *bare {
+        BareItem::Integer(value) => {
+            if value > MAX_INTEGER || value < -MAX_INTEGER {
+                return Err(Error::Serialize("integer out of range"));
+            }
+            out.push_str(&value.to_string());
+        },
+        BareItem::Decimal(value) => {
+            // Round half to even to three decimal places.
+            let scaled = value * 1000.0;
+            let mut rounded = scaled.round();
+            if (scaled - scaled.trunc()).abs() == 0.5 && rounded % 2.0 != 0.0 {
+                rounded -= scaled.signum();
+            }
+            let rounded = rounded / 1000.0;
+            if !rounded.is_finite() || rounded.abs().trunc() >= 1e12 {
+                return Err(Error::Serialize("decimal out of range"));
+            }
+            let text = format!("{:.3}", rounded);
+            let text = text.trim_right_matches('0');
+            out.push_str(text);
+            if text.ends_with('.') {
+                out.push('0');
+            }
+        },
+        BareItem::String(ref value) => {
+            out.push('"');
+            for c in value.chars() {
+                match c {
+                    '"' | '\\' => {
+                        out.push('\\');
+                        out.push(c);
+                    },
+                    ' '...'~' => out.push(c),
+                    _ => return Err(Error::Serialize("string with non-printable or non-ASCII characters")),
+                }
+            }
+            out.push('"');
+        },
+        BareItem::Token(ref value) => {
+            let bytes = value.as_bytes();
+            let valid = match bytes.first() {
+                Some(&b) => b == b'*' || (b >= b'a' && b <= b'z') || (b >= b'A' && b <= b'Z'),
+                None => false,
+            } && bytes.iter().all(|&b| is_tchar(b) || b == b':' || b == b'/');
+            if !valid {
+                return Err(Error::Serialize("invalid token"));
+            }
+            out.push_str(value);
+        },
+        BareItem::ByteSequence(ref value) => {
+            out.push(':');
+            out.push_str(&value.to_base64(base64::STANDARD));
+            out.push(':');
+        },
+        BareItem::Boolean(value) => out.push_str(if value { "?1" } else { "?0" }),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_dictionary, parse_item, parse_list, serialize_dictionary, serialize_item, serialize_list,
+                BareItem, Error, Item, Member};
+
+    #[test]
+    fn test_parse() {
+        let item = parse_item(" \"a \\\"b\\\"\";q=0.5;x ").unwrap();
+        assert_eq!(item.bare, BareItem::String("a \"b\"".to_string()));
+        assert_eq!(item.param("q"), Some(&BareItem::Decimal(0.5)));
+        assert_eq!(item.param("x"), Some(&BareItem::Boolean(true)));
+        assert_eq!(parse_item(":aGVsbG8=:").unwrap().bare, BareItem::ByteSequence(b"hello".to_vec()));
+        assert_eq!(parse_item("text/html").unwrap().bare, BareItem::Token("text/html".to_string()));
+
+        let list = parse_list("sugar, tea;hot, (rum \"cola\");n=2").unwrap();
+        assert_eq!(list.len(), 3);
+        match list[2] {
+            Member::InnerList(ref items, ref params) => {
+                assert_eq!(items.len(), 2);
+                assert_eq!(params[0], ("n".to_string(), BareItem::Integer(2)));
+            },
+            _ => panic!("expected an inner list"),
+        }
+
+        let dict = parse_dictionary("a=1, b, a=?0").unwrap();
+        assert_eq!(dict.members.len(), 2);
+        assert_eq!(dict.members[0].0, "a");
+        assert_eq!(dict.get("a").and_then(|m| m.item()).map(|i| &i.bare), Some(&BareItem::Boolean(false)));
+
+        assert_eq!(parse_list("a,"), Err(Error::Parse(2)));
+        assert_eq!(parse_item("1234567890123456"), Err(Error::Parse(0)));
+        assert_eq!(parse_item("1.2345"), Err(Error::Parse(0)));
+        assert_eq!(parse_dictionary("A=1"), Err(Error::Parse(0)));
+    }
+
+    #[test]
+    fn test_serialize() {
+        for value in &["sugar, tea;hot, (rum \"cola\");n=2", "(), :AQID:;a=?0"] {
+            assert_eq!(&serialize_list(&parse_list(value).unwrap()).unwrap(), value);
+        }
+        for value in &["u=1, i", "a=(1 2), b=\"x\";p"] {
+            assert_eq!(&serialize_dictionary(&parse_dictionary(value).unwrap()).unwrap(), value);
+        }
+        assert_eq!(serialize_item(&Item::new(BareItem::Decimal(1.0))).unwrap(), "1.0");
+        assert_eq!(serialize_item(&Item::new(BareItem::Decimal(0.0025))).unwrap(), "0.002");
+        assert_eq!(serialize_item(&Item::new(BareItem::Token("1a".to_string()))),
+                   Err(Error::Serialize("invalid token")));
+    }
+}
@@ -18,7 +18,7 @@
 //! the `HttpVersion` enum.
 use std::fmt;
 
-use self::HttpVersion::{Http09, Http10, Http11, H2, H2c};
+use self::HttpVersion::{Http09, Http10, Http11, H2, H2c, H3};
 
 /// Represents a version of the HTTP spec.
 #[derive(PartialEq, PartialOrd, Copy, Clone, Eq, Ord, Hash, Debug)]
@@ -33,6 +33,8 @@ pub enum HttpVersion {
     H2,
     /// `HTTP/2.0` over cleartext
     H2c,
+    /// `HTTP/3` over QUIC
+    H3,
 }
 
 impl fmt::Display for HttpVersion {
@@ -43,6 +45,7 @@ impl fmt::Display for HttpVersion {
             Http11 => "HTTP/1.1",
             H2 => "h2",
             H2c => "h2c",
+            H3 => "h3",
         })
     }
 }